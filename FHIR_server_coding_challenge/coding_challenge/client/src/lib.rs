@@ -0,0 +1,497 @@
+//! Typed async client for fhir-server.
+//!
+//! Wraps the REST API with the shared [`models`] so services talking to the
+//! server do not hand-build JSON requests:
+//!
+//! ```no_run
+//! # async fn example() -> fhir_client::Result<()> {
+//! use fhir_client::FhirClient;
+//!
+//! let client = FhirClient::new("http://localhost:3000");
+//! let bundle = client.search().name("Gauß").gender("male").send().await?;
+//! for entry in bundle.entry {
+//!     println!("{:?}", entry.resource.id);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub use fhir_models as models;
+
+use models::{Bundle, Observation, OperationOutcome, Patient};
+use reqwest::{header::CONTENT_TYPE, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+const FHIR_JSON: &str = "application/fhir+json";
+
+/// Failure of a client call
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or the response body not decoded
+    Http(reqwest::Error),
+    /// The server answered with an error status; `outcome` is its
+    /// OperationOutcome when the body contained one
+    Server {
+        status: StatusCode,
+        outcome: Option<OperationOutcome>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "FHIR request failed: {}", e),
+            Error::Server { status, outcome } => {
+                write!(f, "FHIR server returned {}", status)?;
+                let diagnostics = outcome
+                    .iter()
+                    .flat_map(|o| &o.issue)
+                    .filter_map(|i| i.diagnostics.as_deref());
+                for diagnostic in diagnostics {
+                    write!(f, ": {}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Server { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// HTTP status of a server-side failure
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Http(e) => e.status(),
+            Error::Server { status, .. } => Some(*status),
+        }
+    }
+}
+
+/// Client for one fhir-server deployment
+#[derive(Debug, Clone)]
+pub struct FhirClient {
+    http: reqwest::Client,
+    /// The FHIR base, e.g. `http://localhost:3000/fhir`
+    fhir_base: String,
+}
+
+impl FhirClient {
+    /// `base_url` is the server root, e.g. `http://localhost:3000`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(reqwest::Client::new(), base_url)
+    }
+
+    /// Use a preconfigured `reqwest` client (timeouts, TLS, default headers)
+    pub fn with_http_client(http: reqwest::Client, base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into();
+        Self::with_fhir_base(http, format!("{}/fhir", base_url.trim_end_matches('/')))
+    }
+
+    /// Talk to any FHIR server by its base URL, the one `metadata` is read
+    /// below, e.g. `https://fhir.example.org/r4`
+    pub fn with_fhir_base(http: reqwest::Client, fhir_base: impl Into<String>) -> Self {
+        let fhir_base = fhir_base.into().trim_end_matches('/').to_string();
+        Self { http, fhir_base }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.fhir_base, path)
+    }
+
+    /// The server's CapabilityStatement
+    pub async fn capabilities(&self) -> Result<Value> {
+        self.send(self.http.get(self.url("metadata"))).await
+    }
+
+    pub async fn create_patient(&self, patient: &Patient) -> Result<Patient> {
+        self.send(with_resource(self.http.post(self.url("Patient")), patient))
+            .await
+    }
+
+    /// `None` when no patient has this id or it was deleted
+    pub async fn get_patient(&self, id: &str) -> Result<Option<Patient>> {
+        optional(
+            self.send(self.http.get(self.url(&format!("Patient/{}", id))))
+                .await,
+        )
+    }
+
+    /// Replace the patient (PUT semantics), or create it under an id from
+    /// [`FhirClient::reserve_patient_id`]
+    pub async fn update_patient(&self, id: &str, patient: &Patient) -> Result<Patient> {
+        self.send(with_resource(
+            self.http.put(self.url(&format!("Patient/{}", id))),
+            patient,
+        ))
+        .await
+    }
+
+    /// Reserve a Patient id to link records to before the patient is
+    /// created with [`FhirClient::update_patient`]; the reservation expires
+    /// after the server's `id_reservation_days`
+    pub async fn reserve_patient_id(&self) -> Result<String> {
+        let parameters: Value = self
+            .send(self.http.post(self.url("Patient/$reserve-id")))
+            .await?;
+        let id = parameters["parameter"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|parameter| parameter["name"] == "id")
+            .and_then(|parameter| parameter["valueId"].as_str());
+        Ok(id.unwrap_or_default().to_string())
+    }
+
+    /// A link reading the patient's `summary` or `record` without
+    /// credentials for `hours` (the server's default when `None`)
+    pub async fn share_patient(&self, id: &str, scope: &str, hours: Option<u32>) -> Result<String> {
+        let mut request = self
+            .http
+            .post(self.url(&format!("Patient/{}/$share", id)))
+            .query(&[("scope", scope)]);
+        if let Some(hours) = hours {
+            request = request.query(&[("hours", hours)]);
+        }
+        let parameters: Value = self.send(request).await?;
+        let url = parameters["parameter"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|parameter| parameter["name"] == "url")
+            .and_then(|parameter| parameter["valueUrl"].as_str());
+        Ok(url.unwrap_or_default().to_string())
+    }
+
+    /// Conditional update: replace the patient holding `identifier`
+    /// (`system|value`), or create it when no patient does
+    pub async fn upsert_patient(&self, identifier: &str, patient: &Patient) -> Result<Patient> {
+        self.send(with_resource(
+            self.http
+                .put(self.url("Patient"))
+                .query(&[("identifier", identifier)]),
+            patient,
+        ))
+        .await
+    }
+
+    /// Apply a JSON Patch (RFC 6902) document, e.g.
+    /// `json!([{ "op": "replace", "path": "/gender", "value": "female" }])`
+    pub async fn patch_patient(&self, id: &str, patch: &Value) -> Result<Patient> {
+        self.send(
+            self.http
+                .patch(self.url(&format!("Patient/{}", id)))
+                .header(CONTENT_TYPE, "application/json-patch+json")
+                .json(patch),
+        )
+        .await
+    }
+
+    /// Returns whether the patient existed; deleting a deleted patient
+    /// succeeds again
+    pub async fn delete_patient(&self, id: &str) -> Result<bool> {
+        self.delete(&format!("Patient/{}", id)).await
+    }
+
+    /// Version history Bundle of a patient, newest first
+    pub async fn patient_history(&self, id: &str) -> Result<Value> {
+        self.send(self.http.get(self.url(&format!("Patient/{}/_history", id))))
+            .await
+    }
+
+    /// Base version, current version and JSON Patch `diff` between them,
+    /// for rebasing an update that was rejected with 412
+    pub async fn patient_conflict(&self, id: &str, base_version: &str) -> Result<Value> {
+        self.send(
+            self.http
+                .get(self.url(&format!("Patient/{}/$conflict", id)))
+                .query(&[("base", base_version)]),
+        )
+        .await
+    }
+
+    /// Start a Patient search
+    pub fn search(&self) -> PatientSearch<'_> {
+        PatientSearch {
+            client: self,
+            params: Vec::new(),
+        }
+    }
+
+    /// The page after `bundle`, following its `next` link; `None` on the
+    /// last page
+    pub async fn next_page<T: DeserializeOwned>(
+        &self,
+        bundle: &Bundle<T>,
+    ) -> Result<Option<Bundle<T>>> {
+        match bundle.link_url("next") {
+            Some(url) => Ok(Some(self.send(self.http.get(url)).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn create_observation(&self, observation: &Observation) -> Result<Observation> {
+        self.send(with_resource(
+            self.http.post(self.url("Observation")),
+            observation,
+        ))
+        .await
+    }
+
+    /// `None` when no observation has this id
+    pub async fn get_observation(&self, id: &str) -> Result<Option<Observation>> {
+        optional(
+            self.send(self.http.get(self.url(&format!("Observation/{}", id))))
+                .await,
+        )
+    }
+
+    /// Replace the observation (PUT semantics)
+    pub async fn update_observation(
+        &self,
+        id: &str,
+        observation: &Observation,
+    ) -> Result<Observation> {
+        self.send(with_resource(
+            self.http.put(self.url(&format!("Observation/{}", id))),
+            observation,
+        ))
+        .await
+    }
+
+    /// Returns whether the observation existed
+    pub async fn delete_observation(&self, id: &str) -> Result<bool> {
+        self.delete(&format!("Observation/{}", id)).await
+    }
+
+    /// Start an Observation search
+    pub fn search_observations(&self) -> ObservationSearch<'_> {
+        ObservationSearch {
+            client: self,
+            params: Vec::new(),
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let response = request.header("Accept", FHIR_JSON).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(server_error(status, response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    async fn delete(&self, path: &str) -> Result<bool> {
+        let response = self.http.delete(self.url(path)).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(server_error(status, response).await),
+        }
+    }
+
+    async fn search_bundle<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> Result<Bundle<T>> {
+        self.send(self.http.get(self.url(path)).query(params)).await
+    }
+}
+
+async fn server_error(status: StatusCode, response: reqwest::Response) -> Error {
+    let outcome = response
+        .json::<OperationOutcome>()
+        .await
+        .ok()
+        .filter(|o| o.resource_type == "OperationOutcome");
+    Error::Server { status, outcome }
+}
+
+/// Attach a resource body; the content type must be set before `.json()`,
+/// which otherwise defaults it to `application/json`
+fn with_resource(request: RequestBuilder, resource: &impl Serialize) -> RequestBuilder {
+    request.header(CONTENT_TYPE, FHIR_JSON).json(resource)
+}
+
+/// Turn a 404 or 410 (deleted) into `Ok(None)`
+fn optional<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if matches!(e.status(), Some(StatusCode::NOT_FOUND | StatusCode::GONE)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Builder for `GET /fhir/Patient`
+#[derive(Debug, Clone)]
+pub struct PatientSearch<'a> {
+    client: &'a FhirClient,
+    params: Vec<(&'static str, String)>,
+}
+
+impl PatientSearch<'_> {
+    fn param(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.params.push((name, value.into()));
+        self
+    }
+
+    /// Substring match on family or first given name
+    pub fn name(self, name: impl Into<String>) -> Self {
+        self.param("name", name)
+    }
+
+    /// The whole family or first given name, case-sensitive
+    pub fn name_exact(self, name: impl Into<String>) -> Self {
+        self.param("name:exact", name)
+    }
+
+    pub fn gender(self, gender: impl Into<String>) -> Self {
+        self.param("gender", gender)
+    }
+
+    /// Birth date, optionally prefixed (`1990`, `ge1990-06`, `lt2000-01-01`);
+    /// call it twice for a range
+    pub fn birthdate(self, date: impl Into<String>) -> Self {
+        self.param("birthdate", date)
+    }
+
+    /// Logical id; comma-separated ids match any
+    pub fn id(self, id: impl Into<String>) -> Self {
+        self.param("_id", id)
+    }
+
+    /// Token: `value`, `system|value` or `|value`
+    pub fn identifier(self, token: impl Into<String>) -> Self {
+        self.param("identifier", token)
+    }
+
+    pub fn count(self, count: u32) -> Self {
+        self.param("_count", count.to_string())
+    }
+
+    pub fn offset(self, offset: u32) -> Self {
+        self.param("_offset", offset.to_string())
+    }
+
+    /// Continue after the page whose `next` link handed out `token`
+    pub fn page_token(self, token: impl Into<String>) -> Self {
+        self.param("_page_token", token)
+    }
+
+    pub async fn send(self) -> Result<Bundle<Patient>> {
+        self.client.search_bundle("Patient", &self.params).await
+    }
+}
+
+/// Builder for `GET /fhir/Observation`
+#[derive(Debug, Clone)]
+pub struct ObservationSearch<'a> {
+    client: &'a FhirClient,
+    params: Vec<(&'static str, String)>,
+}
+
+impl ObservationSearch<'_> {
+    fn param(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.params.push((name, value.into()));
+        self
+    }
+
+    /// Token: `code`, `system|code`, `system|` or `|code`
+    pub fn code(self, token: impl Into<String>) -> Self {
+        self.param("code", token)
+    }
+
+    /// `Patient/{id}` or a bare id
+    pub fn subject(self, reference: impl Into<String>) -> Self {
+        self.param("subject", reference)
+    }
+
+    /// Effective date with an optional prefix (`ge2024-01-01`); call twice for a range
+    pub fn date(self, date: impl Into<String>) -> Self {
+        self.param("date", date)
+    }
+
+    pub fn count(self, count: u32) -> Self {
+        self.param("_count", count.to_string())
+    }
+
+    pub fn offset(self, offset: u32) -> Self {
+        self.param("_offset", offset.to_string())
+    }
+
+    /// Continue after the page whose `next` link handed out `token`
+    pub fn page_token(self, token: impl Into<String>) -> Self {
+        self.param("_page_token", token)
+    }
+
+    pub async fn send(self) -> Result<Bundle<Observation>> {
+        self.client.search_bundle("Observation", &self.params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url_is_normalized() {
+        let client = FhirClient::new("http://localhost:3000/");
+        assert_eq!(client.url("Patient"), "http://localhost:3000/fhir/Patient");
+        let other = FhirClient::with_fhir_base(reqwest::Client::new(), "https://example.org/r4/");
+        assert_eq!(other.url("metadata"), "https://example.org/r4/metadata");
+    }
+
+    #[test]
+    fn test_search_builder_collects_params() {
+        let client = FhirClient::new("http://localhost:3000");
+        let search = client
+            .search_observations()
+            .code("http://loinc.org|8867-4")
+            .date("ge2024-01-01")
+            .date("lt2024-04-01")
+            .count(10);
+
+        assert_eq!(
+            search.params,
+            vec![
+                ("code", "http://loinc.org|8867-4".to_string()),
+                ("date", "ge2024-01-01".to_string()),
+                ("date", "lt2024-04-01".to_string()),
+                ("_count", "10".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_server_error_display_includes_diagnostics() {
+        let error = Error::Server {
+            status: StatusCode::CONFLICT,
+            outcome: Some(OperationOutcome::error("duplicate", "MRN 42 is taken")),
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "FHIR server returned 409 Conflict: MRN 42 is taken"
+        );
+        assert_eq!(error.status(), Some(StatusCode::CONFLICT));
+    }
+}
@@ -0,0 +1,211 @@
+use fhir_client::models::{CodeableConcept, Coding, HumanName, Observation, Patient, Reference};
+use fhir_client::FhirClient;
+use serde_json::{json, Map};
+
+const BASE_URL: &str = "http://localhost:3000";
+
+fn patient(family: &str) -> Patient {
+    Patient {
+        name: Some(vec![HumanName {
+            family: Some(family.to_string()),
+            given: Some(vec!["Ada".to_string()]),
+            text: None,
+            extra: Map::new(),
+        }]),
+        gender: Some("female".to_string()),
+        birth_date: Some("1815-12-10".to_string()),
+        ..Patient::new()
+    }
+}
+
+#[tokio::test]
+async fn test_patient_round_trip() {
+    let client = FhirClient::new(BASE_URL);
+    let family = format!("Client{}", uuid::Uuid::new_v4().simple());
+
+    let created = client.create_patient(&patient(&family)).await.unwrap();
+    let id = created.id.clone().unwrap();
+
+    let fetched = client.get_patient(&id).await.unwrap().unwrap();
+    assert_eq!(fetched.birth_date.as_deref(), Some("1815-12-10"));
+
+    let patched = client
+        .patch_patient(
+            &id,
+            &json!([{ "op": "replace", "path": "/gender", "value": "other" }]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(patched.gender.as_deref(), Some("other"));
+
+    let bundle = client
+        .search()
+        .name(family.as_str())
+        .gender("other")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(bundle.entry.len(), 1);
+    assert_eq!(bundle.entry[0].resource.id.as_deref(), Some(id.as_str()));
+
+    let by_id = client.search().id(id.as_str()).send().await.unwrap();
+    assert_eq!(by_id.entry.len(), 1);
+    let exact = client
+        .search()
+        .name_exact(family.to_lowercase())
+        .send()
+        .await
+        .unwrap();
+    assert!(exact.entry.is_empty());
+
+    assert!(client.delete_patient(&id).await.unwrap());
+    assert!(client.get_patient(&id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_search_pages_follow_next_links() {
+    let client = FhirClient::new(BASE_URL);
+    let family = format!("Pages{}", uuid::Uuid::new_v4().simple());
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        let created = client.create_patient(&patient(&family)).await.unwrap();
+        ids.push(created.id.unwrap());
+    }
+
+    let mut page = client
+        .search()
+        .name(family.as_str())
+        .count(2)
+        .send()
+        .await
+        .unwrap();
+    let mut found = Vec::new();
+    loop {
+        found.extend(page.entry.iter().filter_map(|e| e.resource.id.clone()));
+        match client.next_page(&page).await.unwrap() {
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+    found.sort();
+    ids.sort();
+    assert_eq!(found, ids);
+    // Reached by `_page_token`, which only pages forward
+    assert!(page.link_url("self").unwrap().contains("_page_token="));
+    assert!(page.link_url("previous").is_none());
+
+    for id in &ids {
+        client.delete_patient(id).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_upsert_patient_by_identifier() {
+    let client = FhirClient::new(BASE_URL);
+    let identifier = format!("urn:test:client|{}", uuid::Uuid::new_v4());
+    let (system, value) = identifier.split_once('|').unwrap();
+    let mut patient = patient("Upsert");
+    patient.extra.insert(
+        "identifier".to_string(),
+        json!([{ "system": system, "value": value }]),
+    );
+
+    let created = client.upsert_patient(&identifier, &patient).await.unwrap();
+    patient.gender = Some("other".to_string());
+    let updated = client.upsert_patient(&identifier, &patient).await.unwrap();
+
+    assert_eq!(updated.id, created.id);
+    assert_eq!(updated.gender.as_deref(), Some("other"));
+    let found = client
+        .search()
+        .identifier(identifier.as_str())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(found.entry.len(), 1);
+    assert_eq!(found.entry[0].resource.id, created.id);
+    assert!(client
+        .delete_patient(created.id.as_deref().unwrap())
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn test_create_patient_under_reserved_id() {
+    let client = FhirClient::new(BASE_URL);
+    let id = client.reserve_patient_id().await.unwrap();
+    assert!(client.get_patient(&id).await.unwrap().is_none());
+
+    let created = client
+        .update_patient(&id, &patient("Reserved"))
+        .await
+        .unwrap();
+    assert_eq!(created.id.as_deref(), Some(id.as_str()));
+    let retried = client
+        .update_patient(&id, &patient("Reserved"))
+        .await
+        .unwrap();
+    let version = |p: &Patient| p.meta.as_ref().and_then(|m| m.version_id.clone());
+    assert_eq!(version(&retried), version(&created));
+
+    assert!(client.delete_patient(&id).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_missing_resources() {
+    let client = FhirClient::new(BASE_URL);
+    let id = uuid::Uuid::new_v4().to_string();
+
+    assert!(client.get_patient(&id).await.unwrap().is_none());
+    assert!(!client.delete_observation(&id).await.unwrap());
+
+    let err = client
+        .update_patient(&id, &patient("Nobody"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.status(), Some(reqwest::StatusCode::NOT_FOUND));
+    assert!(err.to_string().contains("not found"));
+}
+
+#[tokio::test]
+async fn test_observation_search() {
+    let client = FhirClient::new(BASE_URL);
+    let subject = format!("Patient/{}", uuid::Uuid::new_v4());
+
+    let mut observation = Observation::new(
+        "final",
+        CodeableConcept {
+            coding: Some(vec![Coding {
+                system: Some("http://loinc.org".to_string()),
+                code: Some("8867-4".to_string()),
+                display: None,
+                extra: Map::new(),
+            }]),
+            text: None,
+            extra: Map::new(),
+        },
+    );
+    observation.subject = Some(Reference {
+        reference: Some(subject.clone()),
+        display: None,
+        extra: Map::new(),
+    });
+    observation.effective_date_time = Some("2024-03-01T08:30:00Z".to_string());
+    let created = client.create_observation(&observation).await.unwrap();
+
+    let bundle = client
+        .search_observations()
+        .code("http://loinc.org|8867-4")
+        .subject(subject.as_str())
+        .date("ge2024-03-01")
+        .date("lt2024-03-02")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(bundle.entry.len(), 1);
+
+    assert!(client
+        .delete_observation(created.id.as_deref().unwrap())
+        .await
+        .unwrap());
+}
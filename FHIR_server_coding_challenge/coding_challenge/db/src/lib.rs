@@ -1,13 +1,121 @@
+use fhir_models::search::{contains_pattern, normalize};
+use fhir_models::{Observation, Patient};
+use fhir_schema::{HistoryRow, MaintainRow, SearchRow};
+use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting};
 use pgrx::prelude::*;
+use pgrx::spi::quote_literal;
 use pgrx::Uuid;
 
 pgrx::pg_module_magic!();
 
+/// `fhir.strict_validation`: reject documents whose `resourceType` is not
+/// the type written, and Patients and Observations that do not parse
+static STRICT_VALIDATION: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// `fhir.history_enabled`: record the Patients written by `fhir_put` in
+/// `fhir.patient_history`, as the server records its own writes. Only a
+/// superuser, or a role granted `SET` on it, may turn it off
+static HISTORY_ENABLED: GucSetting<bool> = GucSetting::<bool>::new(true);
+
+/// `fhir.max_resource_kb`: largest document `fhir_put` stores, 0 for any
+static MAX_RESOURCE_KB: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Register the settings, which change without reinstalling the extension.
+/// Any user may change `fhir.strict_validation` and `fhir.max_resource_kb`,
+/// per session (`SET fhir.strict_validation = on`), per role or per database
+/// (`ALTER DATABASE fhir SET fhir.max_resource_kb = 512`).
+/// `fhir.history_enabled` is `Suset`: the history is the audit trail of the
+/// writes, so a session cannot stop recording its own.
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    GucRegistry::define_bool_guc(
+        c"fhir.strict_validation",
+        c"Validate documents before fhir_put stores them",
+        c"Rejects documents whose resourceType is not the type written, and Patients and Observations that do not parse.",
+        &STRICT_VALIDATION,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"fhir.history_enabled",
+        c"Record the Patients written by fhir_put in fhir.patient_history",
+        c"The server reads the same setting for its own writes.",
+        &HISTORY_ENABLED,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"fhir.max_resource_kb",
+        c"Largest document fhir_put stores, in kB",
+        c"0 stores documents of any size.",
+        &MAX_RESOURCE_KB,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+}
+
+/// Enforce `fhir.max_resource_kb` and `fhir.strict_validation` on a
+/// document about to be stored as `resource_type`
+fn check_resource(resource_type: &str, resource: &serde_json::Value) {
+    let max_kb = MAX_RESOURCE_KB.get();
+    let size = resource.to_string().len();
+    if max_kb > 0 && size > max_kb as usize * 1024 {
+        error!(
+            "{} of {} bytes exceeds fhir.max_resource_kb ({} kB)",
+            resource_type, size, max_kb
+        );
+    }
+
+    if !STRICT_VALIDATION.get() {
+        return;
+    }
+    let stated = resource.get("resourceType").and_then(|value| value.as_str());
+    if stated != Some(resource_type) {
+        error!(
+            "resourceType {:?} does not match {} (fhir.strict_validation)",
+            stated, resource_type
+        );
+    }
+    let parsed = match resource_type {
+        "Patient" => serde_json::from_value::<Patient>(resource.clone()).map(drop),
+        "Observation" => serde_json::from_value::<Observation>(resource.clone()).map(drop),
+        _ => Ok(()),
+    };
+    if let Err(e) = parsed {
+        error!("Invalid {} (fhir.strict_validation): {}", resource_type, e);
+    }
+}
+
+/// Any resource type can be stored in fhir_resources. The name is
+/// interpolated into SQL, so only the shape of a FHIR type name
+/// (e.g. `Patient`, `MedicationRequest`) is accepted.
+fn ensure_supported(resource_type: &str) {
+    let mut chars = resource_type.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_uppercase())
+        && chars.all(|c| c.is_ascii_alphanumeric());
+    if !valid {
+        error!("Invalid resource type {:?}", resource_type);
+    }
+}
+
+/// Version of the installed extension library, which can differ from the
+/// `extversion` in `pg_extension` when the library was replaced without
+/// `ALTER EXTENSION ... UPDATE`
+#[pg_extern]
+fn fhir_extension_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Store a new resource. The insert is announced on the `fhir_changes`
+/// channel when the transaction commits, by the triggers of migrations
+/// `011_change_outbox.sql` and `023_change_notify.sql` on `fhir_resources`,
+/// with the same payload as the server's writes.
 #[pg_extern]
 fn fhir_put(resource_type: &str, resource_data: pgrx::JsonB) -> Uuid {
-    if resource_type != "Patient" {
-        error!("Only Patient resources are supported");
-    }
+    ensure_supported(resource_type);
+    check_resource(resource_type, &resource_data.0);
     
     let id: Uuid = Spi::get_one("SELECT gen_random_uuid()")
         .expect("Failed to generate UUID")
@@ -15,21 +123,33 @@ fn fhir_put(resource_type: &str, resource_data: pgrx::JsonB) -> Uuid {
     
     // Insert into fhir_resources table
     Spi::run(&format!(
-        "INSERT INTO fhir_resources (id, resource_type, resource_data) VALUES ('{}', '{}', '{}')",
-        id, resource_type, resource_data.0
+        "INSERT INTO fhir_resources (id, resource_type, resource_data) VALUES ('{}', '{}', {})",
+        id, resource_type, quote_literal(resource_data.0.to_string())
     )).expect("Failed to insert resource");
+
+    // Version 1, as the server records a created Patient
+    let record_history = resource_type == "Patient"
+        && HISTORY_ENABLED.get()
+        && Spi::get_one::<bool>("SELECT to_regclass('fhir.patient_history') IS NOT NULL")
+            .expect("Failed to look up fhir.patient_history")
+            .unwrap_or(false);
+    if record_history {
+        Spi::run(&format!(
+            "INSERT INTO fhir.patient_history (id, version_id, resource, txid, ts, status) \
+            VALUES ('{}', 1, {}, txid_current(), now(), 'created')",
+            id, quote_literal(resource_data.0.to_string())
+        )).expect("Failed to record history");
+    }
     
     id
 }
 
 #[pg_extern]
 fn fhir_get(resource_type: &str, resource_id: Uuid) -> Option<pgrx::JsonB> {
-    if resource_type != "Patient" {
-        error!("Only Patient resources are supported");
-    }
+    ensure_supported(resource_type);
     
     let query = format!(
-        "SELECT resource_data FROM fhir_resources WHERE id = '{}' AND resource_type = '{}'",
+        "SELECT resource_data FROM fhir_resources WHERE id = '{}' AND resource_type = '{}' AND deleted_at IS NULL",
         resource_id, resource_type
     );
     
@@ -47,77 +167,319 @@ fn fhir_get(resource_type: &str, resource_id: Uuid) -> Option<pgrx::JsonB> {
     .expect("SPI query failed")
 }
 
+/// The columns of `fhir_search` for a [`SearchRow`]; their names are
+/// checked against [`SearchRow::COLUMNS`] by the tests
+fn search_columns(row: SearchRow) -> (Uuid, pgrx::JsonB) {
+    (Uuid::from_bytes(*row.id.as_bytes()), pgrx::JsonB(row.resource))
+}
+
+/// The matching resources as [`SearchRow`]s
 #[pg_extern]
 fn fhir_search<'a>(
     resource_type: &'a str,
     param: &'a str,
     op: &'a str,
     value: &'a str,
-) -> SetOfIterator<'a, Uuid> {
-    if resource_type != "Patient" {
-        error!("Only Patient resources are supported");
-    }
+) -> TableIterator<'a, (name!(id, Uuid), name!(resource, pgrx::JsonB))> {
+    ensure_supported(resource_type);
     
     let query = if param.is_empty() {
-        // Return all patients
-        format!("SELECT id FROM fhir_resources WHERE resource_type = '{}'", resource_type)
+        // Return all resources of the type
+        format!("SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL", resource_type)
     } else {
-        match param {
-            "name" => {
+        match (resource_type, param) {
+            ("Patient", "name") => {
                 if op == "contains" {
+                    // The normalized names the server stores on every write
+                    // (025_normalized_strings.sql), compared as it does
+                    format!(
+                        "SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL AND \
+                        EXISTS(SELECT 1 FROM fhir.resource_string_value s \
+                        WHERE s.resource_id = fhir_resources.id AND s.code = 'name' AND s.value LIKE {})",
+                        resource_type, quote_literal(contains_pattern(&normalize(value)))
+                    )
+                } else {
+                    return TableIterator::empty();
+                }
+            }
+            ("Patient", "gender") => {
+                if op == "eq" {
                     format!(
-                        "SELECT id FROM fhir_resources WHERE resource_type = '{}' AND (\
-                        LOWER(resource_data->'name'->0->>'family') LIKE LOWER('%{}%') OR \
-                        LOWER(resource_data->'name'->0->>'text') LIKE LOWER('%{}%') OR \
-                        EXISTS(SELECT 1 FROM jsonb_array_elements(resource_data->'name'->0->'given') AS given \
-                        WHERE LOWER(given::text) LIKE LOWER('%{}%')))",
-                        resource_type, value, value, value
+                        "SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL AND resource_data->>'gender' = {}",
+                        resource_type, quote_literal(value)
                     )
                 } else {
-                    return SetOfIterator::new(vec![].into_iter());
+                    return TableIterator::empty();
                 }
             }
-            "gender" => {
+            ("Patient", "birthdate") => {
                 if op == "eq" {
                     format!(
-                        "SELECT id FROM fhir_resources WHERE resource_type = '{}' AND resource_data->>'gender' = '{}'",
-                        resource_type, value
+                        "SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL AND resource_data->>'birthDate' = {}",
+                        resource_type, quote_literal(value)
                     )
                 } else {
-                    return SetOfIterator::new(vec![].into_iter());
+                    return TableIterator::empty();
                 }
             }
-            "birthdate" => {
+            ("Observation", "code") => {
                 if op == "eq" {
+                    // system|code or a bare code
+                    let coding = match value.split_once('|') {
+                        Some((system, code)) => serde_json::json!([{ "system": system, "code": code }]),
+                        None => serde_json::json!([{ "code": value }]),
+                    };
                     format!(
-                        "SELECT id FROM fhir_resources WHERE resource_type = '{}' AND resource_data->>'birthDate' = '{}'",
-                        resource_type, value
+                        "SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL AND resource_data->'code'->'coding' @> {}::jsonb",
+                        resource_type, quote_literal(coding.to_string())
                     )
                 } else {
-                    return SetOfIterator::new(vec![].into_iter());
+                    return TableIterator::empty();
                 }
             }
+            ("Observation", "subject") => {
+                if op == "eq" {
+                    format!(
+                        "SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL AND resource_data->'subject'->>'reference' = {}",
+                        resource_type, quote_literal(value)
+                    )
+                } else {
+                    return TableIterator::empty();
+                }
+            }
+            ("Observation", "date") => {
+                let operator = match op {
+                    "eq" => "=",
+                    "ne" => "<>",
+                    "gt" => ">",
+                    "lt" => "<",
+                    "ge" => ">=",
+                    "le" => "<=",
+                    _ => return TableIterator::empty(),
+                };
+                // Compared at the precision of the search value
+                format!(
+                    "SELECT id, resource_data FROM fhir_resources WHERE resource_type = '{}' AND deleted_at IS NULL AND \
+                    left(resource_data->>'effectiveDateTime', {}) {} {}",
+                    resource_type, value.len(), operator, quote_literal(value)
+                )
+            }
             _ => {
-                return SetOfIterator::new(vec![].into_iter());
+                return TableIterator::empty();
             }
         }
     };
     
-    let result_ids = Spi::connect(|client| {
+    let rows = Spi::connect(|client| {
         let result = client.select(&query, None, &[])?;
-        let mut ids = Vec::new();
+        let mut rows = Vec::new();
         
         for row in result {
             let id: Option<Uuid> = row.get(1)?;
-            if let Some(id) = id {
-                ids.push(id);
+            let resource: Option<pgrx::JsonB> = row.get(2)?;
+            if let (Some(id), Some(resource)) = (id, resource) {
+                rows.push(SearchRow {
+                    id: uuid::Uuid::from_bytes(*id.as_bytes()),
+                    resource: resource.0,
+                });
             }
         }
         
-        Ok::<Vec<Uuid>, pgrx::spi::Error>(ids)
+        Ok::<Vec<SearchRow>, pgrx::spi::Error>(rows)
     }).unwrap_or_default();
     
-    SetOfIterator::new(result_ids.into_iter())
+    TableIterator::new(rows.into_iter().map(search_columns))
+}
+
+/// The columns of `fhir_get_history` for a [`HistoryRow`]; their names are
+/// checked against [`HistoryRow::COLUMNS`] by the tests
+fn history_columns(row: HistoryRow) -> (Uuid, i32, String, pgrx::JsonB, Option<String>) {
+    (
+        Uuid::from_bytes(*row.id.as_bytes()),
+        row.version_id,
+        row.ts,
+        pgrx::JsonB(row.resource),
+        row.status,
+    )
+}
+
+/// The versions of the Patient with `resource_id` as [`HistoryRow`]s,
+/// newest first, as `fhir.fhir_get_history` of `028_extension_row_shapes.sql`
+/// and the server's `_history` return them: `since` keeps the versions
+/// recorded at or after it, `at` the one that was current at that instant.
+/// Empty without `fhir.patient_history`.
+#[pg_extern]
+fn fhir_get_history(
+    resource_id: Uuid,
+    since: default!(Option<TimestampWithTimeZone>, NULL),
+    at: default!(Option<TimestampWithTimeZone>, NULL),
+) -> TableIterator<
+    'static,
+    (
+        name!(id, Uuid),
+        name!(version_id, i32),
+        name!(ts, String),
+        name!(resource, pgrx::JsonB),
+        name!(status, Option<String>),
+    ),
+> {
+    let recorded = Spi::get_one::<bool>("SELECT to_regclass('fhir.patient_history') IS NOT NULL")
+        .expect("Failed to look up fhir.patient_history")
+        .unwrap_or(false);
+    if !recorded {
+        return TableIterator::empty();
+    }
+
+    let query = "SELECT v.id, v.version_id, \
+            to_char(v.ts AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'), \
+            v.resource, v.status::text \
+        FROM ( \
+            SELECT ph.id, ph.version_id, ph.ts, ph.resource, ph.status, \
+                   lead(ph.ts) OVER (ORDER BY ph.version_id) AS superseded_at \
+            FROM fhir.patient_history ph \
+            WHERE ph.id = $1 \
+        ) v \
+        WHERE ($2::timestamptz IS NULL OR v.ts >= $2) \
+        AND ($3::timestamptz IS NULL OR (v.ts <= $3 AND (v.superseded_at IS NULL OR v.superseded_at > $3))) \
+        ORDER BY v.version_id DESC";
+    let rows = Spi::connect(|client| {
+        let result = client.select(query, None, &[resource_id.into(), since.into(), at.into()])?;
+        let mut rows = Vec::new();
+
+        for row in result {
+            let (Some(id), Some(version_id), Some(ts), Some(resource)) = (
+                row.get::<Uuid>(1)?,
+                row.get::<i32>(2)?,
+                row.get::<String>(3)?,
+                row.get::<pgrx::JsonB>(4)?,
+            ) else {
+                continue;
+            };
+            rows.push(HistoryRow {
+                id: uuid::Uuid::from_bytes(*id.as_bytes()),
+                version_id,
+                ts,
+                resource: resource.0,
+                status: row.get::<String>(5)?,
+            });
+        }
+
+        Ok::<Vec<HistoryRow>, pgrx::spi::Error>(rows)
+    })
+    .expect("Failed to read fhir.patient_history");
+
+    TableIterator::new(rows.into_iter().map(history_columns))
+}
+
+/// Superseded versions of `fhir.patient_history` recorded before the cutoff,
+/// `now() - $1`, up to `$2` of them
+const PRUNE_HISTORY: &str = "WITH pruned AS (
+    DELETE FROM fhir.patient_history ph
+    WHERE (ph.id, ph.version_id) IN (
+        SELECT superseded.id, superseded.version_id
+        FROM fhir.patient_history superseded
+        WHERE superseded.ts < now() - $1
+        AND EXISTS (
+            SELECT 1 FROM fhir.patient_history newer
+            WHERE newer.id = superseded.id
+            AND newer.version_id > superseded.version_id
+            AND newer.ts < now() - $1
+        )
+        LIMIT $2
+    )
+    RETURNING 1
+)
+SELECT count(*) FROM pruned";
+
+/// Up to `$2` resources deleted before the cutoff, with all their history
+const PURGE_TOMBSTONES: &str = "WITH purged AS (
+    DELETE FROM fhir_resources r
+    WHERE r.id IN (
+        SELECT t.id FROM fhir_resources t
+        WHERE t.deleted_at < now() - $1
+        LIMIT $2
+    )
+    RETURNING r.id
+), purged_history AS (
+    DELETE FROM fhir.patient_history ph
+    USING purged
+    WHERE ph.id = purged.id
+)
+SELECT count(*) FROM purged";
+
+/// The columns of `fhir_maintain` for a [`MaintainRow`]; their names are
+/// checked against [`MaintainRow::COLUMNS`] by the tests
+fn maintain_columns(row: MaintainRow) -> (i64, i64, bool, String) {
+    (row.history_pruned, row.tombstones_purged, row.complete, row.cutoff)
+}
+
+/// Prune one batch of what is older than `retention`: up to `batch_size`
+/// versions of `fhir.patient_history` superseded before the cutoff and,
+/// once those are gone, up to `batch_size` tombstones with their history.
+/// The version current at the cutoff stays. A call is one statement, and so
+/// holds its locks for one batch; callers repeat it, or `CALL
+/// fhir.fhir_maintain_all(...)` (`031_maintenance_batches.sql`), until the
+/// row says `complete`. Purged tombstones skip the change outbox.
+#[pg_extern]
+fn fhir_maintain(
+    retention: Interval,
+    batch_size: default!(i32, 1000),
+) -> TableIterator<
+    'static,
+    (
+        name!(history_pruned, i64),
+        name!(tombstones_purged, i64),
+        name!(complete, bool),
+        name!(cutoff, String),
+    ),
+> {
+    if batch_size < 1 {
+        error!("fhir_maintain: batch size must be at least 1");
+    }
+    let negative = Spi::get_one_with_args::<bool>("SELECT $1 < interval '0'", &[retention.into()])
+        .expect("Failed to check the retention")
+        .unwrap_or(true);
+    if negative {
+        error!("fhir_maintain: retention must be a non-negative interval");
+    }
+    let cutoff = Spi::get_one_with_args::<String>(
+        "SELECT to_char((now() - $1) AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')",
+        &[retention.into()],
+    )
+    .expect("Failed to compute the cutoff")
+    .unwrap_or_default();
+
+    let history_pruned =
+        Spi::get_one_with_args::<i64>(PRUNE_HISTORY, &[retention.into(), batch_size.into()])
+            .expect("Failed to prune history")
+            .unwrap_or(0);
+    let mut tombstones_purged = 0;
+    if history_pruned < i64::from(batch_size) {
+        // Their deletes were announced when they happened
+        let archiving = Spi::get_one::<String>("SELECT current_setting('fhir.archiving', true)")
+            .expect("Failed to read fhir.archiving")
+            .unwrap_or_default();
+        Spi::run("SELECT set_config('fhir.archiving', 'on', true)")
+            .expect("Failed to set fhir.archiving");
+        tombstones_purged =
+            Spi::get_one_with_args::<i64>(PURGE_TOMBSTONES, &[retention.into(), batch_size.into()])
+                .expect("Failed to purge tombstones")
+                .unwrap_or(0);
+        Spi::run_with_args(
+            "SELECT set_config('fhir.archiving', $1, true)",
+            &[archiving.into()],
+        )
+        .expect("Failed to restore fhir.archiving");
+    }
+
+    let row = MaintainRow {
+        history_pruned,
+        tombstones_purged,
+        complete: history_pruned < i64::from(batch_size)
+            && tombstones_purged < i64::from(batch_size),
+        cutoff,
+    };
+    TableIterator::once(maintain_columns(row))
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -132,6 +494,90 @@ mod tests {
         let result = Spi::get_one::<bool>("SELECT EXISTS(SELECT 1 FROM pg_proc WHERE proname = 'fhir_put')");
         assert!(result.is_ok());
     }
+
+    #[pg_test(error = "Basic of 2050 bytes exceeds fhir.max_resource_kb (1 kB)")]
+    fn test_max_resource_kb_is_set_per_session() {
+        Spi::run("SET fhir.max_resource_kb = 1").expect("SET failed");
+        fhir_put("Basic", pgrx::JsonB(serde_json::Value::String("x".repeat(2048))));
+    }
+
+    #[pg_test(error = "resourceType Some(\"Observation\") does not match Patient (fhir.strict_validation)")]
+    fn test_strict_validation_rejects_other_types() {
+        Spi::run("SET fhir.strict_validation = on").expect("SET failed");
+        fhir_put("Patient", pgrx::JsonB(serde_json::json!({ "resourceType": "Observation" })));
+    }
+
+    #[pg_test]
+    fn test_only_history_enabled_is_superuser_only() {
+        let contexts = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(name || '=' || context ORDER BY name) FROM pg_settings \
+             WHERE name IN ('fhir.history_enabled', 'fhir.max_resource_kb', 'fhir.strict_validation')",
+        )
+        .expect("SPI query failed")
+        .expect("the fhir settings are not registered");
+        assert_eq!(
+            contexts,
+            ["fhir.history_enabled=superuser", "fhir.max_resource_kb=user", "fhir.strict_validation=user"]
+        );
+    }
+
+    #[pg_test]
+    fn test_fhir_search_returns_search_rows() {
+        // The result columns the server decodes a SearchRow from
+        let columns = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(name ORDER BY n) FROM pg_proc, \
+             unnest(proargnames, proargmodes) WITH ORDINALITY AS args(name, mode, n) \
+             WHERE proname = 'fhir_search' AND mode = 't'",
+        )
+        .expect("SPI query failed")
+        .expect("fhir_search has no result columns");
+        assert_eq!(columns, SearchRow::COLUMNS);
+    }
+
+    #[pg_test]
+    fn test_fhir_get_history_returns_history_rows() {
+        let columns = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(name ORDER BY n) FROM pg_proc, \
+             unnest(proargnames, proargmodes) WITH ORDINALITY AS args(name, mode, n) \
+             WHERE proname = 'fhir_get_history' AND mode = 't'",
+        )
+        .expect("SPI query failed")
+        .expect("fhir_get_history has no result columns");
+        assert_eq!(columns, HistoryRow::COLUMNS);
+    }
+
+    #[pg_test]
+    fn test_search_values_are_quoted() {
+        Spi::run(
+            "CREATE TABLE IF NOT EXISTS fhir_resources (id UUID PRIMARY KEY, \
+             resource_type VARCHAR, resource_data JSONB, deleted_at TIMESTAMPTZ)",
+        )
+        .expect("Failed to create fhir_resources");
+        fhir_put("Patient", pgrx::JsonB(serde_json::json!({ "gender": "female" })));
+
+        let matches = Spi::get_one::<i64>(
+            "SELECT count(*) FROM fhir_search('Patient', 'gender', 'eq', 'x'' OR ''1''=''1')",
+        )
+        .expect("fhir_search failed");
+        assert_eq!(matches, Some(0));
+    }
+
+    #[pg_test]
+    fn test_fhir_maintain_returns_maintain_rows() {
+        let columns = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(name ORDER BY n) FROM pg_proc, \
+             unnest(proargnames, proargmodes) WITH ORDINALITY AS args(name, mode, n) \
+             WHERE proname = 'fhir_maintain' AND mode = 't'",
+        )
+        .expect("SPI query failed")
+        .expect("fhir_maintain has no result columns");
+        assert_eq!(columns, MaintainRow::COLUMNS);
+    }
+
+    #[pg_test(error = "fhir_maintain: batch size must be at least 1")]
+    fn test_fhir_maintain_needs_a_batch() {
+        Spi::run("SELECT * FROM fhir_maintain(interval '1 day', 0)").expect("fhir_maintain failed");
+    }
 }
 
 #[cfg(test)]
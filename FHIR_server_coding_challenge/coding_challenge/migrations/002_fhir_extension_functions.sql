@@ -57,6 +57,8 @@ $$ LANGUAGE plpgsql;
 
 -- Search FHIR resources by parameter
 -- All search queries go through this function
+-- (028_extension_row_shapes.sql returns the resources as well)
+DROP FUNCTION IF EXISTS fhir.fhir_search(VARCHAR, VARCHAR, VARCHAR, VARCHAR);
 CREATE OR REPLACE FUNCTION fhir.fhir_search(
     p_resource_type VARCHAR,
     p_param VARCHAR,
@@ -94,8 +96,16 @@ END;
 $$ LANGUAGE plpgsql;
 
 -- Get patient history including current version
+-- p_since keeps the versions recorded at or after it (_since), p_at the
+-- version that was current at that instant (_at)
+-- Usage: SELECT * FROM fhir.fhir_get_history('550e8400-e29b-41d4-a716-446655440000'::uuid, p_since => '2024-05-01T00:00:00Z')
+-- (028_extension_row_shapes.sql returns the columns the server reads)
+DROP FUNCTION IF EXISTS fhir.fhir_get_history(UUID);
+DROP FUNCTION IF EXISTS fhir.fhir_get_history(UUID, TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
 CREATE OR REPLACE FUNCTION fhir.fhir_get_history(
-    p_patient_id UUID
+    p_patient_id UUID,
+    p_since TIMESTAMP WITH TIME ZONE DEFAULT NULL,
+    p_at TIMESTAMP WITH TIME ZONE DEFAULT NULL
 ) RETURNS TABLE(
     version_id INT,
     resource JSONB,
@@ -104,15 +114,21 @@ CREATE OR REPLACE FUNCTION fhir.fhir_get_history(
 ) AS $$
 BEGIN
     RETURN QUERY
-    SELECT 0::INT, p.resource, p.ts, 'PUT'::VARCHAR
-    FROM fhir.patient p
-    WHERE p.id = p_patient_id
-    AND p.status = 'created'
-    UNION ALL
-    SELECT ph.version_id, ph.resource, ph.ts, 'PUT'::VARCHAR
-    FROM fhir.patient_history ph
-    WHERE ph.id = p_patient_id
-    ORDER BY version_id DESC;
+    SELECT v.version_id, v.resource, v.ts, 'PUT'::VARCHAR
+    FROM (
+        SELECT 0::INT AS version_id, p.resource, p.ts
+        FROM fhir.patient p
+        WHERE p.id = p_patient_id
+        AND p.status = 'created'
+        UNION ALL
+        SELECT ph.version_id, ph.resource, ph.ts
+        FROM fhir.patient_history ph
+        WHERE ph.id = p_patient_id
+    ) v
+    WHERE (p_since IS NULL OR v.ts >= p_since)
+    AND (p_at IS NULL OR v.ts <= p_at)
+    ORDER BY v.ts DESC, v.version_id DESC
+    LIMIT CASE WHEN p_at IS NULL THEN NULL ELSE 1 END;
 END;
 $$ LANGUAGE plpgsql;
 
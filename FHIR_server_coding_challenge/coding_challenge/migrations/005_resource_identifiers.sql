@@ -0,0 +1,21 @@
+-- Migration: Resource identifier index
+-- Description: Mirror resource identifiers for lookup and enforce uniqueness
+-- for the identifier systems listed in the server's unique_identifier_systems
+
+CREATE TABLE IF NOT EXISTS fhir.resource_identifier (
+    resource_id UUID NOT NULL REFERENCES fhir_resources(id) ON DELETE CASCADE,
+    resource_type VARCHAR(50) NOT NULL,
+    system TEXT NOT NULL,
+    value TEXT NOT NULL,
+    enforce_unique BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (resource_id, system, value)
+);
+
+-- Only identifiers in a unique system take part in the constraint, so the
+-- same value may still appear under systems without a uniqueness policy
+CREATE UNIQUE INDEX IF NOT EXISTS idx_resource_identifier_unique
+    ON fhir.resource_identifier (resource_type, system, value)
+    WHERE enforce_unique;
+
+CREATE INDEX IF NOT EXISTS idx_resource_identifier_lookup
+    ON fhir.resource_identifier (system, value);
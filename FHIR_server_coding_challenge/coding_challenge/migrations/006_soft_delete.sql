@@ -0,0 +1,8 @@
+-- Migration: Soft delete
+-- Description: Deleted resources keep their row so reads can answer 410 Gone
+-- and history stays complete; Patient reads and searches skip rows with deleted_at set
+
+ALTER TABLE fhir_resources ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_fhir_resources_live
+    ON fhir_resources (resource_type, id) WHERE deleted_at IS NULL;
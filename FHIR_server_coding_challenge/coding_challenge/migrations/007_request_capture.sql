@@ -0,0 +1,22 @@
+-- Migration: Request capture
+-- Description: Ring buffer of sanitized request/response pairs for failed
+-- interactions, written by the server while [request_capture] is enabled.
+-- The slot is nextval(seq) % capacity, so the oldest capture is overwritten.
+
+CREATE SCHEMA IF NOT EXISTS fhir;
+
+CREATE SEQUENCE IF NOT EXISTS fhir.request_capture_seq;
+
+CREATE TABLE IF NOT EXISTS fhir.request_capture (
+    slot INTEGER PRIMARY KEY,
+    request_id UUID NOT NULL UNIQUE,
+    captured_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    method VARCHAR(10) NOT NULL,
+    uri TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    request_headers JSONB NOT NULL,
+    request_body JSONB,
+    response_headers JSONB NOT NULL,
+    response_body JSONB
+);
@@ -0,0 +1,6 @@
+-- Migration: Content hash
+-- Description: SHA-256 of the canonical JSON of the current version; a PUT
+-- whose canonical form hashes the same is a no-op and keeps the version.
+-- Rows written before this migration get a hash on their next write.
+
+ALTER TABLE fhir_resources ADD COLUMN IF NOT EXISTS content_hash TEXT;
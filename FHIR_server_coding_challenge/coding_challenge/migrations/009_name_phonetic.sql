@@ -0,0 +1,15 @@
+-- Migration: Phonetic name index
+-- Description: Double Metaphone and Cologne phonetics codes of every word of
+-- a patient's names, for name:phonetic searches. The codes are computed by
+-- the server; patients written before this migration are indexed at startup.
+
+CREATE TABLE IF NOT EXISTS fhir.resource_name_phonetic (
+    resource_id UUID NOT NULL REFERENCES fhir_resources(id) ON DELETE CASCADE,
+    resource_type VARCHAR(50) NOT NULL,
+    algorithm TEXT NOT NULL,
+    code TEXT NOT NULL,
+    PRIMARY KEY (resource_id, algorithm, code)
+);
+
+CREATE INDEX IF NOT EXISTS idx_resource_name_phonetic_lookup
+    ON fhir.resource_name_phonetic (resource_type, algorithm, code);
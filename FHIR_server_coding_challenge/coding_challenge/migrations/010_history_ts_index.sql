@@ -0,0 +1,6 @@
+-- Migration: History timestamp index
+-- Description: Type- and system-level _history list the versions of all
+-- patients newest first; the index serves that order without a full sort.
+
+CREATE INDEX IF NOT EXISTS idx_patient_history_ts
+    ON fhir.patient_history (ts DESC, id, version_id DESC);
@@ -0,0 +1,55 @@
+-- Migration: Change outbox
+-- Description: One row per write to fhir_resources, recorded by a trigger
+-- in the writing transaction, for the GET /fhir/_changes cursor API. Rows
+-- are read in (txid, seq) order and only once their transaction is older
+-- than every running one, so a cursor never skips a write that commits late.
+
+CREATE TABLE IF NOT EXISTS fhir.change_outbox (
+    seq BIGSERIAL PRIMARY KEY,
+    txid BIGINT NOT NULL DEFAULT txid_current(),
+    resource_type VARCHAR(50) NOT NULL,
+    resource_id UUID NOT NULL,
+    version_id INTEGER NOT NULL,
+    op TEXT NOT NULL CHECK (op IN ('create', 'update', 'delete')),
+    ts TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_change_outbox_cursor
+    ON fhir.change_outbox (txid, seq);
+
+CREATE OR REPLACE FUNCTION fhir.record_change()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO fhir.change_outbox (resource_type, resource_id, version_id, op)
+        VALUES (OLD.resource_type, OLD.id, COALESCE(OLD.version_id, 1), 'delete');
+        RETURN OLD;
+    END IF;
+
+    -- Updates that change neither the version nor the deletion are not changes
+    IF TG_OP = 'UPDATE'
+       AND OLD.version_id IS NOT DISTINCT FROM NEW.version_id
+       AND OLD.deleted_at IS NOT DISTINCT FROM NEW.deleted_at THEN
+        RETURN NEW;
+    END IF;
+
+    INSERT INTO fhir.change_outbox (resource_type, resource_id, version_id, op)
+    VALUES (
+        NEW.resource_type,
+        NEW.id,
+        COALESCE(NEW.version_id, 1),
+        CASE
+            WHEN TG_OP = 'INSERT' THEN 'create'
+            WHEN NEW.deleted_at IS NOT NULL AND OLD.deleted_at IS NULL THEN 'delete'
+            WHEN NEW.deleted_at IS NULL AND OLD.deleted_at IS NOT NULL THEN 'create'
+            ELSE 'update'
+        END
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS fhir_resources_change_outbox ON fhir_resources;
+CREATE TRIGGER fhir_resources_change_outbox
+    AFTER INSERT OR UPDATE OR DELETE ON fhir_resources
+    FOR EACH ROW EXECUTE FUNCTION fhir.record_change();
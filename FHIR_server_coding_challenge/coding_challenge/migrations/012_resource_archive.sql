@@ -0,0 +1,62 @@
+-- Migration: Resource archive
+-- Description: Cold storage for live resources that have not been written
+-- for the configured number of years. The server moves them here in the
+-- background, reads them through by id and moves them back before any
+-- write. Moves in either direction are not changes and skip the outbox.
+
+CREATE TABLE IF NOT EXISTS fhir.resource_archive (
+    id UUID PRIMARY KEY,
+    resource_type VARCHAR(50) NOT NULL,
+    resource_data JSONB NOT NULL,
+    version_id INTEGER DEFAULT 1,
+    last_updated TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE,
+    deleted_at TIMESTAMP WITH TIME ZONE,
+    content_hash TEXT,
+    archived_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_resource_archive_type
+    ON fhir.resource_archive (resource_type);
+
+-- Finds the archiving candidates without scanning the hot table
+CREATE INDEX IF NOT EXISTS idx_fhir_resources_last_updated
+    ON fhir_resources (last_updated) WHERE deleted_at IS NULL;
+
+-- As in 011_change_outbox.sql, except while the server sets
+-- fhir.archiving for a move between the tiers
+CREATE OR REPLACE FUNCTION fhir.record_change()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF current_setting('fhir.archiving', true) = 'on' THEN
+        RETURN COALESCE(NEW, OLD);
+    END IF;
+
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO fhir.change_outbox (resource_type, resource_id, version_id, op)
+        VALUES (OLD.resource_type, OLD.id, COALESCE(OLD.version_id, 1), 'delete');
+        RETURN OLD;
+    END IF;
+
+    -- Updates that change neither the version nor the deletion are not changes
+    IF TG_OP = 'UPDATE'
+       AND OLD.version_id IS NOT DISTINCT FROM NEW.version_id
+       AND OLD.deleted_at IS NOT DISTINCT FROM NEW.deleted_at THEN
+        RETURN NEW;
+    END IF;
+
+    INSERT INTO fhir.change_outbox (resource_type, resource_id, version_id, op)
+    VALUES (
+        NEW.resource_type,
+        NEW.id,
+        COALESCE(NEW.version_id, 1),
+        CASE
+            WHEN TG_OP = 'INSERT' THEN 'create'
+            WHEN NEW.deleted_at IS NOT NULL AND OLD.deleted_at IS NULL THEN 'delete'
+            WHEN NEW.deleted_at IS NULL AND OLD.deleted_at IS NOT NULL THEN 'create'
+            ELSE 'update'
+        END
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,24 @@
+-- Migration: Expand/contract schema changes
+-- Description: Bookkeeping for `fhir-server migrate`. Every server process
+-- records the schema version it was built for and refreshes the row while
+-- it runs, so a contract step can tell whether versions that still use the
+-- old column might be serving. Each column change remembers its phase.
+
+CREATE TABLE IF NOT EXISTS fhir.app_instance (
+    instance_id UUID PRIMARY KEY,
+    schema_version INTEGER NOT NULL,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS fhir.schema_change (
+    name TEXT PRIMARY KEY,
+    table_name TEXT NOT NULL,
+    old_column TEXT NOT NULL,
+    new_column TEXT NOT NULL,
+    phase TEXT NOT NULL CHECK (phase IN ('expanded', 'switched', 'contracted')),
+    -- Instances built for an older schema version still use old_column
+    contract_version INTEGER NOT NULL,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,14 @@
+-- Migration: Canonical URLs of conformance resources
+-- Description: StructureDefinitions, ValueSets and SearchParameters are
+-- looked up by canonical URL when validating references and by `$resolve`.
+-- A url and version pair names one resource of each type; resources
+-- without a url are not indexed.
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_fhir_resources_canonical
+    ON fhir_resources (
+        resource_type,
+        (resource_data->>'url'),
+        (COALESCE(resource_data->>'version', ''))
+    )
+    WHERE resource_type IN ('StructureDefinition', 'ValueSet', 'SearchParameter')
+      AND resource_data ? 'url';
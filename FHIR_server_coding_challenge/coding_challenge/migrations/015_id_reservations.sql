@@ -0,0 +1,16 @@
+-- Migration: Reserved resource ids
+-- Description: Ids handed out by `POST /fhir/Patient/$reserve-id`. Offline
+-- clients link records to the id before they can reach the server and
+-- create the resource later with a PUT to it, which consumes the row.
+-- Rows past `expires_at` no longer allow the create and are removed as new
+-- ids are reserved.
+
+CREATE TABLE IF NOT EXISTS fhir.id_reservation (
+    id UUID PRIMARY KEY,
+    resource_type VARCHAR(50) NOT NULL,
+    reserved_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_id_reservation_expires
+    ON fhir.id_reservation (expires_at);
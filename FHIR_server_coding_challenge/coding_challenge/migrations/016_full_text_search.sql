@@ -0,0 +1,26 @@
+-- Migration: Full-text search
+-- Description: Generated tsvector columns over each resource for the _content
+-- (every string in the resource) and _text (the narrative, without its XHTML
+-- markup) search parameters, with GIN indexes. The 'simple' configuration is
+-- used, so words are lowercased but not stemmed or dropped as stop words.
+-- Adding a stored generated column rewrites fhir_resources; run this in a
+-- maintenance window on large databases.
+
+ALTER TABLE fhir_resources
+    ADD COLUMN IF NOT EXISTS content_tsv tsvector
+    GENERATED ALWAYS AS (to_tsvector('simple', resource_data)) STORED;
+
+ALTER TABLE fhir_resources
+    ADD COLUMN IF NOT EXISTS narrative_tsv tsvector
+    GENERATED ALWAYS AS (
+        to_tsvector(
+            'simple',
+            regexp_replace(coalesce(resource_data #>> '{text,div}', ''), '<[^>]*>', ' ', 'g')
+        )
+    ) STORED;
+
+CREATE INDEX IF NOT EXISTS idx_fhir_resources_content_tsv
+    ON fhir_resources USING GIN (content_tsv);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_resources_narrative_tsv
+    ON fhir_resources USING GIN (narrative_tsv);
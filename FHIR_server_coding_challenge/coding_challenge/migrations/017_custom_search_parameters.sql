@@ -0,0 +1,32 @@
+-- Migration: Custom search parameters
+-- Description: Search parameters added at runtime through
+-- `POST /admin/search-parameters`. Each row of fhir.search_parameter_path is
+-- one base of a stored SearchParameter: the SQL/JSON path its FHIRPath
+-- expression was translated to. fhir.resource_search_value holds the values
+-- the server extracted along that path from every live resource of the base
+-- (token codes with their system, lowercased strings, dates as written); it
+-- is filled for existing resources when the parameter is added and kept up
+-- to date on every write. Both go with the SearchParameter when it is
+-- deleted.
+
+CREATE TABLE IF NOT EXISTS fhir.search_parameter_path (
+    search_parameter_id UUID NOT NULL REFERENCES fhir_resources(id) ON DELETE CASCADE,
+    resource_type VARCHAR(50) NOT NULL,
+    code TEXT NOT NULL,
+    param_type TEXT NOT NULL,
+    path TEXT NOT NULL,
+    PRIMARY KEY (resource_type, code)
+);
+
+CREATE TABLE IF NOT EXISTS fhir.resource_search_value (
+    resource_id UUID NOT NULL REFERENCES fhir_resources(id) ON DELETE CASCADE,
+    search_parameter_id UUID NOT NULL REFERENCES fhir_resources(id) ON DELETE CASCADE,
+    system TEXT,
+    value TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_resource_search_value_lookup
+    ON fhir.resource_search_value (search_parameter_id, value);
+
+CREATE INDEX IF NOT EXISTS idx_resource_search_value_resource
+    ON fhir.resource_search_value (resource_id);
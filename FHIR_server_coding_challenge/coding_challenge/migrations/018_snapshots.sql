@@ -0,0 +1,23 @@
+-- Migration: Dataset snapshots
+-- Description: Named copies of the whole dataset taken through
+-- `POST /admin/snapshots/:name`, for resetting test environments with
+-- `POST /admin/snapshots/:name/restore`. Every row of the server's data
+-- tables (resources, archive, patient history, identifier, phonetic and
+-- custom search parameter index entries, id reservations) is kept as JSON in
+-- fhir.snapshot_row, tagged with its table, and goes with the snapshot when
+-- it is deleted.
+
+CREATE TABLE IF NOT EXISTS fhir.snapshot (
+    name TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resources BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS fhir.snapshot_row (
+    snapshot TEXT NOT NULL REFERENCES fhir.snapshot(name) ON DELETE CASCADE,
+    source TEXT NOT NULL,
+    row_data JSONB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_snapshot_row_source
+    ON fhir.snapshot_row (snapshot, source);
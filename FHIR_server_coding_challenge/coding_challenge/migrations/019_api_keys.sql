@@ -0,0 +1,14 @@
+-- Migration: Admin API keys
+-- Description: Keys that authorize the /admin endpoints, sent as
+-- `Authorization: Bearer <key>`. Only the SHA-256 of a key is stored; the
+-- key itself is shown once, when `fhir-server --bootstrap` creates it. While
+-- the table is empty the /admin endpoints stay open, as before, so that
+-- existing deployments restricting them at the proxy keep working. Deleting
+-- a row revokes its key.
+
+CREATE TABLE IF NOT EXISTS fhir.api_key (
+    name TEXT PRIMARY KEY,
+    key_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
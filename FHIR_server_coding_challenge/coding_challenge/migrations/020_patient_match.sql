@@ -0,0 +1,14 @@
+-- Migration: Patient matching
+-- Description: Trigram similarity (pg_trgm) of patient names for
+-- `POST /fhir/Patient/$match`. The index covers the lower-cased first family
+-- and given name of live patients, the text `$match` compares, so candidates
+-- with a similar name are found without scanning every patient. Without the
+-- extension `$match` is disabled.
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS idx_patient_match_name
+    ON fhir_resources
+    USING gin ((lower(coalesce(resource_data #>> '{name,0,family}', '') || ' ' ||
+                      coalesce(resource_data #>> '{name,0,given,0}', ''))) gin_trgm_ops)
+    WHERE resource_type = 'Patient' AND deleted_at IS NULL;
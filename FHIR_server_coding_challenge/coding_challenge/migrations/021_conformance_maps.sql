@@ -0,0 +1,15 @@
+-- Migration: ConceptMaps and StructureMaps by canonical URL
+-- Description: ConceptMaps and StructureMaps are conformance resources too,
+-- so `$transform` and the `translate` rules of a StructureMap can refer to
+-- them by canonical URL. The canonical index of 014_conformance_canonical.sql
+-- is rebuilt to cover them.
+
+DROP INDEX IF EXISTS idx_fhir_resources_canonical;
+CREATE UNIQUE INDEX idx_fhir_resources_canonical
+    ON fhir_resources (
+        resource_type,
+        (resource_data->>'url'),
+        (COALESCE(resource_data->>'version', ''))
+    )
+    WHERE resource_type IN ('StructureDefinition', 'ValueSet', 'SearchParameter', 'ConceptMap', 'StructureMap')
+      AND resource_data ? 'url';
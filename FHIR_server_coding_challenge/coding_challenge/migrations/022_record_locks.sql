@@ -0,0 +1,16 @@
+-- Migration: Record locks
+-- Description: Locks taken with `POST /fhir/Patient/:id/$lock` for a chart
+-- review. While a lock is live, writes to the patient must send its token in
+-- `X-Lock-Token` and are otherwise refused with 423. Only the SHA-256 of the
+-- token is stored. Rows past `expires_at` no longer lock anything and are
+-- replaced by the next lock of the record.
+
+CREATE TABLE IF NOT EXISTS fhir.record_lock (
+    resource_type VARCHAR(50) NOT NULL,
+    id UUID NOT NULL,
+    token_hash TEXT NOT NULL,
+    owner TEXT,
+    locked_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (resource_type, id)
+);
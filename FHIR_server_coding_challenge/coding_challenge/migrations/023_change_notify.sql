@@ -0,0 +1,27 @@
+-- Migration: Change notifications
+-- Description: NOTIFY on the fhir_changes channel for every row added to
+-- fhir.change_outbox, for the GET /fhir/_events stream. Notifications are
+-- delivered when the writing transaction commits, and not at all if it rolls
+-- back. The payload is the outbox row as JSON; seq keeps the payloads of one
+-- transaction distinct, as identical ones would be folded into one.
+
+CREATE OR REPLACE FUNCTION fhir.notify_change()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('fhir_changes', json_build_object(
+        'txid', NEW.txid,
+        'seq', NEW.seq,
+        'resource_type', NEW.resource_type,
+        'id', NEW.resource_id,
+        'version_id', NEW.version_id,
+        'op', NEW.op,
+        'ts', NEW.ts
+    )::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS change_outbox_notify ON fhir.change_outbox;
+CREATE TRIGGER change_outbox_notify
+    AFTER INSERT ON fhir.change_outbox
+    FOR EACH ROW EXECUTE FUNCTION fhir.notify_change();
@@ -0,0 +1,9 @@
+-- Migration: Provenance target index
+-- Description: Index of the target reference of the Provenance the server
+-- records for every write (`Patient/<id>/_history/<version>`), for
+-- `GET /fhir/Provenance?target=`. The "C" collation makes the prefix range
+-- of an unversioned target (every version of a resource) an index scan.
+
+CREATE INDEX IF NOT EXISTS idx_fhir_resources_provenance_target
+    ON fhir_resources (((resource_data->'target'->0->>'reference') COLLATE "C"))
+    WHERE resource_type = 'Provenance';
@@ -0,0 +1,30 @@
+-- Migration: Normalized string search values
+-- Description: Case-folded, unaccented copies of the string search values of
+-- a resource, for string searches that ignore case and accents: the first
+-- family and the first given name of patients (`name`). The values are
+-- normalized by the server (`fhir_models::search::normalize`) when a
+-- resource is written, and searches normalize their input the same way, so
+-- they compare plain text instead of lowercasing every candidate. Patients
+-- written before this migration are indexed at startup. With pg_trgm
+-- (020_patient_match.sql) the substring matches of `name` use a trigram
+-- index.
+
+CREATE TABLE IF NOT EXISTS fhir.resource_string_value (
+    resource_id UUID NOT NULL REFERENCES fhir_resources(id) ON DELETE CASCADE,
+    resource_type VARCHAR(50) NOT NULL,
+    code TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (resource_id, code, value)
+);
+
+CREATE INDEX IF NOT EXISTS idx_resource_string_value_prefix
+    ON fhir.resource_string_value (resource_type, code, value text_pattern_ops);
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm') THEN
+        CREATE INDEX IF NOT EXISTS idx_resource_string_value_trigram
+            ON fhir.resource_string_value USING gin (value gin_trgm_ops);
+    END IF;
+END
+$$;
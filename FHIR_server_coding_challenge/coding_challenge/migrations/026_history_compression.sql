@@ -0,0 +1,27 @@
+-- Migration: History compression
+-- Description: Compress the Patient history, which outgrows the current
+-- resources, with LZ4 where PostgreSQL supports it (14 or later, built with
+-- lz4) and with pglz otherwise. A lower toast_tuple_target makes PostgreSQL
+-- compress versions of a few hundred bytes rather than only those over 2 kB.
+-- Current resources in fhir_resources keep the default storage. Values are
+-- decompressed as they are read, so queries are unchanged. Versions written
+-- before this migration keep their storage until
+-- `fhir-server compress-history` rewrites them.
+
+ALTER TABLE fhir.patient_history SET (toast_tuple_target = 128);
+
+DO $$
+BEGIN
+    IF current_setting('server_version_num')::int >= 140000 THEN
+        BEGIN
+            ALTER TABLE fhir.patient_history ALTER COLUMN resource SET COMPRESSION lz4;
+        EXCEPTION WHEN feature_not_supported THEN
+            RAISE NOTICE 'PostgreSQL is built without lz4; fhir.patient_history is compressed with pglz';
+            ALTER TABLE fhir.patient_history ALTER COLUMN resource SET COMPRESSION pglz;
+        END;
+    ELSE
+        RAISE NOTICE 'PostgreSQL % has no column compression setting; fhir.patient_history keeps pglz',
+            current_setting('server_version');
+    END IF;
+END
+$$;
@@ -0,0 +1,12 @@
+-- Migration: API key scopes and rate limits
+-- Description: Restrict what an API key may do. `scopes` lists SMART-style
+-- resource scopes (`system/Patient.read`, `system/*.write`) and `admin` for
+-- the /admin endpoints, separated by spaces as in an OAuth scope; keys without scopes, such as the one created by
+-- `fhir-server --bootstrap`, keep full access. `requests_per_minute` caps
+-- the requests made with a key, which are not limited without it. Keys are
+-- managed at /admin/api-keys.
+
+ALTER TABLE fhir.api_key ADD COLUMN IF NOT EXISTS scopes TEXT;
+
+ALTER TABLE fhir.api_key ADD COLUMN IF NOT EXISTS requests_per_minute INTEGER
+    CHECK (requests_per_minute > 0);
@@ -0,0 +1,75 @@
+-- Migration: Row shapes of the extension functions
+-- Description: Return from the SQL fallback functions the same rows as the
+-- pgrx extension, as declared by the fhir-schema crate (SearchRow and
+-- HistoryRow). fhir_search returns each match with its resource, and
+-- fhir_get_history the versions recorded in fhir.patient_history with their
+-- status, newest first, `ts` formatted as the server formats it. A return
+-- type cannot be replaced, so the functions are dropped first.
+
+DROP FUNCTION IF EXISTS fhir.fhir_search(VARCHAR, VARCHAR, VARCHAR, VARCHAR);
+CREATE FUNCTION fhir.fhir_search(
+    p_resource_type VARCHAR,
+    p_param VARCHAR,
+    p_op VARCHAR,
+    p_value VARCHAR
+) RETURNS TABLE(id UUID, resource JSONB) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT p.id, p.resource
+    FROM fhir.patient p
+    WHERE p.resource_type = p_resource_type
+    AND p.status = 'created'
+    AND (
+        (p_param = 'name' AND p_op = 'contains' AND (
+            (p.resource #>> '{name,0,family}' IS NOT NULL AND p.resource #>> '{name,0,family}' ILIKE '%' || p_value || '%')
+            OR (p.resource #>> '{name,0,given,0}' IS NOT NULL AND p.resource #>> '{name,0,given,0}' ILIKE '%' || p_value || '%')
+            OR EXISTS (
+                SELECT 1 FROM jsonb_array_elements(p.resource->'name') AS name_elem
+                WHERE (name_elem->>'family' IS NOT NULL AND name_elem->>'family' ILIKE '%' || p_value || '%')
+                OR (name_elem->>'text' IS NOT NULL AND name_elem->>'text' ILIKE '%' || p_value || '%')
+                OR EXISTS (
+                    SELECT 1 FROM jsonb_array_elements_text(name_elem->'given') AS given_elem
+                    WHERE given_elem ILIKE '%' || p_value || '%'
+                )
+            )
+        ))
+        OR (p_param = 'gender' AND p_op = 'exact' AND p.resource->>'gender' IS NOT NULL AND p.resource->>'gender' = p_value)
+        OR (p_param = 'birthDate' AND p_op = 'eq' AND p.resource->>'birthDate' IS NOT NULL AND p.resource->>'birthDate' = p_value)
+        OR (p_param = 'birthDate' AND p_op = 'ge' AND p.resource->>'birthDate' IS NOT NULL AND p.resource->>'birthDate' >= p_value)
+        OR (p_param = 'birthDate' AND p_op = 'le' AND p.resource->>'birthDate' IS NOT NULL AND p.resource->>'birthDate' <= p_value)
+        OR (p_param = 'active' AND p_op = 'exact' AND p.resource->>'active' IS NOT NULL AND p.resource->>'active' = p_value)
+    )
+    ORDER BY p.id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- p_since keeps the versions recorded at or after it (_since), p_at the
+-- version that was current at that instant (_at)
+DROP FUNCTION IF EXISTS fhir.fhir_get_history(UUID, TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+CREATE FUNCTION fhir.fhir_get_history(
+    p_patient_id UUID,
+    p_since TIMESTAMP WITH TIME ZONE DEFAULT NULL,
+    p_at TIMESTAMP WITH TIME ZONE DEFAULT NULL
+) RETURNS TABLE(
+    id UUID,
+    version_id INT,
+    ts TEXT,
+    resource JSONB,
+    status VARCHAR
+) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT v.id, v.version_id,
+           to_char(v.ts AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"'),
+           v.resource, v.status
+    FROM (
+        SELECT ph.id, ph.version_id, ph.ts, ph.resource, ph.status,
+               lead(ph.ts) OVER (ORDER BY ph.version_id) AS superseded_at
+        FROM fhir.patient_history ph
+        WHERE ph.id = p_patient_id
+    ) v
+    WHERE (p_since IS NULL OR v.ts >= p_since)
+    AND (p_at IS NULL OR (v.ts <= p_at AND (v.superseded_at IS NULL OR v.superseded_at > p_at)))
+    ORDER BY v.version_id DESC;
+END;
+$$ LANGUAGE plpgsql;
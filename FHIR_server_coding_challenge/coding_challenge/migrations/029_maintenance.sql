@@ -0,0 +1,108 @@
+-- Migration: Maintenance
+-- Description: fhir.fhir_maintain(retention) prunes what is older than the
+-- retention window, for pg_cron or an operator to call periodically:
+--   * versions in fhir.patient_history superseded before the cutoff (the
+--     version current at the cutoff stays, so _at within the window and the
+--     latest version of every resource still resolve)
+--   * tombstones, rows of fhir_resources deleted before the cutoff, with
+--     all their history
+-- Rows go p_batch_size at a time, at most p_max_batches batches per call
+-- (NULL for no limit), so one call holds its locks for a bounded time; a
+-- call that stopped at the limit returns complete = false and the next one
+-- goes on. Purged tombstones were announced when they were deleted and skip
+-- the change outbox. The summary row is fhir_schema::MaintainRow.
+
+-- Finds the expired tombstones without scanning the live rows
+CREATE INDEX IF NOT EXISTS idx_fhir_resources_deleted_at
+    ON fhir_resources (deleted_at) WHERE deleted_at IS NOT NULL;
+
+DROP FUNCTION IF EXISTS fhir.fhir_maintain(INTERVAL, INT, INT);
+CREATE FUNCTION fhir.fhir_maintain(
+    p_retention INTERVAL,
+    p_batch_size INT DEFAULT 1000,
+    p_max_batches INT DEFAULT NULL
+) RETURNS TABLE(
+    history_pruned BIGINT,
+    tombstones_purged BIGINT,
+    batches INT,
+    complete BOOLEAN,
+    cutoff TEXT
+) AS $$
+DECLARE
+    v_cutoff TIMESTAMP WITH TIME ZONE;
+    v_archiving TEXT := current_setting('fhir.archiving', true);
+    v_deleted BIGINT;
+    v_history_done BOOLEAN := false;
+    v_tombstones_done BOOLEAN := false;
+BEGIN
+    IF p_retention IS NULL OR p_retention < INTERVAL '0' THEN
+        RAISE EXCEPTION 'fhir_maintain: retention must be a non-negative interval';
+    END IF;
+    IF p_batch_size IS NULL OR p_batch_size < 1 THEN
+        RAISE EXCEPTION 'fhir_maintain: batch size must be at least 1';
+    END IF;
+
+    v_cutoff := NOW() - p_retention;
+    history_pruned := 0;
+    tombstones_purged := 0;
+    batches := 0;
+    cutoff := to_char(v_cutoff AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"');
+
+    LOOP
+        EXIT WHEN p_max_batches IS NOT NULL AND batches >= p_max_batches;
+        DELETE FROM fhir.patient_history ph
+        WHERE (ph.id, ph.version_id) IN (
+            SELECT superseded.id, superseded.version_id
+            FROM fhir.patient_history superseded
+            WHERE superseded.ts < v_cutoff
+            AND EXISTS (
+                SELECT 1 FROM fhir.patient_history newer
+                WHERE newer.id = superseded.id
+                AND newer.version_id > superseded.version_id
+                AND newer.ts < v_cutoff
+            )
+            LIMIT p_batch_size
+        );
+        GET DIAGNOSTICS v_deleted = ROW_COUNT;
+        history_pruned := history_pruned + v_deleted;
+        IF v_deleted > 0 THEN
+            batches := batches + 1;
+        END IF;
+        IF v_deleted < p_batch_size THEN
+            v_history_done := true;
+            EXIT;
+        END IF;
+    END LOOP;
+
+    PERFORM set_config('fhir.archiving', 'on', true);
+    LOOP
+        EXIT WHEN p_max_batches IS NOT NULL AND batches >= p_max_batches;
+        WITH purged AS (
+            DELETE FROM fhir_resources r
+            WHERE r.id IN (
+                SELECT t.id FROM fhir_resources t
+                WHERE t.deleted_at < v_cutoff
+                LIMIT p_batch_size
+            )
+            RETURNING r.id
+        ), purged_history AS (
+            DELETE FROM fhir.patient_history ph
+            USING purged
+            WHERE ph.id = purged.id
+        )
+        SELECT count(*) INTO v_deleted FROM purged;
+        tombstones_purged := tombstones_purged + v_deleted;
+        IF v_deleted > 0 THEN
+            batches := batches + 1;
+        END IF;
+        IF v_deleted < p_batch_size THEN
+            v_tombstones_done := true;
+            EXIT;
+        END IF;
+    END LOOP;
+    PERFORM set_config('fhir.archiving', COALESCE(v_archiving, ''), true);
+
+    complete := v_history_done AND v_tombstones_done;
+    RETURN NEXT;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,25 @@
+-- Migration: Consent instants
+-- Description: fhir.fhir_instant(value) reads a FHIR date or dateTime as a
+-- timestamp, for the server's consent checks to compare provision.period
+-- with: a year or a month is its first day, a date its midnight UTC, and
+-- anything malformed NULL rather than a cast error, which would fail every
+-- search the check is part of. A Consent whose period cannot be read does
+-- not withhold.
+
+CREATE OR REPLACE FUNCTION fhir.fhir_instant(p_value TEXT)
+RETURNS TIMESTAMP WITH TIME ZONE AS $$
+BEGIN
+    IF p_value ~ '^\d{4}$' THEN
+        RETURN (p_value || '-01-01T00:00:00Z')::timestamptz;
+    ELSIF p_value ~ '^\d{4}-\d{2}$' THEN
+        RETURN (p_value || '-01T00:00:00Z')::timestamptz;
+    ELSIF p_value ~ '^\d{4}-\d{2}-\d{2}$' THEN
+        RETURN (p_value || 'T00:00:00Z')::timestamptz;
+    ELSIF p_value ~ '^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:\d{2})$' THEN
+        RETURN p_value::timestamptz;
+    END IF;
+    RETURN NULL;
+EXCEPTION WHEN datetime_field_overflow OR invalid_datetime_format THEN
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
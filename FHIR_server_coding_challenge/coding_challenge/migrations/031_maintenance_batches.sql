@@ -0,0 +1,105 @@
+-- Migration: Maintenance in batches
+-- Description: fhir.fhir_maintain(retention, batch_size) of
+-- 029_maintenance.sql ran every batch in one call, and so in one
+-- transaction holding the locks of all of them. It now prunes one batch per
+-- call, as the extension's fhir_maintain does: up to p_batch_size versions
+-- of fhir.patient_history superseded before the cutoff and, once those are
+-- gone, up to p_batch_size tombstones with their history. complete is true
+-- when nothing older than the cutoff is left; until then the caller calls
+-- again, each call a transaction of its own. fhir.fhir_maintain_all is
+-- that loop for pg_cron: a procedure committing after every batch, up to
+-- p_max_batches batches (NULL for no limit), calling the extension's
+-- function when it is installed. The summary row is fhir_schema::MaintainRow.
+
+DROP FUNCTION IF EXISTS fhir.fhir_maintain(INTERVAL, INT, INT);
+CREATE FUNCTION fhir.fhir_maintain(
+    p_retention INTERVAL,
+    p_batch_size INT DEFAULT 1000
+) RETURNS TABLE(
+    history_pruned BIGINT,
+    tombstones_purged BIGINT,
+    complete BOOLEAN,
+    cutoff TEXT
+) AS $$
+DECLARE
+    v_cutoff TIMESTAMP WITH TIME ZONE;
+    v_archiving TEXT := current_setting('fhir.archiving', true);
+BEGIN
+    IF p_retention IS NULL OR p_retention < INTERVAL '0' THEN
+        RAISE EXCEPTION 'fhir_maintain: retention must be a non-negative interval';
+    END IF;
+    IF p_batch_size IS NULL OR p_batch_size < 1 THEN
+        RAISE EXCEPTION 'fhir_maintain: batch size must be at least 1';
+    END IF;
+
+    v_cutoff := NOW() - p_retention;
+    tombstones_purged := 0;
+    cutoff := to_char(v_cutoff AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"');
+
+    DELETE FROM fhir.patient_history ph
+    WHERE (ph.id, ph.version_id) IN (
+        SELECT superseded.id, superseded.version_id
+        FROM fhir.patient_history superseded
+        WHERE superseded.ts < v_cutoff
+        AND EXISTS (
+            SELECT 1 FROM fhir.patient_history newer
+            WHERE newer.id = superseded.id
+            AND newer.version_id > superseded.version_id
+            AND newer.ts < v_cutoff
+        )
+        LIMIT p_batch_size
+    );
+    GET DIAGNOSTICS history_pruned = ROW_COUNT;
+
+    IF history_pruned < p_batch_size THEN
+        PERFORM set_config('fhir.archiving', 'on', true);
+        WITH purged AS (
+            DELETE FROM fhir_resources r
+            WHERE r.id IN (
+                SELECT t.id FROM fhir_resources t
+                WHERE t.deleted_at < v_cutoff
+                LIMIT p_batch_size
+            )
+            RETURNING r.id
+        ), purged_history AS (
+            DELETE FROM fhir.patient_history ph
+            USING purged
+            WHERE ph.id = purged.id
+        )
+        SELECT count(*) INTO tombstones_purged FROM purged;
+        PERFORM set_config('fhir.archiving', COALESCE(v_archiving, ''), true);
+    END IF;
+
+    complete := history_pruned < p_batch_size AND tombstones_purged < p_batch_size;
+    RETURN NEXT;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE fhir.fhir_maintain_all(
+    p_retention INTERVAL,
+    p_batch_size INT DEFAULT 1000,
+    p_max_batches INT DEFAULT NULL
+) AS $$
+DECLARE
+    v_function TEXT := 'fhir.fhir_maintain';
+    v_schema TEXT;
+    v_batches INT := 0;
+    v_complete BOOLEAN := false;
+BEGIN
+    SELECT n.nspname INTO v_schema
+    FROM pg_extension e JOIN pg_namespace n ON n.oid = e.extnamespace
+    WHERE e.extname = 'fhir_extension';
+    IF v_schema IS NOT NULL
+        AND to_regprocedure(format('%I.fhir_maintain(interval, integer)', v_schema)) IS NOT NULL THEN
+        v_function := format('%I.fhir_maintain', v_schema);
+    END IF;
+
+    WHILE NOT v_complete AND (p_max_batches IS NULL OR v_batches < p_max_batches) LOOP
+        EXECUTE format('SELECT complete FROM %s($1, $2)', v_function)
+            INTO v_complete
+            USING p_retention, p_batch_size;
+        v_batches := v_batches + 1;
+        COMMIT;
+    END LOOP;
+END;
+$$ LANGUAGE plpgsql;
@@ -16,4 +16,85 @@
 \echo 'Running migration 003_fhir_search_helpers.sql...'
 \i migrations/003_fhir_search_helpers.sql
 
+\echo 'Running migration 005_resource_identifiers.sql...'
+\i migrations/005_resource_identifiers.sql
+
+\echo 'Running migration 006_soft_delete.sql...'
+\i migrations/006_soft_delete.sql
+
+\echo 'Running migration 007_request_capture.sql...'
+\i migrations/007_request_capture.sql
+
+\echo 'Running migration 008_content_hash.sql...'
+\i migrations/008_content_hash.sql
+
+\echo 'Running migration 009_name_phonetic.sql...'
+\i migrations/009_name_phonetic.sql
+
+\echo 'Running migration 010_history_ts_index.sql...'
+\i migrations/010_history_ts_index.sql
+
+\echo 'Running migration 011_change_outbox.sql...'
+\i migrations/011_change_outbox.sql
+
+\echo 'Running migration 012_resource_archive.sql...'
+\i migrations/012_resource_archive.sql
+
+\echo 'Running migration 013_schema_changes.sql...'
+\i migrations/013_schema_changes.sql
+
+\echo 'Running migration 014_conformance_canonical.sql...'
+\i migrations/014_conformance_canonical.sql
+
+\echo 'Running migration 015_id_reservations.sql...'
+\i migrations/015_id_reservations.sql
+
+\echo 'Running migration 016_full_text_search.sql...'
+\i migrations/016_full_text_search.sql
+
+\echo 'Running migration 017_custom_search_parameters.sql...'
+\i migrations/017_custom_search_parameters.sql
+
+\echo 'Running migration 018_snapshots.sql...'
+\i migrations/018_snapshots.sql
+
+\echo 'Running migration 019_api_keys.sql...'
+\i migrations/019_api_keys.sql
+
+\echo 'Running migration 020_patient_match.sql...'
+\i migrations/020_patient_match.sql
+
+\echo 'Running migration 021_conformance_maps.sql...'
+\i migrations/021_conformance_maps.sql
+
+\echo 'Running migration 022_record_locks.sql...'
+\i migrations/022_record_locks.sql
+
+\echo 'Running migration 023_change_notify.sql...'
+\i migrations/023_change_notify.sql
+
+\echo 'Running migration 024_provenance_target.sql...'
+\i migrations/024_provenance_target.sql
+
+\echo 'Running migration 025_normalized_strings.sql...'
+\i migrations/025_normalized_strings.sql
+
+\echo 'Running migration 026_history_compression.sql...'
+\i migrations/026_history_compression.sql
+
+\echo 'Running migration 027_api_key_scopes.sql...'
+\i migrations/027_api_key_scopes.sql
+
+\echo 'Running migration 028_extension_row_shapes.sql...'
+\i migrations/028_extension_row_shapes.sql
+
+\echo 'Running migration 029_maintenance.sql...'
+\i migrations/029_maintenance.sql
+
+\echo 'Running migration 030_consent_instants.sql...'
+\i migrations/030_consent_instants.sql
+
+\echo 'Running migration 031_maintenance_batches.sql...'
+\i migrations/031_maintenance_batches.sql
+
 \echo 'All migrations completed successfully!'
@@ -0,0 +1,725 @@
+//! FHIR R4 resource models shared by the server and its clients.
+//!
+//! Only the elements the server interprets are typed; everything else is
+//! kept in `extra` and round-trips unchanged.
+//!
+//! Stored documents predate some typed fields. When a Patient is read, the
+//! elements in [`PROMOTED_PATIENT_ELEMENTS`] are moved from `extra` into
+//! their typed field only if the typed form reproduces the JSON exactly;
+//! anything else (a malformed value, an element the type does not model)
+//! stays in `extra`, so documents of any age keep parsing without loss.
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub mod r5;
+pub mod search;
+pub mod xml;
+
+/// All resource types defined by FHIR R4 (4.0.1), in alphabetical order
+#[rustfmt::skip]
+pub const R4_RESOURCE_TYPES: &[&str] = &[
+    "Account", "ActivityDefinition", "AdverseEvent", "AllergyIntolerance", "Appointment",
+    "AppointmentResponse", "AuditEvent", "Basic", "Binary", "BiologicallyDerivedProduct",
+    "BodyStructure", "Bundle", "CapabilityStatement", "CarePlan", "CareTeam", "CatalogEntry",
+    "ChargeItem", "ChargeItemDefinition", "Claim", "ClaimResponse", "ClinicalImpression",
+    "CodeSystem", "Communication", "CommunicationRequest", "CompartmentDefinition", "Composition",
+    "ConceptMap", "Condition", "Consent", "Contract", "Coverage", "CoverageEligibilityRequest",
+    "CoverageEligibilityResponse", "DetectedIssue", "Device", "DeviceDefinition", "DeviceMetric",
+    "DeviceRequest", "DeviceUseStatement", "DiagnosticReport", "DocumentManifest",
+    "DocumentReference", "EffectEvidenceSynthesis", "Encounter", "Endpoint", "EnrollmentRequest",
+    "EnrollmentResponse", "EpisodeOfCare", "EventDefinition", "Evidence", "EvidenceVariable",
+    "ExampleScenario", "ExplanationOfBenefit", "FamilyMemberHistory", "Flag", "Goal",
+    "GraphDefinition", "Group", "GuidanceResponse", "HealthcareService", "ImagingStudy",
+    "Immunization", "ImmunizationEvaluation", "ImmunizationRecommendation", "ImplementationGuide",
+    "InsurancePlan", "Invoice", "Library", "Linkage", "List", "Location", "Measure",
+    "MeasureReport", "Media", "Medication", "MedicationAdministration", "MedicationDispense",
+    "MedicationKnowledge", "MedicationRequest", "MedicationStatement", "MedicinalProduct",
+    "MedicinalProductAuthorization", "MedicinalProductContraindication",
+    "MedicinalProductIndication", "MedicinalProductIngredient", "MedicinalProductInteraction",
+    "MedicinalProductManufactured", "MedicinalProductPackaged", "MedicinalProductPharmaceutical",
+    "MedicinalProductUndesirableEffect", "MessageDefinition", "MessageHeader", "MolecularSequence",
+    "NamingSystem", "NutritionOrder", "Observation", "ObservationDefinition", "OperationDefinition",
+    "OperationOutcome", "Organization", "OrganizationAffiliation", "Parameters", "Patient",
+    "PaymentNotice", "PaymentReconciliation", "Person", "PlanDefinition", "Practitioner",
+    "PractitionerRole", "Procedure", "Provenance", "Questionnaire", "QuestionnaireResponse",
+    "RelatedPerson", "RequestGroup", "ResearchDefinition", "ResearchElementDefinition",
+    "ResearchStudy", "ResearchSubject", "RiskAssessment", "RiskEvidenceSynthesis", "Schedule",
+    "SearchParameter", "ServiceRequest", "Slot", "Specimen", "SpecimenDefinition",
+    "StructureDefinition", "StructureMap", "Subscription", "Substance", "SubstanceNucleicAcid",
+    "SubstancePolymer", "SubstanceProtein", "SubstanceReferenceInformation",
+    "SubstanceSourceMaterial", "SubstanceSpecification", "SupplyDelivery", "SupplyRequest", "Task",
+    "TerminologyCapabilities", "TestReport", "TestScript", "ValueSet", "VerificationResult",
+    "VisionPrescription",
+];
+
+/// Whether `name` is an R4 resource type
+pub fn is_resource_type(name: &str) -> bool {
+    R4_RESOURCE_TYPES.binary_search(&name).is_ok()
+}
+
+/// Patient elements that were once kept in `extra` and now have typed fields
+pub const PROMOTED_PATIENT_ELEMENTS: &[&str] = &[
+    "identifier",
+    "active",
+    "telecom",
+    "address",
+    "maritalStatus",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredPatient")]
+pub struct Patient {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<Vec<Identifier>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<Vec<HumanName>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub telecom: Option<Vec<ContactPoint>>,
+    #[serde(rename = "birthDate", skip_serializing_if = "Option::is_none")]
+    pub birth_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gender: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<Vec<Address>>,
+    #[serde(rename = "maritalStatus", skip_serializing_if = "Option::is_none")]
+    pub marital_status: Option<CodeableConcept>,
+
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+/// A Patient as written, before promotion of typed elements out of `extra`
+#[derive(Deserialize)]
+struct StoredPatient {
+    id: Option<String>,
+    #[serde(rename = "resourceType")]
+    resource_type: String,
+    meta: Option<Meta>,
+    #[serde(rename = "birthDate")]
+    birth_date: Option<String>,
+    gender: Option<String>,
+    #[serde(flatten, default)]
+    extra: Map<String, Value>,
+}
+
+/// Take `key` out of `extra` as a `T`, unless that would change its JSON
+fn promote<T: DeserializeOwned + Serialize>(
+    extra: &mut Map<String, Value>,
+    key: &str,
+) -> Option<T> {
+    let value = extra.remove(key)?;
+    match T::deserialize(&value) {
+        Ok(typed) if serde_json::to_value(&typed).ok().as_ref() == Some(&value) => Some(typed),
+        _ => {
+            extra.insert(key.to_string(), value);
+            None
+        }
+    }
+}
+
+impl From<StoredPatient> for Patient {
+    fn from(stored: StoredPatient) -> Self {
+        let mut extra = stored.extra;
+        Self {
+            id: stored.id,
+            resource_type: stored.resource_type,
+            meta: stored.meta,
+            identifier: promote(&mut extra, "identifier"),
+            active: promote(&mut extra, "active"),
+            name: promote(&mut extra, "name"),
+            telecom: promote(&mut extra, "telecom"),
+            birth_date: stored.birth_date,
+            gender: stored.gender,
+            address: promote(&mut extra, "address"),
+            marital_status: promote(&mut extra, "maritalStatus"),
+            extra,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identifier {
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// `type`, `period` and `assigner`
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactPoint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<u32>,
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Address {
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub address_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub district: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(rename = "postalCode", skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Meta {
+    #[serde(rename = "versionId", skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+    #[serde(rename = "lastUpdated", skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<DateTime<Utc>>,
+    /// Security labels, e.g. the `R` (restricted) confidentiality code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security: Option<Vec<Coding>>,
+    /// Canonical URLs of the profiles the resource claims to conform to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HumanName {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub given: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Observation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+    pub status: String,
+    pub code: CodeableConcept,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Reference>,
+    #[serde(rename = "effectiveDateTime", skip_serializing_if = "Option::is_none")]
+    pub effective_date_time: Option<String>,
+
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+/// Observation.status codes (http://hl7.org/fhir/observation-status)
+pub const OBSERVATION_STATUSES: &[&str] = &[
+    "registered",
+    "preliminary",
+    "final",
+    "amended",
+    "corrected",
+    "cancelled",
+    "entered-in-error",
+    "unknown",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reference {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle<R = Patient> {
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    #[serde(rename = "type")]
+    pub bundle_type: String,
+    /// Number of matches across all pages; omitted when `_total=none`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<Vec<BundleLink>>,
+    pub entry: Vec<BundleEntry<R>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleLink {
+    pub relation: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntry<R = Patient> {
+    pub resource: R,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationOutcome {
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    pub issue: Vec<OperationOutcomeIssue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationOutcomeIssue {
+    pub severity: String,
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<CodeableConcept>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expression: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeableConcept {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coding: Option<Vec<Coding>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Coding {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Map::is_empty", default)]
+    pub extra: Map<String, Value>,
+}
+
+impl<R> Bundle<R> {
+    /// URL of the link with this relation, e.g. `next` on every searchset
+    /// page but the last
+    pub fn link_url(&self, relation: &str) -> Option<&str> {
+        self.link
+            .iter()
+            .flatten()
+            .find(|link| link.relation == relation)
+            .map(|link| link.url.as_str())
+    }
+}
+
+impl OperationOutcome {
+    /// Wrap already-built issues, e.g. those reported by validation hooks
+    pub fn from_issues(issue: Vec<OperationOutcomeIssue>) -> Self {
+        Self {
+            resource_type: "OperationOutcome".to_string(),
+            issue,
+        }
+    }
+
+    /// Create a new error outcome
+    pub fn error(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            resource_type: "OperationOutcome".to_string(),
+            issue: vec![OperationOutcomeIssue {
+                severity: "error".to_string(),
+                code: code.into(),
+                details: None,
+                diagnostics: Some(message.into()),
+                location: None,
+                expression: None,
+            }],
+        }
+    }
+
+    /// Create outcome with location information
+    pub fn error_with_location(
+        code: impl Into<String>,
+        message: impl Into<String>,
+        location: impl Into<String>,
+    ) -> Self {
+        Self {
+            resource_type: "OperationOutcome".to_string(),
+            issue: vec![OperationOutcomeIssue {
+                severity: "error".to_string(),
+                code: code.into(),
+                details: None,
+                diagnostics: Some(message.into()),
+                location: Some(vec![location.into()]),
+                expression: None,
+            }],
+        }
+    }
+
+    /// Create validation error with field location
+    pub fn validation_error(field: impl Into<String>, message: impl Into<String>) -> Self {
+        let field_str = field.into();
+        Self {
+            resource_type: "OperationOutcome".to_string(),
+            issue: vec![OperationOutcomeIssue {
+                severity: "error".to_string(),
+                code: "invalid".to_string(),
+                details: Some(CodeableConcept {
+                    coding: Some(vec![Coding {
+                        system: Some("http://hl7.org/fhir/issue-type".to_string()),
+                        code: Some("invalid".to_string()),
+                        display: Some("Invalid".to_string()),
+                        extra: Map::new(),
+                    }]),
+                    text: None,
+                    extra: Map::new(),
+                }),
+                diagnostics: Some(message.into()),
+                location: Some(vec![field_str]),
+                expression: None,
+            }],
+        }
+    }
+}
+
+impl Patient {
+    pub fn new() -> Self {
+        Self {
+            id: None,
+            resource_type: "Patient".to_string(),
+            meta: None,
+            identifier: None,
+            active: None,
+            name: None,
+            telecom: None,
+            birth_date: None,
+            gender: None,
+            address: None,
+            marital_status: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+impl Default for Patient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Observation {
+    pub fn new(status: impl Into<String>, code: CodeableConcept) -> Self {
+        Self {
+            id: None,
+            resource_type: "Observation".to_string(),
+            meta: None,
+            status: status.into(),
+            code,
+            subject: None,
+            effective_date_time: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Map;
+    use serde_json::Value;
+
+    #[test]
+    fn test_patient_new() {
+        let patient = Patient::new();
+        assert_eq!(patient.resource_type, "Patient");
+        assert!(patient.id.is_none());
+        assert!(patient.meta.is_none());
+        assert!(patient.name.is_none());
+        assert!(patient.birth_date.is_none());
+        assert!(patient.gender.is_none());
+    }
+
+    #[test]
+    fn test_patient_default() {
+        let patient = Patient::default();
+        assert_eq!(patient.resource_type, "Patient");
+    }
+
+    #[test]
+    fn test_patient_serialization() {
+        let patient = Patient {
+            id: Some("123".to_string()),
+            resource_type: "Patient".to_string(),
+            meta: Some(Meta {
+                version_id: Some("1".to_string()),
+                last_updated: None,
+                profile: None,
+                security: None,
+            }),
+            name: Some(vec![HumanName {
+                family: Some("Gauß".to_string()),
+                given: Some(vec!["Carl".to_string()]),
+                text: Some("Carl Gauß".to_string()),
+                extra: Map::<String, Value>::new(),
+            }]),
+            birth_date: Some("1990-01-01".to_string()),
+            gender: Some("male".to_string()),
+            ..Patient::new()
+        };
+
+        let json = serde_json::to_string(&patient).unwrap();
+        assert!(json.contains("\"resourceType\":\"Patient\""));
+        assert!(json.contains("\"id\":\"123\""));
+        assert!(json.contains("\"birthDate\":\"1990-01-01\""));
+    }
+
+    #[test]
+    fn test_patient_deserialization() {
+        let json = r#"{
+            "resourceType": "Patient",
+            "name": [{"family": "Smith", "given": ["Jane"]}],
+            "gender": "female",
+            "birthDate": "1985-05-15"
+        }"#;
+
+        let patient: Patient = serde_json::from_str(json).unwrap();
+        assert_eq!(patient.resource_type, "Patient");
+        assert_eq!(patient.gender, Some("female".to_string()));
+        assert_eq!(patient.birth_date, Some("1985-05-15".to_string()));
+        assert!(patient.name.is_some());
+    }
+
+    #[test]
+    fn test_patient_promotes_known_extra_elements() {
+        let json = r#"{
+            "resourceType": "Patient",
+            "identifier": [{"system": "urn:mrn", "value": "42", "type": {"text": "MRN"}}],
+            "active": true,
+            "telecom": [{"system": "phone", "value": "555-1234", "use": "home"}],
+            "address": [{"line": ["1 Main St"], "city": "Springfield", "postalCode": "12345"}],
+            "maritalStatus": {"coding": [{"code": "M"}]},
+            "communication": [{"language": {"text": "German"}}]
+        }"#;
+
+        let patient: Patient = serde_json::from_str(json).unwrap();
+        let identifier = &patient.identifier.as_ref().unwrap()[0];
+        assert_eq!(identifier.value.as_deref(), Some("42"));
+        assert!(identifier.extra.contains_key("type"));
+        assert_eq!(patient.active, Some(true));
+        assert_eq!(
+            patient.telecom.as_ref().unwrap()[0].use_.as_deref(),
+            Some("home")
+        );
+        assert_eq!(
+            patient.address.as_ref().unwrap()[0].city.as_deref(),
+            Some("Springfield")
+        );
+        assert!(patient.marital_status.is_some());
+        assert_eq!(
+            patient.extra.keys().collect::<Vec<_>>(),
+            vec!["communication"]
+        );
+
+        let round_trip = serde_json::to_value(&patient).unwrap();
+        assert_eq!(round_trip, serde_json::from_str::<Value>(json).unwrap());
+    }
+
+    #[test]
+    fn test_patient_keeps_unpromotable_elements_in_extra() {
+        // An old document with a malformed `active` and `maritalStatus`, and
+        // a name whose `given` has a null placeholder for `_given`
+        let json = r#"{
+            "resourceType": "Patient",
+            "active": "yes",
+            "maritalStatus": {"coding": {"code": "M"}},
+            "name": [{"given": ["Ada", null], "_given": [null, {"extension": []}]}]
+        }"#;
+
+        let patient: Patient = serde_json::from_str(json).unwrap();
+        assert!(patient.active.is_none());
+        assert!(patient.marital_status.is_none());
+        assert!(patient.name.is_none());
+        assert_eq!(patient.extra["active"], "yes");
+
+        let round_trip = serde_json::to_value(&patient).unwrap();
+        assert_eq!(round_trip, serde_json::from_str::<Value>(json).unwrap());
+    }
+
+    #[test]
+    fn test_primitive_extensions_round_trip() {
+        let json = r#"{
+            "resourceType": "Patient",
+            "birthDate": "1974-12-25",
+            "_birthDate": {"extension": [{"url": "http://hl7.org/fhir/StructureDefinition/patient-birthTime", "valueDateTime": "1974-12-25T14:35:45-05:00"}]},
+            "name": [{"family": "Meier", "_family": {"extension": [{"url": "http://example.org/own-prefix", "valueString": "M"}]}}],
+            "maritalStatus": {"coding": [{"code": "M", "userSelected": true, "_code": {"id": "c1"}}]},
+            "modifierExtension": [{"url": "http://example.org/modifier", "valueBoolean": true}]
+        }"#;
+
+        let patient: Patient = serde_json::from_str(json).unwrap();
+        assert_eq!(patient.birth_date.as_deref(), Some("1974-12-25"));
+        assert_eq!(
+            patient.name.as_ref().unwrap()[0].family.as_deref(),
+            Some("Meier")
+        );
+        let coding = &patient
+            .marital_status
+            .as_ref()
+            .unwrap()
+            .coding
+            .as_ref()
+            .unwrap()[0];
+        assert_eq!(coding.extra["userSelected"], true);
+
+        let round_trip = serde_json::to_value(&patient).unwrap();
+        assert_eq!(round_trip, serde_json::from_str::<Value>(json).unwrap());
+    }
+
+    #[test]
+    fn test_human_name_serialization() {
+        let name = HumanName {
+            family: Some("Johnson".to_string()),
+            given: Some(vec!["Emily".to_string(), "Rose".to_string()]),
+            text: Some("Emily Rose Johnson".to_string()),
+            extra: Map::<String, Value>::new(),
+        };
+
+        let json = serde_json::to_value(&name).unwrap();
+        assert_eq!(json["family"], "Johnson");
+        assert_eq!(json["given"][0], "Emily");
+        assert_eq!(json["given"][1], "Rose");
+    }
+
+    #[test]
+    fn test_bundle_creation() {
+        let patient = Patient::new();
+        let bundle = Bundle {
+            resource_type: "Bundle".to_string(),
+            bundle_type: "searchset".to_string(),
+            total: Some(1),
+            link: None,
+            entry: vec![BundleEntry { resource: patient }],
+        };
+
+        assert_eq!(bundle.resource_type, "Bundle");
+        assert_eq!(bundle.bundle_type, "searchset");
+        assert_eq!(bundle.total, Some(1));
+        assert_eq!(bundle.entry.len(), 1);
+        assert_eq!(bundle.link_url("next"), None);
+    }
+
+    #[test]
+    fn test_operation_outcome() {
+        let outcome = OperationOutcome {
+            resource_type: "OperationOutcome".to_string(),
+            issue: vec![OperationOutcomeIssue {
+                severity: "error".to_string(),
+                code: "not-found".to_string(),
+                details: Some(CodeableConcept {
+                    coding: None,
+                    text: Some("Resource not found".to_string()),
+                    extra: Map::new(),
+                }),
+                diagnostics: Some("Patient with ID xyz not found".to_string()),
+                location: None,
+                expression: None,
+            }],
+        };
+
+        assert_eq!(outcome.resource_type, "OperationOutcome");
+        assert_eq!(outcome.issue.len(), 1);
+        assert_eq!(outcome.issue[0].severity, "error");
+    }
+
+    #[test]
+    fn test_skip_serializing_none_fields() {
+        let patient = Patient::new();
+
+        let json = serde_json::to_string(&patient).unwrap();
+        assert!(!json.contains("\"id\""));
+        assert!(!json.contains("\"meta\""));
+        assert!(!json.contains("\"name\""));
+        assert!(!json.contains("\"birthDate\""));
+        assert!(!json.contains("\"gender\""));
+    }
+
+    #[test]
+    fn test_observation_round_trip() {
+        let json = r#"{
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {"coding": [{"system": "http://loinc.org", "code": "8867-4"}]},
+            "subject": {"reference": "Patient/123"},
+            "effectiveDateTime": "2024-03-01T08:30:00Z",
+            "valueQuantity": {"value": 72, "unit": "beats/minute"}
+        }"#;
+
+        let observation: Observation = serde_json::from_str(json).unwrap();
+        assert_eq!(observation.status, "final");
+        assert_eq!(
+            observation.subject.as_ref().unwrap().reference.as_deref(),
+            Some("Patient/123")
+        );
+        assert!(observation.extra.contains_key("valueQuantity"));
+
+        let value = serde_json::to_value(&observation).unwrap();
+        assert_eq!(value["code"]["coding"][0]["code"], "8867-4");
+        assert_eq!(value["valueQuantity"]["value"], 72);
+    }
+
+    #[test]
+    fn test_is_resource_type() {
+        assert!(is_resource_type("Encounter"));
+        assert!(is_resource_type("VisionPrescription"));
+        assert!(!is_resource_type("encounter"));
+        assert!(!is_resource_type("Encountr"));
+    }
+
+    #[test]
+    fn test_meta_with_timestamp() {
+        let now = Utc::now();
+        let meta = Meta {
+            version_id: Some("2".to_string()),
+            last_updated: Some(now),
+            profile: None,
+            security: None,
+        };
+
+        let json = serde_json::to_value(&meta).unwrap();
+        assert_eq!(json["versionId"], "2");
+        assert!(json["lastUpdated"].is_string());
+    }
+}
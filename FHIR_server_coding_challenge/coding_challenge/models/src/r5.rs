@@ -0,0 +1,396 @@
+//! The FHIR R5 (5.0.0) JSON form of the resources the server also
+//! exchanges as R5: Patient, and the Bundle, OperationOutcome and
+//! Parameters resources holding it (see [`R5_RESOURCE_TYPES`]). Resources
+//! are stored and handled as R4; [`to_r5`] and [`to_r4`] convert at the
+//! edge.
+//!
+//! What R5 added to Patient and the data types it uses ([`R5_ELEMENTS`]) is
+//! carried in R4 by the cross-version extensions
+//! `http://hl7.org/fhir/5.0/StructureDefinition/extension-<path>`, so an R5
+//! resource survives the round trip through R4 storage. Elements R5
+//! renamed or retyped are mapped: the R4 `patient-genderIdentity`
+//! extension is R5's `individual-genderIdentity` with a `value`
+//! sub-extension, and `Attachment.size` is an integer64, a JSON string, in
+//! R5.
+
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+/// Resource types that have an R5 form
+pub const R5_RESOURCE_TYPES: &[&str] = &["Patient", "Bundle", "OperationOutcome", "Parameters"];
+
+/// Elements of R5 that R4 lacks, as (path, element, type of the extension's
+/// `value[x]`, whether it repeats)
+pub const R5_ELEMENTS: &[(&str, &str, &str, bool)] = &[
+    ("Patient.contact", "additionalName", "HumanName", true),
+    ("Patient.contact", "additionalAddress", "Address", true),
+    ("Attachment", "height", "PositiveInt", false),
+    ("Attachment", "width", "PositiveInt", false),
+    ("Attachment", "frames", "PositiveInt", false),
+    ("Attachment", "duration", "Decimal", false),
+    ("Attachment", "pages", "PositiveInt", false),
+];
+
+const CROSS_VERSION_PREFIX: &str = "http://hl7.org/fhir/5.0/StructureDefinition/extension-";
+const R4_GENDER_IDENTITY: &str = "http://hl7.org/fhir/StructureDefinition/patient-genderIdentity";
+const R5_GENDER_IDENTITY: &str =
+    "http://hl7.org/fhir/StructureDefinition/individual-genderIdentity";
+
+/// A FHIR release the server speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FhirVersion {
+    /// 4.0.1, what the server stores
+    #[default]
+    R4,
+    /// 5.0.0
+    R5,
+}
+
+impl FhirVersion {
+    /// A `fhirVersion` MIME-type parameter: the major and minor version,
+    /// with or without the patch
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().trim_matches('"') {
+            "4.0" | "4.0.1" => Some(FhirVersion::R4),
+            "5.0" | "5.0.0" => Some(FhirVersion::R5),
+            _ => None,
+        }
+    }
+
+    /// As the `fhirVersion` parameter is written
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FhirVersion::R4 => "4.0",
+            FhirVersion::R5 => "5.0",
+        }
+    }
+}
+
+/// A resource, or one inside it, has no R5 form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    pub resource_type: String,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is only available as FHIR 4.0; 5.0 is supported for {}",
+            self.resource_type,
+            R5_RESOURCE_TYPES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// The R5 form of an R4 resource
+pub fn to_r5(resource: &Value) -> Result<Value, ConversionError> {
+    let mut resource = resource.clone();
+    convert(&mut resource, FhirVersion::R5)?;
+    Ok(resource)
+}
+
+/// The R4 form of an R5 resource
+pub fn to_r4(resource: &Value) -> Result<Value, ConversionError> {
+    let mut resource = resource.clone();
+    convert(&mut resource, FhirVersion::R4)?;
+    Ok(resource)
+}
+
+/// Convert `resource` in place into the form of `to`
+fn convert(resource: &mut Value, to: FhirVersion) -> Result<(), ConversionError> {
+    let Some(object) = resource.as_object_mut() else {
+        return Ok(());
+    };
+    let resource_type = object
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    if !R5_RESOURCE_TYPES.contains(&resource_type.as_str()) {
+        return Err(ConversionError { resource_type });
+    }
+
+    for contained in array_mut(object, "contained") {
+        convert(contained, to)?;
+    }
+    match resource_type.as_str() {
+        "Patient" => {
+            gender_identity(object, to);
+            for contact in array_mut(object, "contact").filter_map(Value::as_object_mut) {
+                r5_elements(contact, "Patient.contact", to);
+            }
+            for photo in array_mut(object, "photo").filter_map(Value::as_object_mut) {
+                attachment(photo, to);
+            }
+        }
+        "Bundle" => {
+            for entry in array_mut(object, "entry").filter_map(Value::as_object_mut) {
+                if let Some(resource) = entry.get_mut("resource") {
+                    convert(resource, to)?;
+                }
+                if let Some(outcome) = entry
+                    .get_mut("response")
+                    .and_then(|response| response.get_mut("outcome"))
+                {
+                    convert(outcome, to)?;
+                }
+            }
+        }
+        "Parameters" => {
+            for parameter in array_mut(object, "parameter") {
+                parameter_resources(parameter, to)?;
+            }
+        }
+        // The same in both releases
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Convert the resources of a `Parameters.parameter` and its parts
+fn parameter_resources(parameter: &mut Value, to: FhirVersion) -> Result<(), ConversionError> {
+    let Some(parameter) = parameter.as_object_mut() else {
+        return Ok(());
+    };
+    if let Some(resource) = parameter.get_mut("resource") {
+        convert(resource, to)?;
+    }
+    for part in array_mut(parameter, "part") {
+        parameter_resources(part, to)?;
+    }
+    Ok(())
+}
+
+fn array_mut<'a>(
+    object: &'a mut Map<String, Value>,
+    name: &str,
+) -> impl Iterator<Item = &'a mut Value> {
+    object
+        .get_mut(name)
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+}
+
+fn cross_version_url(path: &str, element: &str) -> String {
+    format!("{}{}.{}", CROSS_VERSION_PREFIX, path, element)
+}
+
+/// Remove and return the extensions of `object` with `url`
+fn take_extensions(object: &mut Map<String, Value>, url: &str) -> Vec<Value> {
+    let Some(Value::Array(extensions)) = object.remove("extension") else {
+        return Vec::new();
+    };
+    let (taken, kept): (Vec<Value>, Vec<Value>) = extensions
+        .into_iter()
+        .partition(|extension| extension.get("url").and_then(Value::as_str) == Some(url));
+    if !kept.is_empty() {
+        object.insert("extension".to_string(), Value::Array(kept));
+    }
+    taken
+}
+
+fn push_extension(object: &mut Map<String, Value>, extension: Value) {
+    match object
+        .entry("extension")
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        Value::Array(extensions) => extensions.push(extension),
+        other => *other = Value::Array(vec![extension]),
+    }
+}
+
+/// The [`R5_ELEMENTS`] of `path`: elements in R5, cross-version extensions
+/// in R4
+fn r5_elements(object: &mut Map<String, Value>, path: &str, to: FhirVersion) {
+    for (_, element, value_type, repeats) in R5_ELEMENTS.iter().filter(|(p, ..)| *p == path) {
+        let url = cross_version_url(path, element);
+        let value_key = format!("value{}", value_type);
+        match to {
+            FhirVersion::R5 => {
+                let mut values: Vec<Value> = take_extensions(object, &url)
+                    .into_iter()
+                    .filter_map(|mut extension| extension.get_mut(&value_key).map(Value::take))
+                    .collect();
+                if values.is_empty() {
+                    continue;
+                }
+                let value = if *repeats {
+                    Value::Array(values)
+                } else {
+                    values.swap_remove(0)
+                };
+                object.insert(element.to_string(), value);
+            }
+            FhirVersion::R4 => {
+                let values = match object.remove(*element) {
+                    Some(Value::Array(values)) => values,
+                    Some(value) => vec![value],
+                    None => continue,
+                };
+                for value in values {
+                    push_extension(object, json!({ "url": url, value_key.clone(): value }));
+                }
+            }
+        }
+    }
+}
+
+/// `Attachment`: the R5 elements, and `size` as a number in R4 and a
+/// string in R5
+fn attachment(object: &mut Map<String, Value>, to: FhirVersion) {
+    r5_elements(object, "Attachment", to);
+    let Some(size) = object.get_mut("size") else {
+        return;
+    };
+    match to {
+        FhirVersion::R5 => {
+            if let Some(number) = size.as_u64() {
+                *size = Value::String(number.to_string());
+            }
+        }
+        FhirVersion::R4 => {
+            if let Some(number) = size.as_str().and_then(|s| s.parse::<u64>().ok()) {
+                *size = Value::from(number);
+            }
+        }
+    }
+}
+
+/// The gender identity extension: `valueCodeableConcept` on the R4
+/// extension, a `value` sub-extension of the R5 one. R5 extensions with
+/// further sub-extensions (`period`, `comment`) stay as they are in R4.
+fn gender_identity(object: &mut Map<String, Value>, to: FhirVersion) {
+    match to {
+        FhirVersion::R5 => {
+            for mut extension in take_extensions(object, R4_GENDER_IDENTITY) {
+                let value = extension
+                    .get_mut("valueCodeableConcept")
+                    .map(Value::take)
+                    .unwrap_or_default();
+                push_extension(
+                    object,
+                    json!({
+                        "url": R5_GENDER_IDENTITY,
+                        "extension": [{ "url": "value", "valueCodeableConcept": value }]
+                    }),
+                );
+            }
+        }
+        FhirVersion::R4 => {
+            for extension in take_extensions(object, R5_GENDER_IDENTITY) {
+                let value = match extension.get("extension").and_then(Value::as_array) {
+                    Some(parts) if parts.len() == 1 && parts[0]["url"] == "value" => {
+                        parts[0].get("valueCodeableConcept").cloned()
+                    }
+                    _ => None,
+                };
+                push_extension(
+                    object,
+                    match value {
+                        Some(value) => {
+                            json!({ "url": R4_GENDER_IDENTITY, "valueCodeableConcept": value })
+                        }
+                        None => extension,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r4_patient() -> Value {
+        json!({
+            "resourceType": "Patient",
+            "id": "1",
+            "extension": [
+                { "url": "http://example.org/other", "valueString": "kept" },
+                {
+                    "url": R4_GENDER_IDENTITY,
+                    "valueCodeableConcept": { "text": "non-binary" }
+                }
+            ],
+            "name": [{ "family": "Berg" }],
+            "contact": [{
+                "extension": [{
+                    "url": "http://hl7.org/fhir/5.0/StructureDefinition/extension-Patient.contact.additionalName",
+                    "valueHumanName": { "family": "Berg-Lind" }
+                }],
+                "name": { "family": "Lind" }
+            }],
+            "photo": [{
+                "contentType": "image/png",
+                "size": 1024,
+                "extension": [{
+                    "url": "http://hl7.org/fhir/5.0/StructureDefinition/extension-Attachment.width",
+                    "valuePositiveInt": 64
+                }]
+            }]
+        })
+    }
+
+    fn r5_patient() -> Value {
+        json!({
+            "resourceType": "Patient",
+            "id": "1",
+            "extension": [
+                { "url": "http://example.org/other", "valueString": "kept" },
+                {
+                    "url": R5_GENDER_IDENTITY,
+                    "extension": [{ "url": "value", "valueCodeableConcept": { "text": "non-binary" } }]
+                }
+            ],
+            "name": [{ "family": "Berg" }],
+            "contact": [{
+                "name": { "family": "Lind" },
+                "additionalName": [{ "family": "Berg-Lind" }]
+            }],
+            "photo": [{ "contentType": "image/png", "size": "1024", "width": 64 }]
+        })
+    }
+
+    #[test]
+    fn test_patient_converts_both_ways() {
+        assert_eq!(to_r5(&r4_patient()).unwrap(), r5_patient());
+        assert_eq!(to_r4(&r5_patient()).unwrap(), r4_patient());
+
+        // Resources inside a Bundle are converted, other types refused
+        let bundle = |patient: Value| json!({ "resourceType": "Bundle", "type": "searchset", "entry": [{ "resource": patient }] });
+        assert_eq!(to_r5(&bundle(r4_patient())).unwrap(), bundle(r5_patient()));
+        let observation = bundle(json!({ "resourceType": "Observation", "status": "final" }));
+        assert_eq!(
+            to_r5(&observation).unwrap_err(),
+            ConversionError {
+                resource_type: "Observation".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_gender_identity_with_more_than_a_value_stays_r5() {
+        let extension = json!({
+            "url": R5_GENDER_IDENTITY,
+            "extension": [
+                { "url": "value", "valueCodeableConcept": { "text": "female" } },
+                { "url": "period", "valuePeriod": { "start": "2020" } }
+            ]
+        });
+        let patient = json!({ "resourceType": "Patient", "extension": [extension] });
+        assert_eq!(to_r4(&patient).unwrap(), patient);
+    }
+
+    #[test]
+    fn test_versions_parse() {
+        assert_eq!(FhirVersion::parse("4.0"), Some(FhirVersion::R4));
+        assert_eq!(FhirVersion::parse("\"5.0.0\""), Some(FhirVersion::R5));
+        assert_eq!(FhirVersion::parse("3.0"), None);
+        assert_eq!(FhirVersion::R5.as_str(), "5.0");
+    }
+}
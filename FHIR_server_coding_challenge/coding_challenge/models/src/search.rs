@@ -0,0 +1,88 @@
+//! Normalization of string search values, shared by the server and the
+//! `fhir_extension` functions so that both compare the same text.
+//!
+//! FHIR string search ignores case and accents: `name=muller` finds
+//! "Müller" and `name=JOSE` finds "José". Values are stored normalized when
+//! they are extracted and the input of a search is normalized the same way,
+//! so the comparison is a plain one on both sides. [`normalize`] lowercases,
+//! decomposes compatibility characters (NFKD: `ﬁ` is `fi`, full-width
+//! letters are ASCII), drops combining marks and writes the Latin letters
+//! that have no decomposition (`ß`, `æ`, `ø`, `ł`, ...) as their base
+//! letters.
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Letters folded to base letters although Unicode does not decompose them
+const FOLDED_LETTERS: &[(char, &str)] = &[
+    ('ß', "ss"),
+    ('æ', "ae"),
+    ('œ', "oe"),
+    ('ø', "o"),
+    ('ł', "l"),
+    ('đ', "d"),
+    ('ð', "d"),
+    ('þ', "th"),
+    ('ı', "i"),
+];
+
+/// The case-folded, unaccented form of `text` that string search values are
+/// stored and compared in
+pub fn normalize(text: &str) -> String {
+    let lowercase = text.to_lowercase();
+    let mut normalized = String::with_capacity(lowercase.len());
+    for c in lowercase.nfkd().filter(|c| !is_combining_mark(*c)) {
+        match FOLDED_LETTERS.iter().find(|(letter, _)| *letter == c) {
+            Some((_, base)) => normalized.push_str(base),
+            None => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// A LIKE pattern matching `value` anywhere in the text, with its wildcards
+/// escaped
+pub fn contains_pattern(value: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_folds_case_and_accents() {
+        assert_eq!(normalize("Müller"), "muller");
+        assert_eq!(normalize("JOSÉ"), "jose");
+        assert_eq!(normalize("Ångström"), "angstrom");
+        assert_eq!(normalize("Straße"), "strasse");
+        assert_eq!(normalize("Søren Łukasz"), "soren lukasz");
+        assert_eq!(normalize("İstanbul"), "istanbul");
+        assert_eq!(normalize("ﬁnn"), "finn");
+        // Scripts without accents are only lowercased
+        assert_eq!(normalize("Иванов"), "иванов");
+        assert_eq!(normalize("O'Brien-Smith"), "o'brien-smith");
+    }
+
+    #[test]
+    fn test_normalize_is_idempotent() {
+        for text in ["Müller", "Straße", "ǅemal", "Ærøskøbing", "ﬀ"] {
+            let once = normalize(text);
+            assert_eq!(normalize(&once), once);
+        }
+    }
+
+    #[test]
+    fn test_contains_pattern_escapes_wildcards() {
+        assert_eq!(contains_pattern("mul"), "%mul%");
+        assert_eq!(contains_pattern("50%_a\\b"), "%50\\%\\_a\\\\b%");
+    }
+}
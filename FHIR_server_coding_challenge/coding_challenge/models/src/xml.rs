@@ -0,0 +1,796 @@
+//! FHIR XML for the resources the server exchanges as XML: Patient, Bundle
+//! and OperationOutcome (see [`XML_RESOURCE_TYPES`]).
+//!
+//! Conversion follows the FHIR XML rules on the JSON form: a resource is an
+//! element named by its type in the `http://hl7.org/fhir` namespace, a
+//! primitive is an element with a `value` attribute, a complex value an
+//! element with children, an array repeated elements and a resource inside
+//! another (`Bundle.entry.resource`, `contained`) an element wrapping the
+//! resource's own. `id` on data types and `url` on extensions are
+//! attributes, primitive extensions (`_birthDate`) become children of the
+//! primitive, and `Narrative.div` is copied as XHTML.
+//!
+//! Without the full schema, elements are written in the order of the FHIR
+//! definitions for the elements of the supported types, and reading relies on [`REPEATING_ELEMENTS`], [`BOOLEAN_ELEMENTS`] and
+//! [`NUMBER_ELEMENTS`] to tell arrays and non-string primitives apart; they
+//! cover the three types above and the data types they use.
+
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+/// Resource types served and accepted as `application/fhir+xml`
+pub const XML_RESOURCE_TYPES: &[&str] = &["Patient", "Bundle", "OperationOutcome"];
+
+const FHIR_NAMESPACE: &str = "http://hl7.org/fhir";
+
+/// Nesting depth of elements [`from_xml`] reads; deeper documents are
+/// rejected before they can exhaust the stack
+pub const MAX_DEPTH: usize = 128;
+
+/// Elements with cardinality `0..*` in the supported resources and their
+/// data types; in JSON they are always arrays
+pub const REPEATING_ELEMENTS: &[&str] = &[
+    "address",
+    "coding",
+    "communication",
+    "contact",
+    "contained",
+    "entry",
+    "expression",
+    "extension",
+    "generalPractitioner",
+    "given",
+    "identifier",
+    "issue",
+    "line",
+    "link",
+    "location",
+    "modifierExtension",
+    "name",
+    "photo",
+    "prefix",
+    "profile",
+    "relationship",
+    "security",
+    "suffix",
+    "tag",
+    "telecom",
+];
+
+/// Primitive elements of type `boolean`
+pub const BOOLEAN_ELEMENTS: &[&str] = &[
+    "active",
+    "deceasedBoolean",
+    "multipleBirthBoolean",
+    "preferred",
+    "valueBoolean",
+];
+
+/// Primitive elements of type `integer`, `unsignedInt`, `positiveInt` or `decimal`
+pub const NUMBER_ELEMENTS: &[&str] = &[
+    "multipleBirthInteger",
+    "rank",
+    "score",
+    "total",
+    "valueDecimal",
+    "valueInteger",
+];
+
+/// A document that is not FHIR XML, or a value without an XML form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlError {
+    pub message: String,
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid FHIR XML: {}", self.message)
+    }
+}
+
+impl std::error::Error for XmlError {}
+
+fn error(message: impl Into<String>) -> XmlError {
+    XmlError {
+        message: message.into(),
+    }
+}
+
+/// The XML form of a resource in JSON form
+pub fn to_xml(resource: &Value) -> Result<String, XmlError> {
+    let resource = resource
+        .as_object()
+        .ok_or_else(|| error("a resource must be a JSON object"))?;
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    write_resource(&mut out, resource, true)?;
+    Ok(out)
+}
+
+fn write_resource(
+    out: &mut String,
+    resource: &Map<String, Value>,
+    root: bool,
+) -> Result<(), XmlError> {
+    let resource_type = resource
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .ok_or_else(|| error("a resource must have a resourceType"))?;
+    out.push('<');
+    out.push_str(resource_type);
+    if root {
+        out.push_str(&format!(" xmlns=\"{}\"", FHIR_NAMESPACE));
+    }
+    out.push('>');
+    write_children(out, resource_type, resource)?;
+    out.push_str(&format!("</{}>", resource_type));
+    Ok(())
+}
+
+/// Members of `map`, the JSON form of `parent`, as child elements
+fn write_children(
+    out: &mut String,
+    parent: &str,
+    map: &Map<String, Value>,
+) -> Result<(), XmlError> {
+    let in_resource = starts_uppercase(parent);
+    let mut names: Vec<&str> = map
+        .keys()
+        .map(|name| name.strip_prefix('_').unwrap_or(name))
+        .filter(|name| *name != "resourceType")
+        .filter(|name| in_resource || !is_attribute(parent, name, map))
+        .collect();
+    names.sort_by_key(|name| (element_rank(parent, name), *name));
+    names.dedup();
+
+    for name in names {
+        let value = map.get(name).unwrap_or(&Value::Null);
+        // A primitive may carry only extensions, with `null` as its value
+        let extensions = map.get(&format!("_{}", name));
+        write_property(out, name, value, extensions)?;
+    }
+    Ok(())
+}
+
+/// `id` on data types and `url` on extensions are written as attributes
+fn is_attribute(parent: &str, name: &str, map: &Map<String, Value>) -> bool {
+    let is_string = map.get(name).is_some_and(Value::is_string);
+    match name {
+        "id" => is_string,
+        "url" => is_string && matches!(parent, "extension" | "modifierExtension"),
+        _ => false,
+    }
+}
+
+fn write_property(
+    out: &mut String,
+    name: &str,
+    value: &Value,
+    extensions: Option<&Value>,
+) -> Result<(), XmlError> {
+    match value {
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                write_property(out, name, item, extensions.and_then(|e| e.get(i)))?;
+            }
+        }
+        Value::Object(map) if map.contains_key("resourceType") => {
+            out.push_str(&format!("<{}>", name));
+            write_resource(out, map, false)?;
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Object(map) => {
+            out.push('<');
+            out.push_str(name);
+            write_attributes(out, name, map);
+            out.push('>');
+            write_children(out, name, map)?;
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::String(xhtml) if name == "div" => out.push_str(xhtml),
+        Value::Null if extensions.is_none_or(Value::is_null) => {}
+        primitive => {
+            out.push('<');
+            out.push_str(name);
+            let extensions = extensions.and_then(Value::as_object);
+            if let Some(extensions) = extensions {
+                write_attributes(out, name, extensions);
+            }
+            let text = match primitive {
+                Value::Null => None,
+                Value::String(s) => Some(s.clone()),
+                other => Some(other.to_string()),
+            };
+            if let Some(text) = text {
+                out.push_str(&format!(" value=\"{}\"", escape(&text)));
+            }
+            match extensions {
+                Some(extensions) if extensions.keys().any(|k| k != "id") => {
+                    out.push('>');
+                    write_children(out, name, extensions)?;
+                    out.push_str(&format!("</{}>", name));
+                }
+                _ => out.push_str("/>"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_attributes(out: &mut String, name: &str, map: &Map<String, Value>) {
+    for attribute in ["id", "url"] {
+        if is_attribute(name, attribute, map) {
+            let value = map[attribute].as_str().unwrap_or_default();
+            out.push_str(&format!(" {}=\"{}\"", attribute, escape(value)));
+        }
+    }
+}
+
+/// Position of `name` among the children of `parent` in the FHIR
+/// definitions; elements not listed follow in alphabetical order
+#[rustfmt::skip]
+fn element_rank(parent: &str, name: &str) -> usize {
+    const RESOURCE: &[&str] = &[
+        "id", "meta", "implicitRules", "language", "text", "contained", "extension",
+        "modifierExtension",
+    ];
+    const ELEMENT: &[&str] = &["extension", "modifierExtension"];
+    const CODING: &[&str] = &["system", "version", "code", "display", "userSelected"];
+    const REFERENCE: &[&str] = &["reference", "type", "identifier", "display"];
+
+    let (common, specific): (&[&str], &[&str]) = match parent {
+        "Patient" => (RESOURCE, &[
+            "identifier", "active", "name", "telecom", "gender", "birthDate",
+            "deceasedBoolean", "deceasedDateTime", "address", "maritalStatus",
+            "multipleBirthBoolean", "multipleBirthInteger", "photo", "contact",
+            "communication", "generalPractitioner", "managingOrganization", "link",
+        ]),
+        "Bundle" => (RESOURCE, &[
+            "identifier", "type", "timestamp", "total", "link", "entry", "signature",
+        ]),
+        _ if starts_uppercase(parent) => (RESOURCE, &[]),
+        "entry" => (ELEMENT, &["link", "fullUrl", "resource", "search", "request", "response"]),
+        "request" => (ELEMENT, &[
+            "method", "url", "ifNoneMatch", "ifModifiedSince", "ifMatch", "ifNoneExist",
+        ]),
+        "response" => (ELEMENT, &["status", "location", "etag", "lastModified", "outcome"]),
+        "search" => (ELEMENT, &["mode", "score"]),
+        "issue" => (ELEMENT, &[
+            "severity", "code", "details", "diagnostics", "location", "expression",
+        ]),
+        "meta" => (ELEMENT, &["versionId", "lastUpdated", "source", "profile", "security", "tag"]),
+        "text" => (ELEMENT, &["status", "div"]),
+        "identifier" => (ELEMENT, &["use", "type", "system", "value", "period", "assigner"]),
+        "name" => (ELEMENT, &["use", "text", "family", "given", "prefix", "suffix", "period"]),
+        "telecom" => (ELEMENT, &["system", "value", "use", "rank", "period"]),
+        "address" => (ELEMENT, &[
+            "use", "type", "text", "line", "city", "district", "state", "postalCode",
+            "country", "period",
+        ]),
+        "contact" => (ELEMENT, &[
+            "relationship", "name", "telecom", "address", "gender", "organization", "period",
+        ]),
+        "communication" => (ELEMENT, &["language", "preferred"]),
+        "link" => (ELEMENT, &["relation", "url", "other", "type"]),
+        "period" => (ELEMENT, &["start", "end"]),
+        "coding" | "security" | "tag" => (ELEMENT, CODING),
+        "assigner" | "generalPractitioner" | "managingOrganization" | "organization"
+        | "other" => (ELEMENT, REFERENCE),
+        _ => (ELEMENT, &[]),
+    };
+    common
+        .iter()
+        .chain(specific)
+        .position(|n| *n == name)
+        .unwrap_or(usize::MAX)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\t' => escaped.push_str("&#9;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The JSON form of a resource in FHIR XML, nested at most [`MAX_DEPTH`]
+/// elements deep
+pub fn from_xml(xml: &str) -> Result<Value, XmlError> {
+    from_xml_with_max_depth(xml, MAX_DEPTH)
+}
+
+/// [`from_xml`] for documents nested at most `max_depth` elements deep
+pub fn from_xml_with_max_depth(xml: &str, max_depth: usize) -> Result<Value, XmlError> {
+    let mut parser = Parser {
+        xml,
+        pos: 0,
+        max_depth,
+    };
+    parser.skip_misc()?;
+    let root = parser.element(false, 1)?;
+    parser.skip_misc()?;
+    if parser.pos < xml.len() {
+        return Err(error("content after the root element"));
+    }
+
+    if root.attribute("xmlns") != Some(FHIR_NAMESPACE) {
+        return Err(error(format!(
+            "the root element must be in the {} namespace",
+            FHIR_NAMESPACE
+        )));
+    }
+    resource_from(&root).map(Value::Object)
+}
+
+/// A parsed element; `raw` is its source text, kept for XHTML
+#[derive(Debug)]
+struct Element<'a> {
+    name: &'a str,
+    attributes: Vec<(&'a str, String)>,
+    children: Vec<Element<'a>>,
+    raw: &'a str,
+}
+
+impl Element<'_> {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn starts_uppercase(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+fn resource_from(element: &Element) -> Result<Map<String, Value>, XmlError> {
+    if !starts_uppercase(element.name) {
+        return Err(error(format!("<{}> is not a resource", element.name)));
+    }
+    let mut map = Map::new();
+    map.insert(
+        "resourceType".to_string(),
+        Value::String(element.name.to_string()),
+    );
+    add_children(&mut map, element)?;
+    Ok(map)
+}
+
+fn add_children(map: &mut Map<String, Value>, element: &Element) -> Result<(), XmlError> {
+    for child in &element.children {
+        let (value, extensions) = property_from(child)?;
+        let name = child.name.to_string();
+        if REPEATING_ELEMENTS.contains(&child.name) {
+            let values = map
+                .entry(name.clone())
+                .or_insert_with(|| Value::Array(Vec::new()));
+            let index = values.as_array().map_or(0, Vec::len);
+            push(values, value);
+            if let Some(extensions) = extensions {
+                let all = map
+                    .entry(format!("_{}", name))
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(all) = all {
+                    all.resize(index, Value::Null);
+                    all.push(extensions);
+                }
+            } else if let Some(Value::Array(all)) = map.get_mut(&format!("_{}", name)) {
+                all.push(Value::Null);
+            }
+        } else {
+            if map.contains_key(&name) {
+                return Err(error(format!(
+                    "<{}> may appear only once in <{}>",
+                    name, element.name
+                )));
+            }
+            if let Some(extensions) = extensions {
+                map.insert(format!("_{}", name), extensions);
+            }
+            if !value.is_null() {
+                map.insert(name, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn push(values: &mut Value, value: Value) {
+    if let Value::Array(values) = values {
+        values.push(value);
+    }
+}
+
+/// The JSON value of an element and, for primitives, its `_name` extensions
+fn property_from(element: &Element) -> Result<(Value, Option<Value>), XmlError> {
+    if element.name == "div" {
+        return Ok((Value::String(element.raw.to_string()), None));
+    }
+
+    if let Some(text) = element.attribute("value") {
+        let value = primitive(element.name, text)?;
+        let mut extensions = Map::new();
+        if let Some(id) = element.attribute("id") {
+            extensions.insert("id".to_string(), Value::String(id.to_string()));
+        }
+        add_children(&mut extensions, element)?;
+        let extensions = (!extensions.is_empty()).then_some(Value::Object(extensions));
+        return Ok((value, extensions));
+    }
+
+    if let [only] = element.children.as_slice() {
+        if starts_uppercase(only.name) {
+            return Ok((Value::Object(resource_from(only)?), None));
+        }
+    }
+
+    let mut map = Map::new();
+    for name in ["id", "url"] {
+        if let Some(value) = element.attribute(name) {
+            map.insert(name.to_string(), Value::String(value.to_string()));
+        }
+    }
+    add_children(&mut map, element)?;
+    Ok((Value::Object(map), None))
+}
+
+fn primitive(name: &str, text: &str) -> Result<Value, XmlError> {
+    if BOOLEAN_ELEMENTS.contains(&name) {
+        return match text {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => Err(error(format!("<{}> must be true or false", name))),
+        };
+    }
+    if NUMBER_ELEMENTS.contains(&name) {
+        let number = if let Ok(n) = text.parse::<i64>() {
+            Some(Number::from(n))
+        } else {
+            text.parse::<f64>().ok().and_then(Number::from_f64)
+        };
+        return number
+            .map(Value::Number)
+            .ok_or_else(|| error(format!("<{}> must be a number", name)));
+    }
+    Ok(Value::String(text.to_string()))
+}
+
+/// A small XML reader for FHIR documents: elements, attributes, comments
+/// and the XML declaration. Document type declarations are rejected, which
+/// also rules out entity expansion attacks. Elements are read recursively,
+/// so their nesting is bounded by `max_depth`.
+struct Parser<'a> {
+    xml: &'a str,
+    pos: usize,
+    max_depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.xml[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), XmlError> {
+        let offset = self
+            .rest()
+            .find(end)
+            .ok_or_else(|| error(format!("missing {}", end)))?;
+        self.pos += offset + end.len();
+        Ok(())
+    }
+
+    /// Whitespace, comments and processing instructions around the root
+    fn skip_misc(&mut self) -> Result<(), XmlError> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<!") {
+                return Err(error("document type declarations are not allowed"));
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, XmlError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(error(format!("expected a name at offset {}", self.pos)));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    /// The element at the cursor, `depth` elements deep; `xhtml` allows
+    /// text, as inside a `div`
+    fn element(&mut self, xhtml: bool, depth: usize) -> Result<Element<'a>, XmlError> {
+        if depth > self.max_depth {
+            return Err(error(format!(
+                "elements are nested deeper than {} at offset {}",
+                self.max_depth, self.pos
+            )));
+        }
+        let start = self.pos;
+        if !self.rest().starts_with('<') {
+            return Err(error(format!("expected an element at offset {}", self.pos)));
+        }
+        self.pos += 1;
+        let qualified = self.name()?;
+        // FHIR documents use the default namespace; a prefix is dropped
+        let name = qualified.rsplit(':').next().unwrap_or(qualified);
+
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                    raw: &self.xml[start..self.pos],
+                });
+            }
+            if rest.starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(error(format!("attribute {} has no value", attribute)));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = self
+                .rest()
+                .chars()
+                .next()
+                .filter(|c| *c == '"' || *c == '\'')
+                .ok_or_else(|| error(format!("attribute {} must be quoted", attribute)))?;
+            self.pos += 1;
+            let len = self
+                .rest()
+                .find(quote)
+                .ok_or_else(|| error(format!("attribute {} is not closed", attribute)))?;
+            let value = unescape(&self.rest()[..len])?;
+            self.pos += len + 1;
+            attributes.push((attribute, value));
+        }
+
+        let xhtml = xhtml || name == "div";
+        let mut children = Vec::new();
+        loop {
+            let rest = self.rest();
+            let text_len = rest.find('<').unwrap_or(rest.len());
+            let text = &rest[..text_len];
+            // Only XHTML has text content; it is kept verbatim in `raw`
+            if !text.trim().is_empty() && !xhtml {
+                return Err(error(format!("unexpected text in <{}>", name)));
+            }
+            self.pos += text_len;
+            let rest = self.rest();
+            if rest.is_empty() {
+                return Err(error(format!("<{}> is not closed", qualified)));
+            } else if rest.starts_with("</") {
+                self.pos += 2;
+                let closing = self.name()?;
+                if closing != qualified {
+                    return Err(error(format!(
+                        "<{}> is closed by </{}>",
+                        qualified, closing
+                    )));
+                }
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return Err(error(format!("</{}> is not closed", closing)));
+                }
+                self.pos += 1;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children,
+                    raw: &self.xml[start..self.pos],
+                });
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.skip_past("]]>")?;
+            } else if rest.starts_with("<!") || rest.starts_with("<?") {
+                return Err(error(format!("unexpected markup in <{}>", name)));
+            } else {
+                children.push(self.element(xhtml, depth + 1)?);
+            }
+        }
+    }
+}
+
+fn unescape(text: &str) -> Result<String, XmlError> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let end = rest[amp..]
+            .find(';')
+            .ok_or_else(|| error("unterminated character reference"))?;
+        let entity = &rest[amp + 1..amp + end];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(decimal) = entity.strip_prefix('#') {
+                    decimal.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| error(format!("unknown entity &{};", entity)))?
+            }
+        };
+        out.push(c);
+        rest = &rest[amp + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patient() -> Value {
+        json!({
+            "resourceType": "Patient",
+            "id": "123",
+            "meta": { "versionId": "2", "lastUpdated": "2024-05-01T10:00:00Z" },
+            "text": {
+                "status": "generated",
+                "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\"><p>Anna &amp; <b>Berg</b></p></div>"
+            },
+            "identifier": [{ "system": "urn:mrn", "value": "42" }],
+            "active": true,
+            "name": [{ "id": "n1", "family": "Berg", "given": ["Anna", "Maria"] }],
+            "gender": "female",
+            "birthDate": "1980-02-29",
+            "_birthDate": {
+                "extension": [{
+                    "url": "http://hl7.org/fhir/StructureDefinition/patient-birthTime",
+                    "valueDateTime": "1980-02-29T04:30:00+01:00"
+                }]
+            },
+            "address": [{ "line": ["Hauptstraße 1", "\"Hinterhaus\" <2>"], "city": "Köln" }],
+            "multipleBirthInteger": 2
+        })
+    }
+
+    #[test]
+    fn test_patient_round_trips() {
+        let xml = to_xml(&patient()).unwrap();
+        assert!(xml.contains("<Patient xmlns=\"http://hl7.org/fhir\"><id value=\"123\"/>"));
+        assert!(xml.contains("<name id=\"n1\"><family value=\"Berg\"/><given value=\"Anna\"/><given value=\"Maria\"/></name>"));
+        assert!(xml.contains("<birthDate value=\"1980-02-29\"><extension url=\"http://hl7.org/fhir/StructureDefinition/patient-birthTime\">"));
+        assert!(xml.contains("<line value=\"&quot;Hinterhaus&quot; &lt;2&gt;\"/>"));
+        assert!(xml.contains("<p>Anna &amp; <b>Berg</b></p>"));
+
+        assert_eq!(from_xml(&xml).unwrap(), patient());
+    }
+
+    #[test]
+    fn test_bundle_wraps_resources() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "searchset",
+            "total": 1,
+            "link": [{ "relation": "self", "url": "http://localhost:3000/fhir/Patient" }],
+            "entry": [{
+                "resource": { "resourceType": "Patient", "id": "1", "gender": "male" },
+                "response": {
+                    "status": "400 Bad Request",
+                    "outcome": {
+                        "resourceType": "OperationOutcome",
+                        "issue": [{ "severity": "error", "code": "invalid", "location": ["Patient.gender"] }]
+                    }
+                }
+            }]
+        });
+
+        let xml = to_xml(&bundle).unwrap();
+        assert!(xml.contains("<entry><resource><Patient><id value=\"1\"/>"));
+        assert!(xml.contains("<total value=\"1\"/>"));
+        assert!(xml.contains("<link><relation value=\"self\"/><url value=\"http://localhost:3000/fhir/Patient\"/></link>"));
+        assert_eq!(from_xml(&xml).unwrap(), bundle);
+    }
+
+    #[test]
+    fn test_reads_hand_written_documents() {
+        let xml = r#"<?xml version="1.0"?>
+            <!-- a comment -->
+            <Patient xmlns="http://hl7.org/fhir">
+              <name>
+                <family value='O&apos;Brien'/>
+              </name>
+              <active value="false"/>
+            </Patient>"#;
+
+        assert_eq!(
+            from_xml(xml).unwrap(),
+            json!({
+                "resourceType": "Patient",
+                "name": [{ "family": "O'Brien" }],
+                "active": false
+            })
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_documents() {
+        let message = |xml: &str| from_xml(xml).unwrap_err().message;
+
+        assert!(message("<Patient><id value=\"1\"/></Patient>").contains("namespace"));
+        assert!(
+            message("<Patient xmlns=\"http://hl7.org/fhir\"><id value=\"1\"></Patient>")
+                .contains("closed by")
+        );
+        assert!(message("<Patient xmlns=\"http://hl7.org/fhir\"><gender value=\"male\"/><gender value=\"other\"/></Patient>")
+            .contains("only once"));
+        assert!(message(
+            "<Patient xmlns=\"http://hl7.org/fhir\"><active value=\"yes\"/></Patient>"
+        )
+        .contains("true or false"));
+        assert!(message(
+            "<!DOCTYPE x [<!ENTITY a \"b\">]><Patient xmlns=\"http://hl7.org/fhir\"/>"
+        )
+        .contains("document type"));
+        assert!(
+            message("<Patient xmlns=\"http://hl7.org/fhir\">text</Patient>")
+                .contains("unexpected text")
+        );
+    }
+
+    #[test]
+    fn test_rejects_deep_nesting() {
+        // Deep enough to overflow the stack if the parser recursed into it
+        let depth = 200_000;
+        let xml = format!(
+            "<Patient xmlns=\"http://hl7.org/fhir\">{}{}</Patient>",
+            "<a>".repeat(depth),
+            "</a>".repeat(depth)
+        );
+        assert!(from_xml(&xml)
+            .unwrap_err()
+            .message
+            .contains("nested deeper than 128"));
+
+        let nested = "<Patient xmlns=\"http://hl7.org/fhir\"><name><family value=\"Berg\"/></name></Patient>";
+        assert!(from_xml_with_max_depth(nested, 3).is_ok());
+        assert!(from_xml_with_max_depth(nested, 2).is_err());
+    }
+}
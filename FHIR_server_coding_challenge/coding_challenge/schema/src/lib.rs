@@ -0,0 +1,62 @@
+//! Rows returned by the `fhir_extension` functions, in one place for the
+//! extension that produces them and the server that reads them.
+//!
+//! The pgrx functions build their result tuples from these structs and the
+//! server decodes them with [`sqlx::FromRow`] (feature `sqlx`), so a column
+//! added, dropped or reordered on one side fails to compile or fails the
+//! extension's tests instead of shifting fields silently. `COLUMNS` lists
+//! the result columns in order; the PL/pgSQL fallback functions of the
+//! migrations return the same ones.
+
+use serde_json::Value;
+use uuid::Uuid;
+
+/// A row of `fhir_search(resource_type, param, op, value)`: one match
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct SearchRow {
+    pub id: Uuid,
+    pub resource: Value,
+}
+
+impl SearchRow {
+    pub const COLUMNS: &'static [&'static str] = &["id", "resource"];
+}
+
+/// A row of `fhir_get_history(id, since, at)` and of the server's history
+/// queries: one version of a Patient
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct HistoryRow {
+    pub id: Uuid,
+    pub version_id: i32,
+    /// When the version was recorded, as `YYYY-MM-DDThh:mm:ssZ`
+    pub ts: String,
+    pub resource: Value,
+    /// What the version recorded, e.g. `deleted` for a delete
+    pub status: Option<String>,
+}
+
+impl HistoryRow {
+    pub const COLUMNS: &'static [&'static str] = &["id", "version_id", "ts", "resource", "status"];
+}
+
+/// The summary row of `fhir_maintain(retention, batch_size)`: what one
+/// batch removed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct MaintainRow {
+    /// Superseded versions removed from `fhir.patient_history`
+    pub history_pruned: i64,
+    /// Deleted resources removed with all their history
+    pub tombstones_purged: i64,
+    /// `false` while rows older than the cutoff are left for the next call
+    pub complete: bool,
+    /// Rows older than this went, as `YYYY-MM-DDThh:mm:ssZ`
+    pub cutoff: String,
+}
+
+impl MaintainRow {
+    pub const COLUMNS: &'static [&'static str] =
+        &["history_pruned", "tombstones_purged", "complete", "cutoff"];
+}
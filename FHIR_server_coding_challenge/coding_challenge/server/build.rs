@@ -0,0 +1,41 @@
+//! Records the commit the server is built from as `FHIR_SERVER_GIT_SHA`,
+//! reported by `GET /admin/about`. `GIT_SHA` in the build environment wins
+//! (the Docker build has no `.git`); otherwise `git` is asked, and without
+//! either the variable is left unset.
+
+use std::path::Path;
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+
+    let sha = match std::env::var("GIT_SHA") {
+        Ok(sha) if !sha.trim().is_empty() => Some(sha.trim().to_string()),
+        _ => {
+            if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+                // Commits move HEAD or the branch it points to
+                let branch = git(&["rev-parse", "--symbolic-full-name", "HEAD"]);
+                for file in ["HEAD"].into_iter().chain(branch.as_deref()) {
+                    let path = Path::new(&git_dir).join(file);
+                    if path.exists() {
+                        println!("cargo:rerun-if-changed={}", path.display());
+                    }
+                }
+            }
+            git(&["rev-parse", "--short=12", "HEAD"])
+        }
+    };
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=FHIR_SERVER_GIT_SHA={}", sha);
+    }
+}
@@ -0,0 +1,6 @@
+//! A `$cohort` request body through the cohort query parser
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| fhir_server::fuzzing::cohort(data));
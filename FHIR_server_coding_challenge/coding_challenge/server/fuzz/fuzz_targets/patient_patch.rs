@@ -0,0 +1,6 @@
+//! A JSON Patch applied to a Patient the way PATCH does
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| fhir_server::fuzzing::patient_patch(data));
@@ -0,0 +1,31 @@
+//! One request through the full router: method selector byte, path and
+//! query up to the first newline, body after it. Set `FUZZ_DATABASE_URL`
+//! to a scratch database to reach storage.
+#![no_main]
+
+use fhir_server::fuzzing;
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+
+struct Target {
+    runtime: tokio::runtime::Runtime,
+    app: axum::Router,
+}
+
+fn target() -> &'static Target {
+    static TARGET: OnceLock<Target> = OnceLock::new();
+    TARGET.get_or_init(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let database_url = std::env::var("FUZZ_DATABASE_URL").ok();
+        let app = runtime.block_on(async { fuzzing::app(database_url.as_deref()) });
+        Target { runtime, app }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let target = target();
+    target.runtime.block_on(fuzzing::request(&target.app, data));
+});
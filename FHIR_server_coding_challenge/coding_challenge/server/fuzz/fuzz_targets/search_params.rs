@@ -0,0 +1,6 @@
+//! A query string through the search parameter parsers
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| fhir_server::fuzzing::search(data));
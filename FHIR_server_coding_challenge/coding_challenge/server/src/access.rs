@@ -0,0 +1,304 @@
+//! Reason-for-access governance.
+//!
+//! Patients carrying one of the `[access_reason] restricted_labels` in
+//! `meta.security` (by default the `R` and `V` confidentiality codes) are
+//! restricted. Every read of a restricted patient — instance reads, history,
+//! `$conflict`, search results and Bundle `GET` entries — is recorded on the
+//! `fhir_server::audit` target together with the `X-Access-Reason`
+//! (purpose-of-use) header of the request. With `required` set, such reads
+//! are rejected with `403 forbidden` unless the header names an accepted
+//! purpose; Patient exports, which cannot be checked up front, then always
+//! need one.
+
+use crate::config::ServerConfig;
+use crate::models::{Coding, OperationOutcome, Patient};
+use axum::{
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+pub const ACCESS_REASON_HEADER: &str = "x-access-reason";
+
+const CONFIDENTIALITY: &str = "http://terminology.hl7.org/CodeSystem/v3-Confidentiality";
+
+type ErrorResponse = (StatusCode, Json<OperationOutcome>);
+
+/// The `[access_reason]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AccessReasonConfig {
+    /// Reject reads of restricted patients without an accepted reason
+    pub required: bool,
+    /// `system|code` security labels that make a patient restricted
+    pub restricted_labels: Vec<String>,
+    /// Accepted purpose-of-use codes, e.g. `TREAT`; empty accepts any reason
+    pub purposes: Vec<String>,
+}
+
+impl Default for AccessReasonConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            restricted_labels: vec![
+                format!("{}|R", CONFIDENTIALITY),
+                format!("{}|V", CONFIDENTIALITY),
+            ],
+            purposes: Vec::new(),
+        }
+    }
+}
+
+impl AccessReasonConfig {
+    fn restricts(&self, labels: &[Coding]) -> bool {
+        labels.iter().any(|label| {
+            let label = format!(
+                "{}|{}",
+                label.system.as_deref().unwrap_or(""),
+                label.code.as_deref().unwrap_or("")
+            );
+            self.restricted_labels.contains(&label)
+        })
+    }
+
+    /// A reason is a purpose-of-use code, optionally as `system|code`
+    fn accepts(&self, reason: &str) -> bool {
+        let code = reason.rsplit('|').next().unwrap_or(reason);
+        self.purposes.is_empty() || self.purposes.iter().any(|p| p == code)
+    }
+}
+
+fn reason(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(ACCESS_REASON_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|reason| !reason.is_empty())
+}
+
+fn forbidden(message: String) -> ErrorResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(OperationOutcome::error("forbidden", message)),
+    )
+}
+
+/// Record reads of the `restricted` patients, or reject them
+fn check(
+    config: &ServerConfig,
+    headers: &HeaderMap,
+    restricted: &[&str],
+) -> Result<(), ErrorResponse> {
+    let settings = &config.access_reason;
+    let Some(first) = restricted.first() else {
+        return Ok(());
+    };
+
+    match reason(headers) {
+        Some(reason) if settings.accepts(reason) || !settings.required => {
+            for id in restricted {
+                tracing::info!(
+                    target: "fhir_server::audit",
+                    patient = %id,
+                    reason,
+                    "restricted patient read"
+                );
+            }
+            Ok(())
+        }
+        Some(reason) => {
+            tracing::warn!(
+                target: "fhir_server::audit",
+                patient = %first,
+                reason,
+                "restricted patient read rejected: reason not accepted"
+            );
+            Err(forbidden(format!(
+                "X-Access-Reason '{}' is not an accepted purpose of use; expected one of {}",
+                reason,
+                settings.purposes.join(", ")
+            )))
+        }
+        None if settings.required => {
+            tracing::warn!(
+                target: "fhir_server::audit",
+                patient = %first,
+                "restricted patient read rejected: no reason given"
+            );
+            Err(forbidden(format!(
+                "Patient/{} is restricted; reading it requires an X-Access-Reason header",
+                first
+            )))
+        }
+        None => {
+            for id in restricted {
+                tracing::info!(
+                    target: "fhir_server::audit",
+                    patient = %id,
+                    "restricted patient read without reason"
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Check a request that reads `patients`
+pub(crate) fn check_patient_reads<'a>(
+    config: &ServerConfig,
+    headers: &HeaderMap,
+    patients: impl IntoIterator<Item = &'a Patient>,
+) -> Result<(), ErrorResponse> {
+    let settings = &config.access_reason;
+    let restricted: Vec<&str> = patients
+        .into_iter()
+        .filter(|patient| {
+            let labels = patient.meta.as_ref().and_then(|m| m.security.as_deref());
+            settings.restricts(labels.unwrap_or_default())
+        })
+        .map(|patient| patient.id.as_deref().unwrap_or_default())
+        .collect();
+    check(config, headers, &restricted)
+}
+
+/// Check a request that reads Patient resources given as JSON, e.g.
+/// history entries; each must carry its `id`
+pub(crate) fn check_resource_reads<'a>(
+    config: &ServerConfig,
+    headers: &HeaderMap,
+    resources: impl IntoIterator<Item = &'a Value>,
+) -> Result<(), ErrorResponse> {
+    let settings = &config.access_reason;
+    let restricted: Vec<&str> = resources
+        .into_iter()
+        .filter(|resource| {
+            let labels = resource
+                .pointer("/meta/security")
+                .and_then(|labels| serde_json::from_value::<Vec<Coding>>(labels.clone()).ok());
+            settings.restricts(&labels.unwrap_or_default())
+        })
+        .map(|resource| resource["id"].as_str().unwrap_or_default())
+        .collect();
+    check(config, headers, &restricted)
+}
+
+/// Check a request that may read any patient, such as an export; with
+/// `required` set it needs an accepted reason whatever it matches
+pub(crate) fn check_bulk_read(
+    config: &ServerConfig,
+    headers: &HeaderMap,
+    request: &str,
+) -> Result<(), ErrorResponse> {
+    let settings = &config.access_reason;
+    match reason(headers) {
+        Some(reason) if settings.accepts(reason) || !settings.required => {
+            tracing::info!(target: "fhir_server::audit", request, reason, "bulk patient read");
+            Ok(())
+        }
+        _ if !settings.required => Ok(()),
+        reason => {
+            tracing::warn!(
+                target: "fhir_server::audit",
+                request,
+                "bulk patient read rejected: no accepted reason given"
+            );
+            Err(forbidden(format!(
+                "{} may read restricted patients and requires an accepted X-Access-Reason{}",
+                request,
+                reason
+                    .map(|r| format!("; '{}' is not one", r))
+                    .unwrap_or_default()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Meta;
+    use serde_json::{json, Map};
+
+    fn restricted_patient(id: &str) -> Patient {
+        let mut patient = Patient::new();
+        patient.id = Some(id.to_string());
+        patient.meta = Some(Meta {
+            version_id: None,
+            last_updated: None,
+            profile: None,
+            security: Some(vec![Coding {
+                system: Some(CONFIDENTIALITY.to_string()),
+                code: Some("R".to_string()),
+                display: None,
+                extra: Map::new(),
+            }]),
+        });
+        patient
+    }
+
+    fn headers(reason: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(reason) = reason {
+            headers.insert(ACCESS_REASON_HEADER, reason.parse().unwrap());
+        }
+        headers
+    }
+
+    fn enforcing(purposes: &[&str]) -> ServerConfig {
+        ServerConfig {
+            access_reason: AccessReasonConfig {
+                required: true,
+                purposes: purposes.iter().map(|p| p.to_string()).collect(),
+                ..AccessReasonConfig::default()
+            },
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_restricted_reads_need_a_reason_when_required() {
+        let restricted = restricted_patient("1");
+        let open = Patient::new();
+
+        // Recorded but not enforced by default
+        let config = ServerConfig::default();
+        assert!(check_patient_reads(&config, &headers(None), [&restricted]).is_ok());
+
+        let config = enforcing(&[]);
+        let (status, Json(outcome)) =
+            check_patient_reads(&config, &headers(None), [&open, &restricted]).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(outcome.issue[0].code, "forbidden");
+        assert!(check_patient_reads(&config, &headers(None), [&open]).is_ok());
+        assert!(check_patient_reads(&config, &headers(Some("TREAT")), [&restricted]).is_ok());
+        assert!(check_patient_reads(&config, &headers(Some("  ")), [&restricted]).is_err());
+    }
+
+    #[test]
+    fn test_purposes_limit_accepted_reasons() {
+        let config = enforcing(&["TREAT", "ETREAT"]);
+        let resource = json!({
+            "resourceType": "Patient",
+            "id": "2",
+            "meta": { "security": [{ "system": CONFIDENTIALITY, "code": "V" }] }
+        });
+
+        let check = |reason| check_resource_reads(&config, &headers(reason), [&resource]);
+        assert!(check(Some("TREAT")).is_ok());
+        assert!(check(Some(
+            "http://terminology.hl7.org/CodeSystem/v3-ActReason|ETREAT"
+        ))
+        .is_ok());
+        assert!(check(Some("HMARKT")).is_err());
+
+        assert!(check_bulk_read(&config, &headers(None), "GET /fhir/Patient").is_err());
+        assert!(check_bulk_read(&config, &headers(Some("TREAT")), "GET /fhir/Patient").is_ok());
+        assert!(check_bulk_read(
+            &ServerConfig::default(),
+            &headers(None),
+            "GET /fhir/Patient"
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,466 @@
+//! API keys for the operator endpoints under `/admin`, and for every other
+//! route with `[auth] required = true`.
+//!
+//! `fhir-server --bootstrap` creates the first key, named `admin`, and
+//! prints it once. From then on every `/admin` request must carry a stored
+//! key as `Authorization: Bearer <key>` or is refused with 401. While no
+//! key is stored, or the schema predates `019_api_keys.sql`, `/admin` stays
+//! open as before and is expected to be restricted at the proxy.
+//!
+//! With `required` the other routes need a key as well, except the
+//! `unauthenticated_paths` (by default metadata, metrics, health checks,
+//! `/.well-known`, shared links and subscription channels). Which routes
+//! are meant to be public is recorded in [`crate::routes::ROUTE_ACCESS`].
+//!
+//! Keys may be limited (migration `027_api_key_scopes.sql`, managed at
+//! `/admin/api-keys`): [`ApiKeyLimits::scopes`] restrict a key to the FHIR
+//! interactions of its SMART-style resource scopes, and to `/admin` only
+//! with [`ADMIN_SCOPE`]; requests outside them are refused with 403.
+//! [`ApiKeyLimits::requests_per_minute`] caps the requests made with a key,
+//! answering those over it with 429 and a `Retry-After`. Keys without
+//! scopes, such as the bootstrap key, may do everything.
+//!
+//! With `[auth.smart]` configured the routes outside `/admin` need a
+//! credential as well, and a bearer JWT is checked as a SMART access token
+//! by [`crate::smart`] instead of being looked up as a key.
+
+use crate::authorization::{self, RolePermissions};
+use crate::config::SharedConfig;
+use crate::db::Database;
+use crate::models::OperationOutcome;
+use crate::smart::{self, Scope, ScopeContext, SmartConfig, TokenVerifier};
+use crate::throttle::WriteThrottle;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Prefix of generated keys, so leaked ones are recognizable
+const KEY_PREFIX: &str = "fhk_";
+
+/// Name of the key created by `--bootstrap`
+pub const BOOTSTRAP_KEY_NAME: &str = "admin";
+
+/// Scope of keys allowed to use `/admin`
+pub const ADMIN_SCOPE: &str = "admin";
+
+/// What a key may do; a key without either limit may do everything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyLimits {
+    /// [`ADMIN_SCOPE`] and resource scopes such as `system/Patient.read`;
+    /// `None` for full access
+    pub scopes: Option<Vec<String>>,
+    /// Requests allowed per minute; `None` for no limit
+    pub requests_per_minute: Option<i32>,
+}
+
+impl ApiKeyLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.scopes.is_none() && self.requests_per_minute.is_none()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for scope in self.scopes.iter().flatten() {
+            if scope == ADMIN_SCOPE {
+                continue;
+            }
+            match Scope::parse(scope) {
+                Some(parsed) if parsed.context != ScopeContext::Patient => {}
+                Some(_) => {
+                    return Err(format!(
+                        "{} needs a patient context, which keys do not have; use system/ scopes",
+                        scope
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "{} is neither admin nor a scope like system/Patient.read",
+                        scope
+                    ))
+                }
+            }
+        }
+        match self.requests_per_minute {
+            Some(limit) if limit < 1 => Err("requestsPerMinute must be at least 1".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the scopes allow a request to `path` with `method`
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        let Some(scopes) = &self.scopes else {
+            return true;
+        };
+        if is_admin_path(path) {
+            return scopes.iter().any(|scope| scope == ADMIN_SCOPE);
+        }
+        match smart::required_access(method, path) {
+            Some(access) => scopes
+                .iter()
+                .filter_map(|scope| Scope::parse(scope))
+                .any(|scope| scope.grants(&access, None)),
+            None => true,
+        }
+    }
+}
+
+/// Name of the key a request was authorized with, as a request extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyName(pub String);
+
+/// The `[auth]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Require a key on every route outside `unauthenticated_paths`, not
+    /// only on `/admin`
+    pub required: bool,
+    /// Paths open without a key; a trailing `/*` also opens every path
+    /// below. They never take in `/admin`.
+    pub unauthenticated_paths: Vec<String>,
+    /// SMART access tokens accepted besides API keys
+    pub smart: SmartConfig,
+    /// What the `roles` of access tokens allow; see [`crate::authorization`]
+    pub roles: BTreeMap<String, RolePermissions>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            unauthenticated_paths: [
+                "/health",
+                "/metrics",
+                "/fhir/metadata",
+                "/fhir/$versions",
+                "/.well-known/*",
+                "/fhir/_share/*",
+                "/fhir/subscriptions/*",
+            ]
+            .map(String::from)
+            .to_vec(),
+            smart: SmartConfig::default(),
+            roles: BTreeMap::new(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn is_unauthenticated(&self, path: &str) -> bool {
+        self.unauthenticated_paths
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        for pattern in &self.unauthenticated_paths {
+            if !pattern.starts_with('/') {
+                anyhow::bail!("auth.unauthenticated_paths: {} must start with /", pattern);
+            }
+            let prefix = pattern.strip_suffix("/*").unwrap_or(pattern);
+            if path_matches(pattern, "/admin") || is_admin_path(prefix) {
+                anyhow::bail!("auth.unauthenticated_paths: {} would open /admin", pattern);
+            }
+        }
+        self.smart.validate()?;
+        authorization::validate_roles(&self.roles)
+    }
+}
+
+/// Whether `path` is `pattern`, or below it when it ends in `/*`
+fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => {
+            path == prefix
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }
+        None => path == pattern,
+    }
+}
+
+/// A new random key: the prefix followed by 244 random bits in hex
+pub fn generate_key() -> String {
+    format!(
+        "{}{}{}",
+        KEY_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+/// The stored form of `key`: its SHA-256 in lower-case hex
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Create the `admin` key if no key is stored yet, returning it; the key
+/// cannot be recovered later
+pub async fn bootstrap_key(db: &Database) -> anyhow::Result<Option<String>> {
+    if !db.api_keys_enabled() || db.has_api_keys().await? {
+        return Ok(None);
+    }
+    let key = generate_key();
+    if !db
+        .create_api_key(
+            BOOTSTRAP_KEY_NAME,
+            &hash_key(&key),
+            &ApiKeyLimits::default(),
+        )
+        .await?
+    {
+        return Ok(None);
+    }
+    Ok(Some(key))
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+pub(crate) fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+/// Refuse `/admin` requests without a stored key once any key exists, and
+/// with `[auth] required` or `[auth.smart]` any request outside the
+/// unauthenticated paths
+pub async fn require_api_key(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(verifier): State<Arc<TokenVerifier>>,
+    State(throttle): State<Arc<WriteThrottle>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let admin = is_admin_path(&path);
+    let (required, unauthenticated, smart) = {
+        let config = config.get();
+        (
+            config.auth.required || config.auth.smart.enabled(),
+            config.auth.is_unauthenticated(&path),
+            config.auth.smart.clone(),
+        )
+    };
+    if unauthenticated || !(admin || required) {
+        return next.run(request).await;
+    }
+
+    let token = bearer(request.headers()).filter(|token| smart::is_jwt(token));
+    if let Some(token) = token.filter(|_| smart.enabled() && !admin) {
+        let verified = verifier.verify(&smart, token, db.now().timestamp());
+        let access = smart::required_access(request.method(), &path);
+        let refusal = match &verified {
+            Err(e) => Some(smart::invalid_token_response(e)),
+            Ok(token) => match &access {
+                Some(access) if !token.allows(access) => {
+                    Some(smart::insufficient_scope_response(access))
+                }
+                _ => None,
+            },
+        };
+        if let Some(refusal) = refusal {
+            tracing::warn!(
+                target: "fhir_server::audit",
+                method = %request.method(),
+                path = %path,
+                status = refusal.status().as_u16(),
+                "request with a SMART access token refused"
+            );
+            return refusal;
+        }
+        if let Ok(token) = verified {
+            request.extensions_mut().insert(token);
+        }
+        return next.run(request).await;
+    }
+
+    let outcome = match bearer(request.headers()) {
+        Some(key) if db.api_keys_enabled() => db.use_api_key(&hash_key(key)).await,
+        _ => Ok(None),
+    };
+    let refused = match outcome {
+        Ok(Some(key)) => {
+            if !key.limits.allows(request.method(), &path) {
+                tracing::warn!(
+                    target: "fhir_server::audit",
+                    key = %key.name,
+                    method = %request.method(),
+                    path = %path,
+                    "request outside the scopes of the API key refused"
+                );
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(OperationOutcome::error(
+                        "forbidden",
+                        format!(
+                            "The scopes of the API key {} do not allow {} {}",
+                            key.name,
+                            request.method(),
+                            path
+                        ),
+                    )),
+                )
+                    .into_response();
+            }
+            if let Some(per_minute) = key.limits.requests_per_minute {
+                let per_minute = u32::try_from(per_minute).unwrap_or(1).max(1);
+                if let Err(retry_after) = throttle.admit_api_key(&key.name, per_minute) {
+                    tracing::info!(key = %key.name, retry_after, "API key rate limit exceeded");
+                    return (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [(header::RETRY_AFTER, retry_after.to_string())],
+                        Json(OperationOutcome::error(
+                            "throttled",
+                            format!(
+                                "The API key {} may make {} requests a minute; retry after {} s",
+                                key.name, per_minute, retry_after
+                            ),
+                        )),
+                    )
+                        .into_response();
+                }
+            }
+            if admin {
+                tracing::info!(
+                    target: "fhir_server::audit",
+                    key = %key.name,
+                    method = %request.method(),
+                    path = %path,
+                    "admin request authorized"
+                );
+            }
+            request.extensions_mut().insert(ApiKeyName(key.name));
+            return next.run(request).await;
+        }
+        // Required keys are required even before any is stored
+        Ok(None) if required => Ok(true),
+        Ok(None) if db.api_keys_enabled() => db.has_api_keys().await,
+        Ok(None) => Ok(false),
+        Err(e) => Err(e),
+    };
+
+    match refused {
+        Ok(false) => next.run(request).await,
+        Ok(true) => {
+            tracing::warn!(
+                target: "fhir_server::audit",
+                method = %request.method(),
+                path = %path,
+                "request without a valid API key refused"
+            );
+            let message = if admin {
+                "The /admin endpoints require an API key as 'Authorization: Bearer <key>'"
+            } else if smart.enabled() {
+                "This server requires an access token or API key as 'Authorization: Bearer <token>'"
+            } else {
+                "This server requires an API key as 'Authorization: Bearer <key>'"
+            };
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(OperationOutcome::error("login", message)),
+            )
+                .into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to check the API key: {}", e),
+            )),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_keys() {
+        let key = generate_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(key.len(), KEY_PREFIX.len() + 64);
+        assert_ne!(key, generate_key());
+        assert_eq!(
+            hash_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_bearer_credentials() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(bearer(&headers("Bearer fhk_1")), Some("fhk_1"));
+        assert_eq!(bearer(&headers("bearer  fhk_1 ")), Some("fhk_1"));
+        assert_eq!(bearer(&headers("Basic dXNlcjpwdw==")), None);
+        assert_eq!(bearer(&HeaderMap::new()), None);
+        assert!(is_admin_path("/admin/snapshots"));
+        assert!(!is_admin_path("/administrator"));
+    }
+
+    #[test]
+    fn test_unauthenticated_paths() {
+        let auth = AuthConfig::default();
+        assert!(auth.is_unauthenticated("/fhir/metadata"));
+        assert!(auth.is_unauthenticated("/.well-known/smart-configuration"));
+        assert!(auth.is_unauthenticated("/fhir/_share/abc"));
+        assert!(!auth.is_unauthenticated("/fhir/metadata/x"));
+        assert!(!auth.is_unauthenticated("/fhir/_sharex"));
+        assert!(!auth.is_unauthenticated("/fhir/Patient"));
+        assert!(auth.validate().is_ok());
+
+        for pattern in ["/*", "/admin", "/admin/*", "/admin/snapshots", "metrics"] {
+            let auth = AuthConfig {
+                unauthenticated_paths: vec![pattern.to_string()],
+                ..AuthConfig::default()
+            };
+            assert!(auth.validate().is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_key_scopes() {
+        let limits = |scopes: &[&str]| ApiKeyLimits {
+            scopes: Some(scopes.iter().map(|s| s.to_string()).collect()),
+            requests_per_minute: None,
+        };
+        let reader = limits(&["system/Patient.read"]);
+        assert!(reader.validate().is_ok());
+        assert!(reader.allows(&Method::GET, "/fhir/Patient/1"));
+        assert!(!reader.allows(&Method::POST, "/fhir/Patient"));
+        assert!(!reader.allows(&Method::GET, "/fhir/Observation"));
+        assert!(!reader.allows(&Method::GET, "/admin/about"));
+        assert!(limits(&["admin"]).allows(&Method::GET, "/admin/about"));
+        assert!(ApiKeyLimits::default().allows(&Method::DELETE, "/admin/snapshots/a"));
+
+        assert!(limits(&["patient/Patient.read"]).validate().is_err());
+        assert!(limits(&["everything"]).validate().is_err());
+        let unlimited = ApiKeyLimits {
+            requests_per_minute: Some(0),
+            ..ApiKeyLimits::default()
+        };
+        assert!(unlimited.validate().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Moving cold resources out of the hot table.
+//!
+//! With `[archive]` enabled, a background task wakes every `interval_secs`
+//! and moves live resources last written more than `after_years` years ago
+//! into `fhir.resource_archive`, `batch_size` per transaction. Reads by id
+//! still find them, more slowly, and the next write brings them back; see
+//! [`crate::db::archive`]. `GET /admin/archive` reports both tiers and the
+//! last run, `POST /admin/archive` runs a sweep right away.
+
+use crate::config::SharedConfig;
+use crate::db::{ArchiveTierCounts, Database};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The `[archive]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    /// Resources not written for this many years are archived
+    pub after_years: u32,
+    /// Seconds between sweeps
+    pub interval_secs: u64,
+    /// Resources moved per transaction
+    pub batch_size: u32,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            after_years: 5,
+            interval_secs: 3600,
+            batch_size: 500,
+        }
+    }
+}
+
+/// Outcome of one sweep
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveRun {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Resources moved to the archive
+    pub archived: u64,
+    /// Why the sweep stopped early; what was moved before stays moved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `GET /admin/archive`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveReport {
+    /// Whether the background sweep runs
+    pub enabled: bool,
+    pub after_years: u32,
+    pub resource_types: Vec<ArchiveTierCounts>,
+    /// The last sweep since the server started
+    pub last_run: Option<ArchiveRun>,
+}
+
+/// Runs sweeps and remembers the last one. Sweeps started while another is
+/// running wait for it.
+#[derive(Debug, Default)]
+pub struct Archiver {
+    last_run: Mutex<Option<ArchiveRun>>,
+    running: tokio::sync::Mutex<()>,
+}
+
+impl Archiver {
+    pub fn last_run(&self) -> Option<ArchiveRun> {
+        self.last_run.lock().unwrap().clone()
+    }
+
+    /// Archive everything currently past `settings.after_years`, batch by
+    /// batch
+    pub async fn run(&self, db: &Database, settings: &ArchiveConfig) -> ArchiveRun {
+        let _running = self.running.lock().await;
+        let started_at = db.now();
+        let mut archived = 0;
+        let mut error = None;
+        loop {
+            match db
+                .archive_cold(settings.after_years, settings.batch_size)
+                .await
+            {
+                Ok(moved) => {
+                    archived += moved;
+                    if moved < settings.batch_size as u64 {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        let run = ArchiveRun {
+            started_at,
+            finished_at: db.now(),
+            archived,
+            error,
+        };
+        match &run.error {
+            Some(e) => tracing::error!(archived, "Archive sweep failed: {}", e),
+            None if archived > 0 => tracing::info!("Archived {} cold resources", archived),
+            None => {}
+        }
+        *self.last_run.lock().unwrap() = Some(run.clone());
+        run
+    }
+}
+
+/// Sweep on the configured interval while archiving is enabled. The
+/// settings are re-read before every sweep, so reloads apply from the next.
+pub fn spawn_archiver(archiver: Arc<Archiver>, db: Arc<Database>, config: Arc<SharedConfig>) {
+    tokio::spawn(async move {
+        loop {
+            let settings = config.get().archive.clone();
+            tokio::time::sleep(Duration::from_secs(settings.interval_secs)).await;
+
+            let settings = config.get().archive.clone();
+            if settings.enabled && db.archive_enabled() {
+                archiver.run(&db, &settings).await;
+            }
+        }
+    });
+}
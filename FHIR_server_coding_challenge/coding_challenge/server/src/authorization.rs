@@ -0,0 +1,794 @@
+//! Roles: which FHIR interactions the holders of a SMART access token may
+//! use, per resource type.
+//!
+//! Each `[auth.roles.<name>]` section maps resource types, or `*` for any,
+//! to the interactions of that role: `read`, `vread`, `search`, `history`,
+//! `create`, `update`, `patch`, `delete`, `operation`, `transaction`, or `*`
+//! for all of them. System-level requests (`POST /fhir`, `/fhir/_history`,
+//! `_changes`, `$export`, jobs) only match the `*` entry.
+//!
+//! The roles of a request are the `roles` claim of its token. While no role
+//! is configured nothing is checked here; once one is, [`authorize`] refuses
+//! with 403 every FHIR request whose token has no role allowing it, on top
+//! of what its scopes allow. Requests made with an API key are limited by
+//! the key's scopes instead ([`crate::api_keys`]).
+//!
+//! Tokens with the [`PATIENT_ROLE`] are confined to the Patient compartment
+//! of their patient, taken from the `patient` claim or a `fhirUser` naming a
+//! Patient, whatever their other roles:
+//!
+//! - on Patient only the patient itself; Patient searches need `_id=<id>`
+//! - Observation searches need `patient=<id>` or `subject=Patient/<id>` in
+//!   the URL, and every value of those parameters has to name the patient;
+//!   types without such a parameter cannot be searched, nor can any type
+//!   with `POST _search`
+//! - other resources are only reached when their `subject` or `patient` is
+//!   the patient, both as stored and as sent in creates and updates, or as
+//!   patched
+//! - conditional writes and system-level requests are refused
+//!
+//! The checks run in one layer between authentication and the handlers, so
+//! handlers never see a request the roles do not allow.
+
+use crate::config::SharedConfig;
+use crate::db::Database;
+use crate::fhirpath_patch;
+use crate::handlers::patient::apply_patch;
+use crate::limits::{is_length_limit, unreadable_body, LimitExceeded};
+use crate::models::OperationOutcome;
+use crate::smart::SmartToken;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// The role confined to the Patient compartment of the token's patient
+pub const PATIENT_ROLE: &str = "patient";
+
+/// One `[auth.roles.<name>]` section: resource type (or `*`) to the
+/// interactions allowed on it
+pub type RolePermissions = BTreeMap<String, Vec<String>>;
+
+/// A FHIR interaction, as named in the role sections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    Read,
+    Vread,
+    Search,
+    History,
+    Create,
+    Update,
+    Patch,
+    Delete,
+    Operation,
+    Transaction,
+}
+
+impl Interaction {
+    pub const ALL: [Interaction; 10] = [
+        Interaction::Read,
+        Interaction::Vread,
+        Interaction::Search,
+        Interaction::History,
+        Interaction::Create,
+        Interaction::Update,
+        Interaction::Patch,
+        Interaction::Delete,
+        Interaction::Operation,
+        Interaction::Transaction,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Interaction::Read => "read",
+            Interaction::Vread => "vread",
+            Interaction::Search => "search",
+            Interaction::History => "history",
+            Interaction::Create => "create",
+            Interaction::Update => "update",
+            Interaction::Patch => "patch",
+            Interaction::Delete => "delete",
+            Interaction::Operation => "operation",
+            Interaction::Transaction => "transaction",
+        }
+    }
+}
+
+/// What a request to the FHIR API does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FhirRequest {
+    /// `None` for the system-level routes
+    pub resource_type: Option<String>,
+    /// The resource the path is on
+    pub id: Option<String>,
+    pub interaction: Interaction,
+    /// The Patient of a compartment search (`/fhir/Patient/<id>/<type>`)
+    pub compartment: Option<String>,
+}
+
+/// The interaction of a request to `path` with `method`; `None` outside
+/// `/fhir` and for the CapabilityStatement
+pub fn classify(method: &Method, path: &str) -> Option<FhirRequest> {
+    let rest = path.strip_prefix("/fhir")?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let segments: Vec<&str> = rest.split('/').filter(|s| !s.is_empty()).collect();
+    let is_type = |s: &str| s.starts_with(|c: char| c.is_ascii_uppercase());
+    let reads = method == Method::GET || method == Method::HEAD;
+    let by_method = |read: Interaction| match *method {
+        Method::PUT => Interaction::Update,
+        Method::PATCH => Interaction::Patch,
+        Method::DELETE => Interaction::Delete,
+        _ => read,
+    };
+    let request = |resource_type: Option<&str>, id: Option<&str>, interaction| FhirRequest {
+        resource_type: resource_type.map(String::from),
+        id: id.map(String::from),
+        interaction,
+        compartment: None,
+    };
+
+    Some(match segments.as_slice() {
+        [] if reads => request(None, None, Interaction::Search),
+        [] => request(None, None, Interaction::Transaction),
+        ["metadata"] => return None,
+        ["_history", ..] => request(None, None, Interaction::History),
+        [first, ..] if !is_type(first) => request(None, None, Interaction::Operation),
+        [resource_type] if *method == Method::POST => {
+            request(Some(resource_type), None, Interaction::Create)
+        }
+        [resource_type] => request(Some(resource_type), None, by_method(Interaction::Search)),
+        [resource_type, "_search"] => request(Some(resource_type), None, Interaction::Search),
+        [resource_type, "_history"] => request(Some(resource_type), None, Interaction::History),
+        [resource_type, operation, ..] if operation.starts_with('$') => {
+            request(Some(resource_type), None, Interaction::Operation)
+        }
+        [resource_type, id] => request(Some(resource_type), Some(id), by_method(Interaction::Read)),
+        [resource_type, id, "_history"] => {
+            request(Some(resource_type), Some(id), Interaction::History)
+        }
+        [resource_type, id, "_history", _] => {
+            request(Some(resource_type), Some(id), Interaction::Vread)
+        }
+        ["Patient", id, compartment_type] if is_type(compartment_type) => FhirRequest {
+            compartment: Some(id.to_string()),
+            ..request(Some(compartment_type), None, Interaction::Search)
+        },
+        [resource_type, id, ..] => request(Some(resource_type), Some(id), Interaction::Operation),
+    })
+}
+
+/// Check the interactions and resource types of the role sections
+pub(crate) fn validate_roles(roles: &BTreeMap<String, RolePermissions>) -> anyhow::Result<()> {
+    for (role, permissions) in roles {
+        for (resource_type, interactions) in permissions {
+            let is_type = resource_type.starts_with(|c: char| c.is_ascii_uppercase())
+                && resource_type.chars().all(|c| c.is_ascii_alphanumeric());
+            if resource_type != "*" && !is_type {
+                anyhow::bail!(
+                    "auth.roles.{}: {} is neither a resource type nor *",
+                    role,
+                    resource_type
+                );
+            }
+            if let Some(unknown) = interactions.iter().find(|code| {
+                *code != "*" && !Interaction::ALL.iter().any(|i| i.code() == code.as_str())
+            }) {
+                anyhow::bail!("auth.roles.{}: unknown interaction {}", role, unknown);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `permissions` allow `request`
+pub fn role_allows(permissions: &RolePermissions, request: &FhirRequest) -> bool {
+    permissions.iter().any(|(resource_type, interactions)| {
+        (resource_type == "*" || request.resource_type.as_deref() == Some(resource_type))
+            && interactions
+                .iter()
+                .any(|code| code == "*" || code == request.interaction.code())
+    })
+}
+
+/// The Patient whose compartment confines `token`: its `patient` claim, or
+/// the id of a `fhirUser` naming a Patient
+pub fn token_patient(token: &SmartToken) -> Option<String> {
+    if let Some(patient) = &token.patient {
+        return Some(patient.clone());
+    }
+    let fhir_user = token.fhir_user.as_deref()?;
+    let (prefix, id) = fhir_user.rsplit_once("Patient/")?;
+    (prefix.is_empty() || prefix.ends_with('/'))
+        .then(|| id.to_string())
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+/// What else has to be in the compartment for a request to be allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompartmentCheck {
+    /// Nothing; the path keeps the request in the compartment
+    None,
+    /// The resource stored under the path
+    Stored,
+    /// The resource sent in the body, and for updates the stored one
+    Sent,
+    /// The resource stored under the path, and the same with the patch in
+    /// the body applied
+    Patched,
+}
+
+/// The search parameters that confine a search of `resource_type` to one
+/// Patient; only those its handler filters on count
+fn compartment_parameters(resource_type: &str) -> &'static [&'static str] {
+    match resource_type {
+        "Patient" => &["_id"],
+        "Observation" => &["patient", "subject"],
+        _ => &[],
+    }
+}
+
+/// How `request` with `query` is kept to the compartment of Patient
+/// `patient`, or why it cannot be
+pub fn compartment_check(
+    request: &FhirRequest,
+    query: Option<&str>,
+    patient: &str,
+) -> Result<CompartmentCheck, String> {
+    let Some(resource_type) = request.resource_type.as_deref() else {
+        return Err("System-level interactions are outside the patient's compartment".into());
+    };
+    let parameters: Vec<(String, String)> = query
+        .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+        .unwrap_or_default();
+    // Repeated and comma-separated values match any of them, so each one
+    // has to name the patient; `subject` also matches other types by id
+    let confined = || {
+        let names = compartment_parameters(resource_type);
+        let mut values = parameters
+            .iter()
+            .filter(|(name, _)| names.contains(&name.as_str()))
+            .peekable();
+        values.peek().is_some()
+            && values.all(|(name, value)| {
+                !value.contains(',')
+                    && match name.as_str() {
+                        "subject" => is_patient_reference(value, patient),
+                        _ => references_patient(value, patient),
+                    }
+            })
+    };
+
+    if let Some(compartment) = &request.compartment {
+        return if compartment == patient {
+            Ok(CompartmentCheck::None)
+        } else {
+            Err(format!(
+                "Patient/{} is not the token's patient",
+                compartment
+            ))
+        };
+    }
+    if resource_type == "Patient" {
+        return match (&request.id, request.interaction) {
+            (Some(id), _) if id != patient => {
+                Err(format!("Patient/{} is not the token's patient", id))
+            }
+            (Some(_), _) => Ok(CompartmentCheck::None),
+            (None, Interaction::Search) if confined() => Ok(CompartmentCheck::None),
+            (None, Interaction::Search) => Err(format!(
+                "Patient searches must be limited to _id={}",
+                patient
+            )),
+            (None, _) => Err("Only the token's own Patient may be reached".into()),
+        };
+    }
+    match (&request.id, request.interaction) {
+        (None, Interaction::Search) if compartment_parameters(resource_type).is_empty() => {
+            Err(format!(
+                "{} searches cannot be limited to the patient's compartment",
+                resource_type
+            ))
+        }
+        (None, Interaction::Search) if confined() => Ok(CompartmentCheck::None),
+        (None, Interaction::Search) => Err(format!(
+            "{} searches must be limited to patient={}",
+            resource_type, patient
+        )),
+        (None, Interaction::Create) | (Some(_), Interaction::Update) => Ok(CompartmentCheck::Sent),
+        (Some(_), Interaction::Patch) => Ok(CompartmentCheck::Patched),
+        (Some(_), _) => Ok(CompartmentCheck::Stored),
+        (None, _) => Err(format!(
+            "Type-level {} of {} is outside the patient's compartment",
+            request.interaction.code(),
+            resource_type
+        )),
+    }
+}
+
+/// Whether a search value, `<id>` or a reference, names Patient `patient`
+fn references_patient(value: &str, patient: &str) -> bool {
+    value == patient || is_patient_reference(value, patient)
+}
+
+fn is_patient_reference(reference: &str, patient: &str) -> bool {
+    reference
+        .strip_suffix(patient)
+        .is_some_and(|prefix| prefix == "Patient/" || prefix.ends_with("/Patient/"))
+}
+
+/// Whether `resource` (as stored or sent) belongs to the compartment of
+/// Patient `patient` through its `subject` or `patient`
+pub fn in_compartment(resource: &Value, patient: &str) -> bool {
+    ["subject", "patient"].iter().any(|element| {
+        resource[element]["reference"]
+            .as_str()
+            .is_some_and(|reference| is_patient_reference(reference, patient))
+    })
+}
+
+/// `stored` with the JSON Patch or FHIRPath Patch `patch` applied, as the
+/// PATCH handler applies it; `None` when it does not apply, which the
+/// handler refuses anyway
+fn patched(mut stored: Value, patch: &Value) -> Option<Value> {
+    if fhirpath_patch::is_fhirpath_patch(patch) {
+        let operations = fhirpath_patch::parse(patch).ok()?;
+        fhirpath_patch::apply(&mut stored, &operations).ok()?;
+    } else {
+        let patch = serde_json::from_value(patch.clone()).ok()?;
+        apply_patch(&mut stored, &patch).ok()?;
+    }
+    Some(stored)
+}
+
+/// Refuse the FHIR requests the roles of their token do not allow
+pub async fn authorize(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let roles = config.get().auth.roles.clone();
+    if roles.is_empty() {
+        return next.run(request).await;
+    }
+    let Some(token) = request.extensions().get::<SmartToken>().cloned() else {
+        return next.run(request).await;
+    };
+    let Some(fhir_request) = classify(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let refuse = |message: String| {
+        tracing::warn!(
+            target: "fhir_server::audit",
+            subject = token.subject.as_deref().unwrap_or_default(),
+            roles = %token.roles.join(" "),
+            method = %method,
+            path = %path,
+            "request outside the roles of the token refused: {}",
+            message
+        );
+        (
+            StatusCode::FORBIDDEN,
+            Json(OperationOutcome::error("forbidden", message)),
+        )
+            .into_response()
+    };
+
+    let allowed = token.roles.iter().any(|role| {
+        roles
+            .get(role)
+            .is_some_and(|permissions| role_allows(permissions, &fhir_request))
+    });
+    if !allowed {
+        return refuse(format!(
+            "No role of the token allows {} on {}",
+            fhir_request.interaction.code(),
+            fhir_request
+                .resource_type
+                .as_deref()
+                .unwrap_or("the system")
+        ));
+    }
+    if !token.roles.iter().any(|role| role == PATIENT_ROLE) {
+        return next.run(request).await;
+    }
+
+    let Some(patient) = token_patient(&token) else {
+        return refuse("The token has the patient role but names no patient".into());
+    };
+    if method == Method::POST && fhir_request.interaction == Interaction::Search {
+        return refuse("Searches of the patient's compartment must use GET".into());
+    }
+    let check = match compartment_check(&fhir_request, request.uri().query(), &patient) {
+        Ok(check) => check,
+        Err(message) => return refuse(message),
+    };
+    if check == CompartmentCheck::None {
+        return next.run(request).await;
+    }
+
+    let resource_type = fhir_request.resource_type.as_deref().unwrap_or_default();
+    let mut stored = None;
+    if let Some(id) = &fhir_request.id {
+        // Unknown ids are left to the handler to answer
+        if let Ok(Some(resource)) = db.get_resource(resource_type, id).await {
+            if !in_compartment(&resource.data, &patient) {
+                return refuse(format!(
+                    "{}/{} is outside the patient's compartment",
+                    resource_type, id
+                ));
+            }
+            stored = Some(resource.data);
+        }
+    }
+    if check == CompartmentCheck::Stored {
+        return next.run(request).await;
+    }
+
+    let max_bytes = config.get().resource_limits.max_bytes;
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, max_bytes).await {
+        Ok(bytes) => bytes,
+        Err(e) if is_length_limit(&e) => {
+            return LimitExceeded::Bytes(max_bytes)
+                .outcome(None)
+                .into_response()
+        }
+        Err(e) => return unreadable_body(e).into_response(),
+    };
+    let sent: Value = serde_json::from_slice(&bytes).unwrap_or_default();
+    if check == CompartmentCheck::Patched {
+        let outside = stored
+            .and_then(|stored| patched(stored, &sent))
+            .is_some_and(|patched| !in_compartment(&patched, &patient));
+        if outside {
+            return refuse(format!(
+                "The patch would move the {} out of the patient's compartment",
+                resource_type
+            ));
+        }
+    } else if !in_compartment(&sent, &patient) {
+        return refuse(format!(
+            "The {} sent must have Patient/{} as its subject or patient",
+            resource_type, patient
+        ));
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::AuthConfig;
+    use crate::config::ServerConfig;
+    use crate::db::tests::setup_test_db;
+    use crate::smart::tests::{claims, config as smart_config, key_pair};
+    use crate::smart::SmartConfig;
+    use crate::state::AppState;
+    use axum::Router;
+    use serde_json::json;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    fn get(path: &str) -> FhirRequest {
+        classify(&Method::GET, path).unwrap()
+    }
+
+    #[test]
+    fn test_classify_requests() {
+        let patient = get("/fhir/Patient/p1");
+        assert_eq!(patient.resource_type.as_deref(), Some("Patient"));
+        assert_eq!(patient.id.as_deref(), Some("p1"));
+        assert_eq!(patient.interaction, Interaction::Read);
+        assert_eq!(get("/fhir/Patient").interaction, Interaction::Search);
+        assert_eq!(
+            get("/fhir/Patient/p1/_history").interaction,
+            Interaction::History
+        );
+        assert_eq!(
+            get("/fhir/Patient/p1/_history/2").interaction,
+            Interaction::Vread
+        );
+        assert_eq!(
+            get("/fhir/Patient/p1/$everything").interaction,
+            Interaction::Operation
+        );
+        assert_eq!(get("/fhir/Patient/$match").id, None);
+        let compartment = get("/fhir/Patient/p1/Observation");
+        assert_eq!(compartment.resource_type.as_deref(), Some("Observation"));
+        assert_eq!(compartment.compartment.as_deref(), Some("p1"));
+
+        let interaction = |method: Method, path: &str| classify(&method, path).unwrap().interaction;
+        assert_eq!(
+            interaction(Method::POST, "/fhir/Observation"),
+            Interaction::Create
+        );
+        assert_eq!(
+            interaction(Method::POST, "/fhir/Observation/_search"),
+            Interaction::Search
+        );
+        assert_eq!(
+            interaction(Method::PUT, "/fhir/Observation/o1"),
+            Interaction::Update
+        );
+        assert_eq!(
+            interaction(Method::PATCH, "/fhir/Patient/p1"),
+            Interaction::Patch
+        );
+        assert_eq!(
+            interaction(Method::DELETE, "/fhir/Patient"),
+            Interaction::Delete
+        );
+        assert_eq!(interaction(Method::POST, "/fhir"), Interaction::Transaction);
+        assert_eq!(get("/fhir/_history").resource_type, None);
+        assert_eq!(get("/fhir/$export").interaction, Interaction::Operation);
+        assert_eq!(classify(&Method::GET, "/fhir/metadata"), None);
+        assert_eq!(classify(&Method::GET, "/admin/about"), None);
+    }
+
+    fn permissions(entries: &[(&str, &[&str])]) -> RolePermissions {
+        entries
+            .iter()
+            .map(|(t, codes)| (t.to_string(), codes.iter().map(|c| c.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_roles_allow_interactions() {
+        let clinician = permissions(&[("*", &["read", "search"]), ("Observation", &["create"])]);
+        assert!(role_allows(&clinician, &get("/fhir/Patient/p1")));
+        assert!(!role_allows(&clinician, &get("/fhir/_history")));
+        assert!(role_allows(&clinician, &get("/fhir")));
+        assert!(role_allows(
+            &clinician,
+            &classify(&Method::POST, "/fhir/Observation").unwrap()
+        ));
+        assert!(!role_allows(
+            &clinician,
+            &classify(&Method::POST, "/fhir/Patient").unwrap()
+        ));
+
+        let admin = permissions(&[("*", &["*"])]);
+        assert!(role_allows(
+            &admin,
+            &classify(&Method::POST, "/fhir").unwrap()
+        ));
+        let typed = permissions(&[("Patient", &["*"])]);
+        assert!(!role_allows(&typed, &get("/fhir/_history")));
+
+        let roles = BTreeMap::from([("clinician".to_string(), clinician)]);
+        assert!(validate_roles(&roles).is_ok());
+        let unknown =
+            BTreeMap::from([("clerk".to_string(), permissions(&[("Patient", &["write"])]))]);
+        assert!(validate_roles(&unknown).is_err());
+        let lower = BTreeMap::from([("clerk".to_string(), permissions(&[("patient", &["read"])]))]);
+        assert!(validate_roles(&lower).is_err());
+    }
+
+    #[test]
+    fn test_patient_compartment() {
+        let check = |method: Method, path: &str, query: Option<&str>| {
+            compartment_check(&classify(&method, path).unwrap(), query, "p1")
+        };
+        assert_eq!(
+            check(Method::GET, "/fhir/Patient/p1", None),
+            Ok(CompartmentCheck::None)
+        );
+        assert!(check(Method::GET, "/fhir/Patient/p2", None).is_err());
+        assert!(check(Method::GET, "/fhir/Patient", Some("name=smith")).is_err());
+        assert!(check(Method::GET, "/fhir/Patient", Some("_id=p1")).is_ok());
+        assert!(check(Method::GET, "/fhir/Observation", Some("code=1234-5")).is_err());
+        assert!(check(Method::GET, "/fhir/Observation", Some("subject=Patient/p1")).is_ok());
+        assert!(check(
+            Method::GET,
+            "/fhir/Observation",
+            Some("subject=Patient/p12")
+        )
+        .is_err());
+        assert!(check(Method::GET, "/fhir/Observation", Some("patient=p1")).is_ok());
+        assert!(check(
+            Method::GET,
+            "/fhir/Observation",
+            Some("patient=Patient/p1&code=x")
+        )
+        .is_ok());
+        // Other values would widen the search beyond the patient
+        for query in [
+            "subject=p1",
+            "subject=Patient/p2,Patient/p1",
+            "subject=Patient/p1&subject=Patient/p2",
+            "patient=p1&subject=Patient/p2",
+            "patient=p1,p2",
+        ] {
+            assert!(
+                check(Method::GET, "/fhir/Observation", Some(query)).is_err(),
+                "{}",
+                query
+            );
+        }
+        assert!(check(Method::GET, "/fhir/Patient", Some("_id=p1&_id=p2")).is_err());
+        assert!(check(Method::GET, "/fhir/Encounter", Some("patient=p1")).is_err());
+        assert!(check(Method::GET, "/fhir/Patient/p1/Observation", None).is_ok());
+        assert!(check(Method::GET, "/fhir/Patient/p2/Observation", None).is_err());
+        assert_eq!(
+            check(Method::GET, "/fhir/Observation/o1", None),
+            Ok(CompartmentCheck::Stored)
+        );
+        assert_eq!(
+            check(Method::POST, "/fhir/Observation", None),
+            Ok(CompartmentCheck::Sent)
+        );
+        assert_eq!(
+            check(Method::PATCH, "/fhir/Observation/o1", None),
+            Ok(CompartmentCheck::Patched)
+        );
+        assert!(check(Method::DELETE, "/fhir/Observation", Some("code=x")).is_err());
+        assert!(check(Method::GET, "/fhir/_history", None).is_err());
+
+        let observation = |reference: &str| json!({ "subject": { "reference": reference } });
+        assert!(in_compartment(&observation("Patient/p1"), "p1"));
+        assert!(in_compartment(
+            &observation("https://fhir.example.org/fhir/Patient/p1"),
+            "p1"
+        ));
+        assert!(!in_compartment(&observation("Patient/p12"), "p1"));
+        assert!(!in_compartment(&observation("Group/p1"), "p1"));
+        assert!(!in_compartment(&json!({}), "p1"));
+    }
+
+    #[test]
+    fn test_patches_are_checked_as_applied() {
+        let stored = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "subject": { "reference": "Patient/p1" },
+        });
+        let moved = patched(
+            stored.clone(),
+            &json!([{ "op": "replace", "path": "/subject/reference", "value": "Patient/p2" }]),
+        )
+        .unwrap();
+        assert!(!in_compartment(&moved, "p1"));
+        let amended = patched(
+            stored.clone(),
+            &json!([{ "op": "replace", "path": "/status", "value": "amended" }]),
+        )
+        .unwrap();
+        assert!(in_compartment(&amended, "p1"));
+
+        let fhirpath = json!({
+            "resourceType": "Parameters",
+            "parameter": [{
+                "name": "operation",
+                "part": [
+                    { "name": "type", "valueCode": "replace" },
+                    { "name": "path", "valueString": "Observation.subject" },
+                    { "name": "value", "valueReference": { "reference": "Patient/p2" } },
+                ],
+            }],
+        });
+        assert!(!in_compartment(
+            &patched(stored.clone(), &fhirpath).unwrap(),
+            "p1"
+        ));
+        assert_eq!(
+            patched(stored, &json!([{ "op": "remove", "path": "/missing" }])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_token_patient() {
+        let token = |patient: Option<&str>, fhir_user: Option<&str>| SmartToken {
+            subject: None,
+            client_id: None,
+            patient: patient.map(String::from),
+            fhir_user: fhir_user.map(String::from),
+            scopes: Vec::new(),
+            roles: vec![PATIENT_ROLE.to_string()],
+        };
+        assert_eq!(
+            token_patient(&token(Some("p1"), None)).as_deref(),
+            Some("p1")
+        );
+        assert_eq!(
+            token_patient(&token(
+                None,
+                Some("https://fhir.example.org/fhir/Patient/p2")
+            ))
+            .as_deref(),
+            Some("p2")
+        );
+        assert_eq!(
+            token_patient(&token(None, Some("Patient/p3"))).as_deref(),
+            Some("p3")
+        );
+        assert_eq!(token_patient(&token(None, Some("Practitioner/d1"))), None);
+        assert_eq!(token_patient(&token(None, Some("RelatedPatient/p1"))), None);
+        assert_eq!(token_patient(&token(None, None)), None);
+    }
+
+    async fn search(app: &Router, token: &str, query: &str) -> (StatusCode, Value) {
+        let request = Request::get(format!("/fhir/Observation?{}", query))
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_patient_token_searches_only_its_own_observations() {
+        let db = setup_test_db().await;
+        let own = Uuid::new_v4().to_string();
+        let other = Uuid::new_v4().to_string();
+        let code = format!("compartment-{}", Uuid::new_v4());
+        for patient in [&own, &other] {
+            db.create_resource(
+                "Observation",
+                json!({
+                    "resourceType": "Observation",
+                    "status": "final",
+                    "code": { "coding": [{ "system": "http://loinc.org", "code": code }] },
+                    "subject": { "reference": format!("Patient/{}", patient) },
+                }),
+            )
+            .await
+            .unwrap();
+        }
+
+        let config = ServerConfig {
+            auth: AuthConfig {
+                smart: SmartConfig {
+                    audience: None,
+                    ..smart_config()
+                },
+                roles: BTreeMap::from([(PATIENT_ROLE.to_string(), permissions(&[("*", &["*"])]))]),
+                ..AuthConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (verifier, sign) = key_pair("k1");
+        let mut state = AppState::new(db, Arc::new(SharedConfig::new(config)));
+        state.tokens = Arc::new(verifier);
+        let app = crate::routes::router(state);
+        let mut token = claims("user/*.read");
+        token["exp"] = json!(chrono::Utc::now().timestamp() + 300);
+        token["roles"] = json!(PATIENT_ROLE);
+        token["patient"] = json!(own);
+        let token = sign(&token);
+
+        let (status, bundle) =
+            search(&app, &token, &format!("patient={}&code={}", own, code)).await;
+        assert_eq!(status, StatusCode::OK);
+        let subjects: Vec<&str> = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["resource"]["subject"]["reference"].as_str().unwrap())
+            .collect();
+        assert_eq!(subjects, [format!("Patient/{}", own)]);
+
+        for query in [
+            format!("patient={},{}&code={}", own, other, code),
+            format!("patient={}&subject=Patient/{}", own, other),
+            format!("code={}", code),
+        ] {
+            let (status, _) = search(&app, &token, &query).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::db::{Database, PageStart};
+use crate::models::{HumanName, Patient};
+use serde_json::Map;
+use std::fmt;
+
+/// Tables created by the migrations that the server depends on
+const REQUIRED_TABLES: &[(&str, &str)] = &[
+    ("fhir_resources", "000_fhir_resources_table.sql"),
+    ("fhir.patient", "001_initial_schema.sql"),
+    ("fhir.patient_history", "001_initial_schema.sql"),
+    ("fhir.patient_name_index", "003_fhir_search_helpers.sql"),
+    ("fhir.resource_identifier", "005_resource_identifiers.sql"),
+    ("fhir.request_capture", "007_request_capture.sql"),
+    ("fhir.resource_name_phonetic", "009_name_phonetic.sql"),
+    ("fhir.resource_string_value", "025_normalized_strings.sql"),
+];
+
+/// Columns added to existing tables by later migrations, as (table, column, migration)
+const REQUIRED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("fhir_resources", "deleted_at", "006_soft_delete.sql"),
+    ("fhir_resources", "content_hash", "008_content_hash.sql"),
+];
+
+/// Functions created by the migrations, as (schema, name, migration)
+const REQUIRED_FUNCTIONS: &[(&str, &str, &str)] = &[
+    (
+        "fhir",
+        "search_patient_by_name",
+        "002_add_search_functions.sql",
+    ),
+    (
+        "fhir",
+        "fhir_get_history",
+        "002_fhir_extension_functions.sql",
+    ),
+];
+
+/// Extension interface; provided by the pgrx extension or the SQL fallback
+const EXTENSION_FUNCTIONS: &[&str] = &["fhir_put", "fhir_get", "fhir_search"];
+
+/// Outcome of a single self-check step
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            None => write!(f, "[ok]   {}", self.name),
+            Some(e) => write!(f, "[FAIL] {}: {}", self.name, e),
+        }
+    }
+}
+
+/// Collected results of `fhir-server check`
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(CheckResult::passed)
+    }
+
+    fn record(&mut self, name: impl Into<String>, outcome: Result<(), String>) -> bool {
+        let passed = outcome.is_ok();
+        self.results.push(CheckResult {
+            name: name.into(),
+            error: outcome.err(),
+        });
+        passed
+    }
+}
+
+/// Run schema verification and a create→read→search→delete smoke cycle
+pub async fn run_checks(db: &Database) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    let connected = report.record(
+        "database connection",
+        db.ping().await.map_err(|e| e.to_string()),
+    );
+    if !connected {
+        return report;
+    }
+
+    for (table, migration) in REQUIRED_TABLES {
+        let outcome = match db.table_exists(table).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("missing, run {}", migration)),
+            Err(e) => Err(e.to_string()),
+        };
+        report.record(format!("table {}", table), outcome);
+    }
+
+    for (table, column, migration) in REQUIRED_COLUMNS {
+        let outcome = match db.column_exists(table, column).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("missing, run {}", migration)),
+            Err(e) => Err(e.to_string()),
+        };
+        report.record(format!("column {}.{}", table, column), outcome);
+    }
+
+    for (schema, function, migration) in REQUIRED_FUNCTIONS {
+        let outcome = match db.function_exists(Some(schema), function).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("missing, run {}", migration)),
+            Err(e) => Err(e.to_string()),
+        };
+        report.record(format!("function {}.{}", schema, function), outcome);
+    }
+
+    for function in EXTENSION_FUNCTIONS {
+        let outcome = match db.function_exists(None, function).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("missing, install fhir_extension or run the SQL fallback".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        report.record(format!("extension function {}", function), outcome);
+    }
+
+    if report.passed() {
+        smoke_cycle(db, &mut report).await;
+    }
+
+    report
+}
+
+async fn smoke_cycle(db: &Database, report: &mut CheckReport) {
+    let marker = format!("SelfCheck{}", uuid::Uuid::new_v4().simple());
+    let mut patient = Patient::new();
+    patient.name = Some(vec![HumanName {
+        family: Some(marker.clone()),
+        given: Some(vec!["Temp".to_string()]),
+        text: None,
+        extra: Map::new(),
+    }]);
+
+    let id = match db.create_patient(patient).await {
+        Ok(created) => match created.id {
+            Some(id) => {
+                report.record("create temp patient", Ok(()));
+                id
+            }
+            None => {
+                report.record("create temp patient", Err("no id assigned".to_string()));
+                return;
+            }
+        },
+        Err(e) => {
+            report.record("create temp patient", Err(e.to_string()));
+            return;
+        }
+    };
+
+    let read = match db.get_patient(&id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err("created patient not found".to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    report.record("read temp patient", read);
+
+    // The rows of the SQL extension functions decode as the server's
+    let history = match id.parse() {
+        Ok(uuid) => match db.extension_history(uuid).await {
+            Ok(versions) if !versions.is_empty() => Ok(()),
+            Ok(_) => Err("no versions of the created patient".to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        Err(e) => Err(format!("invalid id {}: {}", id, e)),
+    };
+    report.record("extension history of temp patient", history);
+
+    let search = match db
+        .search_patients(Some(&marker), None, None, None, 10, PageStart::Offset(0))
+        .await
+    {
+        Ok(found) if found.iter().any(|p| p.id.as_deref() == Some(id.as_str())) => Ok(()),
+        Ok(_) => Err("created patient not returned by name search".to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    report.record("search temp patient", search);
+
+    let delete = match db.purge_patient(&id).await {
+        Ok(true) => match db.get_patient(&id).await {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => Err("patient still readable after delete".to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        Ok(false) => Err("nothing deleted".to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    report.record("delete temp patient", delete);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    #[tokio::test]
+    async fn test_run_checks_passes_against_migrated_db() {
+        let db = setup_test_db().await;
+        let report = run_checks(&db).await;
+
+        for result in &report.results {
+            assert!(result.passed(), "{}", result);
+        }
+        assert!(report
+            .results
+            .iter()
+            .any(|r| r.name == "delete temp patient"));
+    }
+
+    #[test]
+    fn test_report_fails_on_any_error() {
+        let mut report = CheckReport::default();
+        report.record("first", Ok(()));
+        assert!(report.passed());
+
+        report.record("second", Err("boom".to_string()));
+        assert!(!report.passed());
+        assert_eq!(report.results[1].to_string(), "[FAIL] second: boom");
+    }
+}
@@ -0,0 +1,61 @@
+//! The time the server stamps on what it writes.
+//!
+//! `meta.lastUpdated`, history timestamps, the archive cut-off and the
+//! start and end of background jobs are read from the [`Clock`] of the
+//! [`Database`](crate::db::Database) rather than from `NOW()` or
+//! `Utc::now()`. The server runs on the [`SystemClock`]; tests install a
+//! [`TestClock`] with [`Database::with_clock`](crate::db::Database::with_clock)
+//! to write at instants of their choosing and move time forward between
+//! writes.
+
+use chrono::{DateTime, Duration, Utc};
+use std::fmt;
+use std::sync::Mutex;
+
+/// A source of the current time
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct TestClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl TestClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.lock() = now;
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.lock() += by;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DateTime<Utc>> {
+        self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.lock()
+    }
+}
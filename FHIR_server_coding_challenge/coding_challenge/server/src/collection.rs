@@ -0,0 +1,216 @@
+//! A Postman collection of the routes, served at `/admin/collection.json`.
+//!
+//! The collection is generated from [`routes::routes`], so it lists exactly
+//! the paths and methods the running server answers. It is in the Postman
+//! v2.1 format, which Bruno and Insomnia import as well. Requests send the
+//! `apiKey` variable as a bearer token, except on the routes that are open
+//! without a key; `baseUrl` starts out as the server's `FHIR_BASE_URL`.
+
+use crate::routes::{self, Route, RouteAccess, ROUTE_ACCESS};
+use axum::http::Method;
+use serde_json::{json, Value};
+
+const SCHEMA: &str = "https://schema.getpostman.com/json/collection/v2.1.0/collection.json";
+
+/// Folders of the collection, by the first segment of the paths they hold
+const FOLDERS: &[(&str, &str)] = &[("fhir", "FHIR"), ("admin", "Admin")];
+
+/// Folder of the paths not in [`FOLDERS`]
+const OTHER_FOLDER: &str = "Server";
+
+/// The collection of every route, against `base_url`
+pub fn collection(base_url: &str) -> Value {
+    collection_of(&routes::routes(), base_url)
+}
+
+fn collection_of(routes: &[Route], base_url: &str) -> Value {
+    let folder_names = FOLDERS.iter().map(|(_, name)| *name).chain([OTHER_FOLDER]);
+    let folders: Vec<Value> = folder_names
+        .filter_map(|folder| {
+            let items: Vec<Value> = routes
+                .iter()
+                .filter(|route| folder_of(route.path) == folder)
+                .flat_map(|route| {
+                    route
+                        .methods
+                        .iter()
+                        .map(move |m| request_item(route.path, m))
+                })
+                .collect();
+            (!items.is_empty()).then(|| json!({ "name": folder, "item": items }))
+        })
+        .collect();
+
+    json!({
+        "info": {
+            "name": "FHIR Server",
+            "description": format!("Routes of fhir-server {}", env!("CARGO_PKG_VERSION")),
+            "schema": SCHEMA,
+        },
+        "auth": bearer_auth(),
+        "variable": [
+            { "key": "baseUrl", "value": base_url.trim_end_matches('/') },
+            { "key": "apiKey", "value": "" },
+        ],
+        "item": folders,
+    })
+}
+
+fn folder_of(path: &str) -> &'static str {
+    let first = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    FOLDERS
+        .iter()
+        .find(|(segment, _)| *segment == first)
+        .map(|(_, name)| *name)
+        .unwrap_or(OTHER_FOLDER)
+}
+
+fn bearer_auth() -> Value {
+    json!({
+        "type": "bearer",
+        "bearer": [{ "key": "token", "value": "{{apiKey}}", "type": "string" }],
+    })
+}
+
+fn is_public(path: &str) -> bool {
+    ROUTE_ACCESS
+        .iter()
+        .any(|(p, access)| *p == path && *access == RouteAccess::Public)
+}
+
+fn request_item(path: &str, method: &Method) -> Value {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let variables: Vec<Value> = segments
+        .iter()
+        .filter_map(|s| s.strip_prefix(':'))
+        .map(|name| json!({ "key": name, "value": "" }))
+        .collect();
+
+    let mut request = json!({
+        "method": method.as_str(),
+        "url": {
+            "raw": format!("{{{{baseUrl}}}}{}", path),
+            "host": ["{{baseUrl}}"],
+            "path": segments,
+            "variable": variables,
+        },
+    });
+    if is_public(path) {
+        request["auth"] = json!({ "type": "noauth" });
+    }
+    if [Method::POST, Method::PUT, Method::PATCH].contains(method) {
+        let content_type = if segments.first() == Some(&"fhir") {
+            "application/fhir+json"
+        } else {
+            "application/json"
+        };
+        request["header"] = json!([{ "key": "Content-Type", "value": content_type }]);
+        request["body"] = json!({
+            "mode": "raw",
+            "raw": example_body(&segments, method),
+            "options": { "raw": { "language": "json" } },
+        });
+    }
+
+    json!({ "name": format!("{} {}", method, path), "request": request })
+}
+
+/// A starting point for the body: an empty JSON Patch for PATCH, an empty
+/// resource of the type of the path if it names one, and `{}` otherwise
+fn example_body(segments: &[&str], method: &Method) -> String {
+    if method == Method::PATCH {
+        return "[]".to_string();
+    }
+    match segments {
+        ["fhir", resource_type] | ["fhir", resource_type, _]
+            if resource_type.starts_with(|c: char| c.is_ascii_uppercase()) =>
+        {
+            json!({ "resourceType": resource_type }).to_string()
+        }
+        _ => "{}".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests(collection: &Value) -> Vec<(String, String)> {
+        collection["item"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|folder| folder["item"].as_array().unwrap())
+            .map(|item| {
+                (
+                    item["request"]["method"].as_str().unwrap().to_string(),
+                    item["request"]["url"]["raw"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_collection_lists_every_route_and_method() {
+        let collection = collection("https://fhir.example.org/");
+        assert_eq!(
+            collection["variable"][0]["value"],
+            "https://fhir.example.org"
+        );
+
+        let requests = requests(&collection);
+        let expected: usize = routes::routes().iter().map(|r| r.methods.len()).sum();
+        assert_eq!(requests.len(), expected);
+        for (method, raw) in [
+            ("GET", "{{baseUrl}}/fhir/Patient/:id"),
+            ("PATCH", "{{baseUrl}}/fhir/Patient/:id"),
+            ("DELETE", "{{baseUrl}}/fhir/Patient/:id"),
+            ("GET", "{{baseUrl}}/admin/collection.json"),
+            ("GET", "{{baseUrl}}/metrics"),
+        ] {
+            assert!(
+                requests.contains(&(method.to_string(), raw.to_string())),
+                "{} {} is missing",
+                method,
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_requests_carry_variables_bodies_and_auth() {
+        let collection = collection("http://localhost:3000");
+        let fhir = &collection["item"][0];
+        assert_eq!(fhir["name"], "FHIR");
+        let item = |name: &str| {
+            fhir["item"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|item| item["name"] == name)
+                .unwrap()
+                .clone()
+        };
+
+        let put = item("PUT /fhir/Patient/:id");
+        assert_eq!(put["request"]["url"]["variable"][0]["key"], "id");
+        assert_eq!(
+            put["request"]["body"]["raw"],
+            r#"{"resourceType":"Patient"}"#
+        );
+        assert_eq!(
+            put["request"]["header"][0]["value"],
+            "application/fhir+json"
+        );
+        // Keyed routes inherit the bearer auth of the collection
+        assert!(put["request"].get("auth").is_none());
+
+        let metadata = item("GET /fhir/metadata");
+        assert_eq!(metadata["request"]["auth"]["type"], "noauth");
+        assert!(metadata["request"].get("body").is_none());
+    }
+}
@@ -0,0 +1,176 @@
+//! Conditional reads and searches.
+//!
+//! Reads answer with the version of the resource as a weak `ETag`, and
+//! searches with [`search_etag`]: a hash of the id and version of every
+//! match on the page, and the total, so it changes when a match is
+//! written, deleted or joins the page. A `GET` whose `If-None-Match` names
+//! the ETag of its answer gets `304 Not Modified` without a body instead;
+//! the handler still runs, so this saves polling clients the transfer, not
+//! the server the query.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Weak ETag of a searchset page with `total` and the matches `versions`,
+/// each an id and its version, in page order
+pub fn search_etag<I: fmt::Display, V: fmt::Display>(
+    total: Option<u32>,
+    versions: impl IntoIterator<Item = (I, V)>,
+) -> HeaderValue {
+    let mut hasher = Sha256::new();
+    if let Some(total) = total {
+        hasher.update(total.to_string());
+    }
+    for (id, version) in versions {
+        hasher.update(format!("\n{}/{}", id, version));
+    }
+    let digest = hasher.finalize();
+    let tag = digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    HeaderValue::from_str(&format!("W/\"{}\"", tag)).expect("hex is a valid header value")
+}
+
+/// An entity tag without its weak prefix, as the weak comparison of
+/// RFC 9110 compares them
+fn opaque_tag(tag: &str) -> &str {
+    let tag = tag.trim();
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// Whether an `If-None-Match` of `request_headers` names `etag`, or is `*`
+fn none_match_fails(request_headers: &HeaderMap, etag: &str) -> bool {
+    request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque_tag(tag) == opaque_tag(etag))
+}
+
+/// Answer a `GET` with `304 Not Modified` when its `If-None-Match` names
+/// the `ETag` of the response
+pub async fn not_modified(request: Request, next: Next) -> Response {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return next.run(request).await;
+    }
+    let request_headers = request.headers().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let fails = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .is_some_and(|etag| none_match_fails(&request_headers, etag));
+    if !fails {
+        return response;
+    }
+
+    let (mut parts, _) = response.into_parts();
+    parts.status = StatusCode::NOT_MODIFIED;
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Router};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_search_etag_follows_the_versions() {
+        let etag = search_etag(Some(2), [("a", "1"), ("b", "3")]);
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+        assert_eq!(etag, search_etag(Some(2), [("a", "1"), ("b", "3")]));
+        assert_ne!(etag, search_etag(Some(2), [("a", "1"), ("b", "4")]));
+        assert_ne!(etag, search_etag(Some(3), [("a", "1"), ("b", "3")]));
+        assert_ne!(etag, search_etag(None, [("a", "1"), ("b", "3")]));
+        assert_ne!(etag, search_etag(Some(2), [("b", "3"), ("a", "1")]));
+    }
+
+    #[test]
+    fn test_if_none_match_compares_weakly() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+            headers
+        };
+        assert!(none_match_fails(&headers("W/\"3\""), "W/\"3\""));
+        assert!(none_match_fails(&headers("\"3\""), "W/\"3\""));
+        assert!(none_match_fails(&headers("W/\"1\", W/\"3\""), "W/\"3\""));
+        assert!(none_match_fails(&headers("*"), "W/\"3\""));
+        assert!(!none_match_fails(&headers("W/\"2\""), "W/\"3\""));
+        assert!(!none_match_fails(&HeaderMap::new(), "W/\"3\""));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_searches_are_not_modified() {
+        // A search whose one match is at the version in `version`
+        let version = Arc::new(AtomicU32::new(1));
+        let search = {
+            let version = version.clone();
+            move || async move {
+                let etag = search_etag(Some(1), [("p1", version.load(Ordering::SeqCst))]);
+                ([(header::ETAG, etag)], r#"{"resourceType":"Bundle"}"#)
+            }
+        };
+        let app = Router::new()
+            .route(
+                "/fhir/Patient",
+                get(search).post(|| async { StatusCode::CREATED }),
+            )
+            .route(
+                "/fhir/Patient/missing",
+                get(|| async { (StatusCode::NOT_FOUND, [(header::ETAG, "W/\"1\"")]) }),
+            )
+            .layer(middleware::from_fn(not_modified));
+        let send = |method: &str, uri: &str, etag: Option<&HeaderValue>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let first = send("GET", "/fhir/Patient", None).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[header::ETAG].clone();
+        let polled = send("GET", "/fhir/Patient", Some(&etag)).await.unwrap();
+        assert_eq!(polled.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(polled.headers()[header::ETAG], etag);
+        assert!(polled.headers().get(header::CONTENT_TYPE).is_none());
+        let body = axum::body::to_bytes(polled.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+
+        // A new version of a match changes the search's ETag
+        version.store(2, Ordering::SeqCst);
+        let polled = send("GET", "/fhir/Patient", Some(&etag)).await.unwrap();
+        assert_eq!(polled.status(), StatusCode::OK);
+        assert_ne!(polled.headers()[header::ETAG], etag);
+
+        // Only successful reads are answered with 304, and only GET and HEAD
+        let any = HeaderValue::from_static("*");
+        let created = send("POST", "/fhir/Patient", Some(&any)).await.unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let missing = send("GET", "/fhir/Patient/missing", Some(&any))
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,813 @@
+use crate::access::AccessReasonConfig;
+use crate::api_keys::AuthConfig;
+use crate::archive::ArchiveConfig;
+use crate::capture::CaptureConfig;
+use crate::consent::ConsentConfig;
+use crate::db::conformance::is_conformance_type;
+use crate::deprecation::Deprecation;
+use crate::features::{FeatureProviderConfig, FeatureSetting};
+use crate::jobs::JobCallbackConfig;
+use crate::limits::ResourceLimits;
+use crate::roster::CsvMapping;
+use crate::search::matching::MatchConfig;
+use crate::sharing::SharingConfig;
+use crate::slo::LatencySlo;
+use crate::throttle::WriteLimit;
+use crate::transform::TransformConfig;
+use crate::validation::ValidationHookConfig;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the config file is polled for modifications
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Resource types with their own routes and handlers
+const DEDICATED_RESOURCE_TYPES: &[&str] = &["Patient", "Observation", "Provenance"];
+
+/// Runtime configuration loaded from the file named by `FHIR_CONFIG`.
+///
+/// Everything except `bind_address` and `profile_packs` is safe to change
+/// while running and is picked up on SIGHUP or when the file is modified.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// tracing filter directive, e.g. `info` or `info,fhir_server=debug`
+    pub log_level: String,
+    /// Listen address; only read at startup
+    pub bind_address: String,
+    /// `_count` used when the client does not send one
+    pub default_page_size: u32,
+    /// Upper bound applied to `_count`
+    pub max_page_size: u32,
+    /// Most patients a Patient search without criteria may match before it
+    /// is rejected as too costly; 0 allows any number
+    pub max_unfiltered_matches: u32,
+    /// Largest batch or transaction Bundle accepted, in bytes of JSON
+    pub max_bundle_bytes: usize,
+    /// Largest request body the other JSON handlers read, in bytes; larger
+    /// ones get 413. Routes with a limit of their own, such as package
+    /// uploads, keep it
+    pub max_body_bytes: usize,
+    /// Size, nesting and array limits of the resources clients send; see
+    /// [`crate::limits`]
+    pub resource_limits: ResourceLimits,
+    /// Allowed CORS origins; `*` allows any origin
+    pub cors_allowed_origins: Vec<String>,
+    /// Queries slower than this are logged with their EXPLAIN plan; 0 disables
+    pub slow_query_threshold_ms: u64,
+    /// Identifier systems whose values may be held by only one patient.
+    /// The server is single-tenant, so uniqueness applies to the whole store.
+    pub unique_identifier_systems: Vec<String>,
+    /// Replay Patient writes through the extension functions and log where
+    /// they disagree with the direct SQL write
+    pub shadow_verify: bool,
+    /// Reject Patient PUTs and PATCHes that carry no `If-Match` with 428
+    pub require_if_match: bool,
+    /// Days an id from `$reserve-id` stays reserved for its PUT
+    pub id_reservation_days: u32,
+    /// Minutes a `$lock` holds a patient unless renewed, and the most a
+    /// `$lock` may ask for
+    pub record_lock_minutes: u32,
+    /// Allow `POST /admin/snapshots/:name/restore`, which replaces the whole
+    /// dataset; meant for test environments only
+    pub allow_snapshot_restore: bool,
+    /// Directory `Prefer: respond-async` searches and `$export` write their
+    /// NDJSON files to
+    pub export_dir: PathBuf,
+    /// Signed notifications to an `X-Callback-Url` when a job finishes
+    pub job_callbacks: JobCallbackConfig,
+    /// WebAssembly modules run on every create/update
+    pub validation_hooks: Vec<ValidationHookConfig>,
+    /// `modifierExtension` URLs writes may carry; any other modifier
+    /// extension rejects the write
+    pub understood_modifier_extensions: Vec<String>,
+    /// Implementation guide packages (`.tgz`) whose search parameters and
+    /// profiles are loaded at startup; see [`crate::profiles`]
+    pub profile_packs: Vec<PathBuf>,
+    /// Also check cardinality limits, fixed values, stored profiles and the
+    /// base definitions of loaded packs on writes; see [`crate::profiles`]
+    pub profile_validation: bool,
+    /// Ordered stages applied to resources returned by reads and searches
+    pub response_transforms: Vec<TransformConfig>,
+    /// Additional R4 resource types served generically at `/fhir/:resourceType`.
+    /// Patient, Observation and the conformance resources are always available.
+    pub resource_types: Vec<String>,
+    /// Storage of failed interactions for `GET /admin/requests/:id`
+    pub request_capture: CaptureConfig,
+    /// Reason-for-access enforcement on reads of restricted patients
+    pub access_reason: AccessReasonConfig,
+    /// Enforcement of stored Consent resources; see [`crate::consent`]
+    pub consent: ConsentConfig,
+    /// Which routes need an API key; see [`crate::api_keys`]
+    pub auth: AuthConfig,
+    /// Signed, expiring links to one patient for readers without accounts
+    pub sharing: SharingConfig,
+    /// Weights and grade thresholds of `Patient/$match`
+    pub patient_match: MatchConfig,
+    /// Write rate limits keyed by resource type
+    pub write_limits: BTreeMap<String, WriteLimit>,
+    /// Latency objectives keyed by interaction; see [`crate::slo`]
+    pub latency_slos: BTreeMap<String, LatencySlo>,
+    /// Column mappings of the CSV rosters `POST /admin/import/csv` loads,
+    /// by name; see [`crate::roster`]
+    pub csv_mappings: BTreeMap<String, CsvMapping>,
+    /// Deprecated routes and parameters, by feature name; see
+    /// [`crate::deprecation`]
+    pub deprecations: BTreeMap<String, Deprecation>,
+    /// Moving resources not written for years to `fhir.resource_archive`
+    pub archive: ArchiveConfig,
+    /// Named feature flags, on, off or rolled out to some requests
+    pub features: BTreeMap<String, FeatureSetting>,
+    /// Remote source of feature flag overrides
+    pub feature_provider: FeatureProviderConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            bind_address: "0.0.0.0:3000".to_string(),
+            default_page_size: 20,
+            max_page_size: 100,
+            max_unfiltered_matches: 0,
+            max_bundle_bytes: 64 * 1024 * 1024,
+            max_body_bytes: 4 * 1024 * 1024,
+            resource_limits: ResourceLimits::default(),
+            cors_allowed_origins: vec!["*".to_string()],
+            slow_query_threshold_ms: 0,
+            unique_identifier_systems: Vec::new(),
+            shadow_verify: false,
+            require_if_match: false,
+            id_reservation_days: 30,
+            record_lock_minutes: 30,
+            allow_snapshot_restore: false,
+            export_dir: std::env::temp_dir().join("fhir-exports"),
+            job_callbacks: JobCallbackConfig::default(),
+            validation_hooks: Vec::new(),
+            understood_modifier_extensions: Vec::new(),
+            profile_packs: Vec::new(),
+            profile_validation: false,
+            response_transforms: Vec::new(),
+            resource_types: Vec::new(),
+            request_capture: CaptureConfig::default(),
+            access_reason: AccessReasonConfig::default(),
+            consent: ConsentConfig::default(),
+            auth: AuthConfig::default(),
+            sharing: SharingConfig::default(),
+            patient_match: MatchConfig::default(),
+            write_limits: BTreeMap::new(),
+            latency_slos: BTreeMap::new(),
+            csv_mappings: BTreeMap::new(),
+            deprecations: BTreeMap::new(),
+            archive: ArchiveConfig::default(),
+            features: BTreeMap::new(),
+            feature_provider: FeatureProviderConfig::default(),
+        }
+    }
+}
+
+/// A single setting whose value differs between two configurations
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    pub setting: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl ServerConfig {
+    /// Parse a TOML config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load from `FHIR_CONFIG` if set, otherwise use defaults
+    pub fn from_env() -> Result<(Self, Option<PathBuf>)> {
+        match std::env::var("FHIR_CONFIG") {
+            Ok(path) => {
+                let path = PathBuf::from(path);
+                Ok((Self::load(&path)?, Some(path)))
+            }
+            Err(_) => Ok((Self::default(), None)),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max_page_size == 0 {
+            anyhow::bail!("max_page_size must be greater than 0");
+        }
+        if self.default_page_size > self.max_page_size {
+            anyhow::bail!("default_page_size must not exceed max_page_size");
+        }
+        if self.max_bundle_bytes == 0 {
+            anyhow::bail!("max_bundle_bytes must be greater than 0");
+        }
+        if self.max_body_bytes == 0 {
+            anyhow::bail!("max_body_bytes must be greater than 0");
+        }
+        self.resource_limits.validate()?;
+        if self.id_reservation_days == 0 {
+            anyhow::bail!("id_reservation_days must be greater than 0");
+        }
+        if self.record_lock_minutes == 0 {
+            anyhow::bail!("record_lock_minutes must be greater than 0");
+        }
+        self.sharing.validate()?;
+        self.auth.validate()?;
+        self.patient_match.validate()?;
+        self.job_callbacks.validate()?;
+        if self.request_capture.capacity == 0 {
+            anyhow::bail!("request_capture.capacity must be greater than 0");
+        }
+        if self.archive.after_years == 0
+            || self.archive.interval_secs == 0
+            || self.archive.batch_size == 0
+        {
+            anyhow::bail!(
+                "archive: after_years, interval_secs and batch_size must be greater than 0"
+            );
+        }
+        for (name, setting) in &self.features {
+            if let FeatureSetting::Rollout(rollout) = setting {
+                if rollout.percentage > 100 {
+                    anyhow::bail!("features.{}: percentage must not exceed 100", name);
+                }
+            }
+        }
+        if self.feature_provider.interval_secs == 0 {
+            anyhow::bail!("feature_provider.interval_secs must be greater than 0");
+        }
+        for resource_type in &self.resource_types {
+            if !crate::models::is_resource_type(resource_type) {
+                anyhow::bail!(
+                    "resource_types: {} is not a FHIR R4 resource type",
+                    resource_type
+                );
+            }
+            if DEDICATED_RESOURCE_TYPES.contains(&resource_type.as_str()) {
+                anyhow::bail!("resource_types: {} has dedicated endpoints", resource_type);
+            }
+            if is_conformance_type(resource_type) {
+                anyhow::bail!(
+                    "resource_types: {} is always served as a conformance resource",
+                    resource_type
+                );
+            }
+        }
+        for (resource_type, limit) in &self.write_limits {
+            if !crate::models::is_resource_type(resource_type) {
+                anyhow::bail!(
+                    "write_limits: {} is not a FHIR R4 resource type",
+                    resource_type
+                );
+            }
+            if limit.per_second.is_nan() || limit.per_second <= 0.0 || limit.burst == 0 {
+                anyhow::bail!(
+                    "write_limits.{}: per_second and burst must be greater than 0",
+                    resource_type
+                );
+            }
+        }
+        for (interaction, slo) in &self.latency_slos {
+            slo.validate(interaction)?;
+        }
+        for (name, mapping) in &self.csv_mappings {
+            mapping.validate(name)?;
+        }
+        for (feature, deprecation) in &self.deprecations {
+            deprecation.validate(feature)?;
+        }
+        Ok(())
+    }
+
+    /// Effective page size for a requested `_count`
+    pub fn page_size(&self, requested: Option<u32>) -> u32 {
+        requested
+            .unwrap_or(self.default_page_size)
+            .min(self.max_page_size)
+    }
+
+    /// Whether `resource_type` is served by the generic resource endpoints:
+    /// the conformance resources and those listed in `resource_types`
+    pub fn serves_resource_type(&self, resource_type: &str) -> bool {
+        is_conformance_type(resource_type) || self.resource_types.iter().any(|t| t == resource_type)
+    }
+
+    pub fn cors_allows(&self, origin: &str) -> bool {
+        self.cors_allowed_origins
+            .iter()
+            .any(|o| o == "*" || o == origin)
+    }
+
+    /// Whether feature `name` is on for every request; see
+    /// [`crate::features`] for per-request decisions
+    pub fn feature_enabled(&self, name: &str) -> bool {
+        self.features.get(name).is_some_and(FeatureSetting::enabled)
+    }
+
+    /// List of settings that differ from `other`
+    pub fn diff(&self, other: &ServerConfig) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        let mut push = |setting: &str, old: String, new: String| {
+            if old != new {
+                changes.push(ConfigChange {
+                    setting: setting.to_string(),
+                    old,
+                    new,
+                });
+            }
+        };
+
+        push("log_level", self.log_level.clone(), other.log_level.clone());
+        push(
+            "bind_address",
+            self.bind_address.clone(),
+            other.bind_address.clone(),
+        );
+        push(
+            "default_page_size",
+            self.default_page_size.to_string(),
+            other.default_page_size.to_string(),
+        );
+        push(
+            "max_page_size",
+            self.max_page_size.to_string(),
+            other.max_page_size.to_string(),
+        );
+        push(
+            "max_unfiltered_matches",
+            self.max_unfiltered_matches.to_string(),
+            other.max_unfiltered_matches.to_string(),
+        );
+        push(
+            "max_bundle_bytes",
+            self.max_bundle_bytes.to_string(),
+            other.max_bundle_bytes.to_string(),
+        );
+        push(
+            "max_body_bytes",
+            self.max_body_bytes.to_string(),
+            other.max_body_bytes.to_string(),
+        );
+        push(
+            "resource_limits",
+            format!("{:?}", self.resource_limits),
+            format!("{:?}", other.resource_limits),
+        );
+        push(
+            "cors_allowed_origins",
+            self.cors_allowed_origins.join(","),
+            other.cors_allowed_origins.join(","),
+        );
+        push(
+            "slow_query_threshold_ms",
+            self.slow_query_threshold_ms.to_string(),
+            other.slow_query_threshold_ms.to_string(),
+        );
+        push(
+            "unique_identifier_systems",
+            self.unique_identifier_systems.join(","),
+            other.unique_identifier_systems.join(","),
+        );
+        push(
+            "shadow_verify",
+            self.shadow_verify.to_string(),
+            other.shadow_verify.to_string(),
+        );
+        push(
+            "require_if_match",
+            self.require_if_match.to_string(),
+            other.require_if_match.to_string(),
+        );
+        push(
+            "id_reservation_days",
+            self.id_reservation_days.to_string(),
+            other.id_reservation_days.to_string(),
+        );
+        push(
+            "record_lock_minutes",
+            self.record_lock_minutes.to_string(),
+            other.record_lock_minutes.to_string(),
+        );
+        push(
+            "allow_snapshot_restore",
+            self.allow_snapshot_restore.to_string(),
+            other.allow_snapshot_restore.to_string(),
+        );
+        push(
+            "export_dir",
+            self.export_dir.display().to_string(),
+            other.export_dir.display().to_string(),
+        );
+        push(
+            "job_callbacks",
+            format!("{:?}", self.job_callbacks),
+            format!("{:?}", other.job_callbacks),
+        );
+        let hooks = |config: &ServerConfig| {
+            config
+                .validation_hooks
+                .iter()
+                .map(|h| format!("{}={}@{}", h.name, h.module.display(), h.fuel))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        push("validation_hooks", hooks(self), hooks(other));
+        push(
+            "understood_modifier_extensions",
+            self.understood_modifier_extensions.join(","),
+            other.understood_modifier_extensions.join(","),
+        );
+        push(
+            "profile_packs",
+            format!("{:?}", self.profile_packs),
+            format!("{:?}", other.profile_packs),
+        );
+        push(
+            "profile_validation",
+            self.profile_validation.to_string(),
+            other.profile_validation.to_string(),
+        );
+        push(
+            "response_transforms",
+            format!("{:?}", self.response_transforms),
+            format!("{:?}", other.response_transforms),
+        );
+        push(
+            "resource_types",
+            self.resource_types.join(","),
+            other.resource_types.join(","),
+        );
+        push(
+            "request_capture",
+            format!("{:?}", self.request_capture),
+            format!("{:?}", other.request_capture),
+        );
+        push(
+            "access_reason",
+            format!("{:?}", self.access_reason),
+            format!("{:?}", other.access_reason),
+        );
+        push(
+            "consent",
+            format!("{:?}", self.consent),
+            format!("{:?}", other.consent),
+        );
+        push(
+            "auth",
+            format!("{:?}", self.auth),
+            format!("{:?}", other.auth),
+        );
+        push(
+            "patient_match",
+            format!("{:?}", self.patient_match),
+            format!("{:?}", other.patient_match),
+        );
+        push(
+            "sharing",
+            format!("{:?}", self.sharing),
+            format!("{:?}", other.sharing),
+        );
+        push(
+            "write_limits",
+            format!("{:?}", self.write_limits),
+            format!("{:?}", other.write_limits),
+        );
+        push(
+            "latency_slos",
+            format!("{:?}", self.latency_slos),
+            format!("{:?}", other.latency_slos),
+        );
+        push(
+            "csv_mappings",
+            format!("{:?}", self.csv_mappings),
+            format!("{:?}", other.csv_mappings),
+        );
+        push(
+            "deprecations",
+            format!("{:?}", self.deprecations),
+            format!("{:?}", other.deprecations),
+        );
+        push(
+            "archive",
+            format!("{:?}", self.archive),
+            format!("{:?}", other.archive),
+        );
+
+        let names: std::collections::BTreeSet<&String> =
+            self.features.keys().chain(other.features.keys()).collect();
+        for name in names {
+            push(
+                &format!("features.{}", name),
+                format!("{:?}", self.features.get(name)),
+                format!("{:?}", other.features.get(name)),
+            );
+        }
+        push(
+            "feature_provider",
+            format!("{:?}", self.feature_provider),
+            format!("{:?}", other.feature_provider),
+        );
+
+        changes
+    }
+}
+
+/// Settings that only take effect after a restart
+const RESTART_REQUIRED: &[&str] = &["bind_address", "profile_packs"];
+
+/// Shared, swappable configuration read by handlers on every request
+#[derive(Debug, Default)]
+pub struct SharedConfig {
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Apply the safe-to-change settings of `new`, keeping restart-only
+    /// settings at their current values. Returns the applied changes.
+    pub fn apply(&self, mut new: ServerConfig) -> Vec<ConfigChange> {
+        let mut current = self.current.write().unwrap();
+        let changes = current.diff(&new);
+
+        for change in &changes {
+            if RESTART_REQUIRED.contains(&change.setting.as_str()) {
+                tracing::warn!(
+                    target: "fhir_server::audit",
+                    setting = %change.setting,
+                    "configuration change requires a restart and was not applied"
+                );
+            }
+        }
+        new.bind_address = current.bind_address.clone();
+        new.profile_packs = current.profile_packs.clone();
+
+        let applied: Vec<ConfigChange> = changes
+            .into_iter()
+            .filter(|c| !RESTART_REQUIRED.contains(&c.setting.as_str()))
+            .collect();
+        for change in &applied {
+            tracing::info!(
+                target: "fhir_server::audit",
+                setting = %change.setting,
+                old = %change.old,
+                new = %change.new,
+                "configuration changed"
+            );
+        }
+
+        *current = Arc::new(new);
+        applied
+    }
+}
+
+/// Reload `path` into `shared` on SIGHUP or when the file changes.
+///
+/// `on_change` is invoked with the new config and the applied changes so
+/// the caller can push settings that live outside `SharedConfig` (tracing
+/// filter, database thresholds).
+pub fn spawn_watcher<F>(path: PathBuf, shared: Arc<SharedConfig>, on_change: F)
+where
+    F: Fn(&ServerConfig, &[ConfigChange]) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_modified = modified_time(&path);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        #[cfg(unix)]
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+
+        loop {
+            #[cfg(unix)]
+            let forced = tokio::select! {
+                _ = interval.tick() => false,
+                Some(_) = async {
+                    match hangup.as_mut() {
+                        Some(signal) => signal.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => true,
+            };
+            #[cfg(not(unix))]
+            let forced = {
+                interval.tick().await;
+                false
+            };
+
+            let modified = modified_time(&path);
+            if !forced && modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            match ServerConfig::load(&path) {
+                Ok(new) => {
+                    let changes = shared.apply(new);
+                    if !changes.is_empty() {
+                        on_change(&shared.get(), &changes);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Keeping previous configuration: {:#}", e);
+                }
+            }
+        }
+    });
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_match_previous_hardcoded_limits() {
+        let config = ServerConfig::default();
+        assert_eq!(config.page_size(None), 20);
+        assert_eq!(config.page_size(Some(500)), 100);
+        assert!(config.cors_allows("https://anything.example"));
+    }
+
+    #[test]
+    fn test_parse_partial_file() {
+        let config = ServerConfig::parse(
+            r#"
+            max_page_size = 50
+            cors_allowed_origins = ["https://app.example"]
+
+            [features]
+            strict_validation = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.default_page_size, 20);
+        assert_eq!(config.page_size(Some(80)), 50);
+        assert!(config.cors_allows("https://app.example"));
+        assert!(!config.cors_allows("https://evil.example"));
+        assert!(config.feature_enabled("strict_validation"));
+        assert!(!config.feature_enabled("unknown"));
+    }
+
+    #[test]
+    fn test_parse_rejects_inconsistent_page_sizes() {
+        assert!(ServerConfig::parse("default_page_size = 200\nmax_page_size = 100").is_err());
+        assert!(ServerConfig::parse("max_bundle_bytes = 0").is_err());
+        assert!(ServerConfig::parse("max_body_bytes = 0").is_err());
+        assert!(ServerConfig::parse("id_reservation_days = 0").is_err());
+        assert!(ServerConfig::parse("record_lock_minutes = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_validates_resource_types() {
+        let config = ServerConfig::parse(r#"resource_types = ["Encounter", "Condition"]"#).unwrap();
+        assert!(config.serves_resource_type("Encounter"));
+        assert!(!config.serves_resource_type("Procedure"));
+
+        assert!(ServerConfig::parse(r#"resource_types = ["Encountr"]"#).is_err());
+        assert!(ServerConfig::parse(r#"resource_types = ["Patient"]"#).is_err());
+        assert!(ServerConfig::parse(r#"resource_types = ["ValueSet"]"#).is_err());
+        assert!(config.serves_resource_type("StructureDefinition"));
+    }
+
+    #[test]
+    fn test_parse_request_capture_section() {
+        let config = ServerConfig::parse("[request_capture]\nenabled = true").unwrap();
+        assert!(config.request_capture.enabled);
+        assert_eq!(config.request_capture.capacity, 500);
+
+        assert!(ServerConfig::parse("[request_capture]\ncapacity = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_resource_limits_section() {
+        let config = ServerConfig::parse("[resource_limits]\nmax_depth = 32").unwrap();
+        assert_eq!(config.resource_limits.max_depth, 32);
+        assert_eq!(config.resource_limits.max_array_length, 10_000);
+
+        assert!(ServerConfig::parse("[resource_limits]\nmax_depth = 200").is_err());
+        assert!(ServerConfig::parse("[resource_limits]\nmax_bytes = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_archive_section() {
+        let config = ServerConfig::parse("[archive]\nenabled = true\nafter_years = 7").unwrap();
+        assert!(config.archive.enabled);
+        assert_eq!(config.archive.after_years, 7);
+        assert_eq!(config.archive.batch_size, 500);
+
+        assert!(ServerConfig::parse("[archive]\nafter_years = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_csv_mappings() {
+        let config = ServerConfig::parse(
+            "[csv_mappings.registration]\ncolumns = { MRN = \"identifier.value\", DOB = \"birthDate\" }\ndelimiter = \";\"",
+        )
+        .unwrap();
+        let mapping = &config.csv_mappings["registration"];
+        assert_eq!(mapping.columns["DOB"], "birthDate");
+        assert_eq!(mapping.delimiter, ';');
+        assert!(mapping.fixed.is_empty());
+
+        assert!(ServerConfig::parse(
+            "[csv_mappings.registration]\ncolumns = { DOB = \"birthDate[0]\" }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_deprecations() {
+        let config = ServerConfig::parse(
+            "[deprecations.offset-paging]\nparameter = \"_offset\"\nsince = \"2026-07-01\"\nsunset = \"2027-01-01\"",
+        )
+        .unwrap();
+        let deprecation = &config.deprecations["offset-paging"];
+        assert_eq!(deprecation.parameter.as_deref(), Some("_offset"));
+        assert!(deprecation.route.is_none());
+        assert_eq!(deprecation.since.to_string(), "2026-07-01");
+
+        assert!(ServerConfig::parse(
+            "[deprecations.old]\nroute = \"/fhir/Nope\"\nsince = \"2026-07-01\""
+        )
+        .is_err());
+        assert!(ServerConfig::parse("[deprecations.old]\nparameter = \"_offset\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_feature_rollouts() {
+        let config = ServerConfig::parse(
+            "[features]\nold = true\n[features.strict_search]\npercentage = 10\ntenants = [\"north\"]",
+        )
+        .unwrap();
+        assert!(config.feature_enabled("old"));
+        assert!(!config.feature_enabled("strict_search"));
+        assert!(matches!(
+            &config.features["strict_search"],
+            FeatureSetting::Rollout(rollout) if rollout.percentage == 10
+        ));
+
+        assert!(ServerConfig::parse("[features.strict_search]\npercentage = 101").is_err());
+        assert!(ServerConfig::parse("[features.strict_search]\npercent = 10").is_err());
+    }
+
+    #[test]
+    fn test_parse_write_limits() {
+        let config =
+            ServerConfig::parse("[write_limits.Patient]\nper_second = 5.0\nanonymous_only = true")
+                .unwrap();
+        let limit = &config.write_limits["Patient"];
+        assert_eq!(limit.per_second, 5.0);
+        assert_eq!(limit.burst, 20);
+        assert!(limit.anonymous_only);
+
+        assert!(ServerConfig::parse("[write_limits.Patients]\nburst = 1").is_err());
+        assert!(ServerConfig::parse("[write_limits.Patient]\nper_second = 0.0").is_err());
+    }
+
+    #[test]
+    fn test_apply_reports_changes_and_keeps_restart_only_settings() {
+        let shared = SharedConfig::new(ServerConfig::default());
+        let new = ServerConfig {
+            log_level: "debug".to_string(),
+            bind_address: "127.0.0.1:4000".to_string(),
+            profile_packs: vec![PathBuf::from("hl7.fhir.us.core.tgz")],
+            features: BTreeMap::from([("strict_validation".to_string(), true.into())]),
+            ..Default::default()
+        };
+
+        let changes = shared.apply(new);
+        let settings: Vec<&str> = changes.iter().map(|c| c.setting.as_str()).collect();
+
+        assert_eq!(settings, vec!["log_level", "features.strict_validation"]);
+        assert_eq!(shared.get().log_level, "debug");
+        assert_eq!(shared.get().bind_address, "0.0.0.0:3000");
+        assert!(shared.get().profile_packs.is_empty());
+        assert!(shared.get().feature_enabled("strict_validation"));
+    }
+}
@@ -0,0 +1,813 @@
+//! `fhir-server conformance-test --against <url>`: drive a FHIR server with
+//! [`fhir_client`] the way an integration would, and report where its
+//! answers deviate from the FHIR REST specification.
+//!
+//! `<url>` is the FHIR base, the URL `metadata` is read below. The run
+//! reads the CapabilityStatement, then creates a Patient with a random
+//! family name and an Observation about it, and reads, updates, patches,
+//! searches and deletes them, reading the Patient's history on the way.
+//! Interactions the CapabilityStatement does not declare for a type are
+//! skipped rather than counted against the server. Every step is a line of
+//! the report; a deviation names what the specification expects. Pointed at
+//! this server (`--against http://localhost:3000/fhir`) it doubles as a
+//! self-test of a deployment. What the run creates is deleted at the end.
+
+use crate::models::{
+    CodeableConcept, Coding, HumanName, Identifier, Observation, Patient, Reference,
+};
+use fhir_client::{Error, FhirClient};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Identifier system of the Patients the run creates
+pub const TEST_SYSTEM: &str = "urn:fhir-server:conformance-test";
+
+/// What to test and how to reach it
+#[derive(Debug, Clone)]
+pub struct ConformanceOptions {
+    /// The FHIR base URL
+    pub against: String,
+    /// Sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// Per request
+    pub timeout: Duration,
+}
+
+impl ConformanceOptions {
+    pub fn new(against: impl Into<String>) -> Self {
+        Self {
+            against: against.into(),
+            token: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How the server did in one step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Conforms,
+    /// What the specification expects and what the server did instead
+    Deviates(String),
+    /// Why the step was not run
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Conforms => write!(f, "[ok]   {}", self.name),
+            Outcome::Deviates(e) => write!(f, "[DEV]  {}: {}", self.name, e),
+            Outcome::Skipped(reason) => write!(f, "[skip] {}: {}", self.name, reason),
+        }
+    }
+}
+
+/// The steps of one run, in order
+#[derive(Debug, Default)]
+pub struct ConformanceReport {
+    pub steps: Vec<Step>,
+}
+
+impl ConformanceReport {
+    pub fn deviations(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step.outcome, Outcome::Deviates(_)))
+            .count()
+    }
+
+    pub fn conforms(&self) -> bool {
+        self.deviations() == 0
+    }
+
+    fn record(&mut self, name: impl Into<String>, outcome: Result<(), String>) -> bool {
+        let conforms = outcome.is_ok();
+        self.steps.push(Step {
+            name: name.into(),
+            outcome: outcome.map_or_else(Outcome::Deviates, |_| Outcome::Conforms),
+        });
+        conforms
+    }
+
+    fn skip(&mut self, name: impl Into<String>, reason: impl Into<String>) {
+        self.steps.push(Step {
+            name: name.into(),
+            outcome: Outcome::Skipped(reason.into()),
+        });
+    }
+}
+
+/// The interactions a CapabilityStatement declares, by resource type;
+/// `None` when it could not be read, in which case everything is tried
+#[derive(Debug, Clone, Default)]
+struct Declared(Option<Vec<(String, BTreeSet<String>)>>);
+
+impl Declared {
+    fn from_capabilities(statement: &Value) -> Self {
+        let resources = statement["rest"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|rest| rest["mode"] == "server")
+            .flat_map(|rest| rest["resource"].as_array().into_iter().flatten())
+            .filter_map(|resource| {
+                let interactions = resource["interaction"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|interaction| interaction["code"].as_str())
+                    .map(String::from)
+                    .collect();
+                Some((resource["type"].as_str()?.to_string(), interactions))
+            })
+            .collect();
+        Declared(Some(resources))
+    }
+
+    /// Why `interaction` on `resource_type` is not tested, if it is not
+    fn missing(&self, resource_type: &str, interaction: &str) -> Option<String> {
+        let resources = self.0.as_ref()?;
+        let declared = resources
+            .iter()
+            .find(|(declared, _)| declared == resource_type)
+            .is_some_and(|(_, interactions)| interactions.contains(interaction));
+        (!declared).then(|| {
+            format!(
+                "the CapabilityStatement does not declare {} for {}",
+                interaction, resource_type
+            )
+        })
+    }
+}
+
+/// What the specification requires of a CapabilityStatement the run relies
+/// on
+pub fn capability_deviations(statement: &Value) -> Result<(), String> {
+    let mut deviations = Vec::new();
+    if statement["resourceType"] != "CapabilityStatement" {
+        deviations.push(format!(
+            "metadata returned {} instead of a CapabilityStatement",
+            statement["resourceType"]
+        ));
+    }
+    for element in ["status", "date", "kind", "fhirVersion"] {
+        if statement[element].as_str().is_none_or(str::is_empty) {
+            deviations.push(format!("{} is required", element));
+        }
+    }
+    let json = statement["format"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .any(|format| format == "json" || format.ends_with("+json"));
+    if !json {
+        deviations.push("format does not list json".to_string());
+    }
+    let servers = statement["rest"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|rest| rest["mode"] == "server")
+        .count();
+    if servers == 0 {
+        deviations.push("no rest entry has mode server".to_string());
+    }
+    joined(deviations)
+}
+
+/// What every resource returned by a create, read or update needs
+fn resource_deviations(resource: &Value, resource_type: &str) -> Vec<String> {
+    let mut deviations = Vec::new();
+    if resource["resourceType"] != resource_type {
+        deviations.push(format!(
+            "resourceType is {} instead of {}",
+            resource["resourceType"], resource_type
+        ));
+    }
+    if resource["id"].as_str().is_none_or(str::is_empty) {
+        deviations.push("no id was assigned".to_string());
+    }
+    if resource["meta"]["versionId"].as_str().is_none() {
+        deviations.push("meta.versionId is missing".to_string());
+    }
+    if resource["meta"]["lastUpdated"].as_str().is_none() {
+        deviations.push("meta.lastUpdated is missing".to_string());
+    }
+    deviations
+}
+
+/// What a Bundle of `bundle_type` holding the resource `id` needs
+fn bundle_deviations(bundle: &Value, bundle_type: &str, id: &str) -> Vec<String> {
+    let mut deviations = Vec::new();
+    if bundle["resourceType"] != "Bundle" {
+        deviations.push(format!(
+            "returned {} instead of a Bundle",
+            bundle["resourceType"]
+        ));
+    }
+    if bundle["type"] != bundle_type {
+        deviations.push(format!(
+            "Bundle.type is {} instead of {}",
+            bundle["type"], bundle_type
+        ));
+    }
+    let entries = bundle["entry"].as_array().map_or(&[][..], Vec::as_slice);
+    if !entries.iter().any(|entry| entry["resource"]["id"] == id) {
+        deviations.push(format!("no entry holds {}", id));
+    }
+    deviations
+}
+
+fn joined(deviations: Vec<String>) -> Result<(), String> {
+    if deviations.is_empty() {
+        Ok(())
+    } else {
+        Err(deviations.join("; "))
+    }
+}
+
+/// A client error as a deviation from `expected`
+fn failed(expected: &str, e: Error) -> String {
+    format!("expected {}, {}", expected, e)
+}
+
+fn to_value(resource: &impl serde::Serialize) -> Value {
+    serde_json::to_value(resource).unwrap_or(Value::Null)
+}
+
+/// Run every step against `options.against`
+pub async fn run(options: &ConformanceOptions) -> ConformanceReport {
+    let mut report = ConformanceReport::default();
+    let mut headers = HeaderMap::new();
+    if let Some(token) = &options.token {
+        match HeaderValue::from_str(&format!("Bearer {}", token)) {
+            Ok(value) => {
+                headers.insert(AUTHORIZATION, value);
+            }
+            Err(_) => {
+                report.record(
+                    "client",
+                    Err("the token is not a valid header value".into()),
+                );
+                return report;
+            }
+        }
+    }
+    let http = match reqwest::Client::builder()
+        .timeout(options.timeout)
+        .default_headers(headers)
+        .build()
+    {
+        Ok(http) => http,
+        Err(e) => {
+            report.record("client", Err(e.to_string()));
+            return report;
+        }
+    };
+    let client = FhirClient::with_fhir_base(http, options.against.as_str());
+
+    let declared = match client.capabilities().await {
+        Ok(statement) => {
+            report.record("capabilities", capability_deviations(&statement));
+            Declared::from_capabilities(&statement)
+        }
+        Err(e) => {
+            report.record("capabilities", Err(failed("a CapabilityStatement", e)));
+            Declared::default()
+        }
+    };
+
+    let family = format!("Conformance{}", Uuid::new_v4().simple());
+    let Some(patient) = patient_steps(&client, &declared, &mut report, &family).await else {
+        return report;
+    };
+    let patient_id = patient["id"].as_str().unwrap_or_default().to_string();
+    let observation = observation_steps(&client, &declared, &mut report, &patient_id).await;
+    error_steps(&client, &declared, &mut report, &patient_id).await;
+
+    if let Some(id) = observation {
+        let deleted = client.delete_observation(&id).await;
+        report.record(
+            "delete Observation",
+            match deleted {
+                Ok(true) => Ok(()),
+                Ok(false) => Err("expected the Observation to be deleted, got 404".into()),
+                Err(e) => Err(failed("a 200 or 204", e)),
+            },
+        );
+    }
+    delete_steps(&client, &declared, &mut report, &patient_id).await;
+
+    report
+}
+
+/// Create, read, update, patch, search and history of a Patient; the
+/// Patient as created, if that worked
+async fn patient_steps(
+    client: &FhirClient,
+    declared: &Declared,
+    report: &mut ConformanceReport,
+    family: &str,
+) -> Option<Value> {
+    if let Some(reason) = declared.missing("Patient", "create") {
+        report.skip("create Patient", reason);
+        return None;
+    }
+    let patient = Patient {
+        identifier: Some(vec![Identifier {
+            use_: None,
+            system: Some(TEST_SYSTEM.to_string()),
+            value: Some(family.to_string()),
+            extra: Map::new(),
+        }]),
+        name: Some(vec![HumanName {
+            family: Some(family.to_string()),
+            given: Some(vec!["Ada".to_string()]),
+            text: None,
+            extra: Map::new(),
+        }]),
+        gender: Some("female".to_string()),
+        birth_date: Some("1990-04-01".to_string()),
+        ..Patient::new()
+    };
+    let created = match client.create_patient(&patient).await {
+        Ok(created) => to_value(&created),
+        Err(e) => {
+            report.record("create Patient", Err(failed("a 201 with the Patient", e)));
+            return None;
+        }
+    };
+    let mut deviations = resource_deviations(&created, "Patient");
+    if created["name"][0]["family"] != family {
+        deviations.push("the stored name differs from the one sent".to_string());
+    }
+    if !report.record("create Patient", joined(deviations)) {
+        return None;
+    }
+    let id = created["id"].as_str().unwrap_or_default().to_string();
+    let version = created["meta"]["versionId"].clone();
+
+    if let Some(reason) = declared.missing("Patient", "read") {
+        report.skip("read Patient", reason);
+    } else {
+        let outcome = match client.get_patient(&id).await {
+            Ok(Some(read)) => {
+                let read = to_value(&read);
+                let mut deviations = resource_deviations(&read, "Patient");
+                if read["id"] != id.as_str() {
+                    deviations.push(format!("returned {} instead of {}", read["id"], id));
+                }
+                if read["meta"]["versionId"] != version {
+                    deviations.push("meta.versionId differs from the create".to_string());
+                }
+                joined(deviations)
+            }
+            Ok(None) => Err("expected the created Patient, got 404".to_string()),
+            Err(e) => Err(failed("a 200 with the Patient", e)),
+        };
+        report.record("read Patient", outcome);
+
+        let unknown = client.get_patient(&Uuid::new_v4().to_string()).await;
+        report.record(
+            "read unknown Patient",
+            match unknown {
+                Ok(None) => Ok(()),
+                Ok(Some(_)) => Err("expected 404, got a Patient".to_string()),
+                Err(e) => Err(failed("a 404", e)),
+            },
+        );
+    }
+
+    if let Some(reason) = declared.missing("Patient", "update") {
+        report.skip("update Patient", reason);
+    } else {
+        let mut changed: Patient = serde_json::from_value(created.clone()).ok()?;
+        changed.gender = Some("other".to_string());
+        let outcome = match client.update_patient(&id, &changed).await {
+            Ok(updated) => {
+                let updated = to_value(&updated);
+                let mut deviations = resource_deviations(&updated, "Patient");
+                if updated["meta"]["versionId"] == version {
+                    deviations.push("meta.versionId did not change".to_string());
+                }
+                if updated["gender"] != "other" {
+                    deviations.push("the update was not applied".to_string());
+                }
+                joined(deviations)
+            }
+            Err(e) => Err(failed("a 200 with the updated Patient", e)),
+        };
+        report.record("update Patient", outcome);
+    }
+
+    if let Some(reason) = declared.missing("Patient", "patch") {
+        report.skip("patch Patient", reason);
+    } else {
+        let patch = json!([{ "op": "replace", "path": "/birthDate", "value": "1990-04-02" }]);
+        let outcome = match client.patch_patient(&id, &patch).await {
+            Ok(patched) if patched.birth_date.as_deref() == Some("1990-04-02") => Ok(()),
+            Ok(_) => Err("the JSON Patch was not applied".to_string()),
+            Err(e) => Err(failed("a 200 with the patched Patient", e)),
+        };
+        report.record("patch Patient", outcome);
+    }
+
+    if let Some(reason) = declared.missing("Patient", "search-type") {
+        report.skip("search Patient", reason);
+    } else {
+        search_steps(client, report, &id, family).await;
+    }
+
+    if let Some(reason) = declared.missing("Patient", "history-instance") {
+        report.skip("history Patient", reason);
+    } else {
+        let outcome = match client.patient_history(&id).await {
+            Ok(bundle) => {
+                let mut deviations = bundle_deviations(&bundle, "history", &id);
+                let versions = bundle["entry"].as_array().map_or(0, Vec::len);
+                if declared.missing("Patient", "update").is_none() && versions < 2 {
+                    deviations.push(format!("{} versions after an update", versions));
+                }
+                joined(deviations)
+            }
+            Err(e) => Err(failed("a history Bundle", e)),
+        };
+        report.record("history Patient", outcome);
+    }
+
+    Some(created)
+}
+
+async fn search_steps(client: &FhirClient, report: &mut ConformanceReport, id: &str, family: &str) {
+    let searches = [
+        ("search Patient by _id", client.search().id(id)),
+        // String search matches the start of the value, ignoring case
+        (
+            "search Patient by name",
+            client
+                .search()
+                .name(family[..family.len() - 4].to_lowercase()),
+        ),
+        (
+            "search Patient by identifier",
+            client
+                .search()
+                .identifier(format!("{}|{}", TEST_SYSTEM, family)),
+        ),
+    ];
+    for (name, search) in searches {
+        let outcome = match search.send().await {
+            Ok(bundle) => {
+                let bundle = to_value(&bundle);
+                let mut deviations = bundle_deviations(&bundle, "searchset", id);
+                if bundle["total"].as_u64().is_some_and(|total| total != 1) {
+                    deviations.push(format!("total is {} instead of 1", bundle["total"]));
+                }
+                joined(deviations)
+            }
+            Err(e) => Err(failed("a searchset Bundle", e)),
+        };
+        report.record(name, outcome);
+    }
+}
+
+/// Create and search an Observation about the Patient; its id, if created
+async fn observation_steps(
+    client: &FhirClient,
+    declared: &Declared,
+    report: &mut ConformanceReport,
+    patient_id: &str,
+) -> Option<String> {
+    if let Some(reason) = declared.missing("Observation", "create") {
+        report.skip("create Observation", reason);
+        return None;
+    }
+    let mut observation = Observation::new(
+        "final",
+        CodeableConcept {
+            coding: Some(vec![Coding {
+                system: Some("http://loinc.org".to_string()),
+                code: Some("8867-4".to_string()),
+                display: Some("Heart rate".to_string()),
+                extra: Map::new(),
+            }]),
+            text: Some("Heart rate".to_string()),
+            extra: Map::new(),
+        },
+    );
+    observation.subject = Some(Reference {
+        reference: Some(format!("Patient/{}", patient_id)),
+        display: None,
+        extra: Map::new(),
+    });
+    observation.extra.insert(
+        "valueQuantity".to_string(),
+        json!({ "value": 72, "unit": "beats/minute", "system": "http://unitsofmeasure.org", "code": "/min" }),
+    );
+    let created = match client.create_observation(&observation).await {
+        Ok(created) => to_value(&created),
+        Err(e) => {
+            report.record(
+                "create Observation",
+                Err(failed("a 201 with the Observation", e)),
+            );
+            return None;
+        }
+    };
+    if !report.record(
+        "create Observation",
+        joined(resource_deviations(&created, "Observation")),
+    ) {
+        return None;
+    }
+    let id = created["id"].as_str().unwrap_or_default().to_string();
+
+    if let Some(reason) = declared.missing("Observation", "search-type") {
+        report.skip("search Observation by subject", reason);
+    } else {
+        let search = client
+            .search_observations()
+            .subject(format!("Patient/{}", patient_id))
+            .send()
+            .await;
+        let outcome = match search {
+            Ok(bundle) => joined(bundle_deviations(&to_value(&bundle), "searchset", &id)),
+            Err(e) => Err(failed("a searchset Bundle", e)),
+        };
+        report.record("search Observation by subject", outcome);
+    }
+
+    Some(id)
+}
+
+/// Errors have to be answered with a 4xx and an OperationOutcome
+async fn error_steps(
+    client: &FhirClient,
+    declared: &Declared,
+    report: &mut ConformanceReport,
+    patient_id: &str,
+) {
+    if let Some(reason) = declared.missing("Patient", "update") {
+        report.skip("update with another id", reason);
+        return;
+    }
+    // The id in the body has to match the one in the URL
+    let mismatched = Patient {
+        id: Some(Uuid::new_v4().to_string()),
+        ..Patient::new()
+    };
+    let outcome = match client.update_patient(patient_id, &mismatched).await {
+        Ok(_) => Err("expected a 400, the update was accepted".to_string()),
+        Err(Error::Server {
+            status,
+            outcome: Some(_),
+        }) if status == StatusCode::BAD_REQUEST => Ok(()),
+        Err(Error::Server {
+            status,
+            outcome: None,
+        }) if status.is_client_error() => {
+            Err(format!("answered {} without an OperationOutcome", status))
+        }
+        Err(e) => Err(failed("a 400 with an OperationOutcome", e)),
+    };
+    report.record("update with another id", outcome);
+}
+
+async fn delete_steps(
+    client: &FhirClient,
+    declared: &Declared,
+    report: &mut ConformanceReport,
+    patient_id: &str,
+) {
+    if let Some(reason) = declared.missing("Patient", "delete") {
+        report.skip("delete Patient", reason);
+        return;
+    }
+    let outcome = match client.delete_patient(patient_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("expected the Patient to be deleted, got 404".to_string()),
+        Err(e) => Err(failed("a 200 or 204", e)),
+    };
+    if !report.record("delete Patient", outcome) {
+        return;
+    }
+    let outcome = match client.get_patient(patient_id).await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err("expected 404 or 410, the deleted Patient was returned".to_string()),
+        Err(e) => Err(failed("a 404 or 410", e)),
+    };
+    report.record("read deleted Patient", outcome);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::{Path, State},
+        http::StatusCode as AxumStatus,
+        routing::{get, post},
+        Json, Router,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn statement() -> Value {
+        json!({
+            "resourceType": "CapabilityStatement",
+            "status": "active",
+            "date": "2024-01-01",
+            "kind": "instance",
+            "fhirVersion": "4.0.1",
+            "format": ["application/fhir+json"],
+            "rest": [{
+                "mode": "server",
+                "resource": [{
+                    "type": "Patient",
+                    "interaction": [{ "code": "read" }, { "code": "create" }],
+                }],
+            }],
+        })
+    }
+
+    #[test]
+    fn test_capability_deviations() {
+        assert_eq!(capability_deviations(&statement()), Ok(()));
+
+        let mut statement = statement();
+        statement["format"] = json!(["xml"]);
+        statement["rest"][0]["mode"] = json!("client");
+        statement.as_object_mut().unwrap().remove("fhirVersion");
+        let deviations = capability_deviations(&statement).unwrap_err();
+        assert!(
+            deviations.contains("fhirVersion is required"),
+            "{}",
+            deviations
+        );
+        assert!(deviations.contains("format does not list json"));
+        assert!(deviations.contains("no rest entry has mode server"));
+    }
+
+    #[test]
+    fn test_undeclared_interactions_are_skipped() {
+        let declared = Declared::from_capabilities(&statement());
+        assert_eq!(declared.missing("Patient", "read"), None);
+        assert!(declared.missing("Patient", "patch").is_some());
+        assert!(declared.missing("Observation", "create").is_some());
+        // Without a CapabilityStatement everything is tried
+        assert_eq!(Declared::default().missing("Patient", "patch"), None);
+    }
+
+    #[test]
+    fn test_bundle_deviations() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "searchset",
+            "entry": [{ "resource": { "id": "1" } }],
+        });
+        assert!(bundle_deviations(&bundle, "searchset", "1").is_empty());
+        assert_eq!(
+            bundle_deviations(&bundle, "history", "2"),
+            vec![
+                "Bundle.type is \"searchset\" instead of history".to_string(),
+                "no entry holds 2".to_string(),
+            ]
+        );
+    }
+
+    /// Patients held in memory by a stub server
+    #[derive(Clone, Default)]
+    struct Stub {
+        patients: Arc<Mutex<HashMap<String, Value>>>,
+        /// Whether created Patients get `meta`
+        stamps: bool,
+    }
+
+    async fn stub_metadata() -> Json<Value> {
+        let mut statement = statement();
+        statement["rest"][0]["resource"][0]["interaction"] =
+            json!([{ "code": "read" }, { "code": "create" }, { "code": "delete" }]);
+        Json(statement)
+    }
+
+    async fn stub_create(
+        State(stub): State<Stub>,
+        Json(mut patient): Json<Value>,
+    ) -> (AxumStatus, Json<Value>) {
+        let id = Uuid::new_v4().to_string();
+        patient["id"] = json!(id);
+        if stub.stamps {
+            patient["meta"] = json!({ "versionId": "1", "lastUpdated": "2024-01-01T00:00:00Z" });
+        }
+        stub.patients.lock().unwrap().insert(id, patient.clone());
+        (AxumStatus::CREATED, Json(patient))
+    }
+
+    async fn stub_read(
+        State(stub): State<Stub>,
+        Path(id): Path<String>,
+    ) -> Result<Json<Value>, AxumStatus> {
+        let patients = stub.patients.lock().unwrap();
+        patients
+            .get(&id)
+            .cloned()
+            .map(Json)
+            .ok_or(AxumStatus::NOT_FOUND)
+    }
+
+    async fn stub_delete(State(stub): State<Stub>, Path(id): Path<String>) -> AxumStatus {
+        match stub.patients.lock().unwrap().remove(&id) {
+            Some(_) => AxumStatus::NO_CONTENT,
+            None => AxumStatus::NOT_FOUND,
+        }
+    }
+
+    /// Run against a stub server; Patients get `meta` when it `stamps`
+    async fn run_against_stub(stamps: bool) -> ConformanceReport {
+        let stub = Stub {
+            stamps,
+            ..Stub::default()
+        };
+        let app = Router::new()
+            .route("/fhir/metadata", get(stub_metadata))
+            .route("/fhir/Patient", post(stub_create))
+            .route("/fhir/Patient/:id", get(stub_read).delete(stub_delete))
+            .with_state(stub);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        run(&ConformanceOptions::new(format!("http://{}/fhir", address))).await
+    }
+
+    fn outcome<'a>(report: &'a ConformanceReport, name: &str) -> &'a Outcome {
+        &report
+            .steps
+            .iter()
+            .find(|step| step.name == name)
+            .unwrap_or_else(|| panic!("no step {}", name))
+            .outcome
+    }
+
+    #[tokio::test]
+    async fn test_declared_interactions_are_run_and_the_rest_skipped() {
+        let report = run_against_stub(true).await;
+        let lines: Vec<String> = report.steps.iter().map(Step::to_string).collect();
+        assert!(report.conforms(), "{:#?}", lines);
+
+        for step in [
+            "capabilities",
+            "create Patient",
+            "read Patient",
+            "read unknown Patient",
+            "delete Patient",
+            "read deleted Patient",
+        ] {
+            assert_eq!(outcome(&report, step), &Outcome::Conforms, "{}", step);
+        }
+        for step in [
+            "update Patient",
+            "patch Patient",
+            "search Patient",
+            "history Patient",
+            "create Observation",
+            "update with another id",
+        ] {
+            assert!(
+                matches!(outcome(&report, step), Outcome::Skipped(_)),
+                "{}",
+                step
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deviations_are_reported() {
+        let report = run_against_stub(false).await;
+        assert!(!report.conforms());
+        match outcome(&report, "create Patient") {
+            Outcome::Deviates(deviation) => {
+                assert!(
+                    deviation.contains("meta.versionId is missing"),
+                    "{}",
+                    deviation
+                );
+            }
+            other => panic!("create Patient {:?}", other),
+        }
+        // The run stops at a Patient it cannot rely on
+        assert_eq!(report.steps.len(), 2);
+    }
+}
@@ -0,0 +1,356 @@
+//! Consent enforcement on reads and searches.
+//!
+//! With `[consent] enforced = true`, stored Consent resources that deny
+//! sharing (see [`crate::db::consent`]) take effect: Patient, Observation
+//! and generic searches leave out the resources withheld from the actor of
+//! the request, through a condition added to their SQL, and instance reads
+//! of one are refused with 403. The other ways of reading patient data do
+//! the same: type and system history, `$cohort`, `$match`, asynchronous
+//! searches and `$export` leave withheld resources out, while instance
+//! history, `$conflict`, GET entries of a batch and `$share` refuse them.
+//! Sharing links are on behalf of nobody in particular, so only the
+//! Consents that deny everyone apply to them, checked again when a link is
+//! read. The actor is the `fhirUser` of the
+//! request's SMART access token; requests without one only see the
+//! Consents that deny everyone applied. `Consent` has to be one of the
+//! `resource_types` for Consents to be stored at all.
+//!
+//! Break-the-glass: a request whose token has one of the
+//! `break_glass_roles` and that gives a justification in `X-Break-Glass`
+//! reads as if no Consent were enforced. Every such request is logged as a
+//! warning on the `fhir_server::audit` target and, when `AuditEvent` is
+//! served, stored as an AuditEvent before it runs, so the Subscriptions on
+//! `AuditEvent` are notified; a request whose AuditEvent cannot be stored is
+//! refused. A justification from any other token is refused with 403.
+
+use crate::config::SharedConfig;
+use crate::db::{Database, SearchSql, Withholding};
+use crate::models::OperationOutcome;
+use crate::smart::SmartToken;
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Request, State},
+    http::{request::Parts, Extensions, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+pub const BREAK_GLASS_HEADER: &str = "x-break-glass";
+
+const ACT_REASON_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-ActReason";
+const AUDIT_EVENT_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/audit-event-type";
+
+type ErrorResponse = (StatusCode, Json<OperationOutcome>);
+
+/// The `[consent]` section of the server config
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ConsentConfig {
+    /// Leave resources withheld by a Consent out of reads and searches
+    pub enforced: bool,
+    /// Token roles that may override the Consents with an `X-Break-Glass`
+    /// justification
+    pub break_glass_roles: Vec<String>,
+}
+
+/// Extension of a request that broke the glass, set by [`break_glass`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakGlass {
+    pub justification: String,
+}
+
+/// Whom a request reads on behalf of, and whether Consents apply to it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsentFilter {
+    /// Reference to the actor, e.g. `Practitioner/<id>`
+    pub actor: Option<String>,
+    pub enforced: bool,
+}
+
+impl ConsentFilter {
+    /// The filter of a request with these `extensions`
+    pub fn of(config: &SharedConfig, extensions: &Extensions) -> Self {
+        ConsentFilter {
+            actor: extensions
+                .get::<SmartToken>()
+                .and_then(|token| token.fhir_user.as_deref())
+                .map(relative_reference),
+            enforced: config.get().consent.enforced && extensions.get::<BreakGlass>().is_none(),
+        }
+    }
+
+    /// The Consents a query over many patients has to honour, if enforced
+    pub fn withholding(&self) -> Option<Withholding<'_>> {
+        self.enforced.then_some(Withholding {
+            actor: self.actor.as_deref(),
+        })
+    }
+
+    /// `search` without the matches withheld from the actor
+    pub fn search(&self, db: &Database, search: SearchSql) -> SearchSql {
+        if self.enforced {
+            search.without_withheld(self.actor.as_deref(), db.now())
+        } else {
+            search
+        }
+    }
+
+    /// Refuse a read of the `resource_type` with `id` when a Consent
+    /// withholds it from the actor
+    pub async fn check_read(
+        &self,
+        db: &Database,
+        resource_type: &str,
+        id: &str,
+    ) -> Result<(), ErrorResponse> {
+        if !self.enforced {
+            return Ok(());
+        }
+        match db
+            .is_withheld(resource_type, id, self.actor.as_deref())
+            .await
+        {
+            Ok(false) => Ok(()),
+            Ok(true) => {
+                tracing::info!(
+                    target: "fhir_server::audit",
+                    resource = %format!("{}/{}", resource_type, id),
+                    actor = self.actor.as_deref().unwrap_or("anonymous"),
+                    "read withheld by a Consent refused"
+                );
+                Err((
+                    StatusCode::FORBIDDEN,
+                    Json(OperationOutcome::error(
+                        "forbidden",
+                        format!(
+                            "The patient has not consented to sharing {}/{}",
+                            resource_type, id
+                        ),
+                    )),
+                ))
+            }
+            Err(e) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!(
+                        "Failed to check the consent for {}/{}: {}",
+                        resource_type, id, e
+                    ),
+                )),
+            )),
+        }
+    }
+}
+
+/// `<type>/<id>` of a reference that may be absolute
+fn relative_reference(reference: &str) -> String {
+    let mut segments = reference.rsplitn(3, '/');
+    match (segments.next(), segments.next()) {
+        (Some(id), Some(resource_type)) => format!("{}/{}", resource_type, id),
+        _ => reference.to_string(),
+    }
+}
+
+/// The AuditEvent of a request by `token` on `target` (`<method> <uri>`)
+/// that broke the glass at `recorded`
+pub fn break_glass_event(
+    token: &SmartToken,
+    target: &str,
+    justification: &str,
+    recorded: DateTime<Utc>,
+) -> Value {
+    let who = match &token.fhir_user {
+        Some(fhir_user) => json!({ "reference": relative_reference(fhir_user) }),
+        None => json!({ "display": token.subject.as_deref().unwrap_or("anonymous") }),
+    };
+    json!({
+        "resourceType": "AuditEvent",
+        "type": { "system": AUDIT_EVENT_TYPE_SYSTEM, "code": "rest" },
+        "recorded": recorded.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "outcome": "0",
+        "purposeOfEvent": [{
+            "coding": [{ "system": ACT_REASON_SYSTEM, "code": "BTG", "display": "break the glass" }],
+            "text": justification,
+        }],
+        "agent": [{ "who": who, "requestor": true }],
+        "source": { "observer": { "display": "fhir-server" } },
+        "entity": [{ "description": target }],
+    })
+}
+
+/// Let the requests of the `break_glass_roles` with an `X-Break-Glass`
+/// justification read past the Consents, auditing each
+pub async fn break_glass(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(justification) = request.headers().get(BREAK_GLASS_HEADER) else {
+        return next.run(request).await;
+    };
+    let settings = config.get();
+    if !settings.consent.enforced {
+        return next.run(request).await;
+    }
+    let justification = justification
+        .to_str()
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    let token = request.extensions().get::<SmartToken>().cloned();
+    let target = format!("{} {}", request.method(), request.uri());
+    let allowed = token.as_ref().is_some_and(|token| {
+        token
+            .roles
+            .iter()
+            .any(|role| settings.consent.break_glass_roles.contains(role))
+    });
+    let subject = token
+        .as_ref()
+        .and_then(|token| token.subject.as_deref())
+        .unwrap_or("anonymous")
+        .to_string();
+    let refuse = |status: StatusCode, code: &str, message: String| {
+        tracing::warn!(
+            target: "fhir_server::audit",
+            subject = %subject,
+            request = %target,
+            "break-the-glass refused: {}",
+            message
+        );
+        (status, Json(OperationOutcome::error(code, message))).into_response()
+    };
+
+    let token = match token {
+        Some(token) if allowed => token,
+        _ => {
+            return refuse(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "No role of the token may override consent with X-Break-Glass".into(),
+            )
+        }
+    };
+    if justification.is_empty() {
+        return refuse(
+            StatusCode::BAD_REQUEST,
+            "required",
+            "X-Break-Glass must give a justification".into(),
+        );
+    }
+
+    tracing::warn!(
+        target: "fhir_server::audit",
+        subject = %subject,
+        actor = token.fhir_user.as_deref().unwrap_or("anonymous"),
+        request = %target,
+        justification = %justification,
+        "consent overridden by break-the-glass"
+    );
+    if settings.serves_resource_type("AuditEvent") {
+        let event = break_glass_event(&token, &target, &justification, db.now());
+        if let Err(e) = db.create_resource("AuditEvent", event).await {
+            return refuse(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "processing",
+                format!("Failed to record the AuditEvent: {}", e),
+            );
+        }
+    }
+    request
+        .extensions_mut()
+        .insert(BreakGlass { justification });
+    next.run(request).await
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ConsentFilter
+where
+    Arc<SharedConfig>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = Arc::<SharedConfig>::from_ref(state);
+        Ok(ConsentFilter::of(&config, &parts.extensions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_actor_is_a_relative_reference() {
+        assert_eq!(relative_reference("Practitioner/p1"), "Practitioner/p1");
+        assert_eq!(
+            relative_reference("https://fhir.example.org/fhir/Practitioner/p1"),
+            "Practitioner/p1"
+        );
+        assert_eq!(relative_reference("p1"), "p1");
+    }
+
+    #[test]
+    fn test_breaking_the_glass_lifts_enforcement() {
+        let config = SharedConfig::new(ServerConfig {
+            consent: ConsentConfig {
+                enforced: true,
+                break_glass_roles: vec!["emergency".to_string()],
+            },
+            ..ServerConfig::default()
+        });
+        let mut extensions = Extensions::new();
+        assert!(ConsentFilter::of(&config, &extensions).enforced);
+        extensions.insert(BreakGlass {
+            justification: "unconscious in the ED".to_string(),
+        });
+        assert!(!ConsentFilter::of(&config, &extensions).enforced);
+    }
+
+    #[test]
+    fn test_break_glass_event() {
+        let token = SmartToken {
+            subject: Some("dr-house".to_string()),
+            fhir_user: Some("https://fhir.example.org/fhir/Practitioner/p1".to_string()),
+            client_id: None,
+            patient: None,
+            scopes: Vec::new(),
+            roles: vec!["emergency".to_string()],
+        };
+        let recorded = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let event = break_glass_event(&token, "GET /fhir/Patient/1", "unconscious", recorded);
+        assert_eq!(event["resourceType"], "AuditEvent");
+        assert_eq!(event["recorded"], "2024-05-01T12:00:00.000Z");
+        assert_eq!(event["purposeOfEvent"][0]["coding"][0]["code"], "BTG");
+        assert_eq!(event["purposeOfEvent"][0]["text"], "unconscious");
+        assert_eq!(event["agent"][0]["who"]["reference"], "Practitioner/p1");
+        assert_eq!(event["entity"][0]["description"], "GET /fhir/Patient/1");
+    }
+
+    #[test]
+    fn test_withholding_only_when_enforced() {
+        let mut consent = ConsentFilter {
+            actor: Some("Practitioner/p1".to_string()),
+            enforced: false,
+        };
+        assert_eq!(consent.withholding(), None);
+        consent.enforced = true;
+        assert_eq!(
+            consent.withholding(),
+            Some(Withholding {
+                actor: Some("Practitioner/p1")
+            })
+        );
+    }
+}
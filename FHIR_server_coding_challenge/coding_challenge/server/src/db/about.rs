@@ -0,0 +1,198 @@
+//! What `GET /admin/about` reports of the database: where the extension
+//! functions come from and which optional components the migrations have
+//! created.
+
+use super::audit::SqlParam;
+use super::bootstrap::EXTENSION;
+use super::settings::ExtensionSettings;
+use super::Database;
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::Row;
+use std::sync::atomic::Ordering;
+
+/// Where `fhir_put`, `fhir_get` and `fhir_search` come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtensionFunctions {
+    /// The pgrx extension built from `db/`
+    Extension,
+    /// The PL/pgSQL functions of `002_fhir_extension_functions.sql`
+    SqlFallback,
+    Missing,
+}
+
+/// The installed extension functions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionReport {
+    pub functions: ExtensionFunctions,
+    /// What `fhir_extension_version()` returns, or the `pg_extension`
+    /// version of libraries older than that function
+    pub version: Option<String>,
+    /// The `fhir.*` settings the server's sessions have
+    pub settings: ExtensionSettings,
+}
+
+/// An optional part of the schema, detected at startup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Component {
+    pub name: &'static str,
+    /// The migration creating it
+    pub migration: &'static str,
+    pub available: bool,
+}
+
+impl Database {
+    /// The optional components, in migration order
+    pub fn components(&self) -> Vec<Component> {
+        let flags = [
+            ("history", "001_initial_schema.sql", &self.history_enabled),
+            (
+                "identifier-index",
+                "005_resource_identifiers.sql",
+                &self.identifier_index_enabled,
+            ),
+            (
+                "phonetic-index",
+                "009_name_phonetic.sql",
+                &self.phonetic_index_enabled,
+            ),
+            ("changes", "011_change_outbox.sql", &self.changes_enabled),
+            ("archive", "012_resource_archive.sql", &self.archive_enabled),
+            (
+                "schema-changes",
+                "013_schema_changes.sql",
+                &self.schema_changes_enabled,
+            ),
+            (
+                "id-reservations",
+                "015_id_reservations.sql",
+                &self.reservations_enabled,
+            ),
+            (
+                "text-search",
+                "016_full_text_search.sql",
+                &self.text_search_enabled,
+            ),
+            (
+                "custom-search-parameters",
+                "017_custom_search_parameters.sql",
+                &self.search_index_enabled,
+            ),
+            ("snapshots", "018_snapshots.sql", &self.snapshots_enabled),
+            ("api-keys", "019_api_keys.sql", &self.api_keys_enabled),
+            (
+                "patient-match",
+                "020_patient_match.sql",
+                &self.patient_match_enabled,
+            ),
+            (
+                "record-locks",
+                "022_record_locks.sql",
+                &self.record_locks_enabled,
+            ),
+            (
+                "change-events",
+                "023_change_notify.sql",
+                &self.events_enabled,
+            ),
+            (
+                "string-index",
+                "025_normalized_strings.sql",
+                &self.string_index_enabled,
+            ),
+            (
+                "history-compression",
+                "026_history_compression.sql",
+                &self.history_compression_enabled,
+            ),
+            (
+                "api-key-scopes",
+                "027_api_key_scopes.sql",
+                &self.api_key_scopes_enabled,
+            ),
+        ];
+        flags
+            .into_iter()
+            .map(|(name, migration, flag)| Component {
+                name,
+                migration,
+                available: flag.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Where the extension functions come from, and the extension version
+    pub async fn extension_report(&self) -> Result<ExtensionReport> {
+        let row = self
+            .fetch_optional(
+                "SELECT extversion FROM pg_extension WHERE extname = $1",
+                &[SqlParam::text(EXTENSION)],
+            )
+            .await?;
+        let settings = self.extension_settings().await?;
+        let Some(row) = row else {
+            let fallback = self.function_exists(Some("fhir"), "fhir_put").await?;
+            return Ok(ExtensionReport {
+                functions: if fallback {
+                    ExtensionFunctions::SqlFallback
+                } else {
+                    ExtensionFunctions::Missing
+                },
+                version: None,
+                settings,
+            });
+        };
+
+        let version = if self.function_exists(None, "fhir_extension_version").await? {
+            self.fetch_one("SELECT fhir_extension_version() AS version", &[])
+                .await?
+                .get("version")
+        } else {
+            row.get("extversion")
+        };
+        Ok(ExtensionReport {
+            functions: ExtensionFunctions::Extension,
+            version: Some(version),
+            settings,
+        })
+    }
+}
+
+/// The migration level a database with `components` has reached: the
+/// migration before the first one whose component is missing, or `latest`
+pub fn migration_level(components: &[Component], latest: i32) -> i32 {
+    components
+        .iter()
+        .find(|component| !component.available)
+        .and_then(|component| component.migration.split('_').next()?.parse::<i32>().ok())
+        .map_or(latest, |missing| missing - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migration_level() {
+        let component = |migration, available| Component {
+            name: "component",
+            migration,
+            available,
+        };
+        assert_eq!(migration_level(&[], 20), 20);
+        assert_eq!(
+            migration_level(
+                &[
+                    component("011_change_outbox.sql", true),
+                    component("016_full_text_search.sql", false),
+                    component("019_api_keys.sql", false),
+                ],
+                20
+            ),
+            15
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! API keys, stored as their SHA-256 in `fhir.api_key` (migration
+//! `019_api_keys.sql`), with the scopes and rate limits of
+//! `027_api_key_scopes.sql`; see [`crate::api_keys`].
+
+use super::audit::SqlParam;
+use super::Database;
+use crate::api_keys::ApiKeyLimits;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+use std::sync::atomic::Ordering;
+
+/// A stored key, without its hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredApiKey {
+    pub name: String,
+    #[serde(flatten)]
+    pub limits: ApiKeyLimits,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl Database {
+    /// Whether `fhir.api_key` exists, checked at startup
+    pub fn api_keys_enabled(&self) -> bool {
+        self.api_keys_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Whether keys have scopes and rate limits (`027_api_key_scopes.sql`),
+    /// checked at startup
+    pub fn api_key_scopes_enabled(&self) -> bool {
+        self.api_key_scopes_enabled.load(Ordering::Relaxed)
+    }
+
+    /// The columns of [`StoredApiKey`] that exist
+    fn api_key_columns(&self) -> &'static str {
+        if self.api_key_scopes_enabled() {
+            "name, created_at, last_used_at, scopes, requests_per_minute"
+        } else {
+            "name, created_at, last_used_at, NULL::TEXT AS scopes, NULL::INTEGER AS requests_per_minute"
+        }
+    }
+
+    /// Store the key `name` with hash `key_hash`; false if the name is
+    /// taken. Limits other than none need `027_api_key_scopes.sql`.
+    pub async fn create_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        limits: &ApiKeyLimits,
+    ) -> Result<bool> {
+        let result = if self.api_key_scopes_enabled() {
+            self.execute(
+                "INSERT INTO fhir.api_key (name, key_hash, scopes, requests_per_minute)
+                 VALUES ($1, $2, $3, NULLIF($4, 0))
+                 ON CONFLICT (name) DO NOTHING",
+                &[
+                    SqlParam::text(name),
+                    SqlParam::text(key_hash),
+                    SqlParam::Text(limits.scopes.as_ref().map(|scopes| scopes.join(" "))),
+                    SqlParam::Int(limits.requests_per_minute.unwrap_or(0)),
+                ],
+            )
+            .await?
+        } else {
+            self.execute(
+                "INSERT INTO fhir.api_key (name, key_hash) VALUES ($1, $2)
+                 ON CONFLICT (name) DO NOTHING",
+                &[SqlParam::text(name), SqlParam::text(key_hash)],
+            )
+            .await?
+        };
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Whether any key is stored
+    pub async fn has_api_keys(&self) -> Result<bool> {
+        let row = self
+            .fetch_one("SELECT EXISTS (SELECT 1 FROM fhir.api_key) AS exists", &[])
+            .await?;
+        Ok(row.get("exists"))
+    }
+
+    /// The key with hash `key_hash`, recording that it was used
+    pub async fn use_api_key(&self, key_hash: &str) -> Result<Option<StoredApiKey>> {
+        let row = self
+            .fetch_optional(
+                &format!(
+                    "UPDATE fhir.api_key SET last_used_at = NOW()
+                     WHERE key_hash = $1
+                     RETURNING {}",
+                    self.api_key_columns()
+                ),
+                &[SqlParam::text(key_hash)],
+            )
+            .await?;
+        Ok(row.as_ref().map(stored_api_key))
+    }
+
+    /// Every stored key, by name
+    pub async fn list_api_keys(&self) -> Result<Vec<StoredApiKey>> {
+        let rows = self
+            .fetch_all(
+                &format!(
+                    "SELECT {} FROM fhir.api_key ORDER BY name",
+                    self.api_key_columns()
+                ),
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(stored_api_key).collect())
+    }
+
+    /// Replace the scopes and rate limit of the key `name`; `None` if there
+    /// is no such key
+    pub async fn update_api_key(
+        &self,
+        name: &str,
+        limits: &ApiKeyLimits,
+    ) -> Result<Option<StoredApiKey>> {
+        let row = self
+            .fetch_optional(
+                &format!(
+                    "UPDATE fhir.api_key SET scopes = $2, requests_per_minute = NULLIF($3, 0)
+                     WHERE name = $1
+                     RETURNING {}",
+                    self.api_key_columns()
+                ),
+                &[
+                    SqlParam::text(name),
+                    SqlParam::Text(limits.scopes.as_ref().map(|scopes| scopes.join(" "))),
+                    SqlParam::Int(limits.requests_per_minute.unwrap_or(0)),
+                ],
+            )
+            .await?;
+        Ok(row.as_ref().map(stored_api_key))
+    }
+
+    /// Remove the key `name`; whether there was one
+    pub async fn delete_api_key(&self, name: &str) -> Result<bool> {
+        let result = self
+            .execute(
+                "DELETE FROM fhir.api_key WHERE name = $1",
+                &[SqlParam::text(name)],
+            )
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn stored_api_key(row: &PgRow) -> StoredApiKey {
+    StoredApiKey {
+        name: row.get("name"),
+        limits: ApiKeyLimits {
+            scopes: row
+                .get::<Option<String>, _>("scopes")
+                .map(|scopes| scopes.split_whitespace().map(String::from).collect()),
+            requests_per_minute: row.get("requests_per_minute"),
+        },
+        created_at: row.get("created_at"),
+        last_used_at: row.get("last_used_at"),
+    }
+}
@@ -0,0 +1,362 @@
+//! The cold tier: live resources not written for years, kept in
+//! `fhir.resource_archive` (migration `012_resource_archive.sql`) so
+//! `fhir_resources` only holds what is in use.
+//!
+//! Reads by id fall back to the archive. Any write moves the resource back
+//! first, with its identifier and phonetic index entries, so the write
+//! itself behaves as if it had never left. Searches, identifier lookups and
+//! `unique_identifier_systems` only see the hot table, and conformance
+//! resources are never archived so their canonical URLs keep resolving.
+//! Moves between the tiers are not recorded in the change feed.
+
+use super::audit::SqlParam;
+use super::conformance::CONFORMANCE_RESOURCE_TYPES;
+use super::resource::{StoredResource, RESOURCE_COLUMNS};
+use super::string_values::{self, NAME_CODE};
+use super::{identifiers, Database};
+use crate::models::Patient;
+use crate::search::phonetic;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::postgres::PgRow;
+use sqlx::{PgConnection, Row};
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// Columns shared by `fhir_resources` and `fhir.resource_archive`
+const MOVED_COLUMNS: &str =
+    "id, resource_type, resource_data, version_id, last_updated, created_at, deleted_at, content_hash";
+
+/// Hot and archived resources of one type
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveTierCounts {
+    pub resource_type: String,
+    /// Rows in `fhir_resources`, including deleted patients
+    pub hot: i64,
+    pub archived: i64,
+    /// Live hot resources old enough to be archived now
+    pub eligible: i64,
+}
+
+/// Conformance resources stay hot, as canonical references to them are
+/// resolved against `fhir_resources`
+fn kept_hot() -> String {
+    CONFORMANCE_RESOURCE_TYPES
+        .iter()
+        .map(|resource_type| format!("'{}'", resource_type))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Database {
+    /// Whether `fhir.resource_archive` exists, checked at startup
+    pub fn archive_enabled(&self) -> bool {
+        self.archive_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Move up to `limit` live resources last written more than
+    /// `after_years` years ago to the archive, oldest first; returns how
+    /// many were moved. Rows locked by a running write are skipped.
+    pub async fn archive_cold(&self, after_years: u32, limit: u32) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        self.set_archiving_on(&mut tx, true).await?;
+        let result = self
+            .execute_on(
+                &mut *tx,
+                &format!(
+                    "WITH cold AS (
+                         SELECT id FROM fhir_resources
+                         WHERE deleted_at IS NULL
+                           AND last_updated < $3 - make_interval(years => $1)
+                           AND resource_type NOT IN ({kept})
+                         ORDER BY last_updated
+                         LIMIT $2
+                         FOR UPDATE SKIP LOCKED
+                     ), moved AS (
+                         DELETE FROM fhir_resources r USING cold WHERE r.id = cold.id
+                         RETURNING r.*
+                     )
+                     INSERT INTO fhir.resource_archive ({columns})
+                     SELECT {columns} FROM moved",
+                    columns = MOVED_COLUMNS,
+                    kept = kept_hot()
+                ),
+                &[
+                    SqlParam::Int(after_years as i32),
+                    SqlParam::BigInt(limit as i64),
+                    SqlParam::Timestamp(self.now()),
+                ],
+            )
+            .await?;
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// An archived resource as stored, for reads that miss the hot table
+    pub(super) async fn get_archived_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: Uuid,
+    ) -> Result<Option<StoredResource>> {
+        if !self.archive_enabled() {
+            return Ok(None);
+        }
+        let row = self
+            .fetch_optional_on(
+                &mut *conn,
+                &format!(
+                    "SELECT {} FROM fhir.resource_archive WHERE id = $1 AND resource_type = $2",
+                    RESOURCE_COLUMNS
+                ),
+                &[SqlParam::Uuid(id), SqlParam::text(resource_type)],
+            )
+            .await?;
+
+        Ok(row.as_ref().map(StoredResource::from_row))
+    }
+
+    /// Before a write on `conn`, which must be a transaction: move `id` back
+    /// from the archive if it is there and lock its hot row, so the archiver
+    /// cannot take it away before the write commits
+    pub(super) async fn restore_archived_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: Uuid,
+    ) -> Result<()> {
+        if !self.archive_enabled() {
+            return Ok(());
+        }
+
+        self.set_archiving_on(conn, true).await?;
+        let restored = self
+            .fetch_optional_on(
+                &mut *conn,
+                &format!(
+                    "WITH restored AS (
+                         DELETE FROM fhir.resource_archive WHERE id = $1 AND resource_type = $2
+                         RETURNING {columns}
+                     )
+                     INSERT INTO fhir_resources ({columns})
+                     SELECT {columns} FROM restored
+                     RETURNING resource_data",
+                    columns = MOVED_COLUMNS
+                ),
+                &[SqlParam::Uuid(id), SqlParam::text(resource_type)],
+            )
+            .await?;
+        self.set_archiving_on(conn, false).await?;
+
+        match restored {
+            Some(row) => {
+                if resource_type == "Patient" {
+                    self.reindex_patient_on(conn, id, &row).await?;
+                }
+                self.sync_search_values_on(conn, id).await
+            }
+            None => {
+                self.execute_on(
+                    &mut *conn,
+                    "SELECT 1 FROM fhir_resources WHERE id = $1 FOR UPDATE",
+                    &[SqlParam::Uuid(id)],
+                )
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Index entries are dropped with the hot row; a restored patient gets
+    /// them back from its stored body
+    async fn reindex_patient_on(
+        &self,
+        conn: &mut PgConnection,
+        id: Uuid,
+        row: &PgRow,
+    ) -> Result<()> {
+        let data: Value = row.get("resource_data");
+        let patient: Patient = serde_json::from_value(data.clone())?;
+        // An identifier in a unique system taken by another patient while
+        // this one was archived fails the write with DuplicateIdentifier
+        self.sync_identifiers(conn, id, &identifiers::patient_identifiers(&patient))
+            .await?;
+        self.sync_name_phonetics(conn, id, &phonetic::patient_name_keys(&patient))
+            .await?;
+        let name_values = string_values::patient_name_values(&data);
+        self.sync_string_values_on(conn, id, "Patient", NAME_CODE, &name_values)
+            .await
+    }
+
+    /// Switch off change-feed recording for the rest of the transaction,
+    /// or back on
+    async fn set_archiving_on(&self, conn: &mut PgConnection, archiving: bool) -> Result<()> {
+        self.execute_on(
+            &mut *conn,
+            "SELECT set_config('fhir.archiving', $1, true)",
+            &[SqlParam::text(if archiving { "on" } else { "off" })],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Per-type resource counts of both tiers, with the number of hot
+    /// resources past `after_years`
+    pub async fn archive_counts(&self, after_years: u32) -> Result<Vec<ArchiveTierCounts>> {
+        let rows = self
+            .fetch_all(
+                &format!(
+                    "SELECT resource_type, SUM(hot)::BIGINT AS hot,
+                            SUM(archived)::BIGINT AS archived, SUM(eligible)::BIGINT AS eligible
+                     FROM (
+                         SELECT resource_type, COUNT(*) AS hot, 0 AS archived,
+                                COUNT(*) FILTER (
+                                    WHERE deleted_at IS NULL
+                                      AND last_updated < $2 - make_interval(years => $1)
+                                      AND resource_type NOT IN ({})
+                                ) AS eligible
+                         FROM fhir_resources GROUP BY resource_type
+                         UNION ALL
+                         SELECT resource_type, 0, COUNT(*), 0
+                         FROM fhir.resource_archive GROUP BY resource_type
+                     ) tiers
+                     GROUP BY resource_type
+                     ORDER BY resource_type",
+                    kept_hot()
+                ),
+                &[
+                    SqlParam::Int(after_years as i32),
+                    SqlParam::Timestamp(self.now()),
+                ],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| ArchiveTierCounts {
+                resource_type: row.get("resource_type"),
+                hot: row.get("hot"),
+                archived: row.get("archived"),
+                eligible: row.get("eligible"),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    /// Pretend `id` was last written `years` years ago
+    async fn backdate(db: &Database, id: &str, years: i32) {
+        db.execute(
+            "UPDATE fhir_resources SET last_updated = NOW() - make_interval(years => $2) WHERE id = $1",
+            &[SqlParam::Uuid(Uuid::parse_str(id).unwrap()), SqlParam::Int(years)],
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn is_archived(db: &Database, id: &str) -> bool {
+        db.fetch_optional(
+            "SELECT 1 FROM fhir.resource_archive WHERE id = $1",
+            &[SqlParam::Uuid(Uuid::parse_str(id).unwrap())],
+        )
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    /// Archive everything backdated by at least 90 years, in as many
+    /// batches as it takes
+    async fn archive_all(db: &Database) {
+        while db.archive_cold(90, 100).await.unwrap() == 100 {}
+    }
+
+    #[tokio::test]
+    async fn test_archived_patient_reads_through_and_returns_on_write() {
+        let db = setup_test_db().await;
+        db.detect_capabilities().await.unwrap();
+        assert!(db.archive_enabled());
+
+        let mut patient = Patient::new();
+        patient.gender = Some("female".to_string());
+        let id = db.create_patient(patient).await.unwrap().id.unwrap();
+        backdate(&db, &id, 100).await;
+        archive_all(&db).await;
+        assert!(is_archived(&db, &id).await);
+
+        let read = db
+            .get_patient(&id)
+            .await
+            .unwrap()
+            .expect("read through the archive");
+        assert_eq!(read.gender.as_deref(), Some("female"));
+        assert_eq!(read.meta.as_ref().unwrap().version_id.as_deref(), Some("1"));
+
+        let mut changed = read;
+        changed.gender = Some("other".to_string());
+        let updated = db.update_patient(&id, changed).await.unwrap().unwrap();
+        assert_eq!(updated.meta.unwrap().version_id.as_deref(), Some("2"));
+        assert!(!is_archived(&db, &id).await);
+
+        // Written just now, so not cold any more
+        archive_all(&db).await;
+        assert!(!is_archived(&db, &id).await);
+    }
+
+    #[tokio::test]
+    async fn test_archived_resource_can_be_deleted() {
+        let db = setup_test_db().await;
+        db.detect_capabilities().await.unwrap();
+
+        let stored = db
+            .create_resource("Basic", serde_json::json!({ "code": { "text": "cold" } }))
+            .await
+            .unwrap();
+        let id = stored.id.to_string();
+        backdate(&db, &id, 100).await;
+        archive_all(&db).await;
+        assert!(is_archived(&db, &id).await);
+
+        let read = db.get_resource("Basic", &id).await.unwrap().unwrap();
+        assert_eq!(read.data["code"]["text"], "cold");
+        let counts = db.archive_counts(90).await.unwrap();
+        assert!(counts
+            .iter()
+            .any(|c| c.resource_type == "Basic" && c.archived >= 1));
+
+        assert!(db.delete_resource("Basic", &id).await.unwrap());
+        assert!(!is_archived(&db, &id).await);
+        assert!(db.get_resource("Basic", &id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_conformance_resources_stay_hot() {
+        let db = setup_test_db().await;
+        db.detect_capabilities().await.unwrap();
+
+        let url = format!("http://example.org/fhir/ValueSet/{}", Uuid::new_v4());
+        let stored = db
+            .create_resource(
+                "ValueSet",
+                serde_json::json!({ "url": url, "status": "active" }),
+            )
+            .await
+            .unwrap();
+        let id = stored.id.to_string();
+        backdate(&db, &id, 100).await;
+        archive_all(&db).await;
+        assert!(!is_archived(&db, &id).await);
+        let counts = db.archive_counts(90).await.unwrap();
+        assert!(counts
+            .iter()
+            .all(|c| c.resource_type != "ValueSet" || c.eligible == 0));
+
+        assert!(db.delete_resource("ValueSet", &id).await.unwrap());
+    }
+}
@@ -0,0 +1,150 @@
+//! Structured query auditing.
+//!
+//! Every repository query goes through [`SqlParam`] binds so it can be
+//! logged with its placeholders and redacted values at DEBUG level on the
+//! `fhir_server::sql` target. Queries slower than the configured threshold
+//! are logged at WARN together with their `EXPLAIN` plan.
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::postgres::{PgArguments, Postgres};
+use sqlx::query::Query;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const SQL_TARGET: &str = "fhir_server::sql";
+
+/// A bind value for an audited query
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlParam {
+    Uuid(Uuid),
+    Int(i32),
+    BigInt(i64),
+    Bool(bool),
+    Text(Option<String>),
+    Json(Value),
+    Timestamp(DateTime<Utc>),
+}
+
+impl SqlParam {
+    pub fn text(value: impl Into<String>) -> Self {
+        SqlParam::Text(Some(value.into()))
+    }
+
+    /// Loggable form of the value. Identifiers and numbers are kept since
+    /// they are needed to reproduce a query; free text and resource bodies
+    /// may contain PHI and are reduced to their size.
+    pub fn redacted(&self) -> String {
+        match self {
+            SqlParam::Uuid(v) => format!("'{}'", v),
+            SqlParam::Int(v) => v.to_string(),
+            SqlParam::BigInt(v) => v.to_string(),
+            SqlParam::Bool(v) => v.to_string(),
+            SqlParam::Text(None) => "NULL".to_string(),
+            SqlParam::Text(Some(v)) => format!("<text {} chars>", v.chars().count()),
+            SqlParam::Json(v) => format!("<jsonb {} bytes>", v.to_string().len()),
+            SqlParam::Timestamp(v) => format!("'{}'", v.to_rfc3339()),
+        }
+    }
+}
+
+/// Redacted bind list, rendered as `$1=…, $2=…`
+pub struct RedactedBinds<'a>(pub &'a [SqlParam]);
+
+impl fmt::Display for RedactedBinds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, param) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "${}={}", i + 1, param.redacted())?;
+        }
+        Ok(())
+    }
+}
+
+/// Attach all params to a query in order
+pub fn bind_params<'q>(
+    mut query: Query<'q, Postgres, PgArguments>,
+    params: &'q [SqlParam],
+) -> Query<'q, Postgres, PgArguments> {
+    for param in params {
+        query = match param {
+            SqlParam::Uuid(v) => query.bind(*v),
+            SqlParam::Int(v) => query.bind(*v),
+            SqlParam::BigInt(v) => query.bind(*v),
+            SqlParam::Bool(v) => query.bind(*v),
+            SqlParam::Text(v) => query.bind(v.as_deref()),
+            SqlParam::Json(v) => query.bind(v),
+            SqlParam::Timestamp(v) => query.bind(*v),
+        };
+    }
+    query
+}
+
+/// Slow-query threshold shared by all queries of a `Database`
+#[derive(Debug, Default)]
+pub struct QueryAudit {
+    /// Milliseconds; 0 disables slow-query EXPLAIN
+    slow_threshold_ms: AtomicU64,
+}
+
+impl QueryAudit {
+    pub fn set_slow_threshold(&self, threshold: Duration) {
+        self.slow_threshold_ms
+            .store(threshold.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        match self.slow_threshold_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        self.slow_threshold().is_some_and(|t| elapsed >= t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redaction_hides_text_and_json() {
+        let id = Uuid::new_v4();
+        let params = vec![
+            SqlParam::Uuid(id),
+            SqlParam::Int(3),
+            SqlParam::text("Gauß"),
+            SqlParam::Text(None),
+            SqlParam::Json(json!({"name": "secret"})),
+        ];
+
+        let rendered = RedactedBinds(&params).to_string();
+
+        assert_eq!(
+            rendered,
+            format!(
+                "$1='{}', $2=3, $3=<text 4 chars>, $4=NULL, $5=<jsonb 17 bytes>",
+                id
+            )
+        );
+        assert!(!rendered.contains("Gauß"));
+        assert!(!rendered.contains("secret"));
+    }
+
+    #[test]
+    fn test_slow_threshold() {
+        let audit = QueryAudit::default();
+        assert!(!audit.is_slow(Duration::from_secs(60)));
+
+        audit.set_slow_threshold(Duration::from_millis(100));
+        assert!(!audit.is_slow(Duration::from_millis(99)));
+        assert!(audit.is_slow(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,219 @@
+//! First-run creation of the schema, for `fhir-server --bootstrap`.
+//!
+//! The migrations of `run_migrations.sql` are compiled into the binary and
+//! applied in order in one transaction, so a bootstrap that fails leaves the
+//! database as empty as it found it. Only a database without
+//! `fhir_resources` is bootstrapped; one that has the schema, complete or
+//! not, is left alone and upgraded with the migrations as before. The SQL
+//! extension functions of `002_fhir_extension_functions.sql` are always
+//! created, and the pgrx extension `fhir_extension` as well when it is
+//! installed on the database server.
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::{Context, Result};
+use sqlx::Row;
+
+/// The migrations in the order `run_migrations.sql` applies them
+pub const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "000_fhir_resources_table.sql",
+        include_str!("../../../migrations/000_fhir_resources_table.sql"),
+    ),
+    (
+        "001_initial_schema.sql",
+        include_str!("../../../migrations/001_initial_schema.sql"),
+    ),
+    (
+        "002_add_search_functions.sql",
+        include_str!("../../../migrations/002_add_search_functions.sql"),
+    ),
+    (
+        "002_fhir_extension_functions.sql",
+        include_str!("../../../migrations/002_fhir_extension_functions.sql"),
+    ),
+    (
+        "003_fhir_search_helpers.sql",
+        include_str!("../../../migrations/003_fhir_search_helpers.sql"),
+    ),
+    (
+        "005_resource_identifiers.sql",
+        include_str!("../../../migrations/005_resource_identifiers.sql"),
+    ),
+    (
+        "006_soft_delete.sql",
+        include_str!("../../../migrations/006_soft_delete.sql"),
+    ),
+    (
+        "007_request_capture.sql",
+        include_str!("../../../migrations/007_request_capture.sql"),
+    ),
+    (
+        "008_content_hash.sql",
+        include_str!("../../../migrations/008_content_hash.sql"),
+    ),
+    (
+        "009_name_phonetic.sql",
+        include_str!("../../../migrations/009_name_phonetic.sql"),
+    ),
+    (
+        "010_history_ts_index.sql",
+        include_str!("../../../migrations/010_history_ts_index.sql"),
+    ),
+    (
+        "011_change_outbox.sql",
+        include_str!("../../../migrations/011_change_outbox.sql"),
+    ),
+    (
+        "012_resource_archive.sql",
+        include_str!("../../../migrations/012_resource_archive.sql"),
+    ),
+    (
+        "013_schema_changes.sql",
+        include_str!("../../../migrations/013_schema_changes.sql"),
+    ),
+    (
+        "014_conformance_canonical.sql",
+        include_str!("../../../migrations/014_conformance_canonical.sql"),
+    ),
+    (
+        "015_id_reservations.sql",
+        include_str!("../../../migrations/015_id_reservations.sql"),
+    ),
+    (
+        "016_full_text_search.sql",
+        include_str!("../../../migrations/016_full_text_search.sql"),
+    ),
+    (
+        "017_custom_search_parameters.sql",
+        include_str!("../../../migrations/017_custom_search_parameters.sql"),
+    ),
+    (
+        "018_snapshots.sql",
+        include_str!("../../../migrations/018_snapshots.sql"),
+    ),
+    (
+        "019_api_keys.sql",
+        include_str!("../../../migrations/019_api_keys.sql"),
+    ),
+    (
+        "020_patient_match.sql",
+        include_str!("../../../migrations/020_patient_match.sql"),
+    ),
+    (
+        "021_conformance_maps.sql",
+        include_str!("../../../migrations/021_conformance_maps.sql"),
+    ),
+    (
+        "022_record_locks.sql",
+        include_str!("../../../migrations/022_record_locks.sql"),
+    ),
+    (
+        "023_change_notify.sql",
+        include_str!("../../../migrations/023_change_notify.sql"),
+    ),
+    (
+        "024_provenance_target.sql",
+        include_str!("../../../migrations/024_provenance_target.sql"),
+    ),
+    (
+        "025_normalized_strings.sql",
+        include_str!("../../../migrations/025_normalized_strings.sql"),
+    ),
+    (
+        "026_history_compression.sql",
+        include_str!("../../../migrations/026_history_compression.sql"),
+    ),
+    (
+        "027_api_key_scopes.sql",
+        include_str!("../../../migrations/027_api_key_scopes.sql"),
+    ),
+    (
+        "028_extension_row_shapes.sql",
+        include_str!("../../../migrations/028_extension_row_shapes.sql"),
+    ),
+    (
+        "029_maintenance.sql",
+        include_str!("../../../migrations/029_maintenance.sql"),
+    ),
+    (
+        "030_consent_instants.sql",
+        include_str!("../../../migrations/030_consent_instants.sql"),
+    ),
+    (
+        "031_maintenance_batches.sql",
+        include_str!("../../../migrations/031_maintenance_batches.sql"),
+    ),
+];
+
+/// Name of the pgrx extension built from `db/`
+pub(crate) const EXTENSION: &str = "fhir_extension";
+
+/// What [`Database::bootstrap_schema`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaBootstrap {
+    /// The database already had the schema
+    Existing,
+    Created {
+        migrations: usize,
+        /// Whether the pgrx extension was created too
+        extension: bool,
+    },
+}
+
+impl Database {
+    /// Create the schema if the database has none
+    pub async fn bootstrap_schema(&self) -> Result<SchemaBootstrap> {
+        if self.table_exists("fhir_resources").await? {
+            return Ok(SchemaBootstrap::Existing);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        for (name, sql) in MIGRATIONS {
+            tracing::info!("Applying migration {}", name);
+            sqlx::raw_sql(sql)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Migration {} failed", name))?;
+        }
+        let available = self
+            .fetch_one_on(
+                &mut *tx,
+                "SELECT EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = $1) AS available",
+                &[SqlParam::text(EXTENSION)],
+            )
+            .await?
+            .get("available");
+        if available {
+            self.execute_on(
+                &mut *tx,
+                &format!("CREATE EXTENSION IF NOT EXISTS {}", EXTENSION),
+                &[],
+            )
+            .await
+            .with_context(|| format!("Failed to create extension {}", EXTENSION))?;
+        }
+        tx.commit().await?;
+
+        Ok(SchemaBootstrap::Created {
+            migrations: MIGRATIONS.len(),
+            extension: available,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_match_run_migrations() {
+        let script = include_str!("../../../migrations/run_migrations.sql");
+        let listed: Vec<&str> = script
+            .lines()
+            .filter_map(|line| line.strip_prefix("\\i migrations/"))
+            .collect();
+        let embedded: Vec<&str> = MIGRATIONS.iter().map(|(name, _)| *name).collect();
+        assert_eq!(embedded, listed);
+    }
+}
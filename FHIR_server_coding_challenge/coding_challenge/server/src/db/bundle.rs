@@ -0,0 +1,201 @@
+//! Storage operations of batch and transaction Bundle entries.
+//!
+//! Entries run on the connection of one [`BundleTransaction`]: a
+//! `transaction` Bundle uses a single one so all its entries commit or roll
+//! back together, a `batch` Bundle opens one per entry. Patients go through
+//! the same queries as the `/fhir/Patient` endpoints, so identifier
+//! uniqueness and history apply to Bundle writes as well.
+
+use super::reservation::PatientPut;
+use super::resource::StoredResource;
+use super::Database;
+use crate::models::Patient;
+use crate::provenance::{Activity, Attribution};
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+/// An open database transaction for Bundle entries; dropping it without
+/// calling [`BundleTransaction::commit`] rolls back every entry
+pub struct BundleTransaction<'a> {
+    db: &'a Database,
+    tx: Transaction<'static, Postgres>,
+}
+
+impl Database {
+    pub async fn begin_bundle(&self) -> Result<BundleTransaction<'_>> {
+        Ok(BundleTransaction {
+            db: self,
+            tx: self.pool.begin().await?,
+        })
+    }
+}
+
+impl BundleTransaction<'_> {
+    /// Create a Patient under a caller-chosen id, so entries can refer to
+    /// it before it is written
+    pub async fn create_patient(&mut self, id: Uuid, patient: Patient) -> Result<Patient> {
+        self.db.create_patient_on(&mut self.tx, id, patient).await
+    }
+
+    pub async fn get_patient(&mut self, id: &str) -> Result<Option<Patient>> {
+        self.db.get_patient_on(&mut self.tx, id).await
+    }
+
+    /// See [`Database::put_patient_if_match`]
+    pub async fn put_patient(
+        &mut self,
+        id: &str,
+        patient: Patient,
+        expected_version: Option<i32>,
+    ) -> Result<Option<PatientPut>> {
+        self.db
+            .put_patient_on(&mut self.tx, id, patient, expected_version)
+            .await
+    }
+
+    /// Refuse to write patient `id` without the token of a lock holding it;
+    /// see [`Database::check_record_lock_on`]
+    pub async fn check_patient_lock(&mut self, id: &str, token_hash: Option<&str>) -> Result<()> {
+        self.db
+            .check_record_lock_on(&mut self.tx, "Patient", id, token_hash)
+            .await
+    }
+
+    pub async fn delete_patient(&mut self, id: &str) -> Result<bool> {
+        self.db.delete_patient_on(&mut self.tx, id).await
+    }
+
+    /// Create a resource of `resource_type` under a caller-chosen id
+    pub async fn create_resource(
+        &mut self,
+        resource_type: &str,
+        id: Uuid,
+        resource: Value,
+    ) -> Result<StoredResource> {
+        self.db
+            .create_resource_on(&mut self.tx, resource_type, id, resource)
+            .await
+    }
+
+    pub async fn get_resource(
+        &mut self,
+        resource_type: &str,
+        id: &str,
+    ) -> Result<Option<StoredResource>> {
+        self.db
+            .get_resource_on(&mut self.tx, resource_type, id)
+            .await
+    }
+
+    pub async fn update_resource(
+        &mut self,
+        resource_type: &str,
+        id: &str,
+        resource: Value,
+    ) -> Result<Option<StoredResource>> {
+        self.db
+            .update_resource_on(&mut self.tx, resource_type, id, resource)
+            .await
+    }
+
+    pub async fn delete_resource(&mut self, resource_type: &str, id: &str) -> Result<bool> {
+        self.db
+            .delete_resource_on(&mut self.tx, resource_type, id)
+            .await
+    }
+
+    /// See [`Database::record_provenance_on`]; the Provenance commits or
+    /// rolls back with the entries
+    pub async fn record_provenance(
+        &mut self,
+        attribution: &Attribution,
+        activity: Activity,
+        resource_type: &str,
+        id: &str,
+        version_id: i32,
+    ) -> Result<()> {
+        self.db
+            .record_provenance_on(
+                &mut self.tx,
+                attribution,
+                activity,
+                resource_type,
+                id,
+                version_id,
+            )
+            .await
+    }
+
+    /// [`BundleTransaction::record_provenance`] for a written Patient
+    pub async fn record_patient_provenance(
+        &mut self,
+        attribution: &Attribution,
+        activity: Activity,
+        patient: &Patient,
+    ) -> Result<()> {
+        self.db
+            .record_patient_provenance_on(&mut self.tx, attribution, activity, patient)
+            .await
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        Ok(self.tx.commit().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_uncommitted_bundle_rolls_back() {
+        let db = setup_test_db().await;
+        let patient_id = Uuid::new_v4();
+        let encounter_id = Uuid::new_v4();
+
+        let mut tx = db.begin_bundle().await.unwrap();
+        tx.create_patient(patient_id, Patient::new()).await.unwrap();
+        tx.create_resource(
+            "Encounter",
+            encounter_id,
+            json!({
+                "resourceType": "Encounter",
+                "subject": { "reference": format!("Patient/{}", patient_id) }
+            }),
+        )
+        .await
+        .unwrap();
+        // Entries see the writes of earlier entries
+        assert!(tx
+            .get_patient(&patient_id.to_string())
+            .await
+            .unwrap()
+            .is_some());
+        drop(tx);
+
+        assert!(db
+            .get_patient(&patient_id.to_string())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db
+            .get_resource("Encounter", &encounter_id.to_string())
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut tx = db.begin_bundle().await.unwrap();
+        let created = tx.create_patient(patient_id, Patient::new()).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(created.id, Some(patient_id.to_string()));
+        assert!(db
+            .get_patient(&patient_id.to_string())
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
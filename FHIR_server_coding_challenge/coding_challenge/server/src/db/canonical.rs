@@ -0,0 +1,132 @@
+//! Canonical JSON for stored resources and their content hash.
+//!
+//! Resources are normalized before they are written: members whose value is
+//! `null` are dropped and numbers without a fractional part are stored as
+//! integers (`1.0`, `1e0` and `1` are the same value). The hash is a SHA-256
+//! over the normalized body with object keys in sorted order, excluding the
+//! server-assigned `id` and `meta`, so two writes of the same content hash
+//! the same whatever order and formatting the client used.
+
+use serde_json::{Map, Number, Value};
+use sha2::{Digest, Sha256};
+
+/// Largest integer an f64 represents exactly
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// `value` with nulls removed from objects and integral numbers as integers
+pub fn normalize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, normalize(v)))
+                .collect::<Map<String, Value>>(),
+        ),
+        // Array positions are significant, so nulls inside arrays are kept
+        Value::Array(items) => Value::Array(items.into_iter().map(normalize).collect()),
+        Value::Number(n) => Value::Number(normalize_number(n)),
+        other => other,
+    }
+}
+
+fn normalize_number(n: Number) -> Number {
+    match n.as_f64() {
+        Some(f)
+            if !n.is_i64() && !n.is_u64() && f.fract() == 0.0 && f.abs() <= MAX_EXACT_INTEGER =>
+        {
+            Number::from(f as i64)
+        }
+        _ => n,
+    }
+}
+
+/// Hex SHA-256 of the canonical form of `resource`, without `id` and `meta`
+pub fn content_hash(resource: &Value) -> String {
+    let mut body = normalize(resource.clone());
+    if let Value::Object(map) = &mut body {
+        map.remove("id");
+        map.remove("meta");
+    }
+    let mut canonical = String::new();
+    write_canonical(&body, &mut canonical);
+
+    Sha256::digest(canonical.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Compact JSON with object keys in sorted order
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(&map[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize_strips_nulls_and_integral_floats() {
+        let normalized = normalize(json!({
+            "gender": null,
+            "multipleBirthInteger": 2.0,
+            "extension": [{ "valueDecimal": 1.5, "url": null }],
+            "given": ["Ada", null]
+        }));
+
+        assert_eq!(
+            normalized,
+            json!({
+                "multipleBirthInteger": 2,
+                "extension": [{ "valueDecimal": 1.5 }],
+                "given": ["Ada", null]
+            })
+        );
+    }
+
+    #[test]
+    fn test_content_hash_ignores_formatting_and_server_elements() {
+        let a: Value = serde_json::from_str(
+            r#"{"resourceType":"Patient","gender":"female","id":"1","meta":{"versionId":"1"},"birthDate":"1980-01-01"}"#,
+        )
+        .unwrap();
+        let b: Value = serde_json::from_str(
+            r#"{ "birthDate": "1980-01-01", "active": null, "gender": "female", "resourceType": "Patient" }"#,
+        )
+        .unwrap();
+
+        assert_eq!(content_hash(&a), content_hash(&b));
+        assert_eq!(content_hash(&a).len(), 64);
+        assert_ne!(
+            content_hash(&a),
+            content_hash(&json!({ "resourceType": "Patient", "gender": "male" }))
+        );
+    }
+}
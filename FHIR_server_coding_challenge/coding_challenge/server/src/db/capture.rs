@@ -0,0 +1,123 @@
+//! Ring buffer of captured failed interactions in `fhir.request_capture`.
+
+use super::audit::SqlParam;
+use super::Database;
+use crate::capture::CapturedExchange;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::Row;
+use uuid::Uuid;
+
+impl Database {
+    /// Store a capture in the next slot of a ring of `capacity` slots,
+    /// overwriting the oldest capture once the ring is full
+    pub async fn record_capture(&self, exchange: &CapturedExchange, capacity: u32) -> Result<()> {
+        self.execute(
+            "INSERT INTO fhir.request_capture
+                 (slot, request_id, captured_at, method, uri, status, duration_ms,
+                  request_headers, request_body, response_headers, response_body)
+             VALUES (nextval('fhir.request_capture_seq') % $1, $2, $3::timestamptz, $4, $5, $6, $7, $8, $9, $10, $11)
+             ON CONFLICT (slot) DO UPDATE SET
+                 request_id = EXCLUDED.request_id,
+                 captured_at = EXCLUDED.captured_at,
+                 method = EXCLUDED.method,
+                 uri = EXCLUDED.uri,
+                 status = EXCLUDED.status,
+                 duration_ms = EXCLUDED.duration_ms,
+                 request_headers = EXCLUDED.request_headers,
+                 request_body = EXCLUDED.request_body,
+                 response_headers = EXCLUDED.response_headers,
+                 response_body = EXCLUDED.response_body",
+            &[
+                SqlParam::BigInt(capacity as i64),
+                SqlParam::Uuid(exchange.request_id),
+                SqlParam::text(exchange.captured_at.to_rfc3339()),
+                SqlParam::text(exchange.method.as_str()),
+                SqlParam::text(exchange.uri.as_str()),
+                SqlParam::Int(exchange.status as i32),
+                SqlParam::BigInt(exchange.duration_ms as i64),
+                SqlParam::Json(exchange.request_headers.clone()),
+                SqlParam::Json(exchange.request_body.clone().unwrap_or(Value::Null)),
+                SqlParam::Json(exchange.response_headers.clone()),
+                SqlParam::Json(exchange.response_body.clone().unwrap_or(Value::Null)),
+            ],
+        )
+        .await?;
+
+        // Slots beyond a reduced capacity are never overwritten again
+        self.execute(
+            "DELETE FROM fhir.request_capture WHERE slot >= $1",
+            &[SqlParam::BigInt(capacity as i64)],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// The capture of a request, if it failed and has not been overwritten
+    pub async fn get_capture(&self, request_id: Uuid) -> Result<Option<CapturedExchange>> {
+        let row = self
+            .fetch_optional(
+                "SELECT request_id, captured_at, method, uri, status, duration_ms,
+                        request_headers, request_body, response_headers, response_body
+                 FROM fhir.request_capture WHERE request_id = $1",
+                &[SqlParam::Uuid(request_id)],
+            )
+            .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let status: i32 = row.get("status");
+        let duration_ms: i64 = row.get("duration_ms");
+        let body = |column: &str| {
+            let value: Value = row.get(column);
+            (!value.is_null()).then_some(value)
+        };
+        Ok(Some(CapturedExchange {
+            request_id: row.get("request_id"),
+            captured_at: row.get("captured_at"),
+            method: row.get("method"),
+            uri: row.get("uri"),
+            status: status as u16,
+            duration_ms: duration_ms as u64,
+            request_headers: row.get("request_headers"),
+            request_body: body("request_body"),
+            response_headers: row.get("response_headers"),
+            response_body: body("response_body"),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_capture_round_trip() {
+        let db = setup_test_db().await;
+        let exchange = CapturedExchange {
+            request_id: Uuid::new_v4(),
+            captured_at: Utc::now(),
+            method: "POST".to_string(),
+            uri: "/fhir/Patient".to_string(),
+            status: 422,
+            duration_ms: 3,
+            request_headers: json!({ "content-type": "application/fhir+json" }),
+            request_body: Some(json!({ "resourceType": "Patient" })),
+            response_headers: json!({}),
+            response_body: None,
+        };
+
+        db.record_capture(&exchange, 1000).await.unwrap();
+        let stored = db.get_capture(exchange.request_id).await.unwrap().unwrap();
+
+        assert_eq!(stored.status, 422);
+        assert_eq!(stored.request_body, exchange.request_body);
+        assert!(stored.response_body.is_none());
+        assert!(db.get_capture(Uuid::new_v4()).await.unwrap().is_none());
+    }
+}
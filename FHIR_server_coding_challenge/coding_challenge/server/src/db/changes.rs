@@ -0,0 +1,232 @@
+//! The change feed read by `GET /fhir/_changes`.
+//!
+//! A trigger on `fhir_resources` records every create, update and delete in
+//! `fhir.change_outbox` within the writing transaction (migration
+//! `011_change_outbox.sql`). Records are read in `(txid, seq)` order, and
+//! only those of transactions older than every transaction still running:
+//! a write that commits late therefore still comes after any cursor handed
+//! out before it, at the cost of the feed waiting for long transactions.
+//!
+//! With migration `023_change_notify.sql` each record is also announced on
+//! the [`CHANGE_CHANNEL`] when its transaction commits, for `GET /fhir/_events`.
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::postgres::{PgListener, PgRow};
+use sqlx::Row;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// Position in the change feed; changes after it have not been read yet.
+/// The default is the start of the feed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ChangeCursor {
+    txid: i64,
+    seq: i64,
+}
+
+impl ChangeCursor {
+    /// Parse a cursor token as handed out by the feed
+    pub fn parse(token: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid cursor '{}'", token);
+        let (txid, seq) = token.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            txid: txid.parse().map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for ChangeCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.txid, self.seq)
+    }
+}
+
+/// One write recorded in the outbox
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub resource_type: String,
+    pub id: Uuid,
+    pub version_id: i32,
+    /// `create`, `update` or `delete`
+    pub op: String,
+    pub timestamp: DateTime<Utc>,
+    /// Cursor just after this change
+    pub cursor: ChangeCursor,
+}
+
+impl Change {
+    fn from_row(row: &PgRow) -> Self {
+        Self {
+            resource_type: row.get("resource_type"),
+            id: row.get("resource_id"),
+            version_id: row.get("version_id"),
+            op: row.get("op"),
+            timestamp: row.get("ts"),
+            cursor: ChangeCursor {
+                txid: row.get("txid"),
+                seq: row.get("seq"),
+            },
+        }
+    }
+}
+
+/// Payload of a notification on [`CHANGE_CHANNEL`]: the outbox row
+#[derive(Deserialize)]
+struct Notified {
+    txid: i64,
+    seq: i64,
+    resource_type: String,
+    id: Uuid,
+    version_id: i32,
+    op: String,
+    ts: DateTime<Utc>,
+}
+
+/// Channel `fhir.notify_change` notifies of every change
+pub const CHANGE_CHANNEL: &str = "fhir_changes";
+
+impl Change {
+    /// The change a notification on [`CHANGE_CHANNEL`] announces
+    pub fn from_notification(payload: &str) -> Result<Self> {
+        let row: Notified = serde_json::from_str(payload)?;
+        Ok(Self {
+            resource_type: row.resource_type,
+            id: row.id,
+            version_id: row.version_id,
+            op: row.op,
+            timestamp: row.ts,
+            cursor: ChangeCursor {
+                txid: row.txid,
+                seq: row.seq,
+            },
+        })
+    }
+}
+
+impl Database {
+    /// Whether `fhir.notify_change` exists, checked at startup
+    pub fn events_enabled(&self) -> bool {
+        self.events_enabled.load(Ordering::Relaxed)
+    }
+
+    /// A connection of its own listening on [`CHANGE_CHANNEL`]
+    pub async fn listen_changes(&self) -> Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANGE_CHANNEL).await?;
+        Ok(listener)
+    }
+
+    /// Whether `fhir.change_outbox` exists, checked at startup
+    pub fn changes_enabled(&self) -> bool {
+        self.changes_enabled.load(Ordering::Relaxed)
+    }
+
+    /// A cursor after every change visible now, to follow only later
+    /// writes; changes of transactions still running come after it
+    pub async fn current_change_cursor(&self) -> Result<ChangeCursor> {
+        let row = self
+            .fetch_one(
+                "SELECT txid_snapshot_xmin(txid_current_snapshot())::BIGINT - 1 AS txid",
+                &[],
+            )
+            .await?;
+        Ok(ChangeCursor {
+            txid: row.get("txid"),
+            seq: i64::MAX,
+        })
+    }
+
+    /// Up to `limit` changes after `cursor`, oldest first
+    pub async fn changes_after(&self, cursor: ChangeCursor, limit: u32) -> Result<Vec<Change>> {
+        let rows = self
+            .fetch_all(
+                "SELECT seq, txid, resource_type, resource_id, version_id, op, ts
+                 FROM fhir.change_outbox
+                 WHERE (txid, seq) > ($1, $2)
+                   AND txid < txid_snapshot_xmin(txid_current_snapshot())
+                 ORDER BY txid, seq
+                 LIMIT $3",
+                &[
+                    SqlParam::BigInt(cursor.txid),
+                    SqlParam::BigInt(cursor.seq),
+                    SqlParam::BigInt(limit as i64),
+                ],
+            )
+            .await?;
+        Ok(rows.iter().map(Change::from_row).collect())
+    }
+
+    /// Number of changes after `cursor` that [`Database::changes_after`]
+    /// would return now
+    pub async fn changes_pending_after(&self, cursor: ChangeCursor) -> Result<i64> {
+        let row = self
+            .fetch_one(
+                "SELECT COUNT(*) AS pending
+                 FROM fhir.change_outbox
+                 WHERE (txid, seq) > ($1, $2)
+                   AND txid < txid_snapshot_xmin(txid_current_snapshot())",
+                &[SqlParam::BigInt(cursor.txid), SqlParam::BigInt(cursor.seq)],
+            )
+            .await?;
+        Ok(row.get("pending"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+
+    #[test]
+    fn test_cursor_tokens_round_trip() {
+        let cursor = ChangeCursor { txid: 812, seq: 40 };
+        assert_eq!(cursor.to_string(), "812-40");
+        assert_eq!(ChangeCursor::parse("812-40"), Ok(cursor));
+        assert!(ChangeCursor::parse("812").is_err());
+        assert!(ChangeCursor::parse("a-b").is_err());
+        assert!(ChangeCursor::default() < cursor);
+    }
+
+    #[tokio::test]
+    async fn test_writes_appear_in_order_after_the_cursor() {
+        let db = setup_test_db().await;
+        let start = db.current_change_cursor().await.unwrap();
+        let created = db.create_patient(Patient::new()).await.unwrap();
+        let id = created.id.clone().unwrap();
+        let mut patient = created;
+        patient.gender = Some("other".to_string());
+        db.update_patient(&id, patient).await.unwrap();
+        db.delete_patient(&id).await.unwrap();
+        let uuid = Uuid::parse_str(&id).unwrap();
+
+        // Other tests' transactions may hold back the newest changes for a
+        // moment
+        let mut seen = Vec::new();
+        for _ in 0..50 {
+            let mut cursor = start;
+            seen.clear();
+            loop {
+                let page = db.changes_after(cursor, 1000).await.unwrap();
+                let Some(last) = page.last() else { break };
+                assert!(last.cursor > cursor);
+                cursor = last.cursor;
+                seen.extend(page.into_iter().filter(|c| c.id == uuid));
+            }
+            if seen.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        let ops: Vec<(&str, i32)> = seen.iter().map(|c| (c.op.as_str(), c.version_id)).collect();
+        assert_eq!(ops, vec![("create", 1), ("update", 2), ("delete", 3)]);
+        assert!(seen.iter().all(|c| c.resource_type == "Patient"));
+    }
+}
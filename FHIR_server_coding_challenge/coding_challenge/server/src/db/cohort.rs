@@ -0,0 +1,222 @@
+//! Cohort queries: a [`Criterion`] tree compiled to one SQL condition over
+//! live Patients.
+
+use super::audit::SqlParam;
+use super::{
+    date_condition, exact_name_condition, identifier_condition, missing_condition, name_condition,
+    phonetic_name_condition, Database, Withholding,
+};
+use crate::search::cohort::{Criterion, PatientParam};
+use anyhow::Result;
+use sqlx::Row;
+use uuid::Uuid;
+
+/// SQL condition for `criterion`, pushing its binds onto `params`
+fn condition(criterion: &Criterion, params: &mut Vec<SqlParam>) -> String {
+    match criterion {
+        Criterion::And(members) => group(members, " AND ", params),
+        Criterion::Or(members) => group(members, " OR ", params),
+        // A missing element makes a comparison NULL; it does not match, so
+        // its negation must
+        Criterion::Not(inner) => format!("NOT COALESCE({}, FALSE)", condition(inner, params)),
+        Criterion::Param(param) => param_condition(param, params),
+    }
+}
+
+fn group(members: &[Criterion], operator: &str, params: &mut Vec<SqlParam>) -> String {
+    let conditions: Vec<String> = members.iter().map(|m| condition(m, params)).collect();
+    format!("({})", conditions.join(operator))
+}
+
+fn param_condition(param: &PatientParam, params: &mut Vec<SqlParam>) -> String {
+    match param {
+        PatientParam::Name(name) => name_condition(name, params),
+        PatientParam::NameExact(name) => exact_name_condition(name, params),
+        PatientParam::NamePhonetic(value) => phonetic_name_condition(value, params),
+        PatientParam::Gender(gender) => {
+            params.push(SqlParam::text(gender.as_str()));
+            format!("(resource_data->>'gender' = ${})", params.len())
+        }
+        PatientParam::BirthDate(date) => {
+            date_condition("resource_data->>'birthDate'", date, params)
+        }
+        PatientParam::Identifier(token) => identifier_condition(token, params),
+        PatientParam::Missing { element, missing } => missing_condition(element, *missing),
+    }
+}
+
+impl Database {
+    /// Ids of the live patients matching `criteria`, in id order, less
+    /// those a Consent withholds when `withholding` is given
+    pub async fn cohort_ids(
+        &self,
+        criteria: &Criterion,
+        withholding: Option<Withholding<'_>>,
+    ) -> Result<Vec<String>> {
+        let mut params = Vec::new();
+        let condition = condition(criteria, &mut params);
+        let query = format!(
+            "SELECT id FROM fhir_resources
+             WHERE resource_type = 'Patient' AND deleted_at IS NULL AND {}{}
+             ORDER BY id",
+            condition,
+            self.unwithheld(withholding, &mut params)
+        );
+
+        let rows = self.fetch_all(&query, &params).await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.get::<Uuid, _>("id").to_string())
+            .collect())
+    }
+
+    /// Number of the patients [`Database::cohort_ids`] lists
+    pub async fn cohort_count(
+        &self,
+        criteria: &Criterion,
+        withholding: Option<Withholding<'_>>,
+    ) -> Result<i64> {
+        let mut params = Vec::new();
+        let condition = condition(criteria, &mut params);
+        let query = format!(
+            "SELECT COUNT(*) AS count FROM fhir_resources
+             WHERE resource_type = 'Patient' AND deleted_at IS NULL AND {}{}",
+            condition,
+            self.unwithheld(withholding, &mut params)
+        );
+
+        let row = self.fetch_one(&query, &params).await?;
+        Ok(row.get("count"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::stored_date_range;
+    use crate::db::tests::setup_test_db;
+    use crate::models::{HumanName, Patient};
+    use crate::search::cohort::CohortQuery;
+    use serde_json::{json, Map};
+
+    fn patient(family: &str, gender: Option<&str>, birth_date: &str, mrn: &str) -> Patient {
+        let mut patient = Patient::new();
+        patient.name = Some(vec![HumanName {
+            family: Some(family.to_string()),
+            given: None,
+            text: None,
+            extra: Map::new(),
+        }]);
+        patient.gender = gender.map(str::to_string);
+        patient.birth_date = Some(birth_date.to_string());
+        patient.extra.insert(
+            "identifier".to_string(),
+            json!([{ "system": "urn:cohort-test", "value": mrn }]),
+        );
+        patient
+    }
+
+    #[test]
+    fn test_condition_binds_in_order() {
+        let query = CohortQuery::parse(&json!({
+            "criteria": { "and": [
+                { "param": "gender", "value": "female" },
+                { "not": { "param": "birthdate", "value": "lt1950" } }
+            ]}
+        }))
+        .unwrap();
+        let mut params = Vec::new();
+
+        let (start, _) = stored_date_range("resource_data->>'birthDate'");
+        assert_eq!(
+            condition(&query.criteria, &mut params),
+            format!(
+                "((resource_data->>'gender' = $1) AND NOT COALESCE(({} < $2::timestamptz), FALSE))",
+                start
+            )
+        );
+        // The date's range: its start and its end
+        assert_eq!(params.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cohort_combines_criteria() {
+        let db = setup_test_db().await;
+        // Criteria are scoped to this run by a batch identifier
+        let batch = Uuid::new_v4().to_string();
+        let mut ids = Vec::new();
+        for (family, gender, birth_date) in [
+            ("Meier", Some("female"), "1948-05-01"),
+            ("Mayer", Some("male"), "1975-02-11"),
+            ("Schulz", Some("female"), "1980-09-30"),
+            ("Schulz", None, "1990-01-01"),
+        ] {
+            let created = db
+                .create_patient(patient(family, gender, birth_date, &batch))
+                .await
+                .unwrap();
+            ids.push(created.id.unwrap());
+        }
+
+        let cohort = |criteria: serde_json::Value| {
+            let db = &db;
+            let batch = batch.clone();
+            async move {
+                let criteria = json!({ "and": [
+                    { "param": "identifier", "value": format!("urn:cohort-test|{}", batch) },
+                    criteria
+                ]});
+                let query = CohortQuery::parse(&json!({ "criteria": criteria })).unwrap();
+                let count = db.cohort_count(&query.criteria, None).await.unwrap();
+                let mut found = db.cohort_ids(&query.criteria, None).await.unwrap();
+                assert_eq!(found.len() as i64, count);
+                found.sort();
+                found
+            }
+        };
+        let sorted = |mut v: Vec<String>| {
+            v.sort();
+            v
+        };
+
+        assert_eq!(
+            cohort(json!({ "or": [
+                { "param": "name:phonetic", "value": "Maier" },
+                { "param": "birthdate", "value": "ge1985" }
+            ]}))
+            .await,
+            sorted(vec![ids[0].clone(), ids[1].clone(), ids[3].clone()])
+        );
+        assert_eq!(
+            cohort(json!({ "and": [
+                { "param": "gender", "value": "female" },
+                { "not": { "param": "name", "value": "Schulz" } }
+            ]}))
+            .await,
+            vec![ids[0].clone()]
+        );
+        // Patients without a gender are not female
+        assert_eq!(
+            cohort(json!({ "not": { "param": "gender", "value": "female" } })).await,
+            sorted(vec![ids[1].clone(), ids[3].clone()])
+        );
+        assert_eq!(
+            cohort(json!({ "param": "gender:missing", "value": "true" })).await,
+            vec![ids[3].clone()]
+        );
+        assert_eq!(
+            cohort(json!({ "and": [
+                { "param": "name:exact", "value": "Schulz" },
+                { "param": "gender:missing", "value": "false" }
+            ]}))
+            .await,
+            vec![ids[2].clone()]
+        );
+
+        db.delete_patient(&ids[3]).await.unwrap();
+        assert_eq!(
+            cohort(json!({ "param": "name", "value": "Schulz" })).await,
+            vec![ids[2].clone()]
+        );
+    }
+}
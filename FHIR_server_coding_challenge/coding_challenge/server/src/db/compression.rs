@@ -0,0 +1,226 @@
+//! Compression of the Patient history at rest.
+//!
+//! Old versions are read far less often than current resources, but they
+//! make up most of the stored data. `026_history_compression.sql` lets
+//! PostgreSQL compress `fhir.patient_history.resource` with LZ4 (PostgreSQL
+//! 14 or later built with lz4), down to versions of a few hundred bytes.
+//! Current resources in `fhir_resources` keep the default storage, and
+//! PostgreSQL decompresses values as it reads them, so every read of
+//! history, the extension functions included, works on compressed rows
+//! unchanged.
+//!
+//! A column's compression only applies to values written after it was set.
+//! `fhir-server compress-history` ([`Database::compress_history`]) rewrites
+//! the existing versions in batches so they are stored with the chosen
+//! method as well. PostgreSQL has no ZSTD compression of column values, so
+//! only `lz4` and `pglz` can be chosen.
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::Row;
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// Versions rewritten per statement
+const COMPRESS_BATCH: i64 = 500;
+
+/// How history versions are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryCompression {
+    Lz4,
+    Pglz,
+}
+
+impl HistoryCompression {
+    pub fn parse(method: &str) -> Result<Self> {
+        match method {
+            "lz4" => Ok(Self::Lz4),
+            "pglz" => Ok(Self::Pglz),
+            "zstd" => anyhow::bail!(
+                "PostgreSQL compresses column values with lz4 or pglz only; zstd is not available"
+            ),
+            other => anyhow::bail!("Unknown compression {}; use lz4 or pglz", other),
+        }
+    }
+
+    /// The name PostgreSQL uses, as in `pg_column_compression`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lz4 => "lz4",
+            Self::Pglz => "pglz",
+        }
+    }
+}
+
+/// Versions stored with one compression method
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionStats {
+    /// `lz4`, `pglz`, or `none` for values stored as they are
+    pub method: String,
+    pub versions: i64,
+    /// Stored size of the versions' resources
+    pub bytes: i64,
+}
+
+impl Database {
+    /// Whether the history column has a compression method set, checked at
+    /// startup
+    pub fn history_compression_enabled(&self) -> bool {
+        self.history_compression_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Whether `fhir.patient_history.resource` has its own compression;
+    /// false before PostgreSQL 14, which has no such setting
+    pub(crate) async fn detect_history_compression(&self) -> bool {
+        let row = self
+            .fetch_optional(
+                "SELECT a.attcompression <> '' AS compressed FROM pg_attribute a
+                 WHERE a.attrelid = 'fhir.patient_history'::regclass AND a.attname = 'resource'",
+                &[],
+            )
+            .await;
+        matches!(row, Ok(Some(row)) if row.get::<bool, _>("compressed"))
+    }
+
+    /// How the history versions are stored, by compression method
+    pub async fn history_compression_report(&self) -> Result<Vec<CompressionStats>> {
+        let rows = self
+            .fetch_all(
+                "SELECT COALESCE(pg_column_compression(resource), 'none') AS method,
+                        COUNT(*) AS versions,
+                        COALESCE(SUM(pg_column_size(resource)), 0)::BIGINT AS bytes
+                 FROM fhir.patient_history
+                 GROUP BY 1
+                 ORDER BY 1",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| CompressionStats {
+                method: row.get("method"),
+                versions: row.get("versions"),
+                bytes: row.get("bytes"),
+            })
+            .collect())
+    }
+
+    /// Set the compression of the history column to `method` and rewrite
+    /// the versions stored otherwise, in batches; `progress` gets the
+    /// number rewritten so far after each batch. Returns how many versions
+    /// were rewritten. Versions too small to compress are rewritten too but
+    /// stay as they are.
+    pub async fn compress_history(
+        &self,
+        method: HistoryCompression,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64> {
+        if !self.history_enabled() {
+            anyhow::bail!("fhir.patient_history not found, run 001_initial_schema.sql");
+        }
+        self.execute_statement(&format!(
+            "ALTER TABLE fhir.patient_history ALTER COLUMN resource SET COMPRESSION {}",
+            method.as_str()
+        ))
+        .await?;
+        self.history_compression_enabled
+            .store(true, Ordering::Relaxed);
+
+        let mut after: Option<(Uuid, i32)> = None;
+        let mut rewritten = 0;
+        loop {
+            let (after_id, after_version) = after.unwrap_or((Uuid::nil(), 0));
+            let last = self
+                .fetch_optional(
+                    "SELECT id, version_id FROM (
+                         SELECT id, version_id FROM fhir.patient_history
+                         WHERE (id, version_id) > ($1, $2)
+                         ORDER BY id, version_id
+                         LIMIT $3
+                     ) batch
+                     ORDER BY id DESC, version_id DESC
+                     LIMIT 1",
+                    &[
+                        SqlParam::Uuid(after_id),
+                        SqlParam::Int(after_version),
+                        SqlParam::BigInt(COMPRESS_BATCH),
+                    ],
+                )
+                .await?;
+            let Some(last) = last else {
+                return Ok(rewritten);
+            };
+            let last: (Uuid, i32) = (last.get("id"), last.get("version_id"));
+
+            // A new value is written with the column's compression; the
+            // same value would be kept as it is stored
+            let result = self
+                .execute(
+                    "UPDATE fhir.patient_history SET resource = resource || '{}'::jsonb
+                     WHERE (id, version_id) > ($1, $2) AND (id, version_id) <= ($3, $4)
+                       AND pg_column_compression(resource) IS DISTINCT FROM $5",
+                    &[
+                        SqlParam::Uuid(after_id),
+                        SqlParam::Int(after_version),
+                        SqlParam::Uuid(last.0),
+                        SqlParam::Int(last.1),
+                        SqlParam::text(method.as_str()),
+                    ],
+                )
+                .await?;
+            rewritten += result.rows_affected();
+            progress(rewritten);
+            after = Some(last);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+
+    #[test]
+    fn test_compression_methods() {
+        assert_eq!(
+            HistoryCompression::parse("lz4").unwrap(),
+            HistoryCompression::Lz4
+        );
+        assert_eq!(HistoryCompression::Pglz.as_str(), "pglz");
+        let zstd = HistoryCompression::parse("zstd").unwrap_err().to_string();
+        assert!(zstd.contains("not available"), "{}", zstd);
+        assert!(HistoryCompression::parse("gzip").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_history_reads_back() {
+        let db = setup_test_db().await;
+        let mut patient = Patient::new();
+        patient.gender = Some("female".to_string());
+        let created = db.create_patient(patient).await.unwrap();
+        let id = created.id.clone().unwrap();
+
+        db.compress_history(HistoryCompression::Pglz, |_| {})
+            .await
+            .unwrap();
+        let again = db
+            .compress_history(HistoryCompression::Pglz, |_| {})
+            .await
+            .unwrap();
+        let report = db.history_compression_report().await.unwrap();
+
+        let version = db.get_patient_version(&id, 1).await.unwrap().unwrap();
+        assert_eq!(version["gender"], "female");
+        assert!(report.iter().map(|stats| stats.versions).sum::<i64>() >= 1);
+        // Only versions too small to compress are left to rewrite
+        let uncompressed = report
+            .iter()
+            .find(|stats| stats.method == "none")
+            .map_or(0, |stats| stats.versions);
+        assert!(again <= uncompressed as u64);
+    }
+}
@@ -0,0 +1,377 @@
+//! Conformance resources by canonical URL.
+//!
+//! StructureDefinitions, ValueSets, SearchParameters, ConceptMaps and
+//! StructureMaps are stored like any generically served type, and are always
+//! served. Their `url` and `version` name them: a live resource of each type
+//! is unique per pair (migrations `014_conformance_canonical.sql` and
+//! `021_conformance_maps.sql`), and a canonical reference
+//! `url|version` resolves to that version while a bare `url` resolves to
+//! the most recently written one. They are never archived, so references
+//! to them keep resolving however long ago they were written.
+
+use super::audit::SqlParam;
+use super::resource::{StoredResource, RESOURCE_COLUMNS};
+use super::Database;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::{PgConnection, Row};
+use std::fmt;
+use uuid::Uuid;
+
+/// Types stored and resolved by canonical URL
+pub const CONFORMANCE_RESOURCE_TYPES: &[&str] = &[
+    "StructureDefinition",
+    "ValueSet",
+    "SearchParameter",
+    "ConceptMap",
+    "StructureMap",
+];
+
+/// Partial unique index over the canonical of live conformance resources
+pub const CANONICAL_INDEX: &str = "idx_fhir_resources_canonical";
+
+pub fn is_conformance_type(resource_type: &str) -> bool {
+    CONFORMANCE_RESOURCE_TYPES.contains(&resource_type)
+}
+
+/// A canonical reference: `url`, optionally `|version`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canonical {
+    pub url: String,
+    /// `None` matches the most recently written version
+    pub version: Option<String>,
+}
+
+impl Canonical {
+    /// Split `url|version`; a `#fragment` names a part of the target and
+    /// does not change what it resolves to
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.split_once('#').map_or(raw, |(target, _)| target);
+        match raw.split_once('|') {
+            Some((url, version)) => Self {
+                url: url.to_string(),
+                version: (!version.is_empty()).then(|| version.to_string()),
+            },
+            None => Self {
+                url: raw.to_string(),
+                version: None,
+            },
+        }
+    }
+
+    /// The `url` and `version` a conformance resource is stored under
+    pub fn of(resource: &Value) -> Option<Self> {
+        let url = resource.get("url").and_then(Value::as_str)?;
+        Some(Self {
+            url: url.to_string(),
+            version: resource
+                .get("version")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for Canonical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}|{}", self.url, version),
+            None => write!(f, "{}", self.url),
+        }
+    }
+}
+
+/// A write was rejected because another resource of the same type already
+/// has its `url` and `version`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateCanonical {
+    pub resource_type: String,
+    pub canonical: Canonical,
+    /// Id of the resource holding the canonical, when known
+    pub existing_id: Option<String>,
+}
+
+impl fmt::Display for DuplicateCanonical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.resource_type, self.canonical.url)?;
+        match &self.canonical.version {
+            Some(version) => write!(f, "version {}", version)?,
+            None => write!(f, "without a version")?,
+        }
+        write!(f, " is already stored")?;
+        if let Some(id) = &self.existing_id {
+            write!(f, " as {}/{}", self.resource_type, id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DuplicateCanonical {}
+
+/// `e` as a [`DuplicateCanonical`] if it violated [`CANONICAL_INDEX`]
+pub(super) fn duplicate_on_violation(
+    e: anyhow::Error,
+    resource_type: &str,
+    canonical: Option<&Canonical>,
+) -> anyhow::Error {
+    let violated = match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_err)) => db_err.constraint() == Some(CANONICAL_INDEX),
+        _ => false,
+    };
+    match canonical {
+        Some(canonical) if violated => DuplicateCanonical {
+            resource_type: resource_type.to_string(),
+            canonical: canonical.clone(),
+            existing_id: None,
+        }
+        .into(),
+        _ => e,
+    }
+}
+
+/// Outcome of [`Database::import_conformance`]
+#[derive(Debug, Default)]
+pub struct ConformanceImport {
+    pub stored: Vec<StoredResource>,
+    /// Resources whose `url` and `version` were already stored; left as
+    /// they were
+    pub existing: Vec<DuplicateCanonical>,
+}
+
+impl Database {
+    /// Store the conformance resources of an imported package in one
+    /// transaction, keeping the ones whose canonical is already stored
+    pub async fn import_conformance(&self, resources: &[Value]) -> Result<ConformanceImport> {
+        let mut import = ConformanceImport::default();
+        let mut tx = self.pool.begin().await?;
+        for resource in resources {
+            let resource_type = resource
+                .get("resourceType")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let created = self
+                .create_resource_on(&mut tx, resource_type, Uuid::new_v4(), resource.clone())
+                .await;
+            // Found by the check before the insert; a violation of the
+            // index by a concurrent writer has aborted the transaction
+            match created {
+                Ok(stored) => import.stored.push(stored),
+                Err(e) => match e.downcast::<DuplicateCanonical>() {
+                    Ok(duplicate) if duplicate.existing_id.is_some() => {
+                        import.existing.push(duplicate)
+                    }
+                    Ok(duplicate) => return Err(duplicate.into()),
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        tx.commit().await?;
+
+        Ok(import)
+    }
+
+    /// The resource of one of `resource_types` that `canonical` names
+    pub async fn resolve_canonical(
+        &self,
+        resource_types: &[&str],
+        canonical: &Canonical,
+    ) -> Result<Option<StoredResource>> {
+        let mut params = vec![SqlParam::text(canonical.url.as_str())];
+        let types: Vec<String> = resource_types
+            .iter()
+            .map(|resource_type| {
+                params.push(SqlParam::text(*resource_type));
+                format!("${}", params.len())
+            })
+            .collect();
+        if types.is_empty() {
+            return Ok(None);
+        }
+        let version = match &canonical.version {
+            Some(version) => {
+                params.push(SqlParam::text(version.as_str()));
+                format!("AND resource_data->>'version' = ${}", params.len())
+            }
+            None => String::new(),
+        };
+
+        let row = self
+            .fetch_optional(
+                &format!(
+                    "SELECT {} FROM fhir_resources
+                     WHERE resource_type IN ({}) AND resource_data->>'url' = $1 {}
+                     ORDER BY last_updated DESC, id
+                     LIMIT 1",
+                    RESOURCE_COLUMNS,
+                    types.join(", "),
+                    version
+                ),
+                &params,
+            )
+            .await?;
+
+        Ok(row.as_ref().map(StoredResource::from_row))
+    }
+
+    /// Fail with [`DuplicateCanonical`] if a resource other than `id` holds
+    /// `canonical`. Checked before writing so the error can name the holder;
+    /// the unique index still guards concurrent writers.
+    pub(super) async fn check_canonical_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: Uuid,
+        canonical: &Canonical,
+    ) -> Result<()> {
+        let existing = self
+            .fetch_optional_on(
+                &mut *conn,
+                "SELECT id FROM fhir_resources
+                 WHERE resource_type = $1 AND resource_data->>'url' = $2
+                   AND COALESCE(resource_data->>'version', '') = $3 AND id <> $4",
+                &[
+                    SqlParam::text(resource_type),
+                    SqlParam::text(canonical.url.as_str()),
+                    SqlParam::text(canonical.version.as_deref().unwrap_or_default()),
+                    SqlParam::Uuid(id),
+                ],
+            )
+            .await?;
+
+        match existing {
+            Some(row) => Err(DuplicateCanonical {
+                resource_type: resource_type.to_string(),
+                canonical: canonical.clone(),
+                existing_id: Some(row.get::<Uuid, _>("id").to_string()),
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_canonical() {
+        assert_eq!(
+            Canonical::parse("http://example.org/ValueSet/codes|2.0#part"),
+            Canonical {
+                url: "http://example.org/ValueSet/codes".to_string(),
+                version: Some("2.0".to_string()),
+            }
+        );
+        assert_eq!(
+            Canonical::parse("http://example.org/ValueSet/codes|").version,
+            None
+        );
+        assert_eq!(
+            Canonical::parse("http://example.org/ValueSet/codes").to_string(),
+            "http://example.org/ValueSet/codes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_by_url_and_version() {
+        let db = setup_test_db().await;
+        let url = format!("http://example.org/fhir/ValueSet/{}", Uuid::new_v4());
+        let value_set = |version: &str| {
+            json!({
+                "resourceType": "ValueSet",
+                "url": url,
+                "version": version,
+                "status": "active"
+            })
+        };
+
+        let first = db
+            .create_resource("ValueSet", value_set("1.0"))
+            .await
+            .unwrap();
+        let second = db
+            .create_resource("ValueSet", value_set("2.0"))
+            .await
+            .unwrap();
+
+        let resolve = |raw: String| {
+            let db = &db;
+            async move {
+                db.resolve_canonical(CONFORMANCE_RESOURCE_TYPES, &Canonical::parse(&raw))
+                    .await
+                    .unwrap()
+                    .map(|stored| stored.id)
+            }
+        };
+        assert_eq!(resolve(format!("{}|1.0", url)).await, Some(first.id));
+        assert_eq!(resolve(url.clone()).await, Some(second.id));
+        assert_eq!(resolve(format!("{}|3.0", url)).await, None);
+        let other_type = db
+            .resolve_canonical(&["StructureDefinition"], &Canonical::parse(&url))
+            .await
+            .unwrap();
+        assert!(other_type.is_none());
+
+        // A second resource may not take an existing url and version
+        let err = db
+            .create_resource("ValueSet", value_set("1.0"))
+            .await
+            .unwrap_err();
+        let duplicate = err.downcast_ref::<DuplicateCanonical>().unwrap();
+        assert_eq!(duplicate.existing_id, Some(first.id.to_string()));
+        let err = db
+            .update_resource("ValueSet", &second.id.to_string(), value_set("1.0"))
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<DuplicateCanonical>().is_some());
+        // Re-saving the holder keeps its canonical
+        let resaved = db
+            .update_resource("ValueSet", &first.id.to_string(), value_set("1.0"))
+            .await
+            .unwrap();
+        assert!(resaved.is_some());
+
+        for stored in [first, second] {
+            db.delete_resource("ValueSet", &stored.id.to_string())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_conformance() {
+        let db = setup_test_db().await;
+        let url = format!("http://example.org/fhir/ValueSet/{}", Uuid::new_v4());
+        let resources = [
+            json!({"resourceType": "ValueSet", "url": url, "version": "1.0", "status": "active"}),
+            json!({"resourceType": "ValueSet", "url": url, "version": "2.0", "status": "active"}),
+        ];
+
+        let first = db.import_conformance(&resources).await.unwrap();
+        assert_eq!(first.stored.len(), 2);
+        assert!(first.existing.is_empty());
+        let resolved = db
+            .resolve_canonical(&["ValueSet"], &Canonical::parse(&format!("{}|1.0", url)))
+            .await
+            .unwrap();
+        assert_eq!(resolved.map(|stored| stored.id), Some(first.stored[0].id));
+
+        // Importing the package again stores nothing
+        let second = db.import_conformance(&resources).await.unwrap();
+        assert!(second.stored.is_empty());
+        assert_eq!(second.existing.len(), 2);
+        assert_eq!(
+            second.existing[1].existing_id,
+            Some(first.stored[1].id.to_string())
+        );
+
+        for stored in first.stored {
+            db.delete_resource("ValueSet", &stored.id.to_string())
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Opt-outs recorded as Consent resources, enforced in the search SQL.
+//!
+//! A stored Consent withholds a patient's resources while it is `active`,
+//! its `provision.type` is `deny` and `now` is within `provision.period`;
+//! a period with a malformed bound withholds nothing.
+//! It covers the Patient and the resources whose `subject` or `patient` is
+//! the Patient, of the types in `provision.class` (all types without one),
+//! from the actors in `provision.actor` (everyone without one). The
+//! patient is never withheld from themselves, and Consents are not withheld
+//! at all. Nested provisions are not evaluated.
+
+use super::audit::SqlParam;
+use super::export::SearchSql;
+use super::Database;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::Row;
+use uuid::Uuid;
+
+/// Whether an active Consent withholds the `fhir_resources` row of the
+/// enclosing query from the actor bound as `$actor`, at the time bound as
+/// `$now`. The period is read with `fhir.fhir_instant`, so a bound that is
+/// not a date leaves the Consent out instead of failing the query.
+const WITHHELD: &str = "EXISTS (
+    SELECT 1 FROM fhir_resources consent
+    WHERE fhir_resources.resource_type <> 'Consent'
+      AND consent.resource_type = 'Consent'
+      AND consent.deleted_at IS NULL
+      AND consent.resource_data->>'status' = 'active'
+      AND consent.resource_data->'provision'->>'type' = 'deny'
+      AND consent.resource_data->'patient'->>'reference' = CASE
+          WHEN fhir_resources.resource_type = 'Patient' THEN 'Patient/' || fhir_resources.id
+          ELSE COALESCE(fhir_resources.resource_data->'subject'->>'reference',
+                        fhir_resources.resource_data->'patient'->>'reference')
+      END
+      AND consent.resource_data->'patient'->>'reference' IS DISTINCT FROM $actor::text
+      AND (consent.resource_data->'provision'->'actor' IS NULL
+           OR consent.resource_data->'provision'->'actor' @> jsonb_build_array(
+              jsonb_build_object('reference', jsonb_build_object('reference', $actor::text))))
+      AND (consent.resource_data->'provision'->'class' IS NULL
+           OR consent.resource_data->'provision'->'class' @> jsonb_build_array(
+              jsonb_build_object('code', fhir_resources.resource_type)))
+      AND (consent.resource_data->'provision'->'period'->>'start' IS NULL
+           OR fhir.fhir_instant(consent.resource_data->'provision'->'period'->>'start') <= $now)
+      AND (consent.resource_data->'provision'->'period'->>'end' IS NULL
+           OR fhir.fhir_instant(consent.resource_data->'provision'->'period'->>'end') > $now)
+)";
+
+/// [`WITHHELD`] for `actor` (a reference such as `Practitioner/<id>`, or
+/// `None` for requests on behalf of nobody in particular) at `now`, with its
+/// parameters appended to `params`
+fn withheld_condition(
+    actor: Option<&str>,
+    now: DateTime<Utc>,
+    params: &mut Vec<SqlParam>,
+) -> String {
+    params.push(SqlParam::Text(actor.map(String::from)));
+    let actor = format!("${}", params.len());
+    params.push(SqlParam::Timestamp(now));
+    let now = format!("${}", params.len());
+    WITHHELD.replace("$actor", &actor).replace("$now", &now)
+}
+
+/// Whose opt-outs a query over many patients honours
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Withholding<'a> {
+    /// Reference to the actor, or `None` for nobody in particular
+    pub actor: Option<&'a str>,
+}
+
+impl SearchSql {
+    /// The matches no Consent withholds from `actor` at `now`
+    pub fn without_withheld(self, actor: Option<&str>, now: DateTime<Utc>) -> Self {
+        self.and(|params| format!("NOT {}", withheld_condition(actor, now, params)))
+    }
+}
+
+impl Database {
+    /// ` AND NOT <WITHHELD>` for a query over `fhir_resources`, or nothing
+    /// when no Consents apply
+    pub(super) fn unwithheld(
+        &self,
+        withholding: Option<Withholding>,
+        params: &mut Vec<SqlParam>,
+    ) -> String {
+        match withholding {
+            Some(withholding) => format!(
+                " AND NOT {}",
+                withheld_condition(withholding.actor, self.now(), params)
+            ),
+            None => String::new(),
+        }
+    }
+
+    /// [`Database::unwithheld`] for a query over `fhir.patient_history`:
+    /// the versions of the patients a Consent withholds are left out
+    pub(super) fn unwithheld_versions(
+        &self,
+        withholding: Option<Withholding>,
+        params: &mut Vec<SqlParam>,
+    ) -> String {
+        match withholding {
+            Some(withholding) => format!(
+                " AND NOT EXISTS (
+                    SELECT 1 FROM fhir_resources
+                    WHERE fhir_resources.id = patient_history.id AND {})",
+                withheld_condition(withholding.actor, self.now(), params)
+            ),
+            None => String::new(),
+        }
+    }
+
+    /// Whether a Consent withholds the `resource_type` with `id` from
+    /// `actor`; `false` for unknown resources
+    pub async fn is_withheld(
+        &self,
+        resource_type: &str,
+        id: &str,
+        actor: Option<&str>,
+    ) -> Result<bool> {
+        let Ok(id) = Uuid::parse_str(id) else {
+            return Ok(false);
+        };
+        let mut params = vec![SqlParam::text(resource_type), SqlParam::Uuid(id)];
+        let withheld = withheld_condition(actor, self.now(), &mut params);
+        let row = self
+            .fetch_optional(
+                &format!(
+                    "SELECT {} AS withheld FROM fhir_resources WHERE resource_type = $1 AND id = $2",
+                    withheld
+                ),
+                &params,
+            )
+            .await?;
+        Ok(row.is_some_and(|row| row.get("withheld")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::db::{patient_search_sql, PageStart, PatientSearch};
+    use crate::search::cohort::CohortQuery;
+    use serde_json::json;
+
+    #[test]
+    fn test_withheld_condition_binds_after_the_search() {
+        let mut params = vec![SqlParam::text("Patient")];
+        let condition = withheld_condition(Some("Practitioner/1"), Utc::now(), &mut params);
+        assert_eq!(params.len(), 3);
+        assert!(condition.contains("IS DISTINCT FROM $2::text"));
+        assert!(condition.contains("<= $3"));
+        assert!(!condition.contains("$actor") && !condition.contains("$now"));
+    }
+
+    #[tokio::test]
+    async fn test_consent_withholds_from_the_named_actor() {
+        let db = setup_test_db().await;
+        let patient = db
+            .create_resource("Patient", json!({ "resourceType": "Patient" }))
+            .await
+            .unwrap();
+        let reference = format!("Patient/{}", patient.id);
+        db.create_resource(
+            "Consent",
+            json!({
+                "resourceType": "Consent",
+                "status": "active",
+                "patient": { "reference": reference },
+                "provision": {
+                    "type": "deny",
+                    "actor": [{ "reference": { "reference": "Practitioner/p1" } }],
+                },
+            }),
+        )
+        .await
+        .unwrap();
+        let id = patient.id.to_string();
+
+        for (actor, withheld) in [
+            ("Practitioner/p1", true),
+            ("Practitioner/p2", false),
+            (reference.as_str(), false),
+        ] {
+            assert_eq!(
+                db.is_withheld("Patient", &id, Some(actor)).await.unwrap(),
+                withheld,
+                "{}",
+                actor
+            );
+        }
+
+        let search = patient_search_sql(&PatientSearch {
+            ids: vec![id.clone()],
+            ..Default::default()
+        });
+        for (actor, matches) in [("Practitioner/p1", 0), ("Practitioner/p2", 1)] {
+            let search = search.clone().without_withheld(Some(actor), db.now());
+            let page = db
+                .list_resources(&search, 10, PageStart::Offset(0))
+                .await
+                .unwrap();
+            assert_eq!(page.len(), matches, "{}", actor);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_withheld_patients_are_left_out_of_history_and_cohorts() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:consent:{}", Uuid::new_v4());
+        let mut ids = Vec::new();
+        for value in ["withheld", "shared"] {
+            let patient = db
+                .create_resource(
+                    "Patient",
+                    json!({
+                        "resourceType": "Patient",
+                        "identifier": [{ "system": system, "value": value }],
+                    }),
+                )
+                .await
+                .unwrap();
+            ids.push(patient.id.to_string());
+        }
+        db.create_resource(
+            "Consent",
+            json!({
+                "resourceType": "Consent",
+                "status": "active",
+                "patient": { "reference": format!("Patient/{}", ids[0]) },
+                "provision": { "type": "deny" },
+            }),
+        )
+        .await
+        .unwrap();
+
+        let query = CohortQuery::parse(&json!({
+            "criteria": { "param": "identifier", "value": format!("{}|", system) }
+        }))
+        .unwrap();
+        let withholding = Some(Withholding { actor: None });
+        let mut all = db.cohort_ids(&query.criteria, None).await.unwrap();
+        all.sort();
+        let mut expected = ids.clone();
+        expected.sort();
+        assert_eq!(all, expected);
+        assert_eq!(
+            db.cohort_ids(&query.criteria, withholding).await.unwrap(),
+            vec![ids[1].clone()]
+        );
+        assert_eq!(
+            db.cohort_count(&query.criteria, withholding).await.unwrap(),
+            1
+        );
+
+        if db.history_enabled() {
+            let total = db.count_patient_history(None).await.unwrap();
+            let shown = db.count_patient_history(withholding).await.unwrap();
+            let page = db
+                .get_all_patients_history(total as u32, 0, withholding)
+                .await
+                .unwrap();
+            assert_eq!(page.len() as i64, shown);
+            assert!(page.iter().all(|version| version.id.to_string() != ids[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_periods_withhold_nothing() {
+        let db = setup_test_db().await;
+        let mut ids = Vec::new();
+        for period in [
+            json!({ "start": "2000", "end": "2999-12-31" }),
+            json!({ "start": "yesterday" }),
+            json!({ "end": "2024-02-31" }),
+        ] {
+            let patient = db
+                .create_resource("Patient", json!({ "resourceType": "Patient" }))
+                .await
+                .unwrap();
+            db.create_resource(
+                "Consent",
+                json!({
+                    "resourceType": "Consent",
+                    "status": "active",
+                    "patient": { "reference": format!("Patient/{}", patient.id) },
+                    "provision": { "type": "deny", "period": period },
+                }),
+            )
+            .await
+            .unwrap();
+            ids.push(patient.id.to_string());
+        }
+
+        for (id, withheld) in ids.iter().zip([true, false, false]) {
+            assert_eq!(
+                db.is_withheld("Patient", id, None).await.unwrap(),
+                withheld,
+                "{}",
+                id
+            );
+        }
+        let search = patient_search_sql(&PatientSearch {
+            ids: ids.clone(),
+            ..Default::default()
+        })
+        .without_withheld(None, db.now());
+        let page = db
+            .list_resources(&search, 10, PageStart::Offset(0))
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 2);
+    }
+}
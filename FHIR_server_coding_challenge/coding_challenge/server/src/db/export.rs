@@ -0,0 +1,327 @@
+//! Searches as SQL that can be paged by offset or by keyset, and counted for
+//! `Bundle.total`. REST searches take either; exports walk result sets too
+//! large to skip through by keyset.
+
+use super::audit::SqlParam;
+use super::resource::{StoredResource, RESOURCE_COLUMNS};
+use super::Database;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::Row;
+use uuid::Uuid;
+
+/// How `Bundle.total` of a search is reported, from `_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Total {
+    /// Not reported
+    None,
+    /// The planner's row estimate, which costs no scan of the matches
+    Estimate,
+    /// `COUNT(*)` of the matches
+    #[default]
+    Accurate,
+}
+
+impl Total {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(Total::None),
+            "estimate" => Ok(Total::Estimate),
+            "accurate" => Ok(Total::Accurate),
+            _ => Err(format!(
+                "Invalid value '{}' for _total: expected none, estimate or accurate",
+                value
+            )),
+        }
+    }
+}
+
+/// Where a page of a search starts, from `_offset` or `_page_token`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageStart {
+    /// After skipping this many matches in id order. Writes between two
+    /// requests shift the following pages.
+    Offset(u32),
+    /// Right after the match with this id. Pages stay in place while other
+    /// resources are written; only matches created behind the page are
+    /// missed.
+    After(Uuid),
+}
+
+impl Default for PageStart {
+    fn default() -> Self {
+        PageStart::Offset(0)
+    }
+}
+
+impl PageStart {
+    /// The start given by a request; a page token is opaque to clients
+    pub fn parse(offset: Option<u32>, page_token: Option<&str>) -> Result<Self, String> {
+        match (offset, page_token) {
+            (Some(_), Some(_)) => Err("_offset and _page_token cannot be combined".to_string()),
+            (offset, None) => Ok(PageStart::Offset(offset.unwrap_or(0))),
+            (None, Some(token)) => {
+                let invalid = || format!("Invalid _page_token '{}'", token);
+                if token.len() != 32 {
+                    return Err(invalid());
+                }
+                Uuid::try_parse(token)
+                    .map(PageStart::After)
+                    .map_err(|_| invalid())
+            }
+        }
+    }
+
+    /// `_page_token` of the page after the match `id`
+    pub fn token_after(id: Uuid) -> String {
+        id.simple().to_string()
+    }
+}
+
+/// The matches of a search: a query selecting [`RESOURCE_COLUMNS`] from
+/// `fhir_resources` with an open `WHERE` clause, without order or paging
+#[derive(Debug, Clone)]
+pub struct SearchSql {
+    query: String,
+    params: Vec<SqlParam>,
+}
+
+impl SearchSql {
+    /// Rows of `fhir_resources` matching `conditions`, joined with `AND`
+    pub(super) fn new(conditions: &[String], params: Vec<SqlParam>) -> Self {
+        Self {
+            query: format!(
+                "SELECT {} FROM fhir_resources WHERE {}",
+                RESOURCE_COLUMNS,
+                conditions.join(" AND ")
+            ),
+            params,
+        }
+    }
+
+    /// The matches that also meet the condition `condition` returns, whose
+    /// parameters it appends to those of the search
+    pub(super) fn and(mut self, condition: impl FnOnce(&mut Vec<SqlParam>) -> String) -> Self {
+        let condition = condition(&mut self.params);
+        self.query.push_str(" AND ");
+        self.query.push_str(&condition);
+        self
+    }
+
+    /// `count` matches in id order after skipping `offset`
+    fn offset_page(&self, count: u32, offset: u32) -> (String, Vec<SqlParam>) {
+        let mut params = self.params.clone();
+        params.push(SqlParam::BigInt(count as i64));
+        params.push(SqlParam::BigInt(offset as i64));
+        let query = format!(
+            "{} ORDER BY id LIMIT ${} OFFSET ${}",
+            self.query,
+            params.len() - 1,
+            params.len()
+        );
+        (query, params)
+    }
+
+    /// `count` matches in id order from `start`
+    pub(super) fn page(&self, count: u32, start: PageStart) -> (String, Vec<SqlParam>) {
+        match start {
+            PageStart::Offset(offset) => self.offset_page(count, offset),
+            PageStart::After(after) => self.after_page(Some(after), count),
+        }
+    }
+
+    /// `COUNT(*)` of all matches, as `total`
+    fn count_query(&self) -> String {
+        format!("SELECT COUNT(*) AS total FROM ({}) AS matches", self.query)
+    }
+
+    /// The first `count` matches in id order whose id is above `after`
+    fn after_page(&self, after: Option<Uuid>, count: u32) -> (String, Vec<SqlParam>) {
+        let mut params = self.params.clone();
+        let mut query = self.query.clone();
+        if let Some(after) = after {
+            params.push(SqlParam::Uuid(after));
+            query.push_str(&format!(" AND id > ${}", params.len()));
+        }
+        params.push(SqlParam::BigInt(count as i64));
+        query.push_str(&format!(" ORDER BY id LIMIT ${}", params.len()));
+        (query, params)
+    }
+}
+
+/// What `$export` writes of `resource_type`: every live resource, or those
+/// updated after `since`
+pub fn bulk_export_sql(resource_type: &str, since: Option<DateTime<Utc>>) -> SearchSql {
+    let mut params = vec![SqlParam::text(resource_type)];
+    let mut conditions = vec![
+        "resource_type = $1".to_string(),
+        "deleted_at IS NULL".to_string(),
+    ];
+    if let Some(since) = since {
+        params.push(SqlParam::text(since.to_rfc3339()));
+        conditions.push(format!("last_updated > ${}::timestamptz", params.len()));
+    }
+    SearchSql::new(&conditions, params)
+}
+
+impl Database {
+    /// `Bundle.total` of `search`, counted in the way `total` asks for
+    pub async fn count_matches(&self, search: &SearchSql, total: Total) -> Result<Option<u32>> {
+        match total {
+            Total::None => Ok(None),
+            Total::Accurate => {
+                let row = self
+                    .fetch_one(&search.count_query(), &search.params)
+                    .await?;
+                let count: i64 = row.get("total");
+                Ok(Some(count.clamp(0, u32::MAX as i64) as u32))
+            }
+            Total::Estimate => {
+                let explain = format!("EXPLAIN (FORMAT JSON) {}", search.query);
+                let row = self.fetch_one(&explain, &search.params).await?;
+                let plan: Value = row.try_get(0)?;
+                let rows = plan[0]["Plan"]["Plan Rows"].as_f64().unwrap_or_default();
+                Ok(Some(rows.clamp(0.0, u32::MAX as f64) as u32))
+            }
+        }
+    }
+
+    /// Whether `search` has more than `limit` matches, counting no further
+    pub async fn matches_more_than(&self, search: &SearchSql, limit: u32) -> Result<bool> {
+        let mut params = search.params.clone();
+        params.push(SqlParam::BigInt(limit as i64 + 1));
+        let row = self
+            .fetch_one(
+                &format!(
+                    "SELECT COUNT(*) AS total FROM ({} LIMIT ${}) AS matches",
+                    search.query,
+                    params.len()
+                ),
+                &params,
+            )
+            .await?;
+        let count: i64 = row.get("total");
+        Ok(count > limit as i64)
+    }
+
+    /// The next `count` matches of `search` after the one with id `after`
+    /// (from the first when `None`); empty once all have been read
+    pub async fn search_after(
+        &self,
+        search: &SearchSql,
+        after: Option<Uuid>,
+        count: u32,
+    ) -> Result<Vec<StoredResource>> {
+        let (query, params) = search.after_page(after, count);
+        let rows = self.fetch_all(&query, &params).await?;
+        Ok(rows.iter().map(StoredResource::from_row).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::db::{patient_search_sql, PatientSearch};
+    use crate::models::{HumanName, Patient};
+    use serde_json::Map;
+
+    #[test]
+    fn test_pages_bind_after_the_search() {
+        let search = SearchSql::new(
+            &["resource_type = $1".to_string()],
+            vec![SqlParam::text("Patient")],
+        );
+
+        let (query, params) = search.after_page(Some(Uuid::nil()), 10);
+        assert!(query.ends_with("WHERE resource_type = $1 AND id > $2 ORDER BY id LIMIT $3"));
+        assert_eq!(params.len(), 3);
+        let (query, params) = search.offset_page(10, 20);
+        assert!(query.ends_with("ORDER BY id LIMIT $2 OFFSET $3"));
+        assert_eq!(params.len(), 3);
+    }
+
+    #[test]
+    fn test_page_start_from_offset_or_token() {
+        let id = Uuid::new_v4();
+        let token = PageStart::token_after(id);
+        assert_eq!(PageStart::parse(None, None), Ok(PageStart::Offset(0)));
+        assert_eq!(PageStart::parse(Some(40), None), Ok(PageStart::Offset(40)));
+        assert_eq!(
+            PageStart::parse(None, Some(&token)),
+            Ok(PageStart::After(id))
+        );
+        assert!(PageStart::parse(Some(0), Some(&token)).is_err());
+        assert!(PageStart::parse(None, Some(&id.to_string())).is_err());
+        assert!(PageStart::parse(None, Some("next")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_after_walks_all_matches() {
+        let db = setup_test_db().await;
+        let family = format!("Keyset{}", Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let mut patient = Patient::new();
+            patient.name = Some(vec![HumanName {
+                family: Some(family.clone()),
+                given: None,
+                text: None,
+                extra: Map::new(),
+            }]);
+            ids.push(db.create_patient(patient).await.unwrap().id.unwrap());
+        }
+        ids.sort();
+
+        let search = patient_search_sql(&PatientSearch {
+            name: vec![family.clone()],
+            ..Default::default()
+        });
+        let mut after = None;
+        let mut seen = Vec::new();
+        loop {
+            let page = db.search_after(&search, after, 2).await.unwrap();
+            let Some(last) = page.last() else { break };
+            assert!(page.len() <= 2);
+            after = Some(last.id);
+            seen.extend(page.iter().map(|r| r.id.to_string()));
+        }
+        assert_eq!(seen, ids);
+    }
+
+    #[tokio::test]
+    async fn test_matches_are_counted_as_asked() {
+        let db = setup_test_db().await;
+        let family = format!("Counted{}", Uuid::new_v4().simple());
+        for _ in 0..3 {
+            let mut patient = Patient::new();
+            patient.name = Some(vec![HumanName {
+                family: Some(family.clone()),
+                given: None,
+                text: None,
+                extra: Map::new(),
+            }]);
+            db.create_patient(patient).await.unwrap();
+        }
+
+        let search = patient_search_sql(&PatientSearch {
+            name: vec![family.clone()],
+            ..Default::default()
+        });
+        assert_eq!(
+            db.count_matches(&search, Total::Accurate).await.unwrap(),
+            Some(3)
+        );
+        assert_eq!(db.count_matches(&search, Total::None).await.unwrap(), None);
+        // Whatever the planner guesses, it is a number
+        assert!(db
+            .count_matches(&search, Total::Estimate)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(Total::parse("sometimes").is_err());
+    }
+}
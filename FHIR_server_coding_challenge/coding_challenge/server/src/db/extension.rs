@@ -0,0 +1,163 @@
+//! Calls of the extension functions that return rows, decoded into the row
+//! types of the `fhir-schema` crate that the extension builds its results
+//! from.
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::Result;
+use fhir_schema::{HistoryRow, MaintainRow, SearchRow};
+use uuid::Uuid;
+
+impl Database {
+    /// `name` of the extension when it is installed, or else the SQL
+    /// fallback of the same name in the `fhir` schema
+    async fn extension_function(&self, name: &'static str) -> Result<String> {
+        Ok(if self.function_exists(Some("public"), name).await? {
+            name.to_string()
+        } else {
+            format!("fhir.{}", name)
+        })
+    }
+
+    /// The matches of `fhir_search(resource_type, param, op, value)`; an
+    /// empty `param` matches every resource of the type
+    pub async fn extension_search(
+        &self,
+        resource_type: &str,
+        param: &str,
+        op: &str,
+        value: &str,
+    ) -> Result<Vec<SearchRow>> {
+        self.fetch_all_as(
+            &format!(
+                "SELECT {} FROM fhir_search($1, $2, $3, $4)",
+                SearchRow::COLUMNS.join(", ")
+            ),
+            &[
+                SqlParam::text(resource_type),
+                SqlParam::text(param),
+                SqlParam::text(op),
+                SqlParam::text(value),
+            ],
+        )
+        .await
+    }
+
+    /// The versions of the Patient with `id` by `fhir_get_history`, newest
+    /// first
+    pub async fn extension_history(&self, id: Uuid) -> Result<Vec<HistoryRow>> {
+        let function = self.extension_function("fhir_get_history").await?;
+        self.fetch_all_as(
+            &format!(
+                "SELECT {} FROM {}($1)",
+                HistoryRow::COLUMNS.join(", "),
+                function
+            ),
+            &[SqlParam::Uuid(id)],
+        )
+        .await
+    }
+
+    /// One batch of `fhir_maintain` with `retention` in PostgreSQL interval
+    /// syntax, e.g. `90 days`, by the extension when it is installed and the
+    /// SQL fallback otherwise. Each call is its own transaction; repeat it
+    /// until the row is `complete`.
+    pub async fn extension_maintain(
+        &self,
+        retention: &str,
+        batch_size: i32,
+    ) -> Result<MaintainRow> {
+        let function = self.extension_function("fhir_maintain").await?;
+        let mut rows: Vec<MaintainRow> = self
+            .fetch_all_as(
+                &format!(
+                    "SELECT {} FROM {}($1::text::interval, $2)",
+                    MaintainRow::COLUMNS.join(", "),
+                    function
+                ),
+                &[SqlParam::text(retention), SqlParam::Int(batch_size)],
+            )
+            .await?;
+        rows.pop()
+            .ok_or_else(|| anyhow::anyhow!("fhir_maintain returned no summary row"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::audit::SqlParam;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+
+    #[tokio::test]
+    async fn test_extension_history_matches_the_server_history() {
+        let db = setup_test_db().await;
+        let created = db.create_patient(Patient::new()).await.unwrap();
+        let id = created.id.unwrap();
+        db.delete_patient(&id).await.unwrap();
+
+        let extension = db.extension_history(id.parse().unwrap()).await.unwrap();
+        let server = db.get_patient_history(&id, None, None).await.unwrap();
+        assert_eq!(extension, server);
+    }
+
+    #[tokio::test]
+    async fn test_maintain_prunes_only_beyond_the_retention() {
+        let db = setup_test_db().await;
+        let kept = db.create_patient(Patient::new()).await.unwrap();
+        let kept_id = kept.id.clone().unwrap();
+        db.update_patient(&kept_id, kept).await.unwrap();
+        let gone = db.create_patient(Patient::new()).await.unwrap();
+        let gone_id = gone.id.unwrap();
+        db.delete_patient(&gone_id).await.unwrap();
+
+        // Age both patients' rows past a one-day retention
+        for id in [&kept_id, &gone_id] {
+            let id = SqlParam::Uuid(id.parse().unwrap());
+            db.execute(
+                "UPDATE fhir.patient_history SET ts = ts - interval '10 days' WHERE id = $1",
+                std::slice::from_ref(&id),
+            )
+            .await
+            .unwrap();
+            db.execute(
+                "UPDATE fhir_resources SET deleted_at = deleted_at - interval '10 days'
+                 WHERE id = $1",
+                &[id],
+            )
+            .await
+            .unwrap();
+        }
+
+        // One row at a time, so every batch but the last is full
+        let (mut pruned, mut purged) = (0, 0);
+        loop {
+            let batch = db.extension_maintain("1 day", 1).await.unwrap();
+            assert!(batch.history_pruned + batch.tombstones_purged <= 2);
+            pruned += batch.history_pruned;
+            purged += batch.tombstones_purged;
+            if batch.complete {
+                break;
+            }
+        }
+        assert!(pruned >= 1);
+        assert!(purged >= 1);
+
+        let history = db.get_patient_history(&kept_id, None, None).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].version_id, 2);
+        assert!(db
+            .get_patient_history(&gone_id, None, None)
+            .await
+            .unwrap()
+            .is_empty());
+        let tombstone = db
+            .fetch_optional(
+                "SELECT id FROM fhir_resources WHERE id = $1",
+                &[SqlParam::Uuid(gone_id.parse().unwrap())],
+            )
+            .await
+            .unwrap();
+        assert!(tombstone.is_none());
+    }
+}
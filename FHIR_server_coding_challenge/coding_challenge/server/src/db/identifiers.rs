@@ -0,0 +1,139 @@
+//! Identifier index and uniqueness policy.
+//!
+//! Every `system|value` identifier of a stored resource is mirrored into
+//! `fhir.resource_identifier`. Rows whose system is listed in
+//! `unique_identifier_systems` are flagged `enforce_unique`, which a partial
+//! unique index turns into a hard constraint (migration 005).
+
+use crate::models::Patient;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::RwLock;
+
+/// Name of the partial unique index created by 005_resource_identifiers.sql
+pub const UNIQUE_INDEX: &str = "idx_resource_identifier_unique";
+
+/// A `system|value` pair taken from a resource's `identifier` array
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Identifier {
+    pub system: String,
+    pub value: String,
+}
+
+/// Identifiers of a patient, de-duplicated. Entries without both a system
+/// and a value cannot be enforced and are skipped.
+/// Both the typed field and an `identifier` left in `extra` are read.
+pub fn patient_identifiers(patient: &Patient) -> Vec<Identifier> {
+    let typed = patient
+        .identifier
+        .iter()
+        .flatten()
+        .filter_map(|identifier| {
+            Some(Identifier {
+                system: identifier.system.clone()?,
+                value: identifier.value.clone()?,
+            })
+        });
+    let untyped = patient
+        .extra
+        .get("identifier")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|identifier| {
+            Some(Identifier {
+                system: identifier.get("system")?.as_str()?.to_string(),
+                value: identifier.get("value")?.as_str()?.to_string(),
+            })
+        });
+
+    typed
+        .chain(untyped)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Identifier systems whose values must be unique; replaced on config reload
+#[derive(Debug, Default)]
+pub struct UniquenessPolicy {
+    systems: RwLock<Vec<String>>,
+}
+
+impl UniquenessPolicy {
+    pub fn set_systems(&self, systems: Vec<String>) {
+        *self.systems.write().unwrap() = systems;
+    }
+
+    pub fn has_systems(&self) -> bool {
+        !self.systems.read().unwrap().is_empty()
+    }
+
+    pub fn is_unique(&self, system: &str) -> bool {
+        self.systems.read().unwrap().iter().any(|s| s == system)
+    }
+}
+
+/// A write was rejected because another resource already holds an
+/// identifier in a unique system
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateIdentifier {
+    pub system: String,
+    pub value: String,
+    /// Id of the resource holding the identifier, when known
+    pub existing_id: Option<String>,
+}
+
+impl fmt::Display for DuplicateIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "identifier {}|{} is already in use",
+            self.system, self.value
+        )?;
+        if let Some(id) = &self.existing_id {
+            write!(f, " by Patient/{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DuplicateIdentifier {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_patient_identifiers_skips_incomplete_and_duplicates() {
+        let mut patient = Patient::new();
+        patient.extra.insert(
+            "identifier".to_string(),
+            json!([
+                { "system": "urn:mrn", "value": "42" },
+                { "system": "urn:mrn", "value": "42" },
+                { "value": "no-system" },
+                { "system": "urn:ssn" }
+            ]),
+        );
+
+        assert_eq!(
+            patient_identifiers(&patient),
+            vec![Identifier {
+                system: "urn:mrn".to_string(),
+                value: "42".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_policy_matches_configured_systems() {
+        let policy = UniquenessPolicy::default();
+        assert!(!policy.has_systems());
+
+        policy.set_systems(vec!["urn:mrn".to_string()]);
+        assert!(policy.is_unique("urn:mrn"));
+        assert!(!policy.is_unique("urn:ssn"));
+    }
+}
@@ -0,0 +1,179 @@
+//! Bulk loading of resources for `POST /admin/import`.
+//!
+//! A batch is stored in one transaction with a single multi-row insert into
+//! `fhir_resources` (and `fhir.patient_history` for its Patients), followed
+//! by the identifier, phonetic and search value indexes the single-resource
+//! writes maintain. When that fails, for a duplicate id or identifier say,
+//! the batch is stored again one resource at a time, each in a savepoint,
+//! so the failing resources can be reported and the others kept.
+
+use super::audit::SqlParam;
+use super::conformance::is_conformance_type;
+use super::resource::storable;
+use super::string_values::{self, NAME_CODE};
+use super::{canonical, identifiers, Database};
+use crate::models::Patient;
+use crate::search::phonetic;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{Connection, PgConnection};
+use uuid::Uuid;
+
+/// A resource to import, with the NDJSON line it was read from
+#[derive(Debug, Clone)]
+pub struct ImportRecord {
+    pub line: usize,
+    pub resource_type: String,
+    pub id: Uuid,
+    pub resource: Value,
+}
+
+/// A resource of a batch that was not stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportFailure {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Unique violations on the primary key are the ids that already exist;
+/// other errors are reported as they are
+fn failure_message(record: &ImportRecord, e: anyhow::Error) -> String {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_err))
+            if db_err.constraint() == Some("fhir_resources_pkey") =>
+        {
+            format!("{}/{} already exists", record.resource_type, record.id)
+        }
+        _ => format!("{:#}", e),
+    }
+}
+
+impl Database {
+    /// Store `records` in one transaction; returns the ones that could not
+    /// be stored, and why
+    pub async fn import_batch(&self, records: &[ImportRecord]) -> Result<Vec<ImportFailure>> {
+        let mut tx = self.pool.begin().await?;
+        // Conformance resources are checked for duplicate canonicals one by one
+        let bulk = !records
+            .iter()
+            .any(|record| is_conformance_type(&record.resource_type));
+        let mut failures = Vec::new();
+        let inserted = if bulk {
+            let mut savepoint = tx.begin().await?;
+            match self.insert_records_on(&mut savepoint, records).await {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    true
+                }
+                Err(e) => {
+                    tracing::debug!("Importing the batch row by row: {:#}", e);
+                    savepoint.rollback().await?;
+                    false
+                }
+            }
+        } else {
+            false
+        };
+        if !inserted {
+            for record in records {
+                let mut savepoint = tx.begin().await?;
+                match self.insert_record_on(&mut savepoint, record).await {
+                    Ok(()) => savepoint.commit().await?,
+                    Err(e) => {
+                        savepoint.rollback().await?;
+                        failures.push(ImportFailure {
+                            line: record.line,
+                            message: failure_message(record, e),
+                        });
+                    }
+                }
+            }
+        }
+        tx.commit().await?;
+
+        Ok(failures)
+    }
+
+    /// Store one record the way `POST /fhir/:resourceType` would
+    async fn insert_record_on(&self, conn: &mut PgConnection, record: &ImportRecord) -> Result<()> {
+        if record.resource_type == "Patient" {
+            let patient: Patient = serde_json::from_value(record.resource.clone())?;
+            self.create_patient_on(conn, record.id, patient).await?;
+        } else {
+            self.create_resource_on(
+                conn,
+                &record.resource_type,
+                record.id,
+                record.resource.clone(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_records_on(
+        &self,
+        conn: &mut PgConnection,
+        records: &[ImportRecord],
+    ) -> Result<()> {
+        let mut rows = Vec::with_capacity(records.len());
+        let mut patients = Vec::new();
+        for record in records {
+            let data = if record.resource_type == "Patient" {
+                let patient: Patient = serde_json::from_value(record.resource.clone())?;
+                let data = canonical::normalize(serde_json::to_value(&patient)?);
+                patients.push((record.id, patient, data.clone()));
+                data
+            } else {
+                storable(&record.resource_type, record.resource.clone())
+            };
+            rows.push(json!({
+                "id": record.id,
+                "resource_type": record.resource_type,
+                "content_hash": canonical::content_hash(&data),
+                "resource_data": data,
+            }));
+        }
+
+        let now = self.now();
+        self.execute_on(
+            &mut *conn,
+            "INSERT INTO fhir_resources (id, resource_type, resource_data, version_id, last_updated, content_hash)
+             SELECT r.id, r.resource_type, r.resource_data, 1, $2, r.content_hash
+             FROM jsonb_to_recordset($1) AS r(id uuid, resource_type text, resource_data jsonb, content_hash text)",
+            &[SqlParam::Json(Value::Array(rows)), SqlParam::Timestamp(now)],
+        )
+        .await?;
+        if self.history_enabled() && !patients.is_empty() {
+            let versions = patients
+                .iter()
+                .map(|(id, _, data)| json!({ "id": id, "resource": data }))
+                .collect();
+            self.execute_on(
+                &mut *conn,
+                "INSERT INTO fhir.patient_history (id, version_id, resource, txid, ts, status)
+                 SELECT r.id, 1, r.resource, txid_current(), $2, 'created'
+                 FROM jsonb_to_recordset($1) AS r(id uuid, resource jsonb)",
+                &[
+                    SqlParam::Json(Value::Array(versions)),
+                    SqlParam::Timestamp(now),
+                ],
+            )
+            .await?;
+        }
+        for (id, patient, data) in &patients {
+            self.sync_identifiers(conn, *id, &identifiers::patient_identifiers(patient))
+                .await?;
+            self.sync_name_phonetics(conn, *id, &phonetic::patient_name_keys(patient))
+                .await?;
+            let name_values = string_values::patient_name_values(data);
+            self.sync_string_values_on(conn, *id, "Patient", NAME_CODE, &name_values)
+                .await?;
+            self.shadow_verify_on(conn, *id, data).await;
+        }
+        let ids: Vec<Uuid> = records.iter().map(|record| record.id).collect();
+        self.extract_search_values_on(conn, &ids).await?;
+        Ok(())
+    }
+}
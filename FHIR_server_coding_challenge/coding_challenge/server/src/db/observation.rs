@@ -0,0 +1,423 @@
+//! Observation storage and search, on top of the generic
+//! [`resource`](super::resource) layer.
+
+use super::audit::SqlParam;
+use super::resource::StoredResource;
+use super::{
+    any_of, date_condition, expression_condition, missing_condition, text_conditions, Database,
+    PageStart, SearchSql, Total,
+};
+use crate::models::Observation;
+use crate::provenance::Attribution;
+use crate::search::{DateSearchValue, ExpressionCriterion, TextCriteria, TokenParam};
+use anyhow::Result;
+use serde_json::json;
+
+/// Criteria for [`Database::search_observations`]; all given criteria must match
+#[derive(Debug, Clone, Default)]
+pub struct ObservationSearch {
+    /// Any of these tokens must match a coding of `Observation.code`
+    pub code: Vec<TokenParam>,
+    /// `Type/id` or bare ids, any of which may be the subject
+    pub subject: Vec<String>,
+    /// Patient ids or `Patient/id` references, any of which must be the
+    /// subject; unlike `subject`, other types of subject never match
+    pub patient: Vec<String>,
+    /// Every group must hold for `effectiveDateTime`, by any of its
+    /// conditions: `date=ge2024-01-01&date=lt2024-04-01` is a range and
+    /// `date=2024-01,2024-03` either month
+    pub date: Vec<Vec<DateSearchValue>>,
+    /// `:missing` of `code`, `subject` and `date`: the element must be
+    /// absent (`true`) or present (`false`)
+    pub code_missing: Option<bool>,
+    pub subject_missing: Option<bool>,
+    pub date_missing: Option<bool>,
+    /// Parameters evaluated from their expression, such as those of
+    /// profile packs
+    pub expressions: Vec<ExpressionCriterion>,
+    pub text: TextCriteria,
+    pub count: u32,
+    pub start: PageStart,
+    pub total: Total,
+}
+
+/// SQL condition for one code token, pushing its binds onto `params`
+fn code_condition(token: &TokenParam, params: &mut Vec<SqlParam>) -> String {
+    match (token.system.as_deref(), token.code.as_deref()) {
+        // Codings without a system cannot be expressed with containment
+        (Some(""), code) => {
+            params.push(SqlParam::text(code.unwrap_or_default()));
+            format!(
+                "EXISTS (SELECT 1 FROM jsonb_array_elements(resource_data->'code'->'coding') c
+                         WHERE c->>'code' = ${} AND NOT c ? 'system')",
+                params.len()
+            )
+        }
+        (system, code) => {
+            let mut coding = json!({});
+            if let Some(system) = system {
+                coding["system"] = json!(system);
+            }
+            if let Some(code) = code {
+                coding["code"] = json!(code);
+            }
+            params.push(SqlParam::Json(json!({ "code": { "coding": [coding] } })));
+            format!("resource_data @> ${}", params.len())
+        }
+    }
+}
+
+/// SQL condition for one subject, pushing its bind onto `params`. A bare id
+/// matches any reference ending in /id, and `Type/id` the absolute URLs
+/// ending in it too
+fn subject_condition(subject: &str, params: &mut Vec<SqlParam>) -> String {
+    params.push(SqlParam::text(subject));
+    format!(
+        "(resource_data->'subject'->>'reference' = ${n}
+          OR right(resource_data->'subject'->>'reference', length(${n}) + 1) = '/' || ${n})",
+        n = params.len()
+    )
+}
+
+/// The matches of `search`, regardless of its `count` and `start`
+pub fn observation_search_sql(search: &ObservationSearch) -> SearchSql {
+    let mut conditions = vec!["resource_type = 'Observation'".to_string()];
+    let mut params = Vec::new();
+
+    if !search.code.is_empty() {
+        conditions.push(any_of(&search.code, &mut params, |token, params| {
+            code_condition(token, params)
+        }));
+    }
+
+    if !search.subject.is_empty() {
+        conditions.push(any_of(&search.subject, &mut params, |subject, params| {
+            subject_condition(subject, params)
+        }));
+    }
+    if !search.patient.is_empty() {
+        conditions.push(any_of(&search.patient, &mut params, |patient, params| {
+            let reference = if patient.contains('/') {
+                patient.clone()
+            } else {
+                format!("Patient/{}", patient)
+            };
+            subject_condition(&reference, params)
+        }));
+    }
+
+    for dates in search.date.iter().filter(|dates| !dates.is_empty()) {
+        conditions.push(any_of(dates, &mut params, |date, params| {
+            date_condition("resource_data->>'effectiveDateTime'", date, params)
+        }));
+    }
+
+    let missing = [
+        ("code", search.code_missing),
+        ("subject", search.subject_missing),
+        ("effectiveDateTime", search.date_missing),
+    ];
+    for (element, missing) in missing {
+        if let Some(missing) = missing {
+            conditions.push(missing_condition(element, missing));
+        }
+    }
+
+    for criterion in search.expressions.iter().filter(|c| !c.values.is_empty()) {
+        conditions.push(expression_condition(criterion, &mut params));
+    }
+    conditions.extend(text_conditions(&search.text, &mut params));
+
+    SearchSql::new(&conditions, params)
+}
+
+fn observation_from(stored: StoredResource) -> Result<Observation> {
+    Ok(serde_json::from_value(stored.into_resource())?)
+}
+
+impl Database {
+    /// Store a new Observation
+    pub async fn create_observation(&self, observation: Observation) -> Result<Observation> {
+        let stored = self
+            .create_resource("Observation", serde_json::to_value(&observation)?)
+            .await?;
+        observation_from(stored)
+    }
+
+    /// [`Database::create_observation`] by `attribution`
+    pub async fn create_observation_by(
+        &self,
+        attribution: &Attribution,
+        observation: Observation,
+    ) -> Result<Observation> {
+        let stored = self
+            .create_resource_by(
+                attribution,
+                "Observation",
+                serde_json::to_value(&observation)?,
+            )
+            .await?;
+        observation_from(stored)
+    }
+
+    /// Get an Observation by UUID
+    pub async fn get_observation(&self, id: &str) -> Result<Option<Observation>> {
+        self.get_resource("Observation", id)
+            .await?
+            .map(observation_from)
+            .transpose()
+    }
+
+    /// Replace an existing Observation (PUT semantics)
+    pub async fn update_observation(
+        &self,
+        id: &str,
+        observation: Observation,
+    ) -> Result<Option<Observation>> {
+        self.update_resource("Observation", id, serde_json::to_value(&observation)?)
+            .await?
+            .map(observation_from)
+            .transpose()
+    }
+
+    /// [`Database::update_observation`] by `attribution`
+    pub async fn update_observation_by(
+        &self,
+        attribution: &Attribution,
+        id: &str,
+        observation: Observation,
+    ) -> Result<Option<Observation>> {
+        self.update_resource_by(
+            attribution,
+            "Observation",
+            id,
+            serde_json::to_value(&observation)?,
+        )
+        .await?
+        .map(observation_from)
+        .transpose()
+    }
+
+    /// Remove an Observation; returns whether it existed
+    pub async fn delete_observation(&self, id: &str) -> Result<bool> {
+        self.delete_resource("Observation", id).await
+    }
+
+    /// Search Observations by code, subject and effective date
+    pub async fn search_observations(
+        &self,
+        search: &ObservationSearch,
+    ) -> Result<Vec<Observation>> {
+        self.observation_page(&observation_search_sql(search), search.count, search.start)
+            .await
+    }
+
+    /// One page of the Observations matching `matches`
+    pub async fn observation_page(
+        &self,
+        matches: &SearchSql,
+        count: u32,
+        start: PageStart,
+    ) -> Result<Vec<Observation>> {
+        let (query_str, params) = matches.page(count, start);
+
+        let rows = self.fetch_all(&query_str, &params).await?;
+        rows.iter()
+            .map(|row| observation_from(StoredResource::from_row(row)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::models::{CodeableConcept, Coding, Reference};
+    use serde_json::Map;
+    use uuid::Uuid;
+
+    fn create_test_observation(code: &str, subject: &str, effective: &str) -> Observation {
+        let mut observation = Observation::new(
+            "final",
+            CodeableConcept {
+                coding: Some(vec![Coding {
+                    system: Some("http://loinc.org".to_string()),
+                    code: Some(code.to_string()),
+                    display: None,
+                    extra: Map::new(),
+                }]),
+                text: None,
+                extra: Map::new(),
+            },
+        );
+        observation.subject = Some(Reference {
+            reference: Some(subject.to_string()),
+            display: None,
+            extra: Map::new(),
+        });
+        observation.effective_date_time = Some(effective.to_string());
+        observation
+    }
+
+    fn search(code: &str) -> ObservationSearch {
+        ObservationSearch {
+            code: vec![TokenParam::parse(code)],
+            count: 50,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observation_crud() {
+        let db = setup_test_db().await;
+        let code = format!("crud-{}", Uuid::new_v4());
+
+        let created = db
+            .create_observation(create_test_observation(&code, "Patient/p1", "2024-03-01"))
+            .await
+            .unwrap();
+        let id = created.id.clone().unwrap();
+        assert_eq!(
+            created.meta.as_ref().unwrap().version_id.as_deref(),
+            Some("1")
+        );
+
+        let mut changed = created.clone();
+        changed.status = "amended".to_string();
+        let updated = db.update_observation(&id, changed).await.unwrap().unwrap();
+        assert_eq!(updated.status, "amended");
+        assert_eq!(
+            updated.meta.as_ref().unwrap().version_id.as_deref(),
+            Some("2")
+        );
+
+        let fetched = db.get_observation(&id).await.unwrap().unwrap();
+        assert_eq!(fetched.status, "amended");
+        // Observations are not visible as Patients
+        assert!(db.get_patient(&id).await.unwrap().is_none());
+
+        assert!(db.delete_observation(&id).await.unwrap());
+        assert!(db.get_observation(&id).await.unwrap().is_none());
+        assert!(!db.delete_observation(&id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_search_observations() {
+        let db = setup_test_db().await;
+        let code = format!("search-{}", Uuid::new_v4());
+        let subject = format!("Patient/{}", Uuid::new_v4());
+
+        let mut ids = Vec::new();
+        for (who, effective) in [
+            (subject.as_str(), "2024-01-15T10:00:00Z"),
+            (subject.as_str(), "2024-03-01"),
+            ("Patient/other", "2024-03-02"),
+        ] {
+            let created = db
+                .create_observation(create_test_observation(&code, who, effective))
+                .await
+                .unwrap();
+            ids.push(created.id.unwrap());
+        }
+
+        let by_code = db
+            .search_observations(&search(&format!("http://loinc.org|{}", code)))
+            .await
+            .unwrap();
+        assert_eq!(by_code.len(), 3);
+        let wrong_system = db
+            .search_observations(&search(&format!("http://snomed.info/sct|{}", code)))
+            .await
+            .unwrap();
+        assert!(wrong_system.is_empty());
+
+        let bare_id = subject.trim_start_matches("Patient/").to_string();
+        let by_subject = db
+            .search_observations(&ObservationSearch {
+                subject: vec![bare_id.clone()],
+                ..search(&code)
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_subject.len(), 2);
+        let either_subject = db
+            .search_observations(&ObservationSearch {
+                subject: vec![subject.clone(), "Patient/other".to_string()],
+                ..search(&code)
+            })
+            .await
+            .unwrap();
+        assert_eq!(either_subject.len(), 3);
+        for patient in [bare_id.clone(), subject.clone()] {
+            let by_patient = db
+                .search_observations(&ObservationSearch {
+                    patient: vec![patient],
+                    ..search(&code)
+                })
+                .await
+                .unwrap();
+            assert_eq!(by_patient.len(), 2);
+        }
+
+        for (missing, expected) in [(false, 3), (true, 0)] {
+            let found = db
+                .search_observations(&ObservationSearch {
+                    date_missing: Some(missing),
+                    ..search(&code)
+                })
+                .await
+                .unwrap();
+            assert_eq!(found.len(), expected);
+        }
+
+        let in_march = db
+            .search_observations(&ObservationSearch {
+                date: vec![vec![DateSearchValue::parse("date", "2024-03").unwrap()]],
+                ..search(&code)
+            })
+            .await
+            .unwrap();
+        assert_eq!(in_march.len(), 2);
+
+        let range = db
+            .search_observations(&ObservationSearch {
+                date: vec![
+                    vec![DateSearchValue::parse("date", "ge2024-01-15").unwrap()],
+                    vec![DateSearchValue::parse("date", "lt2024-03-02").unwrap()],
+                ],
+                ..search(&code)
+            })
+            .await
+            .unwrap();
+        assert_eq!(range.len(), 2);
+
+        let january_or_first = db
+            .search_observations(&ObservationSearch {
+                date: vec![vec![
+                    DateSearchValue::parse("date", "2024-01").unwrap(),
+                    DateSearchValue::parse("date", "2024-03-01").unwrap(),
+                ]],
+                ..search(&code)
+            })
+            .await
+            .unwrap();
+        assert_eq!(january_or_first.len(), 2);
+
+        // Stored dates are ranges too: the day 2024-03-01 starts after
+        // 2024-02 and ends before 2024-03-02
+        for (value, expected) in [("sa2024-02", 2), ("eb2024-03-01", 1), ("ne2024-03", 1)] {
+            let found = db
+                .search_observations(&ObservationSearch {
+                    date: vec![vec![DateSearchValue::parse("date", value).unwrap()]],
+                    ..search(&code)
+                })
+                .await
+                .unwrap();
+            assert_eq!(found.len(), expected, "{}", value);
+        }
+
+        for id in ids {
+            db.delete_observation(&id).await.unwrap();
+        }
+    }
+}
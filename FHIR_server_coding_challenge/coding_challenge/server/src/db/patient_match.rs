@@ -0,0 +1,136 @@
+//! Candidates and scores of `POST /fhir/Patient/$match`; see
+//! [`crate::search::matching`].
+
+use super::audit::SqlParam;
+use super::{any_of, identifier_condition, versioned_meta, Database, Withholding};
+use crate::models::Patient;
+use crate::search::matching::{MatchConfig, MatchInput};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::Row;
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// The name text `idx_patient_match_name` indexes
+const NAME_TEXT: &str = "lower(coalesce(resource_data #>> '{name,0,family}', '') || ' ' || coalesce(resource_data #>> '{name,0,given,0}', ''))";
+
+/// A stored patient and how well it matches
+#[derive(Debug, Clone)]
+pub struct PatientMatch {
+    pub patient: Patient,
+    /// From 0 to 1
+    pub score: f64,
+}
+
+impl Database {
+    /// Whether pg_trgm is installed, checked at startup
+    pub fn patient_match_enabled(&self) -> bool {
+        self.patient_match_enabled.load(Ordering::Relaxed)
+    }
+
+    /// The `limit` best matches of `input` scoring at least
+    /// `config.possible`, best first; with `withholding`, none a Consent
+    /// withholds
+    pub async fn match_patients(
+        &self,
+        input: &MatchInput,
+        config: &MatchConfig,
+        limit: u32,
+        withholding: Option<Withholding<'_>>,
+    ) -> Result<Vec<PatientMatch>> {
+        let mut params = Vec::new();
+        // (weight, SQL from 0 to 1) of each element the input has
+        let mut terms = Vec::new();
+        let mut candidates = Vec::new();
+
+        if let Some(name) = &input.name {
+            params.push(SqlParam::text(name.as_str()));
+            terms.push((
+                config.name_weight,
+                format!("similarity({}, ${})::float8", NAME_TEXT, params.len()),
+            ));
+            candidates.push(format!("{} % ${}", NAME_TEXT, params.len()));
+        }
+        if let Some(birth_date) = &input.birth_date {
+            params.push(SqlParam::text(birth_date.as_str()));
+            let equal = format!("resource_data->>'birthDate' = ${}", params.len());
+            terms.push((
+                config.birth_date_weight,
+                format!("COALESCE({}, FALSE)::int", equal),
+            ));
+            candidates.push(equal);
+        }
+        if let Some(gender) = &input.gender {
+            params.push(SqlParam::text(gender.as_str()));
+            terms.push((
+                config.gender_weight,
+                format!(
+                    "COALESCE(resource_data->>'gender' = ${}, FALSE)::int",
+                    params.len()
+                ),
+            ));
+        }
+        if !input.identifiers.is_empty() {
+            let shared = any_of(&input.identifiers, &mut params, |token, params| {
+                identifier_condition(token, params)
+            });
+            terms.push((config.identifier_weight, format!("({})::int", shared)));
+            candidates.push(shared);
+        }
+
+        let total: f64 = terms.iter().map(|(weight, _)| weight).sum();
+        if candidates.is_empty() || total <= 0.0 {
+            return Ok(Vec::new());
+        }
+        let mut weighted = Vec::new();
+        for (weight, term) in terms {
+            params.push(SqlParam::text((weight / total).to_string()));
+            weighted.push(format!("${}::float8 * {}", params.len(), term));
+        }
+        let unwithheld = self.unwithheld(withholding, &mut params);
+        params.push(SqlParam::text(config.possible.to_string()));
+        let possible = params.len();
+        params.push(SqlParam::BigInt(limit as i64));
+
+        let rows = self
+            .fetch_all(
+                &format!(
+                    "SELECT id, resource_data, version_id, last_updated, score FROM (
+                         SELECT id, resource_data, version_id, last_updated, {} AS score
+                         FROM fhir_resources
+                         WHERE resource_type = 'Patient' AND deleted_at IS NULL AND ({}){}
+                     ) AS candidates
+                     WHERE score >= ${}::float8
+                     ORDER BY score DESC, id
+                     LIMIT ${}",
+                    weighted.join(" + "),
+                    candidates.join(" OR "),
+                    unwithheld,
+                    possible,
+                    params.len()
+                ),
+                &params,
+            )
+            .await?;
+
+        let mut matches = Vec::new();
+        for row in rows {
+            let id: Uuid = row.get("id");
+            let version_id: i32 = row.get("version_id");
+            let last_updated: DateTime<Utc> = row.get("last_updated");
+
+            let mut patient: Patient = serde_json::from_value(row.get("resource_data"))?;
+            patient.id = Some(id.to_string());
+            patient.meta = Some(versioned_meta(
+                patient.meta.take(),
+                version_id,
+                last_updated,
+            ));
+            matches.push(PatientMatch {
+                patient,
+                score: row.get("score"),
+            });
+        }
+        Ok(matches)
+    }
+}
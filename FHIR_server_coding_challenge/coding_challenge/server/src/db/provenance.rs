@@ -0,0 +1,288 @@
+//! Storage of the Provenance records of writes; see [`crate::provenance`].
+//!
+//! Provenance is stored like any generically served resource, on the
+//! connection of the write it describes: the `*_by` writes below and the
+//! Bundle entries record it in their transaction, so a write is never
+//! committed without its Provenance. The record of a version has an id
+//! derived from its target ([`provenance::provenance_id`]), which makes
+//! recording a write that changed nothing a primary key lookup; searches by
+//! target use the index of migration `024_provenance_target.sql`.
+
+use super::audit::SqlParam;
+use super::reservation::PatientPut;
+use super::resource::StoredResource;
+use super::{text_conditions, Database, SearchSql};
+use crate::models::Patient;
+use crate::provenance::{self, Activity, Attribution};
+use crate::search::TextCriteria;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// The reference of the single target the server writes, indexed by
+/// `024_provenance_target.sql`
+const TARGET_REFERENCE: &str = "(resource_data->'target'->0->>'reference') COLLATE \"C\"";
+
+/// The Provenance records targeting `target` and matching `text`: every
+/// version of the target when it is unversioned (`Patient/1`), only that
+/// version otherwise
+pub fn provenance_target_sql(target: &str, text: &TextCriteria) -> SearchSql {
+    let mut params = vec![SqlParam::text("Provenance")];
+    let mut conditions = vec!["resource_type = $1".to_string()];
+    conditions.extend(text_conditions(text, &mut params));
+    if provenance::unversioned_target(target) == target {
+        // Every `<target>/_history/<version>`; '0' follows '/'
+        params.push(SqlParam::text(format!("{}/_history/", target)));
+        params.push(SqlParam::text(format!("{}/_history0", target)));
+        conditions.push(format!(
+            "{reference} >= ${} AND {reference} < ${}",
+            params.len() - 1,
+            params.len(),
+            reference = TARGET_REFERENCE
+        ));
+    } else {
+        params.push(SqlParam::text(target));
+        conditions.push(format!("{} = ${}", TARGET_REFERENCE, params.len()));
+    }
+    SearchSql::new(&conditions, params)
+}
+
+/// Version of a written Patient, from its `meta`
+fn patient_version(patient: &Patient) -> Option<(&str, i32)> {
+    let version_id = patient.meta.as_ref()?.version_id.as_deref()?.parse().ok()?;
+    Some((patient.id.as_deref()?, version_id))
+}
+
+impl Database {
+    /// Store the Provenance of `attribution` writing version `version_id` of
+    /// a resource on `conn`, the connection of the write. A version that
+    /// already has a Provenance, i.e. a write that changed nothing, is not
+    /// recorded again.
+    pub(crate) async fn record_provenance_on(
+        &self,
+        conn: &mut PgConnection,
+        attribution: &Attribution,
+        activity: Activity,
+        resource_type: &str,
+        id: &str,
+        version_id: i32,
+    ) -> Result<()> {
+        if !provenance::is_recorded(resource_type) {
+            return Ok(());
+        }
+        let target = provenance::versioned_reference(resource_type, id, version_id);
+        let provenance_id = provenance::provenance_id(&target);
+        if self
+            .get_resource_on(conn, "Provenance", &provenance_id.to_string())
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let resource = provenance::provenance_resource(&target, activity, attribution, self.now());
+        self.create_resource_on(conn, "Provenance", provenance_id, resource)
+            .await?;
+        Ok(())
+    }
+
+    /// [`Database::record_provenance_on`] for a written Patient
+    pub(crate) async fn record_patient_provenance_on(
+        &self,
+        conn: &mut PgConnection,
+        attribution: &Attribution,
+        activity: Activity,
+        patient: &Patient,
+    ) -> Result<()> {
+        match patient_version(patient) {
+            Some((id, version_id)) => {
+                self.record_provenance_on(conn, attribution, activity, "Patient", id, version_id)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// [`Database::create_patient`] by `attribution`
+    pub async fn create_patient_by(
+        &self,
+        attribution: &Attribution,
+        patient: Patient,
+    ) -> Result<Patient> {
+        let mut tx = self.pool.begin().await?;
+        let created = self
+            .create_patient_on(&mut tx, Uuid::new_v4(), patient)
+            .await?;
+        self.record_patient_provenance_on(&mut tx, attribution, Activity::Create, &created)
+            .await?;
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    /// [`Database::put_patient_if_match`] by `attribution`, holding the lock
+    /// of `lock_token_hash` if the patient is locked
+    pub async fn put_patient_by(
+        &self,
+        attribution: &Attribution,
+        lock_token_hash: Option<&str>,
+        id: &str,
+        patient: Patient,
+        expected_version: Option<i32>,
+    ) -> Result<Option<PatientPut>> {
+        let mut tx = self.pool.begin().await?;
+        self.check_record_lock_on(&mut tx, "Patient", id, lock_token_hash)
+            .await?;
+        let put = self
+            .put_patient_on(&mut tx, id, patient, expected_version)
+            .await?;
+        match &put {
+            Some(PatientPut::Created(created)) => {
+                self.record_patient_provenance_on(&mut tx, attribution, Activity::Create, created)
+                    .await?
+            }
+            Some(PatientPut::Updated(updated)) => {
+                self.record_patient_provenance_on(&mut tx, attribution, Activity::Update, updated)
+                    .await?
+            }
+            None => {}
+        }
+        tx.commit().await?;
+
+        Ok(put)
+    }
+
+    /// [`Database::update_patient_if_match`] by `attribution`, holding the
+    /// lock of `lock_token_hash` if the patient is locked
+    pub async fn update_patient_by(
+        &self,
+        attribution: &Attribution,
+        lock_token_hash: Option<&str>,
+        id: &str,
+        patient: Patient,
+        expected_version: Option<i32>,
+    ) -> Result<Option<Patient>> {
+        let mut tx = self.pool.begin().await?;
+        self.check_record_lock_on(&mut tx, "Patient", id, lock_token_hash)
+            .await?;
+        let updated = self
+            .update_patient_on(&mut tx, id, patient, expected_version)
+            .await?;
+        if let Some(updated) = &updated {
+            self.record_patient_provenance_on(&mut tx, attribution, Activity::Update, updated)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(updated)
+    }
+
+    /// [`Database::create_resource`] by `attribution`
+    pub async fn create_resource_by(
+        &self,
+        attribution: &Attribution,
+        resource_type: &str,
+        resource: Value,
+    ) -> Result<StoredResource> {
+        let mut tx = self.pool.begin().await?;
+        let created = self
+            .create_resource_on(&mut tx, resource_type, Uuid::new_v4(), resource)
+            .await?;
+        self.record_provenance_on(
+            &mut tx,
+            attribution,
+            Activity::Create,
+            resource_type,
+            &created.id.to_string(),
+            created.version_id,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    /// [`Database::update_resource`] by `attribution`
+    pub async fn update_resource_by(
+        &self,
+        attribution: &Attribution,
+        resource_type: &str,
+        id: &str,
+        resource: Value,
+    ) -> Result<Option<StoredResource>> {
+        let mut tx = self.pool.begin().await?;
+        let updated = self
+            .update_resource_on(&mut tx, resource_type, id, resource)
+            .await?;
+        if let Some(updated) = &updated {
+            self.record_provenance_on(
+                &mut tx,
+                attribution,
+                Activity::Update,
+                resource_type,
+                id,
+                updated.version_id,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(updated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::db::PageStart;
+
+    #[tokio::test]
+    async fn test_provenance_per_version() {
+        let db = setup_test_db().await;
+        let attribution = Attribution {
+            agent: Some("importer".to_string()),
+            request_id: Some(Uuid::new_v4()),
+        };
+        let created = db
+            .create_patient_by(&attribution, Patient::new())
+            .await
+            .unwrap();
+        let id = created.id.clone().unwrap();
+        let mut changed = created.clone();
+        changed.gender = Some("female".to_string());
+        db.update_patient_by(&attribution, None, &id, changed.clone(), None)
+            .await
+            .unwrap();
+        // The same content again leaves the patient at version 2
+        db.update_patient_by(&attribution, None, &id, changed, None)
+            .await
+            .unwrap();
+
+        let target = format!("Patient/{}", id);
+        let all = provenance_target_sql(&target, &TextCriteria::default());
+        let mut codes: Vec<String> = db
+            .list_resources(&all, 10, PageStart::Offset(0))
+            .await
+            .unwrap()
+            .iter()
+            .map(|p| {
+                p.data["activity"]["coding"][0]["code"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        codes.sort();
+        assert_eq!(codes, vec!["CREATE", "UPDATE"]);
+
+        let second =
+            provenance_target_sql(&format!("{}/_history/2", target), &TextCriteria::default());
+        let second = db
+            .list_resources(&second, 10, PageStart::Offset(0))
+            .await
+            .unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].data["agent"][0]["who"]["display"], "importer");
+    }
+}
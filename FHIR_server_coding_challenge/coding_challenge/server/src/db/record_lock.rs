@@ -0,0 +1,294 @@
+//! Locks that keep other users from changing a record during a review.
+//!
+//! `POST /fhir/Patient/:id/$lock` stores a lock in `fhir.record_lock`
+//! (migration `022_record_locks.sql`) under the SHA-256 of a token handed to
+//! the client. Until the lock expires, writes to the record are refused
+//! unless they carry the token; taking the lock again with the token renews
+//! it. An expired lock is simply replaced, so abandoned reviews release the
+//! record on their own. Times are taken from the database's clock.
+//!
+//! Writes check for a lock in their own transaction, after locking the
+//! record's row, and taking a lock shares that row lock: a `$lock` therefore
+//! waits for writes in progress, and writes started after it see the lock.
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, Row};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A live lock on a record
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordLock {
+    /// Who the lock was taken for, as the client named them
+    pub owner: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A write refused because a live lock under another token holds the
+/// record
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordLocked {
+    pub resource_type: String,
+    pub id: String,
+    pub lock: RecordLock,
+}
+
+impl std::fmt::Display for RecordLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} is locked until {}",
+            self.resource_type,
+            self.id,
+            self.lock.expires_at.to_rfc3339()
+        )
+    }
+}
+
+impl std::error::Error for RecordLocked {}
+
+/// What taking a lock did
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockOutcome {
+    /// Taken, or renewed by its token
+    Locked(RecordLock),
+    /// Someone else holds the record
+    Held(RecordLock),
+}
+
+/// What releasing a lock did
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnlockOutcome {
+    Released,
+    /// There was no live lock
+    NotLocked,
+    /// Someone else holds the record
+    Held(RecordLock),
+}
+
+impl Database {
+    /// Whether `fhir.record_lock` exists, checked at startup
+    pub fn record_locks_enabled(&self) -> bool {
+        self.record_locks_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Lock `id` of `resource_type` for `ttl` under `token_hash`, unless a
+    /// live lock with another token holds it
+    pub async fn lock_record(
+        &self,
+        resource_type: &str,
+        id: Uuid,
+        token_hash: &str,
+        owner: Option<&str>,
+        ttl: Duration,
+    ) -> Result<LockOutcome> {
+        let mut tx = self.pool.begin().await?;
+        // Waits for writes to the record that checked for a lock before it
+        self.fetch_optional_on(
+            &mut *tx,
+            "SELECT 1 FROM fhir_resources WHERE id = $1 AND resource_type = $2 FOR SHARE",
+            &[SqlParam::Uuid(id), SqlParam::text(resource_type)],
+        )
+        .await?;
+        let row = self
+            .fetch_optional_on(
+                &mut *tx,
+                "INSERT INTO fhir.record_lock (resource_type, id, token_hash, owner, locked_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $5 + make_interval(secs => $6))
+                 ON CONFLICT (resource_type, id) DO UPDATE
+                 SET token_hash = EXCLUDED.token_hash,
+                     owner = COALESCE(EXCLUDED.owner, fhir.record_lock.owner),
+                     locked_at = EXCLUDED.locked_at,
+                     expires_at = EXCLUDED.expires_at
+                 WHERE fhir.record_lock.expires_at <= $5
+                    OR fhir.record_lock.token_hash = EXCLUDED.token_hash
+                 RETURNING owner, expires_at",
+                &[
+                    SqlParam::text(resource_type),
+                    SqlParam::Uuid(id),
+                    SqlParam::text(token_hash),
+                    SqlParam::Text(owner.map(str::to_string)),
+                    SqlParam::Timestamp(self.now()),
+                    SqlParam::BigInt(ttl.as_secs().try_into().unwrap_or(i64::MAX)),
+                ],
+            )
+            .await?;
+        tx.commit().await?;
+        if let Some(row) = row {
+            return Ok(LockOutcome::Locked(RecordLock {
+                owner: row.get("owner"),
+                expires_at: row.get("expires_at"),
+            }));
+        }
+        match self
+            .blocking_lock(resource_type, id, Some(token_hash))
+            .await?
+        {
+            Some(lock) => Ok(LockOutcome::Held(lock)),
+            // Released between the two statements
+            None => Box::pin(self.lock_record(resource_type, id, token_hash, owner, ttl)).await,
+        }
+    }
+
+    /// Release the lock on `id` of `resource_type` that `token_hash` holds
+    pub async fn unlock_record(
+        &self,
+        resource_type: &str,
+        id: Uuid,
+        token_hash: Option<&str>,
+    ) -> Result<UnlockOutcome> {
+        let now = self.now();
+        let row = self
+            .fetch_optional(
+                "DELETE FROM fhir.record_lock
+                 WHERE resource_type = $1 AND id = $2 AND (expires_at <= $3 OR token_hash = $4)
+                 RETURNING expires_at > $3 AS live",
+                &[
+                    SqlParam::text(resource_type),
+                    SqlParam::Uuid(id),
+                    SqlParam::Timestamp(now),
+                    SqlParam::Text(token_hash.map(str::to_string)),
+                ],
+            )
+            .await?;
+        if let Some(row) = row {
+            return Ok(match row.get("live") {
+                true => UnlockOutcome::Released,
+                false => UnlockOutcome::NotLocked,
+            });
+        }
+        match self.blocking_lock(resource_type, id, token_hash).await? {
+            Some(lock) => Ok(UnlockOutcome::Held(lock)),
+            None => Ok(UnlockOutcome::NotLocked),
+        }
+    }
+
+    /// The live lock on `id` of `resource_type` held under a token other
+    /// than `token_hash`, which stops a write that carries `token_hash`
+    pub async fn blocking_lock(
+        &self,
+        resource_type: &str,
+        id: Uuid,
+        token_hash: Option<&str>,
+    ) -> Result<Option<RecordLock>> {
+        if !self.record_locks_enabled() {
+            return Ok(None);
+        }
+        let row = self
+            .fetch_optional(
+                "SELECT owner, expires_at FROM fhir.record_lock
+                 WHERE resource_type = $1 AND id = $2 AND expires_at > $3
+                   AND token_hash IS DISTINCT FROM $4",
+                &[
+                    SqlParam::text(resource_type),
+                    SqlParam::Uuid(id),
+                    SqlParam::Timestamp(self.now()),
+                    SqlParam::Text(token_hash.map(str::to_string)),
+                ],
+            )
+            .await?;
+
+        Ok(row.map(|row| RecordLock {
+            owner: row.get("owner"),
+            expires_at: row.get("expires_at"),
+        }))
+    }
+
+    /// Refuse, with [`RecordLocked`], a write of `id` of `resource_type`
+    /// carrying `token_hash` while a lock under another token holds it. Run
+    /// on the write's transaction: the record's row stays locked until it
+    /// commits, so no lock can be taken in between.
+    pub(crate) async fn check_record_lock_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: &str,
+        token_hash: Option<&str>,
+    ) -> Result<()> {
+        if !self.record_locks_enabled() {
+            return Ok(());
+        }
+        // Ids that are not UUIDs name no stored record, let alone a locked one
+        let Ok(uuid) = Uuid::parse_str(id) else {
+            return Ok(());
+        };
+        self.fetch_optional_on(
+            &mut *conn,
+            "SELECT 1 FROM fhir_resources WHERE id = $1 AND resource_type = $2 FOR UPDATE",
+            &[SqlParam::Uuid(uuid), SqlParam::text(resource_type)],
+        )
+        .await?;
+        let row = self
+            .fetch_optional_on(
+                &mut *conn,
+                "SELECT owner, expires_at FROM fhir.record_lock
+                 WHERE resource_type = $1 AND id = $2 AND expires_at > $3
+                   AND token_hash IS DISTINCT FROM $4
+                 FOR SHARE",
+                &[
+                    SqlParam::text(resource_type),
+                    SqlParam::Uuid(uuid),
+                    SqlParam::Timestamp(self.now()),
+                    SqlParam::Text(token_hash.map(str::to_string)),
+                ],
+            )
+            .await?;
+        match row {
+            Some(row) => Err(RecordLocked {
+                resource_type: resource_type.to_string(),
+                id: id.to_string(),
+                lock: RecordLock {
+                    owner: row.get("owner"),
+                    expires_at: row.get("expires_at"),
+                },
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_lock_waits_for_writes_in_progress() {
+        let db = Arc::new(setup_test_db().await);
+        let id = db.create_patient(Patient::new()).await.unwrap().id.unwrap();
+        let uuid = Uuid::parse_str(&id).unwrap();
+
+        let mut write = db.begin_bundle().await.unwrap();
+        write.check_patient_lock(&id, None).await.unwrap();
+        let locking = tokio::spawn({
+            let db = db.clone();
+            async move {
+                db.lock_record("Patient", uuid, "reviewer", None, Duration::from_secs(60))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!locking.is_finished());
+
+        write.commit().await.unwrap();
+        let outcome = locking.await.unwrap().unwrap();
+        assert!(matches!(outcome, LockOutcome::Locked(_)));
+
+        // Writes checked after the lock was taken are refused
+        let err = db
+            .put_patient_by(&Default::default(), None, &id, Patient::new(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<RecordLocked>().unwrap().id, id);
+        db.unlock_record("Patient", uuid, Some("reviewer"))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! Ids reserved for resources a client creates later.
+//!
+//! `POST /fhir/Patient/$reserve-id` hands out a fresh id and records it in
+//! `fhir.id_reservation` (migration `015_id_reservations.sql`). An offline
+//! client links its records to the id right away; once it reaches the
+//! server, a PUT to the id creates the patient and consumes the
+//! reservation. A retried PUT finds the patient and updates it instead, to
+//! the same content, so sending it again is harmless. Reservations expire
+//! and are removed as new ids are reserved.
+
+use super::audit::SqlParam;
+use super::Database;
+use crate::models::Patient;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, Row};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// An id set aside for a resource of `resource_type`
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdReservation {
+    pub id: Uuid,
+    pub resource_type: String,
+    /// After this a PUT to the id no longer creates the resource
+    pub expires_at: DateTime<Utc>,
+}
+
+/// What a PUT to a Patient id did
+#[derive(Debug, Clone)]
+pub enum PatientPut {
+    /// The id was reserved and the patient is new
+    Created(Patient),
+    Updated(Patient),
+}
+
+impl Database {
+    /// Whether `fhir.id_reservation` exists, checked at startup
+    pub fn reservations_enabled(&self) -> bool {
+        self.reservations_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Reserve a new id of `resource_type` for `ttl`
+    pub async fn reserve_id(&self, resource_type: &str, ttl: Duration) -> Result<IdReservation> {
+        let now = self.now();
+        self.execute(
+            "DELETE FROM fhir.id_reservation WHERE expires_at <= $1",
+            &[SqlParam::Timestamp(now)],
+        )
+        .await?;
+
+        let id = Uuid::new_v4();
+        let row = self
+            .fetch_one(
+                "INSERT INTO fhir.id_reservation (id, resource_type, expires_at)
+                 VALUES ($1, $2, $3 + make_interval(secs => $4))
+                 RETURNING expires_at",
+                &[
+                    SqlParam::Uuid(id),
+                    SqlParam::text(resource_type),
+                    SqlParam::Timestamp(now),
+                    SqlParam::BigInt(ttl.as_secs().try_into().unwrap_or(i64::MAX)),
+                ],
+            )
+            .await?;
+
+        Ok(IdReservation {
+            id,
+            resource_type: resource_type.to_string(),
+            expires_at: row.get("expires_at"),
+        })
+    }
+
+    /// Remove the live reservation of `id` for `resource_type`; whether
+    /// there was one. A concurrent caller taking the same reservation waits
+    /// for this transaction and then finds none.
+    async fn take_reservation_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: Uuid,
+    ) -> Result<bool> {
+        if !self.reservations_enabled() {
+            return Ok(false);
+        }
+        let taken = self
+            .fetch_optional_on(
+                &mut *conn,
+                "DELETE FROM fhir.id_reservation
+                 WHERE id = $1 AND resource_type = $2 AND expires_at > $3
+                 RETURNING id",
+                &[
+                    SqlParam::Uuid(id),
+                    SqlParam::text(resource_type),
+                    SqlParam::Timestamp(self.now()),
+                ],
+            )
+            .await?;
+
+        Ok(taken.is_some())
+    }
+
+    /// Replace the patient `id` like [`Database::update_patient_if_match`],
+    /// or create it if `id` is reserved. A version is only expected of
+    /// existing patients, so with one a reserved id is not created.
+    pub async fn put_patient_if_match(
+        &self,
+        id: &str,
+        patient: Patient,
+        expected_version: Option<i32>,
+    ) -> Result<Option<PatientPut>> {
+        let mut tx = self.pool.begin().await?;
+        let put = self
+            .put_patient_on(&mut tx, id, patient, expected_version)
+            .await?;
+        tx.commit().await?;
+
+        Ok(put)
+    }
+
+    /// [`Database::put_patient_if_match`] on `conn`, which may be part of a
+    /// larger transaction
+    pub(crate) async fn put_patient_on(
+        &self,
+        conn: &mut PgConnection,
+        id: &str,
+        patient: Patient,
+        expected_version: Option<i32>,
+    ) -> Result<Option<PatientPut>> {
+        // A live reservation means the patient does not exist yet: ids are
+        // only reserved fresh and the reservation goes with the create
+        let patient_uuid = Uuid::parse_str(id)?;
+        if expected_version.is_none()
+            && self
+                .take_reservation_on(conn, "Patient", patient_uuid)
+                .await?
+        {
+            let mut patient = patient;
+            patient.id = Some(id.to_string());
+            patient.resource_type = "Patient".to_string();
+            let created = self.create_patient_on(conn, patient_uuid, patient).await?;
+            return Ok(Some(PatientPut::Created(created)));
+        }
+
+        let updated = self
+            .update_patient_on(conn, id, patient, expected_version)
+            .await?;
+        Ok(updated.map(PatientPut::Updated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use serde_json::json;
+
+    fn patient(family: &str) -> Patient {
+        serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "name": [{ "family": family }]
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_put_to_reserved_id_creates_once() {
+        let db = setup_test_db().await;
+        let reservation = db
+            .reserve_id("Patient", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert!(reservation.expires_at > Utc::now());
+        let id = reservation.id.to_string();
+
+        let created = db
+            .put_patient_if_match(&id, patient("Reserved"), None)
+            .await;
+        let Ok(Some(PatientPut::Created(created))) = created else {
+            panic!("expected the reserved id to be created");
+        };
+        assert_eq!(created.id.as_deref(), Some(id.as_str()));
+        assert_eq!(
+            created.meta.as_ref().unwrap().version_id.as_deref(),
+            Some("1")
+        );
+
+        // Sent again, the same PUT updates to the same version
+        let retried = db
+            .put_patient_if_match(&id, patient("Reserved"), None)
+            .await;
+        let Ok(Some(PatientPut::Updated(retried))) = retried else {
+            panic!("expected the retry to update");
+        };
+        assert_eq!(
+            retried.meta.as_ref().unwrap().version_id.as_deref(),
+            Some("1")
+        );
+
+        // Neither unreserved nor expired ids are created, and with an
+        // expected version the patient must exist
+        let expired = db.reserve_id("Patient", Duration::ZERO).await.unwrap();
+        let reserved = db
+            .reserve_id("Patient", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        for (id, expected_version) in [
+            (Uuid::new_v4(), None),
+            (expired.id, None),
+            (reserved.id, Some(1)),
+        ] {
+            let put = db
+                .put_patient_if_match(&id.to_string(), patient("Reserved"), expected_version)
+                .await
+                .unwrap();
+            assert!(put.is_none());
+        }
+
+        db.purge_patient(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reservations_expire_by_the_clock() {
+        use crate::clock::TestClock;
+
+        let start = "2010-03-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let clock = std::sync::Arc::new(TestClock::new(start));
+        let db = setup_test_db().await.with_clock(clock.clone());
+        let reservation = db
+            .reserve_id("Patient", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(reservation.expires_at, start + chrono::Duration::hours(1));
+
+        clock.advance(chrono::Duration::hours(2));
+        let put = db
+            .put_patient_if_match(&reservation.id.to_string(), patient("Late"), None)
+            .await
+            .unwrap();
+        assert!(put.is_none());
+    }
+}
@@ -0,0 +1,355 @@
+//! Type-agnostic storage of FHIR resources in `fhir_resources`.
+//!
+//! Resources are stored as JSON without `id` and `meta`; both are derived
+//! from the row on the way out. Typed access (Observation) and the generic
+//! `/fhir/:resourceType` handlers are built on these primitives, while
+//! Patient keeps its own queries for identifier indexing and history.
+
+use super::audit::SqlParam;
+use super::canonical;
+use super::conformance::{self, Canonical};
+use super::{text_conditions, Database, PageStart, SearchSql};
+use crate::search::TextCriteria;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sqlx::postgres::PgRow;
+use sqlx::{PgConnection, Row};
+use uuid::Uuid;
+
+/// Columns every query below selects
+pub(super) const RESOURCE_COLUMNS: &str =
+    "id, resource_type, resource_data, version_id, last_updated";
+
+/// A row of `fhir_resources`
+#[derive(Debug, Clone)]
+pub struct StoredResource {
+    pub id: Uuid,
+    pub resource_type: String,
+    pub version_id: i32,
+    pub last_updated: DateTime<Utc>,
+    /// Resource body as stored, without `id` and `meta`
+    pub data: Value,
+}
+
+/// The resources of `resource_type` matching `text`
+pub fn resource_list_sql(resource_type: &str, text: &TextCriteria) -> SearchSql {
+    let mut params = vec![SqlParam::text(resource_type)];
+    let mut conditions = vec!["resource_type = $1".to_string()];
+    conditions.extend(text_conditions(text, &mut params));
+    SearchSql::new(&conditions, params)
+}
+
+impl StoredResource {
+    pub(super) fn from_row(row: &PgRow) -> Self {
+        Self {
+            id: row.get("id"),
+            resource_type: row.get("resource_type"),
+            version_id: row.get("version_id"),
+            last_updated: row.get("last_updated"),
+            data: row.get("resource_data"),
+        }
+    }
+
+    /// The resource as served: body plus `resourceType`, `id` and `meta`
+    pub fn into_resource(self) -> Value {
+        let mut resource = self.data;
+        if let Value::Object(map) = &mut resource {
+            map.insert("resourceType".to_string(), json!(self.resource_type));
+            map.insert("id".to_string(), json!(self.id.to_string()));
+            map.insert(
+                "meta".to_string(),
+                json!({
+                    "versionId": self.version_id.to_string(),
+                    "lastUpdated": self.last_updated,
+                }),
+            );
+        }
+        resource
+    }
+}
+
+/// Server-assigned elements are never taken from the client; the rest is
+/// stored in canonical form
+pub(super) fn storable(resource_type: &str, resource: Value) -> Value {
+    let mut resource = canonical::normalize(resource);
+    if let Value::Object(map) = &mut resource {
+        map.remove("id");
+        map.remove("meta");
+        map.insert("resourceType".to_string(), json!(resource_type));
+    }
+    resource
+}
+
+/// The canonical a resource of `resource_type` is stored under, if it has one
+fn stored_canonical(resource_type: &str, resource: &Value) -> Option<Canonical> {
+    conformance::is_conformance_type(resource_type)
+        .then(|| Canonical::of(resource))
+        .flatten()
+}
+
+impl Database {
+    /// Store a new resource of `resource_type`
+    pub async fn create_resource(
+        &self,
+        resource_type: &str,
+        resource: Value,
+    ) -> Result<StoredResource> {
+        let mut tx = self.pool.begin().await?;
+        let created = self
+            .create_resource_on(&mut tx, resource_type, Uuid::new_v4(), resource)
+            .await?;
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    /// Store a new resource under `id` on `conn`, which may be part of a
+    /// larger transaction
+    pub(crate) async fn create_resource_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: Uuid,
+        resource: Value,
+    ) -> Result<StoredResource> {
+        let resource = storable(resource_type, resource);
+        let hash = canonical::content_hash(&resource);
+        let stored = stored_canonical(resource_type, &resource);
+        if let Some(stored) = &stored {
+            self.check_canonical_on(conn, resource_type, id, stored)
+                .await?;
+        }
+        let row = self
+            .fetch_one_on(
+                &mut *conn,
+                &format!(
+                    "INSERT INTO fhir_resources (id, resource_type, resource_data, version_id, last_updated, content_hash)
+                     VALUES ($1, $2, $3, 1, $5, $4)
+                     RETURNING {}",
+                    RESOURCE_COLUMNS
+                ),
+                &[
+                    SqlParam::Uuid(id),
+                    SqlParam::text(resource_type),
+                    SqlParam::Json(resource),
+                    SqlParam::text(hash),
+                    SqlParam::Timestamp(self.now()),
+                ],
+            )
+            .await
+            .map_err(|e| {
+                conformance::duplicate_on_violation(e, resource_type, stored.as_ref())
+            })?;
+        self.sync_search_values_on(conn, id).await?;
+
+        Ok(StoredResource::from_row(&row))
+    }
+
+    /// Get a resource by type and UUID
+    pub async fn get_resource(
+        &self,
+        resource_type: &str,
+        id: &str,
+    ) -> Result<Option<StoredResource>> {
+        let mut conn = self.pool.acquire().await?;
+        self.get_resource_on(&mut conn, resource_type, id).await
+    }
+
+    pub(crate) async fn get_resource_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: &str,
+    ) -> Result<Option<StoredResource>> {
+        let resource_uuid = Uuid::parse_str(id)?;
+
+        let row = self
+            .fetch_optional_on(
+                &mut *conn,
+                &format!(
+                    "SELECT {} FROM fhir_resources WHERE id = $1 AND resource_type = $2",
+                    RESOURCE_COLUMNS
+                ),
+                &[SqlParam::Uuid(resource_uuid), SqlParam::text(resource_type)],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(StoredResource::from_row(&row))),
+            None => {
+                self.get_archived_on(conn, resource_type, resource_uuid)
+                    .await
+            }
+        }
+    }
+
+    /// Replace an existing resource (PUT semantics); `None` if it does not
+    /// exist. Content identical to the current version keeps that version.
+    pub async fn update_resource(
+        &self,
+        resource_type: &str,
+        id: &str,
+        resource: Value,
+    ) -> Result<Option<StoredResource>> {
+        let mut tx = self.pool.begin().await?;
+        let updated = self
+            .update_resource_on(&mut tx, resource_type, id, resource)
+            .await?;
+        tx.commit().await?;
+
+        Ok(updated)
+    }
+
+    pub(crate) async fn update_resource_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: &str,
+        resource: Value,
+    ) -> Result<Option<StoredResource>> {
+        let resource_uuid = Uuid::parse_str(id)?;
+        self.restore_archived_on(conn, resource_type, resource_uuid)
+            .await?;
+        let resource = storable(resource_type, resource);
+        let hash = canonical::content_hash(&resource);
+        let stored = stored_canonical(resource_type, &resource);
+        if let Some(stored) = &stored {
+            self.check_canonical_on(conn, resource_type, resource_uuid, stored)
+                .await?;
+        }
+
+        let row = self
+            .fetch_optional_on(
+                &mut *conn,
+                &format!(
+                    "UPDATE fhir_resources
+                     SET resource_data = $1, version_id = version_id + 1, last_updated = $5, content_hash = $4
+                     WHERE id = $2 AND resource_type = $3 AND content_hash IS DISTINCT FROM $4
+                     RETURNING {}",
+                    RESOURCE_COLUMNS
+                ),
+                &[
+                    SqlParam::Json(resource),
+                    SqlParam::Uuid(resource_uuid),
+                    SqlParam::text(resource_type),
+                    SqlParam::text(hash),
+                    SqlParam::Timestamp(self.now()),
+                ],
+            )
+            .await
+            .map_err(|e| {
+                conformance::duplicate_on_violation(e, resource_type, stored.as_ref())
+            })?;
+
+        match row {
+            Some(row) => {
+                self.sync_search_values_on(conn, resource_uuid).await?;
+                Ok(Some(StoredResource::from_row(&row)))
+            }
+            None => self.get_resource_on(conn, resource_type, id).await,
+        }
+    }
+
+    /// Remove a resource; returns whether it existed
+    pub async fn delete_resource(&self, resource_type: &str, id: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = self.delete_resource_on(&mut tx, resource_type, id).await?;
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+
+    pub(crate) async fn delete_resource_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_type: &str,
+        id: &str,
+    ) -> Result<bool> {
+        let resource_uuid = Uuid::parse_str(id)?;
+        self.restore_archived_on(conn, resource_type, resource_uuid)
+            .await?;
+
+        let result = self
+            .execute_on(
+                &mut *conn,
+                "DELETE FROM fhir_resources WHERE id = $1 AND resource_type = $2",
+                &[SqlParam::Uuid(resource_uuid), SqlParam::text(resource_type)],
+            )
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// One page of the matches of `search` (see [`resource_list_sql`]), in
+    /// stable order
+    pub async fn list_resources(
+        &self,
+        search: &SearchSql,
+        count: u32,
+        start: PageStart,
+    ) -> Result<Vec<StoredResource>> {
+        let (query, params) = search.page(count, start);
+        let rows = self.fetch_all(&query, &params).await?;
+
+        Ok(rows.iter().map(StoredResource::from_row).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    #[tokio::test]
+    async fn test_resource_crud_is_scoped_by_type() {
+        let db = setup_test_db().await;
+
+        let created = db
+            .create_resource(
+                "Encounter",
+                json!({
+                    "resourceType": "Encounter",
+                    "id": "client-chosen",
+                    "status": "planned",
+                    "class": { "code": "AMB" }
+                }),
+            )
+            .await
+            .unwrap();
+        let id = created.id.to_string();
+        assert_eq!(created.version_id, 1);
+
+        let served = created.into_resource();
+        assert_eq!(served["id"], id);
+        assert_eq!(served["meta"]["versionId"], "1");
+        assert_eq!(served["status"], "planned");
+
+        // The same id under another type is a different resource
+        assert!(db.get_resource("Condition", &id).await.unwrap().is_none());
+        assert!(!db.delete_resource("Condition", &id).await.unwrap());
+
+        let updated = db
+            .update_resource("Encounter", &id, json!({ "status": "finished" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.version_id, 2);
+        assert_eq!(updated.data["resourceType"], "Encounter");
+
+        // Identical content (nulls aside) is a no-op
+        let unchanged = db
+            .update_resource(
+                "Encounter",
+                &id,
+                json!({ "status": "finished", "period": null }),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unchanged.version_id, 2);
+
+        assert!(db.delete_resource("Encounter", &id).await.unwrap());
+        assert!(db.get_resource("Encounter", &id).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,225 @@
+//! Bookkeeping of `fhir-server migrate` (migration
+//! `013_schema_changes.sql`): the server processes that might be running
+//! and the phase each expand/contract column change has reached. The steps
+//! themselves are in [`crate::migrations`].
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::Row;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A server process as last reported by its heartbeat
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppInstance {
+    pub id: Uuid,
+    /// Latest migration the process was built for
+    pub schema_version: i32,
+    pub started_at: DateTime<Utc>,
+    pub seen_at: DateTime<Utc>,
+}
+
+/// A column change as recorded in `fhir.schema_change`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaChangeRecord {
+    pub name: String,
+    pub table: String,
+    pub old_column: String,
+    pub new_column: String,
+    /// `expanded`, `switched` or `contracted`
+    pub phase: String,
+    pub contract_version: i32,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// A destructive step was refused because instances built for an older
+/// schema have been seen recently and might still use what it drops
+#[derive(Debug, Clone, PartialEq)]
+pub struct OldInstancesRunning {
+    pub contract_version: i32,
+    pub instances: Vec<AppInstance>,
+}
+
+impl fmt::Display for OldInstancesRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let instances: Vec<String> = self
+            .instances
+            .iter()
+            .map(|i| format!("{} (schema {}, seen {})", i.id, i.schema_version, i.seen_at))
+            .collect();
+        write!(
+            f,
+            "instances older than schema {} might still be running: {}",
+            self.contract_version,
+            instances.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for OldInstancesRunning {}
+
+impl AppInstance {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Self {
+        Self {
+            id: row.get("instance_id"),
+            schema_version: row.get("schema_version"),
+            started_at: row.get("started_at"),
+            seen_at: row.get("seen_at"),
+        }
+    }
+}
+
+impl SchemaChangeRecord {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Self {
+        Self {
+            name: row.get("name"),
+            table: row.get("table_name"),
+            old_column: row.get("old_column"),
+            new_column: row.get("new_column"),
+            phase: row.get("phase"),
+            contract_version: row.get("contract_version"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+const INSTANCE_COLUMNS: &str = "instance_id, schema_version, started_at, seen_at";
+
+impl Database {
+    /// Whether the `fhir.app_instance` and `fhir.schema_change` tables
+    /// exist, checked at startup
+    pub fn schema_changes_enabled(&self) -> bool {
+        self.schema_changes_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Record that this process runs, or still runs; rows not seen for a
+    /// day are dropped on the way
+    pub async fn touch_instance(&self, id: Uuid, schema_version: i32) -> Result<()> {
+        self.execute(
+            "INSERT INTO fhir.app_instance (instance_id, schema_version) VALUES ($1, $2)
+             ON CONFLICT (instance_id) DO UPDATE SET seen_at = NOW()",
+            &[SqlParam::Uuid(id), SqlParam::Int(schema_version)],
+        )
+        .await?;
+        self.execute(
+            "DELETE FROM fhir.app_instance WHERE seen_at < NOW() - INTERVAL '1 day'",
+            &[],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Instances seen within `window`, newest first
+    pub async fn live_instances(&self, window: Duration) -> Result<Vec<AppInstance>> {
+        let rows = self
+            .fetch_all(
+                &format!(
+                    "SELECT {} FROM fhir.app_instance
+                     WHERE seen_at > NOW() - $1::INTEGER * INTERVAL '1 second'
+                     ORDER BY seen_at DESC",
+                    INSTANCE_COLUMNS
+                ),
+                &[SqlParam::Int(window.as_secs() as i32)],
+            )
+            .await?;
+        Ok(rows.iter().map(AppInstance::from_row).collect())
+    }
+
+    pub async fn schema_change(&self, name: &str) -> Result<Option<SchemaChangeRecord>> {
+        let row = self
+            .fetch_optional(
+                "SELECT * FROM fhir.schema_change WHERE name = $1",
+                &[SqlParam::text(name)],
+            )
+            .await?;
+        Ok(row.as_ref().map(SchemaChangeRecord::from_row))
+    }
+
+    pub async fn schema_changes(&self) -> Result<Vec<SchemaChangeRecord>> {
+        let rows = self
+            .fetch_all("SELECT * FROM fhir.schema_change ORDER BY started_at", &[])
+            .await?;
+        Ok(rows.iter().map(SchemaChangeRecord::from_row).collect())
+    }
+
+    /// Run `ddl` and record `change` in one transaction. With `guard`, the
+    /// step is refused with [`OldInstancesRunning`] if an instance built for
+    /// a schema older than `change.contract_version` was seen within
+    /// `guard`; registrations wait for the transaction, so none can slip in
+    /// between the check and the DDL.
+    pub async fn apply_schema_change(
+        &self,
+        change: &SchemaChangeRecord,
+        ddl: &[String],
+        guard: Option<Duration>,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        if let Some(window) = guard {
+            self.execute_on(&mut *tx, "LOCK TABLE fhir.app_instance IN SHARE MODE", &[])
+                .await?;
+            let rows = self
+                .fetch_all_on(
+                    &mut *tx,
+                    &format!(
+                        "SELECT {} FROM fhir.app_instance
+                         WHERE schema_version < $1
+                           AND seen_at > NOW() - $2::INTEGER * INTERVAL '1 second'
+                         ORDER BY seen_at DESC",
+                        INSTANCE_COLUMNS
+                    ),
+                    &[
+                        SqlParam::Int(change.contract_version),
+                        SqlParam::Int(window.as_secs() as i32),
+                    ],
+                )
+                .await?;
+            if !rows.is_empty() {
+                return Err(OldInstancesRunning {
+                    contract_version: change.contract_version,
+                    instances: rows.iter().map(AppInstance::from_row).collect(),
+                }
+                .into());
+            }
+        }
+
+        for statement in ddl {
+            self.execute_on(&mut *tx, statement, &[]).await?;
+        }
+        self.execute_on(
+            &mut *tx,
+            "INSERT INTO fhir.schema_change
+                 (name, table_name, old_column, new_column, phase, contract_version)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (name) DO UPDATE SET
+                 phase = EXCLUDED.phase,
+                 contract_version = EXCLUDED.contract_version,
+                 updated_at = NOW()",
+            &[
+                SqlParam::text(&change.name),
+                SqlParam::text(&change.table),
+                SqlParam::text(&change.old_column),
+                SqlParam::text(&change.new_column),
+                SqlParam::text(&change.phase),
+                SqlParam::Int(change.contract_version),
+            ],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Run a statement generated by [`crate::migrations`]; returns the rows
+    /// it changed
+    pub async fn execute_statement(&self, sql: &str) -> Result<u64> {
+        Ok(self.execute(sql, &[]).await?.rows_affected())
+    }
+
+    /// Run a generated count query whose single column is `pending`
+    pub async fn count_pending(&self, sql: &str) -> Result<i64> {
+        Ok(self.fetch_one(sql, &[]).await?.get("pending"))
+    }
+}
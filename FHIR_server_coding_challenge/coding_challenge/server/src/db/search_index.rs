@@ -0,0 +1,207 @@
+//! Values of custom search parameters.
+//!
+//! A SearchParameter added through `POST /admin/search-parameters` is
+//! stored like any conformance resource, and each of its bases gets a row
+//! in `fhir.search_parameter_path` (migration
+//! `017_custom_search_parameters.sql`) with the SQL/JSON path of its
+//! expression. What the path selects in a resource is extracted into
+//! `fhir.resource_search_value`: for the existing resources when the
+//! parameter is added, and again on every write of a resource. Searches of
+//! the parameter look the values up there, so they use an index instead of
+//! evaluating the expression against every candidate.
+
+use super::audit::SqlParam;
+use super::resource::StoredResource;
+use super::Database;
+use crate::search::{Prefix, SearchParamDefinition, SearchParamType};
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::{PgConnection, Row};
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// Extracts the values of the parameters in `p` from the resources in `r`
+/// that pass `{filter}`: codes (of Codings, Identifiers and plain codes)
+/// with their system, strings and dates as written. Strings are normalized
+/// afterwards by [`Database::normalize_search_values_on`]
+const EXTRACT_VALUES: &str = "INSERT INTO fhir.resource_search_value (resource_id, search_parameter_id, system, value)
+     SELECT r.id, p.search_parameter_id, t.system, t.value
+     FROM fhir_resources r
+     JOIN fhir.search_parameter_path p ON p.resource_type = r.resource_type
+     CROSS JOIN LATERAL jsonb_path_query(r.resource_data, p.path::jsonpath) v
+     CROSS JOIN LATERAL (
+         SELECT c->>'system',
+                COALESCE(c->>'code', c->>'value',
+                         CASE WHEN jsonb_typeof(c) IN ('string', 'number', 'boolean') THEN c #>> '{}' END)
+         FROM jsonb_array_elements(
+             CASE WHEN jsonb_typeof(v) = 'object' AND v ? 'coding'
+                  THEN v->'coding' ELSE jsonb_build_array(v) END) c
+         WHERE p.param_type = 'token'
+         UNION ALL
+         SELECT NULL, v #>> '{}' WHERE p.param_type = 'string' AND jsonb_typeof(v) = 'string'
+         UNION ALL
+         SELECT NULL, v #>> '{}' WHERE p.param_type = 'date' AND jsonb_typeof(v) = 'string'
+     ) t(system, value)
+     WHERE t.value IS NOT NULL AND r.deleted_at IS NULL AND {filter}";
+
+impl Database {
+    /// Whether `fhir.resource_search_value` exists, checked at startup
+    pub fn search_index_enabled(&self) -> bool {
+        self.search_index_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Store the SearchParameter `resource` with its `definitions` (see
+    /// [`crate::search::expression::definitions`]) and extract their values
+    /// from the existing resources; returns the stored resource and how many
+    /// values were extracted
+    pub async fn add_search_parameter(
+        &self,
+        resource: Value,
+        definitions: &[SearchParamDefinition],
+    ) -> Result<(StoredResource, u64)> {
+        let mut tx = self.pool.begin().await?;
+        let stored = self
+            .create_resource_on(&mut tx, "SearchParameter", Uuid::new_v4(), resource)
+            .await?;
+        for definition in definitions {
+            self.execute_on(
+                &mut *tx,
+                "INSERT INTO fhir.search_parameter_path (search_parameter_id, resource_type, code, param_type, path)
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    SqlParam::Uuid(stored.id),
+                    SqlParam::text(definition.base[0].as_str()),
+                    SqlParam::text(definition.code.as_str()),
+                    SqlParam::text(definition.param_type.as_str()),
+                    SqlParam::text(definition.path.as_deref().unwrap_or_default()),
+                ],
+            )
+            .await?;
+        }
+        let extracted = self
+            .execute_on(
+                &mut *tx,
+                &EXTRACT_VALUES.replace("{filter}", "p.search_parameter_id = $1"),
+                &[SqlParam::Uuid(stored.id)],
+            )
+            .await?
+            .rows_affected();
+        self.normalize_search_values_on(
+            &mut tx,
+            "s.search_parameter_id = $1",
+            SqlParam::Uuid(stored.id),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok((stored, extracted))
+    }
+
+    /// The parameters added through [`Database::add_search_parameter`],
+    /// to register at startup
+    pub async fn custom_search_params(&self) -> Result<Vec<SearchParamDefinition>> {
+        if !self.search_index_enabled() {
+            return Ok(Vec::new());
+        }
+        let rows = self
+            .fetch_all(
+                "SELECT p.search_parameter_id, p.resource_type, p.code, p.param_type, p.path, r.resource_data
+                 FROM fhir.search_parameter_path p
+                 JOIN fhir_resources r ON r.id = p.search_parameter_id
+                 ORDER BY p.resource_type, p.code",
+                &[],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let id: Uuid = row.get("search_parameter_id");
+                let resource_type: String = row.get("resource_type");
+                let param_type: String = row.get("param_type");
+                let resource: Value = row.get("resource_data");
+                let text = |name: &str| {
+                    resource
+                        .get(name)
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let mut param = SearchParamDefinition::new(
+                    id.to_string(),
+                    text("url"),
+                    row.get::<String, _>("code"),
+                    &[resource_type.as_str()],
+                    match param_type.as_str() {
+                        "date" => SearchParamType::Date,
+                        "string" => SearchParamType::String,
+                        _ => SearchParamType::Token,
+                    },
+                    text("expression"),
+                    text("description"),
+                )
+                .with_path(row.get::<String, _>("path"))
+                .indexed();
+                if param.param_type == SearchParamType::Date {
+                    param = param.with_comparators(Prefix::ALL);
+                }
+                param
+            })
+            .collect())
+    }
+
+    /// Re-extract the custom parameter values of the resource with id `id`
+    /// after a write; a deleted resource keeps none
+    pub(crate) async fn sync_search_values_on(
+        &self,
+        conn: &mut PgConnection,
+        id: Uuid,
+    ) -> Result<()> {
+        if !self.search_index_enabled() {
+            return Ok(());
+        }
+        self.execute_on(
+            &mut *conn,
+            "DELETE FROM fhir.resource_search_value WHERE resource_id = $1",
+            &[SqlParam::Uuid(id)],
+        )
+        .await?;
+        self.execute_on(
+            &mut *conn,
+            &EXTRACT_VALUES.replace("{filter}", "r.id = $1"),
+            &[SqlParam::Uuid(id)],
+        )
+        .await?;
+        self.normalize_search_values_on(conn, "s.resource_id = $1", SqlParam::Uuid(id))
+            .await?;
+        Ok(())
+    }
+
+    /// Extract the values of the resources `ids`, written in this
+    /// transaction and not indexed yet
+    pub(crate) async fn extract_search_values_on(
+        &self,
+        conn: &mut PgConnection,
+        ids: &[Uuid],
+    ) -> Result<()> {
+        if !self.search_index_enabled() || ids.is_empty() {
+            return Ok(());
+        }
+        self.execute_on(
+            &mut *conn,
+            &EXTRACT_VALUES.replace(
+                "{filter}",
+                "r.id IN (SELECT value::uuid FROM jsonb_array_elements_text($1))",
+            ),
+            &[SqlParam::Json(serde_json::json!(ids))],
+        )
+        .await?;
+        self.normalize_search_values_on(
+            conn,
+            "s.resource_id IN (SELECT value::uuid FROM jsonb_array_elements_text($1))",
+            SqlParam::Json(serde_json::json!(ids)),
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! The `fhir.*` settings of the extension, as the database session sees
+//! them.
+//!
+//! The pgrx extension registers `fhir.strict_validation`,
+//! `fhir.history_enabled` and `fhir.max_resource_kb` when it loads, for its
+//! own functions. A DBA sets them per database, per role or per session
+//! (`ALTER DATABASE fhir SET fhir.history_enabled = off`), and only a
+//! superuser may change `fhir.history_enabled`; they are readable
+//! without the extension too, as placeholders, so the server honors
+//! `fhir.history_enabled` for the history it records itself whether or not
+//! the extension is installed. [`Database::extension_settings`] reports
+//! them for `GET /admin/about`.
+
+use super::Database;
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::Row;
+
+/// Whether the session records history: `fhir.history_enabled`, on unless
+/// set otherwise. A condition for the statements that record it.
+pub(super) const HISTORY_RECORDED: &str =
+    "COALESCE(NULLIF(current_setting('fhir.history_enabled', true), ''), 'on')::bool";
+
+/// The settings of a session; `None` for the ones neither set nor defined
+/// by a loaded extension
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionSettings {
+    pub strict_validation: Option<bool>,
+    pub history_enabled: Option<bool>,
+    pub max_resource_kb: Option<i32>,
+}
+
+/// A boolean setting as Postgres reads one
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" | "t" | "y" => Some(true),
+        "off" | "false" | "no" | "0" | "f" | "n" => Some(false),
+        _ => None,
+    }
+}
+
+impl Database {
+    /// The `fhir.*` settings of a pooled session
+    pub async fn extension_settings(&self) -> Result<ExtensionSettings> {
+        let row = self
+            .fetch_one(
+                "SELECT current_setting('fhir.strict_validation', true) AS strict_validation,
+                        current_setting('fhir.history_enabled', true) AS history_enabled,
+                        current_setting('fhir.max_resource_kb', true) AS max_resource_kb",
+                &[],
+            )
+            .await?;
+        let setting = |name: &str| {
+            row.get::<Option<String>, _>(name)
+                .filter(|value| !value.is_empty())
+        };
+        Ok(ExtensionSettings {
+            strict_validation: setting("strict_validation").as_deref().and_then(parse_bool),
+            history_enabled: setting("history_enabled").as_deref().and_then(parse_bool),
+            max_resource_kb: setting("max_resource_kb").and_then(|value| value.trim().parse().ok()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    #[test]
+    fn test_parse_bool() {
+        assert_eq!(parse_bool("on"), Some(true));
+        assert_eq!(parse_bool("OFF"), Some(false));
+        assert_eq!(parse_bool("0"), Some(false));
+        assert_eq!(parse_bool("maybe"), None);
+    }
+
+    async fn recorded(conn: &mut sqlx::PgConnection) -> bool {
+        sqlx::query(&format!("SELECT {} AS recorded", HISTORY_RECORDED))
+            .fetch_one(conn)
+            .await
+            .unwrap()
+            .get("recorded")
+    }
+
+    #[tokio::test]
+    async fn test_history_follows_the_session_setting() {
+        let db = setup_test_db().await;
+        let mut conn = db.pool.acquire().await.unwrap();
+        assert!(recorded(&mut conn).await);
+
+        sqlx::query("SET fhir.history_enabled = off")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        assert!(!recorded(&mut conn).await);
+        sqlx::query("RESET fhir.history_enabled")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+    }
+}
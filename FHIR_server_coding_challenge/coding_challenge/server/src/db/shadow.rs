@@ -0,0 +1,258 @@
+//! Shadow verification of the extension storage path.
+//!
+//! Patient writes go straight to `fhir_resources` with SQL. With
+//! `shadow_verify` set, every Patient create and update is also replayed
+//! through the extension functions (`fhir.fhir_put`, then `fhir.fhir_get`)
+//! in a savepoint of the write's transaction, and the resource the
+//! extension hands back is compared with the one written by SQL. The
+//! savepoint is always rolled back, so the extension path never changes
+//! what is stored and a failing extension never fails the write.
+//! Discrepancies are logged at WARN on the `fhir_server::shadow` target and
+//! counted in `/metrics`.
+
+use super::audit::SqlParam;
+use super::Database;
+use serde_json::Value;
+use sqlx::{Connection, PgConnection, Row};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use uuid::Uuid;
+
+pub const SHADOW_TARGET: &str = "fhir_server::shadow";
+
+/// At most this many differing paths are logged per write
+const MAX_REPORTED_PATHS: usize = 10;
+
+/// Switch and tallies of shadow verification for a `Database`
+#[derive(Debug, Default)]
+pub struct ShadowVerifier {
+    enabled: AtomicBool,
+    matches: AtomicU64,
+    mismatches: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Verifications since startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShadowCounts {
+    pub matches: u64,
+    pub mismatches: u64,
+    /// Replays that could not be completed, e.g. a missing extension function
+    pub errors: u64,
+}
+
+impl ShadowVerifier {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn counts(&self) -> ShadowCounts {
+        ShadowCounts {
+            matches: self.matches.load(Ordering::Relaxed),
+            mismatches: self.mismatches.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// JSON pointers at which `actual` differs from `expected`
+pub fn differences(expected: &Value, actual: &Value) -> Vec<String> {
+    let mut paths = Vec::new();
+    collect_differences(expected, actual, String::new(), &mut paths);
+    paths
+}
+
+fn collect_differences(expected: &Value, actual: &Value, path: String, paths: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let mut keys: Vec<&String> = expected.keys().chain(actual.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                match (expected.get(key), actual.get(key)) {
+                    (Some(e), Some(a)) => collect_differences(e, a, child, paths),
+                    _ => paths.push(child),
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) if expected.len() == actual.len() => {
+            for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+                collect_differences(e, a, format!("{}/{}", path, i), paths);
+            }
+        }
+        (expected, actual) if expected == actual => {}
+        _ => paths.push(if path.is_empty() {
+            "/".to_string()
+        } else {
+            path
+        }),
+    }
+}
+
+/// The content both paths are expected to agree on: the extension assigns
+/// `id` and `resourceType` itself
+fn comparable(mut resource: Value) -> Value {
+    if let Some(map) = resource.as_object_mut() {
+        map.remove("id");
+        map.remove("resourceType");
+    }
+    resource
+}
+
+impl Database {
+    /// Also verify Patient writes through the extension functions
+    pub fn set_shadow_verify(&self, enabled: bool) {
+        self.shadow.set_enabled(enabled);
+    }
+
+    pub fn shadow_counts(&self) -> ShadowCounts {
+        self.shadow.counts()
+    }
+
+    /// Replay the write of `id` with `written` content through the
+    /// extension on `conn` and compare; a no-op unless enabled
+    pub(crate) async fn shadow_verify_on(
+        &self,
+        conn: &mut PgConnection,
+        id: Uuid,
+        written: &Value,
+    ) {
+        if !self.shadow.enabled() {
+            return;
+        }
+
+        let replayed = async {
+            let mut savepoint = conn.begin().await?;
+            let mut resource = written.clone();
+            if let Some(map) = resource.as_object_mut() {
+                map.insert("id".to_string(), Value::String(id.to_string()));
+            }
+            let row = self
+                .fetch_one_on(
+                    &mut *savepoint,
+                    "SELECT fhir.fhir_put('Patient', $1) AS id",
+                    &[SqlParam::Json(resource)],
+                )
+                .await?;
+            let put_id: Uuid = row.get("id");
+            let row = self
+                .fetch_one_on(
+                    &mut *savepoint,
+                    "SELECT fhir.fhir_get('Patient', $1) AS resource",
+                    &[SqlParam::Uuid(put_id)],
+                )
+                .await?;
+            let stored: Option<Value> = row.get("resource");
+            savepoint.rollback().await?;
+            anyhow::Ok((put_id, stored))
+        };
+
+        match replayed.await {
+            Ok((put_id, Some(stored))) => {
+                let paths = differences(&comparable(written.clone()), &comparable(stored));
+                if paths.is_empty() {
+                    self.shadow.matches.fetch_add(1, Ordering::Relaxed);
+                    tracing::debug!(target: SHADOW_TARGET, patient_id = %id, "extension path agrees");
+                } else {
+                    self.shadow.mismatches.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        target: SHADOW_TARGET,
+                        patient_id = %id,
+                        extension_id = %put_id,
+                        differing = paths.len(),
+                        "extension path disagrees at {}",
+                        paths[..paths.len().min(MAX_REPORTED_PATHS)].join(", ")
+                    );
+                }
+            }
+            Ok((put_id, None)) => {
+                self.shadow.mismatches.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    target: SHADOW_TARGET,
+                    patient_id = %id,
+                    extension_id = %put_id,
+                    "extension path did not return the resource it stored"
+                );
+            }
+            Err(e) => {
+                self.shadow.errors.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(target: SHADOW_TARGET, patient_id = %id, "extension path failed: {:#}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+    use serde_json::json;
+
+    #[test]
+    fn test_differences_name_json_pointers() {
+        let expected = json!({
+            "name": [{ "family": "Berg", "given": ["Anna"] }],
+            "gender": "female",
+            "a/b": 1
+        });
+        assert!(differences(&expected, &expected.clone()).is_empty());
+
+        let actual = json!({
+            "name": [{ "family": "Berg", "given": ["Anne"] }],
+            "birthDate": "1980-01-01",
+            "a/b": 1.5
+        });
+        assert_eq!(
+            differences(&expected, &actual),
+            vec!["/a~1b", "/birthDate", "/gender", "/name/0/given/0"]
+        );
+        assert_eq!(differences(&json!([1]), &json!([1, 2])), vec!["/"]);
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_replayed_without_side_effects() {
+        let db = setup_test_db().await;
+        let mut patient: Patient = serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "name": [{ "family": "Shadow", "given": ["Sam"] }],
+            "gender": "other"
+        }))
+        .unwrap();
+
+        // Off by default
+        let created = db.create_patient(patient.clone()).await.unwrap();
+        assert_eq!(db.shadow_counts(), ShadowCounts::default());
+
+        db.set_shadow_verify(true);
+        patient.gender = Some("unknown".to_string());
+        let id = created.id.unwrap();
+        db.update_patient(&id, patient.clone()).await.unwrap();
+        let created = db.create_patient(patient).await.unwrap();
+        let counts = db.shadow_counts();
+        assert_eq!(
+            counts,
+            ShadowCounts {
+                matches: 2,
+                mismatches: 0,
+                errors: 0
+            }
+        );
+
+        // The replay is rolled back: the extension table holds neither write
+        for id in [id, created.id.unwrap()] {
+            let row = db
+                .fetch_optional(
+                    "SELECT 1 FROM fhir.patient WHERE id = $1",
+                    &[SqlParam::Uuid(id.parse().unwrap())],
+                )
+                .await
+                .unwrap();
+            assert!(row.is_none());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Named snapshots of the dataset, for resetting test environments.
+//!
+//! A snapshot copies every row of the data tables into `fhir.snapshot_row`
+//! (migration `018_snapshots.sql`) in one repeatable-read transaction, so it
+//! is consistent even while clients write. Restoring replaces the contents
+//! of the same tables with the copy in one transaction; readers see the old
+//! dataset or the new one, never a mix. The change feed records the restore
+//! as a delete of every resource followed by a create of every restored one.
+
+use super::audit::SqlParam;
+use super::Database;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, Row};
+use std::sync::atomic::Ordering;
+
+/// The data tables, parents first, with the columns kept; generated columns
+/// are recomputed on restore
+const TABLES: &[(&str, &str)] = &[
+    (
+        "fhir_resources",
+        "id, resource_type, resource_data, version_id, last_updated, created_at, deleted_at, content_hash",
+    ),
+    (
+        "fhir.resource_archive",
+        "id, resource_type, resource_data, version_id, last_updated, created_at, deleted_at, content_hash, archived_at",
+    ),
+    (
+        "fhir.patient_history",
+        "id, version_id, resource, txid, ts, status",
+    ),
+    (
+        "fhir.resource_identifier",
+        "resource_id, resource_type, system, value, enforce_unique",
+    ),
+    (
+        "fhir.resource_name_phonetic",
+        "resource_id, resource_type, algorithm, code",
+    ),
+    (
+        "fhir.resource_string_value",
+        "resource_id, resource_type, code, value",
+    ),
+    (
+        "fhir.search_parameter_path",
+        "search_parameter_id, resource_type, code, param_type, path",
+    ),
+    (
+        "fhir.resource_search_value",
+        "resource_id, search_parameter_id, system, value",
+    ),
+    (
+        "fhir.id_reservation",
+        "id, resource_type, reserved_at, expires_at",
+    ),
+];
+
+/// Snapshot names are limited to these characters, up to 64 of them
+pub fn is_valid_snapshot_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// A stored snapshot
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// Resources it holds, live, deleted and archived
+    pub resources: i64,
+}
+
+impl Snapshot {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Self {
+        Self {
+            name: row.get("name"),
+            created_at: row.get("created_at"),
+            resources: row.get("resources"),
+        }
+    }
+}
+
+impl Database {
+    /// Whether `fhir.snapshot` exists, checked at startup
+    pub fn snapshots_enabled(&self) -> bool {
+        self.snapshots_enabled.load(Ordering::Relaxed)
+    }
+
+    /// The data tables of the components this schema has
+    fn snapshot_tables(&self) -> impl Iterator<Item = &'static (&'static str, &'static str)> + '_ {
+        TABLES.iter().filter(|(table, _)| match *table {
+            "fhir.resource_archive" => self.archive_enabled(),
+            "fhir.patient_history" => self.history_enabled(),
+            "fhir.resource_identifier" => self.identifier_index_enabled.load(Ordering::Relaxed),
+            "fhir.resource_name_phonetic" => self.phonetic_index_enabled(),
+            "fhir.resource_string_value" => self.string_index_enabled(),
+            "fhir.search_parameter_path" | "fhir.resource_search_value" => {
+                self.search_index_enabled()
+            }
+            "fhir.id_reservation" => self.reservations_enabled(),
+            _ => true,
+        })
+    }
+
+    pub async fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        let rows = self
+            .fetch_all(
+                "SELECT name, created_at, resources FROM fhir.snapshot ORDER BY created_at, name",
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(Snapshot::from_row).collect())
+    }
+
+    /// Copy the dataset into a new snapshot `name`; `None` if the name is
+    /// taken
+    pub async fn create_snapshot(&self, name: &str) -> Result<Option<Snapshot>> {
+        let mut tx = self.pool.begin().await?;
+        self.execute_on(
+            &mut *tx,
+            "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ",
+            &[],
+        )
+        .await?;
+        let created = self
+            .fetch_optional_on(
+                &mut *tx,
+                "INSERT INTO fhir.snapshot (name, resources) VALUES ($1, 0)
+                 ON CONFLICT (name) DO NOTHING
+                 RETURNING name",
+                &[SqlParam::text(name)],
+            )
+            .await?;
+        if created.is_none() {
+            return Ok(None);
+        }
+
+        for (table, columns) in self.snapshot_tables() {
+            self.execute_on(
+                &mut *tx,
+                &format!(
+                    "INSERT INTO fhir.snapshot_row (snapshot, source, row_data)
+                     SELECT $1, $2, to_jsonb(t) FROM (SELECT {} FROM {}) t",
+                    columns, table
+                ),
+                &[SqlParam::text(name), SqlParam::text(*table)],
+            )
+            .await?;
+        }
+        let row = self
+            .fetch_one_on(
+                &mut *tx,
+                "UPDATE fhir.snapshot
+                 SET resources = (SELECT COUNT(*) FROM fhir.snapshot_row
+                                  WHERE snapshot = $1
+                                    AND source IN ('fhir_resources', 'fhir.resource_archive'))
+                 WHERE name = $1
+                 RETURNING name, created_at, resources",
+                &[SqlParam::text(name)],
+            )
+            .await?;
+        tx.commit().await?;
+
+        Ok(Some(Snapshot::from_row(&row)))
+    }
+
+    /// Replace the dataset with snapshot `name`; `None` if there is none
+    pub async fn restore_snapshot(&self, name: &str) -> Result<Option<Snapshot>> {
+        let mut tx = self.pool.begin().await?;
+        let snapshot = self
+            .fetch_optional_on(
+                &mut *tx,
+                "SELECT name, created_at, resources FROM fhir.snapshot WHERE name = $1 FOR SHARE",
+                &[SqlParam::text(name)],
+            )
+            .await?;
+        let Some(snapshot) = snapshot.as_ref().map(Snapshot::from_row) else {
+            return Ok(None);
+        };
+
+        let tables: Vec<_> = self.snapshot_tables().collect();
+        for (table, _) in tables.iter().rev() {
+            self.execute_on(&mut *tx, &format!("DELETE FROM {}", table), &[])
+                .await?;
+        }
+        for (table, columns) in &tables {
+            self.restore_table_on(&mut tx, name, table, columns).await?;
+        }
+        tx.commit().await?;
+
+        Ok(Some(snapshot))
+    }
+
+    async fn restore_table_on(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+        table: &str,
+        columns: &str,
+    ) -> Result<()> {
+        let selected = columns
+            .split(", ")
+            .map(|column| format!("r.{}", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.execute_on(
+            &mut *conn,
+            &format!(
+                "INSERT INTO {table} ({columns})
+                 SELECT {selected}
+                 FROM fhir.snapshot_row s
+                 CROSS JOIN LATERAL jsonb_populate_record(NULL::{table}, s.row_data) r
+                 WHERE s.snapshot = $1 AND s.source = $2",
+            ),
+            &[SqlParam::text(name), SqlParam::text(table)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Delete snapshot `name`; whether there was one
+    pub async fn delete_snapshot(&self, name: &str) -> Result<bool> {
+        let result = self
+            .execute(
+                "DELETE FROM fhir.snapshot WHERE name = $1",
+                &[SqlParam::text(name)],
+            )
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_names() {
+        assert!(is_valid_snapshot_name("before-suite_2.1"));
+        assert!(!is_valid_snapshot_name(""));
+        assert!(!is_valid_snapshot_name("../etc"));
+        assert!(!is_valid_snapshot_name("with space"));
+        assert!(!is_valid_snapshot_name(&"a".repeat(65)));
+    }
+}
@@ -0,0 +1,229 @@
+//! Normalized string search values.
+//!
+//! String searches ignore case and accents. Rather than lowercasing every
+//! candidate per query, the values a string parameter compares are stored
+//! normalized ([`normalize`]) when a resource is written and searches
+//! normalize their input the same way:
+//!
+//! - the `name` of a Patient (its first family and first given name) in
+//!   `fhir.resource_string_value` (migration `025_normalized_strings.sql`)
+//! - custom string parameters in `fhir.resource_search_value`, which the SQL
+//!   extraction fills with the values as written and
+//!   [`Database::normalize_search_values_on`] then normalizes
+//!
+//! The `fhir_extension` functions read the same table with the same
+//! normalization, so both storage strategies find the same patients.
+
+use super::audit::SqlParam;
+use super::Database;
+use crate::models::search::normalize;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::{PgConnection, Row};
+use std::collections::BTreeSet;
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// `code` of the Patient name values
+pub const NAME_CODE: &str = "name";
+
+/// Custom string values that may not be normalized: those with an ASCII
+/// capital or any other than ASCII character, of the rows passing `{filter}`
+const UNNORMALIZED_SEARCH_VALUES: &str = "SELECT DISTINCT s.value FROM fhir.resource_search_value s
+     JOIN fhir.search_parameter_path p ON p.search_parameter_id = s.search_parameter_id
+     WHERE p.param_type = 'string' AND s.value ~ '[A-Z]|[^\\x01-\\x7F]' AND {filter}";
+
+/// The normalized first family and first given name of the Patient
+/// `resource`, the values `name` matches
+pub fn patient_name_values(resource: &Value) -> Vec<String> {
+    ["/name/0/family", "/name/0/given/0"]
+        .iter()
+        .filter_map(|pointer| resource.pointer(pointer)?.as_str())
+        .map(normalize)
+        .filter(|value| !value.is_empty())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+impl Database {
+    /// Whether `fhir.resource_string_value` exists, checked at startup
+    pub fn string_index_enabled(&self) -> bool {
+        self.string_index_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Replace the normalized `code` values of a resource
+    pub(crate) async fn sync_string_values_on(
+        &self,
+        conn: &mut PgConnection,
+        resource_id: Uuid,
+        resource_type: &str,
+        code: &str,
+        values: &[String],
+    ) -> Result<()> {
+        if !self.string_index_enabled() {
+            return Ok(());
+        }
+
+        self.execute_on(
+            &mut *conn,
+            "DELETE FROM fhir.resource_string_value WHERE resource_id = $1 AND code = $2",
+            &[SqlParam::Uuid(resource_id), SqlParam::text(code)],
+        )
+        .await?;
+        for value in values {
+            self.execute_on(
+                &mut *conn,
+                "INSERT INTO fhir.resource_string_value (resource_id, resource_type, code, value)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT DO NOTHING",
+                &[
+                    SqlParam::Uuid(resource_id),
+                    SqlParam::text(resource_type),
+                    SqlParam::text(code),
+                    SqlParam::text(value.as_str()),
+                ],
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Index the names of live patients that have no normalized name yet,
+    /// i.e. those written before migration 025; returns how many were indexed
+    pub async fn backfill_name_values(&self) -> Result<u64> {
+        if !self.string_index_enabled() {
+            return Ok(0);
+        }
+
+        let rows = self
+            .fetch_all(
+                "SELECT r.id, r.resource_data FROM fhir_resources r
+                 WHERE r.resource_type = 'Patient' AND r.deleted_at IS NULL
+                   AND r.resource_data ? 'name'
+                   AND NOT EXISTS (SELECT 1 FROM fhir.resource_string_value s
+                                   WHERE s.resource_id = r.id AND s.code = 'name')",
+                &[],
+            )
+            .await?;
+
+        let mut conn = self.pool.acquire().await?;
+        let mut indexed = 0;
+        for row in rows {
+            let values = patient_name_values(&row.get::<Value, _>("resource_data"));
+            if values.is_empty() {
+                continue;
+            }
+            self.sync_string_values_on(&mut conn, row.get("id"), "Patient", NAME_CODE, &values)
+                .await?;
+            indexed += 1;
+        }
+
+        Ok(indexed)
+    }
+
+    /// Normalize the custom string values passing `filter`, a condition on
+    /// `s` (`fhir.resource_search_value`) with `param` as `$1`; returns how
+    /// many distinct values changed
+    pub(crate) async fn normalize_search_values_on(
+        &self,
+        conn: &mut PgConnection,
+        filter: &str,
+        param: SqlParam,
+    ) -> Result<u64> {
+        let rows = self
+            .fetch_all_on(
+                &mut *conn,
+                &UNNORMALIZED_SEARCH_VALUES.replace("{filter}", filter),
+                std::slice::from_ref(&param),
+            )
+            .await?;
+
+        let mut changed = 0;
+        for row in rows {
+            let value: String = row.get("value");
+            let normalized = normalize(&value);
+            if normalized == value {
+                continue;
+            }
+            self.execute_on(
+                &mut *conn,
+                &format!(
+                    "UPDATE fhir.resource_search_value s SET value = $2
+                     WHERE s.value = $3 AND {} AND s.search_parameter_id IN (
+                         SELECT search_parameter_id FROM fhir.search_parameter_path
+                         WHERE param_type = 'string')",
+                    filter
+                ),
+                &[
+                    param.clone(),
+                    SqlParam::text(normalized),
+                    SqlParam::text(value),
+                ],
+            )
+            .await?;
+            changed += 1;
+        }
+
+        Ok(changed)
+    }
+
+    /// Normalize the custom string values extracted before migration 025
+    /// lowercased rather than normalized them; returns how many distinct
+    /// values changed
+    pub async fn backfill_search_values(&self) -> Result<u64> {
+        if !self.search_index_enabled() {
+            return Ok(0);
+        }
+        let mut conn = self.pool.acquire().await?;
+        self.normalize_search_values_on(&mut conn, "$1", SqlParam::Bool(true))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::db::PageStart;
+    use crate::models::Patient;
+    use serde_json::json;
+
+    #[test]
+    fn test_patient_name_values() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "name": [
+                { "family": "Müller", "given": ["José", "Maria"] },
+                { "family": "Miller" },
+            ],
+        });
+        assert_eq!(patient_name_values(&patient), vec!["jose", "muller"]);
+        assert!(patient_name_values(&json!({ "resourceType": "Patient" })).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_name_search_ignores_case_and_accents() {
+        let db = setup_test_db().await;
+        let family = format!("Müller-{}", Uuid::new_v4().simple());
+        let patient: Patient = serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "name": [{ "family": family, "given": ["Zoë"] }],
+        }))
+        .unwrap();
+        let created = db.create_patient(patient).await.unwrap();
+
+        for query in [family.to_uppercase(), normalize(&family), "zoe".to_string()] {
+            let found = db
+                .search_patients(Some(&query), None, None, None, 10, PageStart::Offset(0))
+                .await
+                .unwrap();
+            assert!(
+                found.iter().any(|p| p.id == created.id),
+                "{} did not find the patient",
+                query
+            );
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! The data of one tenant, for off-boarding exports.
+//!
+//! The server keeps every customer in one store; a tenant is an
+//! Organization and what belongs to it:
+//!
+//! - the Organization itself
+//! - resources naming it as `managingOrganization` or `organization`, such
+//!   as its Patients and PractitionerRoles
+//! - resources whose `subject` or `patient` is one of those Patients
+//!
+//! Archived resources count as well. [`Database::tenant_data`] reads the
+//! resources, the history of the tenant's Patients (deleted ones included)
+//! and the Provenance of all of them in one repeatable-read transaction;
+//! [`crate::tenant_export`] packs them into an encrypted archive.
+
+use super::audit::SqlParam;
+use super::resource::{StoredResource, RESOURCE_COLUMNS};
+use super::Database;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::Row;
+use uuid::Uuid;
+
+/// The resources of the tenant as `members`, deleted ones included, from
+/// the rows of `{source}`; `$1` is the Organization's id and `$2` its
+/// reference
+const TENANT_MEMBERS: &str = "WITH owned AS (
+         SELECT r.* FROM {source} r
+         WHERE r.resource_type <> 'Provenance'
+           AND ((r.resource_type = 'Organization' AND r.id = $1)
+                OR r.resource_data->'managingOrganization'->>'reference' = $2
+                OR r.resource_data->'organization'->>'reference' = $2)
+     ),
+     patients AS (
+         SELECT 'Patient/' || id AS reference FROM owned WHERE resource_type = 'Patient'
+     ),
+     members AS (
+         SELECT * FROM owned
+         UNION
+         SELECT r.* FROM {source} r
+         WHERE r.resource_type <> 'Provenance'
+           AND (r.resource_data->'subject'->>'reference' IN (SELECT reference FROM patients)
+                OR r.resource_data->'patient'->>'reference' IN (SELECT reference FROM patients))
+     )";
+
+/// Everything stored for one tenant
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenantData {
+    /// The current version of every live resource, as served
+    pub resources: Vec<Value>,
+    /// Every stored version of the tenant's Patients, oldest first
+    pub history: Vec<Value>,
+    /// The Provenance of the writes to any of them
+    pub audit: Vec<Value>,
+}
+
+impl Database {
+    /// The rows the tenant's resources are taken from
+    fn tenant_source(&self) -> String {
+        let columns = format!("{}, deleted_at", RESOURCE_COLUMNS);
+        if self.archive_enabled() {
+            format!(
+                "(SELECT {0} FROM fhir_resources UNION ALL SELECT {0} FROM fhir.resource_archive)",
+                columns
+            )
+        } else {
+            format!("(SELECT {} FROM fhir_resources)", columns)
+        }
+    }
+
+    /// The data of the tenant of Organization `organization`; `None` if no
+    /// such Organization is stored
+    pub async fn tenant_data(&self, organization: Uuid) -> Result<Option<TenantData>> {
+        let mut tx = self.pool.begin().await?;
+        self.execute_on(
+            &mut *tx,
+            "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY",
+            &[],
+        )
+        .await?;
+        let exists = self
+            .fetch_optional_on(
+                &mut *tx,
+                "SELECT 1 AS found FROM fhir_resources
+                 WHERE resource_type = 'Organization' AND id = $1 AND deleted_at IS NULL",
+                &[SqlParam::Uuid(organization)],
+            )
+            .await?;
+        if exists.is_none() {
+            return Ok(None);
+        }
+
+        let members = TENANT_MEMBERS.replace("{source}", &self.tenant_source());
+        let params = [
+            SqlParam::Uuid(organization),
+            SqlParam::text(format!("Organization/{}", organization)),
+        ];
+        let resources = self
+            .fetch_all_on(
+                &mut *tx,
+                &format!(
+                    "{} SELECT {} FROM members WHERE deleted_at IS NULL ORDER BY resource_type, id",
+                    members, RESOURCE_COLUMNS
+                ),
+                &params,
+            )
+            .await?;
+        let history = if self.history_enabled() {
+            self.fetch_all_on(
+                &mut *tx,
+                &format!(
+                    "{} SELECT h.resource FROM fhir.patient_history h
+                     WHERE h.id IN (SELECT id FROM members WHERE resource_type = 'Patient')
+                     ORDER BY h.id, h.version_id",
+                    members
+                ),
+                &params,
+            )
+            .await?
+        } else {
+            Vec::new()
+        };
+        let audit = self
+            .fetch_all_on(
+                &mut *tx,
+                &format!(
+                    "{} SELECT {} FROM fhir_resources p
+                     WHERE p.resource_type = 'Provenance' AND p.deleted_at IS NULL
+                       AND split_part(p.resource_data->'target'->0->>'reference', '/_history/', 1)
+                           IN (SELECT resource_type || '/' || id FROM members)
+                     ORDER BY p.last_updated, p.id",
+                    members, RESOURCE_COLUMNS
+                ),
+                &params,
+            )
+            .await?;
+        tx.commit().await?;
+
+        let served = |rows: Vec<sqlx::postgres::PgRow>| -> Vec<Value> {
+            rows.iter()
+                .map(|row| StoredResource::from_row(row).into_resource())
+                .collect()
+        };
+        Ok(Some(TenantData {
+            resources: served(resources),
+            history: history.iter().map(|row| row.get("resource")).collect(),
+            audit: served(audit),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_tenant_data_stays_within_the_organization() {
+        let db = setup_test_db().await;
+        let create = |resource: Value| {
+            let db = &db;
+            async move {
+                let resource_type = resource["resourceType"].as_str().unwrap().to_string();
+                db.create_resource(&resource_type, resource).await.unwrap()
+            }
+        };
+        let organization = create(json!({ "resourceType": "Organization", "name": "North" })).await;
+        let other = create(json!({ "resourceType": "Organization", "name": "South" })).await;
+        let patient = |org: &StoredResource| {
+            json!({
+                "resourceType": "Patient",
+                "managingOrganization": { "reference": format!("Organization/{}", org.id) },
+            })
+        };
+        let ours = create(patient(&organization)).await;
+        let theirs = create(patient(&other)).await;
+        for subject in [&ours, &theirs] {
+            create(json!({
+                "resourceType": "Observation",
+                "status": "final",
+                "code": { "text": "Heart rate" },
+                "subject": { "reference": format!("Patient/{}", subject.id) },
+            }))
+            .await;
+        }
+
+        let data = db.tenant_data(organization.id).await.unwrap().unwrap();
+        let ids: Vec<&str> = data
+            .resources
+            .iter()
+            .map(|r| r["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(data.resources.len(), 3, "{:?}", ids);
+        assert!(ids.contains(&ours.id.to_string().as_str()));
+        assert!(!ids.contains(&theirs.id.to_string().as_str()));
+        assert!(db.tenant_data(Uuid::new_v4()).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,217 @@
+//! The dataset of `fhir-server --demo`: a handful of patients with a few
+//! vital signs each, for trying the API without an import.
+//!
+//! Patients are upserted by their demo MRN ([`DEMO_MRN_SYSTEM`]), so
+//! starting with `--demo` again leaves the data as it is instead of adding
+//! copies; the Observations of a patient are only written when the patient
+//! is created. Provenance names `fhir-server --demo` as the agent.
+
+use crate::db::{ConditionalWrite, Database};
+use crate::models::{Observation, Patient};
+use crate::provenance::Attribution;
+use crate::search::TokenParam;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+/// Identifier system of the demo patients' MRNs
+pub const DEMO_MRN_SYSTEM: &str = "urn:fhir-server:demo:mrn";
+
+const AGENT: &str = "fhir-server --demo";
+
+/// What [`seed`] wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DemoSeed {
+    pub patients_created: usize,
+    pub patients_updated: usize,
+    pub observations_created: usize,
+}
+
+struct DemoPatient {
+    mrn: &'static str,
+    family: &'static str,
+    given: &'static str,
+    gender: &'static str,
+    birth_date: &'static str,
+    city: &'static str,
+    /// Value of each of [`VITALS`]
+    vitals: [f64; 2],
+}
+
+const PATIENTS: &[DemoPatient] = &[
+    DemoPatient {
+        mrn: "D-1001",
+        family: "Nguyen",
+        given: "Linh",
+        gender: "female",
+        birth_date: "1984-03-12",
+        city: "Portland",
+        vitals: [61.2, 68.0],
+    },
+    DemoPatient {
+        mrn: "D-1002",
+        family: "Okafor",
+        given: "Chidi",
+        gender: "male",
+        birth_date: "1951-11-02",
+        city: "Baltimore",
+        vitals: [84.5, 74.0],
+    },
+    DemoPatient {
+        mrn: "D-1003",
+        family: "García",
+        given: "Lucía",
+        gender: "female",
+        birth_date: "1997-07-23",
+        city: "San Antonio",
+        vitals: [57.9, 81.0],
+    },
+    DemoPatient {
+        mrn: "D-1004",
+        family: "Schmidt",
+        given: "Jonas",
+        gender: "male",
+        birth_date: "2012-01-30",
+        city: "Milwaukee",
+        vitals: [38.4, 92.0],
+    },
+    DemoPatient {
+        mrn: "D-1005",
+        family: "Haddad",
+        given: "Samir",
+        gender: "unknown",
+        birth_date: "1968-09-05",
+        city: "Dearborn",
+        vitals: [92.0, 63.0],
+    },
+];
+
+/// (LOINC code, display, unit, UCUM code) of the vital signs recorded
+const VITALS: [(&str, &str, &str, &str); 2] = [
+    ("29463-7", "Body weight", "kg", "kg"),
+    ("8867-4", "Heart rate", "beats/minute", "/min"),
+];
+
+impl DemoPatient {
+    fn resource(&self) -> Value {
+        json!({
+            "resourceType": "Patient",
+            "identifier": [{ "system": DEMO_MRN_SYSTEM, "value": self.mrn }],
+            "active": true,
+            "name": [{ "use": "official", "family": self.family, "given": [self.given] }],
+            "gender": self.gender,
+            "birthDate": self.birth_date,
+            "address": [{ "use": "home", "city": self.city, "country": "US" }],
+        })
+    }
+
+    /// The vital signs of the patient stored as `patient_id`
+    fn observations(&self, patient_id: &str) -> Vec<Value> {
+        VITALS
+            .iter()
+            .zip(self.vitals)
+            .map(|(&(code, display, unit, ucum), value)| {
+                json!({
+                    "resourceType": "Observation",
+                    "status": "final",
+                    "category": [{
+                        "coding": [{
+                            "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                            "code": "vital-signs",
+                        }],
+                    }],
+                    "code": {
+                        "coding": [{ "system": "http://loinc.org", "code": code, "display": display }],
+                        "text": display,
+                    },
+                    "subject": { "reference": format!("Patient/{}", patient_id) },
+                    "effectiveDateTime": "2024-05-14T09:30:00Z",
+                    "valueQuantity": {
+                        "value": value,
+                        "unit": unit,
+                        "system": "http://unitsofmeasure.org",
+                        "code": ucum,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+/// Write the demo dataset to `db`
+pub async fn seed(db: &Database) -> Result<DemoSeed> {
+    let attribution = Attribution {
+        agent: Some(AGENT.to_string()),
+        request_id: None,
+    };
+    let mut seeded = DemoSeed::default();
+    for demo in PATIENTS {
+        let patient: Patient = serde_json::from_value(demo.resource())?;
+        let token = TokenParam {
+            system: Some(DEMO_MRN_SYSTEM.to_string()),
+            code: Some(demo.mrn.to_string()),
+        };
+        let created = match db
+            .upsert_patient_by_identifier(&attribution, None, &token, patient)
+            .await
+            .with_context(|| format!("Failed to write demo patient {}", demo.mrn))?
+        {
+            ConditionalWrite::Created(created) => created,
+            ConditionalWrite::Updated(_) => {
+                seeded.patients_updated += 1;
+                continue;
+            }
+            ConditionalWrite::MultipleMatches | ConditionalWrite::IdMismatch(_) => {
+                anyhow::bail!("More than one patient has the demo MRN {}", demo.mrn)
+            }
+        };
+        seeded.patients_created += 1;
+
+        for observation in demo.observations(created.id.as_deref().unwrap_or_default()) {
+            let observation: Observation = serde_json::from_value(observation)?;
+            db.create_observation_by(&attribution, observation).await?;
+            seeded.observations_created += 1;
+        }
+    }
+
+    Ok(seeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    #[tokio::test]
+    async fn test_seeding_twice_adds_nothing() {
+        let db = setup_test_db().await;
+
+        let first = seed(&db).await.unwrap();
+        let second = seed(&db).await.unwrap();
+
+        // The test database may already hold the demo patients of another run
+        assert_eq!(
+            first.patients_created + first.patients_updated,
+            PATIENTS.len()
+        );
+        assert_eq!(
+            second,
+            DemoSeed {
+                patients_created: 0,
+                patients_updated: PATIENTS.len(),
+                observations_created: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_demo_resources_parse() {
+        for demo in PATIENTS {
+            let patient: Patient = serde_json::from_value(demo.resource()).unwrap();
+            assert_eq!(patient.gender.as_deref(), Some(demo.gender));
+            for observation in demo.observations("1") {
+                let observation: Observation = serde_json::from_value(observation).unwrap();
+                assert_eq!(observation.code.coding.map(|c| c.len()), Some(1));
+            }
+        }
+    }
+}
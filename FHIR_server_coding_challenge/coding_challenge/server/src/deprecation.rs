@@ -0,0 +1,410 @@
+//! Announcing routes and query parameters that are going away.
+//!
+//! A `[deprecations.<feature>]` entry marks a route, one of
+//! [`crate::routes::ROUTE_ACCESS`], a query parameter, or a parameter on one
+//! route, as deprecated:
+//!
+//! ```toml
+//! [deprecations.offset-paging]
+//! parameter = "_offset"
+//! since = "2026-07-01"
+//! sunset = "2027-01-01"
+//! link = "https://example.org/fhir/migrating#paging"
+//! note = "Page with the next links of the Bundle instead"
+//! ```
+//!
+//! Responses to requests that use the feature carry `Deprecation` (RFC 9745)
+//! and, with a date, `Sunset` (RFC 8594) headers, and the `link` as
+//! `Link: <...>; rel="deprecation"`. The CapabilityStatement documents every
+//! entry. Each use is logged on the `fhir_server::deprecation` target with
+//! the client that made it, so operators know whom to tell before the sunset.
+
+use crate::api_keys::{hash_key, ApiKeyName};
+use crate::config::SharedConfig;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// One `[deprecations.<feature>]` entry of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Deprecation {
+    /// Route pattern as routed, e.g. `/fhir/Patient/:id/$conflict`; every
+    /// route when only `parameter` is set
+    #[serde(default)]
+    pub route: Option<String>,
+    /// Methods of `route` that are deprecated; all when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Query parameter that is deprecated, matched without its modifier
+    #[serde(default)]
+    pub parameter: Option<String>,
+    /// Day the feature was deprecated
+    pub since: NaiveDate,
+    /// Day after which it may stop working
+    #[serde(default)]
+    pub sunset: Option<NaiveDate>,
+    /// Where clients can read how to migrate
+    #[serde(default)]
+    pub link: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+impl Deprecation {
+    pub(crate) fn validate(&self, feature: &str) -> anyhow::Result<()> {
+        if self.route.is_none() && self.parameter.is_none() {
+            anyhow::bail!("deprecations.{}: set a route, a parameter or both", feature);
+        }
+        if let Some(route) = &self.route {
+            if !crate::routes::ROUTE_ACCESS
+                .iter()
+                .any(|(path, _)| path == route)
+            {
+                anyhow::bail!("deprecations.{}: {} is not a route", feature, route);
+            }
+        }
+        if let Some(method) = self
+            .methods
+            .iter()
+            .find(|method| method.parse::<Method>().is_err() || method.to_uppercase() != **method)
+        {
+            anyhow::bail!(
+                "deprecations.{}: {} is not an upper-case HTTP method",
+                feature,
+                method
+            );
+        }
+        if !self.methods.is_empty() && self.route.is_none() {
+            anyhow::bail!("deprecations.{}: methods need a route", feature);
+        }
+        if self.parameter.as_deref().is_some_and(str::is_empty) {
+            anyhow::bail!("deprecations.{}: parameter must not be empty", feature);
+        }
+        if self.sunset.is_some_and(|sunset| sunset < self.since) {
+            anyhow::bail!("deprecations.{}: sunset is before since", feature);
+        }
+        if let Some(link) = &self.link {
+            if HeaderValue::from_str(link).is_err() || link.contains(['<', '>']) {
+                anyhow::bail!("deprecations.{}: link is not a valid URL", feature);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a request for `route` with `method` and `query` uses the feature
+    fn applies(&self, method: &Method, route: Option<&str>, query: Option<&str>) -> bool {
+        if let Some(deprecated) = &self.route {
+            if route != Some(deprecated.as_str()) {
+                return false;
+            }
+            if !self.methods.is_empty() && !self.methods.iter().any(|m| m == method.as_str()) {
+                return false;
+            }
+        }
+        match &self.parameter {
+            Some(parameter) => query_names(query.unwrap_or_default()).any(|name| name == parameter),
+            None => true,
+        }
+    }
+
+    /// What the CapabilityStatement says about the feature
+    fn describe(&self) -> String {
+        let mut text = format!("Deprecated since {}", self.since);
+        if let Some(sunset) = self.sunset {
+            text.push_str(&format!(", sunset {}", sunset));
+        }
+        if let Some(note) = &self.note {
+            text.push_str(&format!(": {}", note));
+        }
+        text.push('.');
+        if let Some(link) = &self.link {
+            text.push_str(&format!(" See {}", link));
+        }
+        text
+    }
+
+    /// The feature as the rest documentation of the CapabilityStatement
+    /// lists it
+    fn subject(&self) -> String {
+        let route = self
+            .route
+            .as_ref()
+            .map(|route| match self.methods.as_slice() {
+                [] => route.clone(),
+                methods => format!("{} {}", methods.join("/"), route),
+            });
+        match (route, &self.parameter) {
+            (Some(route), Some(parameter)) => format!("{} on {}", parameter, route),
+            (Some(route), None) => route,
+            (None, Some(parameter)) => parameter.clone(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Names of the parameters of a query string, without their modifiers
+fn query_names(query: &str) -> impl Iterator<Item = &str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split('=').next())
+        .map(|name| name.split(':').next().unwrap_or(name))
+        .filter(|name| !name.is_empty())
+}
+
+/// Midnight UTC of `date` as seconds since the epoch
+fn timestamp(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()
+}
+
+/// `Deprecation`, `Sunset` and `Link` headers for the used `features`: the
+/// earliest deprecation and sunset, and every link
+fn announce(headers: &mut HeaderMap, features: &[&Deprecation]) {
+    if let Some(since) = features.iter().map(|d| d.since).min() {
+        let value = format!("@{}", timestamp(since));
+        headers.insert("deprecation", HeaderValue::from_str(&value).unwrap());
+    }
+    if let Some(sunset) = features.iter().filter_map(|d| d.sunset).min() {
+        let value = sunset
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        headers.insert("sunset", HeaderValue::from_str(&value).unwrap());
+    }
+    for link in features.iter().filter_map(|d| d.link.as_ref()) {
+        let value = format!("<{}>; rel=\"deprecation\"", link);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.append(header::LINK, value);
+        }
+    }
+}
+
+/// Who made a request: the name of its API key once checked, else a short
+/// hash of its credential, else `anonymous`
+fn client(request: &Request) -> String {
+    if let Some(ApiKeyName(name)) = request.extensions().get::<ApiKeyName>() {
+        return name.clone();
+    }
+    match request.headers().get(header::AUTHORIZATION) {
+        Some(value) => format!(
+            "credential {}",
+            &hash_key(&String::from_utf8_lossy(value.as_bytes()))[..12]
+        ),
+        None => "anonymous".to_string(),
+    }
+}
+
+/// Add the deprecation headers to responses to requests that use a
+/// deprecated feature, and log the use
+pub async fn announce_deprecations(
+    State(config): State<Arc<SharedConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let deprecations = config.get().deprecations.clone();
+    if deprecations.is_empty() {
+        return next.run(request).await;
+    }
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let used: Vec<(&String, &Deprecation)> = deprecations
+        .iter()
+        .filter(|(_, d)| d.applies(request.method(), route, request.uri().query()))
+        .collect();
+    if used.is_empty() {
+        return next.run(request).await;
+    }
+
+    let client = client(&request);
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    for (feature, _) in &used {
+        tracing::info!(
+            target: "fhir_server::deprecation",
+            feature = %feature,
+            client = %client,
+            user_agent = %user_agent,
+            method = %request.method(),
+            path = %request.uri().path(),
+            "deprecated feature used"
+        );
+    }
+
+    let mut response = next.run(request).await;
+    let features: Vec<&Deprecation> = used.into_iter().map(|(_, d)| d).collect();
+    announce(response.headers_mut(), &features);
+    response
+}
+
+/// Document the deprecated features in a CapabilityStatement: on the
+/// search parameters and operations they name, and all of them on `rest`
+pub fn annotate_capabilities(statement: &mut Value, deprecations: &BTreeMap<String, Deprecation>) {
+    let Some(rest) = statement["rest"].get_mut(0) else {
+        return;
+    };
+    if deprecations.is_empty() {
+        return;
+    }
+
+    for deprecation in deprecations.values() {
+        let note = deprecation.describe();
+        let resource_type = deprecation
+            .route
+            .as_deref()
+            .and_then(|route| route.strip_prefix("/fhir/"))
+            .and_then(|rest| rest.split('/').next());
+        if let Some(parameter) = &deprecation.parameter {
+            let resources = rest["resource"].as_array_mut().into_iter().flatten();
+            for resource in resources.filter(|r| resource_type.is_none_or(|t| r["type"] == t)) {
+                let params = resource["searchParam"].as_array_mut().into_iter().flatten();
+                for param in params.filter(|p| p["name"] == parameter.as_str()) {
+                    append_documentation(param, &note);
+                }
+            }
+        } else if let Some(operation) = deprecation
+            .route
+            .as_deref()
+            .and_then(|route| route.rsplit('/').next())
+            .and_then(|segment| segment.strip_prefix('$'))
+        {
+            let operations = rest["operation"].as_array_mut().into_iter().flatten();
+            for entry in operations.filter(|o| o["name"] == operation) {
+                append_documentation(entry, &note);
+            }
+        }
+    }
+
+    let listed = deprecations
+        .values()
+        .map(|deprecation| format!("{}: {}", deprecation.subject(), deprecation.describe()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    append_documentation(rest, &listed);
+}
+
+fn append_documentation(element: &mut Value, text: &str) {
+    let documentation = match element["documentation"].as_str() {
+        Some(existing) => format!("{} {}", existing, text),
+        None => text.to_string(),
+    };
+    element["documentation"] = json!(documentation);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    fn deprecation(route: Option<&str>, parameter: Option<&str>) -> Deprecation {
+        Deprecation {
+            route: route.map(String::from),
+            methods: Vec::new(),
+            parameter: parameter.map(String::from),
+            since: NaiveDate::from_ymd_opt(2026, 7, 1).unwrap(),
+            sunset: NaiveDate::from_ymd_opt(2027, 1, 1),
+            link: Some("https://example.org/migrating".to_string()),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn test_deprecations_apply_to_their_route_and_parameter() {
+        let offset = deprecation(None, Some("_offset"));
+        assert!(offset.applies(
+            &Method::GET,
+            Some("/fhir/Patient"),
+            Some("name=a&_offset=20")
+        ));
+        assert!(offset.applies(&Method::GET, None, Some("_offset:x=1")));
+        assert!(!offset.applies(&Method::GET, Some("/fhir/Patient"), Some("_offsets=1")));
+        assert!(!offset.applies(&Method::GET, Some("/fhir/Patient"), None));
+
+        let mut conflict = deprecation(Some("/fhir/Patient/:id/$conflict"), None);
+        conflict.methods = vec!["GET".to_string()];
+        assert!(conflict.applies(&Method::GET, Some("/fhir/Patient/:id/$conflict"), None));
+        assert!(!conflict.applies(&Method::POST, Some("/fhir/Patient/:id/$conflict"), None));
+        assert!(!conflict.applies(&Method::GET, Some("/fhir/Patient/:id"), None));
+
+        assert!(conflict.validate("conflict").is_ok());
+        assert!(deprecation(Some("/fhir/Nope"), None).validate("x").is_err());
+        assert!(deprecation(None, None).validate("x").is_err());
+        let mut early = deprecation(None, Some("_offset"));
+        early.sunset = NaiveDate::from_ymd_opt(2026, 1, 1);
+        assert!(early.validate("x").is_err());
+        conflict.methods = vec!["get".to_string()];
+        assert!(conflict.validate("x").is_err());
+    }
+
+    #[test]
+    fn test_headers_announce_the_earliest_dates() {
+        let mut later = deprecation(None, Some("_offset"));
+        later.since = NaiveDate::from_ymd_opt(2026, 9, 1).unwrap();
+        later.sunset = None;
+        later.link = Some("https://example.org/other".to_string());
+        let mut headers = HeaderMap::new();
+        announce(&mut headers, &[&later, &deprecation(None, Some("_count"))]);
+
+        assert_eq!(headers["deprecation"], "@1782864000");
+        assert_eq!(headers["sunset"], "Fri, 01 Jan 2027 00:00:00 GMT");
+        let links: Vec<_> = headers.get_all(header::LINK).iter().collect();
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            links[1],
+            "<https://example.org/migrating>; rel=\"deprecation\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deprecated_parameters_are_announced() {
+        let mut config = ServerConfig::default();
+        config.deprecations.insert(
+            "offset-paging".to_string(),
+            deprecation(Some("/fhir/Patient"), Some("_offset")),
+        );
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config)),
+        ));
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let paged = get("/fhir/Patient?_count=1&_offset=1").await.unwrap();
+        assert_eq!(paged.status(), StatusCode::OK);
+        assert_eq!(paged.headers()["deprecation"], "@1782864000");
+        assert_eq!(paged.headers()["sunset"], "Fri, 01 Jan 2027 00:00:00 GMT");
+
+        let first = get("/fhir/Patient?_count=1").await.unwrap();
+        assert!(first.headers().get("deprecation").is_none());
+
+        let metadata = get("/fhir/metadata").await.unwrap();
+        let body = axum::body::to_bytes(metadata.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let statement: Value = serde_json::from_slice(&body).unwrap();
+        let documentation = statement["rest"][0]["documentation"].as_str().unwrap();
+        assert!(documentation.starts_with(
+            "_offset on /fhir/Patient: Deprecated since 2026-07-01, sunset 2027-01-01."
+        ));
+    }
+}
@@ -0,0 +1,372 @@
+//! `_elements`: reads and searches that return only some elements.
+//!
+//! `GET` requests with `_elements=name,birthDate` are answered with each
+//! resource reduced to the named top-level elements, plus `resourceType`,
+//! `id`, `meta` and the elements its type requires, and tagged `SUBSETTED`
+//! in `meta.tag`. In a Bundle the entry resources are reduced, not the
+//! Bundle, and its paging links keep `_elements`. A name may be qualified
+//! with its type (`Patient.name`) and then applies to that type only.
+//!
+//! The parameter is removed from the URI before routing, so handlers never
+//! see it; asynchronous exports find the selection in the request
+//! extensions and reduce every line they write.
+
+use crate::models::OperationOutcome;
+use crate::negotiation::{has_json_body, without_parameter};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+
+pub const ELEMENTS_PARAMETER: &str = "_elements";
+
+pub const SUBSETTED_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-ObservationValue";
+
+/// Always kept, whatever is selected
+const BASE_ELEMENTS: &[&str] = &["resourceType", "id", "meta"];
+
+/// Elements with a minimum cardinality of one, kept so reduced resources
+/// still parse
+const MANDATORY_ELEMENTS: &[(&str, &[&str])] = &[
+    ("Bundle", &["type"]),
+    (
+        "CapabilityStatement",
+        &["status", "date", "kind", "fhirVersion", "format"],
+    ),
+    ("Observation", &["status", "code"]),
+    ("OperationOutcome", &["issue"]),
+    (
+        "SearchParameter",
+        &[
+            "url",
+            "name",
+            "status",
+            "description",
+            "code",
+            "base",
+            "type",
+        ],
+    ),
+];
+
+/// Top-level elements selected with `_elements`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elements {
+    /// Name and, for `Type.name`, the resource type it is limited to
+    selected: Vec<(Option<String>, String)>,
+    /// The parameter as sent, for paging links
+    query: String,
+}
+
+impl Elements {
+    /// Parse the comma-separated values of every `_elements` parameter
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut selected = Vec::new();
+        let mut sent = Vec::new();
+        for value in values {
+            sent.push(value.to_string());
+            for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                let valid = |part: &str| {
+                    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                };
+                let element = match name.split_once('.') {
+                    Some((resource_type, element))
+                        if resource_type.starts_with(|c: char| c.is_ascii_uppercase())
+                            && valid(resource_type)
+                            && valid(element) =>
+                    {
+                        (Some(resource_type.to_string()), element.to_string())
+                    }
+                    None if valid(name) => (None, name.to_string()),
+                    Some(_) if valid(name.split('.').next().unwrap_or_default()) => {
+                        return Err(format!(
+                            "_elements selects top-level elements only, not {}",
+                            name
+                        ))
+                    }
+                    _ => return Err(format!("Invalid element name '{}' in _elements", name)),
+                };
+                selected.push(element);
+            }
+        }
+        if selected.is_empty() {
+            return Err("_elements names no elements".to_string());
+        }
+        let query = serde_urlencoded::to_string(
+            sent.iter()
+                .map(|value| (ELEMENTS_PARAMETER, value.as_str()))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap_or_default();
+        Ok(Elements { selected, query })
+    }
+
+    /// The selection of `uri` and the URI without it, if present
+    pub fn from_uri(uri: &Uri) -> Result<Option<(Self, Uri)>, String> {
+        let Some(query) = uri.query() else {
+            return Ok(None);
+        };
+        let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+        let values: Vec<&str> = pairs
+            .iter()
+            .filter(|(name, _)| name == ELEMENTS_PARAMETER)
+            .map(|(_, value)| value.as_str())
+            .collect();
+        if values.is_empty() {
+            return Ok(None);
+        }
+        let elements = Self::parse(values)?;
+        Ok(Some((elements, without_parameter(uri, ELEMENTS_PARAMETER))))
+    }
+
+    fn keeps(&self, resource_type: &str, name: &str) -> bool {
+        // `_birthDate` holds the extensions of `birthDate`
+        let name = name.strip_prefix('_').unwrap_or(name);
+        BASE_ELEMENTS.contains(&name)
+            || MANDATORY_ELEMENTS
+                .iter()
+                .any(|(t, names)| *t == resource_type && names.contains(&name))
+            || self.selected.iter().any(|(t, selected)| {
+                selected == name && t.as_deref().is_none_or(|t| t == resource_type)
+            })
+    }
+
+    /// Reduce `resource` to the selected elements and tag it `SUBSETTED`
+    pub fn project(&self, resource: &mut Value) {
+        let resource_type = resource["resourceType"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let Some(map) = resource.as_object_mut() else {
+            return;
+        };
+        let before = map.len();
+        map.retain(|name, _| self.keeps(&resource_type, name));
+        if map.len() == before {
+            return;
+        }
+
+        let meta = map.entry("meta").or_insert_with(|| json!({}));
+        if let Some(meta) = meta.as_object_mut() {
+            let tags = meta.entry("tag").or_insert_with(|| json!([]));
+            if let Some(tags) = tags.as_array_mut() {
+                let subsetted = tags
+                    .iter()
+                    .any(|t| t["system"] == SUBSETTED_SYSTEM && t["code"] == "SUBSETTED");
+                if !subsetted {
+                    tags.push(json!({ "system": SUBSETTED_SYSTEM, "code": "SUBSETTED" }));
+                }
+            }
+        }
+    }
+
+    /// Reduce a response body: the entry resources of a Bundle, or the
+    /// resource itself
+    pub fn project_response(&self, body: &mut Value) {
+        if body["resourceType"] != "Bundle" {
+            return self.project(body);
+        }
+        if let Some(entries) = body.get_mut("entry").and_then(Value::as_array_mut) {
+            for resource in entries.iter_mut().filter_map(|e| e.get_mut("resource")) {
+                if resource["resourceType"] != "OperationOutcome" {
+                    self.project(resource);
+                }
+            }
+        }
+        if let Some(links) = body.get_mut("link").and_then(Value::as_array_mut) {
+            for url in links.iter_mut().filter_map(|l| l.get_mut("url")) {
+                if let Some(link) = url.as_str() {
+                    let separator = if link.contains('?') { '&' } else { '?' };
+                    *url = json!(format!("{}{}{}", link, separator, self.query));
+                }
+            }
+        }
+    }
+}
+
+/// Answer `GET` requests carrying `_elements` with reduced resources
+pub async fn select_elements(mut request: Request, next: Next) -> Response {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return next.run(request).await;
+    }
+    let elements = match Elements::from_uri(request.uri()) {
+        Ok(Some((elements, uri))) => {
+            *request.uri_mut() = uri;
+            elements
+        }
+        Ok(None) => return next.run(request).await,
+        Err(message) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error("invalid", message)),
+            )
+                .into_response()
+        }
+    };
+    request.extensions_mut().insert(elements.clone());
+
+    let response = next.run(request).await;
+    if !response.status().is_success() || !has_json_body(response.headers()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Failed to read response body for _elements: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let Ok(mut resource) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    elements.project_response(&mut resource);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(resource.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn app() -> axum::Router {
+        crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(ServerConfig {
+                resource_types: vec!["Organization".to_string()],
+                ..ServerConfig::default()
+            })),
+        ))
+    }
+
+    async fn get(app: &axum::Router, uri: &str) -> (StatusCode, Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn parse(value: &str) -> Result<Elements, String> {
+        Elements::parse([value])
+    }
+
+    #[test]
+    fn test_projection_keeps_selected_and_mandatory_elements() {
+        let elements = parse("name, birthDate,Observation.subject").unwrap();
+        let mut patient = json!({
+            "resourceType": "Patient",
+            "id": "p1",
+            "meta": { "versionId": "2" },
+            "name": [{ "family": "Berg" }],
+            "birthDate": "1980-01-01",
+            "_birthDate": { "extension": [] },
+            "subject": "not a Patient element",
+            "telecom": [{ "system": "phone", "value": "555" }]
+        });
+        elements.project(&mut patient);
+        assert_eq!(
+            patient,
+            json!({
+                "resourceType": "Patient",
+                "id": "p1",
+                "meta": {
+                    "versionId": "2",
+                    "tag": [{ "system": SUBSETTED_SYSTEM, "code": "SUBSETTED" }]
+                },
+                "name": [{ "family": "Berg" }],
+                "birthDate": "1980-01-01",
+                "_birthDate": { "extension": [] }
+            })
+        );
+
+        let mut observation = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "text": "Weight" },
+            "subject": { "reference": "Patient/p1" },
+            "valueQuantity": { "value": 70 }
+        });
+        elements.project(&mut observation);
+        assert_eq!(observation["status"], "final");
+        assert_eq!(observation["subject"]["reference"], "Patient/p1");
+        assert!(observation.get("valueQuantity").is_none());
+
+        // Nothing removed, nothing tagged
+        let mut minimal = json!({ "resourceType": "Patient", "name": [] });
+        elements.project(&mut minimal);
+        assert!(minimal.get("meta").is_none());
+
+        assert!(parse("name.family").unwrap_err().contains("top-level"));
+        assert!(parse("na me").is_err());
+        assert!(parse(" , ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_and_searches_return_selected_elements() {
+        let app = app().await;
+        let patient = json!({
+            "resourceType": "Patient",
+            "name": [{ "family": "Elementson", "given": ["Eli"] }],
+            "gender": "male",
+            "birthDate": "1975-05-05",
+            "telecom": [{ "system": "email", "value": "eli@example.org" }]
+        });
+        let create = Request::post("/fhir/Patient")
+            .header(header::CONTENT_TYPE, "application/fhir+json")
+            .body(Body::from(patient.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(create).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let created: Value = serde_json::from_slice(&body).unwrap();
+        let id = created["id"].as_str().unwrap();
+
+        let (status, read) = get(
+            &app,
+            &format!("/fhir/Patient/{}?_elements=name,birthDate", id),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(read["id"], id);
+        assert_eq!(read["birthDate"], "1975-05-05");
+        assert!(read.get("telecom").is_none() && read.get("gender").is_none());
+        assert_eq!(read["meta"]["tag"][0]["code"], "SUBSETTED");
+
+        let (status, bundle) = get(
+            &app,
+            "/fhir/Patient?name=Elementson&_count=1&_elements=Patient.gender",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bundle["type"], "searchset");
+        let found = &bundle["entry"][0]["resource"];
+        assert_eq!(found["gender"], "male");
+        assert!(found.get("name").is_none());
+        let (_, history) = get(&app, "/fhir/Patient/_history?_count=1&_elements=gender").await;
+        let links = history["link"].as_array().unwrap();
+        assert!(links.iter().any(|link| link["relation"] == "next"));
+        for link in links {
+            let url = link["url"].as_str().unwrap();
+            assert!(url.ends_with("&_elements=gender"), "{}", url);
+        }
+
+        // Generically served types accept it too
+        let (status, _) = get(&app, "/fhir/Organization?_elements=name").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, outcome) = get(&app, "/fhir/Patient?_elements=name.given").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["issue"][0]["code"], "invalid");
+    }
+}
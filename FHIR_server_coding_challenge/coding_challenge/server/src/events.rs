@@ -0,0 +1,93 @@
+//! Fan-out of change notifications to the `GET /fhir/_events` streams.
+//!
+//! One connection per process listens on [`crate::db::changes::CHANGE_CHANNEL`],
+//! from the first stream opened on; every stream subscribes to what it
+//! receives. Notifications are not stored: a stream that falls more than
+//! [`BUFFERED_EVENTS`] behind, or a listener that loses its connection,
+//! misses changes, and its subscribers get [`ChangeEvent::Gap`] instead.
+//! `GET /fhir/_changes` is the complete record to catch up from.
+
+use crate::db::{Change, Database};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+
+/// Events a slow stream may fall behind before it misses some
+pub const BUFFERED_EVENTS: usize = 1024;
+
+/// What a stream receives
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    Change(Change),
+    /// Changes may have been missed
+    Gap,
+}
+
+/// The listener of this process and the streams subscribed to it
+pub struct ChangeEvents {
+    sender: broadcast::Sender<ChangeEvent>,
+    /// Whether the listener is running
+    listening: Arc<Mutex<bool>>,
+}
+
+impl Default for ChangeEvents {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(BUFFERED_EVENTS).0,
+            listening: Arc::new(Mutex::new(false)),
+        }
+    }
+}
+
+impl ChangeEvents {
+    /// Subscribe to the changes committed from now on, starting the listener
+    /// if it is not running
+    pub async fn subscribe(
+        &self,
+        db: &Database,
+    ) -> anyhow::Result<broadcast::Receiver<ChangeEvent>> {
+        let mut listening = self.listening.lock().await;
+        // Subscribed before the listener starts, so nothing it relays is missed
+        let receiver = self.sender.subscribe();
+        if !*listening {
+            let listener = db.listen_changes().await?;
+            *listening = true;
+            tokio::spawn(relay(listener, self.sender.clone(), self.listening.clone()));
+        }
+        Ok(receiver)
+    }
+}
+
+/// Forward notifications to the subscribers until the listener fails; the
+/// next subscription starts a new one
+async fn relay(
+    mut listener: sqlx::postgres::PgListener,
+    sender: broadcast::Sender<ChangeEvent>,
+    listening: Arc<Mutex<bool>>,
+) {
+    loop {
+        match listener.try_recv().await {
+            Ok(Some(notification)) => match Change::from_notification(notification.payload()) {
+                Ok(change) => {
+                    // No subscribers is not an error
+                    let _ = sender.send(ChangeEvent::Change(change));
+                }
+                Err(e) => tracing::warn!(
+                    error = %e,
+                    payload = notification.payload(),
+                    "Ignoring a malformed change notification"
+                ),
+            },
+            // The connection was lost and is re-established on the next call
+            Ok(None) => {
+                tracing::warn!("Change listener reconnecting; notifications may have been missed");
+                let _ = sender.send(ChangeEvent::Gap);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Change listener failed");
+                *listening.lock().await = false;
+                let _ = sender.send(ChangeEvent::Gap);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Extractors that reject malformed requests with an OperationOutcome
+//! instead of axum's plain-text 400.
+//!
+//! Handlers that take `axum::Json` keep it; [`outcome_for_rejections`]
+//! limits their bodies to `max_body_bytes` and turns the plain-text
+//! rejections of the extractor into OperationOutcomes.
+
+use crate::config::SharedConfig;
+use crate::models::OperationOutcome;
+use axum::{
+    async_trait,
+    body::to_bytes,
+    extract::{DefaultBodyLimit, FromRequestParts, Request, State},
+    http::{header, request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use tower::{Layer, ServiceExt};
+
+/// Longest rejection text read back; axum's are a line or two
+const MAX_REJECTION_BYTES: usize = 16 * 1024;
+
+/// Drop-in replacement for `axum::extract::Query`: a parameter that does not
+/// fit its type, e.g. `_count=abc`, is answered with `400 invalid` naming the
+/// parameter and the type it expects
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<OperationOutcome>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        parse_query(query).map(Query).map_err(|rejection| {
+            let outcome = match rejection.parameter {
+                Some(parameter) => {
+                    OperationOutcome::error_with_location("invalid", rejection.message, parameter)
+                }
+                None => OperationOutcome::error("invalid", rejection.message),
+            };
+            (StatusCode::BAD_REQUEST, Json(outcome))
+        })
+    }
+}
+
+/// Why a query string could not be deserialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryRejection {
+    /// The offending parameter, when the failure is tied to one
+    pub parameter: Option<String>,
+    pub message: String,
+}
+
+/// The type a failed `FromStr` parse was after, recognized from the
+/// standard library's error messages
+fn expected_type(error: &str) -> Option<&'static str> {
+    match error {
+        "invalid digit found in string"
+        | "cannot parse integer from empty string"
+        | "number too large to fit in target type"
+        | "number too small to fit in target type" => Some("an integer"),
+        "invalid float literal" | "cannot parse float from empty string" => Some("a decimal"),
+        "provided string was not `true` or `false`" => Some("true or false"),
+        _ => None,
+    }
+}
+
+pub fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, QueryRejection> {
+    let deserializer =
+        serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let parameter = e.path().to_string();
+        let error = e.into_inner().to_string();
+        if parameter == "." {
+            return QueryRejection {
+                parameter: None,
+                message: format!("Invalid query string: {}", error),
+            };
+        }
+        let value = form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| *name == parameter)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default();
+        let message = match expected_type(&error) {
+            Some(expected) => format!(
+                "Invalid value '{}' for {}: expected {}",
+                value, parameter, expected
+            ),
+            None => format!("Invalid value '{}' for {}: {}", value, parameter, error),
+        };
+        QueryRejection {
+            parameter: Some(parameter),
+            message,
+        }
+    })
+}
+
+/// Read request bodies up to `max_body_bytes`, and answer the extractor
+/// rejections of the handler with an OperationOutcome: 413 `too-long` for
+/// an oversized body, 415 `not-supported` for a body that is not JSON, and
+/// 400 `structure` for JSON that does not parse or does not fit the
+/// handler. Routes with a body limit of their own keep it, since theirs
+/// sits closer to the handler.
+pub async fn outcome_for_rejections(
+    State(config): State<Arc<SharedConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limit = config.get().max_body_bytes;
+    let response = DefaultBodyLimit::max(limit)
+        .layer(next)
+        .oneshot(request)
+        .await
+        .unwrap_or_else(|never| match never {});
+    let Some((status, code)) = rejection_code(&response) else {
+        return response;
+    };
+    let diagnostics = match to_bytes(response.into_body(), MAX_REJECTION_BYTES).await {
+        Ok(text) => String::from_utf8_lossy(&text).into_owned(),
+        Err(_) => "The request body could not be read".to_string(),
+    };
+    (status, Json(OperationOutcome::error(code, diagnostics))).into_response()
+}
+
+/// Status and issue code for a plain-text rejection of axum's body
+/// extractors; handlers here answer errors in JSON
+fn rejection_code(response: &Response) -> Option<(StatusCode, &'static str)> {
+    let plain_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/plain"));
+    if !plain_text {
+        return None;
+    }
+    match response.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => Some((StatusCode::PAYLOAD_TOO_LARGE, "too-long")),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            Some((StatusCode::UNSUPPORTED_MEDIA_TYPE, "not-supported"))
+        }
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            Some((StatusCode::BAD_REQUEST, "structure"))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use axum::{
+        body::Body,
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use serde::Deserialize;
+    use tower::ServiceExt;
+
+    #[derive(Debug, Deserialize)]
+    struct Params {
+        #[serde(rename = "_count")]
+        count: Option<u32>,
+        #[serde(rename = "_summary")]
+        summary: Option<bool>,
+        name: Option<String>,
+    }
+
+    #[test]
+    fn test_rejections_name_the_parameter_and_type() {
+        let params: Params = parse_query("name=Smith&_count=10").unwrap();
+        assert_eq!(params.count, Some(10));
+        assert_eq!(params.name.as_deref(), Some("Smith"));
+        assert!(params.summary.is_none());
+
+        let rejection = parse_query::<Params>("name=Smith&_count=abc").unwrap_err();
+        assert_eq!(rejection.parameter.as_deref(), Some("_count"));
+        assert_eq!(
+            rejection.message,
+            "Invalid value 'abc' for _count: expected an integer"
+        );
+
+        let rejection = parse_query::<Params>("_count=-1").unwrap_err();
+        assert!(rejection.message.ends_with("expected an integer"));
+        let rejection = parse_query::<Params>("_summary=maybe").unwrap_err();
+        assert_eq!(
+            rejection.message,
+            "Invalid value 'maybe' for _summary: expected true or false"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejection_is_an_operation_outcome() {
+        let app = Router::new().route(
+            "/fhir/Patient",
+            get(|Query(params): Query<Params>| async move { format!("{:?}", params.count) }),
+        );
+        let request = Request::get("/fhir/Patient?_count=abc")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let outcome: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(outcome["resourceType"], "OperationOutcome");
+        assert_eq!(outcome["issue"][0]["code"], "invalid");
+        assert_eq!(outcome["issue"][0]["location"][0], "_count");
+    }
+
+    async fn post_json(
+        content_type: &str,
+        body: impl Into<Body>,
+    ) -> (StatusCode, serde_json::Value) {
+        let config = ServerConfig {
+            max_body_bytes: 64,
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route(
+                "/fhir/Patient",
+                post(|Json(resource): Json<Params>| async move { format!("{:?}", resource) }),
+            )
+            .layer(middleware::from_fn_with_state(
+                Arc::new(SharedConfig::new(config)),
+                outcome_for_rejections,
+            ));
+        let request = Request::post("/fhir/Patient")
+            .header("content-type", content_type)
+            .body(body.into())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_body_rejections_are_operation_outcomes() {
+        let (status, _) = post_json("application/json", r#"{"name":"Smith"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for (content_type, body, status, code) in [
+            (
+                "application/json",
+                format!(r#"{{"name":"{}"}}"#, "x".repeat(100)),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too-long",
+            ),
+            (
+                "application/json",
+                r#"{"name":"#.to_string(),
+                StatusCode::BAD_REQUEST,
+                "structure",
+            ),
+            (
+                "application/json",
+                r#"{"_count":"ten"}"#.to_string(),
+                StatusCode::BAD_REQUEST,
+                "structure",
+            ),
+            (
+                "text/csv",
+                "name\nSmith".to_string(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "not-supported",
+            ),
+        ] {
+            let (actual, outcome) = post_json(content_type, body.clone()).await;
+            assert_eq!(actual, status, "{}", body);
+            assert_eq!(outcome["resourceType"], "OperationOutcome");
+            assert_eq!(outcome["issue"][0]["code"], code, "{}", body);
+            assert!(outcome["issue"][0]["diagnostics"].is_string());
+        }
+    }
+}
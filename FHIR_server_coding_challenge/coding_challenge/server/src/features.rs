@@ -0,0 +1,359 @@
+//! Feature flags, decided per request.
+//!
+//! `[features]` entries are either `name = true|false` or a rollout:
+//!
+//! ```toml
+//! [features.strict_search]
+//! enabled = false          # on for everyone
+//! percentage = 10          # on for this share of clients
+//! tenants = ["north"]      # on for requests with X-Tenant-ID: north
+//! ```
+//!
+//! Clients are told apart by their `Authorization` header, hashed with the
+//! flag name, so a client keeps its answer across requests and instances
+//! and different flags reach different clients first. Requests without the
+//! header count as one client.
+//!
+//! A flag is looked up in `FHIR_FEATURE_<NAME>` first (`true`, `false` or a
+//! percentage), then in what the `[feature_provider]` URL last returned (a
+//! JSON object of the same entries, fetched every `interval_secs`), then in
+//! the config file. Handlers ask through the [`Features`] extractor.
+
+use crate::config::SharedConfig;
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Rejects unknown Observation search parameters instead of ignoring them
+pub const STRICT_SEARCH: &str = "strict_search";
+
+/// Header naming the tenant a request is made for
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+const ENV_PREFIX: &str = "FHIR_FEATURE_";
+
+/// One `[features]` entry
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FeatureSetting {
+    Toggle(bool),
+    Rollout(FeatureRollout),
+}
+
+impl From<bool> for FeatureSetting {
+    fn from(enabled: bool) -> Self {
+        FeatureSetting::Toggle(enabled)
+    }
+}
+
+/// A flag switched on for some requests
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureRollout {
+    pub enabled: bool,
+    /// Share of clients, 0 to 100
+    pub percentage: u8,
+    pub tenants: Vec<String>,
+}
+
+/// The `[feature_provider]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FeatureProviderConfig {
+    /// Where to fetch flag overrides from; none when unset
+    pub url: Option<String>,
+    pub interval_secs: u64,
+}
+
+impl Default for FeatureProviderConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            interval_secs: 30,
+        }
+    }
+}
+
+/// Who a flag is decided for
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureSubject {
+    pub tenant: Option<String>,
+    credential: Option<Vec<u8>>,
+}
+
+impl FeatureSubject {
+    pub fn of(headers: &HeaderMap) -> Self {
+        Self {
+            tenant: headers
+                .get(TENANT_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            credential: headers
+                .get(header::AUTHORIZATION)
+                .map(|value| value.as_bytes().to_vec()),
+        }
+    }
+
+    /// Stable bucket in 0..100 of this client for `flag`
+    fn bucket(&self, flag: &str) -> u8 {
+        let mut hasher = Sha256::new();
+        hasher.update(flag.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.credential.as_deref().unwrap_or_default());
+        let digest = hasher.finalize();
+        (u16::from_be_bytes([digest[0], digest[1]]) % 100) as u8
+    }
+}
+
+impl FeatureSetting {
+    /// On for everyone
+    pub fn enabled(&self) -> bool {
+        match self {
+            FeatureSetting::Toggle(enabled) => *enabled,
+            FeatureSetting::Rollout(rollout) => rollout.enabled || rollout.percentage >= 100,
+        }
+    }
+
+    pub fn enabled_for(&self, flag: &str, subject: &FeatureSubject) -> bool {
+        match self {
+            FeatureSetting::Toggle(enabled) => *enabled,
+            FeatureSetting::Rollout(rollout) => {
+                rollout.enabled
+                    || subject
+                        .tenant
+                        .as_ref()
+                        .is_some_and(|tenant| rollout.tenants.contains(tenant))
+                    || subject.bucket(flag) < rollout.percentage
+            }
+        }
+    }
+
+    /// `FHIR_FEATURE_<NAME>` values: `true`, `false` or a percentage
+    fn parse_env(value: &str) -> Option<Self> {
+        match value.trim() {
+            "true" | "on" => Some(FeatureSetting::Toggle(true)),
+            "false" | "off" => Some(FeatureSetting::Toggle(false)),
+            percentage => match percentage.parse::<u8>() {
+                Ok(percentage) if percentage <= 100 => {
+                    Some(FeatureSetting::Rollout(FeatureRollout {
+                        percentage,
+                        ..Default::default()
+                    }))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Flag overrides from the environment and the remote provider; the config
+/// file is consulted on every lookup, so reloads apply right away
+#[derive(Debug, Default)]
+pub struct FeatureFlags {
+    env: BTreeMap<String, FeatureSetting>,
+    remote: RwLock<BTreeMap<String, FeatureSetting>>,
+}
+
+impl FeatureFlags {
+    pub fn from_env() -> Self {
+        Self::from_vars(std::env::vars())
+    }
+
+    fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut env = BTreeMap::new();
+        for (name, value) in vars {
+            let Some(flag) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match FeatureSetting::parse_env(&value) {
+                Some(setting) => {
+                    env.insert(flag.to_ascii_lowercase(), setting);
+                }
+                None => tracing::warn!(
+                    "Ignoring {}={}: expected true, false or a percentage",
+                    name,
+                    value
+                ),
+            }
+        }
+        Self {
+            env,
+            remote: RwLock::default(),
+        }
+    }
+
+    /// Replace what the remote provider returned last
+    pub fn set_remote(&self, settings: BTreeMap<String, FeatureSetting>) {
+        *self.remote.write().unwrap() = settings;
+    }
+
+    pub fn enabled(&self, config: &SharedConfig, flag: &str, subject: &FeatureSubject) -> bool {
+        if let Some(setting) = self.env.get(flag) {
+            return setting.enabled_for(flag, subject);
+        }
+        if let Some(setting) = self.remote.read().unwrap().get(flag) {
+            return setting.enabled_for(flag, subject);
+        }
+        config
+            .get()
+            .features
+            .get(flag)
+            .is_some_and(|setting| setting.enabled_for(flag, subject))
+    }
+
+    /// Every flag set anywhere, with the setting lookups use
+    pub fn settings(&self, config: &SharedConfig) -> BTreeMap<String, FeatureSetting> {
+        let mut settings = config.get().features.clone();
+        settings.extend(self.remote.read().unwrap().clone());
+        settings.extend(self.env.clone());
+        settings
+    }
+}
+
+/// Fetch the `[feature_provider]` overrides every `interval_secs`. The
+/// section is re-read before every fetch; a failed fetch keeps the last
+/// overrides, removing `url` drops them.
+pub fn spawn_provider(flags: Arc<FeatureFlags>, config: Arc<SharedConfig>) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            let provider = config.get().feature_provider.clone();
+            match &provider.url {
+                Some(url) => match fetch(&client, url).await {
+                    Ok(settings) => flags.set_remote(settings),
+                    Err(e) => tracing::warn!("Keeping feature flags from {}: {:#}", url, e),
+                },
+                None => flags.set_remote(BTreeMap::new()),
+            }
+            tokio::time::sleep(Duration::from_secs(provider.interval_secs)).await;
+        }
+    });
+}
+
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+) -> anyhow::Result<BTreeMap<String, FeatureSetting>> {
+    let response = client
+        .get(url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// The feature flags as they apply to the current request
+pub struct Features {
+    flags: Arc<FeatureFlags>,
+    config: Arc<SharedConfig>,
+    subject: FeatureSubject,
+}
+
+impl Features {
+    /// The flags for a request with `headers`
+    pub fn new(flags: Arc<FeatureFlags>, config: Arc<SharedConfig>, headers: &HeaderMap) -> Self {
+        Self {
+            flags,
+            config,
+            subject: FeatureSubject::of(headers),
+        }
+    }
+
+    pub fn enabled(&self, flag: &str) -> bool {
+        self.flags.enabled(&self.config, flag, &self.subject)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Features
+where
+    Arc<FeatureFlags>: FromRef<S>,
+    Arc<SharedConfig>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Features::new(
+            Arc::from_ref(state),
+            Arc::from_ref(state),
+            &parts.headers,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    fn subject(tenant: Option<&str>, credential: &str) -> FeatureSubject {
+        let mut headers = HeaderMap::new();
+        if let Some(tenant) = tenant {
+            headers.insert(TENANT_HEADER, tenant.parse().unwrap());
+        }
+        headers.insert(header::AUTHORIZATION, credential.parse().unwrap());
+        FeatureSubject::of(&headers)
+    }
+
+    #[test]
+    fn test_rollouts_reach_tenants_and_a_share_of_clients() {
+        let setting = FeatureSetting::Rollout(FeatureRollout {
+            enabled: false,
+            percentage: 30,
+            tenants: vec!["north".to_string()],
+        });
+        assert!(setting.enabled_for("f", &subject(Some("north"), "Bearer a")));
+        assert!(!setting.enabled()); // not for everyone
+
+        let clients: Vec<FeatureSubject> = (0..1000)
+            .map(|i| subject(None, &format!("Bearer {}", i)))
+            .collect();
+        let on = clients
+            .iter()
+            .filter(|c| setting.enabled_for("f", c))
+            .count();
+        assert!((200..400).contains(&on), "{} of 1000", on);
+        // Another flag at the same percentage starts with other clients
+        assert!(clients
+            .iter()
+            .any(|c| setting.enabled_for("f", c) != setting.enabled_for("g", c)));
+    }
+
+    #[test]
+    fn test_env_then_remote_then_config() {
+        let config = SharedConfig::new(
+            ServerConfig::parse("[features]\nstrict_search = true\nother = true").unwrap(),
+        );
+        let flags = FeatureFlags::from_vars([
+            (
+                "FHIR_FEATURE_STRICT_SEARCH".to_string(),
+                "false".to_string(),
+            ),
+            ("FHIR_FEATURE_BROKEN".to_string(), "lots".to_string()),
+            ("PATH".to_string(), "/bin".to_string()),
+        ]);
+        let anyone = FeatureSubject::default();
+        assert!(!flags.enabled(&config, STRICT_SEARCH, &anyone));
+        assert!(flags.enabled(&config, "other", &anyone));
+
+        flags.set_remote(serde_json::from_str(r#"{"other": {"tenants": ["north"]}}"#).unwrap());
+        assert!(!flags.enabled(&config, "other", &anyone));
+        assert!(flags.enabled(&config, "other", &subject(Some("north"), "Bearer a")));
+        assert!(!flags.enabled(&config, "missing", &anyone));
+
+        let settings = flags.settings(&config);
+        assert_eq!(settings.len(), 2);
+        assert_eq!(settings[STRICT_SEARCH], FeatureSetting::Toggle(false));
+        assert!(!settings["other"].enabled());
+    }
+}
@@ -0,0 +1,858 @@
+//! FHIRPath Patch: a `Parameters` resource of `operation`s, each naming its
+//! `type` and the FHIRPath `path` it applies to, sent with
+//! `PATCH /fhir/Patient/:id` instead of a JSON Patch.
+//!
+//! The operations are applied in order, and the first that cannot be
+//! applied fails the whole patch:
+//!
+//! - `add` adds `name` with `value` to the one element `path` selects,
+//!   appending to it if the element repeats
+//! - `insert` puts `value` at `index` of the list `path` names
+//! - `delete` removes the one element `path` selects; selecting nothing is
+//!   not an error
+//! - `replace` sets the one element `path` selects to `value`
+//! - `move` moves the item at `source` of the list `path` names to
+//!   `destination`
+//!
+//! Paths start with the resource type and use the navigational subset of
+//! FHIRPath: element names, indexers (`name[0]`), `first()`, `last()`,
+//! `where(<element> = '<string>')` and `extension('<url>')`. A `value` is a
+//! `value[x]` of any type, or `part`s making up a backbone element such as
+//! a contact.
+
+use crate::search::expression::{call, is_name, split_top_level, string_literal, CHOICE_ELEMENTS};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Elements of Patient and of the datatypes it uses that repeat, and so are
+/// JSON arrays even when added with a single value
+const REPEATING_ELEMENTS: &[&str] = &[
+    "identifier",
+    "name",
+    "telecom",
+    "address",
+    "photo",
+    "contact",
+    "communication",
+    "generalPractitioner",
+    "link",
+    "extension",
+    "modifierExtension",
+    "contained",
+    "given",
+    "prefix",
+    "suffix",
+    "line",
+    "coding",
+    "relationship",
+];
+
+/// Elements of backbone elements that do not repeat although an element of
+/// Patient with the same name does, by the backbone element they are in
+const SINGULAR_ELEMENTS: &[(&str, &str)] = &[("contact", "name"), ("contact", "address")];
+
+/// Whether element `name` of element `parent` repeats
+pub(crate) fn repeats(parent: Option<&str>, name: &str) -> bool {
+    REPEATING_ELEMENTS.contains(&name)
+        && !parent.is_some_and(|parent| SINGULAR_ELEMENTS.contains(&(parent, name)))
+}
+
+/// Why a patch was not applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchError {
+    /// OperationOutcome issue code
+    pub code: &'static str,
+    pub message: String,
+    /// `Parameters.parameter[i]` of the failing operation
+    pub location: Option<String>,
+    /// Whether the Parameters resource is at fault (400), rather than its
+    /// operations not applying to the resource (422)
+    pub malformed: bool,
+}
+
+impl PatchError {
+    fn malformed(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            location: None,
+            malformed: true,
+        }
+    }
+
+    fn conflict(message: impl Into<String>) -> Self {
+        Self {
+            code: "processing",
+            message: message.into(),
+            location: None,
+            malformed: false,
+        }
+    }
+
+    fn at(mut self, operation: usize) -> Self {
+        self.location = Some(format!("Parameters.parameter[{}]", operation));
+        self
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationType {
+    Add,
+    Insert,
+    Delete,
+    Replace,
+    Move,
+}
+
+/// The `value` of an operation, with its type when it is a `value[x]`
+/// (`Boolean` for `valueBoolean`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchValue {
+    pub value: Value,
+    pub value_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchOperation {
+    pub kind: OperationType,
+    pub path: String,
+    pub name: Option<String>,
+    pub value: Option<PatchValue>,
+    pub index: Option<usize>,
+    pub source: Option<usize>,
+    pub destination: Option<usize>,
+}
+
+/// Whether a PATCH `body` is a FHIRPath Patch rather than a JSON Patch
+pub fn is_fhirpath_patch(body: &Value) -> bool {
+    body.get("resourceType").and_then(Value::as_str) == Some("Parameters")
+}
+
+/// The operations of the FHIRPath Patch `parameters`
+pub fn parse(parameters: &Value) -> Result<Vec<PatchOperation>, PatchError> {
+    let operations = parameters
+        .get("parameter")
+        .and_then(Value::as_array)
+        .filter(|operations| !operations.is_empty())
+        .ok_or_else(|| {
+            PatchError::malformed("required", "A FHIRPath Patch needs at least one operation")
+        })?;
+    operations
+        .iter()
+        .enumerate()
+        .map(|(i, operation)| parse_operation(operation).map_err(|e| e.at(i)))
+        .collect()
+}
+
+/// The type of a `value[x]` member name (`Boolean` for `valueBoolean`)
+fn value_type(key: &str) -> Option<&str> {
+    key.strip_prefix("value")
+        .filter(|rest| rest.starts_with(|c: char| c.is_ascii_uppercase()))
+}
+
+fn parse_operation(parameter: &Value) -> Result<PatchOperation, PatchError> {
+    if parameter.get("name").and_then(Value::as_str) != Some("operation") {
+        return Err(PatchError::malformed(
+            "invalid",
+            "Every parameter of a FHIRPath Patch must be an 'operation'",
+        ));
+    }
+    let parts = parameter
+        .get("part")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let part = |name: &str| {
+        parts
+            .iter()
+            .find(|part| part.get("name").and_then(Value::as_str) == Some(name))
+    };
+    let text = |name: &str| {
+        part(name)?
+            .as_object()?
+            .iter()
+            .find(|(key, _)| value_type(key).is_some())?
+            .1
+            .as_str()
+            .map(str::to_string)
+    };
+    let integer = |name: &str| match part(name) {
+        None => Ok(None),
+        Some(part) => part
+            .get("valueInteger")
+            .and_then(Value::as_u64)
+            .map(|n| Some(n as usize))
+            .ok_or_else(|| {
+                PatchError::malformed(
+                    "invalid",
+                    format!(
+                        "The {} of an operation must be a valueInteger of 0 or more",
+                        name
+                    ),
+                )
+            }),
+    };
+
+    let kind = match text("type").as_deref() {
+        Some("add") => OperationType::Add,
+        Some("insert") => OperationType::Insert,
+        Some("delete") => OperationType::Delete,
+        Some("replace") => OperationType::Replace,
+        Some("move") => OperationType::Move,
+        Some(other) => {
+            return Err(PatchError::malformed(
+                "not-supported",
+                format!("Unknown operation type {}", other),
+            ))
+        }
+        None => {
+            return Err(PatchError::malformed(
+                "required",
+                "An operation needs a type",
+            ))
+        }
+    };
+    let path = text("path")
+        .ok_or_else(|| PatchError::malformed("required", "An operation needs a path"))?;
+    let name = text("name");
+    let element = name.as_deref().unwrap_or_else(|| last_element(&path));
+    let operation = PatchOperation {
+        kind,
+        value: part("value")
+            .map(|part| patch_value(part, element))
+            .transpose()?,
+        path,
+        name,
+        index: integer("index")?,
+        source: integer("source")?,
+        destination: integer("destination")?,
+    };
+
+    let required: &[(&str, bool)] = match kind {
+        OperationType::Add => &[
+            ("name", operation.name.is_some()),
+            ("value", operation.value.is_some()),
+        ],
+        OperationType::Insert => &[
+            ("index", operation.index.is_some()),
+            ("value", operation.value.is_some()),
+        ],
+        OperationType::Delete => &[],
+        OperationType::Replace => &[("value", operation.value.is_some())],
+        OperationType::Move => &[
+            ("source", operation.source.is_some()),
+            ("destination", operation.destination.is_some()),
+        ],
+    };
+    if let Some((missing, _)) = required.iter().find(|(_, present)| !present) {
+        return Err(PatchError::malformed(
+            "required",
+            format!("A {:?} operation needs a {}", kind, missing).to_lowercase(),
+        ));
+    }
+    Ok(operation)
+}
+
+/// The element name a path ends with
+fn last_element(path: &str) -> &str {
+    let step = path.rsplit('.').next().unwrap_or_default().trim();
+    step.split('[').next().unwrap_or_default()
+}
+
+/// The value of a `value` part for `element`, or of one of its nested parts
+fn patch_value(part: &Value, element: &str) -> Result<PatchValue, PatchError> {
+    if let Some((key, value)) = part
+        .as_object()
+        .into_iter()
+        .flatten()
+        .find(|(key, _)| value_type(key).is_some())
+    {
+        return Ok(PatchValue {
+            value: value.clone(),
+            value_type: value_type(key).map(str::to_string),
+        });
+    }
+    let Some(parts) = part.get("part").and_then(Value::as_array) else {
+        return Err(PatchError::malformed(
+            "required",
+            "A value needs a value[x] or parts",
+        ));
+    };
+
+    let mut members = Map::new();
+    for part in parts {
+        let name = part
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| PatchError::malformed("required", "A value part needs a name"))?;
+        let PatchValue { value, value_type } = patch_value(part, name)?;
+        let key = member_name(name, value_type.as_deref());
+        match members.get_mut(&key) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(_) => {
+                return Err(PatchError::malformed(
+                    "invalid",
+                    format!("{} is given twice but does not repeat", name),
+                ))
+            }
+            None if repeats(Some(element), name) => {
+                members.insert(key, Value::Array(vec![value]));
+            }
+            None => {
+                members.insert(key, value);
+            }
+        }
+    }
+    Ok(PatchValue {
+        value: Value::Object(members),
+        value_type: None,
+    })
+}
+
+/// The JSON member of element `name` holding a value of `value_type`:
+/// a choice element is suffixed with the type (`deceasedBoolean`)
+fn member_name(name: &str, value_type: Option<&str>) -> String {
+    match value_type {
+        Some(value_type) if CHOICE_ELEMENTS.contains(&name) => format!("{}{}", name, value_type),
+        _ => name.to_string(),
+    }
+}
+
+/// The choice element a JSON member is a type of (`deceased` for
+/// `deceasedBoolean`)
+fn choice_of(key: &str) -> Option<&'static str> {
+    CHOICE_ELEMENTS.iter().copied().find(|choice| {
+        key.strip_prefix(choice)
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_uppercase()))
+    })
+}
+
+/// One step from a value to a member or an item of an array
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    Member(String),
+    Item(usize),
+}
+
+fn get<'a>(value: &'a Value, location: &[Key]) -> Option<&'a Value> {
+    location.iter().try_fold(value, |value, key| match key {
+        Key::Member(name) => value.get(name),
+        Key::Item(i) => value.get(i),
+    })
+}
+
+fn get_mut<'a>(value: &'a mut Value, location: &[Key]) -> Option<&'a mut Value> {
+    location.iter().try_fold(value, |value, key| match key {
+        Key::Member(name) => value.get_mut(name),
+        Key::Item(i) => value.get_mut(i),
+    })
+}
+
+fn unsupported(expression: &str, reason: impl fmt::Display) -> PatchError {
+    PatchError::malformed(
+        "not-supported",
+        format!("Unsupported FHIRPath '{}': {}", expression, reason),
+    )
+}
+
+/// Where the elements `expression` selects are in `resource`
+fn select(resource: &Value, expression: &str) -> Result<Vec<Vec<Key>>, PatchError> {
+    let steps = split_top_level(expression, '.');
+    let resource_type = resource
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let Some((root, steps)) = steps.split_first() else {
+        return Err(unsupported(expression, "it is empty"));
+    };
+    if root.trim() != resource_type {
+        return Err(unsupported(
+            expression,
+            format!("paths start with {}", resource_type),
+        ));
+    }
+
+    let mut selected = vec![Vec::new()];
+    for step in steps {
+        selected = select_step(resource, selected, step.trim(), expression)?;
+    }
+    Ok(selected)
+}
+
+fn select_step(
+    resource: &Value,
+    selected: Vec<Vec<Key>>,
+    step: &str,
+    expression: &str,
+) -> Result<Vec<Vec<Key>>, PatchError> {
+    let (step, index) = match step
+        .strip_suffix(']')
+        .and_then(|step| step.rsplit_once('['))
+    {
+        Some((step, index)) => {
+            let index = index
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| unsupported(expression, "indexers must be numbers"))?;
+            (step, Some(index))
+        }
+        None => (step, None),
+    };
+
+    let mut next: Vec<Vec<Key>> = if is_name(step) {
+        selected
+            .iter()
+            .flat_map(|location| children(resource, location, step))
+            .collect()
+    } else if call(step, "first") == Some("") {
+        selected.into_iter().take(1).collect()
+    } else if call(step, "last") == Some("") {
+        selected.into_iter().last().into_iter().collect()
+    } else if let Some(url) = call(step, "extension") {
+        let url = string_literal(url)
+            .ok_or_else(|| unsupported(expression, "extension() takes a string"))?;
+        selected
+            .iter()
+            .flat_map(|location| children(resource, location, "extension"))
+            .filter(|location| {
+                get(resource, location).and_then(|e| e.get("url")) == Some(&Value::from(url))
+            })
+            .collect()
+    } else if let Some(criterion) = call(step, "where") {
+        let (element, value) = criterion
+            .split_once('=')
+            .and_then(|(element, value)| Some((element.trim(), string_literal(value.trim())?)))
+            .filter(|(element, _)| is_name(element))
+            .ok_or_else(|| {
+                unsupported(expression, "where() must compare an element to a string")
+            })?;
+        selected
+            .into_iter()
+            .filter(|location| {
+                get(resource, location).and_then(|item| item.get(element))
+                    == Some(&Value::from(value))
+            })
+            .collect()
+    } else {
+        return Err(unsupported(
+            expression,
+            format!("{} is not supported", step),
+        ));
+    };
+
+    if let Some(index) = index {
+        next = next.into_iter().nth(index).into_iter().collect();
+    }
+    Ok(next)
+}
+
+/// The occurrences of element `name` in the value at `location`
+fn children(resource: &Value, location: &[Key], name: &str) -> Vec<Vec<Key>> {
+    let Some(Value::Object(map)) = get(resource, location) else {
+        return Vec::new();
+    };
+    let choice = CHOICE_ELEMENTS.contains(&name);
+    map.iter()
+        .filter(|(key, _)| *key == name || (choice && choice_of(key) == Some(name)))
+        .flat_map(|(key, value)| {
+            let mut member = location.to_vec();
+            member.push(Key::Member(key.clone()));
+            match value {
+                Value::Array(items) => (0..items.len())
+                    .map(|i| {
+                        let mut item = member.clone();
+                        item.push(Key::Item(i));
+                        item
+                    })
+                    .collect(),
+                Value::Null => Vec::new(),
+                _ => vec![member],
+            }
+        })
+        .collect()
+}
+
+/// The one element `expression` selects
+fn select_one(resource: &Value, expression: &str) -> Result<Vec<Key>, PatchError> {
+    let mut selected = select(resource, expression)?;
+    match selected.len() {
+        1 => Ok(selected.remove(0)),
+        n => Err(PatchError::conflict(format!(
+            "{} selects {} elements; the operation needs exactly one",
+            expression, n
+        ))),
+    }
+}
+
+/// The parent and the name of the list `expression` names, which ends with
+/// an element name
+fn select_list<'a>(
+    resource: &Value,
+    expression: &'a str,
+) -> Result<(Vec<Key>, &'a str), PatchError> {
+    let (parent, name) = expression
+        .rsplit_once('.')
+        .map(|(parent, name)| (parent, name.trim()))
+        .filter(|(_, name)| is_name(name))
+        .ok_or_else(|| {
+            unsupported(
+                expression,
+                "insert and move need a path ending in an element name",
+            )
+        })?;
+    Ok((select_one(resource, parent)?, name))
+}
+
+/// Apply `operations` to `resource` in order
+pub fn apply(resource: &mut Value, operations: &[PatchOperation]) -> Result<(), PatchError> {
+    for (i, operation) in operations.iter().enumerate() {
+        apply_operation(resource, operation).map_err(|e| e.at(i))?;
+    }
+    Ok(())
+}
+
+fn apply_operation(resource: &mut Value, operation: &PatchOperation) -> Result<(), PatchError> {
+    let path = operation.path.as_str();
+    // Presence of the parts each type needs was checked by `parse`
+    let value = || {
+        operation
+            .value
+            .clone()
+            .ok_or_else(|| PatchError::malformed("required", "The operation needs a value"))
+    };
+    match operation.kind {
+        OperationType::Add => {
+            let name = operation.name.as_deref().unwrap_or_default();
+            let PatchValue { value, value_type } = value()?;
+            let container = select_one(resource, path)?;
+            let Some(Value::Object(map)) = get_mut(resource, &container) else {
+                return Err(PatchError::conflict(format!(
+                    "{} is not an element {} can be added to",
+                    path, name
+                )));
+            };
+            let key = member_name(name, value_type.as_deref());
+            let present = map
+                .keys()
+                .find(|existing| **existing == key || choice_of(existing) == Some(name))
+                .cloned();
+            match present.as_ref().and_then(|key| map.get_mut(key)) {
+                Some(Value::Array(items)) => items.push(value),
+                Some(Value::Null) | None => {
+                    let parent = container.iter().rev().find_map(|key| match key {
+                        Key::Member(parent) => Some(parent.as_str()),
+                        Key::Item(_) => None,
+                    });
+                    let value = if repeats(parent, name) {
+                        Value::Array(vec![value])
+                    } else {
+                        value
+                    };
+                    map.insert(key, value);
+                }
+                Some(_) => {
+                    return Err(PatchError::conflict(format!(
+                        "{}.{} already has a value; replace it instead",
+                        path, name
+                    )))
+                }
+            }
+        }
+        OperationType::Insert | OperationType::Move => {
+            let (parent, name) = select_list(resource, path)?;
+            let Some(Value::Object(map)) = get_mut(resource, &parent) else {
+                return Err(PatchError::conflict(format!("{} selects no list", path)));
+            };
+            let items = match map
+                .entry(name.to_string())
+                .or_insert_with(|| Value::Array(Vec::new()))
+            {
+                Value::Array(items) => items,
+                _ => return Err(PatchError::conflict(format!("{} is not a list", path))),
+            };
+            let out_of_range = |what: &str, index: usize, len: usize| {
+                PatchError::conflict(format!(
+                    "The {} {} is out of range; {} has {} items",
+                    what, index, path, len
+                ))
+            };
+            if operation.kind == OperationType::Insert {
+                let index = operation.index.unwrap_or_default();
+                if index > items.len() {
+                    let len = items.len();
+                    if len == 0 {
+                        map.remove(name);
+                    }
+                    return Err(out_of_range("index", index, len));
+                }
+                items.insert(index, value()?.value);
+            } else {
+                let source = operation.source.unwrap_or_default();
+                let destination = operation.destination.unwrap_or_default();
+                for (what, index) in [("source", source), ("destination", destination)] {
+                    if index >= items.len() {
+                        return Err(out_of_range(what, index, items.len()));
+                    }
+                }
+                let item = items.remove(source);
+                items.insert(destination, item);
+            }
+        }
+        OperationType::Delete => {
+            let mut selected = select(resource, path)?;
+            match selected.len() {
+                0 => {}
+                1 => remove(resource, selected.remove(0)),
+                n => {
+                    return Err(PatchError::conflict(format!(
+                        "{} selects {} elements; delete removes only one",
+                        path, n
+                    )))
+                }
+            }
+        }
+        OperationType::Replace => {
+            let PatchValue { value, value_type } = value()?;
+            let mut location = select_one(resource, path)?;
+            // A choice element may change its type
+            if let (Some(Key::Member(key)), Some(value_type)) = (location.last(), value_type) {
+                if let Some(choice) = choice_of(key) {
+                    let key = key.clone();
+                    location.pop();
+                    if let Some(Value::Object(map)) = get_mut(resource, &location) {
+                        map.remove(&key);
+                        map.insert(format!("{}{}", choice, value_type), value);
+                    }
+                    return Ok(());
+                }
+            }
+            if let Some(target) = get_mut(resource, &location) {
+                *target = value;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove the element at `location`, and its list when that becomes empty
+fn remove(resource: &mut Value, mut location: Vec<Key>) {
+    match location.pop() {
+        Some(Key::Item(i)) => {
+            let Some(Value::Array(items)) = get_mut(resource, &location) else {
+                return;
+            };
+            items.remove(i);
+            if items.is_empty() {
+                remove(resource, location);
+            }
+        }
+        Some(Key::Member(name)) => {
+            if let Some(Value::Object(map)) = get_mut(resource, &location) {
+                map.remove(&name);
+            }
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn operation(parts: Value) -> Value {
+        json!({ "name": "operation", "part": parts })
+    }
+
+    fn patch(resource: &mut Value, operations: Vec<Value>) -> Result<(), PatchError> {
+        let parameters = json!({ "resourceType": "Parameters", "parameter": operations });
+        assert!(is_fhirpath_patch(&parameters));
+        apply(resource, &parse(&parameters)?)
+    }
+
+    fn patient() -> Value {
+        json!({
+            "resourceType": "Patient",
+            "id": "1",
+            "identifier": [
+                { "system": "urn:mrn", "value": "42" },
+                { "system": "urn:ssn", "value": "123" }
+            ],
+            "name": [
+                { "use": "official", "family": "Chalmers", "given": ["Peter", "James"] },
+                { "use": "usual", "given": ["Jim"] }
+            ],
+            "deceasedBoolean": false
+        })
+    }
+
+    #[test]
+    fn test_operations() {
+        let mut resource = patient();
+        patch(
+            &mut resource,
+            vec![
+                operation(json!([
+                    { "name": "type", "valueCode": "add" },
+                    { "name": "path", "valueString": "Patient" },
+                    { "name": "name", "valueString": "birthDate" },
+                    { "name": "value", "valueDate": "1974-12-25" }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "add" },
+                    { "name": "path", "valueString": "Patient.name.where(use = 'usual')" },
+                    { "name": "name", "valueString": "given" },
+                    { "name": "value", "valueString": "Jimmy" }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "add" },
+                    { "name": "path", "valueString": "Patient" },
+                    { "name": "name", "valueString": "contact" },
+                    { "name": "value", "part": [
+                        { "name": "name", "valueHumanName": { "family": "Chalmers" } },
+                        { "name": "telecom", "valueContactPoint": { "system": "phone", "value": "555" } }
+                    ] }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "insert" },
+                    { "name": "path", "valueString": "Patient.identifier" },
+                    { "name": "index", "valueInteger": 0 },
+                    { "name": "value", "valueIdentifier": { "system": "urn:new", "value": "7" } }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "move" },
+                    { "name": "path", "valueString": "Patient.name" },
+                    { "name": "source", "valueInteger": 1 },
+                    { "name": "destination", "valueInteger": 0 }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "replace" },
+                    { "name": "path", "valueString": "Patient.name[1].given.first()" },
+                    { "name": "value", "valueString": "Pete" }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "replace" },
+                    { "name": "path", "valueString": "Patient.deceased" },
+                    { "name": "value", "valueDateTime": "2020-01-01" }
+                ])),
+                operation(json!([
+                    { "name": "type", "valueCode": "delete" },
+                    { "name": "path", "valueString": "Patient.identifier.where(system = 'urn:ssn')" }
+                ])),
+                // Deleting what is not there changes nothing
+                operation(json!([
+                    { "name": "type", "valueCode": "delete" },
+                    { "name": "path", "valueString": "Patient.telecom" }
+                ])),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            resource,
+            json!({
+                "resourceType": "Patient",
+                "id": "1",
+                "identifier": [
+                    { "system": "urn:new", "value": "7" },
+                    { "system": "urn:mrn", "value": "42" }
+                ],
+                "name": [
+                    { "use": "usual", "given": ["Jim", "Jimmy"] },
+                    { "use": "official", "family": "Chalmers", "given": ["Pete", "James"] }
+                ],
+                "deceasedDateTime": "2020-01-01",
+                "birthDate": "1974-12-25",
+                "contact": [{
+                    "name": { "family": "Chalmers" },
+                    "telecom": [{ "system": "phone", "value": "555" }]
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn test_conflicts_and_malformed_patches() {
+        let replace_name = operation(json!([
+            { "name": "type", "valueCode": "replace" },
+            { "name": "path", "valueString": "Patient.name" },
+            { "name": "value", "valueHumanName": { "family": "Doe" } }
+        ]));
+        let error = patch(&mut patient(), vec![replace_name]).unwrap_err();
+        assert!(!error.malformed);
+        assert_eq!(error.location.as_deref(), Some("Parameters.parameter[0]"));
+        assert!(error.message.contains("selects 2 elements"));
+
+        let delete_last = operation(json!([
+            { "name": "type", "valueCode": "delete" },
+            { "name": "path", "valueString": "Patient.name.last()" }
+        ]));
+        let add_existing = operation(json!([
+            { "name": "type", "valueCode": "add" },
+            { "name": "path", "valueString": "Patient" },
+            { "name": "name", "valueString": "deceased" },
+            { "name": "value", "valueBoolean": true }
+        ]));
+        let mut resource = patient();
+        let error = patch(&mut resource, vec![delete_last, add_existing]).unwrap_err();
+        assert_eq!(error.location.as_deref(), Some("Parameters.parameter[1]"));
+        assert!(error.message.contains("already has a value"));
+
+        let insert_past_end = operation(json!([
+            { "name": "type", "valueCode": "insert" },
+            { "name": "path", "valueString": "Patient.identifier" },
+            { "name": "index", "valueInteger": 5 },
+            { "name": "value", "valueIdentifier": { "value": "x" } }
+        ]));
+        assert!(
+            !patch(&mut patient(), vec![insert_past_end])
+                .unwrap_err()
+                .malformed
+        );
+
+        for (parts, code) in [
+            (
+                json!([{ "name": "type", "valueCode": "copy" }]),
+                "not-supported",
+            ),
+            (
+                json!([{ "name": "type", "valueCode": "delete" }]),
+                "required",
+            ),
+            (
+                json!([
+                    { "name": "type", "valueCode": "replace" },
+                    { "name": "path", "valueString": "Patient.name" }
+                ]),
+                "required",
+            ),
+            (
+                json!([
+                    { "name": "type", "valueCode": "delete" },
+                    { "name": "path", "valueString": "Patient.name.exists()" }
+                ]),
+                "not-supported",
+            ),
+            (
+                json!([
+                    { "name": "type", "valueCode": "delete" },
+                    { "name": "path", "valueString": "Observation.code" }
+                ]),
+                "not-supported",
+            ),
+        ] {
+            let error = patch(&mut patient(), vec![operation(parts)]).unwrap_err();
+            assert!(error.malformed);
+            assert_eq!(error.code, code, "{}", error);
+        }
+        assert!(parse(&json!({ "resourceType": "Parameters" })).is_err());
+    }
+}
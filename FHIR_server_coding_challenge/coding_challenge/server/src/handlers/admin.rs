@@ -0,0 +1,1258 @@
+//! Operator endpoints outside the FHIR API. Not meant to be reachable by
+//! clients; restrict `/admin` at the proxy, or with an
+//! [API key](crate::api_keys) once one exists.
+
+use super::check_validation_hooks;
+use super::mapping::Mapping;
+use super::observation::validate_observation;
+use super::resource::check_served;
+use crate::api_keys::{generate_key, hash_key, ApiKeyLimits};
+use crate::archive::{ArchiveReport, ArchiveRun, Archiver};
+use crate::capture::CapturedExchange;
+use crate::collection;
+use crate::config::SharedConfig;
+use crate::db::about::{migration_level, Component, ExtensionReport};
+use crate::db::api_key::StoredApiKey;
+use crate::db::import::{ImportFailure, ImportRecord};
+use crate::db::snapshot::{is_valid_snapshot_name, Snapshot};
+use crate::db::Database;
+use crate::extract::Query;
+use crate::features::{FeatureFlags, FeatureSetting};
+use crate::migrations::APP_SCHEMA_VERSION;
+use crate::models::{Observation, OperationOutcome, Patient};
+use crate::profiles::ProfilePack;
+use crate::search::SearchParamRegistry;
+use crate::tenant_export::{self, MIN_PASSPHRASE_CHARS};
+use crate::validation::ValidationHooks;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+type ErrorResponse = (StatusCode, Json<OperationOutcome>);
+
+/// Body of `GET /admin/about`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct About {
+    pub version: &'static str,
+    /// Commit the server was built from; `unknown` when the build had
+    /// neither `GIT_SHA` nor a git checkout
+    pub git_sha: &'static str,
+    pub storage: StorageReport,
+    pub migrations: MigrationReport,
+    /// The optional parts of the schema and whether this database has them
+    pub components: Vec<Component>,
+    /// Feature flags set in the environment, by the provider or in the
+    /// config, with the setting that applies
+    pub features: BTreeMap<String, FeatureSetting>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageReport {
+    /// `sql`: resources are written to `fhir_resources` with SQL;
+    /// `sql+shadow` when the writes are replayed through the extension
+    /// functions as well (`shadow_verify`)
+    pub strategy: &'static str,
+    pub extension: ExtensionReport,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationReport {
+    /// Latest migration this server is built for
+    pub latest: i32,
+    /// Latest migration the database has, judged by its components
+    pub level: i32,
+    /// Phase of each `fhir-server migrate` column change, by name
+    pub schema_changes: BTreeMap<String, String>,
+}
+
+/// Version, build and storage of this server and the migrations and
+/// features it runs with, for support tickets
+pub async fn get_about(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(features): State<Arc<FeatureFlags>>,
+) -> Result<Json<About>, ErrorResponse> {
+    let failed = |e: anyhow::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to inspect the database: {}", e),
+            )),
+        )
+    };
+    let extension = db.extension_report().await.map_err(failed)?;
+    let schema_changes = if db.schema_changes_enabled() {
+        db.schema_changes()
+            .await
+            .map_err(failed)?
+            .into_iter()
+            .map(|change| (change.name, change.phase))
+            .collect()
+    } else {
+        BTreeMap::new()
+    };
+    let components = db.components();
+
+    Ok(Json(About {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: option_env!("FHIR_SERVER_GIT_SHA").unwrap_or("unknown"),
+        storage: StorageReport {
+            strategy: if config.get().shadow_verify {
+                "sql+shadow"
+            } else {
+                "sql"
+            },
+            extension,
+        },
+        migrations: MigrationReport {
+            latest: APP_SCHEMA_VERSION,
+            level: migration_level(&components, APP_SCHEMA_VERSION),
+            schema_changes,
+        },
+        components,
+        features: features.settings(&config),
+    }))
+}
+
+/// Postman collection of the routes of this server; see [`crate::collection`]
+pub async fn get_collection() -> Json<Value> {
+    Json(collection::collection(&super::history::base_url()))
+}
+
+/// Captured exchange of a failed request, by the `X-Request-Id` it was answered with
+pub async fn get_request_capture(
+    State(db): State<Arc<Database>>,
+    Path(request_id): Path<String>,
+) -> Result<Json<CapturedExchange>, ErrorResponse> {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            Json(OperationOutcome::error(
+                "not-found",
+                format!(
+                    "No capture for request {}; only failed requests are captured while request_capture is enabled, and old captures are overwritten",
+                    request_id
+                ),
+            )),
+        )
+    };
+    let Ok(id) = Uuid::parse_str(&request_id) else {
+        return Err(not_found());
+    };
+
+    match db.get_capture(id).await {
+        Ok(Some(exchange)) => Ok(Json(exchange)),
+        Ok(None) => Err(not_found()),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to read request capture: {}", e),
+            )),
+        )),
+    }
+}
+
+fn archive_unavailable() -> ErrorResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "Archiving is not available on this server: the fhir.resource_archive table is missing",
+        )),
+    )
+}
+
+/// Resource counts of the hot and archive tiers and the last sweep
+pub async fn get_archive_report(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(archiver): State<Arc<Archiver>>,
+) -> Result<Json<ArchiveReport>, ErrorResponse> {
+    if !db.archive_enabled() {
+        return Err(archive_unavailable());
+    }
+    let settings = config.get().archive.clone();
+    let resource_types = db.archive_counts(settings.after_years).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to count archived resources: {}", e),
+            )),
+        )
+    })?;
+
+    Ok(Json(ArchiveReport {
+        enabled: settings.enabled,
+        after_years: settings.after_years,
+        resource_types,
+        last_run: archiver.last_run(),
+    }))
+}
+
+/// Sweep now with the configured `after_years`, whether or not the
+/// background sweep is enabled
+pub async fn run_archive(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(archiver): State<Arc<Archiver>>,
+) -> Result<Json<ArchiveRun>, ErrorResponse> {
+    if !db.archive_enabled() {
+        return Err(archive_unavailable());
+    }
+    let run = archiver.run(&db, &config.get().archive).await;
+    match &run.error {
+        Some(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!(
+                    "Archive sweep failed after {} resources: {}",
+                    run.archived, e
+                ),
+            )),
+        )),
+        None => Ok(Json(run)),
+    }
+}
+
+/// Largest package `POST /admin/packages` accepts
+pub const MAX_PACKAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Body of `POST /admin/packages`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageReport {
+    pub name: String,
+    pub version: String,
+    /// Conformance resources stored, as `Type/id`
+    pub stored: Vec<String>,
+    /// Conformance resources already stored under the same url and version
+    pub unchanged: Vec<String>,
+    /// Search parameters made searchable, as `Base.code`
+    pub search_parameters: Vec<String>,
+    /// URLs of the profiles now checked on writes
+    pub profiles: Vec<String>,
+    /// Definitions that were not imported, and why
+    pub skipped: Vec<String>,
+}
+
+/// Import an implementation guide package (`.tgz`) like a configured
+/// [profile pack](crate::profiles) and store its conformance resources.
+/// The package's resources are not run through the validation hooks. Its
+/// search parameters and profiles stay active until the server restarts;
+/// the stored resources stay.
+pub async fn import_package(
+    State(db): State<Arc<Database>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    body: Bytes,
+) -> Result<Json<PackageReport>, ErrorResponse> {
+    let pack = ProfilePack::from_tgz(body.as_ref()).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "structure",
+                format!("Failed to read package: {:#}", e),
+            )),
+        )
+    })?;
+    let import = db.import_conformance(&pack.resources).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to store package {}: {}", pack.name, e),
+            )),
+        )
+    })?;
+
+    let search_parameters = pack
+        .register(&registry)
+        .iter()
+        .map(|param| format!("{}.{}", param.base[0], param.code))
+        .collect();
+    hooks.add_profiles(pack.profiles.clone());
+    tracing::info!(
+        "Imported package {}#{}: {} resources stored, {} unchanged",
+        pack.name,
+        pack.version,
+        import.stored.len(),
+        import.existing.len()
+    );
+
+    Ok(Json(PackageReport {
+        stored: import
+            .stored
+            .iter()
+            .map(|stored| format!("{}/{}", stored.resource_type, stored.id))
+            .collect(),
+        unchanged: import.existing.iter().map(ToString::to_string).collect(),
+        search_parameters,
+        profiles: pack.profiles.iter().map(|p| p.url.clone()).collect(),
+        name: pack.name,
+        version: pack.version,
+        skipped: pack.skipped,
+    }))
+}
+
+/// Largest NDJSON body `POST /admin/import` accepts
+pub const MAX_IMPORT_BYTES: usize = 256 * 1024 * 1024;
+
+/// Lines `POST /admin/import` stores per transaction
+const IMPORT_BATCH: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    /// `ConceptMap/<id>` or `StructureMap/<id>` to rewrite the lines with
+    map: Option<String>,
+}
+
+/// Body of `POST /admin/import`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    /// Resources stored
+    pub imported: usize,
+    /// Lines that were not stored, with line numbers counted from 1
+    pub errors: Vec<ImportFailure>,
+}
+
+/// The error issues of a rejected line, as one message
+fn rejection_message((_, Json(outcome)): ErrorResponse) -> String {
+    outcome
+        .issue
+        .iter()
+        .filter(|issue| matches!(issue.severity.as_str(), "error" | "fatal"))
+        .filter_map(|issue| issue.diagnostics.clone())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Check one imported resource as `POST /fhir/:resourceType` checks a
+/// body, after rewriting it with `mapping`. The resource's `id` is kept so
+/// references between imported resources still resolve; resources without
+/// one get a new id.
+async fn import_record(
+    db: &Database,
+    config: &SharedConfig,
+    hooks: &ValidationHooks,
+    mapping: Option<&Mapping>,
+    line: usize,
+    resource: Value,
+) -> Result<ImportRecord, String> {
+    let resource = match mapping {
+        Some(mapping) => mapping.apply(resource).map_err(|e| e.to_string())?,
+        None => resource,
+    };
+    let resource_type = resource
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .ok_or("Missing resourceType")?
+        .to_string();
+    let id = match resource.get("id") {
+        None => Uuid::new_v4(),
+        Some(id) => id
+            .as_str()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .ok_or_else(|| format!("Invalid id {}: resources are stored under UUIDs", id))?,
+    };
+
+    let resource = match resource_type.as_str() {
+        "Patient" => {
+            let mut patient: Patient =
+                serde_json::from_value(resource).map_err(|e| format!("Invalid Patient: {}", e))?;
+            patient.id = None;
+            check_validation_hooks(db, hooks, "create", &patient)
+                .await
+                .map_err(rejection_message)?;
+            serde_json::to_value(&patient).map_err(|e| e.to_string())?
+        }
+        "Observation" => {
+            let observation: Observation = serde_json::from_value(resource)
+                .map_err(|e| format!("Invalid Observation: {}", e))?;
+            validate_observation(&observation).map_err(rejection_message)?;
+            check_validation_hooks(db, hooks, "create", &observation)
+                .await
+                .map_err(rejection_message)?;
+            serde_json::to_value(&observation).map_err(|e| e.to_string())?
+        }
+        _ => {
+            check_served(config, &resource_type).map_err(rejection_message)?;
+            check_validation_hooks(db, hooks, "create", &resource)
+                .await
+                .map_err(rejection_message)?;
+            resource
+        }
+    };
+    Ok(ImportRecord {
+        line,
+        resource_type,
+        id,
+        resource,
+    })
+}
+
+/// Import resources exported by another FHIR server, one JSON resource per
+/// line. Lines are checked like creates and stored in batches of
+/// [`IMPORT_BATCH`], each in one transaction; a line that fails is reported
+/// and the others are stored. Resources keep their `id`, which must be a
+/// UUID. With `map=ConceptMap/<id>` or `map=StructureMap/<id>` every line is
+/// first rewritten as `$transform` would.
+pub async fn import_resources(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Query(params): Query<ImportParams>,
+    body: Bytes,
+) -> Result<Json<ImportReport>, ErrorResponse> {
+    let mapping = match params.map.as_deref() {
+        None => None,
+        Some(map) => match map.split_once('/') {
+            Some((resource_type @ ("ConceptMap" | "StructureMap"), id)) => {
+                Some(Mapping::load(&db, resource_type, id).await?)
+            }
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(OperationOutcome::error_with_location(
+                        "invalid",
+                        format!(
+                            "Invalid map {}: expected ConceptMap/<id> or StructureMap/<id>",
+                            map
+                        ),
+                        "map",
+                    )),
+                ))
+            }
+        },
+    };
+    let lines = body
+        .split(|&byte| byte == b'\n')
+        .enumerate()
+        .filter_map(ndjson_line);
+    let report = import_records(&db, &config, &hooks, mapping.as_ref(), lines).await?;
+    tracing::info!(
+        target: "fhir_server::audit",
+        imported = report.imported,
+        failed = report.errors.len(),
+        "NDJSON import"
+    );
+
+    Ok(Json(report))
+}
+
+/// The resource on the line at `index`, counted from 1; `None` for
+/// blank lines
+fn ndjson_line((index, bytes): (usize, &[u8])) -> Option<(usize, Result<Value, String>)> {
+    let resource = match std::str::from_utf8(bytes) {
+        Ok(text) if text.trim().is_empty() => return None,
+        Ok(text) => serde_json::from_str(text).map_err(|e| format!("Invalid JSON: {}", e)),
+        Err(_) => Err("Line is not UTF-8".to_string()),
+    };
+    Some((index + 1, resource))
+}
+
+/// Check each resource of `resources`, read from the line it is paired
+/// with, and store those that pass in batches of [`IMPORT_BATCH`]
+async fn import_records(
+    db: &Database,
+    config: &SharedConfig,
+    hooks: &ValidationHooks,
+    mapping: Option<&Mapping>,
+    resources: impl Iterator<Item = (usize, Result<Value, String>)>,
+) -> Result<ImportReport, ErrorResponse> {
+    let mut report = ImportReport {
+        imported: 0,
+        errors: Vec::new(),
+    };
+    let mut batch = Vec::with_capacity(IMPORT_BATCH);
+    let mut resources = resources.peekable();
+    while let Some((line, resource)) = resources.next() {
+        let record = match resource {
+            Ok(resource) => import_record(db, config, hooks, mapping, line, resource).await,
+            Err(message) => Err(message),
+        };
+        match record {
+            Ok(record) => batch.push(record),
+            Err(message) => report.errors.push(ImportFailure { line, message }),
+        }
+
+        if batch.len() == IMPORT_BATCH || (resources.peek().is_none() && !batch.is_empty()) {
+            let failures = db.import_batch(&batch).await.map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(OperationOutcome::error(
+                        "processing",
+                        format!(
+                            "Failed to store lines {} to {}, after importing {} resources: {}",
+                            batch[0].line, line, report.imported, e
+                        ),
+                    )),
+                )
+            })?;
+            report.imported += batch.len() - failures.len();
+            report.errors.extend(failures);
+            batch.clear();
+        }
+    }
+    report.errors.sort_by_key(|failure| failure.line);
+    Ok(report)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CsvImportParams {
+    /// Name of the `[csv_mappings.<name>]` entry the roster is read with
+    mapping: String,
+}
+
+/// Import a CSV patient roster, each row made a Patient with the
+/// `[csv_mappings.<name>]` entry `mapping` names (see [`crate::roster`]).
+/// Rows are checked and stored like the lines of `POST /admin/import`, and
+/// reported by the line they start on, the header being line 1.
+pub async fn import_csv_roster(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Query(params): Query<CsvImportParams>,
+    body: Bytes,
+) -> Result<Json<ImportReport>, ErrorResponse> {
+    let invalid = |message: String, location: &str| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid", message, location,
+            )),
+        )
+    };
+    let Some(mapping) = config.get().csv_mappings.get(&params.mapping).cloned() else {
+        return Err(invalid(
+            format!(
+                "No CSV mapping named {}; mappings are configured under [csv_mappings]",
+                params.mapping
+            ),
+            "mapping",
+        ));
+    };
+    let csv = std::str::from_utf8(&body)
+        .map_err(|_| invalid("The roster is not UTF-8".to_string(), "body"))?;
+    let rows = mapping.patients(csv).map_err(|e| invalid(e, "body"))?;
+    let report = import_records(&db, &config, &hooks, None, rows.into_iter()).await?;
+    tracing::info!(
+        target: "fhir_server::audit",
+        mapping = %params.mapping,
+        imported = report.imported,
+        failed = report.errors.len(),
+        "CSV roster import"
+    );
+
+    Ok(Json(report))
+}
+
+fn snapshots_unavailable() -> ErrorResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "Snapshots are not available on this server: the fhir.snapshot table is missing",
+        )),
+    )
+}
+
+fn snapshot_not_found(name: &str) -> ErrorResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(OperationOutcome::error(
+            "not-found",
+            format!("No snapshot named {}", name),
+        )),
+    )
+}
+
+fn snapshot_error(action: &str, name: &str, e: anyhow::Error) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!("Failed to {} snapshot {}: {}", action, name, e),
+        )),
+    )
+}
+
+/// Stored snapshots, oldest first
+pub async fn list_snapshots(
+    State(db): State<Arc<Database>>,
+) -> Result<Json<Vec<Snapshot>>, ErrorResponse> {
+    if !db.snapshots_enabled() {
+        return Err(snapshots_unavailable());
+    }
+    db.list_snapshots()
+        .await
+        .map(Json)
+        .map_err(|e| snapshot_error("list", "names", e))
+}
+
+/// Snapshot the whole dataset under `name`, which must not be taken
+pub async fn create_snapshot(
+    State(db): State<Arc<Database>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Snapshot>), ErrorResponse> {
+    if !db.snapshots_enabled() {
+        return Err(snapshots_unavailable());
+    }
+    if !is_valid_snapshot_name(&name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "value",
+                "Snapshot names are 1 to 64 letters, digits, '-', '_' or '.'",
+            )),
+        ));
+    }
+
+    match db.create_snapshot(&name).await {
+        Ok(Some(snapshot)) => {
+            tracing::info!(
+                target: "fhir_server::audit",
+                snapshot = %name,
+                resources = snapshot.resources,
+                "dataset snapshot taken"
+            );
+            Ok((StatusCode::CREATED, Json(snapshot)))
+        }
+        Ok(None) => Err((
+            StatusCode::CONFLICT,
+            Json(OperationOutcome::error(
+                "duplicate",
+                format!("A snapshot named {} already exists; delete it first", name),
+            )),
+        )),
+        Err(e) => Err(snapshot_error("create", &name, e)),
+    }
+}
+
+/// Replace the whole dataset with snapshot `name`, if
+/// `allow_snapshot_restore` is set. Custom search parameters are re-read,
+/// since the snapshot may hold other ones.
+pub async fn restore_snapshot(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    Path(name): Path<String>,
+) -> Result<Json<Snapshot>, ErrorResponse> {
+    if !config.get().allow_snapshot_restore {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(OperationOutcome::error(
+                "forbidden",
+                "Restoring a snapshot replaces the whole dataset; set allow_snapshot_restore = true to allow it",
+            )),
+        ));
+    }
+    if !db.snapshots_enabled() {
+        return Err(snapshots_unavailable());
+    }
+    let snapshot = db
+        .restore_snapshot(&name)
+        .await
+        .map_err(|e| snapshot_error("restore", &name, e))?
+        .ok_or_else(|| snapshot_not_found(&name))?;
+    tracing::warn!(
+        target: "fhir_server::audit",
+        snapshot = %name,
+        resources = snapshot.resources,
+        "dataset restored from snapshot"
+    );
+
+    let custom_params = db
+        .custom_search_params()
+        .await
+        .map_err(|e| snapshot_error("restore", &name, e))?;
+    for param in registry.list(None).iter().filter(|p| p.indexed) {
+        registry.remove_indexed(&param.id);
+    }
+    for param in custom_params {
+        registry.register(param);
+    }
+
+    Ok(Json(snapshot))
+}
+
+pub async fn delete_snapshot(
+    State(db): State<Arc<Database>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ErrorResponse> {
+    if !db.snapshots_enabled() {
+        return Err(snapshots_unavailable());
+    }
+    match db.delete_snapshot(&name).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(snapshot_not_found(&name)),
+        Err(e) => Err(snapshot_error("delete", &name, e)),
+    }
+}
+
+/// Body of `POST /admin/api-keys`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewApiKey {
+    pub name: String,
+    #[serde(flatten)]
+    pub limits: ApiKeyLimits,
+}
+
+/// A key just created, the only time it is shown
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub created: NewApiKey,
+}
+
+fn api_keys_unavailable(migration: &str) -> ErrorResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            format!("API keys with these settings need migration {}", migration),
+        )),
+    )
+}
+
+fn api_key_not_found(name: &str) -> ErrorResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(OperationOutcome::error(
+            "not-found",
+            format!("No API key named {}", name),
+        )),
+    )
+}
+
+fn api_key_error(action: &str, name: &str, e: anyhow::Error) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!("Failed to {} API key {}: {}", action, name, e),
+        )),
+    )
+}
+
+fn invalid_api_key(message: String) -> ErrorResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error("value", message)),
+    )
+}
+
+/// Refuse limits that are invalid or that this schema cannot store
+fn check_api_key_limits(db: &Database, limits: &ApiKeyLimits) -> Result<(), ErrorResponse> {
+    limits.validate().map_err(invalid_api_key)?;
+    if !limits.is_unlimited() && !db.api_key_scopes_enabled() {
+        return Err(api_keys_unavailable("027_api_key_scopes.sql"));
+    }
+    Ok(())
+}
+
+/// Stored keys, without their hashes, by name
+pub async fn list_api_keys(
+    State(db): State<Arc<Database>>,
+) -> Result<Json<Vec<StoredApiKey>>, ErrorResponse> {
+    if !db.api_keys_enabled() {
+        return Err(api_keys_unavailable("019_api_keys.sql"));
+    }
+    db.list_api_keys()
+        .await
+        .map(Json)
+        .map_err(|e| api_key_error("list", "names", e))
+}
+
+/// Create a key with the posted name and limits; the response holds the
+/// key, which cannot be recovered later
+pub async fn create_api_key(
+    State(db): State<Arc<Database>>,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), ErrorResponse> {
+    if !db.api_keys_enabled() {
+        return Err(api_keys_unavailable("019_api_keys.sql"));
+    }
+    let new: NewApiKey =
+        serde_json::from_value(body).map_err(|e| invalid_api_key(e.to_string()))?;
+    check_api_key_limits(&db, &new.limits)?;
+    if !is_valid_snapshot_name(&new.name) {
+        return Err(invalid_api_key(
+            "API key names are 1 to 64 letters, digits, '-', '_' or '.'".to_string(),
+        ));
+    }
+
+    let key = generate_key();
+    match db
+        .create_api_key(&new.name, &hash_key(&key), &new.limits)
+        .await
+    {
+        Ok(true) => {
+            tracing::info!(
+                target: "fhir_server::audit",
+                key = %new.name,
+                scopes = ?new.limits.scopes,
+                requests_per_minute = ?new.limits.requests_per_minute,
+                "API key created"
+            );
+            Ok((
+                StatusCode::CREATED,
+                Json(CreatedApiKey { key, created: new }),
+            ))
+        }
+        Ok(false) => Err((
+            StatusCode::CONFLICT,
+            Json(OperationOutcome::error(
+                "duplicate",
+                format!(
+                    "An API key named {} already exists; delete it first",
+                    new.name
+                ),
+            )),
+        )),
+        Err(e) => Err(api_key_error("create", &new.name, e)),
+    }
+}
+
+/// Replace the scopes and rate limit of the key `name`
+pub async fn update_api_key(
+    State(db): State<Arc<Database>>,
+    Path(name): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Json<StoredApiKey>, ErrorResponse> {
+    if !db.api_key_scopes_enabled() {
+        return Err(api_keys_unavailable("027_api_key_scopes.sql"));
+    }
+    let limits: ApiKeyLimits =
+        serde_json::from_value(body).map_err(|e| invalid_api_key(e.to_string()))?;
+    check_api_key_limits(&db, &limits)?;
+    let updated = db
+        .update_api_key(&name, &limits)
+        .await
+        .map_err(|e| api_key_error("update", &name, e))?
+        .ok_or_else(|| api_key_not_found(&name))?;
+    tracing::info!(
+        target: "fhir_server::audit",
+        key = %name,
+        scopes = ?limits.scopes,
+        requests_per_minute = ?limits.requests_per_minute,
+        "API key limits changed"
+    );
+    Ok(Json(updated))
+}
+
+/// Revoke the key `name`
+pub async fn delete_api_key(
+    State(db): State<Arc<Database>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ErrorResponse> {
+    if !db.api_keys_enabled() {
+        return Err(api_keys_unavailable("019_api_keys.sql"));
+    }
+    match db.delete_api_key(&name).await {
+        Ok(true) => {
+            tracing::warn!(
+                target: "fhir_server::audit",
+                key = %name,
+                "API key revoked"
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(api_key_not_found(&name)),
+        Err(e) => Err(api_key_error("delete", &name, e)),
+    }
+}
+
+/// Body of `POST /admin/tenants/:organization/export`
+#[derive(Debug, Deserialize)]
+pub struct TenantExportRequest {
+    /// What the archive is encrypted under; it is not stored
+    pub passphrase: String,
+}
+
+fn export_error(organization: &str, e: anyhow::Error) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!(
+                "Failed to export tenant Organization/{}: {}",
+                organization, e
+            ),
+        )),
+    )
+}
+
+/// The resources, history and audit trail of the tenant of Organization
+/// `organization`, as an archive encrypted under the posted passphrase
+pub async fn export_tenant(
+    State(db): State<Arc<Database>>,
+    Path(organization): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Response, ErrorResponse> {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            Json(OperationOutcome::error(
+                "not-found",
+                format!("No Organization/{}", organization),
+            )),
+        )
+    };
+    let request: TenantExportRequest = serde_json::from_value(body).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error("value", e.to_string())),
+        )
+    })?;
+    if request.passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "value",
+                format!(
+                    "The passphrase must have at least {} characters",
+                    MIN_PASSPHRASE_CHARS
+                ),
+            )),
+        ));
+    }
+    let id = Uuid::try_parse(&organization).map_err(|_| not_found())?;
+
+    let data = db
+        .tenant_data(id)
+        .await
+        .map_err(|e| export_error(&organization, e))?
+        .ok_or_else(not_found)?;
+    let exported_at = db.now();
+    let (resources, history, audit) = (data.resources.len(), data.history.len(), data.audit.len());
+    // Deriving the key takes a moment of CPU on purpose
+    let sealed = tokio::task::spawn_blocking(move || {
+        tenant_export::seal(
+            &request.passphrase,
+            tenant_export::archive(id, exported_at, &data)?,
+        )
+    })
+    .await
+    .map_err(|e| export_error(&organization, e.into()))?
+    .map_err(|e| export_error(&organization, e))?;
+    tracing::warn!(
+        target: "fhir_server::audit",
+        organization = %id,
+        resources,
+        history,
+        audit,
+        bytes = sealed.len(),
+        "tenant data exported"
+    );
+
+    let filename = format!(
+        "tenant-{}-{}.fhirx",
+        id,
+        exported_at.format("%Y%m%dT%H%M%SZ")
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        sealed,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::Request;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    async fn app() -> axum::Router {
+        app_with(crate::config::ServerConfig::default()).await
+    }
+
+    async fn app_with(config: crate::config::ServerConfig) -> axum::Router {
+        crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config)),
+        ))
+    }
+
+    async fn send(app: &axum::Router, request: Request) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn import(lines: &[String]) -> Request {
+        Request::post("/admin/import")
+            .header("content-type", "application/fhir+ndjson")
+            .body(Body::from(lines.join("\n")))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_restore_needs_opt_in() {
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/admin/snapshots/before-suite/restore")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, outcome) = send(&app().await, request()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(outcome["issue"][0]["code"], "forbidden");
+
+        let config = crate::config::ServerConfig {
+            allow_snapshot_restore: true,
+            ..Default::default()
+        };
+        let (status, _) = send(&app_with(config).await, request()).await;
+        assert_ne!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_requests_are_checked() {
+        let app = app().await;
+        let create = |body: Value| {
+            Request::post("/admin/api-keys")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let (status, outcome) = send(
+            &app,
+            create(json!({ "name": "etl", "scopes": ["patient/Patient.read"] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["issue"][0]["code"], "value");
+        let (status, _) = send(&app, create(json!({ "name": "no spaces" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            &app,
+            create(json!({ "name": "etl", "requestsPerMinute": 0 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let missing = format!("/admin/api-keys/missing-{}", Uuid::new_v4().simple());
+        let (status, _) = send(
+            &app,
+            Request::delete(missing.as_str())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_export_is_sealed() {
+        let app = app().await;
+        let organization = setup_test_db()
+            .await
+            .create_resource("Organization", json!({ "resourceType": "Organization" }))
+            .await
+            .unwrap();
+        let export = |id: String, passphrase: &str| {
+            Request::post(format!("/admin/tenants/{}/export", id))
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "passphrase": passphrase }).to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(export(organization.id.to_string(), "a long enough secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let sealed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let archive = tenant_export::open("a long enough secret", &sealed).unwrap();
+        assert!(!archive.is_empty());
+
+        let (status, _) = send(&app, export(organization.id.to_string(), "short")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            &app,
+            export(Uuid::new_v4().to_string(), "a long enough secret"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_import_csv_roster() {
+        let config = crate::config::ServerConfig::parse(
+            "[csv_mappings.registration]\n\
+             columns = { MRN = \"identifier.value\", Surname = \"name.family\", Active = \"active\" }\n\
+             fixed = { \"identifier.system\" = \"urn:roster-test\" }",
+        )
+        .unwrap();
+        let app = app_with(config).await;
+        let family = format!("Roster{}", Uuid::new_v4().simple());
+        let roster = |mapping: &str, csv: String| {
+            Request::post(format!("/admin/import/csv?mapping={}", mapping))
+                .header("content-type", "text/csv")
+                .body(Body::from(csv))
+                .unwrap()
+        };
+
+        let csv = format!(
+            "MRN,Surname,Active\r\n{0},\"{0}\",yes\r\n{0}-2,{0},maybe\r\n{0}-3\r\n",
+            family
+        );
+        let (status, report) = send(&app, roster("registration", csv)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["imported"], 1);
+        let lines: Vec<&Value> = report["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| &e["line"])
+            .collect();
+        assert_eq!(lines, [3, 4]);
+
+        let (_, bundle) = send(
+            &app,
+            Request::get(format!(
+                "/fhir/Patient?identifier=urn:roster-test|{}",
+                family
+            ))
+            .body(Body::empty())
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(bundle["total"], 1);
+        assert_eq!(bundle["entry"][0]["resource"]["name"][0]["family"], family);
+        assert_eq!(bundle["entry"][0]["resource"]["active"], true);
+
+        let (status, outcome) = send(&app, roster("registration", "MRN\n1".to_string())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["issue"][0]["location"][0], "body");
+        let (status, outcome) = send(&app, roster("missing", String::new())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["issue"][0]["location"][0], "mapping");
+    }
+
+    #[tokio::test]
+    async fn test_import_reports_failed_lines() {
+        let app = app().await;
+        let family = format!("Import{}", Uuid::new_v4().simple());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let patient = |id: Uuid, mrn: &str| {
+            json!({
+                "resourceType": "Patient",
+                "id": id,
+                "name": [{ "family": family }],
+                "identifier": [{ "system": "urn:import-test", "value": mrn }]
+            })
+            .to_string()
+        };
+
+        // Stored with the multi-row insert
+        let (status, report) = send(
+            &app,
+            import(&[patient(first, &family), patient(second, "other")]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report, json!({ "imported": 2, "errors": [] }));
+
+        // A duplicate id makes the batch fall back to one row at a time
+        let observation = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4" }] },
+            "subject": { "reference": format!("Patient/{}", first) }
+        });
+        let mut invalid = observation.clone();
+        invalid["status"] = json!("bogus");
+        let (status, report) = send(
+            &app,
+            import(&[
+                observation.to_string(),
+                String::new(),
+                "{not json".to_string(),
+                patient(first, "again"),
+                json!({ "resourceType": "Patient", "id": "123" }).to_string(),
+                invalid.to_string(),
+            ]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["imported"], 1);
+        let lines: Vec<&Value> = report["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| &e["line"])
+            .collect();
+        assert_eq!(lines, [3, 4, 5, 6]);
+        assert_eq!(
+            report["errors"][1]["message"],
+            format!("Patient/{} already exists", first)
+        );
+
+        // Imported resources are indexed like created ones
+        let request = |uri: String| Request::get(uri).body(Body::empty()).unwrap();
+        let (_, bundle) = send(
+            &app,
+            request(format!(
+                "/fhir/Patient?identifier=urn:import-test|{}",
+                family
+            )),
+        )
+        .await;
+        assert_eq!(bundle["total"], 1);
+        assert_eq!(bundle["entry"][0]["resource"]["id"], first.to_string());
+        let (_, bundle) = send(&app, request(format!("/fhir/Patient/{}/_history", second))).await;
+        assert_eq!(bundle["total"], 1);
+        let (_, bundle) = send(
+            &app,
+            request(format!("/fhir/Observation?subject=Patient/{}", first)),
+        )
+        .await;
+        let observation_id = bundle["entry"][0]["resource"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        for path in [
+            format!("/fhir/Observation/{}", observation_id),
+            format!("/fhir/Patient/{}", first),
+            format!("/fhir/Patient/{}", second),
+        ] {
+            let delete = Request::delete(path).body(Body::empty()).unwrap();
+            assert_eq!(send(&app, delete).await.0, StatusCode::NO_CONTENT);
+        }
+    }
+}
@@ -0,0 +1,1026 @@
+//! `POST /fhir`: batch and transaction Bundles.
+//!
+//! Every entry names one interaction in `entry.request`: `POST <Type>`,
+//! `PUT <Type>/<id>` (with `ifMatch` for Patients), `DELETE <Type>/<id>` or
+//! `GET <Type>/<id>`. Entries are checked like the REST call they stand for,
+//! so served types, validation hooks, Observation status, identifier
+//! uniqueness and write limits all apply.
+//!
+//! A `transaction` runs its entries in one SQL transaction: the first entry
+//! that fails rolls back the others and its error is the response. A `batch`
+//! commits each entry on its own and reports failures in the entry's
+//! `response.outcome`. Entries are processed DELETE, POST, PUT, then GET and
+//! answered in request order. References to the `urn:uuid:` fullUrl of a
+//! created entry are rewritten to the id the server assigns it.
+//!
+//! Memory stays close to the size of the request body, up to
+//! `max_bundle_bytes`: entries borrow from the body and their resource is
+//! decoded only while the entry runs, and each response entry is encoded as
+//! soon as its entry is done and sent as a chunk of its own.
+//! `Prefer: return=minimal` leaves the resources out of the response.
+
+use super::lock::{lock_refusal, lock_token_hash};
+use super::observation::validate_observation;
+use super::patient::{if_match_required, missing_patient, version_etag, write_error};
+use super::resource::{
+    check_resource_type, check_served, not_found, processing_error,
+    write_error as resource_write_error, ErrorResponse,
+};
+use super::{check_validation_hooks, prefers, transform_error};
+use crate::access;
+use crate::consent::ConsentFilter;
+use crate::db::{BundleTransaction, PatientPut};
+use crate::limits::{is_length_limit, unreadable_body, ResourceLimits};
+use crate::metrics::Metrics;
+use crate::models::{Observation, OperationOutcome, Patient};
+use crate::negotiation::has_json_body;
+use crate::provenance::{Activity, Attribution};
+use crate::state::AppState;
+use crate::throttle::throttled_outcome;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::error::Category;
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use uuid::Uuid;
+
+/// Interactions an entry may request, in the order they are processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Interaction {
+    Delete,
+    Create,
+    Update,
+    Read,
+}
+
+/// A parsed `Bundle.entry`
+#[derive(Debug)]
+struct Entry<'a> {
+    interaction: Interaction,
+    resource_type: String,
+    /// Target of an update, delete or read; the assigned id of a create
+    id: String,
+    full_url: Option<String>,
+    /// Still encoded in the request body; decoded when the entry runs
+    resource: Option<&'a RawValue>,
+    if_match: Option<String>,
+}
+
+/// The members of a `Bundle.entry` that are read before it runs
+#[derive(Default, Deserialize)]
+struct RawEntry<'a> {
+    #[serde(rename = "fullUrl")]
+    full_url: Option<Value>,
+    request: Option<Value>,
+    #[serde(borrow)]
+    resource: Option<&'a RawValue>,
+}
+
+#[derive(Deserialize)]
+struct ResourceHead {
+    #[serde(rename = "resourceType")]
+    resource_type: Option<Value>,
+}
+
+/// Decode the members `T` reads from `raw` if it is a JSON object; any
+/// others are skipped without being decoded
+fn object<'a, T: Deserialize<'a>>(raw: &'a RawValue) -> Option<T> {
+    if !raw.get().starts_with('{') {
+        return None;
+    }
+    serde_json::from_str(raw.get()).ok()
+}
+
+/// A processed entry, reported in its `response`
+#[derive(Debug)]
+struct EntryOutcome {
+    status: StatusCode,
+    resource: Option<Value>,
+    /// Metric to count once the entry is committed
+    record: Option<fn(&Metrics)>,
+}
+
+impl EntryOutcome {
+    fn new(status: StatusCode, resource: Option<Value>) -> Self {
+        Self {
+            status,
+            resource,
+            record: None,
+        }
+    }
+
+    fn counted(mut self, record: fn(&Metrics)) -> Self {
+        self.record = Some(record);
+        self
+    }
+}
+
+fn invalid_entry(index: usize, message: impl Into<String>) -> ErrorResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error_with_location(
+            "invalid",
+            message,
+            format!("Bundle.entry[{}].request", index),
+        )),
+    )
+}
+
+/// Parse an entry; creates are assigned their id here so later entries can
+/// refer to them
+fn parse_entry<'a>(
+    index: usize,
+    raw: &'a RawValue,
+    limits: &ResourceLimits,
+) -> Result<Entry<'a>, ErrorResponse> {
+    let entry: RawEntry = object(raw).unwrap_or_default();
+    let request = entry
+        .request
+        .as_ref()
+        .ok_or_else(|| invalid_entry(index, "Entry has no request"))?;
+    let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+    let url = request.get("url").and_then(Value::as_str).unwrap_or("");
+    if url.contains('?') || request.get("ifNoneExist").is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "not-supported",
+                "Conditional interactions are not supported in Bundles",
+                format!("Bundle.entry[{}].request", index),
+            )),
+        ));
+    }
+
+    let segments: Vec<&str> = url.trim_matches('/').split('/').collect();
+    let (interaction, resource_type, id) = match (method, segments.as_slice()) {
+        ("POST", [resource_type]) => (
+            Interaction::Create,
+            *resource_type,
+            Uuid::new_v4().to_string(),
+        ),
+        ("PUT", [resource_type, id]) => (Interaction::Update, *resource_type, id.to_string()),
+        ("DELETE", [resource_type, id]) => (Interaction::Delete, *resource_type, id.to_string()),
+        ("GET", [resource_type, id]) => (Interaction::Read, *resource_type, id.to_string()),
+        _ => {
+            return Err(invalid_entry(
+                index,
+                format!(
+                    "Unsupported request '{} {}'; expected POST <type>, or PUT, DELETE or GET <type>/<id>",
+                    method, url
+                ),
+            ))
+        }
+    };
+
+    if matches!(interaction, Interaction::Create | Interaction::Update) {
+        let Some(resource) = entry.resource else {
+            return Err(invalid_entry(
+                index,
+                format!("{} {} requires a resource", method, url),
+            ));
+        };
+        limits
+            .check(resource.get().as_bytes())
+            .map_err(|exceeded| {
+                exceeded.outcome(Some(&format!("Bundle.entry[{}].resource", index)))
+            })?;
+        let found = object::<ResourceHead>(resource).and_then(|head| head.resource_type);
+        check_resource_type(resource_type, found.as_ref())?;
+    }
+
+    Ok(Entry {
+        interaction,
+        resource_type: resource_type.to_string(),
+        id,
+        full_url: entry
+            .full_url
+            .as_ref()
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        resource: entry.resource,
+        if_match: request
+            .get("ifMatch")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// Replace every `reference` in `value` that names a key of `targets`
+fn rewrite_references(value: &mut Value, targets: &HashMap<String, String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, member) in map.iter_mut() {
+                match member {
+                    Value::String(reference) if key == "reference" => {
+                        if let Some(target) = targets.get(reference.as_str()) {
+                            *reference = target.clone();
+                        }
+                    }
+                    other => rewrite_references(other, targets),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rewrite_references(item, targets);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn invalid_body(resource_type: &str, e: serde_json::Error) -> ErrorResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error(
+            "structure",
+            format!("Invalid {}: {}", resource_type, e),
+        )),
+    )
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(
+    resource_type: &str,
+    resource: Value,
+) -> Result<T, ErrorResponse> {
+    serde_json::from_value(resource).map_err(|e| invalid_body(resource_type, e))
+}
+
+/// Updates may not move a resource to another id
+fn check_id(resource: &Value, id: &str) -> Result<(), ErrorResponse> {
+    if resource
+        .get("id")
+        .and_then(Value::as_str)
+        .is_some_and(|body_id| body_id != id)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invariant",
+                "Resource ID cannot be changed",
+            )),
+        ));
+    }
+    Ok(())
+}
+
+fn to_value(resource: &impl serde::Serialize) -> Result<Value, ErrorResponse> {
+    serde_json::to_value(resource).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to serialize resource: {}", e),
+            )),
+        )
+    })
+}
+
+/// Run one entry on `tx`, with references to the entries in `targets`
+/// rewritten
+async fn execute(
+    state: &AppState,
+    request_headers: &HeaderMap,
+    attribution: &Attribution,
+    consent: &ConsentFilter,
+    tx: &mut BundleTransaction<'_>,
+    entry: Entry<'_>,
+    targets: &HashMap<String, String>,
+) -> Result<EntryOutcome, ErrorResponse> {
+    let Entry {
+        interaction,
+        resource_type,
+        id,
+        resource,
+        if_match,
+        ..
+    } = entry;
+    let resource_type = resource_type.as_str();
+    if !matches!(resource_type, "Patient" | "Observation") {
+        check_served(&state.config, resource_type)?;
+    }
+    if interaction != Interaction::Read {
+        state
+            .throttle
+            .admit(&state.config.get(), resource_type, request_headers)
+            .map_err(|retry_after| {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Json(throttled_outcome(resource_type, retry_after)),
+                )
+            })?;
+    }
+    let resource = match resource {
+        Some(raw) => {
+            let mut resource: Value =
+                serde_json::from_str(raw.get()).map_err(|e| invalid_body(resource_type, e))?;
+            rewrite_references(&mut resource, targets);
+            resource
+        }
+        None => Value::Null,
+    };
+
+    match (interaction, resource_type) {
+        (Interaction::Create, "Patient") => {
+            let mut patient: Patient = parse_body(resource_type, resource)?;
+            patient.resource_type = "Patient".to_string();
+            check_validation_hooks(&state.db, &state.validation, "create", &patient).await?;
+            let uuid = Uuid::parse_str(&id).expect("creates are assigned a UUID");
+            let created = tx
+                .create_patient(uuid, patient)
+                .await
+                .map_err(|e| write_error(&state.metrics, "create", e))?;
+            tx.record_patient_provenance(attribution, Activity::Create, &created)
+                .await
+                .map_err(|e| write_error(&state.metrics, "create", e))?;
+            Ok(
+                EntryOutcome::new(StatusCode::CREATED, Some(to_value(&created)?))
+                    .counted(Metrics::patient_created),
+            )
+        }
+        (Interaction::Update, "Patient") => {
+            check_id(&resource, &id)?;
+            let expected_version = match if_match.as_deref() {
+                Some(etag) => Some(version_etag(etag).ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        Json(OperationOutcome::error(
+                            "invalid",
+                            "ifMatch must be a version ETag such as W/\"3\"",
+                        )),
+                    )
+                })?),
+                None if state.config.get().require_if_match => {
+                    return Err(if_match_required("ifMatch"))
+                }
+                None => None,
+            };
+            let patient: Patient = parse_body(resource_type, resource)?;
+            check_validation_hooks(&state.db, &state.validation, "update", &patient).await?;
+            tx.check_patient_lock(&id, lock_token_hash(request_headers).as_deref())
+                .await
+                .map_err(|e| write_error(&state.metrics, "update", e))?;
+            match tx.put_patient(&id, patient, expected_version).await {
+                Ok(Some(PatientPut::Created(created))) => {
+                    tx.record_patient_provenance(attribution, Activity::Create, &created)
+                        .await
+                        .map_err(|e| write_error(&state.metrics, "update", e))?;
+                    Ok(
+                        EntryOutcome::new(StatusCode::CREATED, Some(to_value(&created)?))
+                            .counted(Metrics::patient_created),
+                    )
+                }
+                Ok(Some(PatientPut::Updated(updated))) => {
+                    tx.record_patient_provenance(attribution, Activity::Update, &updated)
+                        .await
+                        .map_err(|e| write_error(&state.metrics, "update", e))?;
+                    Ok(EntryOutcome::new(StatusCode::OK, Some(to_value(&updated)?))
+                        .counted(Metrics::patient_updated))
+                }
+                Ok(None) => Err(missing_patient(&state.db, &id).await),
+                Err(e) => Err(write_error(&state.metrics, "update", e)),
+            }
+        }
+        (Interaction::Delete, "Patient") => {
+            tx.check_patient_lock(&id, lock_token_hash(request_headers).as_deref())
+                .await
+                .map_err(|e| {
+                    lock_refusal(&e).unwrap_or_else(|| processing_error("delete", resource_type, e))
+                })?;
+            match tx.delete_patient(&id).await {
+                Ok(true) => Ok(EntryOutcome::new(StatusCode::NO_CONTENT, None)
+                    .counted(Metrics::patient_deleted)),
+                // Deleting a deleted patient succeeds, as it does over REST
+                Ok(false) => match missing_patient(&state.db, &id).await {
+                    (StatusCode::GONE, _) => Ok(EntryOutcome::new(StatusCode::NO_CONTENT, None)),
+                    error => Err(error),
+                },
+                Err(e) => Err(processing_error("delete", resource_type, e)),
+            }
+        }
+        (Interaction::Read, "Patient") => match tx.get_patient(&id).await {
+            Ok(Some(patient)) => {
+                consent.check_read(&state.db, resource_type, &id).await?;
+                access::check_patient_reads(&state.config.get(), request_headers, [&patient])?;
+                read_outcome(state, to_value(&patient)?).await
+            }
+            Ok(None) => Err(missing_patient(&state.db, &id).await),
+            Err(e) => Err(processing_error("retrieve", resource_type, e)),
+        },
+        (Interaction::Create | Interaction::Update, _) => {
+            let resource = if resource_type == "Observation" {
+                let mut observation: Observation = parse_body(resource_type, resource)?;
+                observation.resource_type = "Observation".to_string();
+                validate_observation(&observation)?;
+                to_value(&observation)?
+            } else {
+                resource
+            };
+            if interaction == Interaction::Create {
+                check_validation_hooks(&state.db, &state.validation, "create", &resource).await?;
+                let uuid = Uuid::parse_str(&id).expect("creates are assigned a UUID");
+                match tx.create_resource(resource_type, uuid, resource).await {
+                    Ok(created) => {
+                        tx.record_provenance(
+                            attribution,
+                            Activity::Create,
+                            resource_type,
+                            &id,
+                            created.version_id,
+                        )
+                        .await
+                        .map_err(|e| resource_write_error("create", resource_type, e))?;
+                        Ok(EntryOutcome::new(
+                            StatusCode::CREATED,
+                            Some(created.into_resource()),
+                        ))
+                    }
+                    Err(e) => Err(resource_write_error("create", resource_type, e)),
+                }
+            } else {
+                check_id(&resource, &id)?;
+                check_validation_hooks(&state.db, &state.validation, "update", &resource).await?;
+                match tx.update_resource(resource_type, &id, resource).await {
+                    Ok(Some(updated)) => {
+                        tx.record_provenance(
+                            attribution,
+                            Activity::Update,
+                            resource_type,
+                            &id,
+                            updated.version_id,
+                        )
+                        .await
+                        .map_err(|e| resource_write_error("update", resource_type, e))?;
+                        Ok(EntryOutcome::new(
+                            StatusCode::OK,
+                            Some(updated.into_resource()),
+                        ))
+                    }
+                    Ok(None) => Err(not_found(resource_type, &id)),
+                    Err(e) => Err(resource_write_error("update", resource_type, e)),
+                }
+            }
+        }
+        (Interaction::Delete, _) => match tx.delete_resource(resource_type, &id).await {
+            Ok(true) => Ok(EntryOutcome::new(StatusCode::NO_CONTENT, None)),
+            Ok(false) => Err(not_found(resource_type, &id)),
+            Err(e) => Err(processing_error("delete", resource_type, e)),
+        },
+        (Interaction::Read, _) => match tx.get_resource(resource_type, &id).await {
+            Ok(Some(stored)) => {
+                consent.check_read(&state.db, resource_type, &id).await?;
+                read_outcome(state, stored.into_resource()).await
+            }
+            Ok(None) => Err(not_found(resource_type, &id)),
+            Err(e) => Err(processing_error("retrieve", resource_type, e)),
+        },
+    }
+}
+
+/// Reads are served through the response transforms like any other read
+async fn read_outcome(state: &AppState, resource: Value) -> Result<EntryOutcome, ErrorResponse> {
+    let resource = state
+        .transforms
+        .apply(vec![resource])
+        .await
+        .map_err(transform_error)?
+        .remove(0);
+    Ok(EntryOutcome::new(StatusCode::OK, Some(resource)))
+}
+
+/// `Bundle.entry` of the response for one processed entry; `minimal`
+/// leaves out the resource
+fn response_entry(
+    interaction: Option<Interaction>,
+    result: Result<EntryOutcome, ErrorResponse>,
+    minimal: bool,
+) -> Value {
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err((status, Json(outcome))) => {
+            return json!({
+                "response": { "status": status.to_string(), "outcome": outcome }
+            })
+        }
+    };
+
+    let mut response = json!({ "status": outcome.status.to_string() });
+    let Some(resource) = outcome.resource else {
+        return json!({ "response": response });
+    };
+    let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+    let id = resource["id"].as_str().unwrap_or_default();
+    if let Some(version) = resource["meta"]["versionId"].as_str() {
+        if interaction != Some(Interaction::Read) {
+            response["location"] = json!(format!("{}/{}/_history/{}", resource_type, id, version));
+        }
+        response["etag"] = json!(format!("W/\"{}\"", version));
+    }
+    if let Some(last_updated) = resource["meta"].get("lastUpdated") {
+        response["lastModified"] = last_updated.clone();
+    }
+    let mut entry = json!({
+        "fullUrl": format!("{}/{}", resource_type, id),
+        "response": response,
+    });
+    if !minimal {
+        entry["resource"] = resource;
+    }
+    entry
+}
+
+/// Tag the issues of a failed transaction with the entry that failed
+fn at_entry(index: usize, (status, Json(mut outcome)): ErrorResponse) -> ErrorResponse {
+    for issue in &mut outcome.issue {
+        issue.diagnostics = Some(format!(
+            "Bundle.entry[{}]: {}",
+            index,
+            issue.diagnostics.as_deref().unwrap_or_default()
+        ));
+    }
+    (status, Json(outcome))
+}
+
+fn invalid_bundle(code: &str, message: &str, location: &str) -> ErrorResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error_with_location(
+            code, message, location,
+        )),
+    )
+}
+
+pub async fn process_bundle(
+    State(state): State<AppState>,
+    attribution: Attribution,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ErrorResponse> {
+    if !has_json_body(&request_headers) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "Bundles must be sent as application/fhir+json",
+            )),
+        ));
+    }
+    let (limit, limits) = {
+        let config = state.config.get();
+        (config.max_bundle_bytes, config.resource_limits.clone())
+    };
+    let bytes = to_bytes(body, limit).await.map_err(|e| {
+        if !is_length_limit(&e) {
+            return unreadable_body(e);
+        }
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(OperationOutcome::error(
+                "too-costly",
+                format!("Bundles are limited to {} bytes", limit),
+            )),
+        )
+    })?;
+
+    // The body stays encoded: members and entries borrow from it and each
+    // resource is decoded only while its entry runs
+    let members: HashMap<Cow<str>, &RawValue> =
+        serde_json::from_slice(&bytes).or_else(|e| match e.classify() {
+            Category::Syntax | Category::Eof | Category::Io => {
+                Err(invalid_bundle("structure", &e.to_string(), "Bundle"))
+            }
+            // Not an object
+            Category::Data => Ok(HashMap::new()),
+        })?;
+    let member = |name: &str| -> Option<Value> {
+        members
+            .get(name)
+            .and_then(|raw| serde_json::from_str(raw.get()).ok())
+    };
+    check_resource_type("Bundle", member("resourceType").as_ref())?;
+    let transaction = match member("type").as_ref().and_then(Value::as_str) {
+        Some("transaction") => true,
+        Some("batch") => false,
+        _ => {
+            return Err(invalid_bundle(
+                "invalid",
+                "Bundle type must be 'batch' or 'transaction'",
+                "Bundle.type",
+            ))
+        }
+    };
+    let raw_entries: Vec<&RawValue> = match members.get("entry") {
+        None => Vec::new(),
+        Some(raw) => serde_json::from_str(raw.get()).map_err(|_| {
+            invalid_bundle("structure", "Bundle.entry must be an array", "Bundle.entry")
+        })?,
+    };
+
+    let mut entries = Vec::with_capacity(raw_entries.len());
+    let mut targets = HashMap::new();
+    for (index, raw) in raw_entries.into_iter().enumerate() {
+        let entry = match parse_entry(index, raw, &limits) {
+            Err(e) if transaction => return Err(at_entry(index, e)),
+            entry => entry,
+        };
+        if let Ok(entry) = &entry {
+            let full_url = entry.full_url.as_deref();
+            if let Some(full_url) = full_url.filter(|url| url.starts_with("urn:uuid:")) {
+                if entry.interaction == Interaction::Create {
+                    targets.insert(
+                        full_url.to_string(),
+                        format!("{}/{}", entry.resource_type, entry.id),
+                    );
+                }
+            }
+        }
+        entries.push(entry);
+    }
+
+    let interactions: Vec<Option<Interaction>> = entries
+        .iter()
+        .map(|entry| entry.as_ref().ok().map(|e| e.interaction))
+        .collect();
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&index| (interactions[index], index));
+
+    // Each response entry is encoded as soon as its entry has run, so only
+    // one decoded resource is held at a time
+    let minimal = prefers(&request_headers, "return=minimal");
+    let mut records = Vec::new();
+    let mut respond = |index: usize, result: Result<EntryOutcome, ErrorResponse>| {
+        if let Ok(EntryOutcome {
+            record: Some(record),
+            ..
+        }) = &result
+        {
+            records.push(*record);
+        }
+        Bytes::from(response_entry(interactions[index], result, minimal).to_string())
+    };
+    let mut slots: Vec<Option<Result<Entry, ErrorResponse>>> =
+        entries.into_iter().map(Some).collect();
+    let mut results: Vec<Option<Bytes>> = (0..slots.len()).map(|_| None).collect();
+    let begin_error = |e| processing_error("begin", "Bundle", e);
+
+    if transaction {
+        let mut tx = state.db.begin_bundle().await.map_err(begin_error)?;
+        for index in order {
+            let Some(Ok(entry)) = slots[index].take() else {
+                continue;
+            };
+            let outcome = execute(
+                &state,
+                &request_headers,
+                &attribution,
+                &consent,
+                &mut tx,
+                entry,
+                &targets,
+            )
+            .await
+            .map_err(|e| at_entry(index, e))?;
+            results[index] = Some(respond(index, Ok(outcome)));
+        }
+        tx.commit()
+            .await
+            .map_err(|e| processing_error("commit", "Bundle", e))?;
+    } else {
+        for index in order {
+            let result = match slots[index].take() {
+                Some(Ok(entry)) => match state.db.begin_bundle().await {
+                    Ok(mut tx) => {
+                        match execute(
+                            &state,
+                            &request_headers,
+                            &attribution,
+                            &consent,
+                            &mut tx,
+                            entry,
+                            &targets,
+                        )
+                        .await
+                        {
+                            Ok(outcome) => tx
+                                .commit()
+                                .await
+                                .map(|_| outcome)
+                                .map_err(|e| processing_error("commit", "Bundle entry", e)),
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Err(e) => Err(begin_error(e)),
+                },
+                Some(Err(e)) => Err(e),
+                None => continue,
+            };
+            results[index] = Some(respond(index, result));
+        }
+    }
+    drop(slots);
+    drop(bytes);
+    for record in records {
+        record(&state.metrics);
+    }
+
+    // Sent as one chunk per entry rather than copied into a single body
+    let bundle_type = if transaction {
+        "transaction-response"
+    } else {
+        "batch-response"
+    };
+    let mut chunks = vec![Bytes::from(format!(
+        r#"{{"resourceType":"Bundle","type":"{}","entry":["#,
+        bundle_type
+    ))];
+    for (index, entry) in results.into_iter().enumerate() {
+        if index > 0 {
+            chunks.push(Bytes::from_static(b","));
+        }
+        chunks.push(entry.expect("every entry is processed"));
+    }
+    chunks.push(Bytes::from_static(b"]}"));
+    let body = Body::from_stream(stream::iter(chunks.into_iter().map(Ok::<_, Infallible>)));
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/fhir+json")],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use crate::db::Database;
+    use crate::search::TokenParam;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use axum::Router;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn test_app() -> (Router, Arc<Database>) {
+        let state = AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        );
+        let db = state.db.clone();
+        (crate::routes::router(state), db)
+    }
+
+    async fn post_bundle(app: &Router, bundle: Value) -> (StatusCode, Value) {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/fhir")
+                    .header("content-type", "application/fhir+json")
+                    .body(Body::from(bundle.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn patient_with_identifier(value: &str) -> Value {
+        json!({
+            "resourceType": "Patient",
+            "identifier": [{ "system": "urn:test:bundle", "value": value }],
+            "gender": "female"
+        })
+    }
+
+    fn identifier(value: &str) -> TokenParam {
+        TokenParam {
+            system: Some("urn:test:bundle".to_string()),
+            code: Some(value.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction_resolves_references_between_entries() {
+        let (app, db) = test_app().await;
+        let value = Uuid::new_v4().to_string();
+
+        let (status, response) = post_bundle(
+            &app,
+            json!({
+                "resourceType": "Bundle",
+                "type": "transaction",
+                "entry": [
+                    {
+                        "resource": {
+                            "resourceType": "Observation",
+                            "status": "final",
+                            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4" }] },
+                            "subject": { "reference": "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a" }
+                        },
+                        "request": { "method": "POST", "url": "Observation" }
+                    },
+                    {
+                        "fullUrl": "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a",
+                        "resource": patient_with_identifier(&value),
+                        "request": { "method": "POST", "url": "Patient" }
+                    }
+                ]
+            }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["type"], "transaction-response");
+        let entries = response["entry"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            assert_eq!(entry["response"]["status"], "201 Created");
+            assert_eq!(entry["response"]["etag"], "W/\"1\"");
+        }
+
+        let patient_id = entries[1]["resource"]["id"].as_str().unwrap();
+        assert_eq!(
+            entries[1]["response"]["location"],
+            format!("Patient/{}/_history/1", patient_id)
+        );
+        assert_eq!(
+            entries[0]["resource"]["subject"]["reference"],
+            format!("Patient/{}", patient_id)
+        );
+        let found = db
+            .find_patients_by_identifier(&identifier(&value), 2)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_transaction_entry_rolls_back_the_bundle() {
+        let (app, db) = test_app().await;
+        let value = Uuid::new_v4().to_string();
+
+        let (status, outcome) = post_bundle(
+            &app,
+            json!({
+                "resourceType": "Bundle",
+                "type": "transaction",
+                "entry": [
+                    {
+                        "resource": patient_with_identifier(&value),
+                        "request": { "method": "POST", "url": "Patient" }
+                    },
+                    {
+                        "resource": { "resourceType": "Observation", "status": "bogus", "code": {} },
+                        "request": { "method": "POST", "url": "Observation" }
+                    }
+                ]
+            }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["resourceType"], "OperationOutcome");
+        assert!(outcome["issue"][0]["diagnostics"]
+            .as_str()
+            .unwrap()
+            .starts_with("Bundle.entry[1]: "));
+        let found = db
+            .find_patients_by_identifier(&identifier(&value), 2)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_each_entry() {
+        let (app, db) = test_app().await;
+        let value = Uuid::new_v4().to_string();
+        let missing = Uuid::new_v4();
+
+        let (status, response) = post_bundle(
+            &app,
+            json!({
+                "resourceType": "Bundle",
+                "type": "batch",
+                "entry": [
+                    {
+                        "resource": patient_with_identifier(&value),
+                        "request": { "method": "POST", "url": "Patient" }
+                    },
+                    { "request": { "method": "GET", "url": format!("Patient/{}", missing) } },
+                    { "request": { "method": "PATCH", "url": "Patient/1" } }
+                ]
+            }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["type"], "batch-response");
+        let entries = response["entry"].as_array().unwrap();
+        assert_eq!(entries[0]["response"]["status"], "201 Created");
+        assert_eq!(entries[1]["response"]["status"], "404 Not Found");
+        assert_eq!(
+            entries[1]["response"]["outcome"]["issue"][0]["code"],
+            "not-found"
+        );
+        assert_eq!(entries[2]["response"]["status"], "400 Bad Request");
+
+        // The failures do not undo the create
+        let found = db
+            .find_patients_by_identifier(&identifier(&value), 2)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        let id = found[0].id.clone().unwrap();
+
+        let (_, response) = post_bundle(
+            &app,
+            json!({
+                "resourceType": "Bundle",
+                "type": "batch",
+                "entry": [
+                    { "request": { "method": "GET", "url": format!("Patient/{}", id) } },
+                    {
+                        "resource": patient_with_identifier(&value),
+                        "request": { "method": "PUT", "url": format!("Patient/{}", id), "ifMatch": "W/\"7\"" }
+                    },
+                    { "request": { "method": "DELETE", "url": format!("Patient/{}", id) } }
+                ]
+            }),
+        )
+        .await;
+        let entries = response["entry"].as_array().unwrap();
+        // Deletes run first, so the read and the update find the patient gone
+        assert_eq!(entries[2]["response"]["status"], "204 No Content");
+        assert_eq!(entries[0]["response"]["status"], "410 Gone");
+        assert_eq!(entries[1]["response"]["status"], "410 Gone");
+    }
+
+    #[tokio::test]
+    async fn test_other_bundle_types_are_rejected() {
+        let (app, _) = test_app().await;
+
+        let (status, outcome) = post_bundle(
+            &app,
+            json!({ "resourceType": "Bundle", "type": "collection", "entry": [] }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["issue"][0]["location"][0], "Bundle.type");
+    }
+
+    #[tokio::test]
+    async fn test_bundle_size_limit_and_minimal_responses() {
+        let config = ServerConfig {
+            max_bundle_bytes: 1024,
+            ..ServerConfig::default()
+        };
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config)),
+        ));
+        let entry = json!({
+            "resource": patient_with_identifier(&Uuid::new_v4().to_string()),
+            "request": { "method": "POST", "url": "Patient" }
+        });
+        let bundle = |entries: usize| json!({ "resourceType": "Bundle", "type": "batch", "entry": vec![entry.clone(); entries] });
+
+        let (status, outcome) = post_bundle(&app, bundle(20)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(outcome["issue"][0]["code"], "too-costly");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/fhir")
+                    .header("content-type", "application/fhir+json")
+                    .header("prefer", "return=minimal")
+                    .body(Body::from(bundle(1).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["type"], "batch-response");
+        let entry = &response["entry"][0];
+        assert_eq!(entry["response"]["status"], "201 Created");
+        assert!(entry["fullUrl"].as_str().unwrap().starts_with("Patient/"));
+        assert!(entry.get("resource").is_none());
+    }
+}
@@ -0,0 +1,304 @@
+//! `GET /fhir/_changes?cursor=<token>`: the writes since `cursor`, for ETL
+//! consumers that follow the store without Subscriptions.
+//!
+//! Each page lists up to `_count` changes (resource type, id, version and
+//! `create`, `update` or `delete`) in the order they are to be applied, each
+//! with the cursor just after it, and the cursor to continue from. Cursors
+//! stay valid across restarts. Delivery is at least once: a consumer that
+//! stores the cursor only after processing a page may see changes again,
+//! never miss any. Without `cursor` the feed starts from its first change;
+//! `cursor=now` skips everything committed so far.
+//!
+//! `GET /fhir/_events[?_type=<types>]` pushes the same changes as
+//! Server-Sent Events as they commit, for consumers that want them without
+//! polling; see [`crate::events`] for what it does not guarantee.
+
+use super::resource::ErrorResponse;
+use crate::config::SharedConfig;
+use crate::db::{Change, ChangeCursor, Database};
+use crate::events::{ChangeEvent, ChangeEvents};
+use crate::extract::Query;
+use crate::models::OperationOutcome;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Json,
+    },
+};
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ChangesParams {
+    cursor: Option<String>,
+    #[serde(rename = "_count")]
+    count: Option<u32>,
+}
+
+fn change_json(change: &Change) -> Value {
+    json!({
+        "resourceType": change.resource_type,
+        "id": change.id,
+        "versionId": change.version_id.to_string(),
+        "op": change.op,
+        "timestamp": change.timestamp,
+        "cursor": change.cursor.to_string(),
+    })
+}
+
+pub async fn get_changes(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<Value>, ErrorResponse> {
+    let failed = |e: anyhow::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to read changes: {}", e),
+            )),
+        )
+    };
+    if !db.changes_enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "_changes is not available on this server: the fhir.change_outbox table is missing",
+            )),
+        ));
+    }
+
+    let cursor = match params.cursor.as_deref() {
+        None => ChangeCursor::default(),
+        Some("now") => db.current_change_cursor().await.map_err(failed)?,
+        Some(token) => ChangeCursor::parse(token).map_err(|message| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error_with_location(
+                    "invalid", message, "cursor",
+                )),
+            )
+        })?,
+    };
+    let count = config.get().page_size(params.count);
+    let changes = db.changes_after(cursor, count).await.map_err(failed)?;
+
+    // Where to continue: after the last change, or where this page started
+    let next = changes.last().map_or(cursor, |change| change.cursor);
+    Ok(Json(json!({
+        "changes": changes.iter().map(change_json).collect::<Vec<_>>(),
+        "cursor": next.to_string(),
+    })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EventsParams {
+    #[serde(rename = "_type")]
+    types: Option<String>,
+}
+
+/// The event of a change: named by its op, with the cursor after it as id
+fn change_event(change: &Change) -> Event {
+    Event::default()
+        .event(change.op.as_str())
+        .id(change.cursor.to_string())
+        .data(change_json(change).to_string())
+}
+
+/// GET /fhir/_events: every change from now on, as it commits; with
+/// `_type` only those of the listed resource types. A `gap` event tells
+/// the consumer that some were missed.
+pub async fn get_events(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(events): State<Arc<ChangeEvents>>,
+    Query(params): Query<EventsParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ErrorResponse> {
+    if !db.events_enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "_events is not available on this server: the fhir.notify_change function is missing",
+            )),
+        ));
+    }
+    let types: Vec<String> = params
+        .types
+        .iter()
+        .flat_map(|types| types.split(','))
+        .map(|resource_type| resource_type.trim().to_string())
+        .filter(|resource_type| !resource_type.is_empty())
+        .collect();
+    if let Some(unserved) = types.iter().find(|resource_type| {
+        !matches!(resource_type.as_str(), "Patient" | "Observation")
+            && !config.get().serves_resource_type(resource_type)
+    }) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "not-supported",
+                format!("Resource type {} is not supported by this server", unserved),
+                "_type",
+            )),
+        ));
+    }
+
+    let receiver = events.subscribe(&db).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to listen for changes: {}", e),
+            )),
+        )
+    })?;
+    let stream = stream::unfold(receiver, move |mut receiver| {
+        let types = types.clone();
+        async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(ChangeEvent::Change(change))
+                        if types.is_empty() || types.contains(&change.resource_type) =>
+                    {
+                        change_event(&change)
+                    }
+                    Ok(ChangeEvent::Change(_)) => continue,
+                    Ok(ChangeEvent::Gap) | Err(RecvError::Lagged(_)) => {
+                        Event::default().event("gap").data("{}")
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok(event), receiver));
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Patient;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    fn params(cursor: &str) -> Query<ChangesParams> {
+        Query(ChangesParams {
+            cursor: Some(cursor.to_string()),
+            count: Some(100),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_changes_continue_from_the_returned_cursor() {
+        let db = setup_test_db().await;
+        let config = Arc::new(SharedConfig::default());
+
+        let Json(page) = get_changes(State(db.clone()), State(config.clone()), params("now"))
+            .await
+            .unwrap();
+        let start = page["cursor"].as_str().unwrap().to_string();
+        let created = db.create_patient(Patient::new()).await.unwrap();
+        let id = created.id.unwrap();
+
+        // Page on until the create shows up; other tests write concurrently
+        let mut found = None;
+        let mut cursor = start;
+        for _ in 0..100 {
+            let Json(page) = get_changes(State(db.clone()), State(config.clone()), params(&cursor))
+                .await
+                .unwrap();
+            let changes = page["changes"].as_array().unwrap();
+            found = changes
+                .iter()
+                .find(|change| change["id"] == id.as_str())
+                .cloned();
+            if found.is_some() {
+                break;
+            }
+            if changes.is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            cursor = page["cursor"].as_str().unwrap().to_string();
+        }
+        let change = found.expect("create not in the feed");
+        assert_eq!(change["resourceType"], "Patient");
+        assert_eq!(change["op"], "create");
+        assert_eq!(change["versionId"], "1");
+
+        // Reading on from the change's cursor does not repeat it
+        let cursor = change["cursor"].as_str().unwrap();
+        let Json(page) = get_changes(State(db.clone()), State(config.clone()), params(cursor))
+            .await
+            .unwrap();
+        assert!(page["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|change| change["id"] != id.as_str()));
+
+        let (status, _) = get_changes(State(db), State(config), params("12"))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_events_stream_commits() {
+        use axum::body::Body;
+        use axum::http::{header, Request};
+        use futures_util::StreamExt;
+        use tower::ServiceExt;
+
+        let db = setup_test_db().await;
+        let state = crate::state::AppState::new(
+            crate::db::tests::setup_test_db().await,
+            Arc::new(SharedConfig::default()),
+        );
+        let app = crate::routes::router(state);
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let unserved = get("/fhir/_events?_type=Nope").await.unwrap();
+        assert_eq!(unserved.status(), StatusCode::BAD_REQUEST);
+
+        let response = get("/fhir/_events?_type=Patient").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut body = response.into_body().into_data_stream();
+
+        let id = db.create_patient(Patient::new()).await.unwrap().id.unwrap();
+        // Other tests write concurrently, so read on until this create
+        let mut received = String::new();
+        let event = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            loop {
+                let chunk = body.next().await.unwrap().unwrap();
+                received.push_str(&String::from_utf8_lossy(&chunk));
+                if let Some(event) = received.split("\n\n").find(|event| event.contains(&id)) {
+                    return event.to_string();
+                }
+            }
+        })
+        .await
+        .expect("create not streamed");
+        assert!(event.contains("event: create\n"));
+        assert!(event.contains("\"resourceType\":\"Patient\""));
+        assert!(event.contains("\nid: "));
+    }
+}
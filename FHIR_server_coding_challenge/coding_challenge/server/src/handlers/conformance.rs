@@ -0,0 +1,196 @@
+//! `GET /fhir/$resolve`: the conformance resource a canonical URL names.
+//!
+//! StructureDefinitions, ValueSets, SearchParameters, ConceptMaps and
+//! StructureMaps are written through the generic resource endpoints; see
+//! [`crate::db::conformance`] for how they are indexed. The SearchParameters built into this server resolve
+//! too, by their `http://hl7.org/fhir/SearchParameter/...` URLs.
+
+use super::resource::{processing_error, ErrorResponse};
+use super::transform_error;
+use crate::db::conformance::{is_conformance_type, Canonical, CONFORMANCE_RESOURCE_TYPES};
+use crate::db::Database;
+use crate::extract::Query;
+use crate::models::OperationOutcome;
+use crate::search::SearchParamRegistry;
+use crate::transform::ResponsePipeline;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveParams {
+    /// `url` or `url|version`
+    url: Option<String>,
+    /// Only resolve to resources of this type
+    #[serde(rename = "resourceType")]
+    resource_type: Option<String>,
+}
+
+fn invalid(code: &str, message: String, location: &str) -> ErrorResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error_with_location(
+            code, message, location,
+        )),
+    )
+}
+
+/// GET /fhir/$resolve?url=http://example.org/fhir/ValueSet/codes|2.0
+///
+/// Without a version the most recently written version is returned.
+pub async fn resolve_canonical(
+    State(db): State<Arc<Database>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Query(params): Query<ResolveParams>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    let Some(raw) = params.url.as_deref().filter(|url| !url.is_empty()) else {
+        return Err(invalid(
+            "required",
+            "$resolve requires a url parameter".to_string(),
+            "url",
+        ));
+    };
+    let resource_types = match params.resource_type.as_deref() {
+        Some(resource_type) if is_conformance_type(resource_type) => vec![resource_type],
+        Some(resource_type) => {
+            return Err(invalid(
+                "not-supported",
+                format!(
+                    "{} is not resolved by canonical URL; use one of {}",
+                    resource_type,
+                    CONFORMANCE_RESOURCE_TYPES.join(", ")
+                ),
+                "resourceType",
+            ))
+        }
+        None => CONFORMANCE_RESOURCE_TYPES.to_vec(),
+    };
+    let canonical = Canonical::parse(raw);
+
+    let resource = match db.resolve_canonical(&resource_types, &canonical).await {
+        Ok(Some(stored)) => stored.into_resource(),
+        Ok(None) => builtin(&registry, &resource_types, &canonical).ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(OperationOutcome::error_with_location(
+                    "not-found",
+                    format!(
+                        "No {} has the canonical {}",
+                        resource_types.join(" or "),
+                        canonical
+                    ),
+                    "url",
+                )),
+            )
+        })?,
+        Err(e) => return Err(processing_error("resolve", raw, e)),
+    };
+    let resource = transforms
+        .apply(vec![resource])
+        .await
+        .map_err(transform_error)?
+        .remove(0);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((StatusCode::OK, headers, Json(resource)))
+}
+
+/// A built-in search parameter; they have no version
+fn builtin(
+    registry: &SearchParamRegistry,
+    resource_types: &[&str],
+    canonical: &Canonical,
+) -> Option<Value> {
+    if !resource_types.contains(&"SearchParameter") || canonical.version.is_some() {
+        return None;
+    }
+    registry
+        .list(None)
+        .into_iter()
+        .find(|p| p.url == canonical.url)
+        .map(|p| p.to_resource())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    async fn resolve(
+        db: &Arc<Database>,
+        url: Option<&str>,
+        resource_type: Option<&str>,
+    ) -> Result<Value, ErrorResponse> {
+        let params = ResolveParams {
+            url: url.map(str::to_string),
+            resource_type: resource_type.map(str::to_string),
+        };
+        resolve_canonical(
+            State(db.clone()),
+            State(Arc::new(SearchParamRegistry::with_builtins())),
+            State(Arc::new(ResponsePipeline::default())),
+            Query(params),
+        )
+        .await
+        .map(|(_, _, Json(resource))| resource)
+    }
+
+    #[tokio::test]
+    async fn test_resolve_stored_and_builtin_definitions() {
+        let db = setup_test_db().await;
+        let url = format!(
+            "http://example.org/fhir/StructureDefinition/{}",
+            uuid::Uuid::new_v4()
+        );
+        let stored = db
+            .create_resource(
+                "StructureDefinition",
+                json!({ "url": url, "version": "1.0", "status": "active" }),
+            )
+            .await
+            .unwrap();
+
+        let resolved = resolve(&db, Some(&format!("{}|1.0", url)), None)
+            .await
+            .unwrap();
+        assert_eq!(resolved["id"], stored.id.to_string());
+        assert_eq!(resolved["resourceType"], "StructureDefinition");
+        let err = resolve(&db, Some(&url), Some("ValueSet"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+        let err = resolve(&db, Some(&format!("{}|2.0", url)), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.1.issue[0].code, "not-found");
+
+        let builtin = resolve(
+            &db,
+            Some("http://hl7.org/fhir/SearchParameter/Patient-name"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(builtin["code"], "name");
+
+        let err = resolve(&db, None, None).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = resolve(&db, Some(&url), Some("Patient")).await.unwrap_err();
+        assert_eq!(err.1.issue[0].code, "not-supported");
+
+        db.delete_resource("StructureDefinition", &stored.id.to_string())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+//! History Bundles. Instance history (`GET /fhir/Patient/:id/_history`)
+//! lives with the other Patient interactions; the type-level
+//! `GET /fhir/Patient/_history` and system-level `GET /fhir/_history` list
+//! the versions of all instances, newest first, `_count` at a time.
+//! Instance history can be narrowed with `_since` and `_at`, and with
+//! `_format=ndjson` (or `Accept: application/fhir+ndjson`) is streamed as
+//! NDJSON instead: one version per line, oldest first.
+//!
+//! Versions are only recorded for Patients, so the system-level history is
+//! the Patient history under the server's base URL.
+
+use super::resource::ErrorResponse;
+use super::transform_error;
+use crate::access;
+use crate::config::{ServerConfig, SharedConfig};
+use crate::consent::ConsentFilter;
+use crate::db::Database;
+use crate::extract::Query;
+use crate::models::OperationOutcome;
+use crate::negotiation::FHIR_NDJSON;
+use crate::transform::ResponsePipeline;
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use futures_util::{future, stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    #[serde(rename = "_count")]
+    count: Option<u32>,
+    #[serde(rename = "_offset")]
+    offset: Option<u32>,
+}
+
+/// Filters of instance history, both FHIR instants such as
+/// `2024-05-01T12:00:00Z`
+#[derive(Debug, Default, Deserialize)]
+pub struct InstanceHistoryParams {
+    /// Only versions recorded at or after this instant
+    #[serde(rename = "_since")]
+    since: Option<String>,
+    /// Only the version that was current at this instant
+    #[serde(rename = "_at")]
+    at: Option<String>,
+}
+
+impl InstanceHistoryParams {
+    /// The parsed `_since`, or a 400 if it is malformed
+    pub(super) fn since(&self) -> Result<Option<DateTime<Utc>>, ErrorResponse> {
+        instant("_since", self.since.as_deref())
+    }
+
+    /// The parsed `_at`, or a 400 if it is malformed
+    pub(super) fn at(&self) -> Result<Option<DateTime<Utc>>, ErrorResponse> {
+        instant("_at", self.at.as_deref())
+    }
+
+    /// The filters as a query string for the self link, empty without any
+    pub(super) fn query_string(&self) -> String {
+        let pairs: Vec<(&str, &str)> = [("_since", &self.since), ("_at", &self.at)]
+            .into_iter()
+            .filter_map(|(name, value)| value.as_deref().map(|v| (name, v)))
+            .collect();
+        if pairs.is_empty() {
+            return String::new();
+        }
+        format!(
+            "?{}",
+            serde_urlencoded::to_string(pairs).unwrap_or_default()
+        )
+    }
+}
+
+pub(super) fn instant(
+    name: &str,
+    value: Option<&str>,
+) -> Result<Option<DateTime<Utc>>, ErrorResponse> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| Some(t.with_timezone(&Utc)))
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invalid",
+                    format!(
+                        "{} must be an instant with a time zone, e.g. 2024-05-01T12:00:00Z; got {}",
+                        name, value
+                    ),
+                )),
+            )
+        })
+}
+
+/// Base URL for absolute fullUrl/link values (FHIR R4 requirement)
+pub(super) fn base_url() -> String {
+    std::env::var("FHIR_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+pub(super) fn not_supported() -> ErrorResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "History is not available on this server: the fhir.patient_history table is missing",
+        )),
+    )
+}
+
+/// Bundle entry of one recorded Patient version.
+///
+/// The resource gets the instance id and meta.versionId/meta.lastUpdated
+/// of the version, and the entry a `request` naming the interaction that
+/// produced it; a delete carries no resource.
+pub(super) fn entry(
+    base_url: &str,
+    id: &str,
+    version_id: i32,
+    ts: String,
+    mut resource: Value,
+    status: Option<String>,
+) -> Value {
+    if let Value::Object(ref mut map) = resource {
+        map.entry("resourceType")
+            .or_insert_with(|| Value::String("Patient".to_string()));
+        map.insert("id".to_string(), Value::String(id.to_string()));
+
+        let meta_entry = map
+            .entry("meta")
+            .or_insert_with(|| Value::Object(serde_json::Map::new()));
+        if let Value::Object(ref mut meta_map) = meta_entry {
+            meta_map.insert(
+                "versionId".to_string(),
+                Value::String(version_id.to_string()),
+            );
+            meta_map.insert("lastUpdated".to_string(), Value::String(ts.clone()));
+        }
+    }
+
+    // Updates, snapshots and anything unrecognized are treated as PUT
+    let method = match status.as_deref() {
+        Some("created") => "POST",
+        Some("deleted") => "DELETE",
+        _ => "PUT",
+    };
+
+    if method == "DELETE" {
+        return json!({
+            "fullUrl": format!("{}/fhir/Patient/{}", base_url, id),
+            "request": {
+                "method": method,
+                "url": format!("Patient/{}", id),
+            },
+            "response": {
+                "status": "204 No Content",
+                "lastModified": ts
+            }
+        });
+    }
+
+    json!({
+        // Absolute logical URL without /_history per bdl-8
+        "fullUrl": format!("{}/fhir/Patient/{}", base_url, id),
+        "resource": resource,
+        "request": {
+            "method": method,
+            "url": format!("Patient/{}", id),
+        },
+        "response": {
+            "status": "200 OK",
+            "lastModified": ts
+        }
+    })
+}
+
+/// Versions read at a time for NDJSON instance history
+pub const NDJSON_BATCH: u32 = 100;
+
+/// What one NDJSON instance history reads, shared by its batches
+struct InstanceHistory {
+    db: Arc<Database>,
+    config: Arc<ServerConfig>,
+    transforms: Arc<ResponsePipeline>,
+    request_headers: HeaderMap,
+    id: String,
+    since: Option<DateTime<Utc>>,
+    at: Option<DateTime<Utc>>,
+}
+
+impl InstanceHistory {
+    /// The lines of the versions after `after_version`, and the version to
+    /// continue after if the batch was full
+    async fn batch(&self, after_version: i32) -> Result<(Bytes, Option<i32>), ErrorResponse> {
+        let versions = self
+            .db
+            .get_patient_history_after(&self.id, self.since, self.at, after_version, NDJSON_BATCH)
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(OperationOutcome::error(
+                        "processing",
+                        format!("Failed to retrieve patient history: {}", e),
+                    )),
+                )
+            })?;
+        let next = (versions.len() == NDJSON_BATCH as usize)
+            .then(|| versions.last().map(|version| version.version_id))
+            .flatten();
+
+        let base_url = base_url();
+        let mut entries: Vec<Value> = versions
+            .into_iter()
+            .map(|version| {
+                entry(
+                    &base_url,
+                    &self.id,
+                    version.version_id,
+                    version.ts,
+                    version.resource,
+                    version.status,
+                )
+            })
+            .collect();
+        access::check_resource_reads(
+            &self.config,
+            &self.request_headers,
+            entries.iter().filter_map(|entry| entry.get("resource")),
+        )?;
+        transform_entries(&self.transforms, &mut entries).await?;
+
+        let mut lines = Vec::new();
+        for resource in entries.iter().filter_map(|entry| entry.get("resource")) {
+            lines.extend_from_slice(resource.to_string().as_bytes());
+            lines.push(b'\n');
+        }
+        Ok((Bytes::from(lines), next))
+    }
+}
+
+/// Instance history as `application/fhir+ndjson`, read [`NDJSON_BATCH`]
+/// versions at a time. A delete carries no resource and so has no line;
+/// the gap in `meta.versionId` marks it.
+///
+/// The first batch is read before answering so its errors, such as a
+/// restricted version without an accepted reason, get their status; a
+/// later batch that fails ends the body early.
+pub(super) async fn instance_ndjson(
+    db: Arc<Database>,
+    config: Arc<ServerConfig>,
+    transforms: Arc<ResponsePipeline>,
+    request_headers: HeaderMap,
+    id: String,
+    since: Option<DateTime<Utc>>,
+    at: Option<DateTime<Utc>>,
+) -> Result<Response, ErrorResponse> {
+    let history = Arc::new(InstanceHistory {
+        db,
+        config,
+        transforms,
+        request_headers,
+        id,
+        since,
+        at,
+    });
+    let (lines, next) = history.batch(0).await?;
+
+    let rest = stream::try_unfold(next, move |next| {
+        let history = history.clone();
+        async move {
+            let Some(after_version) = next else {
+                return Ok(None);
+            };
+            let (lines, next) = history
+                .batch(after_version)
+                .await
+                .map_err(|e| ended_early(&history.id, e))?;
+            Ok(Some((lines, next)))
+        }
+    });
+    let batches = stream::once(future::ready(Ok::<_, std::io::Error>(lines))).chain(rest);
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, FHIR_NDJSON)],
+        Body::from_stream(batches),
+    )
+        .into_response())
+}
+
+/// The body error of an NDJSON history whose later batch failed
+fn ended_early(id: &str, (_, Json(outcome)): ErrorResponse) -> std::io::Error {
+    let message = outcome
+        .issue
+        .into_iter()
+        .next()
+        .and_then(|issue| issue.diagnostics)
+        .unwrap_or_default();
+    tracing::warn!(patient_id = %id, "NDJSON history ended early: {}", message);
+    std::io::Error::other(message)
+}
+
+/// Run the response transforms over the resources of `entries`
+pub(super) async fn transform_entries(
+    transforms: &ResponsePipeline,
+    entries: &mut [Value],
+) -> Result<(), ErrorResponse> {
+    let resources = entries
+        .iter_mut()
+        .filter_map(|e| e.get_mut("resource").map(Value::take))
+        .collect();
+    let resources = transforms.apply(resources).await.map_err(transform_error)?;
+    let with_resource = entries.iter_mut().filter(|e| e.get("resource").is_some());
+    for (entry, resource) in with_resource.zip(resources) {
+        entry["resource"] = resource;
+    }
+    Ok(())
+}
+
+/// `GET /fhir/Patient/_history`
+pub async fn get_patient_type_history(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Query(params): Query<HistoryParams>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    history_page(
+        &db,
+        &config,
+        &transforms,
+        params,
+        &consent,
+        &request_headers,
+        "fhir/Patient/_history",
+    )
+    .await
+}
+
+/// `GET /fhir/_history`
+pub async fn get_system_history(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Query(params): Query<HistoryParams>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    history_page(
+        &db,
+        &config,
+        &transforms,
+        params,
+        &consent,
+        &request_headers,
+        "fhir/_history",
+    )
+    .await
+}
+
+/// One page of the versions of all patients, linked to its neighbours
+async fn history_page(
+    db: &Database,
+    config: &SharedConfig,
+    transforms: &ResponsePipeline,
+    params: HistoryParams,
+    consent: &ConsentFilter,
+    request_headers: &HeaderMap,
+    path: &str,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    if !db.history_enabled() {
+        return Err(not_supported());
+    }
+    let count = config.get().page_size(params.count);
+    let offset = params.offset.unwrap_or(0);
+
+    let history = async {
+        let total = db.count_patient_history(consent.withholding()).await?;
+        let page = db
+            .get_all_patients_history(count, offset, consent.withholding())
+            .await?;
+        anyhow::Ok((total, page))
+    };
+    let (total, page) = history.await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to retrieve history: {}", e),
+            )),
+        )
+    })?;
+
+    let base_url = base_url();
+    let bundle_last_updated = page.first().map(|version| version.ts.clone());
+    let mut entries: Vec<Value> = page
+        .into_iter()
+        .map(|version| {
+            entry(
+                &base_url,
+                &version.id.to_string(),
+                version.version_id,
+                version.ts,
+                version.resource,
+                version.status,
+            )
+        })
+        .collect();
+    access::check_resource_reads(
+        &config.get(),
+        request_headers,
+        entries.iter().filter_map(|entry| entry.get("resource")),
+    )?;
+    transform_entries(transforms, &mut entries).await?;
+
+    let page_url =
+        |offset: u32| format!("{}/{}?_count={}&_offset={}", base_url, path, count, offset);
+    let mut links = vec![json!({ "relation": "self", "url": page_url(offset) })];
+    if count > 0 && (offset as i64 + count as i64) < total {
+        links.push(json!({ "relation": "next", "url": page_url(offset + count) }));
+    }
+    if offset > 0 {
+        links.push(json!({
+            "relation": "previous",
+            "url": page_url(offset.saturating_sub(count)),
+        }));
+    }
+
+    let mut bundle = json!({
+        "resourceType": "Bundle",
+        "type": "history",
+        "total": total,
+        "link": links,
+        "entry": entries
+    });
+    if let (Some(ts), Value::Object(ref mut map)) = (bundle_last_updated, &mut bundle) {
+        map.insert("meta".to_string(), json!({ "lastUpdated": ts }));
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((StatusCode::OK, headers, Json(bundle)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+    use crate::routes::router;
+    use crate::state::AppState;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn get(app: axum::Router, uri: &str) -> (StatusCode, Value) {
+        let response = app
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_type_history_is_newest_first_and_paged() {
+        let db = setup_test_db().await;
+        let created = db.create_patient(Patient::new()).await.unwrap();
+        let id = created.id.clone().unwrap();
+        let mut updated = created.clone();
+        updated.gender = Some("female".to_string());
+        db.update_patient(&id, updated).await.unwrap();
+        db.delete_patient(&id).await.unwrap();
+
+        let app = router(AppState::new(
+            db,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        ));
+        let (status, bundle) = get(app.clone(), "/fhir/Patient/_history?_count=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bundle["type"], "history");
+        assert!(bundle["total"].as_i64().unwrap() >= 3);
+        assert_eq!(bundle["entry"].as_array().unwrap().len(), 2);
+        assert_eq!(bundle["link"][1]["relation"], "next");
+        assert!(bundle["link"][1]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/fhir/Patient/_history?_count=2&_offset=2"));
+
+        let (_, page) = get(app.clone(), "/fhir/Patient/_history?_count=100").await;
+        let entries = page["entry"].as_array().unwrap();
+        let modified: Vec<&str> = entries
+            .iter()
+            .map(|e| e["response"]["lastModified"].as_str().unwrap())
+            .collect();
+        assert!(modified.windows(2).all(|w| w[0] >= w[1]));
+        let ours: Vec<&str> = entries
+            .iter()
+            .filter(|e| e["fullUrl"].as_str().unwrap().ends_with(&id))
+            .map(|e| e["request"]["method"].as_str().unwrap())
+            .collect();
+        assert_eq!(ours, vec!["DELETE", "PUT", "POST"]);
+
+        let (_, second) = get(app.clone(), "/fhir/_history?_count=2&_offset=2").await;
+        assert_eq!(second["type"], "history");
+        assert!(second["link"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|l| l["relation"] == "previous"));
+        assert!(second["link"][0]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/fhir/_history?_count=2&_offset=2"));
+    }
+
+    #[tokio::test]
+    async fn test_instance_history_since_and_at() {
+        let db = setup_test_db().await;
+        let created = db.create_patient(Patient::new()).await.unwrap();
+        let id = created.id.clone().unwrap();
+        let app = router(AppState::new(
+            db,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        ));
+
+        let (status, bundle) = get(
+            app.clone(),
+            &format!("/fhir/Patient/{}/_history?_since=2000-01-01T00:00:00Z", id),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bundle["total"], 1);
+        assert!(bundle["link"][0]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/_history?_since=2000-01-01T00%3A00%3A00Z"));
+
+        let (_, bundle) = get(
+            app.clone(),
+            &format!("/fhir/Patient/{}/_history?_at=2000-01-01T00:00:00Z", id),
+        )
+        .await;
+        assert_eq!(bundle["total"], 0);
+
+        let (status, outcome) = get(
+            app,
+            &format!("/fhir/Patient/{}/_history?_since=yesterday", id),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome["issue"][0]["code"], "invalid");
+    }
+
+    #[tokio::test]
+    async fn test_type_history_not_supported_without_history_table() {
+        let db = setup_test_db().await;
+        db.set_history_enabled(false);
+        let app = router(AppState::new(
+            db,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        ));
+
+        let (status, outcome) = get(app, "/fhir/_history").await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(outcome["issue"][0]["code"], "not-supported");
+    }
+}
@@ -0,0 +1,700 @@
+//! Searches run asynchronously. A `GET /fhir/<Type>` search sent with
+//! `Prefer: respond-async` is answered with `202 Accepted` and the job's
+//! status URL in `Content-Location`; the job writes every match, in batches
+//! of [`EXPORT_BATCH`], to an NDJSON file under `export_dir`. `_count` and
+//! `_offset` do not apply, and the response transforms, `_elements` and
+//! enforced Consents are applied as they would be to a search page.
+//!
+//! `GET /fhir/_jobs/:id` answers `202` with `X-Progress` while the job runs
+//! and a manifest once it is done; the file is served from
+//! `GET /fhir/_jobs/:id/output/0`. `DELETE /fhir/_jobs/:id` cancels the job
+//! and removes the file. With an `X-Callback-Url` header the outcome is also
+//! POSTed there when the job finishes (see [`JobCallback`]).
+//!
+//! `GET /fhir/$export` starts a Bulk Data system export as such a job,
+//! writing a file per resource type; see [`export_system`].
+
+use super::resource::ErrorResponse;
+use super::{history, observation, patient, resource};
+use crate::config::ServerConfig;
+use crate::consent::ConsentFilter;
+use crate::db::{bulk_export_sql, Database, SearchSql, StoredResource};
+use crate::elements::Elements;
+use crate::jobs::{JobCallback, JobHandle, JobOutput, JobState, Jobs};
+use crate::models::OperationOutcome;
+use crate::state::AppState;
+use crate::transform::ResponsePipeline;
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+/// Matches read from the database, transformed and written at a time
+pub const EXPORT_BATCH: u32 = 1000;
+
+/// Whether the client asked for the asynchronous request pattern
+fn prefers_async(headers: &HeaderMap) -> bool {
+    super::prefers(headers, "respond-async")
+}
+
+/// The job callback a request asks for with `X-Callback-Url`
+fn requested_callback(
+    config: &ServerConfig,
+    headers: &HeaderMap,
+) -> Result<Option<JobCallback>, ErrorResponse> {
+    let Some(url) = headers.get("X-Callback-Url") else {
+        return Ok(None);
+    };
+    if !config.job_callbacks.enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "Job callbacks are not enabled on this server: [job_callbacks] secret is not set",
+            )),
+        ));
+    }
+    let invalid = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid",
+                message,
+                "X-Callback-Url",
+            )),
+        )
+    };
+    let url = url
+        .to_str()
+        .map_err(|_| invalid("X-Callback-Url must be a URL".to_string()))?;
+    config
+        .job_callbacks
+        .callback(url, &history::base_url())
+        .map(Some)
+        .map_err(invalid)
+}
+
+/// Resource type of a type-level search, e.g. `Patient` for `/fhir/Patient`
+fn searched_type(path: &str) -> Option<&str> {
+    let resource_type = path.strip_prefix("/fhir/")?;
+    let searchable = !resource_type.contains('/')
+        && resource_type.starts_with(|c: char| c.is_ascii_uppercase())
+        // Search parameters are served from the registry, not the store
+        && resource_type != "SearchParameter";
+    searchable.then_some(resource_type)
+}
+
+/// Start an export job for searches sent with `Prefer: respond-async`;
+/// everything else is passed on
+pub async fn respond_async(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method() != Method::GET || !prefers_async(request.headers()) {
+        return next.run(request).await;
+    }
+    let Some(resource_type) = searched_type(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let query = request.uri().query().unwrap_or("");
+
+    let search = match resource_type {
+        "Patient" => patient::export_search(
+            &state.db,
+            &state.config,
+            &state.search_params,
+            request.headers(),
+            query,
+        ),
+        "Observation" => {
+            observation::export_search(&state.db, &state.config, &state.search_params, query)
+        }
+        other => resource::export_search(&state.db, &state.config, other, query),
+    };
+    let search = match search {
+        Ok(search) => {
+            ConsentFilter::of(&state.config, request.extensions()).search(&state.db, search)
+        }
+        Err(e) => return e.into_response(),
+    };
+    let callback = match requested_callback(&state.config.get(), request.headers()) {
+        Ok(callback) => callback,
+        Err(e) => return e.into_response(),
+    };
+
+    let resource_type = resource_type.to_string();
+    let dir = state.config.get().export_dir.clone();
+    let db = state.db.clone();
+    let transforms = state.transforms.clone();
+    let elements = request.extensions().get::<Elements>().cloned();
+    let id = state
+        .jobs
+        .start(format!("GET {}", request.uri()), callback, |job| {
+            export(db, transforms, elements, search, resource_type, dir, job)
+        });
+    tracing::info!(job = %id, "Started export of {}", request.uri());
+
+    (
+        StatusCode::ACCEPTED,
+        [(
+            header::CONTENT_LOCATION,
+            format!("{}/fhir/_jobs/{}", history::base_url(), id),
+        )],
+    )
+        .into_response()
+}
+
+async fn export(
+    db: Arc<Database>,
+    transforms: Arc<ResponsePipeline>,
+    elements: Option<Elements>,
+    search: SearchSql,
+    resource_type: String,
+    dir: PathBuf,
+    job: JobHandle,
+) -> anyhow::Result<Vec<JobOutput>> {
+    create_export_dir(&dir).await?;
+    let path = dir.join(format!("{}.ndjson", job.id()));
+    let count = write_ndjson(
+        &db,
+        &transforms,
+        elements.as_ref(),
+        &search,
+        &path,
+        &job,
+        |count| format!("{} resources written", count),
+    )
+    .await?;
+
+    Ok(vec![JobOutput {
+        resource_type,
+        path,
+        count,
+    }])
+}
+
+async fn create_export_dir(dir: &FilePath) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create export directory {}", dir.display()))
+}
+
+/// Write every match of `search` to `path`, one resource per line, and
+/// return how many there were; `progress` words the count for `X-Progress`
+async fn write_ndjson(
+    db: &Database,
+    transforms: &ResponsePipeline,
+    elements: Option<&Elements>,
+    search: &SearchSql,
+    path: &FilePath,
+    job: &JobHandle,
+    progress: impl Fn(u64) -> String,
+) -> anyhow::Result<u64> {
+    job.track(path.to_path_buf());
+    let file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("Failed to create export file {}", path.display()))?;
+    let mut file = BufWriter::new(file);
+
+    let mut after = None;
+    let mut count = 0;
+    loop {
+        let batch = db.search_after(search, after, EXPORT_BATCH).await?;
+        let Some(last) = batch.last() else { break };
+        after = Some(last.id);
+
+        let resources = batch
+            .into_iter()
+            .map(StoredResource::into_resource)
+            .collect();
+        for mut resource in transforms.apply(resources).await? {
+            if let Some(elements) = elements {
+                elements.project(&mut resource);
+            }
+            let mut line = serde_json::to_vec(&resource)?;
+            line.push(b'\n');
+            file.write_all(&line).await?;
+            count += 1;
+        }
+        job.report(progress(count));
+    }
+    file.flush().await?;
+    Ok(count)
+}
+
+/// `_outputFormat` values of `$export`, all meaning NDJSON
+const NDJSON_FORMATS: &[&str] = &["application/fhir+ndjson", "application/ndjson", "ndjson"];
+
+/// Query parameters of `$export`
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(rename = "_type")]
+    types: Option<String>,
+    #[serde(rename = "_since")]
+    since: Option<String>,
+    #[serde(rename = "_outputFormat")]
+    output_format: Option<String>,
+}
+
+/// The types `$export` writes: those in `_type`, or else Patient,
+/// Observation and the `resource_types` of the config
+fn export_types(config: &ServerConfig, types: Option<&str>) -> Result<Vec<String>, ErrorResponse> {
+    let Some(types) = types else {
+        let mut all = vec!["Patient".to_string(), "Observation".to_string()];
+        all.extend(config.resource_types.iter().cloned());
+        return Ok(all);
+    };
+    let mut requested: Vec<String> = Vec::new();
+    for resource_type in types.split(',').map(str::trim) {
+        let exported = matches!(resource_type, "Patient" | "Observation")
+            || (config.serves_resource_type(resource_type) && resource_type != "SearchParameter");
+        if !exported {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error_with_location(
+                    "not-supported",
+                    format!(
+                        "Resource type '{}' in _type is not exported by this server",
+                        resource_type
+                    ),
+                    "_type",
+                )),
+            ));
+        }
+        if !requested.iter().any(|t| t == resource_type) {
+            requested.push(resource_type.to_string());
+        }
+    }
+    Ok(requested)
+}
+
+/// `GET /fhir/$export`: start a Bulk Data system export, one NDJSON file
+/// per resource type, of everything stored or what changed after `_since`.
+/// Only the asynchronous request pattern is offered; the job is polled and
+/// its files fetched like those of any other job.
+pub async fn export_system(
+    State(state): State<AppState>,
+    consent: ConsentFilter,
+    headers: HeaderMap,
+    uri: axum::http::Uri,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ErrorResponse> {
+    if !prefers_async(&headers) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid",
+                "$export runs asynchronously; send Prefer: respond-async",
+                "Prefer",
+            )),
+        ));
+    }
+    if let Some(format) = &query.output_format {
+        if !NDJSON_FORMATS.contains(&format.as_str()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error_with_location(
+                    "not-supported",
+                    format!(
+                        "_outputFormat {} is not supported; exports are written as application/fhir+ndjson",
+                        format
+                    ),
+                    "_outputFormat",
+                )),
+            ));
+        }
+    }
+    let config = state.config.get();
+    let since = history::instant("_since", query.since.as_deref())?;
+    let types = export_types(&config, query.types.as_deref())?;
+    let callback = requested_callback(&config, &headers)?;
+
+    let dir = config.export_dir.clone();
+    let db = state.db.clone();
+    let transforms = state.transforms.clone();
+    let id = state
+        .jobs
+        .start(format!("GET {}", uri), callback, |job| async move {
+            create_export_dir(&dir).await?;
+            let mut outputs = Vec::new();
+            let mut written = 0;
+            for (done, resource_type) in types.iter().enumerate() {
+                let path = dir.join(format!("{}-{}.ndjson", job.id(), resource_type));
+                let count = write_ndjson(
+                    &db,
+                    &transforms,
+                    None,
+                    &consent.search(&db, bulk_export_sql(resource_type, since)),
+                    &path,
+                    &job,
+                    |count| {
+                        format!(
+                            "{} of {} types, {} resources written",
+                            done,
+                            types.len(),
+                            written + count
+                        )
+                    },
+                )
+                .await?;
+                written += count;
+                if count == 0 {
+                    // The manifest lists no empty files
+                    tokio::fs::remove_file(&path).await?;
+                    continue;
+                }
+                outputs.push(JobOutput {
+                    resource_type: resource_type.clone(),
+                    path,
+                    count,
+                });
+            }
+            Ok(outputs)
+        });
+    tracing::info!(job = %id, "Started bulk export of {}", uri);
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(
+            header::CONTENT_LOCATION,
+            format!("{}/fhir/_jobs/{}", history::base_url(), id),
+        )],
+    )
+        .into_response())
+}
+
+fn job_not_found(id: &str) -> ErrorResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(OperationOutcome::error(
+            "not-found",
+            format!(
+                "No job {}; jobs are forgotten when deleted or when the server restarts",
+                id
+            ),
+        )),
+    )
+}
+
+/// Status of a job: `202` while it runs, its manifest once it completed
+pub async fn get_job(
+    State(jobs): State<Arc<Jobs>>,
+    Path(id): Path<String>,
+) -> Result<Response, ErrorResponse> {
+    let job = Uuid::parse_str(&id)
+        .ok()
+        .and_then(|uuid| jobs.get(&uuid))
+        .ok_or_else(|| job_not_found(&id))?;
+
+    match job.state {
+        JobState::InProgress => {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RETRY_AFTER, "2".parse().unwrap());
+            if let Some(progress) = job.progress.and_then(|p| p.parse().ok()) {
+                headers.insert("X-Progress", progress);
+            }
+            Ok((StatusCode::ACCEPTED, headers).into_response())
+        }
+        JobState::Completed { outputs, .. } => {
+            let output: Vec<Value> = outputs
+                .iter()
+                .enumerate()
+                .map(|(index, output)| {
+                    json!({
+                        "type": output.resource_type,
+                        "url": format!("{}/fhir/_jobs/{}/output/{}", history::base_url(), id, index),
+                        "count": output.count,
+                    })
+                })
+                .collect();
+            Ok(Json(json!({
+                "transactionTime": job.started_at,
+                "request": job.request,
+                "requiresAccessToken": false,
+                "output": output,
+                "error": [],
+            }))
+            .into_response())
+        }
+        JobState::Failed { error } => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "exception",
+                format!("Job {} failed: {}", id, error),
+            )),
+        )),
+    }
+}
+
+/// An NDJSON file of a completed job, streamed from disk
+pub async fn get_job_output(
+    State(jobs): State<Arc<Jobs>>,
+    Path((id, index)): Path<(String, usize)>,
+) -> Result<Response, ErrorResponse> {
+    let job = Uuid::parse_str(&id)
+        .ok()
+        .and_then(|uuid| jobs.get(&uuid))
+        .ok_or_else(|| job_not_found(&id))?;
+    let output = match job.state {
+        JobState::Completed { outputs, .. } => outputs.into_iter().nth(index),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(OperationOutcome::error(
+                "not-found",
+                format!("Job {} has no output {}", id, index),
+            )),
+        )
+    })?;
+
+    let file = tokio::fs::File::open(&output.path).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "exception",
+                format!("Failed to open output {} of job {}: {}", index, id, e),
+            )),
+        )
+    })?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/fhir+ndjson")],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+/// Cancel a job, or forget a finished one, and remove its files
+pub async fn delete_job(
+    State(jobs): State<Arc<Jobs>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ErrorResponse> {
+    match Uuid::parse_str(&id) {
+        Ok(uuid) if jobs.remove(&uuid) => Ok(StatusCode::ACCEPTED),
+        _ => Err(job_not_found(&id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use axum::body::to_bytes;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    async fn send(app: &axum::Router, request: Request) -> (StatusCode, HeaderMap, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_prefers_async_and_searched_type() {
+        let mut headers = HeaderMap::new();
+        assert!(!prefers_async(&headers));
+        headers.insert("prefer", "return=minimal, Respond-Async".parse().unwrap());
+        assert!(prefers_async(&headers));
+
+        assert_eq!(searched_type("/fhir/Patient"), Some("Patient"));
+        assert_eq!(searched_type("/fhir/Encounter"), Some("Encounter"));
+        assert_eq!(searched_type("/fhir/Patient/1"), None);
+        assert_eq!(searched_type("/fhir/metadata"), None);
+        assert_eq!(searched_type("/fhir/_history"), None);
+        assert_eq!(searched_type("/fhir/SearchParameter"), None);
+    }
+
+    #[test]
+    fn test_requested_callback() {
+        let mut headers = HeaderMap::new();
+        let mut config = ServerConfig::default();
+        assert!(requested_callback(&config, &headers).unwrap().is_none());
+
+        headers.insert(
+            "x-callback-url",
+            "https://hooks.example.org/jobs".parse().unwrap(),
+        );
+        let (status, _) = requested_callback(&config, &headers).unwrap_err();
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+
+        config.job_callbacks.secret = "0123456789abcdef0123456789abcdef".to_string();
+        assert!(requested_callback(&config, &headers).unwrap().is_some());
+        config.job_callbacks.allowed_hosts = vec!["other.example.org".to_string()];
+        let (status, Json(outcome)) = requested_callback(&config, &headers).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            outcome.issue[0].location,
+            Some(vec!["X-Callback-Url".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_search_writes_ndjson() {
+        let config = ServerConfig {
+            export_dir: std::env::temp_dir().join(format!("fhir-export-test-{}", Uuid::new_v4())),
+            ..ServerConfig::default()
+        };
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config.clone())),
+        ));
+        let family = format!("Export{}", Uuid::new_v4().simple());
+        for _ in 0..3 {
+            let body = json!({ "resourceType": "Patient", "name": [{ "family": family }] });
+            let request = Request::post("/fhir/Patient")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            assert_eq!(send(&app, request).await.0, StatusCode::CREATED);
+        }
+
+        let search = Request::get(format!("/fhir/Patient?name={}&_count=1", family))
+            .header("prefer", "respond-async")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(&app, search).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let location = headers[header::CONTENT_LOCATION].to_str().unwrap();
+        let status_path = &location[location.find("/fhir/_jobs/").unwrap()..];
+
+        let mut manifest = None;
+        for _ in 0..100 {
+            let poll = Request::get(status_path).body(Body::empty()).unwrap();
+            let (status, _, body) = send(&app, poll).await;
+            if status == StatusCode::OK {
+                manifest = Some(serde_json::from_str::<Value>(&body).unwrap());
+                break;
+            }
+            assert_eq!(status, StatusCode::ACCEPTED);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let manifest = manifest.expect("export did not complete");
+        assert_eq!(manifest["output"][0]["type"], "Patient");
+        assert_eq!(manifest["output"][0]["count"], 3);
+
+        let output = Request::get(format!("{}/output/0", status_path))
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, output).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/fhir+ndjson");
+        let lines: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines
+            .iter()
+            .all(|p| p["name"][0]["family"] == family.as_str()));
+
+        let delete = Request::delete(status_path).body(Body::empty()).unwrap();
+        assert_eq!(send(&app, delete).await.0, StatusCode::ACCEPTED);
+        let poll = Request::get(status_path).body(Body::empty()).unwrap();
+        assert_eq!(send(&app, poll).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(std::fs::read_dir(&config.export_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&config.export_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bulk_export_writes_a_file_per_type() {
+        let config = ServerConfig {
+            export_dir: std::env::temp_dir().join(format!("fhir-bulk-test-{}", Uuid::new_v4())),
+            ..ServerConfig::default()
+        };
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config.clone())),
+        ));
+        let since = (chrono::Utc::now() - chrono::Duration::seconds(1))
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let family = format!("Bulk{}", Uuid::new_v4().simple());
+        let body = json!({ "resourceType": "Patient", "name": [{ "family": family }] });
+        let request = Request::post("/fhir/Patient")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        assert_eq!(send(&app, request).await.0, StatusCode::CREATED);
+
+        for (uri, prefer) in [
+            ("/fhir/$export", false),
+            ("/fhir/$export?_type=Encounter", true),
+            ("/fhir/$export?_outputFormat=text/csv", true),
+            ("/fhir/$export?_since=yesterday", true),
+        ] {
+            let mut request = Request::get(uri);
+            if prefer {
+                request = request.header("prefer", "respond-async");
+            }
+            let (status, _, _) = send(&app, request.body(Body::empty()).unwrap()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        let export = Request::get(format!(
+            "/fhir/$export?_type=Patient,Observation&_since={}",
+            since.replace('+', "%2B")
+        ))
+        .header("prefer", "respond-async")
+        .body(Body::empty())
+        .unwrap();
+        let (status, headers, _) = send(&app, export).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let location = headers[header::CONTENT_LOCATION].to_str().unwrap();
+        let status_path = &location[location.find("/fhir/_jobs/").unwrap()..];
+
+        let mut manifest = None;
+        for _ in 0..200 {
+            let poll = Request::get(status_path).body(Body::empty()).unwrap();
+            let (status, _, body) = send(&app, poll).await;
+            if status == StatusCode::OK {
+                manifest = Some(serde_json::from_str::<Value>(&body).unwrap());
+                break;
+            }
+            assert_eq!(status, StatusCode::ACCEPTED);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let manifest = manifest.expect("export did not complete");
+        let outputs = manifest["output"].as_array().unwrap();
+        let patients = outputs
+            .iter()
+            .position(|output| output["type"] == "Patient")
+            .expect("no Patient file");
+        assert!(outputs
+            .iter()
+            .all(|output| output["count"].as_u64() > Some(0)));
+
+        let output = Request::get(format!("{}/output/{}", status_path, patients))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(&app, output).await;
+        assert_eq!(status, StatusCode::OK);
+        let lines: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(lines.iter().all(|p| p["resourceType"] == "Patient"));
+        assert!(lines
+            .iter()
+            .any(|p| p["name"][0]["family"] == family.as_str()));
+
+        let delete = Request::delete(status_path).body(Body::empty()).unwrap();
+        assert_eq!(send(&app, delete).await.0, StatusCode::ACCEPTED);
+        assert_eq!(std::fs::read_dir(&config.export_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&config.export_dir).unwrap();
+    }
+}
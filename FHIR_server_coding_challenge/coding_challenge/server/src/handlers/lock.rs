@@ -0,0 +1,317 @@
+//! `POST /fhir/Patient/:id/$lock` and `POST /fhir/Patient/:id/$unlock`, and
+//! the 423 for writes to a patient someone else has locked; see
+//! [`crate::db::record_lock`].
+
+use super::patient::missing_patient;
+use super::resource::ErrorResponse;
+use crate::api_keys::hash_key;
+use crate::config::SharedConfig;
+use crate::db::record_lock::RecordLocked;
+use crate::db::{Database, LockOutcome, RecordLock, UnlockOutcome};
+use crate::extract::Query;
+use crate::models::OperationOutcome;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Carries the token of the lock a write or `$unlock` is made under
+pub const LOCK_TOKEN_HEADER: &str = "x-lock-token";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LockParams {
+    /// Lifetime of the lock; `record_lock_minutes` if not given
+    minutes: Option<u32>,
+    /// Who the lock is for, shown to the users it turns away
+    owner: Option<String>,
+}
+
+fn lock_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(LOCK_TOKEN_HEADER)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// 423 for a write to a patient locked under another token
+fn locked(id: &str, lock: &RecordLock) -> ErrorResponse {
+    let by = lock
+        .owner
+        .as_deref()
+        .map(|owner| format!(" by {}", owner))
+        .unwrap_or_default();
+    (
+        StatusCode::LOCKED,
+        Json(OperationOutcome::error_with_location(
+            "lock-error",
+            format!(
+                "Patient/{} is locked{} until {}; changes need the lock's X-Lock-Token",
+                id,
+                by,
+                lock.expires_at.to_rfc3339()
+            ),
+            format!("Patient/{}", id),
+        )),
+    )
+}
+
+fn not_enabled() -> ErrorResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "$lock is not available on this server: the fhir.record_lock table is missing",
+        )),
+    )
+}
+
+fn lock_failed(e: anyhow::Error) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!("Failed to check the patient's lock: {}", e),
+        )),
+    )
+}
+
+/// SHA-256 of the `X-Lock-Token` a write is sent with, which the write's
+/// transaction checks the patient's lock against
+pub(super) fn lock_token_hash(request_headers: &HeaderMap) -> Option<String> {
+    lock_token(request_headers).map(hash_key)
+}
+
+/// 423 for a write refused with [`RecordLocked`], if `e` is one
+pub(super) fn lock_refusal(e: &anyhow::Error) -> Option<ErrorResponse> {
+    e.downcast_ref::<RecordLocked>()
+        .map(|refused| locked(&refused.id, &refused.lock))
+}
+
+/// POST /fhir/Patient/:id/$lock?minutes=15&owner=Dr.%20Chen
+///
+/// Sent with the `X-Lock-Token` of a lock, renews it.
+pub async fn lock_patient(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    Path(id): Path<String>,
+    Query(params): Query<LockParams>,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    if !db.record_locks_enabled() {
+        return Err(not_enabled());
+    }
+    let max_minutes = config.get().record_lock_minutes;
+    let minutes = params.minutes.unwrap_or(max_minutes);
+    if minutes == 0 || minutes > max_minutes {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid",
+                format!(
+                    "minutes must be between 1 and {}, the longest this server locks for",
+                    max_minutes
+                ),
+                "minutes",
+            )),
+        ));
+    }
+    match db.get_patient(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(missing_patient(&db, &id).await),
+        Err(e) => return Err(lock_failed(e)),
+    }
+
+    let uuid = Uuid::parse_str(&id).map_err(|e| lock_failed(e.into()))?;
+    let token = match lock_token(&request_headers) {
+        Some(token) => token.to_string(),
+        None => Uuid::new_v4().simple().to_string(),
+    };
+    let outcome = db
+        .lock_record(
+            "Patient",
+            uuid,
+            &hash_key(&token),
+            params.owner.as_deref(),
+            Duration::from_secs(u64::from(minutes) * 60),
+        )
+        .await
+        .map_err(lock_failed)?;
+    let lock = match outcome {
+        LockOutcome::Locked(lock) => lock,
+        LockOutcome::Held(lock) => return Err(locked(&id, &lock)),
+    };
+    tracing::info!(
+        target: "fhir_server::audit",
+        patient_id = %id,
+        owner = lock.owner.as_deref().unwrap_or(""),
+        expires_at = %lock.expires_at,
+        "patient locked"
+    );
+
+    let mut parameters = vec![json!({ "name": "token", "valueString": token })];
+    if let Some(owner) = &lock.owner {
+        parameters.push(json!({ "name": "owner", "valueString": owner }));
+    }
+    parameters.push(json!({ "name": "expires", "valueInstant": lock.expires_at }));
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((
+        StatusCode::OK,
+        headers,
+        Json(json!({ "resourceType": "Parameters", "parameter": parameters })),
+    ))
+}
+
+/// POST /fhir/Patient/:id/$unlock with the lock's `X-Lock-Token`; a patient
+/// that is not locked is already unlocked
+pub async fn unlock_patient(
+    State(db): State<Arc<Database>>,
+    Path(id): Path<String>,
+    request_headers: HeaderMap,
+) -> Result<StatusCode, ErrorResponse> {
+    if !db.record_locks_enabled() {
+        return Err(not_enabled());
+    }
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return Err(missing_patient(&db, &id).await);
+    };
+    let token_hash = lock_token(&request_headers).map(hash_key);
+    match db
+        .unlock_record("Patient", uuid, token_hash.as_deref())
+        .await
+        .map_err(lock_failed)?
+    {
+        UnlockOutcome::Released => {
+            tracing::info!(target: "fhir_server::audit", patient_id = %id, "patient unlocked");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        UnlockOutcome::NotLocked => Ok(StatusCode::NO_CONTENT),
+        UnlockOutcome::Held(lock) => Err(locked(&id, &lock)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+    use crate::config::ServerConfig;
+    use crate::db::tests::setup_test_db;
+    use crate::models::Patient;
+    use crate::state::AppState;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::Request;
+    use chrono::Utc;
+    use tower::ServiceExt;
+
+    async fn app(clock: Arc<TestClock>) -> (axum::Router, Arc<Database>) {
+        let state = AppState::new(
+            setup_test_db().await.with_clock(clock),
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        );
+        let db = state.db.clone();
+        (crate::routes::router(state), db)
+    }
+
+    async fn send(
+        app: &axum::Router,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(LOCK_TOKEN_HEADER, token);
+        }
+        let body = match method {
+            "PUT" => {
+                request = request.header("content-type", "application/fhir+json");
+                Body::from(json!({ "resourceType": "Patient", "gender": "female" }).to_string())
+            }
+            _ => Body::empty(),
+        };
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn token(parameters: &Value) -> String {
+        parameters["parameter"][0]["valueString"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_locked_patients_refuse_other_writers() {
+        let clock = Arc::new(TestClock::new(Utc::now()));
+        let (app, db) = app(clock.clone()).await;
+        let id = db.create_patient(Patient::new()).await.unwrap().id.unwrap();
+        let patient = format!("/fhir/Patient/{}", id);
+        let lock = format!("{}/$lock", patient);
+        let unlock = format!("{}/$unlock", patient);
+
+        let (status, parameters) = send(
+            &app,
+            "POST",
+            &format!("{}?minutes=10&owner=Dr.%20Chen", lock),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parameters["parameter"][1]["valueString"], "Dr. Chen");
+        let held = token(&parameters);
+
+        let (status, outcome) = send(&app, "PUT", &patient, None).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(outcome["issue"][0]["code"], "lock-error");
+        assert!(outcome["issue"][0]["diagnostics"]
+            .as_str()
+            .unwrap()
+            .contains("by Dr. Chen"));
+        let (status, _) = send(&app, "DELETE", &patient, Some("someone-else")).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        let (status, _) = send(&app, "POST", &lock, None).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        let (status, _) = send(&app, "POST", &unlock, None).await;
+        assert_eq!(status, StatusCode::LOCKED);
+
+        // The holder writes, and renews the lock, with its token
+        let (status, _) = send(&app, "PUT", &patient, Some(&held)).await;
+        assert_eq!(status, StatusCode::OK);
+        clock.advance(chrono::Duration::minutes(5));
+        let (status, renewed) = send(&app, "POST", &lock, Some(&held)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(token(&renewed), held);
+        assert_eq!(renewed["parameter"][1]["valueString"], "Dr. Chen");
+        let (status, _) = send(&app, "POST", &unlock, Some(&held)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, "PUT", &patient, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Locks expire by themselves
+        let (status, _) = send(&app, "POST", &format!("{}?minutes=10", lock), None).await;
+        assert_eq!(status, StatusCode::OK);
+        clock.advance(chrono::Duration::minutes(11));
+        let (status, _) = send(&app, "PUT", &patient, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send(&app, "POST", &format!("{}?minutes=0", lock), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let missing = format!("/fhir/Patient/{}/$lock", Uuid::new_v4());
+        let (status, _) = send(&app, "POST", &missing, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
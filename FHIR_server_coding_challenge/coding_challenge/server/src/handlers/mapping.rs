@@ -0,0 +1,296 @@
+//! `POST /fhir/ConceptMap/:id/$transform` and
+//! `POST /fhir/StructureMap/:id/$transform`: the posted resource rewritten
+//! with a stored map; see [`crate::mapping`] for what the maps may contain.
+//! Nothing is stored, so a client can check the result before writing it.
+
+use super::resource::{not_found, processing_error, ErrorResponse};
+use crate::db::conformance::Canonical;
+use crate::db::Database;
+use crate::mapping::{ConceptMapping, MappingError, StructureMapping};
+use crate::models::OperationOutcome;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A stored map, ready to apply
+pub(super) enum Mapping {
+    Concept(ConceptMapping),
+    /// With the ConceptMaps its `translate` targets use, by canonical
+    Structure(StructureMapping, HashMap<String, ConceptMapping>),
+}
+
+pub(super) fn mapping_error(e: MappingError) -> ErrorResponse {
+    let outcome = match e.location {
+        Some(location) => OperationOutcome::error_with_location(e.code, e.message, location),
+        None => OperationOutcome::error(e.code, e.message),
+    };
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(outcome))
+}
+
+impl Mapping {
+    /// Read the ConceptMap or StructureMap `id`
+    pub(super) async fn load(
+        db: &Database,
+        resource_type: &str,
+        id: &str,
+    ) -> Result<Self, ErrorResponse> {
+        let resource = match db.get_resource(resource_type, id).await {
+            Ok(Some(stored)) => stored.into_resource(),
+            Ok(None) => return Err(not_found(resource_type, id)),
+            Err(e) => return Err(processing_error("retrieve", resource_type, e)),
+        };
+        if resource_type == "ConceptMap" {
+            return ConceptMapping::parse(&resource)
+                .map(Mapping::Concept)
+                .map_err(mapping_error);
+        }
+
+        let map = StructureMapping::parse(&resource).map_err(mapping_error)?;
+        let mut concept_maps = HashMap::new();
+        for canonical in map.concept_maps() {
+            let stored = db
+                .resolve_canonical(&["ConceptMap"], &Canonical::parse(canonical))
+                .await
+                .map_err(|e| processing_error("resolve", canonical, e))?;
+            // Unresolved maps fail the rules that use them
+            if let Some(stored) = stored {
+                let concept_map =
+                    ConceptMapping::parse(&stored.into_resource()).map_err(mapping_error)?;
+                concept_maps.insert(canonical.to_string(), concept_map);
+            }
+        }
+        Ok(Mapping::Structure(map, concept_maps))
+    }
+
+    pub(super) fn apply(&self, mut resource: Value) -> Result<Value, MappingError> {
+        match self {
+            Mapping::Concept(map) => {
+                map.apply(&mut resource)?;
+                Ok(resource)
+            }
+            Mapping::Structure(map, concept_maps) => map.apply(&resource, concept_maps),
+        }
+    }
+}
+
+/// The resource to transform: the body, or the `content` of a Parameters
+/// body as `$transform` defines it
+fn transform_source(body: Value) -> Result<Value, ErrorResponse> {
+    if body.get("resourceType").and_then(Value::as_str) != Some("Parameters") {
+        return Ok(body);
+    }
+    body.get("parameter")
+        .and_then(Value::as_array)
+        .and_then(|parameters| {
+            parameters
+                .iter()
+                .find(|p| p.get("name").and_then(Value::as_str) == Some("content"))
+        })
+        .and_then(|parameter| parameter.get("resource"))
+        .cloned()
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error_with_location(
+                    "required",
+                    "$transform needs the resource to transform, as the body or the content parameter",
+                    "Parameters.parameter",
+                )),
+            )
+        })
+}
+
+async fn transform(
+    db: &Database,
+    resource_type: &str,
+    id: &str,
+    body: Value,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    let source = transform_source(body)?;
+    let mapping = Mapping::load(db, resource_type, id).await?;
+    let transformed = mapping.apply(source).map_err(mapping_error)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((StatusCode::OK, headers, Json(transformed)))
+}
+
+/// The posted resource with its Codings and Identifiers translated
+pub async fn transform_with_concept_map(
+    State(db): State<Arc<Database>>,
+    Path(id): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    transform(&db, "ConceptMap", &id, body).await
+}
+
+/// The resource the StructureMap's rules build from the posted one
+pub async fn transform_with_structure_map(
+    State(db): State<Arc<Database>>,
+    Path(id): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    transform(&db, "StructureMap", &id, body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::Request;
+    use serde_json::json;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    async fn app() -> axum::Router {
+        crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        ))
+    }
+
+    async fn post(app: &axum::Router, uri: &str, body: String) -> (StatusCode, Value) {
+        let request = Request::post(uri)
+            .header("content-type", "application/fhir+json")
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_transform_with_stored_maps() {
+        let app = app().await;
+        let suffix = Uuid::new_v4().simple().to_string();
+        let concept_map_url = format!("http://example.org/fhir/ConceptMap/mrn-{}", suffix);
+        let (status, concept_map) = post(
+            &app,
+            "/fhir/ConceptMap",
+            json!({
+                "resourceType": "ConceptMap",
+                "url": concept_map_url,
+                "status": "active",
+                "group": [{
+                    "source": "urn:legacy:sex",
+                    "target": "http://hl7.org/fhir/administrative-gender",
+                    "element": [{ "code": "W", "target": [{ "code": "female", "equivalence": "equivalent" }] }]
+                }, {
+                    "source": "urn:oid:1.2.3.4",
+                    "target": "http://hospital.example.org/mrn",
+                    "unmapped": { "mode": "provided" }
+                }]
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let concept_map_id = concept_map["id"].as_str().unwrap();
+
+        let patient = json!({
+            "resourceType": "Patient",
+            "identifier": [{ "system": "urn:oid:1.2.3.4", "value": "4711" }]
+        });
+        let (status, transformed) = post(
+            &app,
+            &format!("/fhir/ConceptMap/{}/$transform", concept_map_id),
+            patient.to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            transformed["identifier"][0]["system"],
+            "http://hospital.example.org/mrn"
+        );
+
+        let (status, structure_map) = post(
+            &app,
+            "/fhir/StructureMap",
+            json!({
+                "resourceType": "StructureMap",
+                "url": format!("http://example.org/fhir/StructureMap/legacy-{}", suffix),
+                "status": "active",
+                "group": [{
+                    "name": "patient",
+                    "input": [{ "name": "src", "mode": "source" }, { "name": "tgt", "mode": "target" }],
+                    "rule": [{
+                        "name": "sex",
+                        "source": [{ "context": "src", "element": "extension", "variable": "e" }],
+                        "rule": [{
+                            "name": "code",
+                            "source": [{ "context": "e", "element": "valueCoding", "variable": "c" }],
+                            "target": [{
+                                "context": "tgt",
+                                "element": "gender",
+                                "transform": "translate",
+                                "parameter": [
+                                    { "valueId": "c" },
+                                    { "valueString": concept_map_url },
+                                    { "valueString": "code" }
+                                ]
+                            }]
+                        }]
+                    }]
+                }]
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let structure_map_id = structure_map["id"].as_str().unwrap();
+        let legacy = |code: &str| {
+            json!({
+                "resourceType": "Patient",
+                "extension": [{
+                    "url": "http://example.org/legacy-sex",
+                    "valueCoding": { "system": "urn:legacy:sex", "code": code }
+                }]
+            })
+        };
+        let transform = format!("/fhir/StructureMap/{}/$transform", structure_map_id);
+        let parameters = json!({
+            "resourceType": "Parameters",
+            "parameter": [{ "name": "content", "resource": legacy("W") }]
+        });
+        let (status, transformed) = post(&app, &transform, parameters.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            transformed,
+            json!({ "resourceType": "Patient", "gender": "female" })
+        );
+        let (status, outcome) = post(&app, &transform, legacy("U").to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(outcome["issue"][0]["code"], "code-invalid");
+
+        // Imports rewrite every line with the map first
+        let (status, report) = post(
+            &app,
+            &format!("/admin/import?map=StructureMap/{}", structure_map_id),
+            [legacy("W"), legacy("U")].map(|l| l.to_string()).join("\n"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["imported"], 1);
+        assert_eq!(report["errors"][0]["line"], 2);
+        let (status, _) = post(&app, "/admin/import?map=Patient/1", String::new()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        for path in [
+            format!("/fhir/StructureMap/{}", structure_map_id),
+            format!("/fhir/ConceptMap/{}", concept_map_id),
+        ] {
+            let delete = Request::delete(path).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(delete).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config::{ServerConfig, SharedConfig};
+use crate::db::shadow::ShadowCounts;
+use crate::db::Database;
+use crate::jobs::Jobs;
+use crate::metrics::{Metrics, OpenMetricsWriter, CONTENT_TYPE, HISTORY_DEPTH_BUCKETS};
+use crate::slo::LatencyTracker;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use std::sync::Arc;
+
+/// Render all KPIs. Store-derived values that cannot be read are left out
+/// rather than failing the scrape, so process counters keep flowing.
+pub async fn render_metrics(
+    db: &Database,
+    metrics: &Metrics,
+    jobs: &Jobs,
+    latency: &LatencyTracker,
+    config: &ServerConfig,
+) -> String {
+    let mut out = OpenMetricsWriter::default();
+    metrics.write_counters(&mut out);
+    latency.write_metrics(config, &mut out);
+
+    match db.patient_totals().await {
+        Ok((total, last_hour)) => {
+            out.gauge("fhir_patients", "Patients currently stored", total as f64);
+            out.gauge(
+                "fhir_patients_created_last_hour",
+                "Patients created in the last hour across all servers",
+                last_hour as f64,
+            );
+        }
+        Err(e) => tracing::warn!("Failed to read patient totals for metrics: {}", e),
+    }
+
+    let shadow = db.shadow_counts();
+    if shadow != ShadowCounts::default() {
+        out.counter(
+            "fhir_shadow_matches",
+            "Patient writes the extension path reproduced",
+            shadow.matches,
+        );
+        out.counter(
+            "fhir_shadow_mismatches",
+            "Patient writes the extension path stored differently",
+            shadow.mismatches,
+        );
+        out.counter(
+            "fhir_shadow_errors",
+            "Patient writes the extension path failed to replay",
+            shadow.errors,
+        );
+    }
+
+    out.histogram(
+        "fhir_job_duration_seconds",
+        "Duration of the background jobs finished by this server",
+        &jobs.duration_histogram(),
+    );
+
+    if db.changes_enabled() {
+        let (channels, slowest) = metrics.subscription_channels();
+        out.gauge(
+            "fhir_subscription_channels",
+            "Subscription channels open on this server",
+            channels as f64,
+        );
+        let backlog = match slowest {
+            Some(cursor) => db.changes_pending_after(cursor).await,
+            None => Ok(0),
+        };
+        match backlog {
+            Ok(backlog) => out.gauge(
+                "fhir_subscription_backlog",
+                "Changes the slowest subscription channel of this server has yet to read",
+                backlog as f64,
+            ),
+            Err(e) => tracing::warn!("Failed to read the subscription backlog for metrics: {}", e),
+        }
+    }
+
+    if db.history_enabled() {
+        match db.history_depth_histogram(HISTORY_DEPTH_BUCKETS).await {
+            Ok(histogram) => out.histogram(
+                "fhir_patient_history_depth",
+                "Stored versions per patient",
+                &histogram,
+            ),
+            Err(e) => tracing::warn!("Failed to read history depth for metrics: {}", e),
+        }
+    }
+
+    out.finish()
+}
+
+/// GET /metrics
+pub async fn get_metrics(
+    State(db): State<Arc<Database>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(jobs): State<Arc<Jobs>>,
+    State(latency): State<Arc<LatencyTracker>>,
+    State(config): State<Arc<SharedConfig>>,
+) -> (StatusCode, HeaderMap, String) {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", CONTENT_TYPE.parse().unwrap());
+
+    let text = render_metrics(&db, &metrics, &jobs, &latency, &config.get()).await;
+    (StatusCode::OK, headers, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    #[tokio::test]
+    async fn test_render_metrics_includes_store_kpis() {
+        let db = setup_test_db().await;
+        let text = render_metrics(
+            &db,
+            &Metrics::default(),
+            &Jobs::default(),
+            &LatencyTracker::default(),
+            &ServerConfig::default(),
+        )
+        .await;
+
+        assert!(text.contains("fhir_patients_created_total 0\n"));
+        assert!(text.contains("# TYPE fhir_patients_created_last_hour gauge\n"));
+        assert!(text.contains("fhir_patient_history_depth_bucket{le=\"+Inf\"}"));
+        assert!(text.contains("fhir_job_duration_seconds_count 0\n"));
+        assert!(text.contains("fhir_subscription_backlog 0\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,276 @@
+pub mod admin;
+pub mod bundle;
+pub mod changes;
+pub mod conformance;
+pub mod history;
+pub mod jobs;
+pub mod lock;
+pub mod mapping;
+pub mod metadata;
+pub mod metrics;
+pub mod observation;
+pub mod patient;
+pub mod provenance;
+pub mod resource;
+pub mod search_parameter;
+pub mod sharing;
+pub mod subscription;
+
+use crate::db::conformance::Canonical;
+use crate::db::{Database, PageStart};
+use crate::models::{BundleLink, OperationOutcome, OperationOutcomeIssue};
+use crate::profiles::Profile;
+use crate::search::TextCriteria;
+use crate::validation::{self, ValidationHooks};
+use axum::{
+    http::{HeaderMap, StatusCode, Uri},
+    response::Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Parameters set per page by [`search_links`]
+const PAGING_PARAMETERS: &[&str] = &["_count", "_offset", "_page_token"];
+
+/// Check modifier extensions and canonical references and run the
+/// configured validation hooks; any error issue rejects the write with 422
+pub(crate) async fn check_validation_hooks(
+    db: &Database,
+    hooks: &ValidationHooks,
+    operation: &str,
+    resource: &impl Serialize,
+) -> Result<(), (StatusCode, Json<OperationOutcome>)> {
+    let resource = serde_json::to_value(resource).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to serialize resource: {}", e),
+            )),
+        )
+    })?;
+
+    let mut issues = hooks.unknown_modifiers(&resource);
+    let (unresolved, stored_profiles) = resolve_canonicals(db, hooks, &resource).await?;
+    issues.extend(unresolved);
+    issues.extend(hooks.profile_issues(&resource, &stored_profiles));
+    issues.extend(hooks.validate(operation, &resource).await);
+    if validation::has_errors(&issues) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(OperationOutcome::from_issues(issues)),
+        ));
+    }
+    Ok(())
+}
+
+/// A `not-found` error for every canonical reference in `resource` that
+/// names no stored conformance resource nor a profile of a profile pack,
+/// and, with profile validation on, the stored profiles it claims
+async fn resolve_canonicals(
+    db: &Database,
+    hooks: &ValidationHooks,
+    resource: &serde_json::Value,
+) -> Result<(Vec<OperationOutcomeIssue>, Vec<Profile>), (StatusCode, Json<OperationOutcome>)> {
+    let claimed = validation::claimed_profiles(resource);
+    let mut issues = Vec::new();
+    let mut profiles = Vec::new();
+    for reference in validation::canonical_references(resource) {
+        if reference.target == "StructureDefinition" && hooks.has_profile(&reference.canonical) {
+            continue;
+        }
+        let canonical = Canonical::parse(&reference.canonical);
+        match db.resolve_canonical(&[reference.target], &canonical).await {
+            Ok(Some(stored)) => {
+                if reference.target == "StructureDefinition"
+                    && hooks.profile_validation()
+                    && claimed.contains(&canonical.url.as_str())
+                {
+                    match Profile::parse(&stored.data) {
+                        Some(profile) => profiles.push(profile),
+                        None => issues.push(validation::unchecked_profile(&reference)),
+                    }
+                }
+            }
+            Ok(None) => issues.push(validation::unresolved_canonical(&reference)),
+            Err(e) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(OperationOutcome::error(
+                        "processing",
+                        format!("Failed to resolve {}: {}", reference.canonical, e),
+                    )),
+                ))
+            }
+        }
+    }
+    Ok((issues, profiles))
+}
+
+/// Responses must not fall back to untransformed data, so pipeline failures are 500s
+pub(crate) fn transform_error(e: anyhow::Error) -> (StatusCode, Json<OperationOutcome>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "exception",
+            format!("Failed to transform response: {:#}", e),
+        )),
+    )
+}
+
+/// 501 for `_text` or `_content` when the database lacks the full-text
+/// columns of migration 016
+pub(crate) fn check_text_search(
+    db: &Database,
+    text: &TextCriteria,
+) -> Result<(), (StatusCode, Json<OperationOutcome>)> {
+    if text.is_empty() || db.text_search_enabled() {
+        return Ok(());
+    }
+    Err((
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "_text and _content are not available on this server: the full-text columns of fhir_resources are missing",
+        )),
+    ))
+}
+
+/// Whether the `Prefer` headers of a request include `preference`, such
+/// as `respond-async` or `return=minimal`
+pub(crate) fn prefers(headers: &HeaderMap, preference: &str) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|sent| sent.trim().eq_ignore_ascii_case(preference))
+}
+
+/// `self`, `next` and `previous` links of a searchset page starting at
+/// `start` whose last match is `last`. The search parameters of `uri` are
+/// kept as sent and the paging parameters set per page: `next` continues
+/// after `last` by `_page_token`, `previous` exists for offset pages only.
+/// Without a `total` a full page is assumed to have a next one.
+pub(crate) fn search_links(
+    uri: &Uri,
+    count: u32,
+    start: PageStart,
+    total: Option<u32>,
+    last: Option<Uuid>,
+    returned: usize,
+) -> Vec<BundleLink> {
+    let criteria: Vec<&str> = uri
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| {
+            !pair.is_empty() && !PAGING_PARAMETERS.contains(&pair.split('=').next().unwrap_or(""))
+        })
+        .collect();
+    let base = format!("{}{}", history::base_url(), uri.path());
+    let link = |relation: &str, start: PageStart| {
+        let paging = match start {
+            PageStart::Offset(offset) => format!("_count={}&_offset={}", count, offset),
+            PageStart::After(id) => {
+                format!(
+                    "_count={}&_page_token={}",
+                    count,
+                    PageStart::token_after(id)
+                )
+            }
+        };
+        let mut query = criteria.clone();
+        query.push(&paging);
+        BundleLink {
+            relation: relation.to_string(),
+            url: format!("{}?{}", base, query.join("&")),
+        }
+    };
+
+    let mut links = vec![link("self", start)];
+    let has_next = match (start, total) {
+        (PageStart::Offset(offset), Some(total)) => (offset as u64 + count as u64) < total as u64,
+        _ => returned as u64 == count as u64,
+    };
+    if let Some(last) = last.filter(|_| count > 0 && has_next) {
+        links.push(link("next", PageStart::After(last)));
+    }
+    if let PageStart::Offset(offset) = start {
+        if offset > 0 {
+            links.push(link(
+                "previous",
+                PageStart::Offset(offset.saturating_sub(count)),
+            ));
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Relation and URL below the base URL of each link
+    fn relations(links: &[BundleLink]) -> Vec<(String, String)> {
+        let base = history::base_url();
+        links
+            .iter()
+            .map(|link| {
+                let path = link.url.strip_prefix(&base).unwrap_or(&link.url);
+                (link.relation.clone(), path.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_search_links_keep_the_criteria() {
+        let uri =
+            Uri::from_static("/fhir/Patient?gender=female&_count=2&_offset=2&name=Sm%C3%ADth");
+        let last = Uuid::new_v4();
+        let page = |paging: &str| {
+            format!(
+                "/fhir/Patient?gender=female&name=Sm%C3%ADth&_count=2&{}",
+                paging
+            )
+        };
+        assert_eq!(
+            relations(&search_links(
+                &uri,
+                2,
+                PageStart::Offset(2),
+                Some(5),
+                Some(last),
+                2
+            )),
+            vec![
+                ("self".to_string(), page("_offset=2")),
+                (
+                    "next".to_string(),
+                    page(&format!("_page_token={}", PageStart::token_after(last)))
+                ),
+                ("previous".to_string(), page("_offset=0")),
+            ]
+        );
+
+        // The last page, with and without a total
+        let uri = Uri::from_static("/fhir/Observation");
+        let links = relations(&search_links(
+            &uri,
+            2,
+            PageStart::Offset(4),
+            Some(5),
+            Some(last),
+            1,
+        ));
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[1].0, "previous");
+        let links = search_links(&uri, 2, PageStart::After(last), None, Some(last), 2);
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[1].relation, "next");
+        assert_eq!(
+            search_links(&uri, 2, PageStart::After(last), None, Some(last), 1).len(),
+            1
+        );
+    }
+}
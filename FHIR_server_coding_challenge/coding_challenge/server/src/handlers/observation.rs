@@ -0,0 +1,615 @@
+use super::{check_text_search, check_validation_hooks, search_links, transform_error};
+use crate::conditional::search_etag;
+use crate::config::SharedConfig;
+use crate::consent::ConsentFilter;
+use crate::db::observation::observation_search_sql;
+use crate::db::{Database, ObservationSearch, PageStart, SearchSql, Total};
+use crate::extract::Query;
+use crate::features::{Features, STRICT_SEARCH};
+use crate::models::{Bundle, BundleEntry, Observation, OperationOutcome, OBSERVATION_STATUSES};
+use crate::provenance::Attribution;
+use crate::search::modifier::{self, parse_missing};
+use crate::search::{
+    split_or, DateSearchValue, ExpressionCriterion, SearchParamRegistry, TokenParam,
+};
+use crate::transform::ResponsePipeline;
+use crate::validation::ValidationHooks;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::Json,
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+type ErrorResponse = (StatusCode, Json<OperationOutcome>);
+
+fn fhir_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    headers
+}
+
+fn not_found(id: &str) -> ErrorResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(OperationOutcome::error_with_location(
+            "not-found",
+            format!("Observation with id {} not found", id),
+            format!("Observation/{}", id),
+        )),
+    )
+}
+
+fn processing_error(action: &str, e: anyhow::Error) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!("Failed to {} observation: {}", action, e),
+        )),
+    )
+}
+
+/// Checks the model cannot express: status must be a known code
+pub(super) fn validate_observation(observation: &Observation) -> Result<(), ErrorResponse> {
+    if !OBSERVATION_STATUSES.contains(&observation.status.as_str()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::validation_error(
+                "Observation.status",
+                format!(
+                    "Unknown status '{}', expected one of: {}",
+                    observation.status,
+                    OBSERVATION_STATUSES.join(", ")
+                ),
+            )),
+        ));
+    }
+    Ok(())
+}
+
+/// Modifiers Observation search honors; others are rejected
+const SUPPORTED_MODIFIERS: &[&str] = &["code:missing", "subject:missing", "date:missing"];
+
+fn unsupported_modifier(name: &str) -> ErrorResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error_with_location(
+            "not-supported",
+            format!(
+                "Modifier {} is not supported for Observation; supported are {}",
+                name,
+                SUPPORTED_MODIFIERS.join(", ")
+            ),
+            name,
+        )),
+    )
+}
+
+/// Parse the query string; `date` may repeat, so a struct extractor is not
+/// enough. Parameters of `registry` evaluated from their expression are
+/// searched too; other unknown parameters are ignored unless `strict`, but
+/// unsupported modifiers never are. Result parameters such as `_elements`
+/// are handled before the search and always pass.
+fn parse_search(
+    config: &SharedConfig,
+    registry: &SearchParamRegistry,
+    params: Vec<(String, String)>,
+    strict: bool,
+) -> Result<ObservationSearch, ErrorResponse> {
+    let invalid = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error("invalid", message)),
+        )
+    };
+    let missing =
+        |name: &str, value: &str| parse_missing(name, value).map_err(|e| invalid(e.to_string()));
+
+    if let Some(name) = modifier::unsupported(
+        params.iter().map(|(name, _)| name.as_str()),
+        SUPPORTED_MODIFIERS,
+    ) {
+        return Err(unsupported_modifier(name));
+    }
+
+    let mut search = ObservationSearch::default();
+    let mut count = None;
+    let mut offset = None;
+    let mut page_token = None;
+    for (name, value) in params {
+        let expression = registry
+            .get("Observation", &name)
+            .and_then(|param| ExpressionCriterion::parse(&param, &value));
+        if let Some(criterion) = expression {
+            search
+                .expressions
+                .push(criterion.map_err(|e| invalid(e.to_string()))?);
+            continue;
+        }
+        if search.text.add(&name, &value) {
+            continue;
+        }
+        match name.as_str() {
+            "code" => search
+                .code
+                .extend(split_or(&value).iter().map(|v| TokenParam::parse(v))),
+            "subject" => search
+                .subject
+                .extend(split_or(&value).into_iter().filter(|v| !v.is_empty())),
+            "patient" => search
+                .patient
+                .extend(split_or(&value).into_iter().filter(|v| !v.is_empty())),
+            "date" => search.date.push(
+                split_or(&value)
+                    .iter()
+                    .map(|v| DateSearchValue::parse("date", v))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| invalid(e.to_string()))?,
+            ),
+            "code:missing" => search.code_missing = Some(missing(&name, &value)?),
+            "subject:missing" => search.subject_missing = Some(missing(&name, &value)?),
+            "date:missing" => search.date_missing = Some(missing(&name, &value)?),
+            "_count" => {
+                count = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid(format!("Invalid value '{}' for _count", value)))?,
+                )
+            }
+            "_offset" => {
+                offset = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid(format!("Invalid value '{}' for _offset", value)))?,
+                )
+            }
+            "_page_token" => page_token = Some(value),
+            "_total" => search.total = Total::parse(&value).map_err(invalid)?,
+            name if strict && !name.starts_with('_') => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(OperationOutcome::error_with_location(
+                        "not-supported",
+                        format!("Search parameter {} is not supported for Observation", name),
+                        name,
+                    )),
+                ))
+            }
+            _ => {}
+        }
+    }
+    search.count = config.get().page_size(count);
+    search.start = PageStart::parse(offset, page_token.as_deref()).map_err(invalid)?;
+    Ok(search)
+}
+
+/// The matches of an Observation search query string, for an export;
+/// `_count`, `_offset`, `_page_token` and `_total` do not apply to it
+pub(super) fn export_search(
+    db: &Database,
+    config: &SharedConfig,
+    registry: &SearchParamRegistry,
+    query: &str,
+) -> Result<SearchSql, ErrorResponse> {
+    let params = serde_urlencoded::from_str(query).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invalid",
+                format!("Invalid search parameters: {}", e),
+            )),
+        )
+    })?;
+    let search = parse_search(config, registry, params, false)?;
+    check_text_search(db, &search.text)?;
+    Ok(observation_search_sql(&search))
+}
+
+pub async fn create_observation(
+    State(db): State<Arc<Database>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    attribution: Attribution,
+    Json(mut observation): Json<Observation>,
+) -> Result<(StatusCode, HeaderMap, Json<Observation>), ErrorResponse> {
+    observation.resource_type = "Observation".to_string();
+    validate_observation(&observation)?;
+    check_validation_hooks(&db, &hooks, "create", &observation).await?;
+
+    match db.create_observation_by(&attribution, observation).await {
+        Ok(created) => {
+            let mut headers = fhir_headers();
+            if let Some(id) = &created.id {
+                headers.insert(
+                    "Location",
+                    format!("/fhir/Observation/{}", id).parse().unwrap(),
+                );
+            }
+            Ok((StatusCode::CREATED, headers, Json(created)))
+        }
+        Err(e) => Err(processing_error("create", e)),
+    }
+}
+
+pub async fn get_observation(
+    State(db): State<Arc<Database>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(id): Path<String>,
+    consent: ConsentFilter,
+) -> Result<(StatusCode, HeaderMap, Json<Observation>), ErrorResponse> {
+    match db.get_observation(&id).await {
+        Ok(Some(observation)) => {
+            consent.check_read(&db, "Observation", &id).await?;
+            let observation = transforms
+                .apply_resources(vec![observation])
+                .await
+                .map_err(transform_error)?
+                .remove(0);
+            Ok((StatusCode::OK, fhir_headers(), Json(observation)))
+        }
+        Ok(None) => Err(not_found(&id)),
+        Err(e) => Err(processing_error("retrieve", e)),
+    }
+}
+
+pub async fn update_observation(
+    State(db): State<Arc<Database>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Path(id): Path<String>,
+    attribution: Attribution,
+    Json(mut observation): Json<Observation>,
+) -> Result<(StatusCode, HeaderMap, Json<Observation>), ErrorResponse> {
+    observation.resource_type = "Observation".to_string();
+    if observation
+        .id
+        .as_ref()
+        .is_some_and(|body_id| body_id != &id)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invariant",
+                "Resource ID cannot be changed",
+            )),
+        ));
+    }
+    validate_observation(&observation)?;
+    check_validation_hooks(&db, &hooks, "update", &observation).await?;
+
+    match db
+        .update_observation_by(&attribution, &id, observation)
+        .await
+    {
+        Ok(Some(updated)) => Ok((StatusCode::OK, fhir_headers(), Json(updated))),
+        Ok(None) => Err(not_found(&id)),
+        Err(e) => Err(processing_error("update", e)),
+    }
+}
+
+pub async fn delete_observation(
+    State(db): State<Arc<Database>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ErrorResponse> {
+    match db.delete_observation(&id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(not_found(&id)),
+        Err(e) => Err(processing_error("delete", e)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn search_observations(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    features: Features,
+    consent: ConsentFilter,
+    Query(params): Query<Vec<(String, String)>>,
+    uri: Uri,
+) -> Result<(StatusCode, HeaderMap, Json<Bundle<Observation>>), ErrorResponse> {
+    let search = parse_search(&config, &registry, params, features.enabled(STRICT_SEARCH))?;
+    check_text_search(&db, &search.text)?;
+    let matches = consent.search(&db, observation_search_sql(&search));
+
+    match tokio::try_join!(
+        db.observation_page(&matches, search.count, search.start),
+        db.count_matches(&matches, search.total)
+    ) {
+        Ok((observations, total)) => {
+            let etag = search_etag(
+                total,
+                observations.iter().map(|o| {
+                    (
+                        o.id.as_deref().unwrap_or_default(),
+                        o.meta
+                            .as_ref()
+                            .and_then(|meta| meta.version_id.as_deref())
+                            .unwrap_or_default(),
+                    )
+                }),
+            );
+            let last = observations
+                .last()
+                .and_then(|o| o.id.as_deref())
+                .and_then(|id| Uuid::parse_str(id).ok());
+            let observations = transforms
+                .apply_resources(observations)
+                .await
+                .map_err(transform_error)?;
+            let entries: Vec<BundleEntry<Observation>> = observations
+                .into_iter()
+                .map(|resource| BundleEntry { resource })
+                .collect();
+            let links = search_links(&uri, search.count, search.start, total, last, entries.len());
+
+            let bundle = Bundle {
+                resource_type: "Bundle".to_string(),
+                bundle_type: "searchset".to_string(),
+                total,
+                link: Some(links),
+                entry: entries,
+            };
+            let mut headers = fhir_headers();
+            headers.insert("ETag", etag);
+            Ok((StatusCode::OK, headers, Json(bundle)))
+        }
+        Err(e) => Err(processing_error("search", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CodeableConcept, Coding, Reference};
+    use crate::search::{Prefix, SearchParamDefinition, SearchParamType};
+    use serde_json::Map;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    fn test_config() -> State<Arc<SharedConfig>> {
+        State(Arc::new(SharedConfig::default()))
+    }
+
+    fn test_validation() -> State<Arc<ValidationHooks>> {
+        State(Arc::new(ValidationHooks::default()))
+    }
+
+    fn test_transforms() -> State<Arc<ResponsePipeline>> {
+        State(Arc::new(ResponsePipeline::default()))
+    }
+
+    fn heart_rate(subject: &str) -> Observation {
+        let mut observation = Observation::new(
+            "final",
+            CodeableConcept {
+                coding: Some(vec![Coding {
+                    system: Some("http://loinc.org".to_string()),
+                    code: Some("8867-4".to_string()),
+                    display: Some("Heart rate".to_string()),
+                    extra: Map::new(),
+                }]),
+                text: None,
+                extra: Map::new(),
+            },
+        );
+        observation.subject = Some(Reference {
+            reference: Some(subject.to_string()),
+            display: None,
+            extra: Map::new(),
+        });
+        observation.effective_date_time = Some("2024-03-01T08:30:00Z".to_string());
+        observation
+    }
+
+    #[tokio::test]
+    async fn test_create_and_search_observation() {
+        let db = setup_test_db().await;
+        let subject = format!("Patient/{}", uuid::Uuid::new_v4());
+
+        let (status, headers, Json(created)) = create_observation(
+            State(db.clone()),
+            test_validation(),
+            Attribution::default(),
+            Json(heart_rate(&subject)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = created.id.clone().unwrap();
+        assert_eq!(
+            headers.get("Location").unwrap(),
+            &format!("/fhir/Observation/{}", id)
+        );
+
+        let params = vec![
+            ("code".to_string(), "http://loinc.org|8867-4".to_string()),
+            ("subject".to_string(), subject.clone()),
+            ("date".to_string(), "ge2024-03-01".to_string()),
+            ("date".to_string(), "le2024-03-01".to_string()),
+        ];
+        let (_, _, Json(bundle)) = search_observations(
+            State(db.clone()),
+            test_config(),
+            State(Arc::new(SearchParamRegistry::with_builtins())),
+            test_transforms(),
+            Features::new(
+                Arc::default(),
+                Arc::new(SharedConfig::default()),
+                &HeaderMap::new(),
+            ),
+            ConsentFilter::default(),
+            Query(params),
+            Uri::from_static("/fhir/Observation"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(bundle.total, Some(1));
+        assert_eq!(bundle.entry[0].resource.id.as_deref(), Some(id.as_str()));
+
+        let status = delete_observation(State(db.clone()), Path(id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let err = get_observation(
+            State(db),
+            test_transforms(),
+            Path(id),
+            ConsentFilter::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_status_is_rejected() {
+        let db = setup_test_db().await;
+        let mut observation = heart_rate("Patient/x");
+        observation.status = "done".to_string();
+
+        let err = create_observation(
+            State(db),
+            test_validation(),
+            Attribution::default(),
+            Json(observation),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            err.1.issue[0].location,
+            Some(vec!["Observation.status".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_invalid_date_is_rejected() {
+        let config = SharedConfig::default();
+        let registry = SearchParamRegistry::with_builtins();
+        let err = parse_search(
+            &config,
+            &registry,
+            vec![("date".to_string(), "yesterday".to_string())],
+            false,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_page_token_replaces_offset() {
+        let config = SharedConfig::default();
+        let registry = SearchParamRegistry::with_builtins();
+        let id = Uuid::new_v4();
+        let param = |name: &str, value: &str| (name.to_string(), value.to_string());
+        let search = parse_search(
+            &config,
+            &registry,
+            vec![param("_page_token", &PageStart::token_after(id))],
+            false,
+        )
+        .unwrap();
+        assert_eq!(search.start, PageStart::After(id));
+
+        let both = vec![
+            param("_offset", "2"),
+            param("_page_token", &PageStart::token_after(id)),
+        ];
+        assert_eq!(
+            parse_search(&config, &registry, both, false).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_strict_search_rejects_unknown_parameters() {
+        let config = SharedConfig::default();
+        let registry = SearchParamRegistry::with_builtins();
+        let params = || {
+            vec![
+                ("category".to_string(), "vital-signs".to_string()),
+                ("_elements".to_string(), "code".to_string()),
+            ]
+        };
+        assert!(parse_search(&config, &registry, params(), false).is_ok());
+        let (status, Json(outcome)) = parse_search(&config, &registry, params(), true).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].code, "not-supported");
+
+        // Once a profile pack defines it, the parameter is searched
+        registry.register(
+            SearchParamDefinition::new(
+                "us-core-observation-category",
+                "http://hl7.org/fhir/us/core/SearchParameter/us-core-observation-category",
+                "category",
+                &["Observation"],
+                SearchParamType::Token,
+                "Observation.category",
+                "",
+            )
+            .with_path(r#"$."category"[*]"#),
+        );
+        let search = parse_search(&config, &registry, params(), true).unwrap();
+        assert_eq!(search.expressions.len(), 1);
+        assert_eq!(search.expressions[0].code, "category");
+    }
+
+    #[test]
+    fn test_or_values() {
+        let config = SharedConfig::default();
+        let registry = SearchParamRegistry::with_builtins();
+        let params = vec![
+            (
+                "code".to_string(),
+                "8867-4,http://loinc.org|8310-5".to_string(),
+            ),
+            ("subject".to_string(), "Patient/a,Patient/b".to_string()),
+            ("date".to_string(), "2024-01,ge2024-03".to_string()),
+            ("date".to_string(), "le2024-12".to_string()),
+        ];
+        let search = parse_search(&config, &registry, params, false).unwrap();
+        assert_eq!(search.code.len(), 2);
+        assert_eq!(search.subject, vec!["Patient/a", "Patient/b"]);
+        assert!(search.patient.is_empty());
+        assert_eq!(search.date.len(), 2);
+        assert_eq!(search.date[0].len(), 2);
+        assert_eq!(search.date[0][1].prefix, Prefix::Ge);
+
+        let params = vec![("date".to_string(), "2024-01,soon".to_string())];
+        assert!(parse_search(&config, &registry, params, false).is_err());
+
+        let params = vec![("patient".to_string(), "a,Patient/b".to_string())];
+        let search = parse_search(&config, &registry, params, true).unwrap();
+        assert_eq!(search.patient, vec!["a", "Patient/b"]);
+        assert!(search.subject.is_empty());
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let config = SharedConfig::default();
+        let registry = SearchParamRegistry::with_builtins();
+        let param = |name: &str, value: &str| vec![(name.to_string(), value.to_string())];
+
+        let search =
+            parse_search(&config, &registry, param("date:missing", "true"), false).unwrap();
+        assert_eq!(search.date_missing, Some(true));
+        let search =
+            parse_search(&config, &registry, param("subject:missing", "false"), false).unwrap();
+        assert_eq!(search.subject_missing, Some(false));
+        let (status, Json(outcome)) =
+            parse_search(&config, &registry, param("code:missing", "1"), false).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].code, "invalid");
+
+        // Unsupported modifiers are refused even without strict search
+        let (_, Json(outcome)) =
+            parse_search(&config, &registry, param("code:text", "pulse"), false).unwrap_err();
+        assert_eq!(outcome.issue[0].code, "not-supported");
+    }
+}
@@ -0,0 +1,3448 @@
+use super::lock::{lock_refusal, lock_token_hash};
+use super::{check_text_search, check_validation_hooks, history, search_links, transform_error};
+use crate::access;
+use crate::conditional::search_etag;
+use crate::config::SharedConfig;
+use crate::consent::ConsentFilter;
+use crate::db::identifiers::DuplicateIdentifier;
+use crate::db::{
+    patient_search_sql, ConditionalWrite, Database, PageStart, PatientPut, PatientSearch,
+    SearchSql, Total, VersionConflict,
+};
+use crate::extract::Query;
+use crate::fhirpath_patch;
+use crate::metrics::Metrics;
+use crate::models::{Bundle, BundleEntry, OperationOutcome, Patient};
+use crate::negotiation;
+use crate::provenance::Attribution;
+use crate::search::cohort::{CohortQuery, CohortResult};
+use crate::search::matching::{MatchInput, MatchRequest, MATCH_GRADE_URL};
+use crate::search::modifier::{self, parse_missing};
+use crate::search::{
+    split_or, DateSearchValue, ExpressionCriterion, PatientCriteria, SearchParamRegistry,
+    TextCriteria, TokenParam,
+};
+use crate::transform::ResponsePipeline;
+use crate::validation::ValidationHooks;
+use axum::{
+    extract::{Path, RawQuery, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Json, Response},
+};
+use json_patch::Patch;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchParams {
+    name: Option<String>,
+    #[serde(rename = "name:contains")]
+    name_contains: Option<String>,
+    #[serde(rename = "name:exact")]
+    name_exact: Option<String>,
+    #[serde(rename = "name:phonetic")]
+    name_phonetic: Option<String>,
+    // `birthdate` may be repeated to form a range, so
+    // [`SearchParams::criteria`] reads it from the query string
+    gender: Option<String>,
+    /// Comma-separated logical ids, any of which may match
+    #[serde(rename = "_id")]
+    id: Option<String>,
+    /// Comma-separated `system|value` tokens, any of which may match
+    identifier: Option<String>,
+    /// `true` or `false`; see [`crate::search::modifier`]
+    #[serde(rename = "name:missing")]
+    name_missing: Option<String>,
+    #[serde(rename = "gender:missing")]
+    gender_missing: Option<String>,
+    #[serde(rename = "birthdate:missing")]
+    birth_date_missing: Option<String>,
+    #[serde(rename = "identifier:missing")]
+    identifier_missing: Option<String>,
+    #[serde(rename = "_count")]
+    count: Option<u32>,
+    #[serde(rename = "_offset")]
+    offset: Option<u32>,
+    #[serde(rename = "_page_token")]
+    page_token: Option<String>,
+    #[serde(rename = "_total")]
+    total: Option<Total>,
+}
+
+/// Modifiers Patient search honors; others are rejected
+const SUPPORTED_MODIFIERS: &[&str] = &[
+    "name:contains",
+    "name:exact",
+    "name:phonetic",
+    "name:missing",
+    "gender:missing",
+    "birthdate:missing",
+    "identifier:missing",
+];
+
+/// 400 for a parameter of `query` with a modifier Patient search does not
+/// honor
+fn check_modifiers(query: Option<&str>) -> Result<(), (StatusCode, Json<OperationOutcome>)> {
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(query.unwrap_or_default()).unwrap_or_default();
+    match modifier::unsupported(params.iter().map(|(name, _)| name.as_str()), SUPPORTED_MODIFIERS) {
+        Some(name) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "not-supported",
+                format!(
+                    "Modifier {} is not supported for Patient; supported are {}",
+                    name,
+                    SUPPORTED_MODIFIERS.join(", ")
+                ),
+                name,
+            )),
+        )),
+        None => Ok(()),
+    }
+}
+
+impl SearchParams {
+    /// The criteria of these parameters and the `birthdate`s of `query`,
+    /// the query string they were read from, together with the parameters
+    /// of `registry` evaluated from their expression
+    fn criteria(
+        &self,
+        query: Option<&str>,
+        registry: &SearchParamRegistry,
+    ) -> Result<PatientSearch, (StatusCode, Json<OperationOutcome>)> {
+        let list = |value: &Option<String>| -> Vec<String> {
+            value
+                .iter()
+                .flat_map(|v| split_or(v))
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect()
+        };
+        let missing = |name: &str, value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| parse_missing(name, value))
+                .transpose()
+                .map_err(|e| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        Json(OperationOutcome::error_with_location(
+                            "invalid",
+                            e.to_string(),
+                            name,
+                        )),
+                    )
+                })
+        };
+        let pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(query.unwrap_or_default()).unwrap_or_default();
+        let birth_date = pairs
+            .iter()
+            .filter(|(name, _)| name == "birthdate")
+            .map(|(_, value)| {
+                split_or(value)
+                    .iter()
+                    .map(|date| date.trim())
+                    .filter(|date| !date.is_empty())
+                    .map(|date| DateSearchValue::parse("birthdate", date))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(OperationOutcome::error_with_location(
+                        "invalid",
+                        e.to_string(),
+                        "birthdate",
+                    )),
+                )
+            })?;
+        let mut text = TextCriteria::default();
+        for (name, value) in &pairs {
+            text.add(name, value);
+        }
+        let expressions = pairs
+            .iter()
+            .filter_map(|(name, value)| {
+                ExpressionCriterion::parse(&registry.get("Patient", name)?, value)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(OperationOutcome::error_with_location(
+                        "invalid",
+                        e.to_string(),
+                        e.param.clone(),
+                    )),
+                )
+            })?;
+        Ok(PatientSearch {
+            // Prioritize :contains modifier over exact match
+            name: list(&self.name_contains.clone().or_else(|| self.name.clone())),
+            name_exact: list(&self.name_exact),
+            name_phonetic: list(&self.name_phonetic),
+            birth_date,
+            gender: list(&self.gender),
+            ids: list(&self.id),
+            identifier: list(&self.identifier)
+                .iter()
+                .map(|token| TokenParam::parse(token))
+                .collect(),
+            name_missing: missing("name:missing", &self.name_missing)?,
+            gender_missing: missing("gender:missing", &self.gender_missing)?,
+            birth_date_missing: missing("birthdate:missing", &self.birth_date_missing)?,
+            identifier_missing: missing("identifier:missing", &self.identifier_missing)?,
+            expressions,
+            text,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictParams {
+    base: Option<String>,
+}
+
+/// Map a failed write to an OperationOutcome; identifier conflicts are
+/// 409s, writes based on a stale version 412s and writes to a patient
+/// locked by someone else 423s
+pub(super) fn write_error(
+    metrics: &Metrics,
+    action: &str,
+    e: anyhow::Error,
+) -> (StatusCode, Json<OperationOutcome>) {
+    if let Some(locked) = lock_refusal(&e) {
+        return locked;
+    }
+    if let Some(conflict) = e.downcast_ref::<VersionConflict>() {
+        return (
+            StatusCode::PRECONDITION_FAILED,
+            Json(OperationOutcome::error_with_location(
+                "conflict",
+                format!(
+                    "Failed to {} patient: {}; GET /fhir/Patient/{}/$conflict?base={} shows the changes since",
+                    action, conflict, conflict.id, conflict.expected
+                ),
+                "Patient.meta.versionId",
+            )),
+        );
+    }
+    match e.downcast_ref::<DuplicateIdentifier>() {
+        Some(duplicate) => {
+            metrics.identifier_conflict();
+            (
+                StatusCode::CONFLICT,
+                Json(OperationOutcome::error_with_location(
+                    "duplicate",
+                    format!("Failed to {} patient: {}", action, duplicate),
+                    "Patient.identifier",
+                )),
+            )
+        }
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to {} patient: {}", action, e),
+            )),
+        ),
+    }
+}
+
+/// 404 for an unknown id, 410 Gone for a patient that was deleted
+pub(super) async fn missing_patient(db: &Database, id: &str) -> (StatusCode, Json<OperationOutcome>) {
+    match db.is_patient_deleted(id).await {
+        Ok(true) => (
+            StatusCode::GONE,
+            Json(OperationOutcome::error_with_location(
+                "deleted",
+                format!("Patient with id {} has been deleted", id),
+                format!("Patient/{}", id),
+            )),
+        ),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(OperationOutcome::error_with_location(
+                "not-found",
+                format!("Patient with id {} not found", id),
+                format!("Patient/{}", id),
+            )),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to retrieve patient: {}", e),
+            )),
+        ),
+    }
+}
+
+/// Version named by an `If-Match` header (`W/"3"`, `"3"` or `3`), which
+/// `require_if_match` makes mandatory
+fn if_match_version(
+    config: &SharedConfig,
+    request_headers: &HeaderMap,
+) -> Result<Option<i32>, (StatusCode, Json<OperationOutcome>)> {
+    let Some(value) = request_headers.get("If-Match") else {
+        return match config.get().require_if_match {
+            true => Err(if_match_required("If-Match")),
+            false => Ok(None),
+        };
+    };
+    value.to_str().ok().and_then(version_etag).map(Some).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invalid",
+                "If-Match must be a version ETag such as W/\"3\"",
+            )),
+        )
+    })
+}
+
+/// 428 for a write without the `If-Match` (or Bundle `ifMatch`) this
+/// server requires
+pub(super) fn if_match_required(name: &str) -> (StatusCode, Json<OperationOutcome>) {
+    (
+        StatusCode::PRECONDITION_REQUIRED,
+        Json(OperationOutcome::error(
+            "required",
+            format!(
+                "{} is required: send the ETag of the version being changed, such as W/\"3\"",
+                name
+            ),
+        )),
+    )
+}
+
+/// Headers of a response carrying `patient`: its content type and, once
+/// stored, `ETag: W/"<versionId>"` to send back in `If-Match`
+fn patient_headers(patient: &Patient) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    let version = patient.meta.as_ref().and_then(|m| m.version_id.as_deref());
+    if let Some(etag) = version.and_then(|v| format!("W/\"{}\"", v).parse().ok()) {
+        headers.insert("ETag", etag);
+    }
+    headers
+}
+
+/// Version of an ETag (`W/"3"`, `"3"` or `3`)
+pub(super) fn version_etag(value: &str) -> Option<i32> {
+    value
+        .trim()
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .ok()
+}
+
+/// Existing patient matching an `If-None-Exist` header, if there is one.
+/// Several matches fail with 412 since the client expected at most one.
+async fn conditional_match(
+    db: &Database,
+    request_headers: &HeaderMap,
+) -> Result<Option<Patient>, (StatusCode, Json<OperationOutcome>)> {
+    let Some(criteria) = request_headers.get("If-None-Exist") else {
+        return Ok(None);
+    };
+    let criteria = criteria
+        .to_str()
+        .map_err(|_| "header is not valid text".to_string())
+        .and_then(|c| PatientCriteria::from_query(c).map_err(|e| e.to_string()))
+        .map_err(|message| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invalid",
+                    format!("If-None-Exist: {}", message),
+                )),
+            )
+        })?;
+
+    // Two rows are enough to tell one match from several
+    let mut matches = db
+        .find_patients_by_identifier(&criteria.identifier, 2)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to search patients: {}", e),
+                )),
+            )
+        })?;
+    match matches.len() {
+        0 | 1 => Ok(matches.pop()),
+        _ => Err((
+            StatusCode::PRECONDITION_FAILED,
+            Json(OperationOutcome::error(
+                "multiple-matches",
+                "If-None-Exist matched more than one patient",
+            )),
+        )),
+    }
+}
+
+pub async fn create_patient(
+    State(db): State<Arc<Database>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    attribution: Attribution,
+    request_headers: HeaderMap,
+    Json(mut patient): Json<Patient>,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    // Conditional create: an existing match is returned instead of a duplicate
+    if let Some(existing) = conditional_match(&db, &request_headers).await? {
+        let mut headers = patient_headers(&existing);
+        if let Some(id) = &existing.id {
+            headers.insert("Location", format!("/fhir/Patient/{}", id).parse().unwrap());
+        }
+        let existing = transforms
+            .apply_resources(vec![existing])
+            .await
+            .map_err(transform_error)?
+            .remove(0);
+        return Ok((StatusCode::OK, headers, Json(existing)));
+    }
+
+    // Ensure resource type is correct
+    patient.resource_type = "Patient".to_string();
+    check_validation_hooks(&db, &hooks, "create", &patient).await?;
+
+    match db.create_patient_by(&attribution, patient).await {
+        Ok(created_patient) => {
+            metrics.patient_created();
+            let mut headers = patient_headers(&created_patient);
+            if let Some(id) = &created_patient.id {
+                headers.insert("Location", format!("/fhir/Patient/{}", id).parse().unwrap());
+            }
+
+            Ok((StatusCode::CREATED, headers, Json(created_patient)))
+        }
+        Err(e) => Err(write_error(&metrics, "create", e)),
+    }
+}
+
+pub async fn get_patient(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(id): Path<String>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    match db.get_patient(&id).await {
+        Ok(Some(patient)) => {
+            consent.check_read(&db, "Patient", &id).await?;
+            access::check_patient_reads(&config.get(), &request_headers, [&patient])?;
+            let patient = transforms
+                .apply_resources(vec![patient])
+                .await
+                .map_err(transform_error)?
+                .remove(0);
+            Ok((StatusCode::OK, patient_headers(&patient), Json(patient)))
+        }
+        Ok(None) => Err(missing_patient(&db, &id).await),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to retrieve patient: {}", e),
+            )),
+        )),
+    }
+}
+
+/// `PUT /fhir/Patient/:id`: replace the patient, or create it under an id
+/// reserved by `$reserve-id`
+#[allow(clippy::too_many_arguments)]
+pub async fn update_patient(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Path(id): Path<String>,
+    attribution: Attribution,
+    request_headers: HeaderMap,
+    Json(patient): Json<Patient>,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    let expected_version = if_match_version(&config, &request_headers)?;
+    check_validation_hooks(&db, &hooks, "update", &patient).await?;
+
+    let lock = lock_token_hash(&request_headers);
+    match db
+        .put_patient_by(
+            &attribution,
+            lock.as_deref(),
+            &id,
+            patient,
+            expected_version,
+        )
+        .await
+    {
+        Ok(Some(PatientPut::Created(created_patient))) => {
+            metrics.patient_created();
+            let mut headers = patient_headers(&created_patient);
+            headers.insert("Location", format!("/fhir/Patient/{}", id).parse().unwrap());
+            Ok((StatusCode::CREATED, headers, Json(created_patient)))
+        }
+        Ok(Some(PatientPut::Updated(updated_patient))) => {
+            metrics.patient_updated();
+            let headers = patient_headers(&updated_patient);
+            Ok((StatusCode::OK, headers, Json(updated_patient)))
+        }
+        Ok(None) => Err(missing_patient(&db, &id).await),
+        Err(e) => Err(write_error(&metrics, "update", e)),
+    }
+}
+
+/// `PUT /fhir/Patient?identifier=...`: update the patient matching the
+/// criteria, or create it when no patient matches
+pub async fn conditional_update_patient(
+    State(db): State<Arc<Database>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    RawQuery(query): RawQuery,
+    attribution: Attribution,
+    request_headers: HeaderMap,
+    Json(mut patient): Json<Patient>,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    let criteria =
+        PatientCriteria::from_query(query.as_deref().unwrap_or_default()).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invalid",
+                    format!("Conditional update: {}", e),
+                )),
+            )
+        })?;
+
+    patient.resource_type = "Patient".to_string();
+    check_validation_hooks(&db, &hooks, "update", &patient).await?;
+
+    // The patient the criteria match may be locked
+    let lock = lock_token_hash(&request_headers);
+    match db
+        .upsert_patient_by_identifier(&attribution, lock.as_deref(), &criteria.identifier, patient)
+        .await
+    {
+        Ok(ConditionalWrite::Created(created_patient)) => {
+            metrics.patient_created();
+            let mut headers = patient_headers(&created_patient);
+            if let Some(id) = &created_patient.id {
+                headers.insert("Location", format!("/fhir/Patient/{}", id).parse().unwrap());
+            }
+            Ok((StatusCode::CREATED, headers, Json(created_patient)))
+        }
+        Ok(ConditionalWrite::Updated(updated_patient)) => {
+            metrics.patient_updated();
+            let headers = patient_headers(&updated_patient);
+            Ok((StatusCode::OK, headers, Json(updated_patient)))
+        }
+        Ok(ConditionalWrite::MultipleMatches) => Err((
+            StatusCode::PRECONDITION_FAILED,
+            Json(OperationOutcome::error(
+                "multiple-matches",
+                "Conditional update matched more than one patient",
+            )),
+        )),
+        Ok(ConditionalWrite::IdMismatch(id)) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid",
+                format!("Resource id does not match the matched patient {}", id),
+                "Patient.id",
+            )),
+        )),
+        Err(e) => Err(write_error(&metrics, "update", e)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn patch_patient(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Path(id): Path<String>,
+    attribution: Attribution,
+    request_headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    let expected_version = if_match_version(&config, &request_headers)?;
+
+    // A JSON Patch is an array of operations, a FHIRPath Patch a Parameters
+    // resource
+    let patch = if fhirpath_patch::is_fhirpath_patch(&body) {
+        PatientPatch::FhirPath(fhirpath_patch::parse(&body).map_err(fhirpath_patch_error)?)
+    } else {
+        PatientPatch::Json(serde_json::from_value(body).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invalid",
+                    format!(
+                        "PATCH needs a JSON Patch array or a FHIRPath Patch Parameters resource: {}",
+                        e
+                    ),
+                )),
+            )
+        })?)
+    };
+
+    // 1. Get existing patient
+    let existing_patient = match db.get_patient(&id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(missing_patient(&db, &id).await),
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to retrieve patient: {}", e),
+                )),
+            ))
+        }
+    };
+
+    // 2. Convert to Value for patching
+    let mut patient_value = serde_json::to_value(&existing_patient).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to serialize patient: {}", e),
+            )),
+        )
+    })?;
+
+    // 3. Apply patch
+    match &patch {
+        PatientPatch::Json(patch) => apply_patch(&mut patient_value, patch).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to apply patch: {}", e),
+                )),
+            )
+        })?,
+        PatientPatch::FhirPath(operations) => {
+            fhirpath_patch::apply(&mut patient_value, operations).map_err(fhirpath_patch_error)?
+        }
+    }
+
+    save_modified(
+        &db,
+        &metrics,
+        &hooks,
+        &attribution,
+        lock_token_hash(&request_headers).as_deref(),
+        &id,
+        patient_value,
+        expected_version,
+    )
+    .await
+}
+
+enum PatientPatch {
+    Json(Patch),
+    FhirPath(Vec<fhirpath_patch::PatchOperation>),
+}
+
+/// A malformed FHIRPath Patch is a 400, one that does not apply to the
+/// patient a 422
+fn fhirpath_patch_error(e: fhirpath_patch::PatchError) -> (StatusCode, Json<OperationOutcome>) {
+    let status = if e.malformed {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    let message = format!("Failed to apply FHIRPath Patch: {}", e.message);
+    let outcome = match &e.location {
+        Some(location) => OperationOutcome::error_with_location(e.code, message, location),
+        None => OperationOutcome::error(e.code, message),
+    };
+    (status, Json(outcome))
+}
+
+/// Apply a JSON Patch to a resource that is thrown away when it fails.
+/// `json_patch::patch` panics when a failed patch cannot be undone, for
+/// example after moving a member to the document root.
+pub fn apply_patch(resource: &mut Value, patch: &Patch) -> Result<(), json_patch::PatchError> {
+    json_patch::patch_unsafe(resource, patch)
+}
+
+/// Top-level elements of `changes` replace those of `current`; `null`
+/// removes one. Nothing is merged below the top level: a `name` in
+/// `changes` replaces every name of `current`.
+fn merge_elements(current: &mut Value, changes: Map<String, Value>) {
+    let Some(current) = current.as_object_mut() else {
+        return;
+    };
+    for (name, value) in changes {
+        if matches!(name.as_str(), "resourceType" | "id" | "meta") {
+            continue;
+        }
+        if value.is_null() {
+            current.remove(&name);
+        } else {
+            current.insert(name, value);
+        }
+    }
+}
+
+/// `POST /fhir/Patient/:id/$merge-update`: change only the elements the
+/// body names, where PUT replaces the whole resource
+#[allow(clippy::too_many_arguments)]
+pub async fn merge_update_patient(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Path(id): Path<String>,
+    attribution: Attribution,
+    request_headers: HeaderMap,
+    Json(changes): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    let expected_version = if_match_version(&config, &request_headers)?;
+
+    let Value::Object(changes) = changes else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "structure",
+                "$merge-update expects a JSON object of Patient elements",
+            )),
+        ));
+    };
+    if let Some(resource_type) = changes.get("resourceType").and_then(Value::as_str) {
+        if resource_type != "Patient" {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invalid",
+                    "Resource type must be 'Patient'",
+                )),
+            ));
+        }
+    }
+    if let Some(body_id) = changes.get("id").and_then(Value::as_str) {
+        if body_id != id {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invariant",
+                    "Resource ID cannot be changed",
+                )),
+            ));
+        }
+    }
+
+    let existing_patient = match db.get_patient(&id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(missing_patient(&db, &id).await),
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to retrieve patient: {}", e),
+                )),
+            ))
+        }
+    };
+    // Without If-Match the merge still applies to the version it read
+    let expected_version = expected_version.or_else(|| {
+        existing_patient
+            .meta
+            .as_ref()
+            .and_then(|m| m.version_id.as_deref()?.parse().ok())
+    });
+
+    let mut patient_value = serde_json::to_value(&existing_patient).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to serialize patient: {}", e),
+            )),
+        )
+    })?;
+    merge_elements(&mut patient_value, changes);
+
+    save_modified(
+        &db,
+        &metrics,
+        &hooks,
+        &attribution,
+        lock_token_hash(&request_headers).as_deref(),
+        &id,
+        patient_value,
+        expected_version,
+    )
+    .await
+}
+
+/// Steps shared by PATCH and `$merge-update`: check the modified resource,
+/// run the validation hooks and store it as the next version, holding the
+/// lock of `lock_token_hash` if the patient is locked
+#[allow(clippy::too_many_arguments)]
+async fn save_modified(
+    db: &Database,
+    metrics: &Metrics,
+    hooks: &ValidationHooks,
+    attribution: &Attribution,
+    lock_token_hash: Option<&str>,
+    id: &str,
+    patient_value: Value,
+    expected_version: Option<i32>,
+) -> Result<(StatusCode, HeaderMap, Json<Patient>), (StatusCode, Json<OperationOutcome>)> {
+    // 4. Convert back to Patient (this validates structure)
+    let mut patched_patient: Patient = serde_json::from_value(patient_value).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid",
+                format!("Patch results in invalid patient resource: {}", e),
+                "Patient",
+            )),
+        )
+    })?;
+
+    // 5. Ensure immutable fields are preserved/restored if necessary
+    // ID should match path ID
+    if let Some(pid) = &patched_patient.id {
+        if pid != id {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invariant",
+                    "Resource ID cannot be changed",
+                )),
+            ));
+        }
+    } else {
+        patched_patient.id = Some(id.to_string());
+    }
+
+    // ResourceType must be Patient
+    if patched_patient.resource_type != "Patient" {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invalid",
+                "Resource type must be 'Patient'",
+            )),
+        ));
+    }
+
+    // 6. Site-specific validation
+    check_validation_hooks(db, hooks, "update", &patched_patient).await?;
+
+    // 7. Update in database
+    match db
+        .update_patient_by(
+            attribution,
+            lock_token_hash,
+            id,
+            patched_patient,
+            expected_version,
+        )
+        .await
+    {
+        Ok(Some(updated_patient)) => {
+            metrics.patient_updated();
+            let headers = patient_headers(&updated_patient);
+            Ok((StatusCode::OK, headers, Json(updated_patient)))
+        }
+        // Should not happen as we checked existence, but possible if deleted concurrently
+        Ok(None) => Err(missing_patient(db, id).await),
+        Err(e) => Err(write_error(metrics, "update", e)),
+    }
+}
+
+/// Soft delete; repeating the delete of a deleted patient is a no-op
+pub async fn delete_patient(
+    State(db): State<Arc<Database>>,
+    State(metrics): State<Arc<Metrics>>,
+    Path(id): Path<String>,
+    request_headers: HeaderMap,
+) -> Result<StatusCode, (StatusCode, Json<OperationOutcome>)> {
+    match db
+        .delete_patient_by(lock_token_hash(&request_headers).as_deref(), &id)
+        .await
+    {
+        Ok(true) => {
+            metrics.patient_deleted();
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => match missing_patient(&db, &id).await {
+            (StatusCode::GONE, _) => Ok(StatusCode::NO_CONTENT),
+            error => Err(error),
+        },
+        Err(e) => Err(lock_refusal(&e).unwrap_or_else(|| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to delete patient: {}", e),
+                )),
+            )
+        })),
+    }
+}
+
+fn phonetic_unavailable() -> (StatusCode, Json<OperationOutcome>) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "name:phonetic is not available on this server: the fhir.resource_name_phonetic table is missing",
+        )),
+    )
+}
+
+fn name_unavailable() -> (StatusCode, Json<OperationOutcome>) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "name is not available on this server: the fhir.resource_string_value table is missing",
+        )),
+    )
+}
+
+/// Name criteria need the normalized names of `025_normalized_strings.sql`
+fn check_name_search(
+    db: &Database,
+    criteria: &PatientSearch,
+) -> Result<(), (StatusCode, Json<OperationOutcome>)> {
+    if criteria.name.is_empty() || db.string_index_enabled() {
+        Ok(())
+    } else {
+        Err(name_unavailable())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn search_patients(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Query(params): Query<SearchParams>,
+    consent: ConsentFilter,
+    uri: Uri,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Bundle>), (StatusCode, Json<OperationOutcome>)> {
+    if params.name_phonetic.is_some() && !db.phonetic_index_enabled() {
+        return Err(phonetic_unavailable());
+    }
+    let count = config.get().page_size(params.count);
+    let start = PageStart::parse(params.offset, params.page_token.as_deref()).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location("invalid", e, "_page_token")),
+        )
+    })?;
+
+    check_modifiers(uri.query())?;
+    let criteria = params.criteria(uri.query(), &registry)?;
+    check_text_search(&db, &criteria.text)?;
+    check_name_search(&db, &criteria)?;
+    let search = consent.search(&db, patient_search_sql(&criteria));
+
+    // Without criteria every patient matches, page by page, unless there
+    // are more than the configured cap
+    let cap = config.get().max_unfiltered_matches;
+    if cap > 0 && criteria.is_unfiltered() {
+        match db.matches_more_than(&search, cap).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(OperationOutcome::error(
+                        "too-costly",
+                        format!(
+                            "A Patient search without criteria may match at most {} patients; add search parameters",
+                            cap
+                        ),
+                    )),
+                ))
+            }
+            Err(e) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(OperationOutcome::error(
+                        "processing",
+                        format!("Failed to search patients: {}", e),
+                    )),
+                ))
+            }
+        }
+    }
+
+    // The page and the total are independent queries
+    match tokio::try_join!(
+        db.patient_page(&search, count, start),
+        db.count_matches(&search, params.total.unwrap_or_default())
+    ) {
+        Ok((patients, total)) => {
+            access::check_patient_reads(&config.get(), &request_headers, &patients)?;
+            let etag = search_etag(
+                total,
+                patients.iter().map(|p| {
+                    (
+                        p.id.as_deref().unwrap_or_default(),
+                        p.meta
+                            .as_ref()
+                            .and_then(|meta| meta.version_id.as_deref())
+                            .unwrap_or_default(),
+                    )
+                }),
+            );
+            let last = patients
+                .last()
+                .and_then(|p| p.id.as_deref())
+                .and_then(|id| Uuid::parse_str(id).ok());
+            let patients = transforms
+                .apply_resources(patients)
+                .await
+                .map_err(transform_error)?;
+            let entries: Vec<BundleEntry> = patients
+                .into_iter()
+                .map(|patient| BundleEntry { resource: patient })
+                .collect();
+
+            let bundle = Bundle {
+                resource_type: "Bundle".to_string(),
+                bundle_type: "searchset".to_string(),
+                total,
+                link: Some(search_links(&uri, count, start, total, last, entries.len())),
+                entry: entries,
+            };
+
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+            headers.insert("ETag", etag);
+
+            Ok((StatusCode::OK, headers, Json(bundle)))
+        }
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to search patients: {}", e),
+            )),
+        )),
+    }
+}
+
+/// The matches of a Patient search query string, for an export; `_count`,
+/// `_offset` and `_total` do not apply to it
+pub(super) fn export_search(
+    db: &Database,
+    config: &SharedConfig,
+    registry: &SearchParamRegistry,
+    request_headers: &HeaderMap,
+    query: &str,
+) -> Result<SearchSql, (StatusCode, Json<OperationOutcome>)> {
+    let params: SearchParams = serde_urlencoded::from_str(query).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invalid",
+                format!("Invalid search parameters: {}", e),
+            )),
+        )
+    })?;
+    if params.name_phonetic.is_some() && !db.phonetic_index_enabled() {
+        return Err(phonetic_unavailable());
+    }
+    check_modifiers(Some(query))?;
+    access::check_bulk_read(&config.get(), request_headers, "An export of Patients")?;
+
+    let criteria = params.criteria(Some(query), registry)?;
+    check_text_search(db, &criteria.text)?;
+    check_name_search(db, &criteria)?;
+    Ok(patient_search_sql(&criteria))
+}
+
+/// `POST /fhir/Patient/$cohort`: the live patients matching a boolean tree
+/// of search criteria (see [`CohortQuery`]), as a Parameters resource with
+/// their `count` and, unless only the count was asked for, an `id` each
+pub async fn patient_cohort(
+    State(db): State<Arc<Database>>,
+    consent: ConsentFilter,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), (StatusCode, Json<OperationOutcome>)> {
+    let query = CohortQuery::parse(&body).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error("invalid", e.to_string())),
+        )
+    })?;
+    if query.criteria.uses_phonetic() && !db.phonetic_index_enabled() {
+        return Err(phonetic_unavailable());
+    }
+    if query.criteria.uses_name() && !db.string_index_enabled() {
+        return Err(name_unavailable());
+    }
+
+    let cohort_failed = |e: anyhow::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to evaluate cohort: {}", e),
+            )),
+        )
+    };
+    let parameters = match query.result {
+        CohortResult::Count => {
+            let count = db
+                .cohort_count(&query.criteria, consent.withholding())
+                .await
+                .map_err(cohort_failed)?;
+            vec![json!({ "name": "count", "valueInteger": count })]
+        }
+        CohortResult::Ids => {
+            let ids = db
+                .cohort_ids(&query.criteria, consent.withholding())
+                .await
+                .map_err(cohort_failed)?;
+            let mut parameters = vec![json!({ "name": "count", "valueInteger": ids.len() })];
+            parameters.extend(
+                ids.into_iter()
+                    .map(|id| json!({ "name": "id", "valueId": id })),
+            );
+            parameters
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((
+        StatusCode::OK,
+        headers,
+        Json(json!({ "resourceType": "Parameters", "parameter": parameters })),
+    ))
+}
+
+/// `POST /fhir/Patient/$match`: the stored patients most likely to be the
+/// one in the `resource` parameter (see [`MatchRequest`]), best first, as a
+/// searchset Bundle with each entry's `search.score` and match grade
+pub async fn match_patients(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), (StatusCode, Json<OperationOutcome>)> {
+    if !db.patient_match_enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "$match is not available on this server: the pg_trgm extension is missing",
+            )),
+        ));
+    }
+    let invalid = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error("invalid", message)),
+        )
+    };
+    let request = MatchRequest::parse(&body).map_err(invalid)?;
+    let input = MatchInput::from_patient(&request.patient);
+    if !input.has_candidates() {
+        return Err(invalid(
+            "The patient to match needs a name, birthDate or identifier".to_string(),
+        ));
+    }
+
+    let settings = config.get();
+    let matching = &settings.patient_match;
+    let count = request
+        .count
+        .unwrap_or(matching.max_results)
+        .min(matching.max_results);
+    let mut matches = db
+        .match_patients(&input, matching, count, consent.withholding())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to match patients: {}", e),
+                )),
+            )
+        })?;
+    if request.only_certain {
+        matches.retain(|m| matching.grade(m.score) == Some("certain"));
+    }
+
+    access::check_patient_reads(&settings, &request_headers, matches.iter().map(|m| &m.patient))?;
+    let scores: Vec<f64> = matches.iter().map(|m| m.score).collect();
+    let patients = transforms
+        .apply_resources(matches.into_iter().map(|m| m.patient).collect())
+        .await
+        .map_err(transform_error)?;
+    let entries: Vec<Value> = patients
+        .into_iter()
+        .zip(scores)
+        .map(|(patient, score)| {
+            json!({
+                "resource": patient,
+                "search": {
+                    "mode": "match",
+                    "score": (score * 1000.0).round() / 1000.0,
+                    "extension": [{
+                        "url": MATCH_GRADE_URL,
+                        "valueCode": matching.grade(score).unwrap_or("possible"),
+                    }],
+                },
+            })
+        })
+        .collect();
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((
+        StatusCode::OK,
+        headers,
+        Json(json!({
+            "resourceType": "Bundle",
+            "type": "searchset",
+            "total": entries.len(),
+            "entry": entries,
+        })),
+    ))
+}
+
+/// `POST /fhir/Patient/$reserve-id`: a new id the client may link records
+/// to before the patient exists, and create the patient under with
+/// `PUT /fhir/Patient/:id` until `expires`. Reserving is not idempotent; an
+/// id that is never used simply expires.
+pub async fn reserve_patient_id(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), (StatusCode, Json<OperationOutcome>)> {
+    if !db.reservations_enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "$reserve-id is not available on this server: the fhir.id_reservation table is missing",
+            )),
+        ));
+    }
+    let days = config.get().id_reservation_days;
+    let reservation = db
+        .reserve_id("Patient", Duration::from_secs(u64::from(days) * 24 * 60 * 60))
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OperationOutcome::error(
+                    "processing",
+                    format!("Failed to reserve an id: {}", e),
+                )),
+            )
+        })?;
+
+    let id = reservation.id.to_string();
+    let reference = json!({ "reference": format!("Patient/{}", id) });
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    Ok((
+        StatusCode::OK,
+        headers,
+        Json(json!({
+            "resourceType": "Parameters",
+            "parameter": [
+                { "name": "id", "valueId": id },
+                { "name": "reference", "valueReference": reference },
+                { "name": "expires", "valueInstant": reservation.expires_at },
+            ]
+        })),
+    ))
+}
+
+/// `GET /fhir/Patient/:id/$conflict?base=<version>`: what changed since the
+/// version a rejected update was based on. `diff` is a JSON Patch from
+/// `base` to `current`; applied to the client's own edit of `base` it gives
+/// the three-way merge to retry with `If-Match` on the current version.
+pub async fn get_patient_conflict(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(id): Path<String>,
+    Query(params): Query<ConflictParams>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), (StatusCode, Json<OperationOutcome>)> {
+    if !db.history_enabled() {
+        return Err(history::not_supported());
+    }
+    let base_version = params
+        .base
+        .as_deref()
+        .and_then(|b| b.parse::<i32>().ok())
+        .filter(|b| *b > 0)
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error_with_location(
+                    "invalid",
+                    "base must be the version number the update was based on",
+                    "base",
+                )),
+            )
+        })?;
+    let processing = |e: anyhow::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to retrieve patient: {}", e),
+            )),
+        )
+    };
+
+    let Some(current) = db.get_patient(&id).await.map_err(processing)? else {
+        return Err(missing_patient(&db, &id).await);
+    };
+    consent.check_read(&db, "Patient", &id).await?;
+    let base = db
+        .get_patient_version(&id, base_version)
+        .await
+        .map_err(processing)?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(OperationOutcome::error_with_location(
+                    "not-found",
+                    format!("Patient/{} has no version {}", id, base_version),
+                    format!("Patient/{}/_history/{}", id, base_version),
+                )),
+            )
+        })?;
+    let base: Patient = serde_json::from_value(base).map_err(|e| processing(e.into()))?;
+    access::check_patient_reads(&config.get(), &request_headers, [&base, &current])?;
+
+    // Both sides are transformed so the diff exposes nothing a read would not
+    let mut versions = transforms
+        .apply_resources(vec![base, current])
+        .await
+        .map_err(transform_error)?
+        .into_iter()
+        .map(|patient| serde_json::to_value(patient).map_err(|e| processing(e.into())));
+    let (Some(base), Some(current)) = (versions.next(), versions.next()) else {
+        return Err(processing(anyhow::anyhow!(
+            "response transforms dropped a version"
+        )));
+    };
+    let (base, current) = (base?, current?);
+    let content = |resource: &Value| {
+        let mut resource = resource.clone();
+        if let Value::Object(map) = &mut resource {
+            map.remove("meta");
+        }
+        resource
+    };
+    let diff = json_patch::diff(&content(&base), &content(&current));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/json".parse().unwrap());
+    Ok((
+        StatusCode::OK,
+        headers,
+        Json(json!({
+            "base": base,
+            "current": current,
+            "diff": diff,
+        })),
+    ))
+}
+
+/// `GET /fhir/Patient/:id/_history`: a history Bundle, newest version
+/// first, or the versions as NDJSON, oldest first, when that is asked for
+pub async fn get_patient_history(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(id): Path<String>,
+    Query(params): Query<history::InstanceHistoryParams>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<OperationOutcome>)> {
+    if !db.history_enabled() {
+        return Err(history::not_supported());
+    }
+    consent.check_read(&db, "Patient", &id).await?;
+    let (since, at) = (params.since()?, params.at()?);
+    if negotiation::accepts_ndjson(&request_headers) {
+        return history::instance_ndjson(
+            db,
+            config.get(),
+            transforms,
+            request_headers,
+            id,
+            since,
+            at,
+        )
+        .await;
+    }
+
+    match db.get_patient_history(&id, since, at).await {
+        Ok(versions) => {
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+            let base_url = history::base_url();
+
+            // Use the newest record timestamp (first row, desc by version_id)
+            let bundle_last_updated = versions.first().map(|version| version.ts.clone());
+
+            let mut entries: Vec<Value> = versions
+                .into_iter()
+                .map(|version| {
+                    history::entry(
+                        &base_url,
+                        &id,
+                        version.version_id,
+                        version.ts,
+                        version.resource,
+                        version.status,
+                    )
+                })
+                .collect();
+            access::check_resource_reads(
+                &config.get(),
+                &request_headers,
+                entries.iter().filter_map(|entry| entry.get("resource")),
+            )?;
+            history::transform_entries(&transforms, &mut entries).await?;
+
+            let mut bundle = json!({
+                "resourceType": "Bundle",
+                "type": "history",
+                "total": entries.len(),
+                "link": [{
+                    "relation": "self",
+                    "url": format!(
+                        "{}/fhir/Patient/{}/_history{}",
+                        base_url,
+                        id,
+                        params.query_string()
+                    ),
+                }],
+                "entry": entries
+            });
+
+            // Add Bundle.meta.lastUpdated if we have at least one history record
+            if let (Some(ts), Value::Object(ref mut map)) = (bundle_last_updated, &mut bundle) {
+                map.insert("meta".to_string(), json!({ "lastUpdated": ts }));
+            }
+
+            Ok((StatusCode::OK, headers, Json(bundle)).into_response())
+        }
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(OperationOutcome::error(
+                "processing",
+                format!("Failed to retrieve patient history: {}", e),
+            )),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::models::HumanName;
+    use crate::profiles::ProfilePack;
+    use serde_json::Map;
+    use serde_json::Value;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    fn test_config() -> State<Arc<SharedConfig>> {
+        State(Arc::new(SharedConfig::default()))
+    }
+
+    fn test_metrics() -> State<Arc<Metrics>> {
+        State(Arc::new(Metrics::default()))
+    }
+
+    fn test_validation() -> State<Arc<ValidationHooks>> {
+        State(Arc::new(ValidationHooks::default()))
+    }
+
+    fn test_transforms() -> State<Arc<ResponsePipeline>> {
+        State(Arc::new(ResponsePipeline::default()))
+    }
+
+    fn test_registry() -> State<Arc<SearchParamRegistry>> {
+        State(Arc::new(SearchParamRegistry::with_builtins()))
+    }
+
+    async fn body(response: Response) -> axum::body::Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    fn create_test_patient(family: &str, given: &str, gender: &str, birth_date: &str) -> Patient {
+        Patient {
+            id: None,
+            resource_type: "Patient".to_string(),
+            meta: None,
+            name: Some(vec![HumanName {
+                family: Some(family.to_string()),
+                given: Some(vec![given.to_string()]),
+                text: Some(format!("{} {}", given, family)),
+                extra: Map::<String, Value>::new(),
+            }]),
+            gender: Some(gender.to_string()),
+            birth_date: Some(birth_date.to_string()),
+            ..Patient::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_patient_handler() {
+        let db = setup_test_db().await;
+        let patient = create_test_patient("TestFamily", "TestGiven", "male", "1990-01-01");
+
+        let result = create_patient(
+            State(db),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let (status, headers, json) = result.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(headers.contains_key("Content-Type"));
+        assert!(headers.contains_key("Location"));
+        assert!(json.id.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_patient_handler() {
+        let db = setup_test_db().await;
+
+        // Create a patient first
+        let patient = create_test_patient("GetTest", "Patient", "female", "1985-05-15");
+        let (_, _, created) = create_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap();
+        let patient_id = created.id.clone().unwrap();
+
+        // Get the patient
+        let result = get_patient(
+            State(db),
+            test_config(),
+            test_transforms(),
+            Path(patient_id.clone()),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let (status, headers, json) = result.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json.id, Some(patient_id));
+        assert_eq!(headers["ETag"], "W/\"1\"");
+    }
+
+    #[tokio::test]
+    async fn test_get_nonexistent_patient_handler() {
+        let db = setup_test_db().await;
+        let fake_id = uuid::Uuid::new_v4().to_string();
+
+        let result = get_patient(
+            State(db),
+            test_config(),
+            test_transforms(),
+            Path(fake_id),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        let (status, outcome) = result.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(outcome.resource_type, "OperationOutcome");
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_handler() {
+        let db = setup_test_db().await;
+
+        // Create test patients
+        let patient1 = create_test_patient("SearchTest1", "Alice", "female", "1990-01-01");
+        let patient2 = create_test_patient("SearchTest2", "Bob", "male", "1985-05-15");
+        let _ = create_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient1),
+        )
+        .await
+        .unwrap();
+        let _ = create_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient2),
+        )
+        .await
+        .unwrap();
+
+        // Search all patients
+        let params = SearchParams {
+            name: None,
+            name_contains: None,
+            name_phonetic: None,
+            gender: None,
+            id: None,
+            identifier: None,
+            count: Some(10),
+            offset: Some(0),
+            page_token: None,
+            total: None,
+            ..Default::default()
+        };
+
+        let result = search_patients(
+            State(db),
+            test_config(),
+            test_registry(),
+            test_transforms(),
+            Query(params),
+            ConsentFilter::default(),
+            Uri::from_static("/fhir/Patient"),
+            HeaderMap::new(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let (status, _, bundle) = result.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bundle.resource_type, "Bundle");
+        assert_eq!(bundle.bundle_type, "searchset");
+        assert!(!bundle.entry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_by_gender_handler() {
+        let db = setup_test_db().await;
+
+        let patient = create_test_patient("GenderTest", "Charlie", "other", "1995-12-25");
+        let _ = create_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap();
+
+        let params = SearchParams {
+            name: None,
+            name_contains: None,
+            name_phonetic: None,
+            gender: Some("other".to_string()),
+            id: None,
+            identifier: None,
+            count: Some(10),
+            offset: Some(0),
+            page_token: None,
+            total: None,
+            ..Default::default()
+        };
+
+        let result = search_patients(
+            State(db),
+            test_config(),
+            test_registry(),
+            test_transforms(),
+            Query(params),
+            ConsentFilter::default(),
+            Uri::from_static("/fhir/Patient"),
+            HeaderMap::new(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let (_, _, bundle) = result.unwrap();
+        assert!(!bundle.entry.is_empty());
+        assert!(bundle
+            .entry
+            .iter()
+            .all(|e| e.resource.gender == Some("other".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_without_criteria_handler() {
+        let db = setup_test_db().await;
+        for given in ["Una", "Ulf"] {
+            db.create_patient(create_test_patient("Unfiltered", given, "other", "1999-09-09"))
+                .await
+                .unwrap();
+        }
+        let search = |max_unfiltered_matches: u32, query: &str| {
+            let config = Arc::new(SharedConfig::new(ServerConfig {
+                max_unfiltered_matches,
+                ..ServerConfig::default()
+            }));
+            search_patients(
+                State(db.clone()),
+                State(config),
+                test_registry(),
+                test_transforms(),
+                Query(serde_urlencoded::from_str(query).unwrap()),
+                ConsentFilter::default(),
+                Uri::from_static("/fhir/Patient"),
+                HeaderMap::new(),
+            )
+        };
+
+        // Every patient matches, one page at a time
+        let (_, _, Json(bundle)) = search(0, "_count=1&_total=accurate").await.unwrap();
+        assert_eq!(bundle.entry.len(), 1);
+        assert!(bundle.total.unwrap() >= 2);
+        assert!(bundle
+            .link
+            .unwrap()
+            .iter()
+            .any(|link| link.relation == "next"));
+
+        let (status, Json(outcome)) = search(1, "_count=1").await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].code, "too-costly");
+        // The cap only applies without criteria
+        let (_, _, Json(bundle)) = search(1, "_count=1&name=Unfiltered").await.unwrap();
+        assert_eq!(bundle.entry.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_by_id_and_identifier_handler() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:mrn:{}", Uuid::new_v4());
+
+        let mut patient = create_test_patient("Lookup", "Mona", "female", "1970-07-07");
+        patient.extra.insert(
+            "identifier".to_string(),
+            json!([{ "system": system, "value": "MRN-42" }, { "value": "LOCAL-42" }]),
+        );
+        let (_, _, Json(created)) = create_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap();
+        let id = created.id.unwrap();
+
+        let search = |query: String| {
+            let db = db.clone();
+            async move {
+                let params: SearchParams = serde_urlencoded::from_str(&query).unwrap();
+                let (_, _, Json(bundle)) = search_patients(
+                    State(db),
+                    test_config(),
+                    test_registry(),
+                    test_transforms(),
+                    Query(params),
+                    ConsentFilter::default(),
+                    Uri::from_static("/fhir/Patient"),
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                bundle
+                    .entry
+                    .into_iter()
+                    .map(|e| e.resource.id.unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+        let encode = |value: &str| serde_urlencoded::to_string([("identifier", value)]).unwrap();
+
+        assert_eq!(search(encode(&format!("{}|MRN-42", system))).await, vec![id.clone()]);
+        assert_eq!(search(encode(&format!("{}|", system))).await, vec![id.clone()]);
+        assert!(search(encode(&format!("{}|MRN-43", system))).await.is_empty());
+        // The unsystemed identifier only matches without a system
+        let local = search(encode("|LOCAL-42")).await;
+        assert!(local.contains(&id));
+        assert!(!search(encode("|MRN-42")).await.contains(&id));
+        let any = format!("{}|MRN-0,{}|MRN-42", system, system);
+        assert_eq!(search(encode(&any)).await, vec![id.clone()]);
+
+        assert_eq!(search(format!("_id={}", id)).await, vec![id.clone()]);
+        assert_eq!(search(format!("_id=not-a-uuid,{}", id)).await, vec![id.clone()]);
+        assert!(search("_id=not-a-uuid".to_string()).await.is_empty());
+        assert!(search(format!("_id={}&gender=male", id)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_missing_and_exact_handler() {
+        let db = setup_test_db().await;
+        let family = format!("Modifier{}", Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for birth_date in [Some("1980-08-08"), None] {
+            let mut patient = create_test_patient(&family, "Max", "male", "1980-08-08");
+            patient.birth_date = birth_date.map(str::to_string);
+            ids.push(db.create_patient(patient).await.unwrap().id.unwrap());
+        }
+
+        let search = |query: String| {
+            let db = db.clone();
+            async move {
+                let uri: Uri = format!("/fhir/Patient?{}", query).parse().unwrap();
+                let params: SearchParams = serde_urlencoded::from_str(&query).unwrap();
+                search_patients(
+                    State(db),
+                    test_config(),
+                    test_registry(),
+                    test_transforms(),
+                    Query(params),
+                    ConsentFilter::default(),
+                    uri,
+                    HeaderMap::new(),
+                )
+                .await
+                .map(|(_, _, Json(bundle))| {
+                    let mut ids: Vec<String> = bundle
+                        .entry
+                        .into_iter()
+                        .map(|e| e.resource.id.unwrap())
+                        .collect();
+                    ids.sort();
+                    ids
+                })
+            }
+        };
+        let mut both = ids.clone();
+        both.sort();
+
+        let query = format!("name={}&birthdate:missing=true", family);
+        assert_eq!(search(query).await.unwrap(), vec![ids[1].clone()]);
+        let query = format!("name={}&birthdate:missing=false", family);
+        assert_eq!(search(query).await.unwrap(), vec![ids[0].clone()]);
+        let query = format!("name={}&gender:missing=false", family);
+        assert_eq!(search(query).await.unwrap(), both);
+
+        // :exact matches the whole name with its case
+        assert_eq!(search(format!("name:exact={}", family)).await.unwrap(), both);
+        let lower = family.to_lowercase();
+        assert!(search(format!("name:exact={}", lower)).await.unwrap().is_empty());
+        assert_eq!(search(format!("name={}", lower)).await.unwrap(), both);
+        let prefix = &family[..family.len() - 1];
+        assert!(search(format!("name:exact={}", prefix)).await.unwrap().is_empty());
+
+        let (status, Json(outcome)) = search("gender:missing=maybe".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].code, "invalid");
+        let (status, Json(outcome)) = search(format!("name:text={}", family))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].code, "not-supported");
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_or_values_handler() {
+        let db = setup_test_db().await;
+        let family = format!("OrValues{}", Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for (given, gender, birth_date) in [
+            ("Anna", "female", "1970-01-01"),
+            ("Ben", "male", "1980-01-01"),
+            ("Cleo", "other", "1990-01-01"),
+        ] {
+            let patient = create_test_patient(&family, given, gender, birth_date);
+            ids.push(db.create_patient(patient).await.unwrap().id.unwrap());
+        }
+
+        let search = |query: String| {
+            let db = db.clone();
+            async move {
+                let uri: Uri = format!("/fhir/Patient?{}", query).parse().unwrap();
+                let params: SearchParams = serde_urlencoded::from_str(&query).unwrap();
+                let (_, _, Json(bundle)) = search_patients(
+                    State(db),
+                    test_config(),
+                    test_registry(),
+                    test_transforms(),
+                    Query(params),
+                    ConsentFilter::default(),
+                    uri,
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                let mut found: Vec<String> = bundle
+                    .entry
+                    .into_iter()
+                    .map(|e| e.resource.id.unwrap())
+                    .collect();
+                found.sort();
+                found
+            }
+        };
+        let sorted = |mut expected: Vec<String>| {
+            expected.sort();
+            expected
+        };
+
+        let query = format!("name={}&gender=male,female", family);
+        assert_eq!(search(query).await, sorted(vec![ids[0].clone(), ids[1].clone()]));
+        let query = format!("name={}&birthdate=1970-01-01,1990-01-01", family);
+        assert_eq!(search(query).await, sorted(vec![ids[0].clone(), ids[2].clone()]));
+        // Alternatives of one parameter are ORed, separate parameters ANDed
+        let query = format!("name:exact={}&name=Anna,Cleo&gender=other", family);
+        assert_eq!(search(query).await, vec![ids[2].clone()]);
+        let query = format!("name={},NoSuchName{}", family, family);
+        assert_eq!(search(query).await, sorted(ids.clone()));
+        let query = format!("_id={},{}&name={}", ids[0], ids[1], family);
+        assert_eq!(search(query).await, sorted(vec![ids[0].clone(), ids[1].clone()]));
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_text_and_content_handler() {
+        let db = setup_test_db().await;
+        let family = format!("FullText{}", Uuid::new_v4().simple());
+        let div =
+            |text: &str| format!("<div xmlns=\"http://www.w3.org/1999/xhtml\">{}</div>", text);
+        let mut ids = Vec::new();
+        for (given, narrative) in [("Anna", "Chronic <b>asthma</b>"), ("Ben", "Broken wrist")] {
+            let mut patient = create_test_patient(&family, given, "unknown", "1970-01-01");
+            patient.extra.insert(
+                "text".to_string(),
+                json!({ "status": "generated", "div": div(narrative) }),
+            );
+            ids.push(db.create_patient(patient).await.unwrap().id.unwrap());
+        }
+
+        let search = |query: String| {
+            let db = db.clone();
+            async move {
+                let uri: Uri = format!("/fhir/Patient?{}", query).parse().unwrap();
+                let params: SearchParams = serde_urlencoded::from_str(&query).unwrap();
+                let (_, _, Json(bundle)) = search_patients(
+                    State(db),
+                    test_config(),
+                    test_registry(),
+                    test_transforms(),
+                    Query(params),
+                    ConsentFilter::default(),
+                    uri,
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                let mut found: Vec<String> = bundle
+                    .entry
+                    .into_iter()
+                    .map(|e| e.resource.id.unwrap())
+                    .collect();
+                found.sort();
+                found
+            }
+        };
+
+        let query = format!("_content={}&_text=asthma", family);
+        assert_eq!(search(query).await, vec![ids[0].clone()]);
+        // Markup is not text, and words match whole and case-insensitively
+        let query = format!("_content={}&_text=xhtml,WRIST", family);
+        assert_eq!(search(query).await, vec![ids[1].clone()]);
+        let query = format!("_content={}+ben&_text=%22chronic+asthma%22", family);
+        assert!(search(query).await.is_empty());
+        let query = format!("_content={}+-anna", family.to_lowercase());
+        assert_eq!(search(query).await, vec![ids[1].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_birthdate_prefixes() {
+        let db = setup_test_db().await;
+        let family = format!("BirthDates{}", Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for birth_date in ["1970-06-15", "1980-01-01", "1985", "1990-12-31"] {
+            let patient = create_test_patient(&family, "Dana", "female", birth_date);
+            ids.push(db.create_patient(patient).await.unwrap().id.unwrap());
+        }
+
+        let search = |birthdates: &[&str]| {
+            let db = db.clone();
+            let mut query = format!("name={}", family);
+            for birthdate in birthdates {
+                query.push_str(&format!("&birthdate={}", birthdate));
+            }
+            async move {
+                let uri: Uri = format!("/fhir/Patient?{}", query).parse().unwrap();
+                let params: SearchParams = serde_urlencoded::from_str(&query).unwrap();
+                search_patients(
+                    State(db),
+                    test_config(),
+                    test_registry(),
+                    test_transforms(),
+                    Query(params),
+                    ConsentFilter::default(),
+                    uri,
+                    HeaderMap::new(),
+                )
+                .await
+                .map(|(_, _, Json(bundle))| {
+                    let mut found: Vec<String> = bundle
+                        .entry
+                        .into_iter()
+                        .map(|e| e.resource.id.unwrap())
+                        .collect();
+                    found.sort();
+                    found
+                })
+            }
+        };
+        let sorted = |indexes: &[usize]| {
+            let mut expected: Vec<String> = indexes.iter().map(|&i| ids[i].clone()).collect();
+            expected.sort();
+            expected
+        };
+
+        // Partial dates are ranges, on both sides
+        assert_eq!(search(&["1970"]).await.unwrap(), sorted(&[0]));
+        assert_eq!(search(&["1985-03"]).await.unwrap(), sorted(&[]));
+        assert_eq!(search(&["gt1980"]).await.unwrap(), sorted(&[2, 3]));
+        assert_eq!(search(&["ge1980"]).await.unwrap(), sorted(&[1, 2, 3]));
+        assert_eq!(search(&["lt1980-01-01"]).await.unwrap(), sorted(&[0]));
+        assert_eq!(search(&["sa1985-06"]).await.unwrap(), sorted(&[3]));
+        assert_eq!(search(&["eb1985-06"]).await.unwrap(), sorted(&[0, 1]));
+        assert_eq!(search(&["ne1985"]).await.unwrap(), sorted(&[0, 1, 3]));
+        // Repeated, the parameter is a range
+        assert_eq!(search(&["ge1975", "lt1990"]).await.unwrap(), sorted(&[1, 2]));
+        assert_eq!(search(&["ap1970-06-15"]).await.unwrap(), sorted(&[0]));
+
+        let (status, Json(outcome)) = search(&["ge1990-13"]).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].location, Some(vec!["birthdate".to_string()]));
+        let query = format!("name={}&birthdate:ge=1980", family);
+        assert!(check_modifiers(Some(&query)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_patients_pagination_handler() {
+        let db = setup_test_db().await;
+
+        // Create multiple patients
+        for i in 0..5 {
+            let patient = create_test_patient(
+                &format!("PaginationTest{}", i),
+                &format!("Patient{}", i),
+                "unknown",
+                "2000-01-01",
+            );
+            let _ = create_patient(
+                State(db.clone()),
+                test_metrics(),
+                test_validation(),
+                test_transforms(),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(patient),
+            )
+            .await
+            .unwrap();
+        }
+
+        // Get first page
+        let params1 = SearchParams {
+            name: None,
+            name_contains: None,
+            name_phonetic: None,
+            gender: None,
+            id: None,
+            identifier: None,
+            count: Some(2),
+            offset: Some(0),
+            page_token: None,
+            total: None,
+            ..Default::default()
+        };
+
+        let result1 = search_patients(
+            State(db.clone()),
+            test_config(),
+            test_registry(),
+            test_transforms(),
+            Query(params1),
+            ConsentFilter::default(),
+            Uri::from_static("/fhir/Patient"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(result1.is_ok());
+        let (_, _, bundle1) = result1.unwrap();
+        assert!(bundle1.entry.len() <= 2);
+        // The total counts every match, not just this page
+        assert!(bundle1.total.unwrap() >= 5);
+
+        // Get second page
+        let params2 = SearchParams {
+            name: None,
+            name_contains: None,
+            name_phonetic: None,
+            gender: None,
+            id: None,
+            identifier: None,
+            count: Some(2),
+            offset: Some(2),
+            page_token: None,
+            total: None,
+            ..Default::default()
+        };
+
+        let result2 = search_patients(
+            State(db.clone()),
+            test_config(),
+            test_registry(),
+            test_transforms(),
+            Query(params2),
+            ConsentFilter::default(),
+            Uri::from_static("/fhir/Patient?_count=2&_offset=2"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(result2.is_ok());
+        let (_, _, Json(bundle2)) = result2.unwrap();
+        assert!(bundle2.entry.len() <= 2);
+        let links = bundle2.link.unwrap();
+        let link = |relation: &str| {
+            links
+                .iter()
+                .find(|link| link.relation == relation)
+                .map(|link| link.url.clone())
+        };
+        assert!(link("self").unwrap().ends_with("/fhir/Patient?_count=2&_offset=2"));
+        let last = Uuid::parse_str(bundle2.entry.last().unwrap().resource.id.as_deref().unwrap())
+            .unwrap();
+        let token = PageStart::token_after(last);
+        assert!(link("next")
+            .unwrap()
+            .ends_with(&format!("/fhir/Patient?_count=2&_page_token={}", token)));
+        assert!(link("previous").unwrap().ends_with("/fhir/Patient?_count=2&_offset=0"));
+
+        // The next page continues after the last patient of this one
+        let params3 = SearchParams {
+            name: None,
+            name_contains: None,
+            name_phonetic: None,
+            gender: None,
+            id: None,
+            identifier: None,
+            count: Some(2),
+            offset: None,
+            page_token: Some(token),
+            total: None,
+            ..Default::default()
+        };
+        let (_, _, Json(bundle3)) = search_patients(
+            State(db),
+            test_config(),
+            test_registry(),
+            test_transforms(),
+            Query(params3),
+            ConsentFilter::default(),
+            Uri::from_static("/fhir/Patient"),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert!(!bundle3.entry.is_empty());
+        assert!(bundle3
+            .entry
+            .iter()
+            .all(|e| Uuid::parse_str(e.resource.id.as_deref().unwrap()).unwrap() > last));
+        assert!(bundle3.link.unwrap().iter().all(|link| link.relation != "previous"));
+    }
+
+    #[tokio::test]
+    async fn test_search_params_defaults() {
+        let db = setup_test_db().await;
+
+        let params = SearchParams {
+            name: None,
+            name_contains: None,
+            name_phonetic: None,
+            gender: None,
+            id: None,
+            identifier: None,
+            count: None,  // Should default to 20
+            offset: None, // Should default to 0
+            page_token: None,
+            total: None,
+            ..Default::default()
+        };
+
+        let result = search_patients(
+            State(db),
+            test_config(),
+            test_registry(),
+            test_transforms(),
+            Query(params),
+            ConsentFilter::default(),
+            Uri::from_static("/fhir/Patient"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create_patient_sets_resource_type() {
+        let db = setup_test_db().await;
+        let mut patient = create_test_patient("TypeTest", "Patient", "male", "1990-01-01");
+        patient.resource_type = "WrongType".to_string();
+
+        let result = create_patient(
+            State(db),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let (_, _, created) = result.unwrap();
+        assert_eq!(created.resource_type, "Patient");
+    }
+
+    #[tokio::test]
+    async fn test_history_not_supported_without_history_table() {
+        let db = setup_test_db().await;
+        db.set_history_enabled(false);
+
+        let result = get_patient_history(
+            State(db),
+            test_config(),
+            test_transforms(),
+            Path(uuid::Uuid::new_v4().to_string()),
+            Query(Default::default()),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await;
+
+        let (status, Json(outcome)) = result.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(outcome.issue[0].code, "not-supported");
+    }
+
+    #[tokio::test]
+    async fn test_create_duplicate_identifier_returns_conflict() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:mrn:{}", uuid::Uuid::new_v4());
+        db.set_unique_identifier_systems(vec![system.clone()]);
+
+        let mut patient = create_test_patient("Twin", "Tom", "male", "1980-02-02");
+        patient.extra.insert(
+            "identifier".to_string(),
+            json!([{ "system": system, "value": "MRN-7" }]),
+        );
+
+        let metrics = Arc::new(Metrics::default());
+        assert!(create_patient(
+            State(db.clone()),
+            State(metrics.clone()),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient.clone())
+        )
+        .await
+        .is_ok());
+        let (status, Json(outcome)) = create_patient(
+            State(db),
+            State(metrics.clone()),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(outcome.issue[0].code, "duplicate");
+
+        let mut out = crate::metrics::OpenMetricsWriter::default();
+        metrics.write_counters(&mut out);
+        let text = out.finish();
+        assert!(text.contains("fhir_patients_created_total 1\n"));
+        assert!(text.contains("fhir_identifier_conflicts_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_create_rejected_by_validation_hook() {
+        let db = setup_test_db().await;
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 16) "[{\"severity\":\"error\",\"code\":\"business-rule\"}]")
+            (func (export "fhir_alloc") (param i32) (result i32) i32.const 1024)
+            ;; (16 << 32) | 45: the issue array in the data segment
+            (func (export "fhir_validate") (param i32 i32) (result i64)
+                i64.const 68719476781))"#;
+        let hooks = ValidationHooks::default();
+        hooks.set(vec![crate::validation::ValidationHook::from_bytes(
+            "reject",
+            wat.as_bytes(),
+            1_000_000,
+        )
+        .unwrap()]);
+
+        let patient = create_test_patient("Rule", "Ruth", "female", "1990-09-09");
+        let (status, Json(outcome)) = create_patient(
+            State(db),
+            test_metrics(),
+            State(Arc::new(hooks)),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(outcome.issue[0].code, "business-rule");
+    }
+
+    #[tokio::test]
+    async fn test_get_patient_applies_response_transforms() {
+        let db = setup_test_db().await;
+        let patient = create_test_patient("Redacted", "Rita", "female", "1975-05-05");
+        let created = db.create_patient(patient).await.unwrap();
+
+        let transforms = ResponsePipeline::default();
+        transforms
+            .reload(&[crate::transform::TransformConfig::Deidentify])
+            .unwrap();
+
+        let (_, _, Json(patient)) = get_patient(
+            State(db),
+            test_config(),
+            State(Arc::new(transforms)),
+            Path(created.id.unwrap()),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert!(patient.name.is_none());
+        assert_eq!(patient.birth_date.as_deref(), Some("1975"));
+    }
+
+    #[tokio::test]
+    async fn test_deleted_patient_is_gone() {
+        let db = setup_test_db().await;
+        let patient = create_test_patient("Deleted", "Dora", "female", "1960-06-06");
+        let created = db.create_patient(patient.clone()).await.unwrap();
+        let id = created.id.unwrap();
+
+        let status = delete_patient(
+            State(db.clone()),
+            test_metrics(),
+            Path(id.clone()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, Json(outcome)) = get_patient(
+            State(db.clone()),
+            test_config(),
+            test_transforms(),
+            Path(id.clone()),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(outcome.issue[0].code, "deleted");
+
+        let (status, _) = update_patient(
+            State(db.clone()),
+            test_config(),
+            test_metrics(),
+            test_validation(),
+            Path(id.clone()),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::GONE);
+
+        // Deleting again succeeds without changing anything
+        let status = delete_patient(
+            State(db.clone()),
+            test_metrics(),
+            Path(id.clone()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let response = get_patient_history(
+            State(db.clone()),
+            test_config(),
+            test_transforms(),
+            Path(id.clone()),
+            Query(Default::default()),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let bundle: Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(bundle["total"], 2);
+        assert_eq!(bundle["entry"][0]["request"]["method"], "DELETE");
+        assert!(bundle["entry"][0].get("resource").is_none());
+        assert_eq!(
+            bundle["entry"][1]["resource"]["name"][0]["family"],
+            "Deleted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patient_history_as_ndjson() {
+        let db = setup_test_db().await;
+        let patient = create_test_patient("Ndjson", "Nia", "female", "1985-05-05");
+        let created = db.create_patient(patient.clone()).await.unwrap();
+        let id = created.id.clone().unwrap();
+        for gender in ["male", "other"] {
+            let mut updated = created.clone();
+            updated.gender = Some(gender.to_string());
+            db.update_patient(&id, updated).await.unwrap();
+        }
+        db.delete_patient(&id).await.unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Accept", "application/fhir+ndjson".parse().unwrap());
+        let response = get_patient_history(
+            State(db),
+            test_config(),
+            test_transforms(),
+            Path(id.clone()),
+            Query(Default::default()),
+            ConsentFilter::default(),
+            headers,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/fhir+ndjson"
+        );
+
+        // Oldest first, one resource per line; the delete has no line
+        let body = body(response).await;
+        let lines: Vec<Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        for (line, (version, gender)) in lines
+            .iter()
+            .zip([("1", "female"), ("2", "male"), ("3", "other")])
+        {
+            assert_eq!(line["id"], id);
+            assert_eq!(line["meta"]["versionId"], version);
+            assert_eq!(line["gender"], gender);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_unknown_patient_is_not_found() {
+        let db = setup_test_db().await;
+
+        let (status, _) = delete_patient(
+            State(db),
+            test_metrics(),
+            Path(uuid::Uuid::new_v4().to_string()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_create() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:mrn:{}", uuid::Uuid::new_v4());
+        let mut patient = create_test_patient("Conditional", "Cora", "female", "1970-07-07");
+        patient.extra.insert(
+            "identifier".to_string(),
+            json!([{ "system": system, "value": "MRN-1" }]),
+        );
+        let mut if_none_exist = HeaderMap::new();
+        if_none_exist.insert(
+            "If-None-Exist",
+            format!("identifier={}|MRN-1", system).parse().unwrap(),
+        );
+
+        let create = |patient: Patient| {
+            create_patient(
+                State(db.clone()),
+                test_metrics(),
+                test_validation(),
+                test_transforms(),
+                Attribution::default(),
+                if_none_exist.clone(),
+                Json(patient),
+            )
+        };
+
+        let (status, _, Json(first)) = create(patient.clone()).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, headers, Json(second)) = create(patient.clone()).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(second.id, first.id);
+        assert_eq!(
+            headers.get("Location").unwrap(),
+            &format!("/fhir/Patient/{}", first.id.unwrap())
+        );
+
+        // A second holder, created unconditionally, makes the criteria ambiguous
+        db.create_patient(patient.clone()).await.unwrap();
+        let (status, Json(outcome)) = create(patient).await.unwrap_err();
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(outcome.issue[0].code, "multiple-matches");
+    }
+
+    #[tokio::test]
+    async fn test_conditional_create_rejects_unsupported_criteria() {
+        let db = setup_test_db().await;
+        let mut headers = HeaderMap::new();
+        headers.insert("If-None-Exist", "name=Smith".parse().unwrap());
+
+        let (status, _) = create_patient(
+            State(db),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            headers,
+            Json(create_test_patient("Smith", "Sam", "male", "1980-01-01")),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_update_creates_then_updates() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:mrn:{}", uuid::Uuid::new_v4());
+        let mut patient = create_test_patient("Upsert", "Uma", "female", "1975-05-05");
+        patient.extra.insert(
+            "identifier".to_string(),
+            json!([{ "system": system, "value": "MRN-2" }]),
+        );
+        let query = format!("identifier={}%7CMRN-2", system);
+
+        let (status, headers, Json(created)) = conditional_update_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            RawQuery(Some(query.clone())),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(headers.contains_key("Location"));
+
+        patient.birth_date = Some("1975-06-06".to_string());
+        let (status, _, Json(updated)) = conditional_update_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            RawQuery(Some(query)),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patient),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated.id, created.id);
+        assert_eq!(updated.birth_date.as_deref(), Some("1975-06-06"));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_update_requires_identifier_criteria() {
+        let db = setup_test_db().await;
+
+        let (status, _) = conditional_update_patient(
+            State(db),
+            test_metrics(),
+            test_validation(),
+            RawQuery(None),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(create_test_patient("Nobody", "Ned", "male", "1980-01-01")),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_put_creates_patient_under_reserved_id() {
+        let db = setup_test_db().await;
+        let (status, _, Json(parameters)) = reserve_patient_id(State(db.clone()), test_config())
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parameters["parameter"][0]["name"], "id");
+        let id = parameters["parameter"][0]["valueId"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            parameters["parameter"][1]["valueReference"]["reference"],
+            format!("Patient/{}", id)
+        );
+        assert!(parameters["parameter"][2]["valueInstant"].is_string());
+
+        let put = |db: Arc<Database>, id: String| async move {
+            update_patient(
+                State(db),
+                test_config(),
+                test_metrics(),
+                test_validation(),
+                Path(id),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(create_test_patient("Reserved", "Rita", "female", "1990-09-09")),
+            )
+            .await
+        };
+        let (status, headers, Json(created)) = put(db.clone(), id.clone()).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            headers.get("Location").unwrap(),
+            &format!("/fhir/Patient/{}", id)
+        );
+        assert_eq!(created.id.as_deref(), Some(id.as_str()));
+
+        // A retry of the same PUT updates without a new version
+        let (status, headers, _) = put(db.clone(), id.clone()).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("ETag").unwrap(), "W/\"1\"");
+
+        let (status, _) = put(db.clone(), Uuid::new_v4().to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        db.purge_patient(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_update_conflicts_and_reports_changes() {
+        let db = setup_test_db().await;
+        let (_, _, Json(created)) = create_patient(
+            State(db.clone()),
+            test_metrics(),
+            test_validation(),
+            test_transforms(),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(create_test_patient("Rebase", "Rae", "female", "1990-09-09")),
+        )
+        .await
+        .unwrap();
+        let id = created.id.clone().unwrap();
+        let mut if_match = HeaderMap::new();
+        if_match.insert("If-Match", "W/\"1\"".parse().unwrap());
+        let update = |gender: &str| {
+            let mut patient = created.clone();
+            patient.gender = Some(gender.to_string());
+            update_patient(
+                State(db.clone()),
+                test_config(),
+                test_metrics(),
+                test_validation(),
+                Path(id.clone()),
+                Attribution::default(),
+                if_match.clone(),
+                Json(patient),
+            )
+        };
+
+        let (status, _, _) = update("other").await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let (status, Json(outcome)) = update("male").await.unwrap_err();
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(outcome.issue[0].code, "conflict");
+
+        let (status, _, Json(conflict)) = get_patient_conflict(
+            State(db.clone()),
+            test_config(),
+            test_transforms(),
+            Path(id.clone()),
+            Query(ConflictParams {
+                base: Some("1".to_string()),
+            }),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(conflict["base"]["gender"], "female");
+        assert_eq!(conflict["current"]["meta"]["versionId"], "2");
+        assert_eq!(
+            conflict["diff"],
+            json!([{ "op": "replace", "path": "/gender", "value": "other" }])
+        );
+
+        let (status, _) = get_patient_conflict(
+            State(db),
+            test_config(),
+            test_transforms(),
+            Path(id),
+            Query(ConflictParams {
+                base: Some("7".to_string()),
+            }),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_required_if_match() {
+        let db = setup_test_db().await;
+        let created = db
+            .create_patient(create_test_patient("Etag", "Ed", "male", "1954-04-04"))
+            .await
+            .unwrap();
+        let id = created.id.clone().unwrap();
+        let config = Arc::new(SharedConfig::new(ServerConfig {
+            require_if_match: true,
+            ..ServerConfig::default()
+        }));
+        let update = |headers: HeaderMap| {
+            let mut patient = created.clone();
+            patient.gender = Some("other".to_string());
+            update_patient(
+                State(db.clone()),
+                State(config.clone()),
+                test_metrics(),
+                test_validation(),
+                Path(id.clone()),
+                Attribution::default(),
+                headers,
+                Json(patient),
+            )
+        };
+
+        let (status, Json(outcome)) = update(HeaderMap::new()).await.unwrap_err();
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(outcome.issue[0].code, "required");
+
+        let (_, read_headers, _) = get_patient(
+            State(db.clone()),
+            test_config(),
+            test_transforms(),
+            Path(id.clone()),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let mut if_match = HeaderMap::new();
+        if_match.insert("If-Match", read_headers["ETag"].clone());
+        let (status, headers, Json(updated)) = update(if_match).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["ETag"], "W/\"2\"");
+        assert_eq!(updated.gender.as_deref(), Some("other"));
+    }
+
+    #[tokio::test]
+    async fn test_merge_update_changes_only_named_elements() {
+        let db = setup_test_db().await;
+        let created = db
+            .create_patient(create_test_patient("Merge", "Mia", "female", "1966-06-06"))
+            .await
+            .unwrap();
+        let id = created.id.clone().unwrap();
+        let merge = |changes: Value| {
+            merge_update_patient(
+                State(db.clone()),
+                test_config(),
+                test_metrics(),
+                test_validation(),
+                Path(id.clone()),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(changes),
+            )
+        };
+
+        let (status, headers, Json(merged)) =
+            merge(json!({ "gender": "other", "birthDate": null }))
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["ETag"], "W/\"2\"");
+        assert_eq!(merged.gender.as_deref(), Some("other"));
+        assert!(merged.birth_date.is_none());
+        assert_eq!(json!(merged.name), json!(created.name));
+
+        // A named element is replaced whole, not merged
+        let (_, _, Json(merged)) = merge(json!({ "name": [{ "given": ["Maria"] }] }))
+            .await
+            .unwrap();
+        let name = &merged.name.unwrap()[0];
+        assert_eq!(name.given, Some(vec!["Maria".to_string()]));
+        assert!(name.family.is_none());
+
+        let (status, _) = merge(json!({ "id": "someone-else" })).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = merge(json!(["gender"])).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_failed_patch_that_cannot_be_undone_is_rejected() {
+        let db = setup_test_db().await;
+        let created = db
+            .create_patient(create_test_patient("Patch", "Pia", "female", "1970-07-07"))
+            .await
+            .unwrap();
+        let id = created.id.clone().unwrap();
+        // Found by the patient_patch fuzz target
+        let patch = json!([
+            { "op": "move", "from": "/meta", "path": "" },
+            { "op": "add", "path": "/name/name", "value": null }
+        ]);
+
+        let (status, _) = patch_patient(
+            State(db.clone()),
+            test_config(),
+            test_metrics(),
+            test_validation(),
+            Path(id.clone()),
+            Attribution::default(),
+            HeaderMap::new(),
+            Json(patch),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let stored = db.get_patient(&id).await.unwrap().unwrap();
+        assert_eq!(json!(stored.name), json!(created.name));
+    }
+
+    #[tokio::test]
+    async fn test_fhirpath_patch() {
+        let db = setup_test_db().await;
+        let created = db
+            .create_patient(create_test_patient("Patch", "Paul", "male", "1980-08-08"))
+            .await
+            .unwrap();
+        let id = created.id.clone().unwrap();
+        let patch = |operations: Value| {
+            patch_patient(
+                State(db.clone()),
+                test_config(),
+                test_metrics(),
+                test_validation(),
+                Path(id.clone()),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(json!({ "resourceType": "Parameters", "parameter": operations })),
+            )
+        };
+
+        let (status, _, Json(patched)) = patch(json!([
+            { "name": "operation", "part": [
+                { "name": "type", "valueCode": "replace" },
+                { "name": "path", "valueString": "Patient.gender" },
+                { "name": "value", "valueCode": "other" }
+            ] },
+            { "name": "operation", "part": [
+                { "name": "type", "valueCode": "add" },
+                { "name": "path", "valueString": "Patient.name.first()" },
+                { "name": "name", "valueString": "given" },
+                { "name": "value", "valueString": "Peter" }
+            ] }
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(patched.gender.as_deref(), Some("other"));
+        assert_eq!(json!(patched.name.unwrap()[0].given), json!(["Paul", "Peter"]));
+
+        // Gender is already set, so nothing is saved
+        let (status, Json(outcome)) = patch(json!([
+            { "name": "operation", "part": [
+                { "name": "type", "valueCode": "delete" },
+                { "name": "path", "valueString": "Patient.birthDate" }
+            ] },
+            { "name": "operation", "part": [
+                { "name": "type", "valueCode": "add" },
+                { "name": "path", "valueString": "Patient" },
+                { "name": "name", "valueString": "gender" },
+                { "name": "value", "valueCode": "male" }
+            ] }
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            outcome.issue[0].location,
+            Some(vec!["Parameters.parameter[1]".to_string()])
+        );
+        let stored = db.get_patient(&id).await.unwrap().unwrap();
+        assert_eq!(stored.birth_date, created.birth_date);
+
+        let (status, _) = patch(json!([{ "name": "operation", "part": [
+            { "name": "type", "valueCode": "delete" },
+            { "name": "path", "valueString": "Patient.name.exists()" }
+        ] }]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_modifier_extensions_must_be_understood() {
+        let db = setup_test_db().await;
+        let url = "http://example.org/fhir/StructureDefinition/test-only";
+        let patient: Patient = serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "gender": "female",
+            "modifierExtension": [{ "url": url, "valueBoolean": true }]
+        }))
+        .unwrap();
+        let hooks = Arc::new(ValidationHooks::default());
+        let create = |patient: Patient| {
+            create_patient(
+                State(db.clone()),
+                test_metrics(),
+                State(hooks.clone()),
+                test_transforms(),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(patient),
+            )
+        };
+
+        let (status, Json(outcome)) = create(patient.clone()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(outcome.issue[0].code, "extension");
+        assert_eq!(
+            outcome.issue[0].location,
+            Some(vec!["Patient.modifierExtension[0]".to_string()])
+        );
+
+        hooks.set_understood_modifiers(vec![url.to_string()]);
+        let (status, _, Json(created)) = create(patient).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let stored = db
+            .get_patient(created.id.as_deref().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.extra["modifierExtension"][0]["url"], url);
+    }
+
+    #[tokio::test]
+    async fn test_profile_pack_search_and_validation() {
+        let db = setup_test_db().await;
+        let race = "http://hl7.org/fhir/us/core/StructureDefinition/us-core-race";
+        let profile = "http://hl7.org/fhir/us/core/StructureDefinition/us-core-patient";
+        let pack = ProfilePack::from_resources(
+            &json!({ "name": "hl7.fhir.us.core", "version": "6.1.0" }),
+            &[
+                json!({
+                    "resourceType": "SearchParameter",
+                    "id": "us-core-race",
+                    "url": "http://hl7.org/fhir/us/core/SearchParameter/us-core-race",
+                    "code": "race",
+                    "base": ["Patient"],
+                    "type": "token",
+                    "expression": format!(
+                        "Patient.extension.where(url = '{}').extension.value.code",
+                        race
+                    )
+                }),
+                json!({
+                    "resourceType": "StructureDefinition",
+                    "url": profile,
+                    "kind": "resource",
+                    "derivation": "constraint",
+                    "type": "Patient",
+                    "differential": { "element": [
+                        { "id": "Patient.identifier", "path": "Patient.identifier", "min": 1 }
+                    ] }
+                }),
+            ],
+        );
+        let registry = Arc::new(SearchParamRegistry::with_builtins());
+        assert_eq!(pack.register(&registry).len(), 1);
+        let hooks = Arc::new(ValidationHooks::default());
+        hooks.set_profiles(pack.profiles);
+        let create = |patient: Value| {
+            create_patient(
+                State(db.clone()),
+                test_metrics(),
+                State(hooks.clone()),
+                test_transforms(),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(serde_json::from_value(patient).unwrap()),
+            )
+        };
+
+        // The profile needs no stored StructureDefinition, but is checked
+        let family = format!("UsCore{}", Uuid::new_v4().simple());
+        let (status, Json(outcome)) = create(json!({
+            "resourceType": "Patient",
+            "meta": { "profile": [profile] },
+            "name": [{ "family": family }]
+        }))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(outcome.issue.len(), 1);
+        assert_eq!(outcome.issue[0].code, "required");
+        assert_eq!(
+            outcome.issue[0].location,
+            Some(vec!["Patient.identifier".to_string()])
+        );
+
+        let mut ids = Vec::new();
+        for code in ["2106-3", "2054-5"] {
+            let (_, _, Json(created)) = create(json!({
+                "resourceType": "Patient",
+                "meta": { "profile": [format!("{}|6.1.0", profile)] },
+                "identifier": [{ "system": "urn:test:us-core", "value": code }],
+                "name": [{ "family": family }],
+                "extension": [{
+                    "url": race,
+                    "extension": [
+                        { "url": "ombCategory", "valueCoding": {
+                            "system": "urn:oid:2.16.840.1.113883.6.238", "code": code
+                        } },
+                        { "url": "text", "valueString": "Race" }
+                    ]
+                }]
+            }))
+            .await
+            .unwrap();
+            assert_eq!(
+                created.meta.unwrap().profile,
+                Some(vec![format!("{}|6.1.0", profile)])
+            );
+            ids.push(created.id.unwrap());
+        }
+
+        let search = |race: &str| {
+            let db = db.clone();
+            let registry = registry.clone();
+            let query = format!("name={}&race={}", family, race);
+            async move {
+                let uri: Uri = format!("/fhir/Patient?{}", query).parse().unwrap();
+                let params: SearchParams = serde_urlencoded::from_str(&query).unwrap();
+                let (_, _, Json(bundle)) = search_patients(
+                    State(db),
+                    test_config(),
+                    State(registry),
+                    test_transforms(),
+                    Query(params),
+                    ConsentFilter::default(),
+                    uri,
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                bundle
+                    .entry
+                    .into_iter()
+                    .map(|e| e.resource.id.unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(search("2106-3").await, vec![ids[0].clone()]);
+        assert_eq!(search("2106-3,2054-5").await.len(), 2);
+        // The expression selects bare codes, which carry no system
+        assert!(search("urn:oid:2.16.840.1.113883.6.238%7C2054-5")
+            .await
+            .is_empty());
+        assert_eq!(search("%7C2054-5").await, vec![ids[1].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_stored_profile_validation() {
+        let db = setup_test_db().await;
+        let profile = format!(
+            "http://example.org/fhir/StructureDefinition/{}",
+            Uuid::new_v4()
+        );
+        let definition = db
+            .create_resource(
+                "StructureDefinition",
+                json!({
+                    "resourceType": "StructureDefinition",
+                    "url": profile,
+                    "kind": "resource",
+                    "derivation": "constraint",
+                    "type": "Patient",
+                    "differential": { "element": [
+                        { "id": "Patient.gender", "path": "Patient.gender", "fixedCode": "female" },
+                        { "id": "Patient.name", "path": "Patient.name", "max": "1" }
+                    ] }
+                }),
+            )
+            .await
+            .unwrap();
+        let hooks = Arc::new(ValidationHooks::default());
+        let create = |gender: &str| {
+            create_patient(
+                State(db.clone()),
+                test_metrics(),
+                State(hooks.clone()),
+                test_transforms(),
+                Attribution::default(),
+                HeaderMap::new(),
+                Json(
+                    serde_json::from_value(json!({
+                        "resourceType": "Patient",
+                        "meta": { "profile": [profile] },
+                        "gender": gender,
+                        "name": [{ "family": "StoredProfile" }, { "family": "Alias" }]
+                    }))
+                    .unwrap(),
+                ),
+            )
+        };
+
+        // Without profile validation a stored profile only has to resolve
+        let (_, _, Json(unchecked)) = create("male").await.unwrap();
+
+        hooks.set_profile_validation(true);
+        let (status, Json(outcome)) = create("male").await.unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let issues: Vec<(&str, Vec<String>)> = outcome
+            .issue
+            .iter()
+            .map(|issue| (issue.code.as_str(), issue.location.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                ("structure", vec!["Patient.name".to_string()]),
+                ("value", vec!["Patient.gender".to_string()]),
+            ]
+        );
+
+        db.delete_patient(unchecked.id.as_deref().unwrap())
+            .await
+            .unwrap();
+        db.delete_resource("StructureDefinition", &definition.id.to_string())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_patient_match() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:match:{}", uuid::Uuid::new_v4());
+        let mut patient = create_test_patient("Okonkwo", "Adaeze", "female", "1983-03-14");
+        patient
+            .extra
+            .insert("identifier".to_string(), json!([{ "system": system, "value": "1" }]));
+        let created = db.create_patient(patient).await.unwrap();
+        let id = created.id.clone().unwrap();
+
+        let matches = |parameters: Value| {
+            patient_match_request(
+                db.clone(),
+                json!({ "resourceType": "Parameters", "parameter": parameters }),
+            )
+        };
+        let submitted = |family: &str, identifier: bool| {
+            let mut resource = json!({
+                "resourceType": "Patient",
+                "name": [{ "family": family, "given": ["Adaeze"] }],
+                "gender": "female",
+                "birthDate": "1983-03-14"
+            });
+            if identifier {
+                resource["identifier"] = json!([{ "system": system, "value": "1" }]);
+            }
+            json!({ "name": "resource", "resource": resource })
+        };
+
+        let bundle = matches(json!([submitted("Okonkwo", true)])).await.unwrap();
+        assert_eq!(bundle["type"], "searchset");
+        let first = &bundle["entry"][0];
+        assert_eq!(first["resource"]["id"], json!(id));
+        assert_eq!(first["search"]["mode"], "match");
+        assert_eq!(first["search"]["score"], json!(1.0));
+        assert_eq!(first["search"]["extension"][0]["valueCode"], "certain");
+
+        // A misspelt name without the identifier is still found, less surely
+        let bundle = matches(json!([submitted("Okonkow", false)])).await.unwrap();
+        let entry = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| entry["resource"]["id"] == json!(id))
+            .unwrap();
+        let score = entry["search"]["score"].as_f64().unwrap();
+        assert!((0.6..1.0).contains(&score), "{}", score);
+
+        let bundle = matches(json!([
+            submitted("Okonkwo", true),
+            { "name": "onlyCertainMatches", "valueBoolean": true },
+            { "name": "count", "valueInteger": 5 }
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(bundle["total"], 1);
+
+        let (status, _) = matches(json!([{ "name": "resource", "resource": {
+            "resourceType": "Patient", "gender": "female"
+        } }]))
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    async fn patient_match_request(
+        db: Arc<Database>,
+        body: Value,
+    ) -> Result<Value, (StatusCode, Json<OperationOutcome>)> {
+        let (_, _, Json(bundle)) = match_patients(
+            State(db),
+            test_config(),
+            test_transforms(),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+            Json(body),
+        )
+        .await?;
+        Ok(bundle)
+    }
+
+    #[tokio::test]
+    async fn test_patient_cohort() {
+        let db = setup_test_db().await;
+        let system = format!("urn:test:cohort:{}", uuid::Uuid::new_v4());
+        let mut ids = Vec::new();
+        for gender in ["female", "male"] {
+            let mut patient = Patient::new();
+            patient.gender = Some(gender.to_string());
+            patient.extra.insert(
+                "identifier".to_string(),
+                json!([{ "system": system, "value": gender }]),
+            );
+            ids.push(db.create_patient(patient).await.unwrap().id.unwrap());
+        }
+        let criteria = json!({ "and": [
+            { "param": "identifier", "value": format!("{}|", system) },
+            { "not": { "param": "gender", "value": "male" } }
+        ]});
+
+        let (status, _, Json(parameters)) =
+            patient_cohort(
+                State(db.clone()),
+                ConsentFilter::default(),
+                Json(json!({ "criteria": criteria })),
+            )
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parameters["resourceType"], "Parameters");
+        assert_eq!(
+            parameters["parameter"],
+            json!([
+                { "name": "count", "valueInteger": 1 },
+                { "name": "id", "valueId": ids[0] }
+            ])
+        );
+
+        let (_, _, Json(parameters)) = patient_cohort(
+            State(db.clone()),
+            ConsentFilter::default(),
+            Json(json!({ "criteria": criteria["and"][0], "result": "count" })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            parameters["parameter"],
+            json!([{ "name": "count", "valueInteger": 2 }])
+        );
+
+        let (status, Json(outcome)) = patient_cohort(
+            State(db),
+            ConsentFilter::default(),
+            Json(json!({ "criteria": { "or": [{ "param": "telecom", "value": "x" }] } })),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(outcome.issue[0].code, "invalid");
+        assert!(outcome.issue[0]
+            .diagnostics
+            .as_deref()
+            .unwrap()
+            .contains("criteria.or[0].param"));
+    }
+
+    #[tokio::test]
+    async fn test_restricted_patient_reads_need_a_reason() {
+        let db = setup_test_db().await;
+        let family = format!("Restricted{}", uuid::Uuid::new_v4().simple());
+        let mut patient = create_test_patient(&family, "Rita", "female", "1977-07-07");
+        patient.meta = Some(crate::models::Meta {
+            version_id: None,
+            last_updated: None,
+            profile: None,
+            security: Some(vec![crate::models::Coding {
+                system: Some(
+                    "http://terminology.hl7.org/CodeSystem/v3-Confidentiality".to_string(),
+                ),
+                code: Some("R".to_string()),
+                display: None,
+                extra: Map::new(),
+            }]),
+        });
+        let created = db.create_patient(patient).await.unwrap();
+        let id = created.id.clone().unwrap();
+        assert!(created.meta.unwrap().security.is_some());
+
+        let config = Arc::new(SharedConfig::new(ServerConfig {
+            access_reason: crate::access::AccessReasonConfig {
+                required: true,
+                ..Default::default()
+            },
+            ..ServerConfig::default()
+        }));
+        let read = |reason: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(reason) = reason {
+                headers.insert("X-Access-Reason", reason.parse().unwrap());
+            }
+            get_patient(
+                State(db.clone()),
+                State(config.clone()),
+                test_transforms(),
+                Path(id.clone()),
+                ConsentFilter::default(),
+                headers,
+            )
+        };
+
+        let (status, Json(outcome)) = read(None).await.unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(outcome.issue[0].code, "forbidden");
+        let (status, _, Json(patient)) = read(Some("TREAT")).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(patient.id, Some(id));
+
+        let search = |headers: HeaderMap| {
+            let params: SearchParams =
+                serde_urlencoded::from_str(&format!("name={}", family)).unwrap();
+            search_patients(
+                State(db.clone()),
+                State(config.clone()),
+                test_registry(),
+                test_transforms(),
+                Query(params),
+                ConsentFilter::default(),
+                Uri::from_static("/fhir/Patient"),
+                headers,
+            )
+        };
+        let (status, _) = search(HeaderMap::new()).await.unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,206 @@
+//! Read and search of the Provenance the server records for writes; see
+//! [`crate::provenance`]. Provenance cannot be written by clients.
+
+use super::resource::{not_found, processing_error, search_params, searchset, ErrorResponse};
+use super::{check_text_search, transform_error};
+use crate::config::SharedConfig;
+use crate::db::resource::resource_list_sql;
+use crate::db::{provenance_target_sql, Database};
+use crate::extract::Query;
+use crate::models::{Bundle, OperationOutcome};
+use crate::transform::ResponsePipeline;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::Json,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// `GET /fhir/Provenance?target=Patient/:id`: the Provenance of every
+/// version of the target, or of one version with `Patient/:id/_history/:vid`;
+/// without `target`, all of it
+pub async fn search_provenance(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Query(mut params): Query<HashMap<String, String>>,
+    uri: Uri,
+) -> Result<(StatusCode, HeaderMap, Json<Bundle<Value>>), ErrorResponse> {
+    let target = params.remove("target");
+    let (count, start, total, text) = search_params("Provenance", &params)?;
+    check_text_search(&db, &text)?;
+    let matches = match target.as_deref() {
+        Some(target) if target.split('/').count() < 2 => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error_with_location(
+                    "invalid",
+                    format!(
+                        "target must be a reference such as Patient/<id>, not '{}'",
+                        target
+                    ),
+                    "target",
+                )),
+            ))
+        }
+        Some(target) => provenance_target_sql(target, &text),
+        None => resource_list_sql("Provenance", &text),
+    };
+    let count = config.get().page_size(count);
+    searchset(
+        &db,
+        &transforms,
+        "Provenance",
+        &matches,
+        (count, start, total),
+        &uri,
+    )
+    .await
+}
+
+/// `GET /fhir/Provenance/:id`
+pub async fn get_provenance(
+    State(db): State<Arc<Database>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    match db.get_resource("Provenance", &id).await {
+        Ok(Some(stored)) => {
+            let resource = transforms
+                .apply(vec![stored.into_resource()])
+                .await
+                .map_err(transform_error)?
+                .remove(0);
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+            Ok((StatusCode::OK, headers, Json(resource)))
+        }
+        Ok(None) => Err(not_found("Provenance", &id)),
+        Err(e) => Err(processing_error("retrieve", "Provenance", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::REQUEST_ID_HEADER;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use crate::provenance::REQUEST_ID_EXTENSION;
+    use crate::state::AppState;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::Request;
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn app() -> axum::Router {
+        let state = AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        );
+        crate::routes::router(state)
+    }
+
+    async fn send(
+        app: &axum::Router,
+        method: &str,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, String, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/fhir+json");
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let request_id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            request_id,
+            serde_json::from_slice(&body).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_searchable_by_target() {
+        let app = app().await;
+        let patient = json!({ "resourceType": "Patient", "gender": "female" });
+
+        let (status, created_in, created) =
+            send(&app, "POST", "/fhir/Patient", Some(patient.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = created["id"].as_str().unwrap();
+        let (status, _, _) = send(
+            &app,
+            "PUT",
+            &format!("/fhir/Patient/{}", id),
+            Some(json!({ "resourceType": "Patient", "id": id, "gender": "male" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _, bundle) = send(
+            &app,
+            "GET",
+            &format!("/fhir/Provenance?target=Patient/{}", id),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bundle["total"], 2);
+        let mut provenance: Vec<&Value> = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| &entry["resource"])
+            .collect();
+        provenance.sort_by_key(|p| p["target"][0]["reference"].as_str().unwrap().to_string());
+        assert_eq!(
+            provenance[0]["target"][0]["reference"],
+            format!("Patient/{}/_history/1", id)
+        );
+        assert_eq!(provenance[0]["activity"]["coding"][0]["code"], "CREATE");
+        assert_eq!(provenance[0]["agent"][0]["who"]["display"], "anonymous");
+        assert_eq!(provenance[0]["extension"][0]["url"], REQUEST_ID_EXTENSION);
+        assert_eq!(provenance[0]["extension"][0]["valueString"], created_in);
+        assert_eq!(provenance[1]["activity"]["coding"][0]["code"], "UPDATE");
+
+        let provenance_id = provenance[1]["id"].as_str().unwrap();
+        let (status, _, read) = send(
+            &app,
+            "GET",
+            &format!("/fhir/Provenance/{}", provenance_id),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            read["target"][0]["reference"],
+            format!("Patient/{}/_history/2", id)
+        );
+
+        // Clients cannot write Provenance, and a target is a reference
+        let (status, _, _) = send(
+            &app,
+            "POST",
+            "/fhir/Provenance",
+            Some(json!({ "resourceType": "Provenance" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _, _) = send(&app, "GET", "/fhir/Provenance?target=Patient", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,616 @@
+//! Generic handlers for the resource types listed in `resource_types`.
+//!
+//! Resources are handled as plain JSON: the server checks `resourceType`,
+//! assigns `id` and `meta` and runs validation hooks and response transforms,
+//! but does not interpret any other element. Search supports paging and the
+//! full-text `_text` and `_content` only.
+
+use super::{check_text_search, check_validation_hooks, search_links, transform_error};
+use crate::conditional::search_etag;
+use crate::config::SharedConfig;
+use crate::consent::ConsentFilter;
+use crate::db::conformance::DuplicateCanonical;
+use crate::db::resource::resource_list_sql;
+use crate::db::{Database, PageStart, SearchSql, StoredResource, Total};
+use crate::extract::Query;
+use crate::models::{Bundle, BundleEntry, OperationOutcome};
+use crate::provenance::Attribution;
+use crate::search::TextCriteria;
+use crate::transform::ResponsePipeline;
+use crate::validation::ValidationHooks;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::Json,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub(super) type ErrorResponse = (StatusCode, Json<OperationOutcome>);
+
+fn fhir_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    headers
+}
+
+/// Reject types that are not registered in the live config
+pub(super) fn check_served(
+    config: &SharedConfig,
+    resource_type: &str,
+) -> Result<(), ErrorResponse> {
+    if config.get().serves_resource_type(resource_type) {
+        return Ok(());
+    }
+    Err((
+        StatusCode::NOT_FOUND,
+        Json(OperationOutcome::error(
+            "not-supported",
+            format!(
+                "Resource type {} is not supported by this server",
+                resource_type
+            ),
+        )),
+    ))
+}
+
+/// The body must be a JSON object of the type named in the URL
+pub(super) fn check_body(resource_type: &str, resource: &Value) -> Result<(), ErrorResponse> {
+    check_resource_type(resource_type, resource.get("resourceType"))
+}
+
+/// [`check_body`] given the body's `resourceType` member
+pub(super) fn check_resource_type(
+    resource_type: &str,
+    found: Option<&Value>,
+) -> Result<(), ErrorResponse> {
+    if found.and_then(Value::as_str) == Some(resource_type) {
+        return Ok(());
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error_with_location(
+            "invalid",
+            format!("Resource type must be '{}'", resource_type),
+            format!("{}.resourceType", resource_type),
+        )),
+    ))
+}
+
+pub(super) fn not_found(resource_type: &str, id: &str) -> ErrorResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(OperationOutcome::error_with_location(
+            "not-found",
+            format!("{} with id {} not found", resource_type, id),
+            format!("{}/{}", resource_type, id),
+        )),
+    )
+}
+
+pub(super) fn processing_error(
+    action: &str,
+    resource_type: &str,
+    e: anyhow::Error,
+) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!("Failed to {} {}: {}", action, resource_type, e),
+        )),
+    )
+}
+
+/// [`processing_error`], except that a canonical URL and version already
+/// in use is a 409
+pub(super) fn write_error(action: &str, resource_type: &str, e: anyhow::Error) -> ErrorResponse {
+    match e.downcast_ref::<DuplicateCanonical>() {
+        Some(duplicate) => (
+            StatusCode::CONFLICT,
+            Json(OperationOutcome::error_with_location(
+                "duplicate",
+                format!("Failed to {} {}: {}", action, resource_type, duplicate),
+                format!("{}.url", resource_type),
+            )),
+        ),
+        None => processing_error(action, resource_type, e),
+    }
+}
+
+pub async fn create_resource(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Path(resource_type): Path<String>,
+    attribution: Attribution,
+    Json(resource): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    check_served(&config, &resource_type)?;
+    check_body(&resource_type, &resource)?;
+    check_validation_hooks(&db, &hooks, "create", &resource).await?;
+
+    match db
+        .create_resource_by(&attribution, &resource_type, resource)
+        .await
+    {
+        Ok(created) => {
+            let mut headers = fhir_headers();
+            headers.insert(
+                "Location",
+                format!("/fhir/{}/{}", resource_type, created.id)
+                    .parse()
+                    .unwrap(),
+            );
+            Ok((StatusCode::CREATED, headers, Json(created.into_resource())))
+        }
+        Err(e) => Err(write_error("create", &resource_type, e)),
+    }
+}
+
+pub async fn get_resource(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path((resource_type, id)): Path<(String, String)>,
+    consent: ConsentFilter,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    check_served(&config, &resource_type)?;
+
+    match db.get_resource(&resource_type, &id).await {
+        Ok(Some(stored)) => {
+            consent.check_read(&db, &resource_type, &id).await?;
+            let resource = transforms
+                .apply(vec![stored.into_resource()])
+                .await
+                .map_err(transform_error)?
+                .remove(0);
+            Ok((StatusCode::OK, fhir_headers(), Json(resource)))
+        }
+        Ok(None) => Err(not_found(&resource_type, &id)),
+        Err(e) => Err(processing_error("retrieve", &resource_type, e)),
+    }
+}
+
+pub async fn update_resource(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    Path((resource_type, id)): Path<(String, String)>,
+    attribution: Attribution,
+    Json(resource): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    check_served(&config, &resource_type)?;
+    check_body(&resource_type, &resource)?;
+    if resource
+        .get("id")
+        .and_then(Value::as_str)
+        .is_some_and(|body_id| body_id != id)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invariant",
+                "Resource ID cannot be changed",
+            )),
+        ));
+    }
+    check_validation_hooks(&db, &hooks, "update", &resource).await?;
+
+    match db
+        .update_resource_by(&attribution, &resource_type, &id, resource)
+        .await
+    {
+        Ok(Some(updated)) => Ok((
+            StatusCode::OK,
+            fhir_headers(),
+            Json(updated.into_resource()),
+        )),
+        Ok(None) => Err(not_found(&resource_type, &id)),
+        Err(e) => Err(write_error("update", &resource_type, e)),
+    }
+}
+
+pub async fn delete_resource(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    Path((resource_type, id)): Path<(String, String)>,
+) -> Result<StatusCode, ErrorResponse> {
+    check_served(&config, &resource_type)?;
+
+    match db.delete_resource(&resource_type, &id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(not_found(&resource_type, &id)),
+        Err(e) => Err(processing_error("delete", &resource_type, e)),
+    }
+}
+
+/// `_count`, `_offset`, `_page_token`, `_total` and the full-text `_text`
+/// and `_content`, the only parameters of generically served types;
+/// ignoring a filter would silently return unfiltered results
+pub(super) fn search_params(
+    resource_type: &str,
+    params: &HashMap<String, String>,
+) -> Result<(Option<u32>, PageStart, Total, TextCriteria), ErrorResponse> {
+    let mut count = None;
+    let mut offset = None;
+    let mut page_token = None;
+    let mut total = Total::default();
+    let mut text = TextCriteria::default();
+    for (name, value) in params {
+        if text.add(name, value) {
+            continue;
+        }
+        let parsed = match name.as_str() {
+            "_count" => value.parse().map(|c| count = Some(c)).is_ok(),
+            "_offset" => value.parse().map(|o| offset = Some(o)).is_ok(),
+            "_page_token" => {
+                page_token = Some(value.as_str());
+                true
+            }
+            "_total" => Total::parse(value).map(|t| total = t).is_ok(),
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(OperationOutcome::error(
+                        "not-supported",
+                        format!(
+                            "Search parameter {} is not supported for {}",
+                            name, resource_type
+                        ),
+                    )),
+                ))
+            }
+        };
+        if !parsed {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(OperationOutcome::error(
+                    "invalid",
+                    format!("Invalid value '{}' for {}", value, name),
+                )),
+            ));
+        }
+    }
+    let start = PageStart::parse(offset, page_token).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid",
+                e,
+                "_page_token",
+            )),
+        )
+    })?;
+    Ok((count, start, total, text))
+}
+
+pub async fn search_resources(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(resource_type): Path<String>,
+    consent: ConsentFilter,
+    Query(params): Query<HashMap<String, String>>,
+    uri: Uri,
+) -> Result<(StatusCode, HeaderMap, Json<Bundle<Value>>), ErrorResponse> {
+    check_served(&config, &resource_type)?;
+    let (count, start, total, text) = search_params(&resource_type, &params)?;
+    check_text_search(&db, &text)?;
+    let count = config.get().page_size(count);
+    let matches = consent.search(&db, resource_list_sql(&resource_type, &text));
+    searchset(
+        &db,
+        &transforms,
+        &resource_type,
+        &matches,
+        (count, start, total),
+        &uri,
+    )
+    .await
+}
+
+/// The page of `matches` of `count` resources from `start` as a searchset
+/// Bundle
+pub(super) async fn searchset(
+    db: &Database,
+    transforms: &ResponsePipeline,
+    resource_type: &str,
+    matches: &SearchSql,
+    (count, start, total): (u32, PageStart, Total),
+    uri: &Uri,
+) -> Result<(StatusCode, HeaderMap, Json<Bundle<Value>>), ErrorResponse> {
+    match tokio::try_join!(
+        db.list_resources(matches, count, start),
+        db.count_matches(matches, total)
+    ) {
+        Ok((stored, total)) => {
+            let etag = search_etag(
+                total,
+                stored
+                    .iter()
+                    .map(|resource| (resource.id, resource.version_id)),
+            );
+            let last = stored.last().map(|resource| resource.id);
+            let resources = stored
+                .into_iter()
+                .map(StoredResource::into_resource)
+                .collect();
+            let resources = transforms.apply(resources).await.map_err(transform_error)?;
+            let entries: Vec<BundleEntry<Value>> = resources
+                .into_iter()
+                .map(|resource| BundleEntry { resource })
+                .collect();
+
+            let bundle = Bundle {
+                resource_type: "Bundle".to_string(),
+                bundle_type: "searchset".to_string(),
+                total,
+                link: Some(search_links(uri, count, start, total, last, entries.len())),
+                entry: entries,
+            };
+            let mut headers = fhir_headers();
+            headers.insert("ETag", etag);
+            Ok((StatusCode::OK, headers, Json(bundle)))
+        }
+        Err(e) => Err(processing_error("search", resource_type, e)),
+    }
+}
+
+/// The matches of a search of a generically served type, for an export
+pub(super) fn export_search(
+    db: &Database,
+    config: &SharedConfig,
+    resource_type: &str,
+    query: &str,
+) -> Result<SearchSql, ErrorResponse> {
+    check_served(config, resource_type)?;
+    let params: HashMap<String, String> = serde_urlencoded::from_str(query).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error(
+                "invalid",
+                format!("Invalid search parameters: {}", e),
+            )),
+        )
+    })?;
+    let (_, _, _, text) = search_params(resource_type, &params)?;
+    check_text_search(db, &text)?;
+    Ok(resource_list_sql(resource_type, &text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use serde_json::json;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    fn serving(types: &[&str]) -> State<Arc<SharedConfig>> {
+        State(Arc::new(SharedConfig::new(ServerConfig {
+            resource_types: types.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        })))
+    }
+
+    fn test_validation() -> State<Arc<ValidationHooks>> {
+        State(Arc::new(ValidationHooks::default()))
+    }
+
+    fn test_transforms() -> State<Arc<ResponsePipeline>> {
+        State(Arc::new(ResponsePipeline::default()))
+    }
+
+    #[tokio::test]
+    async fn test_registered_type_round_trip() {
+        let db = setup_test_db().await;
+
+        let (status, headers, Json(created)) = create_resource(
+            State(db.clone()),
+            serving(&["Condition"]),
+            test_validation(),
+            Path("Condition".to_string()),
+            Attribution::default(),
+            Json(json!({ "resourceType": "Condition", "clinicalStatus": { "text": "active" } })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(
+            headers.get("Location").unwrap(),
+            &format!("/fhir/Condition/{}", id)
+        );
+
+        let (_, _, Json(fetched)) = get_resource(
+            State(db.clone()),
+            serving(&["Condition"]),
+            test_transforms(),
+            Path(("Condition".to_string(), id.clone())),
+            ConsentFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fetched["clinicalStatus"]["text"], "active");
+
+        let status = delete_resource(
+            State(db),
+            serving(&["Condition"]),
+            Path(("Condition".to_string(), id)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_type_is_not_supported() {
+        let db = setup_test_db().await;
+
+        let err = search_resources(
+            State(db),
+            serving(&["Condition"]),
+            test_transforms(),
+            Path("Encounter".to_string()),
+            ConsentFilter::default(),
+            Query(HashMap::new()),
+            Uri::from_static("/fhir/Encounter"),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+        assert_eq!(err.1.issue[0].code, "not-supported");
+    }
+
+    #[tokio::test]
+    async fn test_body_type_must_match_path() {
+        let db = setup_test_db().await;
+
+        let err = create_resource(
+            State(db),
+            serving(&["Condition"]),
+            test_validation(),
+            Path("Condition".to_string()),
+            Attribution::default(),
+            Json(json!({ "resourceType": "Encounter" })),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_search_parameter_is_rejected() {
+        let db = setup_test_db().await;
+
+        let err = search_resources(
+            State(db),
+            serving(&["Condition"]),
+            test_transforms(),
+            Path("Condition".to_string()),
+            ConsentFilter::default(),
+            Query(HashMap::from([("code".to_string(), "x".to_string())])),
+            Uri::from_static("/fhir/Condition?code=x"),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert_eq!(err.1.issue[0].code, "not-supported");
+    }
+
+    #[tokio::test]
+    async fn test_search_by_content() {
+        let db = setup_test_db().await;
+        let word = format!("content{}", uuid::Uuid::new_v4().simple());
+
+        let (_, _, Json(created)) = create_resource(
+            State(db.clone()),
+            serving(&["Condition"]),
+            test_validation(),
+            Path("Condition".to_string()),
+            Attribution::default(),
+            Json(json!({ "resourceType": "Condition", "note": [{ "text": word }] })),
+        )
+        .await
+        .unwrap();
+
+        let search = |params: &[(&str, &str)]| {
+            let db = db.clone();
+            let params: HashMap<String, String> = params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            async move {
+                let (_, _, Json(bundle)) = search_resources(
+                    State(db),
+                    serving(&["Condition"]),
+                    test_transforms(),
+                    Path("Condition".to_string()),
+                    ConsentFilter::default(),
+                    Query(params),
+                    Uri::from_static("/fhir/Condition"),
+                )
+                .await
+                .unwrap();
+                bundle.entry
+            }
+        };
+        let found = search(&[("_content", &word)]).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].resource["id"], created["id"]);
+        // The note is content but not narrative
+        assert!(search(&[("_content", &word), ("_text", &word)])
+            .await
+            .is_empty());
+
+        let id = created["id"].as_str().unwrap().to_string();
+        delete_resource(
+            State(db),
+            serving(&["Condition"]),
+            Path(("Condition".to_string(), id)),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conformance_resources_by_canonical() {
+        let db = setup_test_db().await;
+        let base = format!("http://example.org/fhir/ValueSet/{}", uuid::Uuid::new_v4());
+        let create = |resource: Value| {
+            let db = db.clone();
+            async move {
+                create_resource(
+                    State(db),
+                    serving(&[]),
+                    test_validation(),
+                    Path("ValueSet".to_string()),
+                    Attribution::default(),
+                    Json(resource),
+                )
+                .await
+            }
+        };
+        let importing = json!({
+            "resourceType": "ValueSet",
+            "url": format!("{}-all", base),
+            "compose": { "include": [{ "valueSet": [format!("{}|1.0", base)] }] }
+        });
+
+        // Served without being listed, but references must resolve
+        let err = create(importing.clone()).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.1.issue[0].code, "not-found");
+        assert_eq!(
+            err.1.issue[0].location.as_ref().unwrap()[0],
+            "ValueSet.compose.include[0].valueSet[0]"
+        );
+
+        let included = json!({ "resourceType": "ValueSet", "url": base, "version": "1.0" });
+        let (_, _, Json(first)) = create(included.clone()).await.unwrap();
+        let (status, _, Json(second)) = create(importing).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+
+        let err = create(included).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::CONFLICT);
+        assert_eq!(err.1.issue[0].code, "duplicate");
+
+        for created in [second, first] {
+            let id = created["id"].as_str().unwrap().to_string();
+            delete_resource(
+                State(db.clone()),
+                serving(&[]),
+                Path(("ValueSet".to_string(), id)),
+            )
+            .await
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use super::{check_validation_hooks, resource};
+use crate::config::SharedConfig;
+use crate::db::Database;
+use crate::extract::Query;
+use crate::models::OperationOutcome;
+use crate::provenance::Attribution;
+use crate::search::expression::{self, SEARCHABLE_TYPES};
+use crate::search::SearchParamRegistry;
+use crate::validation::ValidationHooks;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParameterQuery {
+    base: Option<String>,
+    code: Option<String>,
+}
+
+/// GET /fhir/SearchParameter?base=Patient
+///
+/// Lists the search parameters this deployment supports, including the
+/// modifiers and comparators each one honors, as a searchset Bundle.
+pub async fn search_search_parameters(
+    State(registry): State<Arc<SearchParamRegistry>>,
+    Query(params): Query<SearchParameterQuery>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), (StatusCode, Json<OperationOutcome>)> {
+    let base_url =
+        std::env::var("FHIR_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+
+    let entries: Vec<Value> = registry
+        .list(params.base.as_deref())
+        .into_iter()
+        .filter(|p| params.code.as_deref().is_none_or(|c| p.code == c))
+        .map(|p| {
+            json!({
+                "fullUrl": format!("{}/fhir/SearchParameter/{}", base_url, p.id),
+                "resource": p.to_resource(),
+                "search": { "mode": "match" }
+            })
+        })
+        .collect();
+
+    let bundle = json!({
+        "resourceType": "Bundle",
+        "type": "searchset",
+        "total": entries.len(),
+        "entry": entries
+    });
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+
+    Ok((StatusCode::OK, headers, Json(bundle)))
+}
+
+/// POST /fhir/SearchParameter
+///
+/// Stores a SearchParameter like any conformance resource, so it can be
+/// read by id and resolved by canonical URL. The search parameters this
+/// server evaluates are the ones listed above; storing a definition does
+/// not add it to them, adding it through [`add_search_parameter`] does.
+pub async fn create_search_parameter(
+    db: State<Arc<Database>>,
+    config: State<Arc<SharedConfig>>,
+    hooks: State<Arc<ValidationHooks>>,
+    attribution: Attribution,
+    resource: Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), (StatusCode, Json<OperationOutcome>)> {
+    resource::create_resource(
+        db,
+        config,
+        hooks,
+        Path("SearchParameter".to_string()),
+        attribution,
+        resource,
+    )
+    .await
+}
+
+type ErrorResponse = (StatusCode, Json<OperationOutcome>);
+
+fn unprocessable(code: &str, message: String, location: &str) -> ErrorResponse {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(OperationOutcome::error_with_location(
+            code, message, location,
+        )),
+    )
+}
+
+/// POST /admin/search-parameters
+///
+/// Stores a SearchParameter and makes it searchable on its Patient and
+/// Observation bases: its FHIRPath expression must be in the [supported
+/// subset](crate::search::expression), and its values are extracted from
+/// every existing resource before the response (see
+/// [`crate::db::search_index`]). Codes already searchable on a base, built-in
+/// or added, are refused with 409.
+pub async fn add_search_parameter(
+    State(db): State<Arc<Database>>,
+    State(hooks): State<Arc<ValidationHooks>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    Json(resource): Json<Value>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    resource::check_body("SearchParameter", &resource)?;
+    if !db.search_index_enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "Custom search parameters are not available on this server: the fhir.resource_search_value table is missing",
+            )),
+        ));
+    }
+
+    let mut definitions = Vec::new();
+    for definition in expression::definitions(&resource) {
+        let definition = definition.map_err(|reason| {
+            unprocessable("not-supported", reason, "SearchParameter.expression")
+        })?;
+        let base = &definition.base[0];
+        if registry.get(base, &definition.code).is_some() {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(OperationOutcome::error_with_location(
+                    "duplicate",
+                    format!(
+                        "Search parameter {} is already defined for {}",
+                        definition.code, base
+                    ),
+                    "SearchParameter.code",
+                )),
+            ));
+        }
+        definitions.push(definition);
+    }
+    if definitions.is_empty() {
+        return Err(unprocessable(
+            "not-supported",
+            format!(
+                "SearchParameter.base must include one of {}",
+                SEARCHABLE_TYPES.join(", ")
+            ),
+            "SearchParameter.base",
+        ));
+    }
+    check_validation_hooks(&db, &hooks, "create", &resource).await?;
+
+    let (stored, extracted) = db
+        .add_search_parameter(resource, &definitions)
+        .await
+        .map_err(|e| resource::write_error("create", "SearchParameter", e))?;
+    for mut definition in definitions {
+        tracing::info!(
+            param = %definition.url,
+            values = extracted,
+            "added search parameter {} on {}",
+            definition.code,
+            definition.base[0]
+        );
+        definition.id = stored.id.to_string();
+        registry.register(definition.indexed());
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    headers.insert(
+        "Location",
+        format!("/fhir/SearchParameter/{}", stored.id)
+            .parse()
+            .unwrap(),
+    );
+    Ok((StatusCode::CREATED, headers, Json(stored.into_resource())))
+}
+
+/// DELETE /admin/search-parameters/:id
+///
+/// Deletes a SearchParameter added through [`add_search_parameter`] together
+/// with its extracted values, and stops searching by it.
+pub async fn remove_search_parameter(
+    State(db): State<Arc<Database>>,
+    State(registry): State<Arc<SearchParamRegistry>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ErrorResponse> {
+    if !registry.list(None).iter().any(|p| p.indexed && p.id == id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(OperationOutcome::error(
+                "not-found",
+                format!("No search parameter was added with id {}", id),
+            )),
+        ));
+    }
+    db.delete_resource("SearchParameter", &id)
+        .await
+        .map_err(|e| resource::processing_error("delete", "SearchParameter", e))?;
+    registry.remove_indexed(&id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::ConsentFilter;
+    use crate::handlers::patient::{search_patients, SearchParams};
+    use crate::models::Patient;
+    use crate::transform::ResponsePipeline;
+    use axum::http::Uri;
+    use uuid::Uuid;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    #[tokio::test]
+    async fn test_search_parameters_for_patient() {
+        let registry = Arc::new(SearchParamRegistry::with_builtins());
+        let params = SearchParameterQuery {
+            base: Some("Patient".to_string()),
+            code: None,
+        };
+
+        let (status, _, Json(bundle)) = search_search_parameters(State(registry), Query(params))
+            .await
+            .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bundle["type"], "searchset");
+        let entries = bundle["entry"].as_array().unwrap();
+        assert!(!entries.is_empty());
+        assert!(entries
+            .iter()
+            .all(|e| e["resource"]["base"][0] == "Patient"));
+    }
+
+    #[tokio::test]
+    async fn test_search_parameters_by_code() {
+        let registry = Arc::new(SearchParamRegistry::with_builtins());
+        let params = SearchParameterQuery {
+            base: Some("Patient".to_string()),
+            code: Some("name".to_string()),
+        };
+
+        let (_, _, Json(bundle)) = search_search_parameters(State(registry), Query(params))
+            .await
+            .unwrap();
+
+        assert_eq!(bundle["total"], 1);
+        assert_eq!(bundle["entry"][0]["resource"]["modifier"][0], "contains");
+    }
+
+    #[tokio::test]
+    async fn test_added_search_parameter_is_indexed_and_searched() {
+        let db = setup_test_db().await;
+        let registry = Arc::new(SearchParamRegistry::with_builtins());
+        let code = format!("nickname{}", Uuid::new_v4().simple());
+        let family = format!("Custom{}", Uuid::new_v4().simple());
+        let patient = |nickname: &str| -> Patient {
+            serde_json::from_value(json!({
+                "resourceType": "Patient",
+                "name": [{ "family": family }, { "use": "nickname", "given": [nickname] }]
+            }))
+            .unwrap()
+        };
+        let add = |resource: Value| {
+            add_search_parameter(
+                State(db.clone()),
+                State(Arc::new(ValidationHooks::default())),
+                State(registry.clone()),
+                Json(resource),
+            )
+        };
+        let search = |value: &str| {
+            let db = db.clone();
+            let registry = registry.clone();
+            let query = format!("name={}&{}={}", family, code, value);
+            async move {
+                let (_, _, Json(bundle)) = search_patients(
+                    State(db),
+                    State(Arc::new(SharedConfig::default())),
+                    State(registry),
+                    State(Arc::new(ResponsePipeline::default())),
+                    Query(serde_urlencoded::from_str::<SearchParams>(&query).unwrap()),
+                    ConsentFilter::default(),
+                    format!("/fhir/Patient?{}", query).parse::<Uri>().unwrap(),
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                let mut found: Vec<String> = bundle
+                    .entry
+                    .into_iter()
+                    .map(|e| e.resource.id.unwrap())
+                    .collect();
+                found.sort();
+                found
+            }
+        };
+
+        // Written before the parameter, so indexed when it is added
+        let bobby = db.create_patient(patient("Bobby")).await.unwrap();
+        let definition = json!({
+            "resourceType": "SearchParameter",
+            "url": format!("http://example.org/SearchParameter/{}", code),
+            "status": "active",
+            "code": code,
+            "base": ["Patient", "Practitioner"],
+            "type": "string",
+            "expression": "Patient.name.where(use = 'nickname').given"
+        });
+        let (status, headers, Json(created)) = add(definition.clone()).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(
+            headers.get("Location").unwrap(),
+            &format!("/fhir/SearchParameter/{}", id)
+        );
+        let param = registry.get("Patient", &code).unwrap();
+        assert!(param.indexed);
+        assert_eq!(param.id, id);
+
+        // Written after: indexed on create and on update
+        let liz = db.create_patient(patient("Liz")).await.unwrap();
+        assert_eq!(search("bob").await, vec![bobby.id.clone().unwrap()]);
+        assert_eq!(search("LI").await, vec![liz.id.clone().unwrap()]);
+        let liz_id = liz.id.clone().unwrap();
+        db.update_patient(&liz_id, patient("Beth")).await.unwrap();
+        assert!(search("li").await.is_empty());
+        let mut both = vec![bobby.id.unwrap(), liz_id];
+        both.sort();
+        assert_eq!(search("bob,beth").await, both);
+
+        let mut again = definition.clone();
+        again["url"] = json!(format!("http://example.org/SearchParameter/{}-2", code));
+        let (status, Json(outcome)) = add(again).await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(outcome.issue[0].code, "duplicate");
+        let mut unsupported = definition.clone();
+        unsupported["expression"] = json!("Patient.name.exists()");
+        let (status, _) = add(unsupported).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let mut elsewhere = definition;
+        elsewhere["base"] = json!(["Practitioner"]);
+        let (status, Json(outcome)) = add(elsewhere).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            outcome.issue[0].location,
+            Some(vec!["SearchParameter.base".to_string()])
+        );
+
+        let remove = |id: String| {
+            remove_search_parameter(State(db.clone()), State(registry.clone()), Path(id))
+        };
+        assert_eq!(remove(id.clone()).await.unwrap(), StatusCode::NO_CONTENT);
+        assert!(registry.get("Patient", &code).is_none());
+        assert!(db
+            .get_resource("SearchParameter", &id)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(remove(id).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        // Built-ins cannot be removed
+        let (status, _) = remove("Patient-name".to_string()).await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,446 @@
+//! `POST /fhir/Patient/:id/$share` and `GET /fhir/_share/:token`; see
+//! [`crate::sharing`] for what a link grants.
+
+use super::history::base_url;
+use super::patient::missing_patient;
+use super::resource::ErrorResponse;
+use super::transform_error;
+use crate::access;
+use crate::config::{ServerConfig, SharedConfig};
+use crate::consent::ConsentFilter;
+use crate::db::observation::observation_search_sql;
+use crate::db::{Database, ObservationSearch};
+use crate::elements::Elements;
+use crate::extract::Query;
+use crate::models::{OperationOutcome, Patient};
+use crate::sharing::{InvalidShare, ShareGrant, ShareScope};
+use crate::transform::ResponsePipeline;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use chrono::Duration;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Elements of a Patient in its `summary`, those FHIR marks as summary
+const SUMMARY_ELEMENTS: &[&str] = &[
+    "identifier",
+    "active",
+    "name",
+    "telecom",
+    "gender",
+    "birthDate",
+    "deceasedBoolean",
+    "deceasedDateTime",
+    "address",
+    "managingOrganization",
+    "link",
+];
+
+/// Observations read per query for a `record`
+const RECORD_BATCH: u32 = 100;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ShareParams {
+    /// `summary` (default) or `record`
+    scope: Option<String>,
+    /// Lifetime of the link; `[sharing] default_hours` if not given
+    hours: Option<u32>,
+}
+
+fn not_enabled() -> ErrorResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(OperationOutcome::error(
+            "not-supported",
+            "Sharing links are not enabled on this server: [sharing] secret is not set",
+        )),
+    )
+}
+
+fn read_failed(e: anyhow::Error) -> ErrorResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OperationOutcome::error(
+            "processing",
+            format!("Failed to read the shared patient: {}", e),
+        )),
+    )
+}
+
+fn fhir_json() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/fhir+json".parse().unwrap());
+    headers
+}
+
+/// POST /fhir/Patient/:id/$share?scope=record&hours=48
+///
+/// The link grants what a read of the patient would, so restricted
+/// patients need an `X-Access-Reason` as for a read, and patients a Consent
+/// withholds from the requester or from the link's readers are refused.
+pub async fn share_patient(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    Path(id): Path<String>,
+    Query(params): Query<ShareParams>,
+    consent: ConsentFilter,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    let config = config.get();
+    let settings = &config.sharing;
+    if !settings.enabled() {
+        return Err(not_enabled());
+    }
+    let invalid = |message: String, location: &str| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OperationOutcome::error_with_location(
+                "invalid", message, location,
+            )),
+        )
+    };
+    let scope = match params.scope.as_deref() {
+        None => ShareScope::Summary,
+        Some(scope) => ShareScope::parse(scope).ok_or_else(|| {
+            invalid(
+                format!("Unknown scope '{}': expected summary or record", scope),
+                "scope",
+            )
+        })?,
+    };
+    let hours = params.hours.unwrap_or(settings.default_hours);
+    if hours == 0 || hours > settings.max_hours {
+        return Err(invalid(
+            format!(
+                "hours must be between 1 and {}, the longest this server shares for",
+                settings.max_hours
+            ),
+            "hours",
+        ));
+    }
+
+    let patient = match db.get_patient(&id).await {
+        Ok(Some(patient)) => patient,
+        Ok(None) => return Err(missing_patient(&db, &id).await),
+        Err(e) => return Err(read_failed(e)),
+    };
+    consent.check_read(&db, "Patient", &id).await?;
+    readers(&config).check_read(&db, "Patient", &id).await?;
+    access::check_patient_reads(&config, &request_headers, [&patient])?;
+
+    let grant = ShareGrant {
+        patient_id: Uuid::parse_str(&id).map_err(|e| read_failed(e.into()))?,
+        scope,
+        expires_at: db.now() + Duration::hours(i64::from(hours)),
+    };
+    let url = format!(
+        "{}/fhir/_share/{}",
+        base_url(),
+        grant.token(&settings.secret)
+    );
+    tracing::info!(
+        target: "fhir_server::audit",
+        patient = %id,
+        scope = scope.as_str(),
+        expires = %grant.expires_at,
+        "sharing link created"
+    );
+
+    Ok((
+        StatusCode::OK,
+        fhir_json(),
+        Json(json!({
+            "resourceType": "Parameters",
+            "parameter": [
+                { "name": "url", "valueUrl": url },
+                { "name": "scope", "valueCode": scope.as_str() },
+                { "name": "expires", "valueInstant": grant.expires_at },
+            ]
+        })),
+    ))
+}
+
+/// Whom a link is read on behalf of: nobody in particular, so only the
+/// Consents that deny everyone withhold from its readers
+fn readers(config: &ServerConfig) -> ConsentFilter {
+    ConsentFilter {
+        actor: None,
+        enforced: config.consent.enforced,
+    }
+}
+
+/// GET /fhir/_share/:token
+///
+/// The summary is the Patient itself, reduced and tagged `SUBSETTED`; the
+/// record a `collection` Bundle of the Patient and its Observations. The
+/// Consents are checked again on each read, so a patient who opts out
+/// after a link was made is not shared through it.
+pub async fn read_shared(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(transforms): State<Arc<ResponsePipeline>>,
+    Path(token): Path<String>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), ErrorResponse> {
+    let config = config.get();
+    let settings = &config.sharing;
+    if !settings.enabled() {
+        return Err(not_enabled());
+    }
+    let grant = ShareGrant::verify(&token, &settings.secret, db.now()).map_err(|e| {
+        tracing::warn!(target: "fhir_server::audit", reason = %e, "sharing link refused");
+        let code = match e {
+            InvalidShare::Forged => "forbidden",
+            InvalidShare::Expired(_) => "expired",
+        };
+        (
+            StatusCode::FORBIDDEN,
+            Json(OperationOutcome::error(code, e.to_string())),
+        )
+    })?;
+
+    let id = grant.patient_id.to_string();
+    let patient = match db.get_patient(&id).await {
+        Ok(Some(patient)) => patient,
+        Ok(None) => return Err(missing_patient(&db, &id).await),
+        Err(e) => return Err(read_failed(e)),
+    };
+    let readers = readers(&config);
+    readers.check_read(&db, "Patient", &id).await?;
+    tracing::info!(
+        target: "fhir_server::audit",
+        patient = %id,
+        scope = grant.scope.as_str(),
+        expires = %grant.expires_at,
+        "shared patient read"
+    );
+
+    let body = match grant.scope {
+        ShareScope::Summary => summary(&transforms, patient).await?,
+        ShareScope::Record => record(&db, &transforms, &readers, patient).await?,
+    };
+    Ok((StatusCode::OK, fhir_json(), Json(body)))
+}
+
+async fn summary(transforms: &ResponsePipeline, patient: Patient) -> Result<Value, ErrorResponse> {
+    let patient = serde_json::to_value(patient).map_err(|e| read_failed(e.into()))?;
+    let mut patient = transforms
+        .apply(vec![patient])
+        .await
+        .map_err(transform_error)?
+        .remove(0);
+    Elements::parse(SUMMARY_ELEMENTS.iter().copied())
+        .expect("summary elements are top-level names")
+        .project(&mut patient);
+    Ok(patient)
+}
+
+async fn record(
+    db: &Database,
+    transforms: &ResponsePipeline,
+    readers: &ConsentFilter,
+    patient: Patient,
+) -> Result<Value, ErrorResponse> {
+    let search = readers.search(
+        db,
+        observation_search_sql(&ObservationSearch {
+            subject: vec![format!(
+                "Patient/{}",
+                patient.id.as_deref().unwrap_or_default()
+            )],
+            ..ObservationSearch::default()
+        }),
+    );
+    let mut resources = vec![serde_json::to_value(patient).map_err(|e| read_failed(e.into()))?];
+    let mut after = None;
+    loop {
+        let batch = db
+            .search_after(&search, after, RECORD_BATCH)
+            .await
+            .map_err(read_failed)?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = Some(last.id);
+        resources.extend(batch.into_iter().map(|stored| stored.into_resource()));
+    }
+
+    let base = base_url();
+    let entries: Vec<Value> = transforms
+        .apply(resources)
+        .await
+        .map_err(transform_error)?
+        .into_iter()
+        .map(|resource| {
+            let full_url = format!(
+                "{}/fhir/{}/{}",
+                base,
+                resource["resourceType"].as_str().unwrap_or_default(),
+                resource["id"].as_str().unwrap_or_default()
+            );
+            json!({ "fullUrl": full_url, "resource": resource })
+        })
+        .collect();
+    Ok(json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "total": entries.len(),
+        "entry": entries,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CodeableConcept, Coding, Observation, Reference};
+    use crate::sharing::SharingConfig;
+    use serde_json::Map;
+
+    async fn setup_test_db() -> Arc<Database> {
+        Arc::new(crate::db::tests::setup_test_db().await)
+    }
+
+    fn sharing_config() -> Arc<SharedConfig> {
+        Arc::new(SharedConfig::new(ServerConfig {
+            sharing: SharingConfig {
+                secret: "0123456789abcdef0123456789abcdef".to_string(),
+                ..SharingConfig::default()
+            },
+            ..ServerConfig::default()
+        }))
+    }
+
+    async fn share(
+        db: &Arc<Database>,
+        config: &Arc<SharedConfig>,
+        id: &str,
+        params: ShareParams,
+    ) -> Result<String, ErrorResponse> {
+        let (_, _, Json(parameters)) = share_patient(
+            State(db.clone()),
+            State(config.clone()),
+            Path(id.to_string()),
+            Query(params),
+            ConsentFilter::default(),
+            HeaderMap::new(),
+        )
+        .await?;
+        let url = parameters["parameter"][0]["valueUrl"].as_str().unwrap();
+        Ok(url.rsplit('/').next().unwrap().to_string())
+    }
+
+    async fn read(
+        db: &Arc<Database>,
+        config: &Arc<SharedConfig>,
+        token: String,
+    ) -> Result<Value, ErrorResponse> {
+        read_shared(
+            State(db.clone()),
+            State(config.clone()),
+            State(Arc::new(ResponsePipeline::default())),
+            Path(token),
+        )
+        .await
+        .map(|(_, _, Json(body))| body)
+    }
+
+    #[tokio::test]
+    async fn test_share_summary_and_record() {
+        let db = setup_test_db().await;
+        let config = sharing_config();
+        let mut patient: Patient = serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "name": [{ "family": "Shared" }],
+            "gender": "female",
+            "maritalStatus": { "text": "married" }
+        }))
+        .unwrap();
+        patient.birth_date = Some("1960-06-06".to_string());
+        let id = db.create_patient(patient).await.unwrap().id.unwrap();
+        let mut observation = Observation::new(
+            "final",
+            CodeableConcept {
+                coding: Some(vec![Coding {
+                    system: Some("http://loinc.org".to_string()),
+                    code: Some("8867-4".to_string()),
+                    display: None,
+                    extra: Map::new(),
+                }]),
+                text: None,
+                extra: Map::new(),
+            },
+        );
+        observation.subject = Some(Reference {
+            reference: Some(format!("Patient/{}", id)),
+            display: None,
+            extra: Map::new(),
+        });
+        let observation = db.create_observation(observation).await.unwrap();
+
+        let token = share(&db, &config, &id, ShareParams::default())
+            .await
+            .unwrap();
+        let summary = read(&db, &config, token.clone()).await.unwrap();
+        assert_eq!(summary["resourceType"], "Patient");
+        assert_eq!(summary["birthDate"], "1960-06-06");
+        assert!(summary.get("maritalStatus").is_none());
+        assert_eq!(summary["meta"]["tag"][0]["code"], "SUBSETTED");
+
+        let params = ShareParams {
+            scope: Some("record".to_string()),
+            hours: Some(1),
+        };
+        let token = share(&db, &config, &id, params).await.unwrap();
+        let record = read(&db, &config, token.clone()).await.unwrap();
+        assert_eq!(record["type"], "collection");
+        assert_eq!(record["total"], 2);
+        assert_eq!(
+            record["entry"][0]["resource"]["maritalStatus"]["text"],
+            "married"
+        );
+        assert_eq!(
+            record["entry"][1]["resource"]["id"],
+            observation.id.clone().unwrap()
+        );
+
+        // Tampered links and bad requests are refused
+        let (status, Json(outcome)) = read(&db, &config, format!("{}0", token)).await.unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(outcome.issue[0].code, "forbidden");
+        let params = ShareParams {
+            scope: Some("everything".to_string()),
+            hours: None,
+        };
+        let (status, _) = share(&db, &config, &id, params).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let params = ShareParams {
+            scope: None,
+            hours: Some(100_000),
+        };
+        let (status, _) = share(&db, &config, &id, params).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = share(
+            &db,
+            &config,
+            &Uuid::new_v4().to_string(),
+            ShareParams::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let disabled = Arc::new(SharedConfig::default());
+        let (status, _) = share(&db, &disabled, &id, ShareParams::default())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+
+        db.delete_observation(observation.id.as_deref().unwrap())
+            .await
+            .unwrap();
+        db.purge_patient(&id).await.unwrap();
+    }
+}
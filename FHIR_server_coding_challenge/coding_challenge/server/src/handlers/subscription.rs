@@ -0,0 +1,529 @@
+//! `GET /fhir/subscriptions/:id`: the WebSocket channel of a Subscription,
+//! for browser apps that cannot offer a callback URL.
+//!
+//! The Subscription is stored like any generically served resource (list
+//! `Subscription` in `resource_types`), `active`, with `channel.type`
+//! `websocket`. Once connected the client is sent `bound <id>`, and then
+//! `ping <id>` whenever resources of the type its `criteria` names were
+//! written; it then searches for what changed. Search parameters in
+//! `criteria` are not evaluated, so a ping may be for a change the client
+//! does not care about. `bind <id>` from the client is answered with
+//! `bound <id>` again, as R4's websocket channel has it. The channel follows
+//! the change feed (see [`super::changes`]), so it needs
+//! `fhir.change_outbox`, and closes when the Subscription is deleted or no
+//! longer active.
+//!
+//! The feed is read when a change notification on `fhir_changes` (migration
+//! `023_change_notify.sql`, see [`crate::events`]) announces a write of the
+//! type or of the Subscription, and every [`CATCH_UP_INTERVAL`] for the
+//! writes whose transactions committed after a newer one's. Without
+//! notifications it is read every [`POLL_INTERVAL`].
+
+use super::resource::{check_served, not_found, processing_error, ErrorResponse};
+use crate::config::SharedConfig;
+use crate::db::{ChangeCursor, Database};
+use crate::events::{ChangeEvent, ChangeEvents};
+use crate::metrics::Metrics;
+use crate::models::OperationOutcome;
+use crate::websocket::{self, Message, MessageReader};
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    response::{Json, Response},
+};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// How often a channel looks for new changes without notifications
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a channel woken by notifications reads the feed regardless
+pub const CATCH_UP_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Changes read per poll
+const POLL_BATCH: u32 = 500;
+
+/// The resource type whose writes ping the channel of `subscription`, or
+/// why it has no channel
+fn channel_type(subscription: &Value) -> Result<String, String> {
+    let status = subscription["status"].as_str().unwrap_or_default();
+    if status != "active" {
+        return Err(format!("the Subscription is {}, not active", status));
+    }
+    let channel = subscription["channel"]["type"].as_str().unwrap_or_default();
+    if channel != "websocket" {
+        return Err(format!(
+            "its channel.type is {:?}; only websocket channels are served",
+            channel
+        ));
+    }
+    let criteria = subscription["criteria"].as_str().unwrap_or_default();
+    let resource_type = criteria.split('?').next().unwrap_or_default();
+    if !crate::models::is_resource_type(resource_type) {
+        return Err(format!(
+            "its criteria {:?} does not start with a resource type",
+            criteria
+        ));
+    }
+    Ok(resource_type.to_string())
+}
+
+/// Subscription `id`, if it still has a channel pinged for writes of
+/// `resource_type`
+async fn still_bound(db: &Database, id: &str, resource_type: &str) -> bool {
+    match db.get_resource("Subscription", id).await {
+        Ok(Some(stored)) => channel_type(&stored.into_resource()).as_deref() == Ok(resource_type),
+        Ok(None) => false,
+        Err(e) => {
+            // The channel stays open; the next change to the Subscription
+            // is checked again
+            tracing::warn!("Failed to re-read Subscription/{}: {}", id, e);
+            true
+        }
+    }
+}
+
+/// Open the WebSocket channel of Subscription `id`
+pub async fn subscription_channel(
+    State(db): State<Arc<Database>>,
+    State(config): State<Arc<SharedConfig>>,
+    State(metrics): State<Arc<Metrics>>,
+    State(events): State<Arc<ChangeEvents>>,
+    Path(id): Path<String>,
+    mut request: Request,
+) -> Result<Response, ErrorResponse> {
+    if !db.changes_enabled() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                "Subscription channels are not available on this server: the fhir.change_outbox table is missing",
+            )),
+        ));
+    }
+    check_served(&config, "Subscription")?;
+    if Uuid::parse_str(&id).is_err() {
+        return Err(not_found("Subscription", &id));
+    }
+    let subscription = match db.get_resource("Subscription", &id).await {
+        Ok(Some(stored)) => stored.into_resource(),
+        Ok(None) => return Err(not_found("Subscription", &id)),
+        Err(e) => return Err(processing_error("retrieve", "Subscription", e)),
+    };
+    let resource_type = channel_type(&subscription).map_err(|reason| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(OperationOutcome::error_with_location(
+                "business-rule",
+                format!("Subscription/{} has no WebSocket channel: {}", id, reason),
+                format!("Subscription/{}", id),
+            )),
+        )
+    })?;
+
+    let (Some(accept), Some(on_upgrade)) = (
+        websocket::handshake_accept(request.headers()),
+        request.extensions_mut().remove::<OnUpgrade>(),
+    ) else {
+        return Err((
+            StatusCode::UPGRADE_REQUIRED,
+            Json(OperationOutcome::error(
+                "not-supported",
+                format!(
+                    "Connect to Subscription/{}'s channel with a WebSocket (ws:// or wss://)",
+                    id
+                ),
+            )),
+        ));
+    };
+    // Writes committed from here on are pinged
+    let cursor = db
+        .current_change_cursor()
+        .await
+        .map_err(|e| processing_error("read the change feed for", "Subscription", e))?;
+
+    let notifications = if db.events_enabled() {
+        match events.subscribe(&db).await {
+            Ok(receiver) => Some(receiver),
+            Err(e) => {
+                tracing::warn!(
+                    "Subscription/{} channel polls; failed to listen for changes: {}",
+                    id,
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    tracing::info!(subscription = %id, resource_type = %resource_type, "subscription channel opened");
+    let channel = Uuid::new_v4();
+    metrics.subscription_channel_at(channel, cursor);
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let connection = TokioIo::new(upgraded);
+                run_channel(
+                    &db,
+                    &metrics,
+                    channel,
+                    &id,
+                    &resource_type,
+                    cursor,
+                    notifications,
+                    connection,
+                )
+                .await
+            }
+            Err(e) => tracing::warn!("Subscription/{} channel upgrade failed: {}", id, e),
+        }
+        metrics.subscription_channel_closed(channel);
+        tracing::info!(subscription = %id, "subscription channel closed");
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .expect("valid handshake response"))
+}
+
+/// Whether `event` may announce a change the channel of Subscription `id`
+/// for writes of `resource_type` has to read
+fn wakes(event: &Result<ChangeEvent, RecvError>, id: &str, resource_type: &str) -> bool {
+    match event {
+        Ok(ChangeEvent::Change(change)) => {
+            change.resource_type == resource_type
+                || (change.resource_type == "Subscription" && change.id.to_string() == id)
+        }
+        Ok(ChangeEvent::Gap) | Err(_) => true,
+    }
+}
+
+/// The next event of `notifications`; never without them
+async fn notified(
+    notifications: &mut Option<broadcast::Receiver<ChangeEvent>>,
+) -> Result<ChangeEvent, RecvError> {
+    match notifications {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Ping the client on `connection` for writes of `resource_type` after
+/// `cursor` until either side closes the channel, reporting how far it has
+/// read as `channel` to `metrics`. The feed is read when `notifications`
+/// announce a change, if there are any, and periodically.
+#[allow(clippy::too_many_arguments)]
+async fn run_channel<S>(
+    db: &Database,
+    metrics: &Metrics,
+    channel: Uuid,
+    id: &str,
+    resource_type: &str,
+    mut cursor: ChangeCursor,
+    mut notifications: Option<broadcast::Receiver<ChangeEvent>>,
+    connection: S,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(connection);
+    // Reads are not cancel safe, so they get a task of their own
+    let (messages, mut received) = mpsc::channel(8);
+    let reading = tokio::spawn(async move {
+        let mut reader = MessageReader::new(reader);
+        loop {
+            let message = match reader.next().await {
+                Ok(Some(message)) => Ok(message),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(e),
+                Ok(None) | Err(_) => break,
+            };
+            let failed = message.is_err();
+            if messages.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut poll = tokio::time::interval(if notifications.is_some() {
+        CATCH_UP_INTERVAL
+    } else {
+        POLL_INTERVAL
+    });
+    let mut reply = Some(Message::Text(format!("bound {}", id)));
+    loop {
+        if let Some(message) = reply.take() {
+            let closing = matches!(message, Message::Close(_));
+            if websocket::write_message(&mut writer, &message)
+                .await
+                .is_err()
+                || closing
+            {
+                break;
+            }
+        }
+        let read = tokio::select! {
+            message = received.recv() => {
+                reply = match message {
+                    Some(Ok(Message::Ping(data))) => Some(Message::Pong(data)),
+                    Some(Ok(Message::Text(command))) if command.trim() == format!("bind {}", id) => {
+                        Some(Message::Text(format!("bound {}", id)))
+                    }
+                    Some(Ok(Message::Close(_))) | None => Some(Message::Close(None)),
+                    Some(Ok(_)) => None,
+                    // Section 7.1.7: fail the connection
+                    Some(Err(e)) => Some(Message::Close(Some((websocket::PROTOCOL_ERROR, e.to_string())))),
+                };
+                false
+            }
+            _ = poll.tick() => true,
+            event = notified(&mut notifications) => {
+                if matches!(event, Err(RecvError::Closed)) {
+                    notifications = None;
+                    poll = tokio::time::interval(POLL_INTERVAL);
+                }
+                wakes(&event, id, resource_type)
+            }
+        };
+        if !read {
+            continue;
+        }
+
+        let changes = match db.changes_after(cursor, POLL_BATCH).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                tracing::warn!("Subscription/{} channel failed to read changes: {}", id, e);
+                continue;
+            }
+        };
+        let Some(last) = changes.last() else {
+            continue;
+        };
+        cursor = last.cursor;
+        metrics.subscription_channel_at(channel, cursor);
+        let unbound = changes
+            .iter()
+            .any(|change| change.resource_type == "Subscription" && change.id.to_string() == id)
+            && !still_bound(db, id, resource_type).await;
+        reply = if unbound {
+            Some(Message::Close(Some((
+                1000,
+                format!("Subscription/{} was deleted or changed", id),
+            ))))
+        } else if changes
+            .iter()
+            .any(|change| change.resource_type == resource_type)
+        {
+            Some(Message::Text(format!("ping {}", id)))
+        } else {
+            None
+        };
+    }
+    reading.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::body::to_bytes;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_channel_type() {
+        let subscription = json!({
+            "resourceType": "Subscription",
+            "status": "active",
+            "criteria": "Observation?code=http://loinc.org|8867-4",
+            "channel": { "type": "websocket" }
+        });
+        assert_eq!(channel_type(&subscription).unwrap(), "Observation");
+        for (element, value) in [
+            ("status", json!("off")),
+            ("criteria", json!("observations")),
+            ("channel", json!({ "type": "rest-hook" })),
+        ] {
+            let mut other = subscription.clone();
+            other[element] = value;
+            assert!(channel_type(&other).is_err(), "{}", element);
+        }
+    }
+
+    #[test]
+    fn test_notifications_wake_for_the_type_and_the_subscription() {
+        let id = Uuid::new_v4();
+        let change = |resource_type: &str, changed: Uuid| {
+            Ok(ChangeEvent::Change(crate::db::Change {
+                resource_type: resource_type.to_string(),
+                id: changed,
+                version_id: 1,
+                op: "update".to_string(),
+                timestamp: chrono::Utc::now(),
+                cursor: ChangeCursor::default(),
+            }))
+        };
+        let id_text = id.to_string();
+        assert!(wakes(
+            &change("Observation", Uuid::new_v4()),
+            &id_text,
+            "Observation"
+        ));
+        assert!(wakes(&change("Subscription", id), &id_text, "Observation"));
+        assert!(!wakes(
+            &change("Subscription", Uuid::new_v4()),
+            &id_text,
+            "Observation"
+        ));
+        assert!(!wakes(
+            &change("Patient", Uuid::new_v4()),
+            &id_text,
+            "Observation"
+        ));
+        assert!(wakes(&Ok(ChangeEvent::Gap), &id_text, "Observation"));
+        assert!(wakes(&Err(RecvError::Lagged(3)), &id_text, "Observation"));
+    }
+
+    /// A text frame from the client, masked as clients must
+    fn client_text(text: &str) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | text.len() as u8];
+        frame.extend(mask);
+        frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn next(messages: &mut MessageReader<tokio::net::tcp::OwnedReadHalf>) -> Option<Message> {
+        tokio::time::timeout(Duration::from_secs(10), messages.next())
+            .await
+            .expect("no message in time")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_channel_pings_for_writes() {
+        let config = ServerConfig {
+            resource_types: vec!["Subscription".to_string()],
+            ..Default::default()
+        };
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config)),
+        ));
+        let send = |method: &str, uri: &str, body: Value| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/fhir+json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let mut subscription = json!({
+            "resourceType": "Subscription",
+            "status": "active",
+            "reason": "Patient list",
+            "criteria": "Patient",
+            "channel": { "type": "websocket" }
+        });
+        let created = send("POST", "/fhir/Subscription", subscription.clone())
+            .await
+            .unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let created: Value =
+            serde_json::from_slice(&to_bytes(created.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        let id = created["id"].as_str().unwrap().to_string();
+        let channel = format!("/fhir/subscriptions/{}", id);
+
+        // Without the handshake there is no channel
+        let response = send("GET", &channel, Value::Null).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        let response = send("GET", "/fhir/subscriptions/nope", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = app.clone();
+        tokio::spawn(async move { axum::serve(listener, server).await });
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                     Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                    channel, address
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap().to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 101"), "{}", response);
+        assert!(response.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+
+        let (reader, mut writer) = stream.into_split();
+        let mut messages = MessageReader::from_server(reader);
+        assert_eq!(
+            next(&mut messages).await,
+            Some(Message::Text(format!("bound {}", id)))
+        );
+        writer
+            .write_all(&client_text(&format!("bind {}", id)))
+            .await
+            .unwrap();
+        assert_eq!(
+            next(&mut messages).await,
+            Some(Message::Text(format!("bound {}", id)))
+        );
+
+        let patient = json!({ "resourceType": "Patient", "gender": "other" });
+        let response = send("POST", "/fhir/Patient", patient).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            next(&mut messages).await,
+            Some(Message::Text(format!("ping {}", id)))
+        );
+
+        // Switching the Subscription off closes its channel
+        subscription["id"] = json!(id);
+        subscription["status"] = json!("off");
+        let response = send("PUT", &format!("/fhir/Subscription/{}", id), subscription)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Patients written by other tests may still be pinged first
+        let mut message = next(&mut messages).await;
+        while message == Some(Message::Text(format!("ping {}", id))) {
+            message = next(&mut messages).await;
+        }
+        assert!(matches!(message, Some(Message::Close(Some((1000, _))))));
+
+        let response = send("DELETE", &format!("/fhir/Subscription/{}", id), Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,624 @@
+//! Background jobs of the FHIR asynchronous request pattern.
+//!
+//! A request sent with `Prefer: respond-async` is answered with `202
+//! Accepted` and a `Content-Location` of `/fhir/_jobs/:id` while its work
+//! runs on a tokio task; polling that URL reports progress until the job
+//! completes or fails, and `DELETE` cancels it and removes its files. Jobs
+//! are held in memory only, so their status is forgotten on restart.
+//!
+//! A job may instead notify a [`JobCallback`] URL when it finishes, with a
+//! JSON summary signed by HMAC-SHA256 under `[job_callbacks] secret`.
+
+use crate::clock::{Clock, SystemClock};
+use crate::metrics::{Histogram, HistogramSnapshot, JOB_DURATION_BUCKETS};
+use crate::sharing::{self, MIN_SECRET_LEN};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+/// A file written by a completed job
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobOutput {
+    pub resource_type: String,
+    pub path: PathBuf,
+    /// Number of resources in the file
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    InProgress,
+    Completed {
+        finished_at: DateTime<Utc>,
+        outputs: Vec<JobOutput>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// The request that started the job, e.g. `GET /fhir/Patient?gender=female`
+    pub request: String,
+    pub started_at: DateTime<Utc>,
+    /// Last progress reported by the job, sent as `X-Progress` while it runs
+    pub progress: Option<String>,
+    pub state: JobState,
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    abort: Option<AbortHandle>,
+    /// Files to remove when the job is deleted
+    files: Vec<PathBuf>,
+}
+
+/// Every job started since the server came up and not deleted since
+#[derive(Debug)]
+pub struct Jobs {
+    entries: Mutex<HashMap<Uuid, Entry>>,
+    clock: Arc<dyn Clock>,
+    /// Seconds from start to completion or failure of every finished job
+    durations: Mutex<Histogram>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl Jobs {
+    /// Jobs whose start and end are stamped by `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            entries: Mutex::default(),
+            clock,
+            durations: Mutex::new(Histogram::new(JOB_DURATION_BUCKETS)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `work` in the background as a new job and return its id;
+    /// `callback` is notified once it has completed or failed
+    pub fn start<F, Fut>(
+        self: &Arc<Self>,
+        request: impl Into<String>,
+        callback: Option<JobCallback>,
+        work: F,
+    ) -> Uuid
+    where
+        F: FnOnce(JobHandle) -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<JobOutput>>> + Send + 'static,
+    {
+        let id = Uuid::new_v4();
+        let started_at = self.clock.now();
+        self.lock().insert(
+            id,
+            Entry {
+                job: Job {
+                    request: request.into(),
+                    started_at,
+                    progress: None,
+                    state: JobState::InProgress,
+                },
+                abort: None,
+                files: Vec::new(),
+            },
+        );
+
+        let work = work(JobHandle {
+            id,
+            jobs: self.clone(),
+        });
+        let jobs = self.clone();
+        let task = tokio::spawn(async move {
+            let state = match work.await {
+                Ok(outputs) => JobState::Completed {
+                    finished_at: jobs.clock.now(),
+                    outputs,
+                },
+                Err(e) => {
+                    tracing::warn!(job = %id, "Job failed: {:#}", e);
+                    JobState::Failed {
+                        error: format!("{:#}", e),
+                    }
+                }
+            };
+            jobs.observe_duration(jobs.clock.now() - started_at);
+            jobs.finish(id, state);
+            if let (Some(callback), Some(job)) = (callback, jobs.get(&id)) {
+                callback.notify(id, &job, jobs.clock.as_ref()).await;
+            }
+        });
+
+        if let Some(entry) = self.lock().get_mut(&id) {
+            entry.abort = Some(task.abort_handle());
+        }
+        id
+    }
+
+    fn observe_duration(&self, duration: chrono::Duration) {
+        let seconds = duration.num_milliseconds().max(0) as f64 / 1000.0;
+        self.durations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .observe(seconds);
+    }
+
+    /// Durations of the jobs finished since the server came up, for `/metrics`
+    pub fn duration_histogram(&self) -> HistogramSnapshot {
+        self.durations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .snapshot()
+    }
+
+    fn finish(&self, id: Uuid, state: JobState) {
+        let mut entries = self.lock();
+        match entries.get_mut(&id) {
+            Some(entry) => entry.job.state = state,
+            // Deleted while it ran; files created after that were never tracked
+            None => {
+                if let JobState::Completed { outputs, .. } = state {
+                    drop(entries);
+                    for output in outputs {
+                        remove_file(&output.path);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Job> {
+        self.lock().get(id).map(|entry| entry.job.clone())
+    }
+
+    /// Cancel the job if it is still running and remove its files; `false`
+    /// if there is no such job
+    pub fn remove(&self, id: &Uuid) -> bool {
+        let Some(entry) = self.lock().remove(id) else {
+            return false;
+        };
+        if let Some(abort) = entry.abort {
+            abort.abort();
+        }
+        for file in &entry.files {
+            remove_file(file);
+        }
+        true
+    }
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!("Failed to remove job file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// What a running job uses to report on itself
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    id: Uuid,
+    jobs: Arc<Jobs>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn report(&self, progress: impl Into<String>) {
+        if let Some(entry) = self.jobs.lock().get_mut(&self.id) {
+            entry.job.progress = Some(progress.into());
+        }
+    }
+
+    /// Remove `path` along with the job; call before creating the file
+    pub fn track(&self, path: PathBuf) {
+        if let Some(entry) = self.jobs.lock().get_mut(&self.id) {
+            entry.files.push(path);
+        }
+    }
+}
+
+/// The `[job_callbacks]` section of the server config
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct JobCallbackConfig {
+    /// Key callbacks are signed with; callbacks are refused while it is empty
+    pub secret: String,
+    /// Hosts callbacks may be sent to; empty allows any
+    pub allowed_hosts: Vec<String>,
+    /// Deliveries tried before a callback is given up, a second apart,
+    /// then two, four and so on
+    pub attempts: u32,
+}
+
+impl Default for JobCallbackConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            allowed_hosts: Vec::new(),
+            attempts: 3,
+        }
+    }
+}
+
+// The secret is left out so configuration changes can be logged
+impl fmt::Debug for JobCallbackConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobCallbackConfig")
+            .field(
+                "secret",
+                &if self.secret.is_empty() {
+                    "unset"
+                } else {
+                    "set"
+                },
+            )
+            .field("allowed_hosts", &self.allowed_hosts)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+impl JobCallbackConfig {
+    pub fn enabled(&self) -> bool {
+        !self.secret.is_empty()
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.enabled() && self.secret.len() < MIN_SECRET_LEN {
+            anyhow::bail!(
+                "job_callbacks.secret must be at least {} characters",
+                MIN_SECRET_LEN
+            );
+        }
+        if self.attempts == 0 {
+            anyhow::bail!("job_callbacks.attempts must be greater than 0");
+        }
+        Ok(())
+    }
+
+    /// A callback to `url`, if it is an http(s) URL to an allowed host
+    pub fn callback(&self, url: &str, base_url: &str) -> Result<JobCallback, String> {
+        let url: reqwest::Url = url
+            .parse()
+            .map_err(|e| format!("Invalid callback URL '{}': {}", url, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("Callback URL {} must be http or https", url));
+        }
+        let host = url.host_str().unwrap_or_default();
+        if !self.allowed_hosts.is_empty()
+            && !self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        {
+            return Err(format!(
+                "Callbacks may not be sent to {}; allowed are {}",
+                host,
+                self.allowed_hosts.join(", ")
+            ));
+        }
+        Ok(JobCallback {
+            url,
+            secret: self.secret.clone(),
+            attempts: self.attempts,
+            base_url: base_url.to_string(),
+        })
+    }
+}
+
+/// Where to report a finished job. The body is signed over
+/// `<X-Signature-Timestamp>.<body>` and the signature sent as
+/// `X-Signature: sha256=<hex>`, so receivers can reject forged and, by the
+/// timestamp, replayed notifications.
+#[derive(Clone)]
+pub struct JobCallback {
+    url: reqwest::Url,
+    secret: String,
+    attempts: u32,
+    /// Root the job's status and output URLs are given under
+    base_url: String,
+}
+
+impl fmt::Debug for JobCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobCallback")
+            .field("url", &self.url.as_str())
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+impl JobCallback {
+    /// The notification of `job`: its status URL, request and outcome, and
+    /// the outputs of a completed job as in its manifest
+    fn payload(&self, id: Uuid, job: &Job) -> Value {
+        let status = format!("{}/fhir/_jobs/{}", self.base_url, id);
+        let mut payload = json!({
+            "job": id,
+            "status": status,
+            "request": job.request,
+            "transactionTime": job.started_at,
+        });
+        match &job.state {
+            JobState::Completed {
+                finished_at,
+                outputs,
+            } => {
+                let output: Vec<Value> = outputs
+                    .iter()
+                    .enumerate()
+                    .map(|(index, output)| {
+                        json!({
+                            "type": output.resource_type,
+                            "url": format!("{}/output/{}", status, index),
+                            "count": output.count,
+                        })
+                    })
+                    .collect();
+                payload["outcome"] = json!("completed");
+                payload["finishedAt"] = json!(finished_at);
+                payload["output"] = json!(output);
+            }
+            JobState::Failed { error } => {
+                payload["outcome"] = json!("failed");
+                payload["error"] = json!(error);
+            }
+            JobState::InProgress => {}
+        }
+        payload
+    }
+
+    /// POST the signed notification of `job`, retrying failed deliveries;
+    /// signatures are timestamped by `clock`
+    async fn notify(&self, id: Uuid, job: &Job, clock: &dyn Clock) {
+        let body = self.payload(id, job).to_string();
+        let client = reqwest::Client::new();
+        let mut delay = Duration::from_secs(1);
+        for attempt in 1..=self.attempts {
+            let timestamp = clock.now().timestamp().to_string();
+            let signature = sharing::sign(
+                self.secret.as_bytes(),
+                format!("{}.{}", timestamp, body).as_bytes(),
+            );
+            let sent = client
+                .post(self.url.clone())
+                .timeout(Duration::from_secs(10))
+                .header("Content-Type", "application/json")
+                .header("X-Signature-Timestamp", &timestamp)
+                .header("X-Signature", format!("sha256={}", signature))
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match sent {
+                Ok(_) => {
+                    tracing::info!(job = %id, url = %self.url, "Job callback delivered");
+                    return;
+                }
+                Err(e) if attempt < self.attempts => {
+                    tracing::debug!(job = %id, url = %self.url, "Job callback failed, retrying: {}", e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    tracing::warn!(job = %id, url = %self.url, "Job callback given up: {}", e)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn wait_until_done(jobs: &Jobs, id: &Uuid) -> Job {
+        for _ in 0..100 {
+            let job = jobs.get(id).unwrap();
+            if job.state != JobState::InProgress {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("job {} did not finish", id);
+    }
+
+    #[tokio::test]
+    async fn test_jobs_report_their_outcome() {
+        let jobs = Arc::new(Jobs::default());
+        let path = std::env::temp_dir().join(format!("job-test-{}", Uuid::new_v4()));
+
+        let written = path.clone();
+        let id = jobs.start("GET /fhir/Patient", None, move |handle| async move {
+            handle.report("1 resource written");
+            handle.track(written.clone());
+            tokio::fs::write(&written, "{}\n").await?;
+            Ok(vec![JobOutput {
+                resource_type: "Patient".to_string(),
+                path: written,
+                count: 1,
+            }])
+        });
+        let job = wait_until_done(&jobs, &id).await;
+        assert_eq!(job.request, "GET /fhir/Patient");
+        assert_eq!(job.progress.as_deref(), Some("1 resource written"));
+        let JobState::Completed { outputs, .. } = job.state else {
+            panic!("expected a completed job");
+        };
+        assert_eq!(outputs[0].count, 1);
+        assert!(path.exists());
+
+        assert!(jobs.remove(&id));
+        assert!(!path.exists());
+        assert!(jobs.get(&id).is_none());
+        assert!(!jobs.remove(&id));
+
+        let failing = jobs.start("GET /fhir/Observation", None, |_| async {
+            Err(anyhow::anyhow!("database unavailable"))
+        });
+        let job = wait_until_done(&jobs, &failing).await;
+        assert_eq!(
+            job.state,
+            JobState::Failed {
+                error: "database unavailable".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_stamped_by_their_clock() {
+        use crate::clock::TestClock;
+
+        let start = "2010-03-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let clock = Arc::new(TestClock::new(start));
+        let jobs = Arc::new(Jobs::new(clock.clone()));
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let id = jobs.start("GET /fhir/$export", None, |_| async move {
+            released.await?;
+            Ok(Vec::new())
+        });
+        assert_eq!(jobs.get(&id).unwrap().started_at, start);
+
+        clock.advance(chrono::Duration::minutes(5));
+        release.send(()).unwrap();
+        let job = wait_until_done(&jobs, &id).await;
+        assert_eq!(job.started_at, start);
+        let JobState::Completed { finished_at, .. } = job.state else {
+            panic!("expected a completed job");
+        };
+        assert_eq!(finished_at, start + chrono::Duration::minutes(5));
+
+        let durations = jobs.duration_histogram();
+        assert_eq!(durations.count, 1);
+        assert_eq!(durations.sum, 300.0);
+        assert_eq!(durations.buckets[3], (60.0, 0));
+        assert_eq!(durations.buckets[4], (300.0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_remove_cancels_running_job() {
+        let jobs = Arc::new(Jobs::default());
+        let id = jobs.start("GET /fhir/Patient", None, |_| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(Vec::new())
+        });
+
+        assert_eq!(jobs.get(&id).unwrap().state, JobState::InProgress);
+        assert!(jobs.remove(&id));
+        assert!(jobs.get(&id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_callback_is_signed_and_retried() {
+        use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use tokio::sync::mpsc;
+
+        // The first delivery is refused, the retry taken
+        type Sender = mpsc::UnboundedSender<(HeaderMap, String)>;
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let refused = Arc::new(AtomicBool::new(false));
+        let receiver = Router::new()
+            .route(
+                "/done",
+                post(
+                    |State((sender, refused)): State<(Sender, Arc<AtomicBool>)>,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        if !refused.swap(true, Ordering::SeqCst) {
+                            return StatusCode::SERVICE_UNAVAILABLE;
+                        }
+                        sender.send((headers, body)).unwrap();
+                        StatusCode::NO_CONTENT
+                    },
+                ),
+            )
+            .with_state((sender, refused));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let secret = "0123456789abcdef0123456789abcdef";
+        let config = JobCallbackConfig {
+            secret: secret.to_string(),
+            ..JobCallbackConfig::default()
+        };
+        let callback = config
+            .callback(&format!("http://{}/done", address), "http://fhir.test")
+            .unwrap();
+        let jobs = Arc::new(Jobs::default());
+        let id = jobs.start("GET /fhir/Patient", Some(callback), |_| async {
+            Ok(vec![JobOutput {
+                resource_type: "Patient".to_string(),
+                path: PathBuf::from("unused"),
+                count: 2,
+            }])
+        });
+
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let timestamp = headers["x-signature-timestamp"].to_str().unwrap();
+        let signature = headers["x-signature"].to_str().unwrap();
+        assert!(sharing::verify(
+            secret.as_bytes(),
+            format!("{}.{}", timestamp, body).as_bytes(),
+            signature.strip_prefix("sha256=").unwrap()
+        ));
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["job"], id.to_string());
+        assert_eq!(payload["outcome"], "completed");
+        assert_eq!(
+            payload["output"][0]["url"],
+            format!("http://fhir.test/fhir/_jobs/{}/output/0", id)
+        );
+        assert_eq!(payload["output"][0]["count"], 2);
+    }
+
+    #[test]
+    fn test_callback_urls_are_checked() {
+        let config = JobCallbackConfig {
+            secret: "0123456789abcdef0123456789abcdef".to_string(),
+            allowed_hosts: vec!["hooks.example.org".to_string()],
+            ..JobCallbackConfig::default()
+        };
+        assert!(config
+            .callback("https://HOOKS.example.org/jobs", "http://fhir.test")
+            .is_ok());
+        for url in [
+            "https://internal.example.org/jobs",
+            "ftp://hooks.example.org/jobs",
+            "not a url",
+        ] {
+            assert!(config.callback(url, "http://fhir.test").is_err(), "{}", url);
+        }
+        assert!(!format!("{:?}", config).contains("0123456789"));
+        let short = JobCallbackConfig {
+            secret: "short".to_string(),
+            ..JobCallbackConfig::default()
+        };
+        assert!(short.validate().is_err());
+    }
+}
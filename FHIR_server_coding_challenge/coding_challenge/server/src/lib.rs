@@ -1,3 +1,46 @@
+pub mod access;
+pub mod api_keys;
+pub mod archive;
+pub mod authorization;
+pub mod capture;
+pub mod check;
+pub mod clock;
+pub mod collection;
+pub mod conditional;
+pub mod config;
+pub mod conformance_test;
+pub mod consent;
 pub mod db;
+pub mod demo;
+pub mod deprecation;
+pub mod elements;
+pub mod events;
+pub mod extract;
+pub mod features;
+pub mod fhirpath_patch;
+pub mod fuzzing;
 pub mod handlers;
-pub mod models;
+pub mod jobs;
+pub mod limits;
+pub mod mapping;
+pub mod metrics;
+pub mod migrations;
+pub mod negotiation;
+pub mod profiles;
+pub mod provenance;
+pub mod roster;
+pub mod routes;
+pub mod sandbox;
+pub mod search;
+pub mod sharing;
+pub mod slo;
+pub mod smart;
+pub mod state;
+pub mod tenant_export;
+pub mod throttle;
+pub mod transform;
+pub mod validation;
+pub mod websocket;
+
+/// FHIR resource models, shared with `fhir-client`
+pub use fhir_models as models;
@@ -0,0 +1,356 @@
+//! Limits on the size and shape of the resources clients send.
+//!
+//! `[resource_limits]` caps the JSON bodies of creates, updates, patches and
+//! operations under `/fhir`, and every resource in a Bundle, before they are
+//! decoded: `max_bytes` of JSON, `max_depth` nested objects and arrays, and
+//! `max_array_length` elements in any one array. Validation, profiles and
+//! patching then never see a pathological payload. The checks are one pass
+//! over the bytes; malformed JSON is left to the decoder to refuse. Each
+//! limit is answered with its own issue code:
+//!
+//! | limit              | status | code         |
+//! |--------------------|--------|--------------|
+//! | `max_bytes`        | 413    | `too-long`   |
+//! | `max_depth`        | 400    | `structure`  |
+//! | `max_array_length` | 413    | `too-costly` |
+//!
+//! Bundles as a whole are limited by `max_bundle_bytes` instead.
+
+use crate::config::SharedConfig;
+use crate::models::OperationOutcome;
+use crate::negotiation::has_json_body;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http_body_util::LengthLimitError;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Deepest `max_depth`; the JSON decoder refuses deeper documents anyway
+pub const MAX_DEPTH_LIMIT: usize = 128;
+
+/// The `[resource_limits]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Largest resource, in bytes of JSON
+    pub max_bytes: usize,
+    /// Most objects and arrays nested in one another, the resource included
+    pub max_depth: usize,
+    /// Most elements of any one array
+    pub max_array_length: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 4 * 1024 * 1024,
+            max_depth: 64,
+            max_array_length: 10_000,
+        }
+    }
+}
+
+/// A limit a resource went over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Bytes(usize),
+    Depth(usize),
+    ArrayLength(usize),
+}
+
+impl LimitExceeded {
+    /// The response refusing the resource, with `location` if it is part of
+    /// a larger request
+    pub(crate) fn outcome(self, location: Option<&str>) -> (StatusCode, Json<OperationOutcome>) {
+        let (status, code, message) = match self {
+            LimitExceeded::Bytes(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too-long",
+                format!("Resources are limited to {} bytes", limit),
+            ),
+            LimitExceeded::Depth(limit) => (
+                StatusCode::BAD_REQUEST,
+                "structure",
+                format!("Resources may nest at most {} levels deep", limit),
+            ),
+            LimitExceeded::ArrayLength(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too-costly",
+                format!("Arrays in resources are limited to {} elements", limit),
+            ),
+        };
+        tracing::info!(code, %message, "Resource over a limit refused");
+        let outcome = match location {
+            Some(location) => OperationOutcome::error_with_location(code, message, location),
+            None => OperationOutcome::error(code, message),
+        };
+        (status, Json(outcome))
+    }
+}
+
+/// One object or array the scan is inside
+enum Open {
+    Object,
+    /// With the elements seen so far
+    Array(usize),
+}
+
+impl ResourceLimits {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.max_bytes == 0 || self.max_array_length == 0 {
+            anyhow::bail!("resource_limits: max_bytes and max_array_length must be greater than 0");
+        }
+        if self.max_depth == 0 || self.max_depth > MAX_DEPTH_LIMIT {
+            anyhow::bail!(
+                "resource_limits: max_depth must be between 1 and {}",
+                MAX_DEPTH_LIMIT
+            );
+        }
+        Ok(())
+    }
+
+    /// Check the JSON of one resource against the limits
+    pub fn check(&self, json: &[u8]) -> Result<(), LimitExceeded> {
+        if json.len() > self.max_bytes {
+            return Err(LimitExceeded::Bytes(self.max_bytes));
+        }
+
+        let mut open: Vec<Open> = Vec::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        for &byte in json {
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+            if let Some(Open::Array(elements)) = open.last_mut() {
+                if *elements == 0 && byte != b']' {
+                    *elements = 1;
+                } else if byte == b',' {
+                    *elements += 1;
+                }
+                if *elements > self.max_array_length {
+                    return Err(LimitExceeded::ArrayLength(self.max_array_length));
+                }
+            }
+            match byte {
+                b'"' => in_string = true,
+                b'{' | b'[' => {
+                    open.push(if byte == b'{' {
+                        Open::Object
+                    } else {
+                        Open::Array(0)
+                    });
+                    if open.len() > self.max_depth {
+                        return Err(LimitExceeded::Depth(self.max_depth));
+                    }
+                }
+                b'}' | b']' => {
+                    open.pop();
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the body of a request is a resource the limits apply to; Bundles
+/// posted to the base are checked entry by entry
+fn carries_resource(request: &Request) -> bool {
+    let path = request.uri().path();
+    matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH
+    ) && path.starts_with("/fhir/")
+        && path != "/fhir/"
+        && has_json_body(request.headers())
+}
+
+/// Whether reading a body failed because it was longer than the limit
+/// passed to `to_bytes`, rather than because the client went away or the read
+/// failed
+pub fn is_length_limit(e: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// 400 for a request body that could not be read
+pub fn unreadable_body(e: axum::Error) -> (StatusCode, Json<OperationOutcome>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OperationOutcome::error(
+            "structure",
+            format!("Failed to read the request body: {}", e),
+        )),
+    )
+}
+
+/// Refuse request bodies over the `[resource_limits]` before any handler
+/// decodes them
+pub async fn enforce_resource_limits(
+    State(config): State<Arc<SharedConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !carries_resource(&request) {
+        return next.run(request).await;
+    }
+    let limits = config.get().resource_limits.clone();
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, limits.max_bytes).await {
+        Ok(bytes) => bytes,
+        Err(e) if is_length_limit(&e) => {
+            return LimitExceeded::Bytes(limits.max_bytes)
+                .outcome(None)
+                .into_response()
+        }
+        Err(e) => return unreadable_body(e).into_response(),
+    };
+    if let Err(exceeded) = limits.check(&bytes) {
+        return exceeded.outcome(None).into_response();
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::http::header;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_only_overlong_bodies_hit_the_length_limit() {
+        let overlong = to_bytes(Body::from("x".repeat(20)), 10).await.unwrap_err();
+        assert!(is_length_limit(&overlong));
+
+        let reset = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let stream = futures_util::stream::iter([Err::<axum::body::Bytes, _>(reset)]);
+        let failed = to_bytes(Body::from_stream(stream), 10).await.unwrap_err();
+        assert!(!is_length_limit(&failed));
+        assert_eq!(unreadable_body(failed).0, StatusCode::BAD_REQUEST);
+    }
+
+    fn limits(max_depth: usize, max_array_length: usize) -> ResourceLimits {
+        ResourceLimits {
+            max_bytes: 1024,
+            max_depth,
+            max_array_length,
+        }
+    }
+
+    #[test]
+    fn test_depth_and_array_length() {
+        let patient = br#"{"resourceType":"Patient","name":[{"given":["A","B"]},{"family":"C"}]}"#;
+        assert_eq!(limits(4, 2).check(patient), Ok(()));
+        assert_eq!(limits(3, 2).check(patient), Err(LimitExceeded::Depth(3)));
+        assert_eq!(
+            limits(4, 1).check(patient),
+            Err(LimitExceeded::ArrayLength(1))
+        );
+        assert_eq!(limits(4, 1).check(br#"{"a": [ ], "b": [[]]}"#), Ok(()));
+        assert_eq!(
+            limits(4, 2).check(&[b' '; 1025]),
+            Err(LimitExceeded::Bytes(1024))
+        );
+    }
+
+    #[test]
+    fn test_strings_are_not_structure() {
+        // Brackets, commas and escaped quotes inside strings do not count
+        let text = br#"{"text":"[[[[,,,,\"{{{{","a":["x,y","]"]}"#;
+        assert_eq!(limits(2, 2).check(text), Ok(()));
+        assert!(limits(4, 1).check(text).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_resources_are_refused() {
+        let config = ServerConfig {
+            resource_limits: ResourceLimits {
+                max_bytes: 4096,
+                max_depth: 8,
+                max_array_length: 3,
+            },
+            ..ServerConfig::default()
+        };
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config)),
+        ));
+        let post = |uri: &str, body: Value| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/fhir+json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+        let outcome = |response: Response| async move {
+            let status = response.status();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let outcome: Value = serde_json::from_slice(&body).unwrap();
+            (status, outcome["issue"][0].clone())
+        };
+
+        let names = json!({ "resourceType": "Patient", "name": [{}, {}, {}, {}] });
+        let (status, issue) = outcome(post("/fhir/Patient", names).await.unwrap()).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(issue["code"], "too-costly");
+
+        let mut nested = json!({ "url": "http://example.org/leaf", "valueString": "x" });
+        for _ in 0..5 {
+            nested = json!({ "url": "http://example.org/node", "extension": [nested] });
+        }
+        let deep = json!({ "resourceType": "Patient", "extension": [nested] });
+        let (status, issue) = outcome(post("/fhir/Patient", deep.clone()).await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(issue["code"], "structure");
+
+        let long = json!({ "resourceType": "Patient", "name": [{ "family": "x".repeat(5000) }] });
+        let (status, issue) = outcome(post("/fhir/Patient", long).await.unwrap()).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(issue["code"], "too-long");
+
+        // Resources in Bundles are held to the same limits
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [{ "resource": deep, "request": { "method": "POST", "url": "Patient" } }],
+        });
+        let (status, issue) = outcome(post("/fhir", bundle).await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(issue["code"], "structure");
+        assert_eq!(issue["location"][0], "Bundle.entry[0].resource");
+
+        let fine = json!({ "resourceType": "Patient", "name": [{ "family": "Limits" }] });
+        let response = post("/fhir/Patient", fine).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+}
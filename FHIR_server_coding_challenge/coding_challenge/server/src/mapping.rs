@@ -0,0 +1,1015 @@
+//! `$transform`: rewriting a resource with a ConceptMap or a StructureMap.
+//!
+//! A [`ConceptMapping`] rewrites the resource in place. Every Coding whose
+//! `system` is the `source` of one of the map's groups gets the group's
+//! `target` system and the code its `element` maps the code to; every
+//! Identifier of that system is rewritten the same way by its `value`. A
+//! code without a mapping takes the group's `unmapped` rule: `provided` keeps
+//! the code under the target system (what moving identifiers to a new system
+//! needs) and `fixed` uses the rule's code. Without a rule the transform
+//! fails, so no code is left behind in a system it does not belong to.
+//!
+//! A [`StructureMapping`] builds a new resource from the rules of the map's
+//! first group, the subset of the mapping language that moves and
+//! translates elements:
+//!
+//! - a rule has one source, `context.element as variable`, and runs once
+//!   per item when the element repeats
+//! - a target `context.element` is set to `copy(value)` (also the shorthand
+//!   without transform and variable), a new element (`create`, or a target
+//!   variable without transform) or `translate(value, map, output)` with a
+//!   stored ConceptMap, `output` being `code`, `system`, `display`, `coding`
+//!   or `CodeableConcept`
+//! - nested rules see the variables of the rules around them, and targets
+//!   repeat where their source does
+//!
+//! Dependent rules, other groups, conditions and other transforms are
+//! rejected as not supported rather than skipped.
+
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Why a map could not be read or applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingError {
+    /// OperationOutcome issue code
+    pub code: &'static str,
+    pub message: String,
+    /// Element of the map or of the resource at fault
+    pub location: Option<String>,
+}
+
+impl MappingError {
+    fn new(code: &'static str, message: impl Into<String>, location: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            location: Some(location.into()),
+        }
+    }
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} (at {})", self.message, location),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+fn string<'a>(value: &'a Value, name: &str) -> Option<&'a str> {
+    value.get(name).and_then(Value::as_str)
+}
+
+fn items<'a>(value: &'a Value, name: &str) -> &'a [Value] {
+    value
+        .get(name)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+fn check_type(resource: &Value, expected: &str) -> Result<(), MappingError> {
+    match string(resource, "resourceType") {
+        Some(resource_type) if resource_type == expected => Ok(()),
+        other => Err(MappingError {
+            code: "invalid",
+            message: format!(
+                "Expected a {}, got {}",
+                expected,
+                other.unwrap_or("a resource without resourceType")
+            ),
+            location: None,
+        }),
+    }
+}
+
+/// What a code maps to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedCode {
+    pub system: Option<String>,
+    pub version: Option<String>,
+    pub code: String,
+    pub display: Option<String>,
+}
+
+impl MappedCode {
+    fn coding(&self) -> Value {
+        let mut coding = Map::new();
+        for (name, value) in [
+            ("system", &self.system),
+            ("version", &self.version),
+            ("code", &Some(self.code.clone())),
+            ("display", &self.display),
+        ] {
+            if let Some(value) = value {
+                coding.insert(name.to_string(), json!(value));
+            }
+        }
+        Value::Object(coding)
+    }
+}
+
+/// `ConceptMap.group.unmapped`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Unmapped {
+    /// No rule: an unmapped code fails the transform
+    Fail,
+    /// Keep the code, under the target system
+    Provided,
+    Fixed {
+        code: String,
+        display: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MapGroup {
+    source: Option<String>,
+    target: Option<String>,
+    target_version: Option<String>,
+    elements: HashMap<String, MappedCode>,
+    unmapped: Unmapped,
+}
+
+impl MapGroup {
+    fn unmapped(&self, code: &str) -> Option<MappedCode> {
+        let (code, display) = match &self.unmapped {
+            Unmapped::Fail => return None,
+            Unmapped::Provided => (code.to_string(), None),
+            Unmapped::Fixed { code, display } => (code.clone(), display.clone()),
+        };
+        Some(MappedCode {
+            system: self.target.clone().or_else(|| self.source.clone()),
+            version: self.target_version.clone(),
+            code,
+            display,
+        })
+    }
+}
+
+/// Target relationships that mean the codes do not correspond (`equivalence`
+/// in R4, `relationship` in R5)
+const UNRELATED: &[&str] = &["unmatched", "disjoint", "not-related-to"];
+
+/// A parsed ConceptMap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConceptMapping {
+    pub url: Option<String>,
+    groups: Vec<MapGroup>,
+}
+
+impl ConceptMapping {
+    pub fn parse(resource: &Value) -> Result<Self, MappingError> {
+        check_type(resource, "ConceptMap")?;
+        let mut groups = Vec::new();
+        for (i, group) in items(resource, "group").iter().enumerate() {
+            let location = format!("ConceptMap.group[{}]", i);
+            let target = string(group, "target").map(str::to_string);
+            let target_version = string(group, "targetVersion").map(str::to_string);
+            let mut elements = HashMap::new();
+            for (j, element) in items(group, "element").iter().enumerate() {
+                let code = string(element, "code").ok_or_else(|| {
+                    MappingError::new(
+                        "required",
+                        "A mapped element needs a code",
+                        format!("{}.element[{}].code", location, j),
+                    )
+                })?;
+                let mapped = items(element, "target").iter().find_map(|mapped| {
+                    let relationship = string(mapped, "equivalence")
+                        .or_else(|| string(mapped, "relationship"))
+                        .unwrap_or_default();
+                    if UNRELATED.contains(&relationship) {
+                        return None;
+                    }
+                    Some(MappedCode {
+                        system: target.clone(),
+                        version: target_version.clone(),
+                        code: string(mapped, "code")?.to_string(),
+                        display: string(mapped, "display").map(str::to_string),
+                    })
+                });
+                if let Some(mapped) = mapped {
+                    elements.entry(code.to_string()).or_insert(mapped);
+                }
+            }
+            let unmapped = match group.get("unmapped") {
+                None => Unmapped::Fail,
+                Some(unmapped) => match string(unmapped, "mode") {
+                    Some("provided") | Some("use-source-code") => Unmapped::Provided,
+                    Some("fixed") => Unmapped::Fixed {
+                        code: string(unmapped, "code")
+                            .ok_or_else(|| {
+                                MappingError::new(
+                                    "required",
+                                    "A fixed unmapped rule needs a code",
+                                    format!("{}.unmapped.code", location),
+                                )
+                            })?
+                            .to_string(),
+                        display: string(unmapped, "display").map(str::to_string),
+                    },
+                    mode => {
+                        return Err(MappingError::new(
+                            "not-supported",
+                            format!(
+                                "Unmapped mode {} is not supported; use provided or fixed",
+                                mode.unwrap_or("(none)")
+                            ),
+                            format!("{}.unmapped.mode", location),
+                        ))
+                    }
+                },
+            };
+            groups.push(MapGroup {
+                source: string(group, "source").map(str::to_string),
+                target,
+                target_version,
+                elements,
+                unmapped,
+            });
+        }
+        Ok(Self {
+            url: string(resource, "url").map(str::to_string),
+            groups,
+        })
+    }
+
+    /// What `code` of `system` maps to: `None` when no group maps `system`,
+    /// `Some(None)` when one does but has no mapping for `code`. A code
+    /// without a system is looked up in the elements of every group; which
+    /// unmapped rule applies to it is only clear with a single group.
+    pub fn lookup(&self, system: Option<&str>, code: &str) -> Option<Option<MappedCode>> {
+        let groups: Vec<&MapGroup> = self
+            .groups
+            .iter()
+            .filter(|group| system.is_none() || group.source.as_deref() == system)
+            .collect();
+        if groups.is_empty() {
+            return None;
+        }
+        let mapped = groups
+            .iter()
+            .find_map(|group| group.elements.get(code).cloned())
+            .or_else(|| {
+                let unmapped = system.is_some() || self.groups.len() == 1;
+                groups
+                    .iter()
+                    .filter(|_| unmapped)
+                    .find_map(|group| group.unmapped(code))
+            });
+        Some(mapped)
+    }
+
+    fn name(&self) -> &str {
+        self.url.as_deref().unwrap_or("The ConceptMap")
+    }
+
+    /// Rewrite the Codings and Identifiers of `resource` in the systems
+    /// this map translates
+    pub fn apply(&self, resource: &mut Value) -> Result<(), MappingError> {
+        let location = string(resource, "resourceType")
+            .unwrap_or("Resource")
+            .to_string();
+        self.rewrite(resource, location)
+    }
+
+    fn rewrite(&self, value: &mut Value, location: String) -> Result<(), MappingError> {
+        match value {
+            Value::Array(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.rewrite(item, format!("{}[{}]", location, i))?;
+                }
+            }
+            Value::Object(map) => {
+                // Codings have a code, Identifiers a value
+                let key = match (map.get("code"), map.get("value")) {
+                    (Some(Value::String(_)), _) => Some("code"),
+                    (None, Some(Value::String(_))) => Some("value"),
+                    _ => None,
+                };
+                let coded = key.and_then(|key| {
+                    let system = map.get("system")?.as_str()?;
+                    Some((key, system.to_string(), map[key].as_str()?.to_string()))
+                });
+                if let Some((key, system, code)) = coded {
+                    match self.lookup(Some(&system), &code) {
+                        None => {}
+                        Some(None) => {
+                            return Err(MappingError::new(
+                                "code-invalid",
+                                format!("{} has no mapping for {}|{}", self.name(), system, code),
+                                location,
+                            ))
+                        }
+                        Some(Some(mapped)) => {
+                            map.remove("version");
+                            if key == "code" {
+                                map.remove("display");
+                            }
+                            for (name, value) in [
+                                ("system", mapped.system),
+                                ("version", mapped.version),
+                                (key, Some(mapped.code)),
+                                ("display", mapped.display.filter(|_| key == "code")),
+                            ] {
+                                if let Some(value) = value {
+                                    map.insert(name.to_string(), json!(value));
+                                }
+                            }
+                            return Ok(());
+                        }
+                    }
+                }
+                for (name, member) in map.iter_mut() {
+                    self.rewrite(member, format!("{}.{}", location, name))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A transform parameter: a variable (`valueId`) or a literal value
+#[derive(Debug, Clone, PartialEq)]
+enum Param {
+    Variable(String),
+    Literal(Value),
+}
+
+impl Param {
+    fn parse(parameter: &Value, location: &str) -> Result<Self, MappingError> {
+        if let Some(id) = string(parameter, "valueId") {
+            return Ok(Param::Variable(id.to_string()));
+        }
+        parameter
+            .as_object()
+            .and_then(|map| {
+                map.iter()
+                    .find(|(name, _)| name.starts_with("value"))
+                    .map(|(_, value)| Param::Literal(value.clone()))
+            })
+            .ok_or_else(|| MappingError::new("required", "A parameter needs a value", location))
+    }
+
+    fn literal(&self) -> Option<&str> {
+        match self {
+            Param::Literal(Value::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Transform {
+    /// The parameter, or the source item without one
+    Copy(Option<Param>),
+    Create,
+    Translate {
+        value: Param,
+        map: String,
+        output: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct RuleTarget {
+    context: String,
+    element: String,
+    variable: Option<String>,
+    transform: Transform,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    location: String,
+    context: String,
+    element: Option<String>,
+    variable: Option<String>,
+    targets: Vec<RuleTarget>,
+    rules: Vec<Rule>,
+}
+
+const TRANSLATE_OUTPUTS: &[&str] = &["code", "system", "display", "coding", "CodeableConcept"];
+
+impl Rule {
+    fn parse(rule: &Value, location: String) -> Result<Self, MappingError> {
+        let unsupported = |message: &str, path: &str| {
+            Err(MappingError::new(
+                "not-supported",
+                message,
+                format!("{}.{}", location, path),
+            ))
+        };
+        if !items(rule, "dependent").is_empty() {
+            return unsupported("Dependent rules are not supported", "dependent");
+        }
+        let source = match items(rule, "source") {
+            [source] => source,
+            [] => return unsupported("A rule needs a source", "source"),
+            _ => {
+                return unsupported(
+                    "Rules with more than one source are not supported",
+                    "source",
+                )
+            }
+        };
+        for condition in ["condition", "check"] {
+            if source.get(condition).is_some() {
+                return unsupported("Source conditions are not supported", condition);
+            }
+        }
+        let context = string(source, "context").ok_or_else(|| {
+            MappingError::new(
+                "required",
+                "A source needs a context",
+                format!("{}.source[0].context", location),
+            )
+        })?;
+
+        let mut targets = Vec::new();
+        for (i, target) in items(rule, "target").iter().enumerate() {
+            let path = format!("{}.target[{}]", location, i);
+            let (Some(context), Some(element)) =
+                (string(target, "context"), string(target, "element"))
+            else {
+                return Err(MappingError::new(
+                    "not-supported",
+                    "A target needs a context and an element",
+                    path,
+                ));
+            };
+            let variable = string(target, "variable").map(str::to_string);
+            let parameters = items(target, "parameter")
+                .iter()
+                .enumerate()
+                .map(|(j, parameter)| {
+                    Param::parse(parameter, &format!("{}.parameter[{}]", path, j))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let transform = match (string(target, "transform"), parameters.as_slice()) {
+                (None, []) if variable.is_some() => Transform::Create,
+                (None, []) => Transform::Copy(None),
+                (None | Some("copy"), [value]) => Transform::Copy(Some(value.clone())),
+                (Some("create"), _) => Transform::Create,
+                (Some("translate"), [value, map, output])
+                    if map.literal().is_some()
+                        && output
+                            .literal()
+                            .is_some_and(|o| TRANSLATE_OUTPUTS.contains(&o)) =>
+                {
+                    Transform::Translate {
+                        value: value.clone(),
+                        map: map.literal().unwrap_or_default().to_string(),
+                        output: output.literal().unwrap_or_default().to_string(),
+                    }
+                }
+                (Some("translate"), _) => {
+                    return Err(MappingError::new(
+                        "invalid",
+                        format!(
+                            "translate takes a value, the ConceptMap's canonical and one of {}",
+                            TRANSLATE_OUTPUTS.join(", ")
+                        ),
+                        format!("{}.parameter", path),
+                    ))
+                }
+                (transform, _) => {
+                    return Err(MappingError::new(
+                        "not-supported",
+                        format!(
+                            "Transform {} is not supported; use copy, create or translate",
+                            transform.unwrap_or("copy")
+                        ),
+                        format!("{}.transform", path),
+                    ))
+                }
+            };
+            targets.push(RuleTarget {
+                context: context.to_string(),
+                element: element.to_string(),
+                variable,
+                transform,
+            });
+        }
+
+        let rules = items(rule, "rule")
+            .iter()
+            .enumerate()
+            .map(|(i, nested)| Rule::parse(nested, format!("{}.rule[{}]", location, i)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            context: context.to_string(),
+            element: string(source, "element").map(str::to_string),
+            variable: string(source, "variable").map(str::to_string),
+            location,
+            targets,
+            rules,
+        })
+    }
+
+    fn concept_maps<'a>(&'a self, maps: &mut Vec<&'a str>) {
+        for target in &self.targets {
+            if let Transform::Translate { map, .. } = &target.transform {
+                if !maps.contains(&map.as_str()) {
+                    maps.push(map);
+                }
+            }
+        }
+        for rule in &self.rules {
+            rule.concept_maps(maps);
+        }
+    }
+}
+
+/// The type a `StructureMap.structure.url` names, its last path segment
+fn structure_type(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or(url)
+}
+
+/// A parsed StructureMap
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureMapping {
+    pub url: Option<String>,
+    source_type: Option<String>,
+    target_type: Option<String>,
+    source: String,
+    target: String,
+    rules: Vec<Rule>,
+}
+
+/// Variables in scope of a rule: source items, and targets as JSON pointers
+/// into the resource being built
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    sources: HashMap<String, Value>,
+    targets: HashMap<String, String>,
+}
+
+impl StructureMapping {
+    pub fn parse(resource: &Value) -> Result<Self, MappingError> {
+        check_type(resource, "StructureMap")?;
+        let structure = |mode: &str| {
+            items(resource, "structure")
+                .iter()
+                .find(|structure| string(structure, "mode") == Some(mode))
+                .and_then(|structure| string(structure, "url"))
+                .map(|url| structure_type(url).to_string())
+        };
+        let group = match items(resource, "group") {
+            [] => {
+                return Err(MappingError::new(
+                    "required",
+                    "A StructureMap needs a group",
+                    "StructureMap.group",
+                ))
+            }
+            groups => &groups[0],
+        };
+        if group.get("extends").is_some() {
+            return Err(MappingError::new(
+                "not-supported",
+                "Groups extending other groups are not supported",
+                "StructureMap.group[0].extends",
+            ));
+        }
+        let input = |mode: &str| {
+            items(group, "input")
+                .iter()
+                .find(|input| string(input, "mode") == Some(mode))
+                .and_then(|input| string(input, "name"))
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    MappingError::new(
+                        "required",
+                        format!("The first group needs a {} input", mode),
+                        "StructureMap.group[0].input",
+                    )
+                })
+        };
+        let rules = items(group, "rule")
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| Rule::parse(rule, format!("StructureMap.group[0].rule[{}]", i)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            url: string(resource, "url").map(str::to_string),
+            source_type: structure("source"),
+            target_type: structure("target"),
+            source: input("source")?,
+            target: input("target")?,
+            rules,
+        })
+    }
+
+    /// Canonicals of the ConceptMaps the `translate` targets use
+    pub fn concept_maps(&self) -> Vec<&str> {
+        let mut maps = Vec::new();
+        for rule in &self.rules {
+            rule.concept_maps(&mut maps);
+        }
+        maps
+    }
+
+    /// Build the target resource from `source`; `maps` holds the
+    /// ConceptMaps of [`StructureMapping::concept_maps`] by canonical
+    pub fn apply(
+        &self,
+        source: &Value,
+        maps: &HashMap<String, ConceptMapping>,
+    ) -> Result<Value, MappingError> {
+        let source_type = string(source, "resourceType");
+        if let Some(expected) = &self.source_type {
+            if source_type != Some(expected.as_str()) {
+                return Err(MappingError {
+                    code: "invalid",
+                    message: format!(
+                        "{} maps {} resources, not {}",
+                        self.url.as_deref().unwrap_or("The StructureMap"),
+                        expected,
+                        source_type.unwrap_or("a resource without resourceType")
+                    ),
+                    location: None,
+                });
+            }
+        }
+        let target_type = self.target_type.as_deref().or(source_type);
+        let mut output = match target_type {
+            Some(target_type) => json!({ "resourceType": target_type }),
+            None => json!({}),
+        };
+        let mut scope = Scope::default();
+        scope.sources.insert(self.source.clone(), source.clone());
+        scope.targets.insert(self.target.clone(), String::new());
+        for rule in &self.rules {
+            run_rule(rule, &scope, &mut output, maps)?;
+        }
+        Ok(output)
+    }
+}
+
+fn run_rule(
+    rule: &Rule,
+    scope: &Scope,
+    output: &mut Value,
+    maps: &HashMap<String, ConceptMapping>,
+) -> Result<(), MappingError> {
+    let context = scope.sources.get(&rule.context).ok_or_else(|| {
+        MappingError::new(
+            "processing",
+            format!("{} is not a source variable", rule.context),
+            format!("{}.source[0].context", rule.location),
+        )
+    })?;
+    let selected: Vec<(Value, bool)> = match &rule.element {
+        None => vec![(context.clone(), false)],
+        Some(element) => match context.get(element) {
+            None => Vec::new(),
+            Some(Value::Array(items)) => items.iter().map(|item| (item.clone(), true)).collect(),
+            Some(value) => vec![(value.clone(), false)],
+        },
+    };
+
+    for (item, repeats) in selected {
+        let mut scope = scope.clone();
+        if let Some(variable) = &rule.variable {
+            scope.sources.insert(variable.clone(), item.clone());
+        }
+        for (i, target) in rule.targets.iter().enumerate() {
+            let location = format!("{}.target[{}]", rule.location, i);
+            let value = match &target.transform {
+                Transform::Copy(None) => item.clone(),
+                Transform::Copy(Some(param)) => resolve(param, &scope, &location)?,
+                Transform::Create => json!({}),
+                Transform::Translate { value, map, output } => {
+                    let value = resolve(value, &scope, &location)?;
+                    let map = maps.get(map).ok_or_else(|| {
+                        MappingError::new(
+                            "not-found",
+                            format!("{} does not resolve to a stored ConceptMap", map),
+                            format!("{}.parameter[1]", location),
+                        )
+                    })?;
+                    translate(map, &value, output, &location)?
+                }
+            };
+            let pointer = scope.targets.get(&target.context).ok_or_else(|| {
+                MappingError::new(
+                    "processing",
+                    format!("{} is not a target variable", target.context),
+                    format!("{}.context", location),
+                )
+            })?;
+            let pointer = write(output, pointer, &target.element, value, repeats, &location)?;
+            if let Some(variable) = &target.variable {
+                scope.targets.insert(variable.clone(), pointer);
+            }
+        }
+        for nested in &rule.rules {
+            run_rule(nested, &scope, output, maps)?;
+        }
+    }
+    Ok(())
+}
+
+fn resolve(param: &Param, scope: &Scope, location: &str) -> Result<Value, MappingError> {
+    match param {
+        Param::Literal(value) => Ok(value.clone()),
+        Param::Variable(name) => scope.sources.get(name).cloned().ok_or_else(|| {
+            MappingError::new(
+                "processing",
+                format!("{} is not a source variable", name),
+                format!("{}.parameter", location),
+            )
+        }),
+    }
+}
+
+/// Translate a code, Coding or CodeableConcept; a CodeableConcept
+/// translates by its first Coding the map has a mapping for
+fn translate(
+    map: &ConceptMapping,
+    value: &Value,
+    output: &str,
+    location: &str,
+) -> Result<Value, MappingError> {
+    let codings: Vec<(Option<&str>, &str)> = match value {
+        Value::String(code) => vec![(None, code.as_str())],
+        Value::Object(_) if value.get("coding").is_some() => items(value, "coding")
+            .iter()
+            .filter_map(|coding| Some((string(coding, "system"), string(coding, "code")?)))
+            .collect(),
+        Value::Object(_) => string(value, "code")
+            .map(|code| vec![(string(value, "system"), code)])
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    let mapped = codings
+        .iter()
+        .find_map(|(system, code)| map.lookup(*system, code).flatten())
+        .ok_or_else(|| {
+            let codes: Vec<String> = codings
+                .iter()
+                .map(|(system, code)| match system {
+                    Some(system) => format!("{}|{}", system, code),
+                    None => code.to_string(),
+                })
+                .collect();
+            MappingError::new(
+                "code-invalid",
+                format!(
+                    "{} has no mapping for {}",
+                    map.name(),
+                    if codes.is_empty() {
+                        value.to_string()
+                    } else {
+                        codes.join(", ")
+                    }
+                ),
+                location,
+            )
+        })?;
+    Ok(match output {
+        "code" => json!(mapped.code),
+        "system" => json!(mapped.system),
+        "display" => json!(mapped.display),
+        "coding" => mapped.coding(),
+        _ => json!({ "coding": [mapped.coding()] }),
+    })
+}
+
+/// Set `element` of the object at `pointer` to `value`, appending when it
+/// repeats; returns the pointer of the value
+fn write(
+    output: &mut Value,
+    pointer: &str,
+    element: &str,
+    value: Value,
+    repeats: bool,
+    location: &str,
+) -> Result<String, MappingError> {
+    let parent = output
+        .pointer_mut(pointer)
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| {
+            MappingError::new(
+                "processing",
+                format!("Cannot set {} on a value that is not an element", element),
+                location,
+            )
+        })?;
+    let path = format!("{}/{}", pointer, element);
+    match parent.get_mut(element) {
+        Some(Value::Array(items)) => {
+            items.push(value);
+            Ok(format!("{}/{}", path, items.len() - 1))
+        }
+        _ if repeats => {
+            parent.insert(element.to_string(), json!([value]));
+            Ok(format!("{}/0", path))
+        }
+        _ => {
+            parent.insert(element.to_string(), value);
+            Ok(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn concept_map() -> Value {
+        json!({
+            "resourceType": "ConceptMap",
+            "url": "http://example.org/fhir/ConceptMap/legacy",
+            "group": [{
+                "source": "urn:legacy:gender",
+                "target": "http://hl7.org/fhir/administrative-gender",
+                "element": [
+                    { "code": "M", "target": [{ "code": "male", "display": "Male", "equivalence": "equivalent" }] },
+                    { "code": "F", "target": [{ "code": "female", "equivalence": "equivalent" }] },
+                    { "code": "X", "target": [{ "code": "other", "equivalence": "unmatched" }] }
+                ]
+            }, {
+                "source": "urn:oid:1.2.3.4",
+                "target": "http://hospital.example.org/mrn",
+                "unmapped": { "mode": "provided" }
+            }]
+        })
+    }
+
+    #[test]
+    fn test_concept_map_rewrites_codings_and_identifiers() {
+        let map = ConceptMapping::parse(&concept_map()).unwrap();
+        let mut patient = json!({
+            "resourceType": "Patient",
+            "identifier": [
+                { "system": "urn:oid:1.2.3.4", "value": "4711" },
+                { "system": "urn:other", "value": "1" }
+            ],
+            "extension": [{
+                "url": "http://example.org/sex",
+                "valueCoding": { "system": "urn:legacy:gender", "code": "M", "display": "Mann" }
+            }]
+        });
+        map.apply(&mut patient).unwrap();
+        assert_eq!(
+            patient["identifier"],
+            json!([
+                { "system": "http://hospital.example.org/mrn", "value": "4711" },
+                { "system": "urn:other", "value": "1" }
+            ])
+        );
+        assert_eq!(
+            patient["extension"][0]["valueCoding"],
+            json!({ "system": "http://hl7.org/fhir/administrative-gender", "code": "male", "display": "Male" })
+        );
+
+        // Unmatched targets are no mapping, and there is no unmapped rule
+        let mut unmapped = json!({
+            "resourceType": "Observation",
+            "code": { "coding": [{ "system": "urn:legacy:gender", "code": "X" }] }
+        });
+        let error = map.apply(&mut unmapped).unwrap_err();
+        assert_eq!(error.code, "code-invalid");
+        assert_eq!(
+            error.location.as_deref(),
+            Some("Observation.code.coding[0]")
+        );
+        assert_eq!(map.lookup(Some("urn:elsewhere"), "M"), None);
+        assert_eq!(map.lookup(None, "F").flatten().unwrap().code, "female");
+
+        let mut other_map = concept_map();
+        other_map["group"][1]["unmapped"] = json!({ "mode": "other-map" });
+        assert_eq!(
+            ConceptMapping::parse(&other_map).unwrap_err().code,
+            "not-supported"
+        );
+    }
+
+    fn structure_map(rules: Value) -> Value {
+        json!({
+            "resourceType": "StructureMap",
+            "url": "http://example.org/fhir/StructureMap/legacy-patient",
+            "structure": [
+                { "url": "http://example.org/StructureDefinition/LegacyPatient", "mode": "source" },
+                { "url": "http://hl7.org/fhir/StructureDefinition/Patient", "mode": "target" }
+            ],
+            "group": [{
+                "name": "patient",
+                "input": [{ "name": "src", "mode": "source" }, { "name": "tgt", "mode": "target" }],
+                "rule": rules
+            }]
+        })
+    }
+
+    #[test]
+    fn test_structure_map_builds_the_target() {
+        let map = StructureMapping::parse(&structure_map(json!([{
+            "name": "sex",
+            "source": [{ "context": "src", "element": "sex", "variable": "s" }],
+            "target": [{
+                "context": "tgt",
+                "element": "gender",
+                "transform": "translate",
+                "parameter": [
+                    { "valueId": "s" },
+                    { "valueString": "http://example.org/fhir/ConceptMap/legacy" },
+                    { "valueString": "code" }
+                ]
+            }]
+        }, {
+            "name": "names",
+            "source": [{ "context": "src", "element": "names", "variable": "n" }],
+            "target": [{ "context": "tgt", "element": "name", "variable": "tn" }],
+            "rule": [{
+                "name": "surname",
+                "source": [{ "context": "n", "element": "surname", "variable": "f" }],
+                "target": [{ "context": "tn", "element": "family", "transform": "copy", "parameter": [{ "valueId": "f" }] }]
+            }, {
+                "name": "use",
+                "source": [{ "context": "n" }],
+                "target": [{ "context": "tn", "element": "use", "transform": "copy", "parameter": [{ "valueString": "official" }] }]
+            }]
+        }, {
+            "name": "dob",
+            "source": [{ "context": "src", "element": "dob" }],
+            "target": [{ "context": "tgt", "element": "birthDate" }]
+        }])))
+        .unwrap();
+        assert_eq!(
+            map.concept_maps(),
+            ["http://example.org/fhir/ConceptMap/legacy"]
+        );
+        let maps = HashMap::from([(
+            "http://example.org/fhir/ConceptMap/legacy".to_string(),
+            ConceptMapping::parse(&concept_map()).unwrap(),
+        )]);
+        let legacy = json!({
+            "resourceType": "LegacyPatient",
+            "sex": "F",
+            "names": [{ "surname": "Berg" }, { "surname": "Lind" }],
+            "dob": "1970-05-01",
+            "ignored": true
+        });
+        assert_eq!(
+            map.apply(&legacy, &maps).unwrap(),
+            json!({
+                "resourceType": "Patient",
+                "gender": "female",
+                "name": [
+                    { "family": "Berg", "use": "official" },
+                    { "family": "Lind", "use": "official" }
+                ],
+                "birthDate": "1970-05-01"
+            })
+        );
+
+        let error = map
+            .apply(&json!({ "resourceType": "Patient" }), &maps)
+            .unwrap_err();
+        assert_eq!(error.code, "invalid");
+        let error = map
+            .apply(
+                &json!({ "resourceType": "LegacyPatient", "sex": "Q" }),
+                &maps,
+            )
+            .unwrap_err();
+        assert_eq!(error.code, "code-invalid");
+        assert_eq!(
+            error.location.as_deref(),
+            Some("StructureMap.group[0].rule[0].target[0]")
+        );
+    }
+
+    #[test]
+    fn test_unsupported_structure_maps_are_rejected() {
+        for (rule, location) in [
+            (
+                json!({ "source": [{ "context": "src" }], "dependent": [{ "name": "other" }] }),
+                "StructureMap.group[0].rule[0].dependent",
+            ),
+            (
+                json!({ "source": [{ "context": "src", "condition": "true" }] }),
+                "StructureMap.group[0].rule[0].condition",
+            ),
+            (
+                json!({
+                    "source": [{ "context": "src", "element": "a", "variable": "a" }],
+                    "target": [{ "context": "tgt", "element": "b", "transform": "evaluate", "parameter": [{ "valueId": "a" }] }]
+                }),
+                "StructureMap.group[0].rule[0].target[0].transform",
+            ),
+        ] {
+            let error = StructureMapping::parse(&structure_map(json!([rule]))).unwrap_err();
+            assert_eq!(error.code, "not-supported");
+            assert_eq!(error.location.as_deref(), Some(location));
+        }
+    }
+}
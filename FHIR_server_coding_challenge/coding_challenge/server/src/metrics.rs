@@ -0,0 +1,291 @@
+//! Domain KPIs exposed in OpenMetrics text format at `GET /metrics`.
+//!
+//! Counters for events handled by this process live in [`Metrics`]; values
+//! derived from the store (patient totals, history depth) are queried on
+//! each scrape so they stay correct across restarts and replicas.
+
+use crate::db::ChangeCursor;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Bucket bounds for the number of stored versions per patient
+pub const HISTORY_DEPTH_BUCKETS: &[i64] = &[1, 2, 5, 10, 25, 50];
+
+/// Bucket bounds, in seconds, for the duration of background jobs
+pub const JOB_DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Process-local event counters
+#[derive(Debug, Default)]
+pub struct Metrics {
+    patients_created: AtomicU64,
+    patients_updated: AtomicU64,
+    patients_deleted: AtomicU64,
+    identifier_conflicts: AtomicU64,
+    /// Cursor of each open subscription channel, by connection
+    subscription_channels: Mutex<HashMap<Uuid, ChangeCursor>>,
+}
+
+impl Metrics {
+    pub fn patient_created(&self) {
+        self.patients_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn patient_updated(&self) {
+        self.patients_updated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn patient_deleted(&self) {
+        self.patients_deleted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn identifier_conflict(&self) {
+        self.identifier_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn channels(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, ChangeCursor>> {
+        self.subscription_channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Subscription channel `connection` has read the change feed up to
+    /// `cursor`
+    pub fn subscription_channel_at(&self, connection: Uuid, cursor: ChangeCursor) {
+        self.channels().insert(connection, cursor);
+    }
+
+    pub fn subscription_channel_closed(&self, connection: Uuid) {
+        self.channels().remove(&connection);
+    }
+
+    /// Number of open subscription channels and the cursor of the one
+    /// furthest behind
+    pub fn subscription_channels(&self) -> (usize, Option<ChangeCursor>) {
+        let channels = self.channels();
+        (channels.len(), channels.values().min().copied())
+    }
+
+    /// Append this process's counters to `out`
+    pub fn write_counters(&self, out: &mut OpenMetricsWriter) {
+        out.counter(
+            "fhir_patients_created",
+            "Patients created through this server",
+            self.patients_created.load(Ordering::Relaxed),
+        );
+        out.counter(
+            "fhir_patients_updated",
+            "Patient updates and patches applied through this server",
+            self.patients_updated.load(Ordering::Relaxed),
+        );
+        out.counter(
+            "fhir_patients_deleted",
+            "Patients deleted through this server",
+            self.patients_deleted.load(Ordering::Relaxed),
+        );
+        out.counter(
+            "fhir_identifier_conflicts",
+            "Writes rejected because a unique identifier was already taken",
+            self.identifier_conflicts.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Cumulative histogram as read from the store
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// (upper bound, observations <= bound), in ascending bound order
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// Histogram of values observed by this process
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations <= each bound, not cumulated
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulated = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(bound, count)| {
+                cumulated += count;
+                (*bound, cumulated)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count,
+            sum: self.sum,
+        }
+    }
+}
+
+/// Render `pairs` as the labels of a sample: `name="value",...`
+pub fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Builder for an OpenMetrics text exposition
+#[derive(Debug, Default)]
+pub struct OpenMetricsWriter {
+    out: String,
+}
+
+impl OpenMetricsWriter {
+    fn header(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.out, "# TYPE {} {}", name, metric_type);
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.out, "{}_total {}", name, value);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    /// A gauge with one sample per label set, rendered with [`labels`]
+    pub fn labeled_gauge(&mut self, name: &str, help: &str, samples: &[(String, f64)]) {
+        self.header(name, "gauge", help);
+        for (labels, value) in samples {
+            let _ = writeln!(self.out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &HistogramSnapshot) {
+        self.header(name, "histogram", help);
+        for (bound, count) in &histogram.buckets {
+            let _ = writeln!(self.out, "{}_bucket{{le=\"{:?}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(
+            self.out,
+            "{}_bucket{{le=\"+Inf\"}} {}",
+            name, histogram.count
+        );
+        let _ = writeln!(self.out, "{}_count {}", name, histogram.count);
+        let _ = writeln!(self.out, "{}_sum {}", name, histogram.sum);
+    }
+
+    /// Terminate the exposition with the mandatory `# EOF` marker
+    pub fn finish(mut self) -> String {
+        self.out.push_str("# EOF\n");
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_render_with_total_suffix() {
+        let metrics = Metrics::default();
+        metrics.patient_created();
+        metrics.patient_created();
+
+        let mut out = OpenMetricsWriter::default();
+        metrics.write_counters(&mut out);
+        let text = out.finish();
+
+        assert!(text.contains("# TYPE fhir_patients_created counter\n"));
+        assert!(text.contains("fhir_patients_created_total 2\n"));
+        assert!(text.contains("fhir_patients_updated_total 0\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_histogram_cumulates_buckets() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        for value in [0.5, 2.0, 3.0, 40.0] {
+            histogram.observe(value);
+        }
+
+        assert_eq!(
+            histogram.snapshot(),
+            HistogramSnapshot {
+                buckets: vec![(1.0, 1), (10.0, 3)],
+                count: 4,
+                sum: 45.5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_slowest_subscription_channel() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.subscription_channels(), (0, None));
+
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let behind = ChangeCursor::parse("10-1").unwrap();
+        metrics.subscription_channel_at(first, ChangeCursor::parse("12-3").unwrap());
+        metrics.subscription_channel_at(second, behind);
+        assert_eq!(metrics.subscription_channels(), (2, Some(behind)));
+
+        metrics.subscription_channel_closed(second);
+        assert_eq!(metrics.subscription_channels().0, 1);
+    }
+
+    #[test]
+    fn test_histogram_rendering() {
+        let mut out = OpenMetricsWriter::default();
+        out.histogram(
+            "fhir_patient_history_depth",
+            "Versions per patient",
+            &HistogramSnapshot {
+                buckets: vec![(1.0, 3), (5.0, 4)],
+                count: 5,
+                sum: 17.0,
+            },
+        );
+        let text = out.finish();
+
+        assert!(text.contains("fhir_patient_history_depth_bucket{le=\"1.0\"} 3\n"));
+        assert!(text.contains("fhir_patient_history_depth_bucket{le=\"+Inf\"} 5\n"));
+        assert!(text.contains("fhir_patient_history_depth_count 5\n"));
+        assert!(text.contains("fhir_patient_history_depth_sum 17\n"));
+    }
+}
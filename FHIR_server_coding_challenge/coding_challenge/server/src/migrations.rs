@@ -0,0 +1,462 @@
+//! Zero-downtime column changes, run with `fhir-server migrate`.
+//!
+//! Renaming or retyping a column in one migration breaks every server still
+//! running the previous release. A [`ColumnChange`] splits it into steps
+//! that each keep both releases working:
+//!
+//! 1. `expand` adds the new column and a trigger that fills it from the old
+//!    one on every write that does not set it, so old releases dual-write
+//!    without knowing
+//! 2. `backfill` fills it for existing rows, in batches
+//! 3. `switch` checks nothing is left to backfill and records that releases
+//!    built for `contract_version` may be rolled out; they read the new
+//!    column and keep writing both
+//! 4. `contract` drops the trigger and the old column
+//!
+//! Every server process records the schema version it was built for in
+//! `fhir.app_instance` and refreshes it while it runs. `contract` is refused
+//! while an instance older than `contract_version` was seen within
+//! [`INSTANCE_WINDOW`], as it might still read the old column.
+
+use crate::db::{Database, OldInstancesRunning, SchemaChangeRecord};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Latest migration this build knows; bump with every migration added
+pub const APP_SCHEMA_VERSION: i32 = 31;
+
+/// How often a running server refreshes its `fhir.app_instance` row
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Instances seen this recently might still be running
+pub const INSTANCE_WINDOW: Duration = Duration::from_secs(300);
+
+/// Rows filled per backfill statement
+const BACKFILL_BATCH: u32 = 1000;
+
+pub const USAGE: &str =
+    "usage: fhir-server migrate status | fhir-server migrate <expand|backfill|switch|contract> <plan.toml>";
+
+/// Phase of a column change, as stored in `fhir.schema_change`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Expanded,
+    Switched,
+    Contracted,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Expanded => "expanded",
+            Phase::Switched => "switched",
+            Phase::Contracted => "contracted",
+        }
+    }
+}
+
+/// A plan file: move `table.old_column` to `table.new_column`, filled with
+/// the SQL expression `backfill` over the row's columns
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ColumnChange {
+    pub name: String,
+    pub table: String,
+    pub old_column: String,
+    pub new_column: String,
+    pub column_type: String,
+    pub backfill: String,
+    /// First schema version that no longer reads `old_column`
+    pub contract_version: i32,
+}
+
+/// Whether `name` is a lower-case SQL identifier that needs no quoting
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+impl ColumnChange {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let change: Self =
+            toml::from_str(&content).with_context(|| format!("Invalid plan {}", path.display()))?;
+        change.validate()?;
+        Ok(change)
+    }
+
+    /// Names go into DDL unquoted, so only plain identifiers are accepted;
+    /// `backfill` is SQL by design and trusted like a migration file
+    fn validate(&self) -> Result<()> {
+        for (field, value) in [
+            ("name", &self.name),
+            ("old_column", &self.old_column),
+            ("new_column", &self.new_column),
+        ] {
+            if !is_identifier(value) {
+                anyhow::bail!("{} '{}' is not a lower-case SQL identifier", field, value);
+            }
+        }
+        if !self.table.split('.').all(is_identifier) || self.table.split('.').count() > 2 {
+            anyhow::bail!(
+                "table '{}' is not a (schema-qualified) identifier",
+                self.table
+            );
+        }
+        if self.old_column == self.new_column {
+            anyhow::bail!("old_column and new_column must differ");
+        }
+        let type_chars = |c: char| c.is_ascii_alphanumeric() || " _(),[]".contains(c);
+        if self.column_type.is_empty() || !self.column_type.chars().all(type_chars) {
+            anyhow::bail!("column_type '{}' is not a type name", self.column_type);
+        }
+        if self.backfill.trim().is_empty() || self.backfill.contains(';') {
+            anyhow::bail!("backfill must be a single SQL expression");
+        }
+        Ok(())
+    }
+
+    fn trigger(&self) -> String {
+        format!("{}_dual_write", self.name)
+    }
+
+    fn record(&self, phase: Phase) -> SchemaChangeRecord {
+        SchemaChangeRecord {
+            name: self.name.clone(),
+            table: self.table.clone(),
+            old_column: self.old_column.clone(),
+            new_column: self.new_column.clone(),
+            phase: phase.as_str().to_string(),
+            contract_version: self.contract_version,
+            updated_at: None,
+        }
+    }
+
+    /// Add the new column and the trigger keeping it filled on writes of
+    /// releases that only know the old one
+    pub fn expand_sql(&self) -> Vec<String> {
+        let trigger = self.trigger();
+        vec![
+            format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                self.table, self.new_column, self.column_type
+            ),
+            format!(
+                "CREATE OR REPLACE FUNCTION fhir.{trigger}() RETURNS TRIGGER AS $$
+                 BEGIN
+                     IF (TG_OP = 'INSERT' AND NEW.{new} IS NULL)
+                        OR (TG_OP = 'UPDATE' AND NEW.{old} IS DISTINCT FROM OLD.{old}
+                            AND NEW.{new} IS NOT DISTINCT FROM OLD.{new}) THEN
+                         NEW.{new} := (SELECT {expr} FROM (SELECT NEW.*) AS src);
+                     END IF;
+                     RETURN NEW;
+                 END;
+                 $$ LANGUAGE plpgsql",
+                trigger = trigger,
+                old = self.old_column,
+                new = self.new_column,
+                expr = self.backfill
+            ),
+            format!("DROP TRIGGER IF EXISTS {} ON {}", trigger, self.table),
+            format!(
+                "CREATE TRIGGER {trigger} BEFORE INSERT OR UPDATE ON {table}
+                 FOR EACH ROW EXECUTE FUNCTION fhir.{trigger}()",
+                trigger = trigger,
+                table = self.table
+            ),
+        ]
+    }
+
+    /// Rows the backfill still has to fill
+    fn pending_condition(&self) -> String {
+        format!(
+            "{} IS NULL AND {} IS NOT NULL AND ({}) IS NOT NULL",
+            self.new_column, self.old_column, self.backfill
+        )
+    }
+
+    pub fn pending_sql(&self) -> String {
+        format!(
+            "SELECT COUNT(*) AS pending FROM {} WHERE {}",
+            self.table,
+            self.pending_condition()
+        )
+    }
+
+    /// Fill up to `batch` rows, skipping rows locked by running writes
+    pub fn backfill_sql(&self, batch: u32) -> String {
+        format!(
+            "UPDATE {table} SET {new} = {expr}
+             WHERE ctid IN (
+                 SELECT ctid FROM {table} WHERE {pending} LIMIT {batch} FOR UPDATE SKIP LOCKED
+             )",
+            table = self.table,
+            new = self.new_column,
+            expr = self.backfill,
+            pending = self.pending_condition(),
+            batch = batch
+        )
+    }
+
+    pub fn contract_sql(&self) -> Vec<String> {
+        let trigger = self.trigger();
+        vec![
+            format!("DROP TRIGGER IF EXISTS {} ON {}", trigger, self.table),
+            format!("DROP FUNCTION IF EXISTS fhir.{}()", trigger),
+            format!(
+                "ALTER TABLE {} DROP COLUMN IF EXISTS {}",
+                self.table, self.old_column
+            ),
+        ]
+    }
+
+    async fn phase(&self, db: &Database) -> Result<Option<String>> {
+        Ok(db
+            .schema_change(&self.name)
+            .await?
+            .map(|record| record.phase))
+    }
+
+    /// Step 1; may be repeated until the change is switched
+    pub async fn expand(&self, db: &Database) -> Result<()> {
+        match self.phase(db).await?.as_deref() {
+            None | Some("expanded") => {}
+            Some(phase) => anyhow::bail!("{} is already {}", self.name, phase),
+        }
+        db.apply_schema_change(&self.record(Phase::Expanded), &self.expand_sql(), None)
+            .await
+    }
+
+    /// Step 2: fill existing rows batch by batch, calling `progress` with
+    /// the running total; returns the rows filled
+    pub async fn backfill(&self, db: &Database, mut progress: impl FnMut(u64)) -> Result<u64> {
+        if self.phase(db).await?.as_deref() != Some("expanded") {
+            anyhow::bail!("{} must be expanded before the backfill", self.name);
+        }
+        let sql = self.backfill_sql(BACKFILL_BATCH);
+        let mut filled = 0;
+        loop {
+            let batch = db.execute_statement(&sql).await?;
+            filled += batch;
+            progress(filled);
+            if batch == 0 {
+                return Ok(filled);
+            }
+        }
+    }
+
+    /// Step 3
+    pub async fn switch(&self, db: &Database) -> Result<()> {
+        if self.phase(db).await?.as_deref() != Some("expanded") {
+            anyhow::bail!("{} must be expanded before the switch", self.name);
+        }
+        let pending = db.count_pending(&self.pending_sql()).await?;
+        if pending > 0 {
+            anyhow::bail!("{} rows still need the backfill", pending);
+        }
+        db.apply_schema_change(&self.record(Phase::Switched), &[], None)
+            .await
+    }
+
+    /// Step 4; fails with [`OldInstancesRunning`] while old releases might
+    /// still run
+    pub async fn contract(&self, db: &Database) -> Result<()> {
+        if self.phase(db).await?.as_deref() != Some("switched") {
+            anyhow::bail!("{} must be switched before the contract", self.name);
+        }
+        db.apply_schema_change(
+            &self.record(Phase::Contracted),
+            &self.contract_sql(),
+            Some(INSTANCE_WINDOW),
+        )
+        .await
+    }
+}
+
+/// Keep this process's row in `fhir.app_instance` fresh
+pub fn spawn_heartbeat(db: Arc<Database>) {
+    if !db.schema_changes_enabled() {
+        return;
+    }
+    let id = Uuid::new_v4();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = db.touch_instance(id, APP_SCHEMA_VERSION).await {
+                tracing::warn!("Failed to record this instance in fhir.app_instance: {}", e);
+            }
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        }
+    });
+}
+
+/// `fhir-server migrate ...`, with the arguments after `migrate`; prints
+/// what it did
+pub async fn run_command(db: &Database, args: &[String]) -> Result<()> {
+    if !db.table_exists("fhir.schema_change").await? {
+        anyhow::bail!("fhir.schema_change not found, run 013_schema_changes.sql");
+    }
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["status"] => {
+            for record in db.schema_changes().await? {
+                println!(
+                    "{}: {}.{} -> {} {} (contract at schema {})",
+                    record.name,
+                    record.table,
+                    record.old_column,
+                    record.new_column,
+                    record.phase,
+                    record.contract_version
+                );
+            }
+            for instance in db.live_instances(INSTANCE_WINDOW).await? {
+                println!(
+                    "instance {}: schema {}, seen {}",
+                    instance.id, instance.schema_version, instance.seen_at
+                );
+            }
+            Ok(())
+        }
+        [step, plan] => {
+            let change = ColumnChange::load(Path::new(plan))?;
+            match *step {
+                "expand" => change.expand(db).await?,
+                "backfill" => {
+                    let filled = change
+                        .backfill(db, |filled| {
+                            println!("{}: {} rows filled", change.name, filled)
+                        })
+                        .await?;
+                    println!("{}: backfill complete, {} rows", change.name, filled);
+                }
+                "switch" => change.switch(db).await?,
+                "contract" => match change.contract(db).await {
+                    Err(e) if e.downcast_ref::<OldInstancesRunning>().is_some() => {
+                        return Err(e.context(format!(
+                            "Refusing to drop {}.{}; retry once they are stopped",
+                            change.table, change.old_column
+                        )))
+                    }
+                    outcome => outcome?,
+                },
+                _ => anyhow::bail!("{}", USAGE),
+            }
+            println!("{}: {} done", change.name, step);
+            Ok(())
+        }
+        _ => anyhow::bail!("{}", USAGE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+
+    fn change(table: &str) -> ColumnChange {
+        ColumnChange {
+            name: format!("{}_family", table.replace('.', "_")),
+            table: table.to_string(),
+            old_column: "family".to_string(),
+            new_column: "family_lower".to_string(),
+            column_type: "TEXT".to_string(),
+            backfill: "lower(family)".to_string(),
+            contract_version: APP_SCHEMA_VERSION,
+        }
+    }
+
+    #[test]
+    fn test_plans_only_take_identifiers() {
+        assert!(change("fhir.patient").validate().is_ok());
+        let mut bad = change("fhir.patient; DROP TABLE fhir_resources");
+        bad.name = "ok".to_string();
+        assert!(bad.validate().is_err());
+        let mut bad = change("fhir.patient");
+        bad.new_column = "Family".to_string();
+        assert!(bad.validate().is_err());
+        let mut bad = change("fhir.patient");
+        bad.backfill = "lower(family); DROP TABLE fhir_resources".to_string();
+        assert!(bad.validate().is_err());
+        assert!(ColumnChange {
+            new_column: "family".to_string(),
+            ..change("fhir.patient")
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expand_backfill_switch_contract() {
+        let db = setup_test_db().await;
+        let table = format!("fhir.ec_test_{}", Uuid::new_v4().simple());
+        db.execute_statement(&format!("CREATE TABLE {} (id SERIAL, family TEXT)", table))
+            .await
+            .unwrap();
+        db.execute_statement(&format!(
+            "INSERT INTO {} (family) VALUES ('SMITH'), (NULL)",
+            table
+        ))
+        .await
+        .unwrap();
+        let change = change(&table);
+
+        change.expand(&db).await.unwrap();
+        // A release that only knows the old column still fills the new one
+        db.execute_statement(&format!("INSERT INTO {} (family) VALUES ('JONES')", table))
+            .await
+            .unwrap();
+        assert!(change.switch(&db).await.is_err());
+        assert!(change.contract(&db).await.is_err());
+
+        assert_eq!(change.backfill(&db, |_| {}).await.unwrap(), 1);
+        assert_eq!(db.count_pending(&change.pending_sql()).await.unwrap(), 0);
+        change.switch(&db).await.unwrap();
+        assert!(change.expand(&db).await.is_err());
+
+        let old = Uuid::new_v4();
+        db.touch_instance(old, APP_SCHEMA_VERSION - 1)
+            .await
+            .unwrap();
+        let err = change.contract(&db).await.unwrap_err();
+        let running = err.downcast_ref::<OldInstancesRunning>().unwrap();
+        assert!(running.instances.iter().any(|i| i.id == old));
+        assert!(db.column_exists(&table, "family").await.unwrap());
+
+        db.execute_statement(&format!(
+            "DELETE FROM fhir.app_instance WHERE instance_id = '{}'",
+            old
+        ))
+        .await
+        .unwrap();
+        change.contract(&db).await.unwrap();
+        assert!(!db.column_exists(&table, "family").await.unwrap());
+        let pending = db
+            .count_pending(&format!(
+                "SELECT COUNT(*) AS pending FROM {} WHERE family_lower IN ('smith', 'jones')",
+                table
+            ))
+            .await
+            .unwrap();
+        assert_eq!(pending, 2);
+        assert_eq!(
+            db.schema_change(&change.name).await.unwrap().unwrap().phase,
+            "contracted"
+        );
+
+        db.execute_statement(&format!("DROP TABLE {}", table))
+            .await
+            .unwrap();
+        db.execute_statement(&format!(
+            "DELETE FROM fhir.schema_change WHERE name = '{}'",
+            change.name
+        ))
+        .await
+        .unwrap();
+    }
+}
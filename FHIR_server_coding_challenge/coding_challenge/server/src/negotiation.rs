@@ -0,0 +1,1020 @@
+//! Content negotiation between FHIR JSON and FHIR XML.
+//!
+//! Handlers speak JSON only. Request bodies sent as XML (`Content-Type:
+//! application/fhir+xml`, `application/xml` or `text/xml`) are converted to
+//! JSON before they reach a handler, and JSON responses are converted to
+//! XML when the `Accept` header prefers it. Only the types in
+//! [`XML_RESOURCE_TYPES`] have an XML form: other XML bodies are rejected
+//! with `415`, and other responses stay JSON, or are answered with `406`
+//! when the client does not accept JSON at all.
+//!
+//! A `_format` query parameter (`json`, `xml` or a media type) takes the
+//! place of `Accept`, for clients that cannot set headers. It is removed
+//! from the URI before routing, so handlers never see it.
+//!
+//! `_format=ndjson` (or an NDJSON media type) asks for
+//! `application/fhir+ndjson`. It stands in for that `Accept` value, and
+//! only handlers that stream NDJSON, such as instance history, can honor
+//! it; any other successful response is answered with `406`.
+//!
+//! Handlers speak FHIR R4 only. The `fhirVersion` parameter of
+//! `Content-Type` and `Accept` (`application/fhir+json; fhirVersion=5.0`)
+//! selects R5 instead for the types in
+//! [`R5_RESOURCE_TYPES`](crate::models::r5::R5_RESOURCE_TYPES), converted by
+//! [`crate::models::r5`]: R5 bodies are converted to R4 before they reach
+//! a handler, and responses to R5 when `Accept` prefers it, with the
+//! parameter on their `Content-Type`. Other types are refused and answered
+//! as with XML; a version the server does not speak is a `415` or `406`.
+
+use crate::limits::{is_length_limit, unreadable_body, MAX_DEPTH_LIMIT};
+use crate::models::r5::{to_r4, to_r5, FhirVersion};
+use crate::models::xml::{from_xml_with_max_depth, to_xml, XML_RESOURCE_TYPES};
+use crate::models::OperationOutcome;
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, response::Parts, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::Value;
+
+pub const FHIR_XML: &str = "application/fhir+xml";
+pub const FHIR_NDJSON: &str = "application/fhir+ndjson";
+
+/// Largest XML or R5 request body converted; more is answered with `413`
+const MAX_CONVERTED_BODY: usize = 4 * 1024 * 1024;
+
+const FORMAT_PARAMETER: &str = "_format";
+
+const XML_TYPES: &[&str] = &[FHIR_XML, "application/xml", "text/xml"];
+const NDJSON_TYPES: &[&str] = &[FHIR_NDJSON, "application/ndjson", "application/x-ndjson"];
+const JSON_TYPES: &[&str] = &[
+    "application/fhir+json",
+    "application/json",
+    "application/*",
+    "*/*",
+];
+
+/// Representations that can be negotiated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Xml,
+    Ndjson,
+}
+
+/// What the `Accept` header asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Preference {
+    format: Format,
+    /// Whether JSON is acceptable, as the fallback for types without XML
+    accepts_json: bool,
+}
+
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// The `q` of one range of an `Accept` header, 1 if it has none
+fn quality(range: &str) -> f32 {
+    range
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0)
+}
+
+/// Whether `Accept` (or `_format`, which the middleware puts in its place)
+/// accepts NDJSON
+pub fn accepts_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| NDJSON_TYPES.contains(&media_type(range).as_str()) && quality(range) > 0.0)
+}
+
+/// Every `Accept` header, as one list of ranges
+fn accept_ranges(headers: &HeaderMap) -> String {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// XML is preferred if it has a higher `q` than any JSON type (or `*/*`),
+/// or the same `q` and is listed first; without `Accept` the server
+/// answers JSON
+fn preference(headers: &HeaderMap) -> Preference {
+    let json = Preference {
+        format: Format::Json,
+        accepts_json: true,
+    };
+    let accept = accept_ranges(headers);
+    if accept.trim().is_empty() {
+        return json;
+    }
+
+    // Best (q, negated position) seen for each format
+    let mut xml_best: Option<(f32, isize)> = None;
+    let mut json_best: Option<(f32, isize)> = None;
+    for (position, range) in accept.split(',').enumerate() {
+        let media = media_type(range);
+        let q = quality(range);
+        let best = if XML_TYPES.contains(&media.as_str()) {
+            &mut xml_best
+        } else if JSON_TYPES.contains(&media.as_str()) {
+            &mut json_best
+        } else {
+            continue;
+        };
+        let candidate = (q, -(position as isize));
+        if best.is_none_or(|b| candidate.0 > b.0) {
+            *best = Some(candidate);
+        }
+    }
+
+    let accepts_json = json_best.is_some_and(|(q, _)| q > 0.0);
+    match xml_best {
+        Some(xml) if xml.0 > 0.0 && json_best.is_none_or(|json| xml > json) => Preference {
+            format: Format::Xml,
+            accepts_json,
+        },
+        // Only other types listed, e.g. `text/html`: answer JSON as before
+        _ => json,
+    }
+}
+
+/// Which FHIR version `Accept` asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VersionPreference {
+    version: FhirVersion,
+    /// Whether R4 is acceptable, as the fallback for types without R5
+    accepts_r4: bool,
+}
+
+/// The `fhirVersion` parameter of a media type or range, or the value if
+/// it names no version the server speaks
+fn fhir_version(value: &str) -> Option<Result<FhirVersion, String>> {
+    value.split(';').skip(1).find_map(|param| {
+        let (name, version) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("fhirVersion")
+            .then(|| FhirVersion::parse(version).ok_or_else(|| version.trim().to_string()))
+    })
+}
+
+/// R5 is preferred as XML is: by a higher `q`, or the same `q` and an
+/// earlier place. Ranges without `fhirVersion` ask for R4, the server's
+/// default; an `Accept` whose FHIR ranges only name versions the server
+/// does not speak is an error.
+fn version_preference(headers: &HeaderMap) -> Result<VersionPreference, String> {
+    let r4 = VersionPreference {
+        version: FhirVersion::R4,
+        accepts_r4: true,
+    };
+
+    // Best (q, negated position) seen for each version
+    let mut r4_best: Option<(f32, isize)> = None;
+    let mut r5_best: Option<(f32, isize)> = None;
+    let mut unsupported = None;
+    for (position, range) in accept_ranges(headers).split(',').enumerate() {
+        let media = media_type(range);
+        if ![XML_TYPES, JSON_TYPES, NDJSON_TYPES]
+            .iter()
+            .any(|types| types.contains(&media.as_str()))
+        {
+            continue;
+        }
+        let best = match fhir_version(range) {
+            None | Some(Ok(FhirVersion::R4)) => &mut r4_best,
+            Some(Ok(FhirVersion::R5)) => &mut r5_best,
+            Some(Err(version)) => {
+                unsupported = Some(version);
+                continue;
+            }
+        };
+        let candidate = (quality(range), -(position as isize));
+        if best.is_none_or(|b| candidate.0 > b.0) {
+            *best = Some(candidate);
+        }
+    }
+
+    if let (None, None, Some(version)) = (r4_best, r5_best, unsupported) {
+        return Err(format!(
+            "fhirVersion {} is not supported; this server speaks {} and {}",
+            version,
+            FhirVersion::R4.as_str(),
+            FhirVersion::R5.as_str()
+        ));
+    }
+    match r5_best {
+        Some(r5) if r5.0 > 0.0 && r4_best.is_none_or(|r4| r5 > r4) => Ok(VersionPreference {
+            version: FhirVersion::R5,
+            accepts_r4: r4_best.is_some_and(|(q, _)| q > 0.0),
+        }),
+        _ => Ok(r4),
+    }
+}
+
+/// The FHIR version of a request body, from its `Content-Type`
+fn body_version(headers: &HeaderMap) -> Result<FhirVersion, String> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    match fhir_version(content_type) {
+        None => Ok(FhirVersion::R4),
+        Some(Ok(version)) => Ok(version),
+        Some(Err(version)) => Err(format!(
+            "Request bodies of fhirVersion {} are not supported; this server speaks {} and {}",
+            version,
+            FhirVersion::R4.as_str(),
+            FhirVersion::R5.as_str()
+        )),
+    }
+}
+
+/// `media` marked with `version` unless it is R4, the default
+fn versioned_content_type(media: &'static str, version: FhirVersion) -> HeaderValue {
+    match version {
+        FhirVersion::R4 => HeaderValue::from_static(media),
+        FhirVersion::R5 => format!("{}; fhirVersion={}", media, version.as_str())
+            .parse()
+            .unwrap_or(HeaderValue::from_static(media)),
+    }
+}
+
+/// The version a response's `Content-Type` is marked with
+fn response_version(headers: &HeaderMap) -> FhirVersion {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(fhir_version)
+        .and_then(Result::ok)
+        .unwrap_or_default()
+}
+
+/// The format named by `_format` and the URI without it, if present.
+/// An exact format is asked for, so types without XML are refused with 406
+fn format_override(uri: &Uri) -> Result<Option<(Preference, Uri)>, String> {
+    let Some(query) = uri.query() else {
+        return Ok(None);
+    };
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+    let Some((_, value)) = pairs.iter().find(|(name, _)| name == FORMAT_PARAMETER) else {
+        return Ok(None);
+    };
+
+    // An unescaped `+` in `application/fhir+xml` arrives as a space
+    let media = media_type(&value.replace(' ', "+"));
+    let format = match media.as_str() {
+        "json" => Format::Json,
+        "xml" => Format::Xml,
+        "ndjson" => Format::Ndjson,
+        media if XML_TYPES.contains(&media) => Format::Xml,
+        media if NDJSON_TYPES.contains(&media) => Format::Ndjson,
+        media if JSON_TYPES.contains(&media) => Format::Json,
+        _ => {
+            return Err(format!(
+            "Unsupported _format '{}'; expected json, xml, ndjson, application/fhir+json, {} or {}",
+            value, FHIR_XML, FHIR_NDJSON
+        ))
+        }
+    };
+
+    let uri = without_parameter(uri, FORMAT_PARAMETER);
+    let preference = Preference {
+        format,
+        accepts_json: format == Format::Json,
+    };
+    Ok(Some((preference, uri)))
+}
+
+/// `uri` without any `name=...` pairs in its query
+pub(crate) fn without_parameter(uri: &Uri, name: &str) -> Uri {
+    let rest: Vec<&str> = uri
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some(name))
+        .collect();
+    let path_and_query = if rest.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), rest.join("&"))
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+fn has_xml_body(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| XML_TYPES.contains(&media_type(value).as_str()))
+}
+
+pub(crate) fn has_json_body(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(media_type)
+        .is_some_and(|media| media == "application/json" || media == "application/fhir+json")
+}
+
+/// Refuse a successful response that is not NDJSON when only NDJSON was
+/// asked for
+fn ndjson_response(response: Response) -> Response {
+    let is_ndjson = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| NDJSON_TYPES.contains(&media_type(value).as_str()));
+    if is_ndjson || !response.status().is_success() {
+        return response;
+    }
+    error(
+        StatusCode::NOT_ACCEPTABLE,
+        "not-supported",
+        format!(
+            "This response is not available as {}; NDJSON is supported for instance history",
+            FHIR_NDJSON
+        ),
+    )
+}
+
+fn error(status: StatusCode, code: &str, message: String) -> Response {
+    (status, Json(OperationOutcome::error(code, message))).into_response()
+}
+
+/// Convert an XML request body to JSON, or the error to answer with
+async fn json_request(request: Request) -> Result<Request, Response> {
+    let (mut parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_CONVERTED_BODY).await.map_err(|e| {
+        if !is_length_limit(&e) {
+            return unreadable_body(e).into_response();
+        }
+        error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too-costly",
+            format!(
+                "XML request bodies are limited to {} bytes",
+                MAX_CONVERTED_BODY
+            ),
+        )
+    })?;
+    let text = std::str::from_utf8(&bytes).map_err(|_| {
+        error(
+            StatusCode::BAD_REQUEST,
+            "structure",
+            "XML request body is not valid UTF-8".to_string(),
+        )
+    })?;
+    // Bounded like JSON bodies, before the resource limits see the result
+    let resource = from_xml_with_max_depth(text, MAX_DEPTH_LIMIT)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, "structure", e.to_string()))?;
+
+    let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+    if !XML_RESOURCE_TYPES.contains(&resource_type) {
+        return Err(error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "not-supported",
+            format!(
+                "{} cannot be sent as XML; XML is supported for {}",
+                resource_type,
+                XML_RESOURCE_TYPES.join(", ")
+            ),
+        ));
+    }
+
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/fhir+json"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(resource.to_string())))
+}
+
+/// Convert an R5 JSON request body to R4, or the error to answer with.
+/// Bodies that are not JSON are left for the handler to refuse.
+async fn r4_request(request: Request) -> Result<Request, Response> {
+    let (mut parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_CONVERTED_BODY).await.map_err(|e| {
+        if !is_length_limit(&e) {
+            return unreadable_body(e).into_response();
+        }
+        error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too-costly",
+            format!(
+                "FHIR 5.0 request bodies are limited to {} bytes",
+                MAX_CONVERTED_BODY
+            ),
+        )
+    })?;
+    let Ok(resource) = serde_json::from_slice::<Value>(&bytes) else {
+        return Ok(Request::from_parts(parts, Body::from(bytes)));
+    };
+    let resource = to_r4(&resource).map_err(|e| {
+        error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "not-supported",
+            e.to_string(),
+        )
+    })?;
+
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/fhir+json"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(resource.to_string())))
+}
+
+/// Convert a JSON response to R5; types without an R5 form stay R4 if the
+/// client accepts it
+async fn r5_response(response: Response, accepts_r4: bool) -> Response {
+    if !has_json_body(response.headers()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Failed to read response body for FHIR 5.0: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let Ok(resource) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    let converted = match to_r5(&resource) {
+        Ok(converted) => converted,
+        Err(_) if accepts_r4 => return Response::from_parts(parts, Body::from(bytes)),
+        Err(e) => {
+            // Headers such as the request id stay; those of the withheld resource go
+            parts.status = StatusCode::NOT_ACCEPTABLE;
+            for name in [header::ETAG, header::LAST_MODIFIED, header::LOCATION] {
+                parts.headers.remove(name);
+            }
+            let outcome = OperationOutcome::error("not-supported", e.to_string());
+            serde_json::to_value(outcome).unwrap_or_default()
+        }
+    };
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        versioned_content_type("application/fhir+json", FhirVersion::R5),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(converted.to_string()))
+}
+
+/// Convert a JSON response to XML; types without an XML form stay JSON if
+/// the client accepts it
+async fn xml_response(response: Response, accepts_json: bool) -> Response {
+    if !has_json_body(response.headers()) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Failed to read response body for XML: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let resource = serde_json::from_slice::<Value>(&bytes).ok();
+    let resource_type = resource
+        .as_ref()
+        .and_then(|r| r["resourceType"].as_str())
+        .unwrap_or_default();
+
+    if !XML_RESOURCE_TYPES.contains(&resource_type) {
+        if accepts_json || resource.is_none() {
+            return Response::from_parts(parts, Body::from(bytes));
+        }
+        let outcome = OperationOutcome::error(
+            "not-supported",
+            format!(
+                "{} is only available as application/fhir+json; XML is supported for {}",
+                resource_type,
+                XML_RESOURCE_TYPES.join(", ")
+            ),
+        );
+        let outcome = serde_json::to_value(outcome).unwrap_or_default();
+        // Headers such as the request id stay; those of the withheld resource go
+        let mut parts = parts;
+        parts.status = StatusCode::NOT_ACCEPTABLE;
+        for name in [header::ETAG, header::LAST_MODIFIED, header::LOCATION] {
+            parts.headers.remove(name);
+        }
+        return xml_body(parts, &outcome);
+    }
+
+    xml_body(parts, &resource.unwrap_or_default())
+}
+
+/// `resource` as the XML body of a response with `parts`
+fn xml_body(mut parts: Parts, resource: &Value) -> Response {
+    match to_xml(resource) {
+        Ok(xml) => {
+            let version = response_version(&parts.headers);
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                versioned_content_type(FHIR_XML, version),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(xml))
+        }
+        Err(e) => {
+            tracing::warn!("Failed to convert response to XML: {}", e);
+            Response::from_parts(parts, Body::from(resource.to_string()))
+        }
+    }
+}
+
+/// Accept XML and R5 request bodies and answer in XML or R5 when `Accept`
+/// prefers it
+pub async fn negotiate_format(mut request: Request, next: Next) -> Response {
+    let version = match version_preference(request.headers()) {
+        Ok(version) => version,
+        Err(message) => return error(StatusCode::NOT_ACCEPTABLE, "not-supported", message),
+    };
+    let body_version = match body_version(request.headers()) {
+        Ok(version) => version,
+        Err(message) => return error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "not-supported", message),
+    };
+    let preference = match format_override(request.uri()) {
+        Ok(Some((preference, uri))) => {
+            *request.uri_mut() = uri;
+            if preference.format == Format::Ndjson {
+                request
+                    .headers_mut()
+                    .insert(header::ACCEPT, HeaderValue::from_static(FHIR_NDJSON));
+            }
+            preference
+        }
+        Ok(None) => preference(request.headers()),
+        Err(message) => return error(StatusCode::BAD_REQUEST, "invalid", message),
+    };
+
+    let request = if has_xml_body(request.headers()) {
+        json_request(request).await
+    } else {
+        Ok(request)
+    };
+    let request = match request {
+        Ok(request) if body_version == FhirVersion::R5 => r4_request(request).await,
+        other => other,
+    };
+    let response = match request {
+        Ok(request) => next.run(request).await,
+        Err(response) => response,
+    };
+    let response = match version.version {
+        FhirVersion::R4 => response,
+        FhirVersion::R5 => r5_response(response, version.accepts_r4).await,
+    };
+
+    match preference.format {
+        Format::Json => response,
+        Format::Xml => xml_response(response, preference.accepts_json).await,
+        Format::Ndjson => ndjson_response(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, SharedConfig};
+    use crate::db::tests::setup_test_db;
+    use crate::models::xml::from_xml;
+    use crate::state::AppState;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn app() -> axum::Router {
+        crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(ServerConfig::default())),
+        ))
+    }
+
+    async fn send(app: &axum::Router, request: Request) -> (StatusCode, HeaderMap, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn accept(value: &str) -> Preference {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        preference(&headers)
+    }
+
+    fn format(uri: &str) -> Result<Option<(Format, String)>, String> {
+        let uri: Uri = uri.parse().unwrap();
+        format_override(&uri).map(|o| o.map(|(p, uri)| (p.format, uri.to_string())))
+    }
+
+    #[tokio::test]
+    async fn test_deeply_nested_xml_is_refused() {
+        let depth = 200_000;
+        let xml = format!(
+            "<Patient xmlns=\"http://hl7.org/fhir\">{}{}</Patient>",
+            "<a>".repeat(depth),
+            "</a>".repeat(depth)
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/fhir/Patient")
+            .header(header::CONTENT_TYPE, FHIR_XML)
+            .body(Body::from(xml))
+            .unwrap();
+
+        let response = json_request(request).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let outcome: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(outcome["issue"][0]["code"], "structure");
+    }
+
+    #[test]
+    fn test_format_parameter_is_taken_from_the_query() {
+        assert_eq!(format("/fhir/Patient?name=x"), Ok(None));
+        assert_eq!(
+            format("/fhir/Patient?_format=xml&name=x"),
+            Ok(Some((Format::Xml, "/fhir/Patient?name=x".to_string())))
+        );
+        assert_eq!(
+            format("/fhir/Patient/1?_format=application/fhir+xml"),
+            Ok(Some((Format::Xml, "/fhir/Patient/1".to_string())))
+        );
+        assert_eq!(
+            format("/fhir/metadata?_format=application%2Ffhir%2Bjson"),
+            Ok(Some((Format::Json, "/fhir/metadata".to_string())))
+        );
+        assert_eq!(
+            format("/fhir/Patient/1/_history?_format=ndjson"),
+            Ok(Some((
+                Format::Ndjson,
+                "/fhir/Patient/1/_history".to_string()
+            )))
+        );
+        assert_eq!(
+            format("/fhir/Patient/1/_history?_format=application/x-ndjson")
+                .map(|o| o.map(|(format, _)| format)),
+            Ok(Some(Format::Ndjson))
+        );
+        assert!(format("/fhir/Patient?_format=turtle").is_err());
+    }
+
+    #[test]
+    fn test_preference_follows_accept_quality() {
+        assert_eq!(preference(&HeaderMap::new()).format, Format::Json);
+        assert_eq!(accept("application/fhir+json").format, Format::Json);
+        assert_eq!(accept("text/html").format, Format::Json);
+
+        let xml = accept("application/fhir+xml");
+        assert_eq!(xml.format, Format::Xml);
+        assert!(!xml.accepts_json);
+
+        // Ties go to the type listed first
+        assert_eq!(
+            accept("application/xml, application/json").format,
+            Format::Xml
+        );
+        assert_eq!(
+            accept("application/json, application/xml").format,
+            Format::Json
+        );
+        assert_eq!(
+            accept("application/fhir+json;q=0.5, application/fhir+xml;q=0.9").format,
+            Format::Xml
+        );
+        assert_eq!(accept("text/xml;q=0.2, */*;q=0.8").format, Format::Json);
+        assert_eq!(accept("application/fhir+xml;q=0").format, Format::Json);
+
+        let fallback = accept("application/fhir+xml, */*;q=0.1");
+        assert_eq!(fallback.format, Format::Xml);
+        assert!(fallback.accepts_json);
+    }
+
+    #[test]
+    fn test_fhir_version_follows_accept_and_content_type() {
+        let version = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, value.parse().unwrap());
+            version_preference(&headers)
+        };
+        let r4 = VersionPreference {
+            version: FhirVersion::R4,
+            accepts_r4: true,
+        };
+        assert_eq!(version_preference(&HeaderMap::new()), Ok(r4));
+        assert_eq!(version("application/fhir+json"), Ok(r4));
+        assert_eq!(version("application/fhir+json; fhirVersion=4.0.1"), Ok(r4));
+        assert_eq!(
+            version("application/fhir+json; fhirVersion=5.0"),
+            Ok(VersionPreference {
+                version: FhirVersion::R5,
+                accepts_r4: false,
+            })
+        );
+        assert_eq!(
+            version("application/fhir+xml;fhirVersion=5.0, application/fhir+json;q=0.5"),
+            Ok(VersionPreference {
+                version: FhirVersion::R5,
+                accepts_r4: true,
+            })
+        );
+        assert_eq!(
+            version("application/fhir+json, application/fhir+json;fhirVersion=5.0"),
+            Ok(r4)
+        );
+        assert_eq!(
+            version("application/fhir+json;fhirVersion=3.0, application/json"),
+            Ok(r4)
+        );
+        assert!(version("application/fhir+json;fhirVersion=3.0").is_err());
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(body_version(&headers), Ok(FhirVersion::R4));
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/fhir+json; fhirVersion=5.0".parse().unwrap(),
+        );
+        assert_eq!(body_version(&headers), Ok(FhirVersion::R5));
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/fhir+json; fhirVersion=6.0".parse().unwrap(),
+        );
+        assert!(body_version(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_patients_are_exchanged_as_r5() {
+        let app = app().await;
+        let r5 = "application/fhir+json; fhirVersion=5.0";
+        let patient = serde_json::json!({
+            "resourceType": "Patient",
+            "contact": [{
+                "name": { "family": "Lind" },
+                "additionalName": [{ "family": "Berg-Lind" }]
+            }]
+        });
+        let create = Request::post("/fhir/Patient")
+            .header(header::CONTENT_TYPE, r5)
+            .header(header::ACCEPT, r5)
+            .body(Body::from(patient.to_string()))
+            .unwrap();
+        let (status, headers, body) = send(&app, create).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(headers[header::CONTENT_TYPE], r5);
+        let created: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            created["contact"][0]["additionalName"][0]["family"],
+            "Berg-Lind"
+        );
+        let id = created["id"].as_str().unwrap();
+
+        // R4 clients see the cross-version extension
+        let read = Request::get(format!("/fhir/Patient/{}", id))
+            .body(Body::empty())
+            .unwrap();
+        let (_, headers, body) = send(&app, read).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/fhir+json");
+        let r4: Value = serde_json::from_str(&body).unwrap();
+        assert!(r4["contact"][0].get("additionalName").is_none());
+        assert_eq!(
+            r4["contact"][0]["extension"][0]["valueHumanName"]["family"],
+            "Berg-Lind"
+        );
+
+        let read = Request::get(format!("/fhir/Patient/{}", id))
+            .header(header::ACCEPT, "application/fhir+xml; fhirVersion=5.0")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, read).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_TYPE],
+            "application/fhir+xml; fhirVersion=5.0"
+        );
+        assert!(body.contains("<additionalName><family value=\"Berg-Lind\"/></additionalName>"));
+
+        // Types without R5 fall back to R4 only where the client accepts it
+        let metadata = Request::get("/fhir/metadata")
+            .header(header::ACCEPT, r5)
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(&app, metadata).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert!(body.contains("CapabilityStatement is only available as FHIR 4.0"));
+        let metadata = Request::get("/fhir/metadata")
+            .header(
+                header::ACCEPT,
+                "application/fhir+json; fhirVersion=5.0, */*;q=0.1",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(&app, metadata).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/fhir+json");
+
+        let observation = Request::post("/fhir/Observation")
+            .header(header::CONTENT_TYPE, r5)
+            .body(Body::from(
+                r#"{"resourceType":"Observation","status":"final"}"#,
+            ))
+            .unwrap();
+        assert_eq!(
+            send(&app, observation).await.0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let unknown = Request::get(format!("/fhir/Patient/{}", id))
+            .header(header::ACCEPT, "application/fhir+json; fhirVersion=3.0")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, unknown).await.0, StatusCode::NOT_ACCEPTABLE);
+
+        let versions = Request::get("/fhir/$versions").body(Body::empty()).unwrap();
+        let (status, _, body) = send(&app, versions).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"valueCode\":\"5.0\""));
+
+        let delete = Request::delete(format!("/fhir/Patient/{}", id))
+            .body(Body::empty())
+            .unwrap();
+        send(&app, delete).await;
+    }
+
+    #[tokio::test]
+    async fn test_patients_are_exchanged_as_xml() {
+        let app = app().await;
+        let patient = r#"<Patient xmlns="http://hl7.org/fhir">
+              <name><family value="Xmlson"/><given value="Ada"/></name>
+              <gender value="female"/>
+              <birthDate value="1990-01-01"/>
+            </Patient>"#;
+        let create = Request::post("/fhir/Patient")
+            .header(header::CONTENT_TYPE, FHIR_XML)
+            .header(header::ACCEPT, FHIR_XML)
+            .body(Body::from(patient))
+            .unwrap();
+        let (status, headers, body) = send(&app, create).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(headers[header::CONTENT_TYPE], FHIR_XML);
+        let created = from_xml(&body).unwrap();
+        assert_eq!(created["name"][0]["given"][0], "Ada");
+        let id = created["id"].as_str().unwrap();
+
+        // JSON clients read the same resource as before
+        let read = Request::get(format!("/fhir/Patient/{}", id))
+            .body(Body::empty())
+            .unwrap();
+        let (_, headers, body) = send(&app, read).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/fhir+json");
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["birthDate"], "1990-01-01");
+
+        let read = Request::get(format!("/fhir/Patient/{}", id))
+            .header(
+                header::ACCEPT,
+                "application/xml;q=0.9, application/json;q=0.5",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, read).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], FHIR_XML);
+        assert!(headers.contains_key(header::ETAG));
+        assert_eq!(from_xml(&body).unwrap(), json);
+
+        let missing = Request::get(format!("/fhir/Patient/{}", uuid::Uuid::new_v4()))
+            .header(header::ACCEPT, FHIR_XML)
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(&app, missing).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.starts_with("<?xml"));
+        assert_eq!(from_xml(&body).unwrap()["resourceType"], "OperationOutcome");
+    }
+
+    #[tokio::test]
+    async fn test_types_without_xml_are_refused() {
+        let app = app().await;
+
+        let malformed = Request::post("/fhir/Patient")
+            .header(header::CONTENT_TYPE, "text/xml")
+            .body(Body::from("<Patient xmlns=\"http://hl7.org/fhir\"><name>"))
+            .unwrap();
+        let (status, _, body) = send(&app, malformed).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("structure"));
+
+        let observation = Request::post("/fhir/Observation")
+            .header(header::CONTENT_TYPE, FHIR_XML)
+            .body(Body::from(
+                "<Observation xmlns=\"http://hl7.org/fhir\"><status value=\"final\"/></Observation>",
+            ))
+            .unwrap();
+        let (status, _, _) = send(&app, observation).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let search = Request::get("/fhir/Patient?_format=xml&_count=1")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, search).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], FHIR_XML);
+        assert_eq!(from_xml(&body).unwrap()["type"], "searchset");
+
+        let overridden = Request::get("/fhir/metadata?_format=json")
+            .header(header::ACCEPT, FHIR_XML)
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(&app, overridden).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/fhir+json");
+
+        // CapabilityStatement has no XML form: JSON if acceptable, else 406
+        let metadata = Request::get("/fhir/metadata")
+            .header(header::ACCEPT, "application/fhir+xml, */*;q=0.5")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(&app, metadata).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/fhir+json");
+
+        let metadata = Request::get("/fhir/metadata")
+            .header(header::ACCEPT, FHIR_XML)
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, metadata).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(headers[header::CONTENT_TYPE], FHIR_XML);
+        assert!(body.contains("CapabilityStatement is only available"));
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_format_reaches_the_handler() {
+        let app = app().await;
+        let create = Request::post("/fhir/Patient")
+            .header(header::CONTENT_TYPE, "application/fhir+json")
+            .body(Body::from(
+                r#"{"resourceType":"Patient","gender":"female"}"#,
+            ))
+            .unwrap();
+        let (_, _, body) = send(&app, create).await;
+        let created: Value = serde_json::from_str(&body).unwrap();
+        let id = created["id"].as_str().unwrap();
+
+        let history = Request::get(format!("/fhir/Patient/{}/_history?_format=ndjson", id))
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, history).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], FHIR_NDJSON);
+        let line: Value = serde_json::from_str(body.trim_end()).unwrap();
+        assert_eq!(line["id"], id);
+
+        // Other responses have no NDJSON form
+        let metadata = Request::get("/fhir/metadata?_format=ndjson")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(&app, metadata).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert!(body.contains("not-supported"));
+        let missing = Request::get(format!(
+            "/fhir/Patient/{}?_format=ndjson",
+            uuid::Uuid::new_v4()
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(send(&app, missing).await.0, StatusCode::NOT_FOUND);
+
+        let delete = Request::delete(format!("/fhir/Patient/{}", id))
+            .body(Body::empty())
+            .unwrap();
+        send(&app, delete).await;
+    }
+}
@@ -0,0 +1,771 @@
+//! Profile packs: the search parameters and profiles of a FHIR
+//! implementation guide, such as US Core (`hl7.fhir.us.core`) or the German
+//! Basisprofil (`de.basisprofil.r4`), read from its NPM package (`.tgz`).
+//!
+//! Each package listed in `profile_packs` is imported at startup:
+//!
+//! - its SearchParameters on Patient and Observation whose expression is
+//!   in the [supported subset](crate::search::expression) become
+//!   searchable and are advertised like the built-ins. A built-in
+//!   parameter of the same code keeps precedence; parameters of other
+//!   types than token, string and date are skipped with a warning.
+//! - its resource profiles (constraining StructureDefinitions) check the
+//!   required elements and extensions of every create and update that
+//!   claims them in `meta.profile`. Claiming a profile of a loaded pack
+//!   needs no stored StructureDefinition.
+//!
+//! With `profile_validation` on, writes are checked more thoroughly: the
+//! maximum cardinality and the fixed and pattern values of profile elements
+//! outside slices are checked as well, a claimed profile may also be a
+//! stored StructureDefinition, and the base definitions of a core package
+//! (`hl7.fhir.r4.core`) listed as a pack apply to every resource of their
+//! type, whether it claims them or not.
+//!
+//! A package posted to `POST /admin/packages` is imported the same way
+//! while the server runs, and its conformance resources are stored as
+//! well, so canonical references to them resolve.
+//!
+//! Only the definitions directly in the package's `package/` folder are
+//! read; examples and other folders are not.
+
+use crate::db::conformance::is_conformance_type;
+use crate::models::OperationOutcomeIssue;
+use crate::search::expression;
+use crate::search::{SearchParamDefinition, SearchParamRegistry};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use serde_json::Value;
+use std::io::Read;
+use std::path::Path;
+
+/// An imported implementation guide package
+#[derive(Debug, Clone)]
+pub struct ProfilePack {
+    /// Package id, e.g. `hl7.fhir.us.core`
+    pub name: String,
+    pub version: String,
+    /// One definition per searchable base, with its path
+    pub search_params: Vec<SearchParamDefinition>,
+    pub profiles: Vec<Profile>,
+    /// The conformance resources of the package that have a `url`, to store
+    pub resources: Vec<Value>,
+    /// Why definitions of the pack could not be imported
+    pub skipped: Vec<String>,
+}
+
+impl ProfilePack {
+    /// Import the package at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open profile pack {}", path.display()))?;
+        Self::from_tgz(file)
+            .with_context(|| format!("Failed to import profile pack {}", path.display()))
+    }
+
+    /// Import a gzipped package tarball
+    pub fn from_tgz(reader: impl Read) -> Result<Self> {
+        let mut archive = tar::Archive::new(GzDecoder::new(reader));
+        let mut manifest = None;
+        let mut resources = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let Some(name) = path
+                .strip_prefix("package/")
+                .filter(|name| !name.contains('/') && name.ends_with(".json"))
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            let json: Value = serde_json::from_slice(&bytes)
+                .with_context(|| format!("{} is not valid JSON", path))?;
+            if name == "package.json" {
+                manifest = Some(json);
+            } else if json
+                .get("resourceType")
+                .and_then(Value::as_str)
+                .is_some_and(is_conformance_type)
+            {
+                resources.push(json);
+            }
+        }
+        let manifest = manifest.context("package/package.json is missing")?;
+        Ok(Self::from_resources(&manifest, &resources))
+    }
+
+    /// The pack of a package manifest and its definitions
+    pub fn from_resources(manifest: &Value, resources: &[Value]) -> Self {
+        let text = |value: &Value, name: &str| {
+            value
+                .get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let mut pack = Self {
+            name: text(manifest, "name"),
+            version: text(manifest, "version"),
+            search_params: Vec::new(),
+            profiles: Vec::new(),
+            resources: Vec::new(),
+            skipped: Vec::new(),
+        };
+        for resource in resources {
+            let resource_type = text(resource, "resourceType");
+            match resource_type.as_str() {
+                "SearchParameter" => pack.add_search_param(resource),
+                "StructureDefinition" => pack.profiles.extend(Profile::parse(resource)),
+                _ => {}
+            }
+            if !is_conformance_type(&resource_type) {
+                continue;
+            }
+            if resource.get("url").and_then(Value::as_str).is_some() {
+                pack.resources.push(resource.clone());
+            } else {
+                pack.skipped.push(format!(
+                    "{} {} has no url and is not stored",
+                    resource_type,
+                    text(resource, "id")
+                ));
+            }
+        }
+        pack
+    }
+
+    fn add_search_param(&mut self, resource: &Value) {
+        for definition in expression::definitions(resource) {
+            match definition {
+                Ok(param) => self.search_params.push(param),
+                Err(reason) => self.skipped.push(reason),
+            }
+        }
+    }
+
+    /// Register the pack's search parameters with `registry`, returning the
+    /// ones that were; built-in and custom parameters of the same code stay
+    /// in place
+    pub fn register(&self, registry: &SearchParamRegistry) -> Vec<&SearchParamDefinition> {
+        let mut registered = Vec::new();
+        for param in &self.search_params {
+            let base = &param.base[0];
+            if registry
+                .get(base, &param.code)
+                .is_some_and(|existing| existing.path.is_none() || existing.indexed)
+            {
+                tracing::debug!(
+                    pack = %self.name,
+                    param = %param.url,
+                    "{} parameter {} of the server takes precedence",
+                    base,
+                    param.code
+                );
+                continue;
+            }
+            registry.register(param.clone());
+            registered.push(param);
+        }
+        registered
+    }
+}
+
+/// An element or extension a profile requires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequiredElement {
+    /// Element names below the resource (`identifier`, `system`); the last
+    /// may be a choice (`value[x]`)
+    pub path: Vec<String>,
+    /// Occurrences required wherever its parent occurs
+    pub min: u64,
+    /// For a required extension slice, the extension's URL
+    pub extension: Option<String>,
+}
+
+/// An element a profile allows a limited number of times
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementLimit {
+    pub path: Vec<String>,
+    /// Occurrences allowed wherever its parent occurs
+    pub max: u64,
+}
+
+/// A value an element must have wherever it occurs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedValue {
+    pub path: Vec<String>,
+    pub value: Value,
+    /// A pattern only needs to be contained in the element (`pattern[x]`);
+    /// otherwise the element must equal it exactly (`fixed[x]`)
+    pub pattern: bool,
+}
+
+/// The checks of a resource profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub url: String,
+    pub resource_type: String,
+    /// The definition of the resource type itself (`derivation` of
+    /// `specialization`), which applies to every resource of the type
+    pub base: bool,
+    pub required: Vec<RequiredElement>,
+    pub limits: Vec<ElementLimit>,
+    pub fixed: Vec<FixedValue>,
+}
+
+impl Profile {
+    /// The profile a StructureDefinition defines, if it constrains or
+    /// defines a resource type. Its snapshot is read when present, else its
+    /// differential; constraints within slices other than the presence
+    /// of an extension slice are not checked.
+    pub fn parse(definition: &Value) -> Option<Self> {
+        let text = |name: &str| definition.get(name).and_then(Value::as_str);
+        let base = match text("derivation") {
+            Some("constraint") => false,
+            Some("specialization") => true,
+            _ => return None,
+        };
+        if text("kind") != Some("resource") {
+            return None;
+        }
+        let resource_type = text("type")?;
+        let elements = ["snapshot", "differential"]
+            .iter()
+            .find_map(|view| definition.get(view)?.get("element")?.as_array())?;
+        let element_path = |element: &Value| -> Option<Vec<String>> {
+            let path = element.get("path").and_then(Value::as_str)?;
+            Some(
+                path.strip_prefix(resource_type)?
+                    .strip_prefix('.')?
+                    .split('.')
+                    .map(str::to_string)
+                    .collect(),
+            )
+        };
+        // Elements outside slices
+        let unsliced = || {
+            elements.iter().filter_map(|element| {
+                let id = element.get("id").and_then(Value::as_str)?;
+                if id.contains(':') {
+                    return None;
+                }
+                Some((element, element_path(element)?))
+            })
+        };
+
+        let required = elements
+            .iter()
+            .filter_map(|element| {
+                let min = element.get("min").and_then(Value::as_u64).unwrap_or(0);
+                let id = element.get("id").and_then(Value::as_str)?;
+                let path = element_path(element)?;
+                if min == 0 {
+                    return None;
+                }
+                let extension = match id.split_once(':') {
+                    None => None,
+                    // The slice itself, not an element within it
+                    Some((_, slice)) if !slice.contains(['.', ':']) => {
+                        if !matches!(path.last()?.as_str(), "extension" | "modifierExtension") {
+                            return None;
+                        }
+                        Some(
+                            element
+                                .get("type")?
+                                .get(0)?
+                                .get("profile")?
+                                .get(0)?
+                                .as_str()?
+                                .to_string(),
+                        )
+                    }
+                    Some(_) => return None,
+                };
+                Some(RequiredElement {
+                    path,
+                    min,
+                    extension,
+                })
+            })
+            .collect();
+        let limits = unsliced()
+            .filter_map(|(element, path)| {
+                let max = element.get("max").and_then(Value::as_str)?.parse().ok()?;
+                Some(ElementLimit { path, max })
+            })
+            .collect();
+        let fixed = unsliced()
+            .flat_map(|(element, path)| {
+                element
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(move |(key, value)| {
+                        let pattern = if is_choice_of(key, "fixed") {
+                            false
+                        } else if is_choice_of(key, "pattern") {
+                            true
+                        } else {
+                            return None;
+                        };
+                        Some(FixedValue {
+                            path: path.clone(),
+                            value: value.clone(),
+                            pattern,
+                        })
+                    })
+            })
+            .collect();
+
+        Some(Self {
+            url: text("url")?.to_string(),
+            resource_type: resource_type.to_string(),
+            base,
+            required,
+            limits,
+            fixed,
+        })
+    }
+
+    /// A `required` error for every required element `resource` lacks
+    pub fn check(&self, resource: &Value) -> Vec<OperationOutcomeIssue> {
+        let mut issues = Vec::new();
+        for required in &self.required {
+            self.find_missing(
+                resource,
+                &required.path,
+                self.resource_type.clone(),
+                required,
+                &mut issues,
+            );
+        }
+        issues
+    }
+
+    /// A `structure` error for every element of `resource` occurring more
+    /// often than the profile allows, and a `value` error for every element
+    /// that differs from its fixed value or pattern
+    pub fn check_constraints(&self, resource: &Value) -> Vec<OperationOutcomeIssue> {
+        let mut issues = Vec::new();
+        for limit in &self.limits {
+            let Some((name, parents)) = limit.path.split_last() else {
+                continue;
+            };
+            for (location, parent) in occurrences(resource, parents, &self.resource_type) {
+                let found = element(parent, name).len() as u64;
+                if found > limit.max {
+                    issues.push(self.issue(
+                        "structure",
+                        format!(
+                            "Profile {} allows at most {} {} at {}, found {}",
+                            self.url, limit.max, name, location, found
+                        ),
+                        format!("{}.{}", location, name),
+                    ));
+                }
+            }
+        }
+        for fixed in &self.fixed {
+            for (location, value) in occurrences(resource, &fixed.path, &self.resource_type) {
+                let conforms = if fixed.pattern {
+                    matches_pattern(value, &fixed.value)
+                } else {
+                    *value == fixed.value
+                };
+                if !conforms {
+                    let what = if fixed.pattern { "match" } else { "equal" };
+                    issues.push(self.issue(
+                        "value",
+                        format!(
+                            "Profile {} requires {} to {} {}",
+                            self.url, location, what, fixed.value
+                        ),
+                        location,
+                    ));
+                }
+            }
+        }
+        issues
+    }
+
+    fn issue(&self, code: &str, diagnostics: String, location: String) -> OperationOutcomeIssue {
+        OperationOutcomeIssue {
+            severity: "error".to_string(),
+            code: code.to_string(),
+            details: None,
+            diagnostics: Some(diagnostics),
+            location: Some(vec![location]),
+            expression: None,
+        }
+    }
+
+    fn find_missing(
+        &self,
+        value: &Value,
+        path: &[String],
+        location: String,
+        required: &RequiredElement,
+        issues: &mut Vec<OperationOutcomeIssue>,
+    ) {
+        let Some((name, rest)) = path.split_first() else {
+            return;
+        };
+        let children = element(value, name);
+        if !rest.is_empty() {
+            for (at, child) in children {
+                let location = format!("{}.{}", location, at);
+                self.find_missing(child, rest, location, required, issues);
+            }
+            return;
+        }
+
+        let found = children
+            .iter()
+            .filter(|(_, child)| match &required.extension {
+                Some(url) => child.get("url").and_then(Value::as_str) == Some(url),
+                None => true,
+            })
+            .count() as u64;
+        if found < required.min {
+            let what = match &required.extension {
+                Some(url) => format!("extension {}", url),
+                None => name.to_string(),
+            };
+            issues.push(OperationOutcomeIssue {
+                severity: "error".to_string(),
+                code: "required".to_string(),
+                details: None,
+                diagnostics: Some(format!(
+                    "Profile {} requires {} {} at {}, found {}",
+                    self.url, required.min, what, location, found
+                )),
+                location: Some(vec![format!("{}.{}", location, name)]),
+                expression: None,
+            });
+        }
+    }
+}
+
+/// Every occurrence of the element at `path` below `resource`, with its
+/// location starting at `root`
+fn occurrences<'a>(resource: &'a Value, path: &[String], root: &str) -> Vec<(String, &'a Value)> {
+    let mut found = vec![(root.to_string(), resource)];
+    for name in path {
+        found = found
+            .into_iter()
+            .flat_map(|(location, value)| {
+                element(value, name)
+                    .into_iter()
+                    .map(move |(at, child)| (format!("{}.{}", location, at), child))
+            })
+            .collect();
+    }
+    found
+}
+
+/// Whether `key` is `prefix` followed by a type name (`fixedCode`)
+fn is_choice_of(key: &str, prefix: &str) -> bool {
+    key.strip_prefix(prefix)
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_uppercase()))
+}
+
+/// Whether `value` contains `pattern`: every member of a pattern object is
+/// matched by the same member of the value, and every item of a pattern
+/// array by some item of the value
+fn matches_pattern(value: &Value, pattern: &Value) -> bool {
+    match (value, pattern) {
+        (Value::Object(value), Value::Object(pattern)) => pattern
+            .iter()
+            .all(|(key, p)| value.get(key).is_some_and(|v| matches_pattern(v, p))),
+        (Value::Array(values), Value::Array(patterns)) => patterns
+            .iter()
+            .all(|p| values.iter().any(|v| matches_pattern(v, p))),
+        (value, pattern) => value == pattern,
+    }
+}
+
+/// The occurrences of element `name` in `value`, each with where it was
+/// found (`identifier[0]`); a choice (`value[x]`) matches any of its types
+fn element<'a>(value: &'a Value, name: &str) -> Vec<(String, &'a Value)> {
+    let Value::Object(map) = value else {
+        return Vec::new();
+    };
+    let matches = |key: &str| match name.strip_suffix("[x]") {
+        Some(choice) => is_choice_of(key, choice),
+        None => key == name,
+    };
+    map.iter()
+        .filter(|(key, _)| matches(key))
+        .flat_map(|(key, value)| match value {
+            Value::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (format!("{}[{}]", key, i), item))
+                .collect(),
+            Value::Null => Vec::new(),
+            value => vec![(key.to_string(), value)],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+
+    const RACE: &str = "http://hl7.org/fhir/us/core/StructureDefinition/us-core-race";
+
+    /// A package in the layout of the published US Core package
+    fn us_core_package() -> Vec<u8> {
+        let files = [
+            (
+                "package/package.json",
+                json!({"name": "hl7.fhir.us.core", "version": "6.1.0"}),
+            ),
+            (
+                "package/SearchParameter-us-core-race.json",
+                json!({
+                    "resourceType": "SearchParameter",
+                    "id": "us-core-race",
+                    "url": "http://hl7.org/fhir/us/core/SearchParameter/us-core-race",
+                    "code": "race",
+                    "base": ["Patient"],
+                    "type": "token",
+                    "expression": format!("Patient.extension.where(url = '{}').extension.value.code", RACE),
+                    "description": "Returns patients with a race extension matching the specified code."
+                }),
+            ),
+            (
+                "package/SearchParameter-us-core-patient-gender.json",
+                json!({
+                    "resourceType": "SearchParameter",
+                    "id": "us-core-patient-gender",
+                    "url": "http://hl7.org/fhir/us/core/SearchParameter/us-core-patient-gender",
+                    "code": "gender",
+                    "base": ["Patient"],
+                    "type": "token",
+                    "expression": "Patient.gender"
+                }),
+            ),
+            (
+                "package/SearchParameter-us-core-patient-family.json",
+                json!({
+                    "resourceType": "SearchParameter",
+                    "id": "us-core-patient-family",
+                    "url": "http://hl7.org/fhir/us/core/SearchParameter/us-core-patient-family",
+                    "code": "family",
+                    "base": ["Patient"],
+                    "type": "string",
+                    "expression": "Patient.name.family"
+                }),
+            ),
+            (
+                "package/SearchParameter-us-core-encounter-class.json",
+                json!({
+                    "resourceType": "SearchParameter",
+                    "id": "us-core-encounter-class",
+                    "url": "http://hl7.org/fhir/us/core/SearchParameter/us-core-encounter-class",
+                    "code": "class",
+                    "base": ["Encounter"],
+                    "type": "token",
+                    "expression": "Encounter.class"
+                }),
+            ),
+            (
+                "package/SearchParameter-us-core-observation-patient.json",
+                json!({
+                    "resourceType": "SearchParameter",
+                    "id": "us-core-observation-patient",
+                    "url": "http://hl7.org/fhir/us/core/SearchParameter/us-core-observation-patient",
+                    "code": "patient",
+                    "base": ["Observation"],
+                    "type": "reference",
+                    "expression": "Observation.subject.where(resolve() is Patient)"
+                }),
+            ),
+            (
+                "package/StructureDefinition-us-core-patient.json",
+                json!({
+                    "resourceType": "StructureDefinition",
+                    "url": "http://hl7.org/fhir/us/core/StructureDefinition/us-core-patient",
+                    "kind": "resource",
+                    "derivation": "constraint",
+                    "type": "Patient",
+                    "snapshot": {"element": [
+                        {"id": "Patient", "path": "Patient", "min": 0},
+                        {"id": "Patient.extension:race", "path": "Patient.extension", "min": 0,
+                         "type": [{"code": "Extension", "profile": [RACE]}]},
+                        {"id": "Patient.identifier", "path": "Patient.identifier", "min": 1},
+                        {"id": "Patient.identifier.system", "path": "Patient.identifier.system", "min": 1},
+                        {"id": "Patient.name", "path": "Patient.name", "min": 1},
+                        {"id": "Patient.gender", "path": "Patient.gender", "min": 1}
+                    ]}
+                }),
+            ),
+            (
+                "package/StructureDefinition-us-core-race.json",
+                json!({
+                    "resourceType": "StructureDefinition",
+                    "url": RACE,
+                    "kind": "complex-type",
+                    "derivation": "constraint",
+                    "type": "Extension"
+                }),
+            ),
+            (
+                "package/example/Patient-example.json",
+                json!({"resourceType": "Patient", "id": "example"}),
+            ),
+        ];
+
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, json) in files {
+            let bytes = serde_json::to_vec(&json).unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, bytes.as_slice())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_import_package() {
+        let pack = ProfilePack::from_tgz(us_core_package().as_slice()).unwrap();
+        assert_eq!(pack.name, "hl7.fhir.us.core");
+        assert_eq!(pack.version, "6.1.0");
+
+        let codes: Vec<&str> = pack.search_params.iter().map(|p| p.code.as_str()).collect();
+        assert_eq!(codes, vec!["race", "gender", "family"]);
+        assert_eq!(pack.skipped.len(), 1);
+        assert!(pack.skipped[0].contains("reference parameters are not supported"));
+        // Only resource profiles are checked
+        assert_eq!(pack.profiles.len(), 1);
+        assert_eq!(pack.profiles[0].required.len(), 4);
+        // Every definition with a url is stored, supported or not
+        assert_eq!(pack.resources.len(), 7);
+
+        let registry = SearchParamRegistry::with_builtins();
+        assert_eq!(pack.register(&registry).len(), 2);
+        let race = registry.get("Patient", "race").unwrap();
+        assert!(race.path.is_some());
+        assert_eq!(race.to_resource()["type"], "token");
+        // The built-in gender parameter is kept
+        assert!(registry.get("Patient", "gender").unwrap().path.is_none());
+
+        assert!(ProfilePack::from_tgz(&b"not a package"[..]).is_err());
+    }
+
+    #[test]
+    fn test_required_elements() {
+        let definition = json!({
+            "resourceType": "StructureDefinition",
+            "url": "http://fhir.de/StructureDefinition/example-patient",
+            "kind": "resource",
+            "derivation": "constraint",
+            "type": "Patient",
+            "differential": {"element": [
+                {"id": "Patient.identifier", "path": "Patient.identifier", "min": 1},
+                {"id": "Patient.identifier.value", "path": "Patient.identifier.value", "min": 1},
+                {"id": "Patient.extension:origin", "path": "Patient.extension", "min": 1,
+                 "type": [{"code": "Extension", "profile": ["http://fhir.de/StructureDefinition/origin"]}]},
+                {"id": "Patient.extension:origin.value[x]", "path": "Patient.extension.value[x]", "min": 1},
+                {"id": "Patient.deceased[x]", "path": "Patient.deceased[x]", "min": 1}
+            ]}
+        });
+        let profile = Profile::parse(&definition).unwrap();
+        assert_eq!(profile.required.len(), 4);
+
+        let conforming = json!({
+            "resourceType": "Patient",
+            "identifier": [{"system": "urn:x", "value": "1"}],
+            "extension": [{"url": "http://fhir.de/StructureDefinition/origin", "valueString": "x"}],
+            "deceasedBoolean": false
+        });
+        assert!(profile.check(&conforming).is_empty());
+
+        let lacking = json!({
+            "resourceType": "Patient",
+            "identifier": [{"value": "1"}, {"system": "urn:x"}],
+            "extension": [{"url": "http://example.org/other"}]
+        });
+        let locations: Vec<String> = profile
+            .check(&lacking)
+            .into_iter()
+            .flat_map(|issue| issue.location.unwrap_or_default())
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "Patient.identifier[1].value",
+                "Patient.extension",
+                "Patient.deceased[x]"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cardinality_and_fixed_values() {
+        // The core definition of Observation, shortened
+        let definition = json!({
+            "resourceType": "StructureDefinition",
+            "url": "http://hl7.org/fhir/StructureDefinition/Observation",
+            "kind": "resource",
+            "derivation": "specialization",
+            "type": "Observation",
+            "snapshot": {"element": [
+                {"id": "Observation", "path": "Observation", "min": 0, "max": "*"},
+                {"id": "Observation.status", "path": "Observation.status", "min": 1, "max": "1"},
+                {"id": "Observation.category", "path": "Observation.category", "min": 0, "max": "*",
+                 "patternCodeableConcept": {"coding": [{
+                     "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                     "code": "vital-signs"
+                 }]}},
+                {"id": "Observation.value[x]", "path": "Observation.value[x]", "min": 0, "max": "1"},
+                {"id": "Observation.component:bp", "path": "Observation.component", "min": 0, "max": "0",
+                 "fixedString": "slices are not checked"}
+            ]}
+        });
+        let profile = Profile::parse(&definition).unwrap();
+        assert!(profile.base);
+        // Unbounded elements and slices have no limits
+        assert_eq!(profile.limits.len(), 2);
+        assert_eq!(profile.fixed.len(), 1);
+
+        let conforming = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "category": [{"coding": [
+                {"system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                 "code": "vital-signs", "display": "Vital Signs"}
+            ]}],
+            "valueQuantity": {"value": 72}
+        });
+        assert!(profile.check_constraints(&conforming).is_empty());
+
+        let violating = json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "category": [{"coding": [{"code": "laboratory"}]}],
+            "valueQuantity": {"value": 72},
+            "valueString": "72"
+        });
+        let issues: Vec<(String, String)> = profile
+            .check_constraints(&violating)
+            .into_iter()
+            .map(|issue| (issue.code, issue.location.unwrap().remove(0)))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                ("structure".to_string(), "Observation.value[x]".to_string()),
+                ("value".to_string(), "Observation.category[0]".to_string()),
+            ]
+        );
+    }
+}
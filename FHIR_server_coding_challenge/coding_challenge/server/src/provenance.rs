@@ -0,0 +1,227 @@
+//! Provenance of writes.
+//!
+//! Every create and update of a resource, over REST or in a Bundle entry,
+//! stores a `Provenance` whose `target` is the version written, e.g.
+//! `Patient/<id>/_history/2`. Its agent is the API key the request was
+//! authorized with (see [`crate::api_keys`]), or `anonymous` on servers that
+//! do not require one, and it records the `X-Request-Id` of the request so
+//! the write can be traced to a captured failure or log line.
+//!
+//! Provenance is written by the server only, in the transaction of the
+//! write it describes; `GET /fhir/Provenance?target=` lists the records of a
+//! resource. Writes that leave a resource at its current version and writes
+//! of Provenance itself are not recorded.
+
+use crate::api_keys::ApiKeyName;
+use crate::capture::RequestId;
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use uuid::Uuid;
+
+/// Extension holding the id of the request that made the write
+pub const REQUEST_ID_EXTENSION: &str =
+    "http://fhir-server.example.org/StructureDefinition/provenance-request-id";
+
+/// System of the agent identifier: the name of an API key
+pub const API_KEY_SYSTEM: &str = "http://fhir-server.example.org/api-key";
+
+const DATA_OPERATION_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-DataOperation";
+const PARTICIPANT_TYPE_SYSTEM: &str =
+    "http://terminology.hl7.org/CodeSystem/provenance-participant-type";
+
+/// Who made a write and in which request, taken from the extensions the
+/// auth and capture middleware set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attribution {
+    /// Name of the API key; `None` for requests without one
+    pub agent: Option<String>,
+    pub request_id: Option<Uuid>,
+}
+
+impl Attribution {
+    fn agent_who(&self) -> Value {
+        match &self.agent {
+            Some(name) => json!({
+                "identifier": { "system": API_KEY_SYSTEM, "value": name },
+                "display": name,
+            }),
+            None => json!({ "display": "anonymous" }),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Attribution
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Attribution {
+            agent: parts
+                .extensions
+                .get::<ApiKeyName>()
+                .map(|ApiKeyName(name)| name.clone()),
+            request_id: parts.extensions.get::<RequestId>().map(|RequestId(id)| *id),
+        })
+    }
+}
+
+/// What a write did to its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Create,
+    Update,
+}
+
+impl Activity {
+    /// The `v3-DataOperation` code
+    pub fn code(self) -> &'static str {
+        match self {
+            Activity::Create => "CREATE",
+            Activity::Update => "UPDATE",
+        }
+    }
+
+    fn display(self) -> &'static str {
+        match self {
+            Activity::Create => "create",
+            Activity::Update => "revise",
+        }
+    }
+}
+
+/// Whether writes of `resource_type` get a Provenance
+pub fn is_recorded(resource_type: &str) -> bool {
+    resource_type != "Provenance"
+}
+
+/// `<type>/<id>/_history/<version>`, the target of a Provenance
+pub fn versioned_reference(resource_type: &str, id: &str, version_id: i32) -> String {
+    format!("{}/{}/_history/{}", resource_type, id, version_id)
+}
+
+/// Id of the Provenance of `target`, derived from it so that a version is
+/// recorded once and its record is found by primary key
+pub fn provenance_id(target: &str) -> Uuid {
+    let digest = Sha256::digest(target.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
+
+/// The Provenance of writing `target` (a [`versioned_reference`]) at
+/// `recorded`
+pub fn provenance_resource(
+    target: &str,
+    activity: Activity,
+    attribution: &Attribution,
+    recorded: DateTime<Utc>,
+) -> Value {
+    let recorded = recorded.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let mut provenance = json!({
+        "resourceType": "Provenance",
+        "target": [{ "reference": target }],
+        "occurredDateTime": recorded,
+        "recorded": recorded,
+        "activity": {
+            "coding": [{
+                "system": DATA_OPERATION_SYSTEM,
+                "code": activity.code(),
+                "display": activity.display(),
+            }]
+        },
+        "agent": [{
+            "type": {
+                "coding": [{ "system": PARTICIPANT_TYPE_SYSTEM, "code": "author" }]
+            },
+            "who": attribution.agent_who(),
+        }],
+    });
+    if let Some(request_id) = attribution.request_id {
+        provenance["extension"] = json!([{
+            "url": REQUEST_ID_EXTENSION,
+            "valueString": request_id.to_string(),
+        }]);
+    }
+    provenance
+}
+
+/// The unversioned form of a `target` search value: `Patient/1` for
+/// `Patient/1` and `Patient/1/_history/2`
+pub fn unversioned_target(target: &str) -> &str {
+    match target.split_once("/_history/") {
+        Some((reference, _)) => reference,
+        None => target,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_provenance_resource() {
+        let recorded = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let request_id = Uuid::new_v4();
+        let attribution = Attribution {
+            agent: Some("emr-sync".to_string()),
+            request_id: Some(request_id),
+        };
+        let provenance = provenance_resource(
+            &versioned_reference("Patient", "p1", 2),
+            Activity::Update,
+            &attribution,
+            recorded,
+        );
+
+        assert_eq!(
+            provenance["target"][0]["reference"],
+            "Patient/p1/_history/2"
+        );
+        assert_eq!(provenance["recorded"], "2024-05-01T12:00:00.000Z");
+        assert_eq!(provenance["activity"]["coding"][0]["code"], "UPDATE");
+        assert_eq!(
+            provenance["agent"][0]["who"]["identifier"]["value"],
+            "emr-sync"
+        );
+        assert_eq!(provenance["extension"][0]["url"], REQUEST_ID_EXTENSION);
+        assert_eq!(
+            provenance["extension"][0]["valueString"],
+            request_id.to_string()
+        );
+
+        let anonymous = provenance_resource(
+            "Observation/o1/_history/1",
+            Activity::Create,
+            &Attribution::default(),
+            recorded,
+        );
+        assert_eq!(
+            anonymous["agent"][0]["who"],
+            json!({ "display": "anonymous" })
+        );
+        assert!(anonymous.get("extension").is_none());
+    }
+
+    #[test]
+    fn test_targets() {
+        assert!(is_recorded("Patient"));
+        assert!(!is_recorded("Provenance"));
+        assert_eq!(unversioned_target("Patient/p1/_history/3"), "Patient/p1");
+        assert_eq!(unversioned_target("Patient/p1"), "Patient/p1");
+        assert_eq!(
+            provenance_id("Patient/p1/_history/1"),
+            provenance_id("Patient/p1/_history/1")
+        );
+        assert_ne!(
+            provenance_id("Patient/p1/_history/1"),
+            provenance_id("Patient/p1/_history/2")
+        );
+    }
+}
@@ -0,0 +1,483 @@
+//! Patient rosters as CSV, for `POST /admin/import/csv`.
+//!
+//! A `[csv_mappings.<name>]` entry of the server config names the Patient
+//! element each column of a roster is written to, so registration offices
+//! can load the spreadsheets they keep without converting them to FHIR
+//! first:
+//!
+//! ```toml
+//! [csv_mappings.registration]
+//! columns = { MRN = "identifier.value", Surname = "name.family", "First name" = "name.given[0]", DOB = "birthDate" }
+//! fixed = { "identifier.system" = "http://hospital.example.org/mrn" }
+//! ```
+//!
+//! Paths are element names joined by `.`, optionally after `Patient.`, with
+//! an index on elements that repeat (`name.given[1]`); a repeating element
+//! without one means its first item. `fixed` values are set on every
+//! patient. Empty cells are left out, and `active`, `deceasedBoolean` and
+//! `multipleBirthBoolean` take `true`/`false`, `yes`/`no` or `1`/`0`.
+//!
+//! The CSV is RFC 4180: the first record is the header, fields may be
+//! quoted, and quoted fields may hold the delimiter, line breaks and `""`
+//! for a quote.
+
+use crate::fhirpath_patch::repeats;
+use crate::search::expression::is_name;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Elements written as JSON booleans rather than strings
+const BOOLEAN_ELEMENTS: &[&str] = &["active", "deceasedBoolean", "multipleBirthBoolean"];
+
+/// Elements written as JSON integers rather than strings
+const INTEGER_ELEMENTS: &[&str] = &["multipleBirthInteger"];
+
+/// A `[csv_mappings.<name>]` entry of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CsvMapping {
+    /// Element path each column is written to, by column header
+    pub columns: BTreeMap<String, String>,
+    /// Values every patient gets, by element path
+    #[serde(default)]
+    pub fixed: BTreeMap<String, String>,
+    /// Field separator
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+/// One step of an element path: `name` or `name[index]`
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    name: String,
+    index: Option<usize>,
+}
+
+fn parse_path(path: &str) -> Result<Vec<Step>, String> {
+    let trimmed = path.trim();
+    let trimmed = trimmed.strip_prefix("Patient.").unwrap_or(trimmed);
+    let mut steps = Vec::new();
+    for step in trimmed.split('.') {
+        let (name, index) = match step.split_once('[') {
+            Some((name, index)) => {
+                let index = index
+                    .strip_suffix(']')
+                    .and_then(|index| index.parse().ok())
+                    .ok_or_else(|| format!("{}: invalid index in {}", path, step))?;
+                (name, Some(index))
+            }
+            None => (step, None),
+        };
+        if !is_name(name) {
+            return Err(format!("{}: {:?} is not an element name", path, name));
+        }
+        let parent = steps.last().map(|step: &Step| step.name.as_str());
+        if index.is_some() && !repeats(parent, name) {
+            return Err(format!("{}: {} does not repeat", path, name));
+        }
+        steps.push(Step {
+            name: name.to_string(),
+            index,
+        });
+    }
+    if matches!(steps[0].name.as_str(), "resourceType" | "meta") {
+        return Err(format!("{}: {} is set by the server", path, steps[0].name));
+    }
+    Ok(steps)
+}
+
+/// The JSON value `text` is written as at `element`
+fn typed_value(element: &str, text: &str) -> Result<Value, String> {
+    if BOOLEAN_ELEMENTS.contains(&element) {
+        return match text.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "y" | "1" => Ok(Value::Bool(true)),
+            "false" | "no" | "n" | "0" => Ok(Value::Bool(false)),
+            _ => Err(format!("{:?} is not true or false", text)),
+        };
+    }
+    if INTEGER_ELEMENTS.contains(&element) {
+        return text
+            .parse::<u32>()
+            .map(Value::from)
+            .map_err(|_| format!("{:?} is not a whole number", text));
+    }
+    Ok(Value::String(text.to_string()))
+}
+
+/// Set the element at `steps` of `resource` to `value`. Items before an
+/// index that are missing are left `null`, for [`compact`] to drop.
+fn set(resource: &mut Map<String, Value>, steps: &[Step], value: Value) {
+    let mut object = resource;
+    let mut parent: Option<&str> = None;
+    for (position, step) in steps.iter().enumerate() {
+        let last = position == steps.len() - 1;
+        let slot = if repeats(parent, &step.name) {
+            let items = object
+                .entry(step.name.clone())
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if !items.is_array() {
+                *items = Value::Array(Vec::new());
+            }
+            let items = items.as_array_mut().expect("just made an array");
+            let index = step.index.unwrap_or(0);
+            if items.len() <= index {
+                items.resize(index + 1, Value::Null);
+            }
+            &mut items[index]
+        } else {
+            object.entry(step.name.clone()).or_insert(Value::Null)
+        };
+        if last {
+            *slot = value;
+            return;
+        }
+        if !slot.is_object() {
+            *slot = Value::Object(Map::new());
+        }
+        object = slot.as_object_mut().expect("just made an object");
+        parent = Some(&step.name);
+    }
+}
+
+/// Drop the `null`s [`set`] left in lists, so `name.given[1]` with an
+/// empty `name.given[0]` is the first given name
+fn compact(value: &mut Value) {
+    match value {
+        Value::Array(items) => {
+            items.retain(|item| !item.is_null());
+            items.iter_mut().for_each(compact);
+        }
+        Value::Object(members) => members.values_mut().for_each(compact),
+        _ => {}
+    }
+}
+
+/// A column of the header the mapping writes, with its element path
+struct MappedColumn<'a> {
+    header: &'a str,
+    position: usize,
+    steps: Vec<Step>,
+}
+
+/// A roster's rows as Patient resources, by the line each row starts on
+/// (the header is line 1)
+pub type RosterRows = Vec<(usize, Result<Value, String>)>;
+
+impl CsvMapping {
+    /// Whether the paths of the `[csv_mappings.<name>]` entry can be written
+    pub(crate) fn validate(&self, name: &str) -> anyhow::Result<()> {
+        if self.columns.is_empty() {
+            anyhow::bail!("csv_mappings.{}: no columns are mapped", name);
+        }
+        if matches!(self.delimiter, '"' | '\r' | '\n') {
+            anyhow::bail!(
+                "csv_mappings.{}: {:?} cannot be the delimiter",
+                name,
+                self.delimiter
+            );
+        }
+        let mut targets = BTreeMap::new();
+        for (source, path) in self
+            .columns
+            .iter()
+            .map(|(header, path)| (format!("column {}", header), path))
+            .chain(self.fixed.keys().map(|path| ("fixed".to_string(), path)))
+        {
+            let steps =
+                parse_path(path).map_err(|e| anyhow::anyhow!("csv_mappings.{}: {}", name, e))?;
+            let target = steps
+                .iter()
+                .map(|step| format!("{}[{}]", step.name, step.index.unwrap_or(0)))
+                .collect::<Vec<_>>()
+                .join(".");
+            if let Some(other) = targets.insert(target, source.clone()) {
+                anyhow::bail!(
+                    "csv_mappings.{}: {} and {} both write {}",
+                    name,
+                    other,
+                    source,
+                    path
+                );
+            }
+        }
+        self.fixed_values()
+            .map_err(|e| anyhow::anyhow!("csv_mappings.{}: {}", name, e))?;
+        Ok(())
+    }
+
+    fn fixed_values(&self) -> Result<Vec<(Vec<Step>, Value)>, String> {
+        self.fixed
+            .iter()
+            .map(|(path, value)| {
+                let steps = parse_path(path)?;
+                let element = &steps[steps.len() - 1].name;
+                let value = typed_value(element, value).map_err(|e| format!("{}: {}", path, e))?;
+                Ok((steps, value))
+            })
+            .collect()
+    }
+
+    /// The Patient of every row of `csv`, or why the roster cannot be read
+    /// at all: it has no header, or the header lacks a mapped column
+    pub fn patients(&self, csv: &str) -> Result<RosterRows, String> {
+        let mut records = parse_csv(csv, self.delimiter).into_iter();
+        let header = match records.next() {
+            Some((_, Ok(header))) => header,
+            Some((line, Err(e))) => return Err(format!("Line {}: {}", line, e)),
+            None => {
+                return Err("The roster is empty; its first line must be the header".to_string())
+            }
+        };
+        let header: Vec<&str> = header.iter().map(|name| name.trim()).collect();
+
+        let mut columns = Vec::with_capacity(self.columns.len());
+        let mut missing = Vec::new();
+        for (name, path) in &self.columns {
+            match header.iter().position(|header| header == name) {
+                Some(position) => columns.push(MappedColumn {
+                    header: name,
+                    position,
+                    steps: parse_path(path)?,
+                }),
+                None => missing.push(name.as_str()),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!("The header has no column {}", missing.join(", ")));
+        }
+        let fixed = self.fixed_values()?;
+
+        Ok(records
+            .map(|(line, fields)| {
+                let patient = fields.and_then(|fields| {
+                    if fields.len() != header.len() {
+                        return Err(format!(
+                            "Row has {} fields, the header has {}",
+                            fields.len(),
+                            header.len()
+                        ));
+                    }
+                    patient(&columns, &fixed, &fields)
+                });
+                (line, patient)
+            })
+            .collect())
+    }
+}
+
+fn patient(
+    columns: &[MappedColumn],
+    fixed: &[(Vec<Step>, Value)],
+    fields: &[String],
+) -> Result<Value, String> {
+    let mut patient = Map::new();
+    patient.insert("resourceType".to_string(), Value::from("Patient"));
+    for (steps, value) in fixed {
+        set(&mut patient, steps, value.clone());
+    }
+    for column in columns {
+        let text = fields[column.position].trim();
+        if text.is_empty() {
+            continue;
+        }
+        let element = &column.steps[column.steps.len() - 1].name;
+        let value = typed_value(element, text).map_err(|e| format!("{}: {}", column.header, e))?;
+        set(&mut patient, &column.steps, value);
+    }
+    let mut patient = Value::Object(patient);
+    compact(&mut patient);
+    Ok(patient)
+}
+
+/// The records of `text`, each with the line it starts on. A quote left
+/// open fails the record it opens, which runs to the end of the text.
+/// Blank lines are skipped.
+fn parse_csv(text: &str, delimiter: char) -> Vec<(usize, Result<Vec<String>, String>)> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut was_quoted = false;
+        loop {
+            let Some(c) = chars.next() else {
+                if quoted {
+                    records.push((start, Err("A quoted field is not closed".to_string())));
+                    return records;
+                }
+                break;
+            };
+            if quoted {
+                match c {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => quoted = false,
+                    '\n' => {
+                        line += 1;
+                        field.push(c);
+                    }
+                    _ => field.push(c),
+                }
+                continue;
+            }
+            match c {
+                '"' if field.is_empty() && !was_quoted => {
+                    quoted = true;
+                    was_quoted = true;
+                }
+                '\r' if chars.peek() == Some(&'\n') => {}
+                '\n' => {
+                    line += 1;
+                    break;
+                }
+                c if c == delimiter => {
+                    fields.push(std::mem::take(&mut field));
+                    was_quoted = false;
+                }
+                _ => field.push(c),
+            }
+        }
+        let blank = fields.is_empty() && field.trim().is_empty() && !was_quoted;
+        if !blank {
+            fields.push(field);
+            records.push((start, Ok(fields)));
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mapping(columns: &[(&str, &str)], fixed: &[(&str, &str)]) -> CsvMapping {
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        CsvMapping {
+            columns: pairs(columns),
+            fixed: pairs(fixed),
+            delimiter: ',',
+        }
+    }
+
+    #[test]
+    fn test_parse_csv_follows_rfc_4180() {
+        let csv = "\u{feff}a,b,c\r\n1,\"two, \"\"quoted\"\"\",3\r\n\r\n\"multi\nline\",,\n x ,y,z";
+        let records = parse_csv(csv, ',');
+        assert_eq!(
+            records,
+            vec![
+                (1, Ok(vec!["a".into(), "b".into(), "c".into()])),
+                (
+                    2,
+                    Ok(vec!["1".into(), "two, \"quoted\"".into(), "3".into()])
+                ),
+                (4, Ok(vec!["multi\nline".into(), "".into(), "".into()])),
+                (6, Ok(vec![" x ".into(), "y".into(), "z".into()])),
+            ]
+        );
+        assert_eq!(
+            parse_csv("a;b", ';'),
+            vec![(1, Ok(vec!["a".into(), "b".into()]))]
+        );
+        assert_eq!(
+            parse_csv("a\n\"open,b\nc", ','),
+            vec![
+                (1, Ok(vec!["a".into()])),
+                (2, Err("A quoted field is not closed".into()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rows_become_patients() {
+        let mapping = mapping(
+            &[
+                ("MRN", "identifier.value"),
+                ("Surname", "Patient.name.family"),
+                ("First", "name.given[0]"),
+                ("Middle", "name.given[1]"),
+                ("DOB", "birthDate"),
+                ("Active", "active"),
+            ],
+            &[("identifier.system", "http://hospital.example.org/mrn")],
+        );
+        let csv = "MRN,Surname,First,Middle,DOB,Active,Ward\n\
+                   4711,Okafor,Ada,,1980-02-01,yes,B\n\
+                   4712,Lund,Per,Erik,1975-07-30,N,C\n\
+                   4713,Mori,Ken,,1990-01-01,maybe,A\n\
+                   4714,Short";
+        let rows = mapping.patients(csv).unwrap();
+        assert_eq!(
+            rows[0],
+            (
+                2,
+                Ok(json!({
+                    "resourceType": "Patient",
+                    "identifier": [{ "system": "http://hospital.example.org/mrn", "value": "4711" }],
+                    "name": [{ "family": "Okafor", "given": ["Ada"] }],
+                    "birthDate": "1980-02-01",
+                    "active": true
+                }))
+            )
+        );
+        assert_eq!(
+            rows[1].1.as_ref().unwrap()["name"][0]["given"],
+            json!(["Per", "Erik"])
+        );
+        assert_eq!(rows[1].1.as_ref().unwrap()["active"], false);
+        assert_eq!(
+            rows[2],
+            (4, Err("Active: \"maybe\" is not true or false".to_string()))
+        );
+        assert_eq!(
+            rows[3],
+            (5, Err("Row has 2 fields, the header has 7".to_string()))
+        );
+
+        assert_eq!(
+            mapping.patients("MRN,Surname\n1,2"),
+            Err("The header has no column Active, DOB, First, Middle".to_string())
+        );
+        assert!(mapping.patients("").is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_unwritable_paths() {
+        assert!(mapping(&[("A", "name.given[1]")], &[])
+            .validate("m")
+            .is_ok());
+        for (columns, fixed) in [
+            (vec![], vec![]),
+            (vec![("A", "gender[0]")], vec![]),
+            (vec![("A", "name..family")], vec![]),
+            (vec![("A", "name[x]")], vec![]),
+            (vec![("A", "resourceType")], vec![]),
+            (vec![("A", "name.family"), ("B", "name[0].family")], vec![]),
+            (vec![("A", "gender")], vec![("gender", "female")]),
+            (vec![("A", "gender")], vec![("active", "maybe")]),
+        ] {
+            assert!(
+                mapping(&columns, &fixed).validate("m").is_err(),
+                "{:?} {:?}",
+                columns,
+                fixed
+            );
+        }
+    }
+}
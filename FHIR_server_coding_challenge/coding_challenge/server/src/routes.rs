@@ -0,0 +1,371 @@
+use crate::api_keys;
+use crate::authorization;
+use crate::capture;
+use crate::conditional;
+use crate::consent;
+use crate::deprecation;
+use crate::elements;
+use crate::extract;
+use crate::handlers::{
+    admin, bundle, changes, conformance, history, jobs, lock, mapping, metadata, metrics,
+    observation, patient, provenance, resource, search_parameter, sharing, subscription,
+};
+use crate::limits;
+use crate::negotiation;
+use crate::slo;
+use crate::state::AppState;
+use crate::throttle;
+use axum::{
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    http::Method,
+    middleware,
+    routing::{MethodFilter, MethodRouter},
+    Router,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::trace::TraceLayer;
+
+/// Who may call a route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteAccess {
+    /// Open without a key; listed in the default `auth.unauthenticated_paths`
+    Public,
+    /// Needs a key with `[auth] required`
+    Authenticated,
+    /// Needs a key once one is stored
+    Admin,
+}
+
+/// The access of every route below; a test fails when a route is added
+/// without an entry here
+pub const ROUTE_ACCESS: &[(&str, RouteAccess)] = &[
+    ("/fhir", RouteAccess::Authenticated),
+    ("/fhir/_changes", RouteAccess::Authenticated),
+    ("/fhir/_events", RouteAccess::Authenticated),
+    ("/fhir/_history", RouteAccess::Authenticated),
+    ("/fhir/_jobs/:id", RouteAccess::Authenticated),
+    ("/fhir/_jobs/:id/output/:index", RouteAccess::Authenticated),
+    ("/fhir/metadata", RouteAccess::Public),
+    ("/fhir/$export", RouteAccess::Authenticated),
+    ("/fhir/$versions", RouteAccess::Public),
+    ("/fhir/$resolve", RouteAccess::Authenticated),
+    (
+        "/fhir/ConceptMap/:id/$transform",
+        RouteAccess::Authenticated,
+    ),
+    (
+        "/fhir/StructureMap/:id/$transform",
+        RouteAccess::Authenticated,
+    ),
+    ("/fhir/Patient", RouteAccess::Authenticated),
+    ("/fhir/Patient/$cohort", RouteAccess::Authenticated),
+    ("/fhir/Patient/$match", RouteAccess::Authenticated),
+    ("/fhir/Patient/$reserve-id", RouteAccess::Authenticated),
+    ("/fhir/Patient/_history", RouteAccess::Authenticated),
+    ("/fhir/Patient/:id/_history", RouteAccess::Authenticated),
+    ("/fhir/Patient/:id/$conflict", RouteAccess::Authenticated),
+    (
+        "/fhir/Patient/:id/$merge-update",
+        RouteAccess::Authenticated,
+    ),
+    ("/fhir/Patient/:id/$share", RouteAccess::Authenticated),
+    ("/fhir/Patient/:id/$lock", RouteAccess::Authenticated),
+    ("/fhir/Patient/:id/$unlock", RouteAccess::Authenticated),
+    // The signed token is the credential
+    ("/fhir/_share/:token", RouteAccess::Public),
+    // Browsers cannot send a key with a WebSocket; the Subscription's id
+    // is the credential, and pings carry nothing else
+    ("/fhir/subscriptions/:id", RouteAccess::Public),
+    ("/fhir/Patient/:id", RouteAccess::Authenticated),
+    ("/fhir/Observation", RouteAccess::Authenticated),
+    ("/fhir/Observation/:id", RouteAccess::Authenticated),
+    ("/fhir/Provenance", RouteAccess::Authenticated),
+    ("/fhir/Provenance/:id", RouteAccess::Authenticated),
+    ("/fhir/SearchParameter", RouteAccess::Authenticated),
+    ("/fhir/:resource_type", RouteAccess::Authenticated),
+    ("/fhir/:resource_type/:id", RouteAccess::Authenticated),
+    ("/metrics", RouteAccess::Public),
+    ("/.well-known/smart-configuration", RouteAccess::Public),
+    ("/admin/about", RouteAccess::Admin),
+    ("/admin/collection.json", RouteAccess::Admin),
+    ("/admin/requests/:request_id", RouteAccess::Admin),
+    ("/admin/archive", RouteAccess::Admin),
+    ("/admin/packages", RouteAccess::Admin),
+    ("/admin/import", RouteAccess::Admin),
+    ("/admin/import/csv", RouteAccess::Admin),
+    ("/admin/api-keys", RouteAccess::Admin),
+    ("/admin/api-keys/:name", RouteAccess::Admin),
+    ("/admin/snapshots", RouteAccess::Admin),
+    ("/admin/snapshots/:name", RouteAccess::Admin),
+    ("/admin/snapshots/:name/restore", RouteAccess::Admin),
+    ("/admin/tenants/:organization/export", RouteAccess::Admin),
+    ("/admin/search-parameters", RouteAccess::Admin),
+    ("/admin/search-parameters/:id", RouteAccess::Admin),
+];
+
+/// A path of the router with the handlers of its methods
+pub struct Route {
+    pub path: &'static str,
+    /// The methods answered, in the order they were added
+    pub methods: Vec<Method>,
+    handlers: MethodRouter<AppState>,
+}
+
+impl Route {
+    fn new(path: &'static str) -> Self {
+        Self {
+            path,
+            methods: Vec::new(),
+            handlers: MethodRouter::new(),
+        }
+    }
+
+    fn on<H, T>(mut self, method: Method, handler: H) -> Self
+    where
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone()).expect("a routable method");
+        self.handlers = self.handlers.on(filter, handler);
+        self.methods.push(method);
+        self
+    }
+
+    fn get<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> Self {
+        self.on(Method::GET, handler)
+    }
+
+    fn post<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> Self {
+        self.on(Method::POST, handler)
+    }
+
+    fn put<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> Self {
+        self.on(Method::PUT, handler)
+    }
+
+    fn patch<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> Self {
+        self.on(Method::PATCH, handler)
+    }
+
+    fn delete<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> Self {
+        self.on(Method::DELETE, handler)
+    }
+
+    /// Accept request bodies of up to `limit` bytes on this path
+    fn body_limit(mut self, limit: usize) -> Self {
+        self.handlers = self.handlers.layer(DefaultBodyLimit::max(limit));
+        self
+    }
+}
+
+/// The routes of [`router`]; [`ROUTE_ACCESS`] has an entry for each path
+pub fn routes() -> Vec<Route> {
+    vec![
+        Route::new("/fhir").post(bundle::process_bundle),
+        Route::new("/fhir/_changes").get(changes::get_changes),
+        Route::new("/fhir/_events").get(changes::get_events),
+        Route::new("/fhir/_history").get(history::get_system_history),
+        Route::new("/fhir/_jobs/:id")
+            .get(jobs::get_job)
+            .delete(jobs::delete_job),
+        Route::new("/fhir/_jobs/:id/output/:index").get(jobs::get_job_output),
+        Route::new("/fhir/metadata").get(metadata::get_metadata),
+        Route::new("/fhir/$export").get(jobs::export_system),
+        Route::new("/fhir/$versions").get(metadata::get_versions),
+        Route::new("/fhir/$resolve").get(conformance::resolve_canonical),
+        Route::new("/fhir/ConceptMap/:id/$transform").post(mapping::transform_with_concept_map),
+        Route::new("/fhir/StructureMap/:id/$transform").post(mapping::transform_with_structure_map),
+        Route::new("/fhir/Patient")
+            .get(patient::search_patients)
+            .post(patient::create_patient)
+            .put(patient::conditional_update_patient),
+        Route::new("/fhir/Patient/$cohort").post(patient::patient_cohort),
+        Route::new("/fhir/Patient/$match").post(patient::match_patients),
+        Route::new("/fhir/Patient/$reserve-id").post(patient::reserve_patient_id),
+        Route::new("/fhir/Patient/_history").get(history::get_patient_type_history),
+        Route::new("/fhir/Patient/:id/_history").get(patient::get_patient_history),
+        Route::new("/fhir/Patient/:id/$conflict").get(patient::get_patient_conflict),
+        Route::new("/fhir/Patient/:id/$merge-update").post(patient::merge_update_patient),
+        Route::new("/fhir/Patient/:id/$share").post(sharing::share_patient),
+        Route::new("/fhir/Patient/:id/$lock").post(lock::lock_patient),
+        Route::new("/fhir/Patient/:id/$unlock").post(lock::unlock_patient),
+        Route::new("/fhir/_share/:token").get(sharing::read_shared),
+        Route::new("/fhir/subscriptions/:id").get(subscription::subscription_channel),
+        Route::new("/fhir/Patient/:id")
+            .get(patient::get_patient)
+            .put(patient::update_patient)
+            .patch(patient::patch_patient)
+            .delete(patient::delete_patient),
+        Route::new("/fhir/Observation")
+            .get(observation::search_observations)
+            .post(observation::create_observation),
+        Route::new("/fhir/Observation/:id")
+            .get(observation::get_observation)
+            .put(observation::update_observation)
+            .delete(observation::delete_observation),
+        Route::new("/fhir/Provenance").get(provenance::search_provenance),
+        Route::new("/fhir/Provenance/:id").get(provenance::get_provenance),
+        Route::new("/fhir/SearchParameter")
+            .get(search_parameter::search_search_parameters)
+            .post(search_parameter::create_search_parameter),
+        // Any other type registered in `resource_types`; the static routes
+        // above take precedence
+        Route::new("/fhir/:resource_type")
+            .get(resource::search_resources)
+            .post(resource::create_resource),
+        Route::new("/fhir/:resource_type/:id")
+            .get(resource::get_resource)
+            .put(resource::update_resource)
+            .delete(resource::delete_resource),
+        Route::new("/metrics").get(metrics::get_metrics),
+        Route::new("/.well-known/smart-configuration").get(metadata::get_smart_configuration),
+        Route::new("/admin/about").get(admin::get_about),
+        Route::new("/admin/collection.json").get(admin::get_collection),
+        Route::new("/admin/requests/:request_id").get(admin::get_request_capture),
+        Route::new("/admin/archive")
+            .get(admin::get_archive_report)
+            .post(admin::run_archive),
+        Route::new("/admin/packages")
+            .post(admin::import_package)
+            .body_limit(admin::MAX_PACKAGE_BYTES),
+        Route::new("/admin/import")
+            .post(admin::import_resources)
+            .body_limit(admin::MAX_IMPORT_BYTES),
+        Route::new("/admin/import/csv")
+            .post(admin::import_csv_roster)
+            .body_limit(admin::MAX_IMPORT_BYTES),
+        Route::new("/admin/api-keys")
+            .get(admin::list_api_keys)
+            .post(admin::create_api_key),
+        Route::new("/admin/api-keys/:name")
+            .put(admin::update_api_key)
+            .delete(admin::delete_api_key),
+        Route::new("/admin/snapshots").get(admin::list_snapshots),
+        Route::new("/admin/snapshots/:name")
+            .post(admin::create_snapshot)
+            .delete(admin::delete_snapshot),
+        Route::new("/admin/snapshots/:name/restore").post(admin::restore_snapshot),
+        Route::new("/admin/tenants/:organization/export").post(admin::export_tenant),
+        Route::new("/admin/search-parameters").post(search_parameter::add_search_parameter),
+        Route::new("/admin/search-parameters/:id")
+            .delete(search_parameter::remove_search_parameter),
+    ]
+}
+
+/// Build the application router with all FHIR routes
+pub fn router(state: AppState) -> Router {
+    // Origins are checked against the live config so CORS changes apply on reload
+    let config = state.config.clone();
+    let cors = CorsLayer::permissive().allow_origin(AllowOrigin::predicate(move |origin, _| {
+        origin
+            .to_str()
+            .map(|o| config.get().cors_allows(o))
+            .unwrap_or(false)
+    }));
+
+    routes()
+        .into_iter()
+        .fold(Router::new(), |router, route| {
+            router.route(route.path, route.handlers)
+        })
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            extract::outcome_for_rejections,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            limits::enforce_resource_limits,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            jobs::respond_async,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            throttle::limit_writes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            capture::capture_failures,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            deprecation::announce_deprecations,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            consent::break_glass,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            authorization::authorize,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_keys::require_api_key,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            slo::track_latency,
+        ))
+        .layer(middleware::from_fn(elements::select_elements))
+        .layer(middleware::from_fn(negotiation::negotiate_format))
+        .layer(middleware::from_fn(conditional::not_modified))
+        .layer(cors)
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::{is_admin_path, AuthConfig};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_every_route_has_an_access_policy() {
+        let listed: BTreeSet<&str> = ROUTE_ACCESS.iter().map(|(path, _)| *path).collect();
+        assert_eq!(listed.len(), ROUTE_ACCESS.len(), "a route is listed twice");
+        let routed: BTreeSet<&str> = routes().iter().map(|route| route.path).collect();
+        assert!(routed.contains("/fhir/Patient/:id"));
+        let unlisted: Vec<_> = routed.difference(&listed).collect();
+        assert!(
+            unlisted.is_empty(),
+            "no entry in ROUTE_ACCESS: {:?}",
+            unlisted
+        );
+        let stale: Vec<_> = listed.difference(&routed).collect();
+        assert!(
+            stale.is_empty(),
+            "in ROUTE_ACCESS but not routed: {:?}",
+            stale
+        );
+
+        // The default config enforces the policy
+        let auth = AuthConfig::default();
+        for (path, access) in ROUTE_ACCESS {
+            let sample = path
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with(':') {
+                        "x"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let expected = (
+                *access == RouteAccess::Public,
+                *access == RouteAccess::Admin,
+            );
+            assert_eq!(
+                (auth.is_unauthenticated(&sample), is_admin_path(&sample)),
+                expected,
+                "{}",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Sandboxed execution of deployer-supplied WebAssembly modules.
+//!
+//! Modules are compiled once and run in a fresh, import-free instance per
+//! call, with a fuel budget and a memory cap so a faulty module cannot stall
+//! or exhaust the server. Data is exchanged as bytes through the module's
+//! exported `memory`:
+//!
+//! - `fhir_alloc(len: i32) -> i32`: returns a buffer of `len` bytes for the input
+//! - the entry point `(ptr: i32, len: i32) -> i64` returns `(ptr << 32) | len`
+//!   of its output, or 0 for no output
+
+use anyhow::{Context, Result};
+use std::sync::OnceLock;
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Upper bound on an instance's linear memory
+pub const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+pub fn default_fuel() -> u64 {
+    10_000_000
+}
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    })
+}
+
+fn wasm_err(e: wasmi::Error) -> anyhow::Error {
+    anyhow::anyhow!("{}", e)
+}
+
+/// A compiled module with its per-call fuel budget
+pub struct SandboxModule {
+    module: Module,
+    fuel: u64,
+}
+
+impl SandboxModule {
+    /// Compile `.wasm` or `.wat` bytes
+    pub fn compile(bytes: &[u8], fuel: u64) -> Result<Self> {
+        let module = Module::new(engine(), bytes).map_err(wasm_err)?;
+        Ok(Self { module, fuel })
+    }
+
+    /// Pass `input` to the exported `entry` function and return its output
+    pub fn call(&self, entry: &str, input: &[u8]) -> Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build();
+        let mut store: Store<StoreLimits> = Store::new(engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel).map_err(wasm_err)?;
+
+        let instance = Linker::new(engine())
+            .instantiate_and_start(&mut store, &self.module)
+            .map_err(wasm_err)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .context("module does not export memory")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "fhir_alloc")
+            .map_err(wasm_err)?;
+        let entry_fn = instance
+            .get_typed_func::<(i32, i32), i64>(&store, entry)
+            .map_err(wasm_err)?;
+
+        let len = i32::try_from(input.len()).context("input too large")?;
+        let ptr = alloc.call(&mut store, len).map_err(wasm_err)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(|e| anyhow::anyhow!("fhir_alloc returned an invalid buffer: {}", e))?;
+
+        let packed = entry_fn.call(&mut store, (ptr, len)).map_err(wasm_err)?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let out_ptr = (packed as u64 >> 32) as usize;
+        let out_len = (packed as u64 & 0xffff_ffff) as usize;
+        // Refuse a buffer outside the instance's memory before allocating it
+        let in_memory = out_ptr
+            .checked_add(out_len)
+            .is_some_and(|end| end <= memory.data_size(&store) && end <= MEMORY_LIMIT);
+        if !in_memory {
+            anyhow::bail!(
+                "{} returned an invalid buffer: {} bytes at {}",
+                entry,
+                out_len,
+                out_ptr
+            );
+        }
+        let mut output = vec![0u8; out_len];
+        memory
+            .read(&store, out_ptr, &mut output)
+            .map_err(|e| anyhow::anyhow!("{} returned an invalid buffer: {}", entry, e))?;
+
+        Ok(Some(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_outside_memory_is_refused() {
+        // A 4 GiB output claimed by a module with a single 64 KiB page
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "fhir_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "run") (param i32 i32) (result i64) i64.const 0xffffffff))"#;
+        let module = SandboxModule::compile(wat.as_bytes(), default_fuel()).unwrap();
+
+        let err = module.call("run", b"{}").unwrap_err();
+        assert!(err.to_string().contains("invalid buffer"));
+    }
+}
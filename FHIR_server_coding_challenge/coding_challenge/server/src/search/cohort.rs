@@ -0,0 +1,429 @@
+//! Criteria of `POST /fhir/Patient/$cohort`.
+//!
+//! A cohort is described by a boolean tree of Patient search criteria:
+//!
+//! ```json
+//! {
+//!   "criteria": {
+//!     "and": [
+//!       { "param": "gender", "value": "female" },
+//!       { "or": [
+//!         { "param": "birthdate", "value": "ge1950" },
+//!         { "not": { "param": "name:phonetic", "value": "Meier" } }
+//!       ]}
+//!     ]
+//!   },
+//!   "result": "count"
+//! }
+//! ```
+//!
+//! Leaves take the same parameters and value syntax as `GET /fhir/Patient`
+//! plus `identifier`, so `"value": "male,female"` matches either gender;
+//! each alternative counts toward [`MAX_CRITERIA`]. The whole tree is
+//! compiled to a single SQL condition by `Database::cohort_ids` and
+//! `Database::cohort_count`.
+
+use super::date::DateSearchValue;
+use super::modifier::{self, parse_missing};
+use super::value::{split_or, InvalidSearchValue, TokenParam};
+use serde_json::Value;
+use std::fmt;
+
+/// Deepest nesting of groups accepted in one query
+pub const MAX_DEPTH: usize = 16;
+/// Most leaf criteria accepted in one query
+pub const MAX_CRITERIA: usize = 100;
+
+/// A node of the criteria tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Criterion {
+    And(Vec<Criterion>),
+    Or(Vec<Criterion>),
+    Not(Box<Criterion>),
+    Param(PatientParam),
+}
+
+/// A single Patient search criterion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatientParam {
+    /// `name` and `name:contains`: substring of the first family or given name
+    Name(String),
+    /// `name:exact`: the whole first family or given name, case-sensitive
+    NameExact(String),
+    /// `name:phonetic`: every word sounds like one of the patient's name words
+    NamePhonetic(String),
+    Gender(String),
+    BirthDate(DateSearchValue),
+    Identifier(TokenParam),
+    /// `<param>:missing`: the Patient element is absent (or present if
+    /// `missing` is false)
+    Missing {
+        element: &'static str,
+        missing: bool,
+    },
+}
+
+/// What `$cohort` returns for the matching patients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CohortResult {
+    /// The ids and their count
+    #[default]
+    Ids,
+    /// Only the count
+    Count,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CohortQuery {
+    pub criteria: Criterion,
+    pub result: CohortResult,
+}
+
+/// A request body that is not a valid cohort query; `path` locates the
+/// offending element, e.g. `criteria.and[1].value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCohort {
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidCohort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid cohort query at {}: {}", self.path, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidCohort {}
+
+fn invalid(path: &str, reason: impl Into<String>) -> InvalidCohort {
+    InvalidCohort {
+        path: path.to_string(),
+        reason: reason.into(),
+    }
+}
+
+impl CohortQuery {
+    pub fn parse(body: &Value) -> Result<Self, InvalidCohort> {
+        let body = body
+            .as_object()
+            .ok_or_else(|| invalid("$", "expected a JSON object"))?;
+        if let Some(key) = body.keys().find(|k| *k != "criteria" && *k != "result") {
+            return Err(invalid(key, "unknown element"));
+        }
+
+        let result = match body.get("result").map(|r| r.as_str()) {
+            None | Some(Some("ids")) => CohortResult::Ids,
+            Some(Some("count")) => CohortResult::Count,
+            _ => return Err(invalid("result", "expected \"ids\" or \"count\"")),
+        };
+        let criteria = body
+            .get("criteria")
+            .ok_or_else(|| invalid("criteria", "required"))?;
+        let mut leaves = 0;
+        let criteria = Criterion::parse(criteria, "criteria", 1, &mut leaves)?;
+
+        Ok(Self { criteria, result })
+    }
+}
+
+impl Criterion {
+    fn parse(
+        node: &Value,
+        path: &str,
+        depth: usize,
+        leaves: &mut usize,
+    ) -> Result<Self, InvalidCohort> {
+        if depth > MAX_DEPTH {
+            return Err(invalid(
+                path,
+                format!("groups may be nested at most {} deep", MAX_DEPTH),
+            ));
+        }
+        let map = node
+            .as_object()
+            .ok_or_else(|| invalid(path, "expected an object"))?;
+
+        let mut group = |key: &str| -> Result<Vec<Criterion>, InvalidCohort> {
+            let path = format!("{}.{}", path, key);
+            let members = map[key]
+                .as_array()
+                .filter(|members| !members.is_empty())
+                .ok_or_else(|| invalid(&path, "expected a non-empty array"))?;
+            members
+                .iter()
+                .enumerate()
+                .map(|(i, member)| {
+                    Criterion::parse(member, &format!("{}[{}]", path, i), depth + 1, leaves)
+                })
+                .collect()
+        };
+
+        let keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        match keys.as_slice() {
+            ["and"] => Ok(Criterion::And(group("and")?)),
+            ["or"] => Ok(Criterion::Or(group("or")?)),
+            ["not"] => {
+                let inner =
+                    Criterion::parse(&map["not"], &format!("{}.not", path), depth + 1, leaves)?;
+                Ok(Criterion::Not(Box::new(inner)))
+            }
+            _ if map.contains_key("param") => {
+                let mut alternatives = PatientParam::parse(map, path)?;
+                *leaves += alternatives.len();
+                if *leaves > MAX_CRITERIA {
+                    return Err(invalid(
+                        path,
+                        format!("at most {} criteria are allowed", MAX_CRITERIA),
+                    ));
+                }
+                Ok(match alternatives.len() {
+                    1 => Criterion::Param(alternatives.remove(0)),
+                    _ => Criterion::Or(alternatives.into_iter().map(Criterion::Param).collect()),
+                })
+            }
+            _ => Err(invalid(
+                path,
+                "expected exactly one of and, or, not, or a param criterion",
+            )),
+        }
+    }
+
+    /// Whether any criterion needs the phonetic name index
+    pub fn uses_phonetic(&self) -> bool {
+        self.any_param(&|param| matches!(param, PatientParam::NamePhonetic(_)))
+    }
+
+    /// Whether any criterion needs the normalized name index
+    pub fn uses_name(&self) -> bool {
+        self.any_param(&|param| matches!(param, PatientParam::Name(_)))
+    }
+
+    fn any_param(&self, test: &dyn Fn(&PatientParam) -> bool) -> bool {
+        match self {
+            Criterion::And(members) | Criterion::Or(members) => {
+                members.iter().any(|member| member.any_param(test))
+            }
+            Criterion::Not(inner) => inner.any_param(test),
+            Criterion::Param(param) => test(param),
+        }
+    }
+}
+
+impl PatientParam {
+    /// The criteria of a leaf, one per comma-separated alternative of its
+    /// value
+    fn parse(map: &serde_json::Map<String, Value>, path: &str) -> Result<Vec<Self>, InvalidCohort> {
+        if let Some(key) = map.keys().find(|k| *k != "param" && *k != "value") {
+            return Err(invalid(&format!("{}.{}", path, key), "unknown element"));
+        }
+        let param = map["param"]
+            .as_str()
+            .ok_or_else(|| invalid(&format!("{}.param", path), "expected a string"))?;
+        let value = map
+            .get("value")
+            .and_then(Value::as_str)
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| invalid(&format!("{}.value", path), "expected a non-empty string"))?;
+        // `true,false` is not a list of alternatives
+        if modifier::split(param).1 == Some(modifier::MISSING) {
+            return Self::parse_value(param, value, path).map(|param| vec![param]);
+        }
+        let value_path = format!("{}.value", path);
+        split_or(value)
+            .iter()
+            .map(|alternative| match alternative.as_str() {
+                "" => Err(invalid(&value_path, "expected no empty alternatives")),
+                alternative => Self::parse_value(param, alternative, path),
+            })
+            .collect()
+    }
+
+    fn parse_value(param: &str, value: &str, path: &str) -> Result<Self, InvalidCohort> {
+        let bad_value = |e: InvalidSearchValue| invalid(&format!("{}.value", path), e.reason);
+
+        match param {
+            "name" | "name:contains" => Ok(PatientParam::Name(value.to_string())),
+            "name:exact" => Ok(PatientParam::NameExact(value.to_string())),
+            "name:phonetic" => Ok(PatientParam::NamePhonetic(value.to_string())),
+            "gender" => Ok(PatientParam::Gender(value.to_string())),
+            "birthdate" => DateSearchValue::parse(param, value)
+                .map(PatientParam::BirthDate)
+                .map_err(bad_value),
+            "identifier" => {
+                let token = TokenParam::parse(value);
+                if token.code.is_none() && token.system.as_deref().is_none_or(str::is_empty) {
+                    return Err(invalid(
+                        &format!("{}.value", path),
+                        "expected value, system|value, system| or |value",
+                    ));
+                }
+                Ok(PatientParam::Identifier(token))
+            }
+            "name:missing" | "gender:missing" | "birthdate:missing" | "identifier:missing" => {
+                let element = match param {
+                    "name:missing" => "name",
+                    "gender:missing" => "gender",
+                    "birthdate:missing" => "birthDate",
+                    _ => "identifier",
+                };
+                parse_missing(param, value)
+                    .map(|missing| PatientParam::Missing { element, missing })
+                    .map_err(bad_value)
+            }
+            other => Err(invalid(
+                &format!("{}.param", path),
+                format!("unsupported parameter {}", other),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tree() {
+        let query = CohortQuery::parse(&json!({
+            "criteria": {
+                "and": [
+                    { "param": "gender", "value": "female" },
+                    { "or": [
+                        { "param": "birthdate", "value": "ge1950" },
+                        { "not": { "param": "name:phonetic", "value": "Meier" } }
+                    ]}
+                ]
+            },
+            "result": "count"
+        }))
+        .unwrap();
+
+        assert_eq!(query.result, CohortResult::Count);
+        assert!(query.criteria.uses_phonetic());
+        let Criterion::And(members) = &query.criteria else {
+            panic!("expected an and group");
+        };
+        assert_eq!(
+            members[0],
+            Criterion::Param(PatientParam::Gender("female".to_string()))
+        );
+        let Criterion::Or(alternatives) = &members[1] else {
+            panic!("expected an or group");
+        };
+        assert_eq!(
+            alternatives[0],
+            Criterion::Param(PatientParam::BirthDate(
+                DateSearchValue::parse("birthdate", "ge1950").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_comma_values_are_alternatives() {
+        let query = CohortQuery::parse(&json!({
+            "criteria": { "param": "gender", "value": "male,female" }
+        }))
+        .unwrap();
+        assert_eq!(
+            query.criteria,
+            Criterion::Or(vec![
+                Criterion::Param(PatientParam::Gender("male".to_string())),
+                Criterion::Param(PatientParam::Gender("female".to_string())),
+            ])
+        );
+
+        let query = CohortQuery::parse(&json!({
+            "criteria": { "param": "name:exact", "value": r"Smith\, Jr" }
+        }))
+        .unwrap();
+        assert_eq!(
+            query.criteria,
+            Criterion::Param(PatientParam::NameExact("Smith, Jr".to_string()))
+        );
+
+        let err = CohortQuery::parse(&json!({
+            "criteria": { "param": "gender", "value": "male," }
+        }))
+        .unwrap_err();
+        assert_eq!(err.path, "criteria.value");
+        let err = CohortQuery::parse(&json!({
+            "criteria": { "param": "gender:missing", "value": "true,false" }
+        }))
+        .unwrap_err();
+        assert_eq!(err.path, "criteria.value");
+        let err = CohortQuery::parse(&json!({
+            "criteria": { "param": "birthdate", "value": "ge1950,xx1960" }
+        }))
+        .unwrap_err();
+        assert_eq!(err.path, "criteria.value");
+    }
+
+    #[test]
+    fn test_result_defaults_to_ids() {
+        let query = CohortQuery::parse(&json!({
+            "criteria": { "param": "identifier", "value": "urn:mrn|42" }
+        }))
+        .unwrap();
+        assert_eq!(query.result, CohortResult::Ids);
+        assert!(!query.criteria.uses_phonetic());
+
+        let query = CohortQuery::parse(&json!({
+            "criteria": { "param": "birthdate:missing", "value": "true" }
+        }))
+        .unwrap();
+        assert_eq!(
+            query.criteria,
+            Criterion::Param(PatientParam::Missing {
+                element: "birthDate",
+                missing: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_errors_name_the_offending_element() {
+        let path = |body: Value| CohortQuery::parse(&body).unwrap_err().path;
+
+        assert_eq!(path(json!({})), "criteria");
+        assert_eq!(path(json!({ "criteria": { "and": [] } })), "criteria.and");
+        assert_eq!(
+            path(json!({ "criteria": { "or": [
+                { "param": "gender", "value": "male" },
+                { "param": "birthdate", "value": "1990-13" }
+            ]}})),
+            "criteria.or[1].value"
+        );
+        assert_eq!(
+            path(json!({ "criteria": { "not": { "param": "telecom", "value": "x" } } })),
+            "criteria.not.param"
+        );
+        assert_eq!(
+            path(json!({ "criteria": { "param": "gender:missing", "value": "yes" } })),
+            "criteria.value"
+        );
+        assert_eq!(
+            path(json!({ "criteria": { "and": [], "or": [] } })),
+            "criteria"
+        );
+        assert_eq!(
+            path(json!({ "criteria": { "param": "gender", "value": "male" }, "result": "rows" })),
+            "result"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut deep = json!({ "param": "gender", "value": "male" });
+        for _ in 0..MAX_DEPTH {
+            deep = json!({ "not": deep });
+        }
+        assert!(CohortQuery::parse(&json!({ "criteria": deep })).is_err());
+
+        let wide: Vec<Value> = (0..=MAX_CRITERIA)
+            .map(|_| json!({ "param": "gender", "value": "male" }))
+            .collect();
+        let err = CohortQuery::parse(&json!({ "criteria": { "or": wide } })).unwrap_err();
+        assert!(err.reason.contains("at most"));
+    }
+}
@@ -0,0 +1,85 @@
+//! Criteria of conditional interactions (`If-None-Exist` on create).
+//!
+//! Conditional interactions must resolve to at most one existing resource,
+//! so only `identifier` is accepted: it is the one Patient parameter meant
+//! to identify a single patient, and ignoring any other parameter would
+//! silently widen the match.
+
+use super::value::{InvalidSearchValue, TokenParam};
+
+/// Criteria selecting an existing patient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatientCriteria {
+    pub identifier: TokenParam,
+}
+
+impl PatientCriteria {
+    /// Parse a search query string such as `identifier=urn:mrn|42`
+    pub fn from_query(query: &str) -> Result<Self, InvalidSearchValue> {
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|_| InvalidSearchValue {
+                param: "criteria".to_string(),
+                value: query.to_string(),
+                reason: "not a valid query string",
+            })?;
+        Self::parse(params)
+    }
+
+    pub fn parse(params: Vec<(String, String)>) -> Result<Self, InvalidSearchValue> {
+        let mut identifier = None;
+        for (name, value) in params {
+            let invalid = |reason| InvalidSearchValue {
+                param: name.clone(),
+                value: value.clone(),
+                reason,
+            };
+            if name != "identifier" {
+                return Err(invalid(
+                    "only identifier is supported in conditional criteria",
+                ));
+            }
+            if identifier.is_some() {
+                return Err(invalid("identifier may only be given once"));
+            }
+            let token = TokenParam::parse(&value);
+            if token.code.is_none() && token.system.as_deref().is_none_or(str::is_empty) {
+                return Err(invalid("expected value, system|value, system| or |value"));
+            }
+            identifier = Some(token);
+        }
+
+        identifier
+            .map(|identifier| Self { identifier })
+            .ok_or(InvalidSearchValue {
+                param: "identifier".to_string(),
+                value: String::new(),
+                reason: "conditional criteria must include an identifier",
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_query() {
+        let criteria =
+            PatientCriteria::from_query("identifier=http%3A%2F%2Fhospital.example%2Fmrn%7C42")
+                .unwrap();
+        assert_eq!(
+            criteria.identifier,
+            TokenParam {
+                system: Some("http://hospital.example/mrn".to_string()),
+                code: Some("42".to_string()),
+            }
+        );
+
+        assert!(PatientCriteria::from_query("identifier=42").is_ok());
+        assert!(PatientCriteria::from_query("").is_err());
+        assert!(PatientCriteria::from_query("identifier=").is_err());
+        assert!(PatientCriteria::from_query("identifier=|").is_err());
+        assert!(PatientCriteria::from_query("name=Smith").is_err());
+        assert!(PatientCriteria::from_query("identifier=1&identifier=2").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Date search values: a prefix and the range of instants the value stands
+//! for. `2024` is all of that year, `2024-03` that month, `2024-03-01` that
+//! day and `2024-03-01T08:30:00Z` that second; dates without a time are
+//! taken in UTC. Stored dates are ranges the same way, and the prefix says
+//! how the two ranges must relate, following the FHIR search rules:
+//!
+//! | prefix | matches a stored range that                              |
+//! |--------|----------------------------------------------------------|
+//! | `eq`   | lies within the value's range (the default)               |
+//! | `ne`   | does not                                                  |
+//! | `gt`   | extends past the end of the value's range                 |
+//! | `lt`   | starts before the value's range                           |
+//! | `ge`   | extends past its end or lies within it                    |
+//! | `le`   | starts before it or lies within it                        |
+//! | `sa`   | starts after the value's range ends                       |
+//! | `eb`   | ends before the value's range starts                      |
+//! | `ap`   | overlaps the value's range widened by 10% of its distance to now |
+
+use super::value::{InvalidSearchValue, Prefix};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+
+/// A date search value, e.g. `ge1990` or `2024-03-01T08:30:00Z`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateSearchValue {
+    pub prefix: Prefix,
+    /// The value as given, without its prefix
+    pub value: String,
+    /// First instant of the value's range
+    pub start: DateTime<Utc>,
+    /// First instant after it
+    pub end: DateTime<Utc>,
+}
+
+impl DateSearchValue {
+    pub fn parse(param: &str, raw: &str) -> Result<Self, InvalidSearchValue> {
+        let (prefix, value) = match raw.get(..2).and_then(Prefix::parse) {
+            Some(prefix) => (prefix, &raw[2..]),
+            None => (Prefix::Eq, raw),
+        };
+        let invalid = |reason| InvalidSearchValue {
+            param: param.to_string(),
+            value: raw.to_string(),
+            reason,
+        };
+
+        let day = |date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok();
+        let (start, end) = match value.len() {
+            4 => day(&format!("{}-01-01", value))
+                .map(|start| (start, start.with_year(start.year() + 1))),
+            7 => day(&format!("{}-01", value))
+                .map(|start| (start, start.checked_add_months(chrono::Months::new(1)))),
+            10 => day(value).map(|start| (start, start.succ_opt())),
+            _ => {
+                if value.get(..10).and_then(day).is_none() {
+                    return Err(invalid("expected YYYY, YYYY-MM, YYYY-MM-DD or a dateTime"));
+                }
+                let instant = DateTime::parse_from_rfc3339(value)
+                    .map_err(|_| invalid("times must include seconds and a time zone"))?
+                    .with_timezone(&Utc);
+                return Ok(Self {
+                    prefix,
+                    value: value.to_string(),
+                    start: instant,
+                    end: instant + Duration::seconds(1),
+                });
+            }
+        }
+        .and_then(|(start, end)| Some((start, end?)))
+        .ok_or_else(|| invalid("expected YYYY, YYYY-MM, YYYY-MM-DD or a dateTime"))?;
+
+        let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        Ok(Self {
+            prefix,
+            value: value.to_string(),
+            start: midnight(start),
+            end: midnight(end),
+        })
+    }
+
+    /// The range `ap` matches against as of `now`: the value's range
+    /// widened on both sides by a tenth of its distance from `now`
+    pub fn approximate(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let distance = if now < self.start {
+            self.start - now
+        } else if now > self.end {
+            now - self.end
+        } else {
+            Duration::zero()
+        };
+        let margin = distance / 10;
+        (self.start - margin, self.end + margin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_partial_dates_are_ranges() {
+        let year = DateSearchValue::parse("birthdate", "gt1990").unwrap();
+        assert_eq!(year.prefix, Prefix::Gt);
+        assert_eq!(year.value, "1990");
+        assert_eq!((year.start, year.end), (utc(1990, 1, 1), utc(1991, 1, 1)));
+
+        let month = DateSearchValue::parse("birthdate", "2024-12").unwrap();
+        assert_eq!(month.prefix, Prefix::Eq);
+        assert_eq!(
+            (month.start, month.end),
+            (utc(2024, 12, 1), utc(2025, 1, 1))
+        );
+
+        let leap = DateSearchValue::parse("birthdate", "sa2024-02-29").unwrap();
+        assert_eq!(leap.prefix, Prefix::Sa);
+        assert_eq!((leap.start, leap.end), (utc(2024, 2, 29), utc(2024, 3, 1)));
+
+        let instant = DateSearchValue::parse("date", "eb2024-03-01T08:30:00+01:00").unwrap();
+        assert_eq!(instant.prefix, Prefix::Eb);
+        assert_eq!(
+            instant.start,
+            Utc.with_ymd_and_hms(2024, 3, 1, 7, 30, 0).unwrap()
+        );
+        assert_eq!(instant.end - instant.start, Duration::seconds(1));
+    }
+
+    #[test]
+    fn test_invalid_dates() {
+        for raw in [
+            "",
+            "19",
+            "ge2024-13",
+            "2023-02-29",
+            "2024-03-01T08:30",
+            "xx2024",
+            "2024-03-01T08:30:00",
+        ] {
+            let err = DateSearchValue::parse("birthdate", raw).unwrap_err();
+            assert_eq!(err.value, raw);
+        }
+    }
+
+    #[test]
+    fn test_approximate_widens_by_distance() {
+        let value = DateSearchValue::parse("birthdate", "ap2000-01-01").unwrap();
+        let (start, end) = value.approximate(utc(2000, 1, 12));
+        assert_eq!((start, end), (utc(1999, 12, 31), utc(2000, 1, 3)));
+
+        // A range holding now is not widened
+        let today = DateSearchValue::parse("date", "ap2024").unwrap();
+        assert_eq!(today.approximate(utc(2024, 6, 1)), (today.start, today.end));
+    }
+}
@@ -0,0 +1,441 @@
+//! Search parameters evaluated from their FHIRPath expression, such as
+//! those loaded from a [profile pack](crate::profiles), rather than by a
+//! hand-written condition.
+//!
+//! Only the navigational subset of FHIRPath that search parameter
+//! definitions mostly use is understood, and it is translated to a
+//! Postgres SQL/JSON path over the stored resource:
+//!
+//! - element names, `Patient.name.family`; a choice element is named
+//!   without its type (`Observation.value`) or with `.as(Quantity)` /
+//!   `.ofType(Quantity)`
+//! - `where(<element> = '<string>')`, e.g. `extension.where(url = '...')`
+//! - `extension('<url>')`
+//! - alternatives joined by `|`, of which those for the searched resource
+//!   type are kept
+//!
+//! Anything else (`resolve()`, `exists()`, comparisons, arithmetic) is
+//! refused, and the parameter is not registered.
+
+use super::date::DateSearchValue;
+use super::registry::{SearchParamDefinition, SearchParamType};
+use super::value::{split_or, InvalidSearchValue, Prefix, TokenParam};
+use serde_json::Value;
+use std::fmt;
+
+/// Resource types whose searches evaluate expression-based parameters
+pub const SEARCHABLE_TYPES: &[&str] = &["Patient", "Observation"];
+
+/// Choice elements (`value[x]` and the like) of the resource types served;
+/// these are stored under their name followed by the type (`valueQuantity`)
+pub(crate) const CHOICE_ELEMENTS: &[&str] = &[
+    "value",
+    "effective",
+    "deceased",
+    "multipleBirth",
+    "onset",
+    "abatement",
+    "occurrence",
+    "performed",
+    "timing",
+];
+
+/// An expression outside the supported subset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedExpression {
+    pub expression: String,
+    pub reason: String,
+}
+
+impl fmt::Display for UnsupportedExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unsupported FHIRPath expression '{}': {}",
+            self.expression, self.reason
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedExpression {}
+
+/// The SQL/JSON path selecting what `expression` selects in a resource of
+/// `resource_type`
+pub fn json_path(expression: &str, resource_type: &str) -> Result<String, UnsupportedExpression> {
+    let unsupported = |reason: String| UnsupportedExpression {
+        expression: expression.to_string(),
+        reason,
+    };
+
+    let alternatives: Vec<String> = split_top_level(expression, '|')
+        .into_iter()
+        .map(|alternative| strip_parentheses(alternative.trim()).to_string())
+        .filter(|alternative| {
+            alternative
+                .split('.')
+                .next()
+                .is_some_and(|root| root == resource_type || root == "Resource")
+        })
+        .collect();
+    let [alternative] = alternatives.as_slice() else {
+        return Err(unsupported(format!(
+            "expected exactly one alternative for {}",
+            resource_type
+        )));
+    };
+
+    let mut path = String::from("$");
+    let mut steps = split_top_level(alternative, '.')
+        .into_iter()
+        .skip(1)
+        .peekable();
+    while let Some(step) = steps.next() {
+        let step = step.trim();
+        match step.split_once('(') {
+            None if is_name(step) => {
+                // A following .as(Type) names the choice type itself
+                let cast = steps
+                    .peek()
+                    .and_then(|next| call(next, "as").or(call(next, "ofType")));
+                match cast {
+                    Some(type_name) if CHOICE_ELEMENTS.contains(&step) => {
+                        let type_name = string_literal(type_name)
+                            .unwrap_or(type_name)
+                            .trim_start_matches("FHIR.");
+                        path.push_str(&member(&format!("{}{}", step, capitalize(type_name))));
+                        steps.next();
+                    }
+                    _ if CHOICE_ELEMENTS.contains(&step) => {
+                        path.push_str(&format!(
+                            ".keyvalue() ? (@.key like_regex \"^{}[A-Z]\").value[*]",
+                            step
+                        ));
+                    }
+                    _ => path.push_str(&member(step)),
+                }
+            }
+            Some(_) => {
+                if let Some(url) = call(step, "extension") {
+                    let url = string_literal(url)
+                        .ok_or_else(|| unsupported("extension() takes a string".to_string()))?;
+                    path.push_str(&member("extension"));
+                    path.push_str(&filter("url", url));
+                } else if let Some(criterion) = call(step, "where") {
+                    let (element, value) = criterion
+                        .split_once('=')
+                        .map(|(element, value)| (element.trim(), string_literal(value.trim())))
+                        .filter(|(element, value)| is_name(element) && value.is_some())
+                        .ok_or_else(|| {
+                            unsupported("where() must compare an element to a string".to_string())
+                        })?;
+                    path.push_str(&filter(element, value.unwrap_or_default()));
+                } else {
+                    return Err(unsupported(format!("{} is not supported", step)));
+                }
+            }
+            None => return Err(unsupported(format!("{} is not an element name", step))),
+        }
+    }
+    if path == "$" {
+        return Err(unsupported("it names no element".to_string()));
+    }
+    Ok(path)
+}
+
+/// `text` split at `separator`s outside parentheses and string literals
+pub(crate) fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut quoted, mut start) = (0, false, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            c if c == separator && !quoted && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// `text` without parentheses around all of it
+fn strip_parentheses(text: &str) -> &str {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return if text.starts_with('(') && i == text.len() - 1 {
+                strip_parentheses(text[1..i].trim())
+            } else {
+                text
+            };
+        }
+    }
+    text
+}
+
+pub(crate) fn is_name(step: &str) -> bool {
+    let mut chars = step.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The argument of `step` if it calls `function`
+pub(crate) fn call<'a>(step: &'a str, function: &str) -> Option<&'a str> {
+    step.trim()
+        .strip_prefix(function)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+        .map(str::trim)
+}
+
+/// The content of a single-quoted FHIRPath string
+pub(crate) fn string_literal(text: &str) -> Option<&str> {
+    text.strip_prefix('\'')?
+        .strip_suffix('\'')
+        .filter(|inner| !inner.contains('\''))
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+        .unwrap_or_default()
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Accessor of a member, unwrapping arrays so every repetition is visited
+fn member(name: &str) -> String {
+    format!(".{}[*]", quote(name))
+}
+
+fn filter(element: &str, value: &str) -> String {
+    format!(" ? (@.{} == {})", quote(element), quote(value))
+}
+
+/// The definitions of a SearchParameter resource, one per base among the
+/// [`SEARCHABLE_TYPES`], each with its path; or why the parameter cannot be
+/// searched on that base. Only token, string and date parameters can.
+pub fn definitions(resource: &Value) -> Vec<Result<SearchParamDefinition, String>> {
+    let text = |name: &str| {
+        resource
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+    };
+    resource
+        .get("base")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .filter(|base| SEARCHABLE_TYPES.contains(base))
+        .map(|base| {
+            let skip =
+                |reason: String| format!("SearchParameter {} on {}: {}", text("url"), base, reason);
+            let param_type = match text("type") {
+                "token" => SearchParamType::Token,
+                "string" => SearchParamType::String,
+                "date" => SearchParamType::Date,
+                other => return Err(skip(format!("{} parameters are not supported", other))),
+            };
+            let path = json_path(text("expression"), base).map_err(|e| skip(e.to_string()))?;
+            let param = SearchParamDefinition::new(
+                text("id"),
+                text("url"),
+                text("code"),
+                &[base],
+                param_type,
+                text("expression"),
+                text("description"),
+            )
+            .with_path(path);
+            Ok(match param_type {
+                SearchParamType::Date => param.with_comparators(Prefix::ALL),
+                _ => param,
+            })
+        })
+        .collect()
+}
+
+/// A value of an [expression-evaluated](self) search parameter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpressionValue {
+    /// Matches a code, Coding, CodeableConcept, Identifier or boolean
+    Token(TokenParam),
+    /// Case-insensitive prefix of a string
+    String(String),
+    Date(DateSearchValue),
+}
+
+/// One occurrence of an expression-evaluated parameter in a search: any of
+/// its values must match something `path` selects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionCriterion {
+    pub code: String,
+    pub path: String,
+    /// For an [indexed](SearchParamDefinition::indexed) parameter, the id
+    /// of its SearchParameter, whose extracted values are searched instead
+    /// of evaluating `path`
+    pub index: Option<String>,
+    pub values: Vec<ExpressionValue>,
+}
+
+impl ExpressionCriterion {
+    /// The criterion of `raw` for `param`, or `None` when `param` is not
+    /// evaluated from its expression
+    pub fn parse(
+        param: &SearchParamDefinition,
+        raw: &str,
+    ) -> Option<Result<Self, InvalidSearchValue>> {
+        let path = param.path.clone()?;
+        let values = split_or(raw)
+            .iter()
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(|value| match param.param_type {
+                SearchParamType::Date => {
+                    DateSearchValue::parse(&param.code, value).map(ExpressionValue::Date)
+                }
+                SearchParamType::String => Ok(ExpressionValue::String(value.to_string())),
+                _ => Ok(ExpressionValue::Token(TokenParam::parse(value))),
+            })
+            .collect::<Result<Vec<_>, _>>();
+        Some(values.map(|values| Self {
+            code: param.code.clone(),
+            path,
+            index: param.indexed.then(|| param.id.clone()),
+            values,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_path() {
+        assert_eq!(
+            json_path("Patient.name.family", "Patient").unwrap(),
+            r#"$."name"[*]."family"[*]"#
+        );
+        assert_eq!(
+            json_path(
+                "Patient.extension.where(url = 'http://hl7.org/fhir/us/core/StructureDefinition/us-core-race').extension.value.code",
+                "Patient"
+            )
+            .unwrap(),
+            r#"$."extension"[*] ? (@."url" == "http://hl7.org/fhir/us/core/StructureDefinition/us-core-race")."extension"[*].keyvalue() ? (@.key like_regex "^value[A-Z]").value[*]."code"[*]"#
+        );
+        assert_eq!(
+            json_path("(Observation.value.as(Quantity).unit)", "Observation").unwrap(),
+            r#"$."valueQuantity"[*]."unit"[*]"#
+        );
+        assert_eq!(
+            json_path("Patient.extension('http://example.org/x')", "Patient").unwrap(),
+            r#"$."extension"[*] ? (@."url" == "http://example.org/x")"#
+        );
+        // Only the alternative for the searched type is kept
+        assert_eq!(
+            json_path("Practitioner.name | Patient.name", "Patient").unwrap(),
+            r#"$."name"[*]"#
+        );
+    }
+
+    #[test]
+    fn test_unsupported_expressions() {
+        for expression in [
+            "Patient.link.other.resolve()",
+            "Patient.name.exists()",
+            "Patient.name.where(use = 'official' or use = 'usual')",
+            "Patient.name | Patient.alias",
+            "Observation.code",
+            "Patient",
+            "Patient.na-me",
+        ] {
+            assert!(json_path(expression, "Patient").is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_definitions() {
+        let resource = serde_json::json!({
+            "resourceType": "SearchParameter",
+            "id": "birthplace",
+            "url": "http://example.org/SearchParameter/birthplace",
+            "code": "birthplace",
+            "base": ["Patient", "Practitioner", "Observation"],
+            "type": "string",
+            "expression": "Patient.extension('http://hl7.org/fhir/StructureDefinition/patient-birthPlace').value.city"
+        });
+        let found = definitions(&resource);
+
+        // Practitioner is not searchable, and the expression has no
+        // alternative for Observation
+        assert_eq!(found.len(), 2);
+        let patient = found[0].as_ref().unwrap();
+        assert_eq!(patient.base, vec!["Patient"]);
+        assert_eq!(patient.param_type, SearchParamType::String);
+        assert!(patient.path.as_deref().unwrap().ends_with(r#"."city"[*]"#));
+        assert!(found[1].as_ref().unwrap_err().starts_with(
+            "SearchParameter http://example.org/SearchParameter/birthplace on Observation"
+        ));
+
+        let mut quantity = resource;
+        quantity["type"] = serde_json::json!("quantity");
+        let skipped = definitions(&quantity).remove(0).unwrap_err();
+        assert!(skipped.contains("quantity parameters are not supported"));
+    }
+
+    #[test]
+    fn test_criterion_values_by_type() {
+        let race = SearchParamDefinition::new(
+            "us-core-race",
+            "http://hl7.org/fhir/us/core/SearchParameter/us-core-race",
+            "race",
+            &["Patient"],
+            SearchParamType::Token,
+            "Patient.extension.where(url = 'r').extension.value.code",
+            "",
+        )
+        .with_path("$.x");
+        let criterion = ExpressionCriterion::parse(&race, "2106-3,urn:oid:1|2028-9")
+            .unwrap()
+            .unwrap();
+        assert_eq!(criterion.path, "$.x");
+        assert_eq!(criterion.index, None);
+        assert_eq!(
+            criterion.values[1],
+            ExpressionValue::Token(TokenParam::parse("urn:oid:1|2028-9"))
+        );
+
+        let mut date = race.clone();
+        date.param_type = SearchParamType::Date;
+        assert!(ExpressionCriterion::parse(&date, "maybe").unwrap().is_err());
+
+        let indexed = race.clone().indexed();
+        let criterion = ExpressionCriterion::parse(&indexed, "2106-3")
+            .unwrap()
+            .unwrap();
+        assert_eq!(criterion.index.as_deref(), Some("us-core-race"));
+
+        // Built-ins are not evaluated from their expression
+        let mut builtin = race;
+        builtin.path = None;
+        assert!(ExpressionCriterion::parse(&builtin, "2106-3").is_none());
+    }
+}
@@ -0,0 +1,291 @@
+//! `POST /fhir/Patient/$match`: probabilistic matching of a submitted
+//! patient against the stored ones, for deduplication at registration.
+//!
+//! A candidate's score is the weighted mean of what the submitted patient
+//! gives: the trigram similarity of the first family and given name
+//! (pg_trgm, migration `020_patient_match.sql`), and whether the birth date,
+//! the gender and any identifier are equal. Elements the submitted patient
+//! lacks do not count, so a candidate is not penalized for them. Only
+//! patients sharing a similar name, the birth date or an identifier are
+//! candidates; the score grades each match as `certain`, `probable` or
+//! `possible`, and scores below `possible` are not returned.
+
+use super::value::TokenParam;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Extension on `Bundle.entry.search` carrying the match grade
+pub const MATCH_GRADE_URL: &str = "http://hl7.org/fhir/StructureDefinition/match-grade";
+
+/// The `[patient_match]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MatchConfig {
+    /// Weight of the name similarity, from 0 to 1
+    pub name_weight: f64,
+    /// Weight of an equal birth date
+    pub birth_date_weight: f64,
+    /// Weight of an equal gender
+    pub gender_weight: f64,
+    /// Weight of a shared identifier
+    pub identifier_weight: f64,
+    /// Lowest score graded `certain`
+    pub certain: f64,
+    /// Lowest score graded `probable`
+    pub probable: f64,
+    /// Lowest score graded `possible` and returned at all
+    pub possible: f64,
+    /// Most matches returned, and the default of the `count` parameter
+    pub max_results: u32,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            name_weight: 0.35,
+            birth_date_weight: 0.3,
+            gender_weight: 0.1,
+            identifier_weight: 0.25,
+            certain: 0.95,
+            probable: 0.8,
+            possible: 0.6,
+            max_results: 10,
+        }
+    }
+}
+
+impl MatchConfig {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let weights = [
+            self.name_weight,
+            self.birth_date_weight,
+            self.gender_weight,
+            self.identifier_weight,
+        ];
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) || weights.iter().sum::<f64>() <= 0.0
+        {
+            anyhow::bail!("patient_match: weights must be 0 or more, and not all 0");
+        }
+        if !(0.0 < self.possible && self.possible <= self.probable && self.probable <= self.certain)
+            || self.certain > 1.0
+        {
+            anyhow::bail!(
+                "patient_match: thresholds must satisfy 0 < possible <= probable <= certain <= 1"
+            );
+        }
+        if self.max_results == 0 {
+            anyhow::bail!("patient_match.max_results must be greater than 0");
+        }
+        Ok(())
+    }
+
+    /// The grade of a match with `score`; `None` below `possible`
+    pub fn grade(&self, score: f64) -> Option<&'static str> {
+        if score >= self.certain {
+            Some("certain")
+        } else if score >= self.probable {
+            Some("probable")
+        } else if score >= self.possible {
+            Some("possible")
+        } else {
+            None
+        }
+    }
+}
+
+/// The parameters of a `$match` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchRequest {
+    /// The `resource` parameter
+    pub patient: Value,
+    /// `onlyCertainMatches`: leave out everything not graded `certain`
+    pub only_certain: bool,
+    /// `count`: most matches to return
+    pub count: Option<u32>,
+}
+
+impl MatchRequest {
+    pub fn parse(body: &Value) -> Result<Self, String> {
+        if body.get("resourceType").and_then(Value::as_str) != Some("Parameters") {
+            return Err("$match takes a Parameters resource".to_string());
+        }
+        let parameters = body
+            .get("parameter")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let parameter = |name: &str| {
+            parameters
+                .iter()
+                .find(|p| p.get("name").and_then(Value::as_str) == Some(name))
+        };
+
+        let patient = parameter("resource")
+            .and_then(|p| p.get("resource"))
+            .ok_or("$match needs a resource parameter holding a Patient")?;
+        if patient.get("resourceType").and_then(Value::as_str) != Some("Patient") {
+            return Err("The resource parameter of $match must be a Patient".to_string());
+        }
+        let only_certain = match parameter("onlyCertainMatches") {
+            None => false,
+            Some(p) => p
+                .get("valueBoolean")
+                .and_then(Value::as_bool)
+                .ok_or("onlyCertainMatches must be a valueBoolean")?,
+        };
+        let count = match parameter("count") {
+            None => None,
+            Some(p) => Some(
+                p.get("valueInteger")
+                    .and_then(Value::as_u64)
+                    .filter(|count| *count > 0)
+                    .ok_or("count must be a positive valueInteger")?
+                    .min(u32::MAX as u64) as u32,
+            ),
+        };
+
+        Ok(Self {
+            patient: patient.clone(),
+            only_certain,
+            count,
+        })
+    }
+}
+
+/// What a submitted patient is matched on
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MatchInput {
+    /// The first family and first given name, lower-cased and joined as the
+    /// trigram index has them
+    pub name: Option<String>,
+    pub birth_date: Option<String>,
+    pub gender: Option<String>,
+    /// Identifiers with a value; one without a system matches in any
+    pub identifiers: Vec<TokenParam>,
+}
+
+impl MatchInput {
+    pub fn from_patient(patient: &Value) -> Self {
+        let text = |value: Option<&Value>| {
+            value
+                .and_then(Value::as_str)
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        };
+        let family = patient.pointer("/name/0/family").and_then(Value::as_str);
+        let given = patient.pointer("/name/0/given/0").and_then(Value::as_str);
+        let name = (family.is_some() || given.is_some()).then(|| {
+            format!(
+                "{} {}",
+                family.unwrap_or_default(),
+                given.unwrap_or_default()
+            )
+            .to_lowercase()
+        });
+        let identifiers = patient
+            .get("identifier")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|identifier| {
+                Some(TokenParam {
+                    system: text(identifier.get("system")),
+                    code: Some(text(identifier.get("value"))?),
+                })
+            })
+            .collect();
+
+        Self {
+            name: name.filter(|name| !name.trim().is_empty()),
+            birth_date: text(patient.get("birthDate")),
+            gender: text(patient.get("gender")),
+            identifiers,
+        }
+    }
+
+    /// Whether candidates can be found: the gender alone does not narrow
+    /// them down
+    pub fn has_candidates(&self) -> bool {
+        self.name.is_some() || self.birth_date.is_some() || !self.identifiers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_match_request() {
+        let request = MatchRequest::parse(&json!({
+            "resourceType": "Parameters",
+            "parameter": [
+                { "name": "resource", "resource": {
+                    "resourceType": "Patient",
+                    "name": [{ "family": "Chalmers", "given": ["Peter"] }],
+                    "birthDate": "1974-12-25",
+                    "identifier": [{ "system": "urn:mrn", "value": "42" }, { "system": "urn:x" }]
+                } },
+                { "name": "onlyCertainMatches", "valueBoolean": true },
+                { "name": "count", "valueInteger": 3 }
+            ]
+        }))
+        .unwrap();
+        assert!(request.only_certain);
+        assert_eq!(request.count, Some(3));
+
+        let input = MatchInput::from_patient(&request.patient);
+        assert_eq!(input.name.as_deref(), Some("chalmers peter"));
+        assert_eq!(input.birth_date.as_deref(), Some("1974-12-25"));
+        assert_eq!(input.gender, None);
+        assert_eq!(input.identifiers, vec![TokenParam::parse("urn:mrn|42")]);
+        assert!(input.has_candidates());
+        assert!(!MatchInput::from_patient(&json!({ "gender": "male" })).has_candidates());
+
+        for body in [
+            json!({ "resourceType": "Patient" }),
+            json!({ "resourceType": "Parameters", "parameter": [] }),
+            json!({ "resourceType": "Parameters", "parameter": [
+                { "name": "resource", "resource": { "resourceType": "Observation" } }
+            ] }),
+            json!({ "resourceType": "Parameters", "parameter": [
+                { "name": "resource", "resource": { "resourceType": "Patient" } },
+                { "name": "count", "valueInteger": 0 }
+            ] }),
+        ] {
+            assert!(MatchRequest::parse(&body).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_grades_and_config() {
+        let config = MatchConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.grade(1.0), Some("certain"));
+        assert_eq!(config.grade(0.85), Some("probable"));
+        assert_eq!(config.grade(0.6), Some("possible"));
+        assert_eq!(config.grade(0.59), None);
+
+        for invalid in [
+            MatchConfig {
+                name_weight: -1.0,
+                ..MatchConfig::default()
+            },
+            MatchConfig {
+                probable: 0.99,
+                ..MatchConfig::default()
+            },
+            MatchConfig {
+                possible: 0.0,
+                ..MatchConfig::default()
+            },
+            MatchConfig {
+                max_results: 0,
+                ..MatchConfig::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,17 @@
+pub mod cohort;
+pub mod conditional;
+pub mod date;
+pub mod expression;
+pub mod matching;
+pub mod modifier;
+pub mod phonetic;
+pub mod registry;
+pub mod text;
+pub mod value;
+
+pub use conditional::PatientCriteria;
+pub use date::DateSearchValue;
+pub use expression::{ExpressionCriterion, ExpressionValue};
+pub use registry::{SearchParamDefinition, SearchParamRegistry, SearchParamType};
+pub use text::TextCriteria;
+pub use value::{split_or, InvalidSearchValue, Prefix, TokenParam};
@@ -0,0 +1,71 @@
+//! Search parameter modifiers.
+//!
+//! Besides the parameter-specific ones (`name:phonetic`, `name:contains`),
+//! every parameter takes `:missing` and string parameters take `:exact`:
+//!
+//! - `gender:missing=true` matches resources without the element, and
+//!   `gender:missing=false` those with it; an empty array counts as missing.
+//! - `name:exact=Smith` matches the whole value with case and accents, where
+//!   plain `name` matches a case-insensitive substring.
+//!
+//! A modifier the parameter does not support is rejected rather than
+//! ignored, since ignoring it would silently widen the match.
+
+use super::value::InvalidSearchValue;
+
+pub const MISSING: &str = "missing";
+pub const EXACT: &str = "exact";
+
+/// `name` split into the parameter and its modifier, if any
+pub fn split(name: &str) -> (&str, Option<&str>) {
+    match name.split_once(':') {
+        Some((param, modifier)) => (param, Some(modifier)),
+        None => (name, None),
+    }
+}
+
+/// The value of a `:missing` parameter
+pub fn parse_missing(param: &str, value: &str) -> Result<bool, InvalidSearchValue> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(InvalidSearchValue {
+            param: param.to_string(),
+            value: value.to_string(),
+            reason: "expected true or false",
+        }),
+    }
+}
+
+/// The first of `names` that carries a modifier not listed, as
+/// `param:modifier`, in `supported`
+pub fn unsupported<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    supported: &[&str],
+) -> Option<&'a str> {
+    names
+        .into_iter()
+        .find(|name| split(name).1.is_some() && !supported.contains(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifiers() {
+        assert_eq!(split("gender:missing"), ("gender", Some("missing")));
+        assert_eq!(split("gender"), ("gender", None));
+
+        assert_eq!(parse_missing("gender:missing", "true"), Ok(true));
+        assert_eq!(parse_missing("gender:missing", "false"), Ok(false));
+        assert!(parse_missing("gender:missing", "yes").is_err());
+
+        let supported = &["name:exact", "gender:missing"];
+        assert_eq!(unsupported(["name", "gender:missing"], supported), None);
+        assert_eq!(
+            unsupported(["name:exact", "gender:text", "name:fuzzy"], supported),
+            Some("gender:text")
+        );
+    }
+}
@@ -0,0 +1,433 @@
+use super::value::Prefix;
+use serde_json::{json, Value};
+use std::sync::RwLock;
+
+/// FHIR search parameter types (http://hl7.org/fhir/search-param-type)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchParamType {
+    Number,
+    Date,
+    String,
+    Token,
+    Reference,
+    Composite,
+    Quantity,
+    Uri,
+    Special,
+}
+
+impl SearchParamType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchParamType::Number => "number",
+            SearchParamType::Date => "date",
+            SearchParamType::String => "string",
+            SearchParamType::Token => "token",
+            SearchParamType::Reference => "reference",
+            SearchParamType::Composite => "composite",
+            SearchParamType::Quantity => "quantity",
+            SearchParamType::Uri => "uri",
+            SearchParamType::Special => "special",
+        }
+    }
+}
+
+/// A search parameter this deployment understands, together with the
+/// modifiers and prefixes the search handlers actually honor for it.
+#[derive(Debug, Clone)]
+pub struct SearchParamDefinition {
+    /// Logical id of the SearchParameter resource (e.g. `Patient-name`)
+    pub id: String,
+    /// Canonical URL of the definition
+    pub url: String,
+    /// Query parameter name (e.g. `name`, `birthdate`)
+    pub code: String,
+    /// Resource types the parameter applies to
+    pub base: Vec<String>,
+    pub param_type: SearchParamType,
+    /// FHIRPath expression selecting the indexed element(s)
+    pub expression: String,
+    pub description: String,
+    /// Supported modifiers without the leading colon (e.g. `contains`)
+    pub modifiers: Vec<String>,
+    /// Supported value prefixes for ordered types (e.g. `ge`, `le`)
+    pub comparators: Vec<String>,
+    /// SQL/JSON path of `expression` for parameters searched by evaluating
+    /// it (see [`expression`](super::expression)); `None` for the
+    /// built-ins, which search has dedicated conditions for
+    pub path: Option<String>,
+    /// Whether searches look up the values extracted along `path` into
+    /// `fhir.resource_search_value` rather than evaluate it, as for
+    /// parameters added through `POST /admin/search-parameters`
+    pub indexed: bool,
+}
+
+impl SearchParamDefinition {
+    pub fn new(
+        id: impl Into<String>,
+        url: impl Into<String>,
+        code: impl Into<String>,
+        base: &[&str],
+        param_type: SearchParamType,
+        expression: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            url: url.into(),
+            code: code.into(),
+            base: base.iter().map(|b| b.to_string()).collect(),
+            param_type,
+            expression: expression.into(),
+            description: description.into(),
+            modifiers: Vec::new(),
+            comparators: Vec::new(),
+            path: None,
+            indexed: false,
+        }
+    }
+
+    pub fn with_modifiers(mut self, modifiers: &[&str]) -> Self {
+        self.modifiers = modifiers.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    pub fn with_comparators(mut self, comparators: &[&str]) -> Self {
+        self.comparators = comparators.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn indexed(mut self) -> Self {
+        self.indexed = true;
+        self
+    }
+
+    pub fn applies_to(&self, resource_type: &str) -> bool {
+        self.base.iter().any(|b| b == resource_type)
+    }
+
+    /// Render the definition as a FHIR R4 SearchParameter resource
+    pub fn to_resource(&self) -> Value {
+        let mut resource = json!({
+            "resourceType": "SearchParameter",
+            "id": self.id,
+            "url": self.url,
+            "name": self.code,
+            "status": "active",
+            "description": self.description,
+            "code": self.code,
+            "base": self.base,
+            "type": self.param_type.as_str(),
+            "expression": self.expression,
+        });
+
+        if let Value::Object(ref mut map) = resource {
+            if !self.comparators.is_empty() {
+                map.insert("comparator".to_string(), json!(self.comparators));
+            }
+            if !self.modifiers.is_empty() {
+                map.insert("modifier".to_string(), json!(self.modifiers));
+            }
+        }
+
+        resource
+    }
+}
+
+/// Registry of all search parameters known to the server (built-in and
+/// custom). Handlers and the introspection endpoint read from here so the
+/// advertised capabilities never drift from what search really supports.
+pub struct SearchParamRegistry {
+    params: RwLock<Vec<SearchParamDefinition>>,
+}
+
+impl SearchParamRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self {
+            params: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Create a registry pre-populated with the built-in parameters
+    pub fn with_builtins() -> Self {
+        let registry = Self::new();
+        for param in builtin_params() {
+            registry.register(param);
+        }
+        registry
+    }
+
+    /// Register a parameter, replacing any existing one with the same base and code
+    pub fn register(&self, param: SearchParamDefinition) {
+        let mut params = self.params.write().unwrap();
+        params.retain(|p| !(p.code == param.code && p.base == param.base));
+        params.push(param);
+    }
+
+    /// Remove the indexed parameters defined by the SearchParameter with
+    /// logical id `id`, returning how many there were
+    pub fn remove_indexed(&self, id: &str) -> usize {
+        let mut params = self.params.write().unwrap();
+        let before = params.len();
+        params.retain(|p| !(p.indexed && p.id == id));
+        before - params.len()
+    }
+
+    /// All parameters, optionally restricted to one resource type
+    pub fn list(&self, base: Option<&str>) -> Vec<SearchParamDefinition> {
+        let params = self.params.read().unwrap();
+        params
+            .iter()
+            .filter(|p| base.is_none_or(|b| p.applies_to(b)))
+            .cloned()
+            .collect()
+    }
+
+    /// Look up a parameter by resource type and code
+    pub fn get(&self, base: &str, code: &str) -> Option<SearchParamDefinition> {
+        let params = self.params.read().unwrap();
+        params
+            .iter()
+            .find(|p| p.code == code && p.applies_to(base))
+            .cloned()
+    }
+}
+
+impl Default for SearchParamRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parameters implemented natively by `Database::search_patients` and
+/// `Database::search_observations`
+fn builtin_params() -> Vec<SearchParamDefinition> {
+    vec![
+        SearchParamDefinition::new(
+            "Patient-name",
+            "http://hl7.org/fhir/SearchParameter/Patient-name",
+            "name",
+            &["Patient"],
+            SearchParamType::String,
+            "Patient.name",
+            "Substring match on the first name's family or first given name; name:exact matches either whole and case-sensitive; name:phonetic matches any name word by Double Metaphone or Cologne phonetics",
+        )
+        .with_modifiers(&["contains", "exact", "phonetic", "missing"]),
+        SearchParamDefinition::new(
+            "individual-gender",
+            "http://hl7.org/fhir/SearchParameter/individual-gender",
+            "gender",
+            &["Patient"],
+            SearchParamType::Token,
+            "Patient.gender",
+            "Exact match on the administrative gender code",
+        )
+        .with_modifiers(&["missing"]),
+        SearchParamDefinition::new(
+            "individual-birthdate",
+            "http://hl7.org/fhir/SearchParameter/individual-birthdate",
+            "birthdate",
+            &["Patient"],
+            SearchParamType::Date,
+            "Patient.birthDate",
+            "Match on birth date (YYYY, YYYY-MM or YYYY-MM-DD, each a range); may be repeated to form a range",
+        )
+        .with_comparators(Prefix::ALL)
+        .with_modifiers(&["missing"]),
+        SearchParamDefinition::new(
+            "Resource-id",
+            "http://hl7.org/fhir/SearchParameter/Resource-id",
+            "_id",
+            &["Patient"],
+            SearchParamType::Token,
+            "Resource.id",
+            "Match on the logical id; comma-separated values match any",
+        ),
+        SearchParamDefinition::new(
+            "Patient-identifier",
+            "http://hl7.org/fhir/SearchParameter/Patient-identifier",
+            "identifier",
+            &["Patient"],
+            SearchParamType::Token,
+            "Patient.identifier",
+            "Match on an identifier (value, system|value or |value); comma-separated values match any",
+        )
+        .with_modifiers(&["missing"]),
+        SearchParamDefinition::new(
+            "clinical-code",
+            "http://hl7.org/fhir/SearchParameter/clinical-code",
+            "code",
+            &["Observation"],
+            SearchParamType::Token,
+            "Observation.code",
+            "Match on a coding of the observation code (code, system|code, system| or |code); comma-separated values match any",
+        )
+        .with_modifiers(&["missing"]),
+        SearchParamDefinition::new(
+            "Observation-subject",
+            "http://hl7.org/fhir/SearchParameter/Observation-subject",
+            "subject",
+            &["Observation"],
+            SearchParamType::Reference,
+            "Observation.subject",
+            "Match on the subject reference (Type/id, or a bare id of any type)",
+        )
+        .with_modifiers(&["missing"]),
+        SearchParamDefinition::new(
+            "clinical-patient",
+            "http://hl7.org/fhir/SearchParameter/clinical-patient",
+            "patient",
+            &["Observation"],
+            SearchParamType::Reference,
+            "Observation.subject.where(resolve() is Patient)",
+            "Match on a subject that is a Patient (Patient/id or a bare Patient id)",
+        ),
+        SearchParamDefinition::new(
+            "clinical-date",
+            "http://hl7.org/fhir/SearchParameter/clinical-date",
+            "date",
+            &["Observation"],
+            SearchParamType::Date,
+            "Observation.effective",
+            "Match on effectiveDateTime at the precision of the value; may be repeated to form a range",
+        )
+        .with_comparators(Prefix::ALL)
+        .with_modifiers(&["missing"]),
+        SearchParamDefinition::new(
+            "DomainResource-text",
+            "http://hl7.org/fhir/SearchParameter/DomainResource-text",
+            "_text",
+            &["Patient", "Observation"],
+            SearchParamType::String,
+            "DomainResource.text",
+            "Full-text search of the narrative: all words must occur, \"quoted phrase\", or, -excluded; not stemmed",
+        ),
+        SearchParamDefinition::new(
+            "Resource-content",
+            "http://hl7.org/fhir/SearchParameter/Resource-content",
+            "_content",
+            &["Patient", "Observation"],
+            SearchParamType::String,
+            "Resource",
+            "Full-text search of every string in the resource, with the query syntax of _text",
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins_for_patient() {
+        let registry = SearchParamRegistry::with_builtins();
+        let codes: Vec<String> = registry
+            .list(Some("Patient"))
+            .into_iter()
+            .map(|p| p.code)
+            .collect();
+
+        assert!(codes.contains(&"name".to_string()));
+        assert!(codes.contains(&"gender".to_string()));
+        assert!(codes.contains(&"birthdate".to_string()));
+        assert!(codes.contains(&"_id".to_string()));
+        let identifier = registry.get("Patient", "identifier").unwrap();
+        assert_eq!(identifier.param_type, SearchParamType::Token);
+        let name = registry.get("Patient", "name").unwrap();
+        assert!(name.modifiers.contains(&"phonetic".to_string()));
+        assert!(name.modifiers.contains(&"exact".to_string()));
+    }
+
+    #[test]
+    fn test_builtins_for_observation() {
+        let registry = SearchParamRegistry::with_builtins();
+
+        let date = registry.get("Observation", "date").unwrap();
+        assert_eq!(date.param_type, SearchParamType::Date);
+        assert!(date.comparators.contains(&"gt".to_string()));
+        assert!(registry.get("Observation", "code").is_some());
+        assert!(registry.get("Observation", "subject").is_some());
+        assert!(registry.get("Observation", "patient").is_some());
+        assert!(registry.get("Observation", "gender").is_none());
+        assert!(registry.get("Observation", "_text").is_some());
+        assert!(registry.get("Patient", "_content").is_some());
+    }
+
+    #[test]
+    fn test_list_unknown_base_is_empty() {
+        let registry = SearchParamRegistry::with_builtins();
+        assert!(registry.list(Some("Encounter")).is_empty());
+    }
+
+    #[test]
+    fn test_register_replaces_existing() {
+        let registry = SearchParamRegistry::with_builtins();
+        let before = registry.list(Some("Patient")).len();
+
+        registry.register(
+            SearchParamDefinition::new(
+                "Patient-name",
+                "http://example.org/SearchParameter/Patient-name",
+                "name",
+                &["Patient"],
+                SearchParamType::String,
+                "Patient.name",
+                "Replacement",
+            )
+            .with_modifiers(&["contains", "exact"]),
+        );
+
+        assert_eq!(registry.list(Some("Patient")).len(), before);
+        let name = registry.get("Patient", "name").unwrap();
+        assert_eq!(name.modifiers, vec!["contains", "exact"]);
+    }
+
+    #[test]
+    fn test_remove_indexed() {
+        let registry = SearchParamRegistry::with_builtins();
+        let custom = |base: &str| {
+            SearchParamDefinition::new(
+                "4f1c",
+                "http://example.org/SearchParameter/nickname",
+                "nickname",
+                &[base],
+                SearchParamType::String,
+                "Patient.name.where(use = 'nickname').given",
+                "",
+            )
+            .with_path("$.x")
+            .indexed()
+        };
+        registry.register(custom("Patient"));
+        registry.register(custom("Observation"));
+
+        // Built-ins are never removed
+        assert_eq!(registry.remove_indexed("Patient-name"), 0);
+        assert_eq!(registry.remove_indexed("4f1c"), 2);
+        assert!(registry.get("Patient", "nickname").is_none());
+        assert!(registry.get("Patient", "name").is_some());
+    }
+
+    #[test]
+    fn test_to_resource() {
+        let registry = SearchParamRegistry::with_builtins();
+        let resource = registry.get("Patient", "birthdate").unwrap().to_resource();
+
+        assert_eq!(resource["resourceType"], "SearchParameter");
+        assert_eq!(resource["code"], "birthdate");
+        assert_eq!(resource["type"], "date");
+        assert_eq!(resource["base"][0], "Patient");
+        assert_eq!(resource["comparator"][4], "ge");
+        assert_eq!(resource["comparator"][8], "ap");
+        assert_eq!(resource["modifier"][0], "missing");
+        // Parameters without modifiers omit the element
+        let id = registry.get("Patient", "_id").unwrap().to_resource();
+        assert!(id.get("modifier").is_none());
+    }
+}
@@ -0,0 +1,66 @@
+//! The full-text parameters `_text` and `_content`, which every search
+//! handler takes.
+//!
+//! `_text` searches the narrative (`text.div`, without its markup) and
+//! `_content` every string in the resource. A value is a web-search style
+//! query over whole words: `heart failure` needs both, `"heart failure"`
+//! the phrase, `heart or cardiac` either and `-failure` excludes. Words are
+//! lowercased but not stemmed, so `fracture` does not match `fractured`.
+//! Both need the generated columns of migration 016.
+
+use super::value::split_or;
+
+pub const TEXT: &str = "_text";
+pub const CONTENT: &str = "_content";
+
+/// Occurrences of `_text` and `_content` in a search; every occurrence must
+/// match by any of its comma-separated values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextCriteria {
+    pub text: Vec<Vec<String>>,
+    pub content: Vec<Vec<String>>,
+}
+
+impl TextCriteria {
+    /// Add the query parameter `name` if it is `_text` or `_content`;
+    /// whether it was
+    pub fn add(&mut self, name: &str, value: &str) -> bool {
+        let occurrences = match name {
+            TEXT => &mut self.text,
+            CONTENT => &mut self.content,
+            _ => return false,
+        };
+        let values: Vec<String> = split_or(value)
+            .iter()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if !values.is_empty() {
+            occurrences.push(values);
+        }
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.content.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add() {
+        let mut criteria = TextCriteria::default();
+        assert!(criteria.add("_text", "asthma,copd"));
+        assert!(criteria.add("_content", "\"heart failure\""));
+        assert!(criteria.add("_content", " "));
+        assert!(!criteria.add("name", "smith"));
+
+        assert_eq!(criteria.text, vec![vec!["asthma", "copd"]]);
+        assert_eq!(criteria.content, vec![vec!["\"heart failure\""]]);
+        assert!(!criteria.is_empty());
+        assert!(TextCriteria::default().is_empty());
+    }
+}
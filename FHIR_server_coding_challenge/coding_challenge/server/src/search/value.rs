@@ -0,0 +1,139 @@
+//! Parsing of FHIR search parameter values (alternatives, prefixes, tokens);
+//! dates are in [`super::date`].
+
+use std::fmt;
+
+/// The comma-separated alternatives of a search value, any of which may
+/// match: `gender=male,female`. `\,` is a comma within an alternative and
+/// `\\` a backslash; empty alternatives are kept for the caller to judge.
+pub fn split_or(raw: &str) -> Vec<String> {
+    let mut alternatives = Vec::new();
+    let mut current = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' => alternatives.push(std::mem::take(&mut current)),
+            '\\' => match chars.next() {
+                Some(escaped @ (',' | '\\')) => current.push(escaped),
+                other => {
+                    current.push('\\');
+                    current.extend(other);
+                }
+            },
+            c => current.push(c),
+        }
+    }
+    alternatives.push(current);
+    alternatives
+}
+
+/// Comparison prefix of an ordered search value (e.g. `ge2024-01-01`); see
+/// [`super::date`] for what each means for dates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    /// Starts after
+    Sa,
+    /// Ends before
+    Eb,
+    /// Approximately
+    Ap,
+}
+
+impl Prefix {
+    pub const ALL: &'static [&'static str] =
+        &["eq", "ne", "gt", "lt", "ge", "le", "sa", "eb", "ap"];
+
+    pub fn parse(prefix: &str) -> Option<Self> {
+        match prefix {
+            "eq" => Some(Prefix::Eq),
+            "ne" => Some(Prefix::Ne),
+            "gt" => Some(Prefix::Gt),
+            "lt" => Some(Prefix::Lt),
+            "ge" => Some(Prefix::Ge),
+            "le" => Some(Prefix::Le),
+            "sa" => Some(Prefix::Sa),
+            "eb" => Some(Prefix::Eb),
+            "ap" => Some(Prefix::Ap),
+            _ => None,
+        }
+    }
+}
+
+/// A search value that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSearchValue {
+    pub param: String,
+    pub value: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidSearchValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid value '{}' for search parameter {}: {}",
+            self.value, self.param, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidSearchValue {}
+
+/// A token search value: `code`, `system|code`, `system|` or `|code`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenParam {
+    /// `None` matches any system, `Some("")` only codings without one
+    pub system: Option<String>,
+    pub code: Option<String>,
+}
+
+impl TokenParam {
+    pub fn parse(raw: &str) -> Self {
+        let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+        match raw.split_once('|') {
+            Some((system, code)) => Self {
+                system: Some(system.to_string()),
+                code: non_empty(code),
+            },
+            None => Self {
+                system: None,
+                code: non_empty(raw),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_or() {
+        assert_eq!(split_or("male,female"), vec!["male", "female"]);
+        assert_eq!(split_or("Smith"), vec!["Smith"]);
+        assert_eq!(split_or(r"Smith\, Jr,Doe"), vec!["Smith, Jr", "Doe"]);
+        assert_eq!(split_or(r"a\\,b\|c"), vec![r"a\", r"b\|c"]);
+        assert_eq!(split_or("a,"), vec!["a", ""]);
+        assert_eq!(split_or(""), vec![""]);
+    }
+
+    #[test]
+    fn test_token_forms() {
+        assert_eq!(
+            TokenParam::parse("http://loinc.org|8867-4"),
+            TokenParam {
+                system: Some("http://loinc.org".to_string()),
+                code: Some("8867-4".to_string()),
+            }
+        );
+        assert_eq!(TokenParam::parse("8867-4").system, None);
+        assert_eq!(TokenParam::parse("|8867-4").system, Some(String::new()));
+        assert_eq!(TokenParam::parse("http://loinc.org|").code, None);
+    }
+}
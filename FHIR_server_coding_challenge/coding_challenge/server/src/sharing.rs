@@ -0,0 +1,281 @@
+//! Time-limited sharing links.
+//!
+//! `POST /fhir/Patient/:id/$share` hands out a URL under `/fhir/_share/`
+//! through which anyone holding it may read one patient until it expires:
+//! its `summary` (the Patient reduced to its summary elements) or its
+//! `record` (the Patient and its Observations). The link carries the
+//! grant itself, signed with HMAC-SHA256 under `[sharing] secret`, so
+//! nothing is stored: changing the secret revokes every link handed out.
+//! Creating a link and every read through one are recorded on the
+//! `fhir_server::audit` target with the patient, scope and expiry.
+
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fmt;
+use uuid::Uuid;
+
+/// Shortest `[sharing] secret` accepted
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// The `[sharing]` section of the server config
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SharingConfig {
+    /// Key links are signed with; sharing is disabled while it is empty
+    pub secret: String,
+    /// Lifetime of a link when the request names none
+    pub default_hours: u32,
+    /// Longest lifetime a link may be given
+    pub max_hours: u32,
+}
+
+impl Default for SharingConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            default_hours: 24,
+            max_hours: 30 * 24,
+        }
+    }
+}
+
+// The secret is left out so configuration changes can be logged
+impl fmt::Debug for SharingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharingConfig")
+            .field(
+                "secret",
+                &if self.secret.is_empty() {
+                    "unset"
+                } else {
+                    "set"
+                },
+            )
+            .field("default_hours", &self.default_hours)
+            .field("max_hours", &self.max_hours)
+            .finish()
+    }
+}
+
+impl SharingConfig {
+    pub fn enabled(&self) -> bool {
+        !self.secret.is_empty()
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.enabled() && self.secret.len() < MIN_SECRET_LEN {
+            anyhow::bail!(
+                "sharing.secret must be at least {} characters",
+                MIN_SECRET_LEN
+            );
+        }
+        if self.default_hours == 0 || self.default_hours > self.max_hours {
+            anyhow::bail!("sharing: default_hours must be between 1 and max_hours");
+        }
+        Ok(())
+    }
+}
+
+/// What a sharing link grants read access to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareScope {
+    /// The Patient reduced to its summary elements
+    Summary,
+    /// The Patient and its Observations
+    Record,
+}
+
+impl ShareScope {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "summary" => Some(ShareScope::Summary),
+            "record" => Some(ShareScope::Record),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShareScope::Summary => "summary",
+            ShareScope::Record => "record",
+        }
+    }
+}
+
+/// Read access to one patient until `expires_at`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareGrant {
+    pub patient_id: Uuid,
+    pub scope: ShareScope,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Why a sharing token was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidShare {
+    /// Not a token this server hands out, or not signed with its secret
+    Forged,
+    Expired(DateTime<Utc>),
+}
+
+impl fmt::Display for InvalidShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidShare::Forged => write!(f, "This sharing link is not valid"),
+            InvalidShare::Expired(at) => write!(f, "This sharing link expired at {}", at),
+        }
+    }
+}
+
+impl std::error::Error for InvalidShare {}
+
+/// HMAC-SHA256 of `message` under `secret`, in lower-case hex
+pub(crate) fn sign(secret: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `signature` is the hex [`sign`]ature of `message`, compared in
+/// constant time
+pub(crate) fn verify(secret: &[u8], message: &[u8], signature: &str) -> bool {
+    let Some(signature) = decode_hex(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl ShareGrant {
+    /// `<patient id>.<scope>.<expiry in Unix seconds>.<signature>`, all of
+    /// it safe in a URL path
+    pub fn token(&self, secret: &str) -> String {
+        let claims = format!(
+            "{}.{}.{}",
+            self.patient_id.simple(),
+            self.scope.as_str(),
+            self.expires_at.timestamp()
+        );
+        let signature = sign(secret.as_bytes(), claims.as_bytes());
+        format!("{}.{}", claims, signature)
+    }
+
+    /// The grant of a token signed under `secret`, if it is still valid at
+    /// `now`
+    pub fn verify(token: &str, secret: &str, now: DateTime<Utc>) -> Result<Self, InvalidShare> {
+        let (claims, signature) = token.rsplit_once('.').ok_or(InvalidShare::Forged)?;
+        if !verify(secret.as_bytes(), claims.as_bytes(), signature) {
+            return Err(InvalidShare::Forged);
+        }
+        let mut parts = claims.split('.');
+        let (Some(id), Some(scope), Some(expires), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(InvalidShare::Forged);
+        };
+        let grant = Self {
+            patient_id: Uuid::try_parse(id).map_err(|_| InvalidShare::Forged)?,
+            scope: ShareScope::parse(scope).ok_or(InvalidShare::Forged)?,
+            expires_at: expires
+                .parse()
+                .ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+                .ok_or(InvalidShare::Forged)?,
+        };
+        if grant.expires_at <= now {
+            return Err(InvalidShare::Expired(grant.expires_at));
+        }
+        Ok(grant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_token_round_trip() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let grant = ShareGrant {
+            patient_id: Uuid::new_v4(),
+            scope: ShareScope::Record,
+            expires_at: now + Duration::hours(24),
+        };
+        let token = grant.token(SECRET);
+        assert_eq!(ShareGrant::verify(&token, SECRET, now), Ok(grant.clone()));
+
+        assert_eq!(
+            ShareGrant::verify(&token, SECRET, grant.expires_at),
+            Err(InvalidShare::Expired(grant.expires_at))
+        );
+        let other_secret = "fedcba9876543210fedcba9876543210";
+        assert_eq!(
+            ShareGrant::verify(&token, other_secret, now),
+            Err(InvalidShare::Forged)
+        );
+        // Widening the scope or the expiry breaks the signature
+        let widened = token.replace(".record.", ".summary.");
+        assert_eq!(
+            ShareGrant::verify(&widened, SECRET, now),
+            Err(InvalidShare::Forged)
+        );
+        for forged in ["", "abc", "a.b.c.d", &format!("{}0", token)] {
+            assert_eq!(
+                ShareGrant::verify(forged, SECRET, now),
+                Err(InvalidShare::Forged)
+            );
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(verify(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        ));
+        assert!(!verify(b"Jefe", b"what do ya want?", "5bdc"));
+        assert!(!verify(b"Jefe", b"what do ya want?", "zz"));
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(!SharingConfig::default().enabled());
+        assert!(SharingConfig::default().validate().is_ok());
+        let short = SharingConfig {
+            secret: "short".to_string(),
+            ..SharingConfig::default()
+        };
+        assert!(short.validate().is_err());
+        let configured = SharingConfig {
+            secret: SECRET.to_string(),
+            ..SharingConfig::default()
+        };
+        assert!(!format!("{:?}", configured).contains(SECRET));
+    }
+}
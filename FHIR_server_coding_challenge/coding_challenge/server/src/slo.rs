@@ -0,0 +1,457 @@
+//! Latency of FHIR interactions against configured objectives.
+//!
+//! Every request under `/fhir` is timed, until its response head is ready,
+//! and counted towards one interaction: `read`, `search`, `create`,
+//! `update`, `patch`, `delete`, `history`, `transaction` (Bundles),
+//! `capabilities` or `operation` (`$` operations). `[latency_slos.<interaction>]`
+//! sets an objective for one of them:
+//!
+//! ```toml
+//! [latency_slos.read]
+//! percentile = 95       # 95% of reads ...
+//! threshold_ms = 200    # ... take at most 200 ms
+//! window_secs = 300     # over the last 5 minutes
+//! ```
+//!
+//! The objective allows `100 - percentile` percent of requests to be slower;
+//! the burn rate is the share that was, divided by that budget. At 1 the
+//! budget is used up exactly as fast as allowed. Objectives are checked every
+//! [`EVALUATION_INTERVAL`]: when the burn rate of one goes above 1 a warning
+//! is logged on the `fhir_server::slo` target, and again when it recovers.
+//! p50, p95, p99 and the burn rates are exposed at `GET /metrics`.
+//!
+//! Latencies are held in memory, so each replica reports its own.
+
+use crate::config::{ServerConfig, SharedConfig};
+use crate::metrics::{labels, OpenMetricsWriter};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Interactions latencies are tracked for
+pub const INTERACTIONS: &[&str] = &[
+    "read",
+    "search",
+    "create",
+    "update",
+    "patch",
+    "delete",
+    "history",
+    "transaction",
+    "capabilities",
+    "operation",
+];
+
+/// How often objectives are checked for alerts
+pub const EVALUATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest `window_secs`; latencies older than this are dropped
+pub const MAX_WINDOW_SECS: u64 = 3600;
+
+/// Window of the percentiles of interactions without an objective
+const DEFAULT_WINDOW_SECS: u64 = 300;
+
+/// Latencies kept per interaction; the oldest are dropped first
+const MAX_SAMPLES: usize = 10_000;
+
+/// Requests a window needs before its objective can alert, so a single slow
+/// request after a quiet period does not
+const MIN_ALERT_REQUESTS: usize = 20;
+
+/// One `[latency_slos.<interaction>]` entry of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LatencySlo {
+    /// Share of requests, in percent, that must meet `threshold_ms`
+    pub percentile: f64,
+    pub threshold_ms: u64,
+    /// Period the objective is measured over, at most [`MAX_WINDOW_SECS`]
+    pub window_secs: u64,
+}
+
+impl Default for LatencySlo {
+    fn default() -> Self {
+        Self {
+            percentile: 95.0,
+            threshold_ms: 500,
+            window_secs: DEFAULT_WINDOW_SECS,
+        }
+    }
+}
+
+impl LatencySlo {
+    pub(crate) fn validate(&self, interaction: &str) -> anyhow::Result<()> {
+        if !INTERACTIONS.contains(&interaction) {
+            anyhow::bail!(
+                "latency_slos: {} is not one of {}",
+                interaction,
+                INTERACTIONS.join(", ")
+            );
+        }
+        if !(self.percentile > 0.0 && self.percentile < 100.0) {
+            anyhow::bail!(
+                "latency_slos.{}: percentile must be between 0 and 100",
+                interaction
+            );
+        }
+        if self.threshold_ms == 0 || self.window_secs == 0 || self.window_secs > MAX_WINDOW_SECS {
+            anyhow::bail!(
+                "latency_slos.{}: threshold_ms must be greater than 0 and window_secs between 1 and {}",
+                interaction,
+                MAX_WINDOW_SECS
+            );
+        }
+        Ok(())
+    }
+
+    fn threshold(&self) -> Duration {
+        Duration::from_millis(self.threshold_ms)
+    }
+}
+
+/// Latencies of one interaction within a window
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowLatency {
+    pub requests: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// Share of requests over the objective's threshold divided by its
+    /// budget; `None` without an objective
+    pub burn_rate: Option<f64>,
+}
+
+/// Recent latencies of every interaction
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    samples: Mutex<HashMap<&'static str, VecDeque<(Instant, Duration)>>>,
+    /// Interactions whose objective is currently breached
+    breached: Mutex<HashSet<&'static str>>,
+    breaches: AtomicU64,
+}
+
+impl LatencyTracker {
+    pub fn record(&self, interaction: &'static str, latency: Duration, now: Instant) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        let samples = samples.entry(interaction).or_default();
+        while samples.len() >= MAX_SAMPLES
+            || samples.front().is_some_and(|(at, _)| {
+                now.duration_since(*at) > Duration::from_secs(MAX_WINDOW_SECS)
+            })
+        {
+            samples.pop_front();
+        }
+        samples.push_back((now, latency));
+    }
+
+    /// Latencies of `interaction` within the window of its objective in
+    /// `config`, or the default window
+    pub fn window(
+        &self,
+        config: &ServerConfig,
+        interaction: &str,
+        now: Instant,
+    ) -> Option<WindowLatency> {
+        let slo = config.latency_slos.get(interaction);
+        let window = Duration::from_secs(slo.map_or(DEFAULT_WINDOW_SECS, |slo| slo.window_secs));
+        let mut latencies: Vec<Duration> = {
+            let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+            samples
+                .get(interaction)?
+                .iter()
+                .filter(|(at, _)| now.duration_since(*at) <= window)
+                .map(|(_, latency)| *latency)
+                .collect()
+        };
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort();
+
+        let requests = latencies.len();
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * requests as f64).ceil() as usize;
+            latencies[rank.clamp(1, requests) - 1]
+        };
+        let burn_rate = slo.map(|slo| {
+            let over = latencies.iter().filter(|l| **l > slo.threshold()).count();
+            (over as f64 / requests as f64) / (1.0 - slo.percentile / 100.0)
+        });
+        Some(WindowLatency {
+            requests,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            burn_rate,
+        })
+    }
+
+    /// Check every objective, logging those that started or stopped being
+    /// breached since the last check
+    pub fn evaluate(&self, config: &ServerConfig, now: Instant) {
+        let mut breached = self.breached.lock().unwrap_or_else(|e| e.into_inner());
+        for interaction in INTERACTIONS {
+            let Some(slo) = config.latency_slos.get(*interaction) else {
+                breached.remove(interaction);
+                continue;
+            };
+            let window = self.window(config, interaction, now);
+            let burn_rate = window.as_ref().and_then(|w| w.burn_rate).unwrap_or(0.0);
+            let requests = window.as_ref().map_or(0, |w| w.requests);
+
+            if burn_rate > 1.0 && requests >= MIN_ALERT_REQUESTS {
+                if breached.insert(interaction) {
+                    self.breaches.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        target: "fhir_server::slo",
+                        interaction,
+                        burn_rate,
+                        requests,
+                        "latency objective exceeded: more than {}% of {} requests in the last {} s took over {} ms",
+                        100.0 - slo.percentile,
+                        interaction,
+                        slo.window_secs,
+                        slo.threshold_ms
+                    );
+                }
+            } else if burn_rate <= 1.0 && breached.remove(interaction) {
+                tracing::info!(
+                    target: "fhir_server::slo",
+                    interaction,
+                    burn_rate,
+                    requests,
+                    "latency objective met again"
+                );
+            }
+        }
+    }
+
+    /// Append the percentiles, objectives and burn rates to `out`
+    pub fn write_metrics(&self, config: &ServerConfig, out: &mut OpenMetricsWriter) {
+        let now = Instant::now();
+        let windows: Vec<(&str, WindowLatency)> = INTERACTIONS
+            .iter()
+            .filter_map(|interaction| Some((*interaction, self.window(config, interaction, now)?)))
+            .collect();
+
+        let mut quantiles = Vec::new();
+        for (interaction, window) in &windows {
+            for (quantile, latency) in [
+                ("0.5", window.p50),
+                ("0.95", window.p95),
+                ("0.99", window.p99),
+            ] {
+                quantiles.push((
+                    labels(&[("interaction", interaction), ("quantile", quantile)]),
+                    latency.as_secs_f64(),
+                ));
+            }
+        }
+        out.labeled_gauge(
+            "fhir_interaction_latency_seconds",
+            "Latency percentiles of recent requests by interaction",
+            &quantiles,
+        );
+
+        let objectives: Vec<(String, f64)> = config
+            .latency_slos
+            .iter()
+            .map(|(interaction, slo)| {
+                (
+                    labels(&[
+                        ("interaction", interaction),
+                        ("percentile", &slo.percentile.to_string()),
+                    ]),
+                    slo.threshold().as_secs_f64(),
+                )
+            })
+            .collect();
+        out.labeled_gauge(
+            "fhir_latency_slo_threshold_seconds",
+            "Latency the configured share of requests must meet",
+            &objectives,
+        );
+        let burn_rates: Vec<(String, f64)> = windows
+            .iter()
+            .filter_map(|(interaction, window)| {
+                Some((labels(&[("interaction", interaction)]), window.burn_rate?))
+            })
+            .collect();
+        out.labeled_gauge(
+            "fhir_latency_slo_burn_rate",
+            "Share of requests over the latency objective divided by its error budget",
+            &burn_rates,
+        );
+        out.counter(
+            "fhir_latency_slo_breaches",
+            "Times a latency objective started being exceeded",
+            self.breaches.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Interaction of a request to the route `route`, if it is one
+fn interaction(method: &Method, route: &str) -> Option<&'static str> {
+    let segments: Vec<&str> = route
+        .strip_prefix("/fhir")?
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    if segments.iter().any(|segment| segment.starts_with('$')) {
+        return Some("operation");
+    }
+    if segments.contains(&"_history") {
+        return Some("history");
+    }
+    match (segments.as_slice(), method) {
+        ([], &Method::POST) => Some("transaction"),
+        (["metadata"], _) => Some("capabilities"),
+        // `_changes`, `_jobs` and `_share` are not FHIR interactions
+        ([first, ..], _) if first.starts_with('_') => None,
+        ([_], &Method::GET) => Some("search"),
+        ([_], &Method::POST) => Some("create"),
+        ([_], &Method::PUT) | ([_, _], &Method::PUT) => Some("update"),
+        ([_, _], &Method::GET) => Some("read"),
+        ([_, _], &Method::PATCH) => Some("patch"),
+        ([_, _], &Method::DELETE) => Some("delete"),
+        _ => None,
+    }
+}
+
+/// Time requests to FHIR routes
+pub async fn track_latency(
+    State(tracker): State<Arc<LatencyTracker>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let interaction = request
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| interaction(request.method(), route.as_str()));
+    let Some(interaction) = interaction else {
+        return next.run(request).await;
+    };
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    tracker.record(interaction, started.elapsed(), Instant::now());
+    response
+}
+
+/// Check the objectives every [`EVALUATION_INTERVAL`]; reloaded objectives
+/// apply from the next check
+pub fn spawn_monitor(tracker: Arc<LatencyTracker>, config: Arc<SharedConfig>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(EVALUATION_INTERVAL).await;
+            tracker.evaluate(&config.get(), Instant::now());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(percentile: f64, threshold_ms: u64) -> ServerConfig {
+        let mut config = ServerConfig::default();
+        config.latency_slos.insert(
+            "read".to_string(),
+            LatencySlo {
+                percentile,
+                threshold_ms,
+                window_secs: 60,
+            },
+        );
+        config
+    }
+
+    #[test]
+    fn test_interaction_of_route() {
+        let cases = [
+            (Method::GET, "/fhir/Patient/:id", Some("read")),
+            (Method::GET, "/fhir/:resource_type", Some("search")),
+            (Method::POST, "/fhir/Observation", Some("create")),
+            (Method::PUT, "/fhir/Patient", Some("update")),
+            (Method::PATCH, "/fhir/Patient/:id", Some("patch")),
+            (Method::GET, "/fhir/Patient/:id/_history", Some("history")),
+            (Method::POST, "/fhir", Some("transaction")),
+            (Method::GET, "/fhir/metadata", Some("capabilities")),
+            (Method::POST, "/fhir/Patient/$cohort", Some("operation")),
+            (Method::GET, "/fhir/_jobs/:id", None),
+            (Method::GET, "/metrics", None),
+        ];
+        for (method, route, expected) in cases {
+            assert_eq!(
+                interaction(&method, route),
+                expected,
+                "{} {}",
+                method,
+                route
+            );
+        }
+    }
+
+    #[test]
+    fn test_percentiles_and_burn_rate() {
+        let tracker = LatencyTracker::default();
+        let start = Instant::now();
+        let now = start + Duration::from_secs(100);
+        // Latencies of 1 to 100 ms; the first 10 fall out of the window
+        for ms in 1..=100 {
+            let at = if ms <= 10 { start } else { now };
+            tracker.record("read", Duration::from_millis(ms), at);
+        }
+
+        let window = tracker.window(&config(95.0, 90), "read", now).unwrap();
+        assert_eq!(window.requests, 90);
+        assert_eq!(window.p50, Duration::from_millis(55));
+        assert_eq!(window.p95, Duration::from_millis(96));
+        assert_eq!(window.p99, Duration::from_millis(100));
+        // 10 of 90 over 90 ms against a budget of 5%
+        let burn_rate = window.burn_rate.unwrap();
+        assert!((burn_rate - 10.0 / 90.0 / 0.05).abs() < 1e-9);
+
+        assert!(tracker.window(&config(95.0, 90), "search", now).is_none());
+        assert!(tracker
+            .window(&ServerConfig::default(), "read", now)
+            .unwrap()
+            .burn_rate
+            .is_none());
+    }
+
+    #[test]
+    fn test_breach_is_counted_once_until_recovered() {
+        let tracker = LatencyTracker::default();
+        let now = Instant::now();
+        for _ in 0..MIN_ALERT_REQUESTS {
+            tracker.record("read", Duration::from_millis(300), now);
+        }
+
+        tracker.evaluate(&config(95.0, 200), now);
+        tracker.evaluate(&config(95.0, 200), now);
+        assert_eq!(tracker.breaches.load(Ordering::Relaxed), 1);
+
+        tracker.evaluate(&config(95.0, 500), now);
+        assert!(tracker.breached.lock().unwrap().is_empty());
+        tracker.evaluate(&config(95.0, 200), now);
+        assert_eq!(tracker.breaches.load(Ordering::Relaxed), 2);
+
+        let mut out = OpenMetricsWriter::default();
+        tracker.write_metrics(&config(50.0, 200), &mut out);
+        let text = out.finish();
+        assert!(text.contains(
+            "fhir_interaction_latency_seconds{interaction=\"read\",quantile=\"0.95\"} 0.3\n"
+        ));
+        assert!(text.contains("fhir_latency_slo_burn_rate{interaction=\"read\"} 2\n"));
+        assert!(text.contains("fhir_latency_slo_breaches_total 2\n"));
+    }
+}
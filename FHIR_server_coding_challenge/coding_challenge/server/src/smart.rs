@@ -0,0 +1,1023 @@
+//! SMART on FHIR access tokens.
+//!
+//! With `[auth.smart]` configured, a bearer token that is a JWT rather than
+//! an API key is verified against the signing keys the issuer publishes at
+//! `jwks_url` (RS256/384/512, PS256/384/512, ES256/384), and must carry the
+//! configured `iss`, `aud` and a current `exp`. Its `scope` claim then
+//! decides which FHIR interactions it allows:
+//!
+//! - `patient/`, `user/` and `system/` scopes name a resource type or `*`
+//!   and the interactions, `read`, `write` or `*` (SMART v1) or any of the
+//!   `cruds` letters (SMART v2); scopes with a query (`?category=...`) are
+//!   not supported and grant nothing
+//! - `patient/` scopes only reach the Patient named by the token's
+//!   `patient` claim and what is below its path
+//! - the system-level routes (Bundles, system history, `_changes`,
+//!   `$export`, jobs) need a `*` scope of `user/` or `system/`
+//!
+//! With `[auth.roles]` configured, the token's `roles` claim is checked by
+//! [`crate::authorization`] as well.
+//!
+//! Tokens never open `/admin`, which keeps requiring an API key. A token
+//! that fails verification is answered with 401 and an `invalid_token`
+//! challenge, one lacking the scope with 403 and `insufficient_scope`.
+//!
+//! Clients discover the authorization server from
+//! `/.well-known/smart-configuration` ([`smart_configuration`]) and from the
+//! `security` of the CapabilityStatement ([`rest_security`]).
+
+use crate::api_keys::AuthConfig;
+use crate::config::SharedConfig;
+use crate::models::OperationOutcome;
+use axum::{
+    http::{header, Method, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// The `[auth.smart]` section of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SmartConfig {
+    /// `iss` of accepted tokens; tokens are not accepted while it is empty
+    pub issuer: String,
+    /// Where the issuer publishes its signing keys as a JWK Set
+    pub jwks_url: String,
+    /// `aud` accepted tokens must name, usually the FHIR base URL; any
+    /// audience is accepted while unset
+    pub audience: Option<String>,
+    /// How often the signing keys are fetched again
+    pub jwks_refresh_secs: u64,
+    /// Clock skew tolerated on `exp` and `nbf`
+    pub leeway_secs: i64,
+    /// OAuth endpoints of the issuer, published for discovery
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    /// SMART capabilities of the issuer, published for discovery
+    pub capabilities: Vec<String>,
+}
+
+impl Default for SmartConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            jwks_url: String::new(),
+            audience: None,
+            jwks_refresh_secs: 3600,
+            leeway_secs: 60,
+            authorization_endpoint: None,
+            token_endpoint: None,
+            capabilities: [
+                "launch-standalone",
+                "client-public",
+                "client-confidential-symmetric",
+                "context-standalone-patient",
+                "permission-patient",
+                "permission-user",
+                "permission-v1",
+                "permission-v2",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl SmartConfig {
+    pub fn enabled(&self) -> bool {
+        !self.issuer.is_empty()
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        if !self.jwks_url.starts_with("https://") && !self.jwks_url.starts_with("http://") {
+            anyhow::bail!("auth.smart.jwks_url must be an http(s) URL when issuer is set");
+        }
+        if self.jwks_refresh_secs < 60 {
+            anyhow::bail!("auth.smart.jwks_refresh_secs must be at least 60");
+        }
+        if self.leeway_secs < 0 {
+            anyhow::bail!("auth.smart.leeway_secs must not be negative");
+        }
+        for (name, url) in [
+            ("authorization_endpoint", &self.authorization_endpoint),
+            ("token_endpoint", &self.token_endpoint),
+        ] {
+            if let Some(url) = url.as_deref().filter(|url| !url.starts_with("https://")) {
+                anyhow::bail!("auth.smart.{} must be an https URL, not {}", name, url);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scopes this server enforces, as published for discovery
+const SCOPES_SUPPORTED: &[&str] = &[
+    "openid",
+    "fhirUser",
+    "launch/patient",
+    "offline_access",
+    "patient/*.read",
+    "patient/*.rs",
+    "user/*.read",
+    "user/*.write",
+    "user/*.cruds",
+    "system/*.read",
+    "system/*.write",
+    "system/*.cruds",
+];
+
+/// The SMART App Launch discovery document of `config`, served at
+/// `/.well-known/smart-configuration`
+pub fn smart_configuration(config: &SmartConfig) -> Value {
+    let mut document = json!({
+        "issuer": config.issuer,
+        "jwks_uri": config.jwks_url,
+        "grant_types_supported": ["authorization_code", "client_credentials"],
+        "response_types_supported": ["code"],
+        "code_challenge_methods_supported": ["S256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "private_key_jwt"],
+        "scopes_supported": SCOPES_SUPPORTED,
+        "capabilities": config.capabilities,
+    });
+    if let Some(url) = &config.authorization_endpoint {
+        document["authorization_endpoint"] = json!(url);
+    }
+    if let Some(url) = &config.token_endpoint {
+        document["token_endpoint"] = json!(url);
+    }
+    document
+}
+
+/// `rest.security` of the CapabilityStatement: SMART on FHIR with the
+/// issuer's endpoints when tokens are accepted, a description of the API
+/// keys when only those are; `None` while the FHIR routes are open
+pub fn rest_security(auth: &AuthConfig, cors: bool) -> Option<Value> {
+    if auth.smart.enabled() {
+        let uris: Vec<Value> = [
+            ("authorize", &auth.smart.authorization_endpoint),
+            ("token", &auth.smart.token_endpoint),
+        ]
+        .into_iter()
+        .filter_map(|(name, url)| Some(json!({ "url": name, "valueUri": url.as_ref()? })))
+        .collect();
+        let mut security = json!({
+            "cors": cors,
+            "service": [{
+                "coding": [{
+                    "system": SECURITY_SERVICE_SYSTEM,
+                    "code": "SMART-on-FHIR",
+                }],
+                "text": "OAuth2 using SMART-on-FHIR profile (see http://docs.smarthealthit.org)",
+            }],
+            "description": format!(
+                "Send an access token issued by {} or an API key as 'Authorization: Bearer <token>'; see /.well-known/smart-configuration",
+                auth.smart.issuer
+            ),
+        });
+        if !uris.is_empty() {
+            security["extension"] = json!([{ "url": OAUTH_URIS_EXTENSION, "extension": uris }]);
+        }
+        Some(security)
+    } else if auth.required {
+        Some(json!({
+            "cors": cors,
+            "description": "Send an API key as 'Authorization: Bearer <key>'",
+        }))
+    } else {
+        None
+    }
+}
+
+const SECURITY_SERVICE_SYSTEM: &str =
+    "http://terminology.hl7.org/CodeSystem/restful-security-service";
+const OAUTH_URIS_EXTENSION: &str =
+    "http://fhir-registry.smarthealthit.org/StructureDefinition/oauth-uris";
+
+/// Whether `token` looks like a JWT (three base64url parts) rather than an
+/// API key
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeContext {
+    Patient,
+    User,
+    System,
+}
+
+/// One resource scope of a token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub context: ScopeContext,
+    /// `None` for `*`
+    pub resource_type: Option<String>,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Scope {
+    /// `patient/Observation.read`, `user/*.write`, `system/Patient.rs`, ...;
+    /// `None` for other scopes (`openid`, `launch`, ...) and those with a
+    /// query
+    pub fn parse(scope: &str) -> Option<Self> {
+        let (context, rest) = scope.split_once('/')?;
+        let context = match context {
+            "patient" => ScopeContext::Patient,
+            "user" => ScopeContext::User,
+            "system" => ScopeContext::System,
+            _ => return None,
+        };
+        let (resource_type, interactions) = rest.split_once('.')?;
+        let resource_type = match resource_type {
+            "*" => None,
+            t if t.starts_with(|c: char| c.is_ascii_uppercase())
+                && t.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                Some(t.to_string())
+            }
+            _ => return None,
+        };
+        let (read, write) = match interactions {
+            "read" => (true, false),
+            "write" => (false, true),
+            "*" => (true, true),
+            letters if !letters.is_empty() && letters.chars().all(|c| "cruds".contains(c)) => (
+                letters.contains(['r', 's']),
+                letters.contains(['c', 'u', 'd']),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            context,
+            resource_type,
+            read,
+            write,
+        })
+    }
+
+    /// Whether the scope allows `access` for a token in the context of
+    /// `patient`
+    pub fn grants(&self, access: &RequiredAccess, patient: Option<&str>) -> bool {
+        let resource_type = match (&self.resource_type, &access.resource_type) {
+            (None, _) => true,
+            (Some(scope), Some(required)) => scope == required,
+            (Some(_), None) => false,
+        };
+        let interaction = match access.interaction {
+            Interaction::Read => self.read,
+            Interaction::Write => self.write,
+        };
+        let context = match self.context {
+            ScopeContext::Patient => {
+                access.patient.is_some() && access.patient.as_deref() == patient
+            }
+            ScopeContext::User | ScopeContext::System => true,
+        };
+        resource_type && interaction && context
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    Read,
+    Write,
+}
+
+/// What a FHIR request needs a scope for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequiredAccess {
+    /// `None` for the system-level routes
+    pub resource_type: Option<String>,
+    pub interaction: Interaction,
+    /// The id of the Patient the path is on (`/fhir/Patient/<id>...`)
+    pub patient: Option<String>,
+}
+
+/// Operations served on POST that change data; the others only read
+const WRITE_OPERATIONS: &[&str] = &["$merge-update", "$reserve-id", "$lock", "$unlock", "$share"];
+
+/// The access a request to `path` with `method` needs; `None` outside
+/// `/fhir`
+pub fn required_access(method: &Method, path: &str) -> Option<RequiredAccess> {
+    let rest = path.strip_prefix("/fhir")?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let segments: Vec<&str> = rest.split('/').filter(|s| !s.is_empty()).collect();
+    let resource_type = segments
+        .first()
+        .filter(|s| s.starts_with(|c: char| c.is_ascii_uppercase()))
+        .map(|s| s.to_string());
+    let patient = match segments.as_slice() {
+        ["Patient", id, ..] if !id.starts_with(['$', '_']) => Some(id.to_string()),
+        _ => None,
+    };
+    let last = segments.last().copied().unwrap_or_default();
+    let reads = method == Method::GET
+        || method == Method::HEAD
+        || (method == Method::POST && last.starts_with('$') && !WRITE_OPERATIONS.contains(&last));
+    let interaction = if reads {
+        Interaction::Read
+    } else {
+        Interaction::Write
+    };
+    Some(RequiredAccess {
+        resource_type,
+        interaction,
+        patient,
+    })
+}
+
+/// A verified token, as a request extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmartToken {
+    pub subject: Option<String>,
+    pub client_id: Option<String>,
+    /// The `patient` launch context
+    pub patient: Option<String>,
+    /// The `fhirUser` claim, e.g. `Patient/<id>` or `Practitioner/<id>`
+    pub fhir_user: Option<String>,
+    pub scopes: Vec<Scope>,
+    /// The `roles` claim, checked by [`crate::authorization`]
+    pub roles: Vec<String>,
+}
+
+impl SmartToken {
+    /// Whether the scopes allow `access`
+    pub fn allows(&self, access: &RequiredAccess) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| scope.grants(access, self.patient.as_deref()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum VerifyingKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    P256(Vec<u8>),
+    P384(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Jwk {
+    kid: Option<String>,
+    key: VerifyingKey,
+}
+
+/// The signing keys of a JWK Set; keys of other types and curves, and
+/// those not meant for signatures, are left out
+fn parse_jwks(jwks: &Value) -> anyhow::Result<Vec<Jwk>> {
+    let keys = jwks["keys"]
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("The JWK Set has no keys array"))?;
+    let field = |key: &Value, name: &str| -> Option<Vec<u8>> {
+        URL_SAFE_NO_PAD.decode(key[name].as_str()?).ok()
+    };
+    Ok(keys
+        .iter()
+        .filter(|key| key["use"].as_str().is_none_or(|u| u == "sig"))
+        .filter_map(|key| {
+            let verifying = match (key["kty"].as_str()?, key["crv"].as_str()) {
+                ("RSA", _) => {
+                    let n = field(key, "n")?;
+                    let start = n.iter().position(|b| *b != 0).unwrap_or(n.len());
+                    VerifyingKey::Rsa {
+                        n: n[start..].to_vec(),
+                        e: field(key, "e")?,
+                    }
+                }
+                ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                    let mut point = vec![0x04];
+                    point.extend(field(key, "x")?);
+                    point.extend(field(key, "y")?);
+                    if crv == "P-256" {
+                        VerifyingKey::P256(point)
+                    } else {
+                        VerifyingKey::P384(point)
+                    }
+                }
+                _ => return None,
+            };
+            Some(Jwk {
+                kid: key["kid"].as_str().map(String::from),
+                key: verifying,
+            })
+        })
+        .collect())
+}
+
+/// Why a token was not accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidToken(pub String);
+
+impl std::fmt::Display for InvalidToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidToken {}
+
+fn invalid(message: impl Into<String>) -> InvalidToken {
+    InvalidToken(message.into())
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    aud: Option<OneOrMany>,
+    exp: i64,
+    nbf: Option<i64>,
+    sub: Option<String>,
+    client_id: Option<String>,
+    #[serde(default)]
+    scope: String,
+    patient: Option<String>,
+    #[serde(rename = "fhirUser")]
+    fhir_user: Option<String>,
+    roles: Option<OneOrMany>,
+}
+
+/// The issuer's signing keys, fetched by [`spawn_jwks_refresh`]
+#[derive(Default)]
+pub struct TokenVerifier {
+    keys: RwLock<Vec<Jwk>>,
+}
+
+impl TokenVerifier {
+    /// Replace the signing keys with those of the JWK Set `jwks`
+    pub fn set_jwks(&self, jwks: &Value) -> anyhow::Result<usize> {
+        let keys = parse_jwks(jwks)?;
+        let count = keys.len();
+        *self.keys.write().unwrap() = keys;
+        Ok(count)
+    }
+
+    /// Verify `token` as issued under `config` at `now` (seconds since the
+    /// epoch)
+    pub fn verify(
+        &self,
+        config: &SmartConfig,
+        token: &str,
+        now: i64,
+    ) -> Result<SmartToken, InvalidToken> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [header, payload, signature] = parts.as_slice() else {
+            return Err(invalid("The token is not a JWT"));
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| invalid("The token is not base64url encoded"))
+        };
+        let header: JwtHeader = serde_json::from_slice(&decode(header)?)
+            .map_err(|_| invalid("The token header is not valid"))?;
+        let signature = decode(signature)?;
+        let message = &token[..token.len() - parts[2].len() - 1];
+        self.verify_signature(&header, message.as_bytes(), &signature)?;
+
+        let claims: Claims = serde_json::from_slice(&decode(payload)?)
+            .map_err(|_| invalid("The token claims are not valid"))?;
+        if claims.iss != config.issuer {
+            return Err(invalid(format!(
+                "The token is not issued by {}",
+                config.issuer
+            )));
+        }
+        if let Some(audience) = &config.audience {
+            let named = match &claims.aud {
+                Some(OneOrMany::One(aud)) => aud == audience,
+                Some(OneOrMany::Many(auds)) => auds.contains(audience),
+                None => false,
+            };
+            if !named {
+                return Err(invalid(format!("The token is not meant for {}", audience)));
+            }
+        }
+        if claims.exp + config.leeway_secs <= now {
+            return Err(invalid("The token has expired"));
+        }
+        if claims.nbf.is_some_and(|nbf| nbf - config.leeway_secs > now) {
+            return Err(invalid("The token is not valid yet"));
+        }
+
+        Ok(SmartToken {
+            subject: claims.sub,
+            client_id: claims.client_id,
+            patient: claims.patient,
+            fhir_user: claims.fhir_user,
+            scopes: claims
+                .scope
+                .split_whitespace()
+                .filter_map(Scope::parse)
+                .collect(),
+            roles: claims.roles.map(OneOrMany::into_vec).unwrap_or_default(),
+        })
+    }
+
+    fn verify_signature(
+        &self,
+        header: &JwtHeader,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), InvalidToken> {
+        let rsa: Option<&'static signature::RsaParameters> = match header.alg.as_str() {
+            "RS256" => Some(&signature::RSA_PKCS1_2048_8192_SHA256),
+            "RS384" => Some(&signature::RSA_PKCS1_2048_8192_SHA384),
+            "RS512" => Some(&signature::RSA_PKCS1_2048_8192_SHA512),
+            "PS256" => Some(&signature::RSA_PSS_2048_8192_SHA256),
+            "PS384" => Some(&signature::RSA_PSS_2048_8192_SHA384),
+            "PS512" => Some(&signature::RSA_PSS_2048_8192_SHA512),
+            "ES256" | "ES384" => None,
+            alg => {
+                return Err(invalid(format!(
+                    "Tokens signed with {} are not accepted",
+                    alg
+                )))
+            }
+        };
+
+        let keys = self.keys.read().unwrap();
+        let candidates = keys
+            .iter()
+            .filter(|jwk| header.kid.is_none() || jwk.kid.is_none() || jwk.kid == header.kid);
+        let mut known = false;
+        for jwk in candidates {
+            let verified = match (&jwk.key, rsa, header.alg.as_str()) {
+                (VerifyingKey::Rsa { n, e }, Some(params), _) => {
+                    RsaPublicKeyComponents { n, e }.verify(params, message, signature)
+                }
+                (VerifyingKey::P256(point), None, "ES256") => {
+                    UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                        .verify(message, signature)
+                }
+                (VerifyingKey::P384(point), None, "ES384") => {
+                    UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                        .verify(message, signature)
+                }
+                _ => continue,
+            };
+            if verified.is_ok() {
+                return Ok(());
+            }
+            known = true;
+        }
+        Err(invalid(if known {
+            "The token signature is not valid"
+        } else {
+            "The token is not signed with a known key"
+        }))
+    }
+}
+
+/// Fetch the signing keys now and every `jwks_refresh_secs`; the keys
+/// fetched last are kept while the issuer cannot be reached
+pub fn spawn_jwks_refresh(verifier: Arc<TokenVerifier>, config: Arc<SharedConfig>) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            let smart = config.get().auth.smart.clone();
+            if smart.enabled() {
+                match fetch_jwks(&client, &smart.jwks_url).await {
+                    Ok(jwks) => match verifier.set_jwks(&jwks) {
+                        Ok(count) => {
+                            tracing::debug!("Loaded {} keys from {}", count, smart.jwks_url)
+                        }
+                        Err(e) => tracing::warn!("Keeping the keys of {}: {:#}", smart.jwks_url, e),
+                    },
+                    Err(e) => tracing::warn!("Keeping the keys of {}: {:#}", smart.jwks_url, e),
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(smart.jwks_refresh_secs)).await;
+        }
+    });
+}
+
+async fn fetch_jwks(client: &reqwest::Client, url: &str) -> anyhow::Result<Value> {
+    let response = client
+        .get(url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// 401 for a token that failed verification
+pub fn invalid_token_response(error: &InvalidToken) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(
+            header::WWW_AUTHENTICATE,
+            format!(
+                "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                error.0.replace('"', "'")
+            ),
+        )],
+        Json(OperationOutcome::error("login", error.0.clone())),
+    )
+        .into_response()
+}
+
+/// 403 for a token whose scopes do not allow `access`
+pub fn insufficient_scope_response(access: &RequiredAccess) -> Response {
+    let scope = format!(
+        "user/{}.{}",
+        access.resource_type.as_deref().unwrap_or("*"),
+        match access.interaction {
+            Interaction::Read => "read",
+            Interaction::Write => "write",
+        }
+    );
+    (
+        StatusCode::FORBIDDEN,
+        [(
+            header::WWW_AUTHENTICATE,
+            format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope),
+        )],
+        Json(OperationOutcome::error(
+            "forbidden",
+            format!(
+                "The token's scopes do not allow this request; it needs {}",
+                scope
+            ),
+        )),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::body::Body;
+    use axum::http::Request;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use tower::ServiceExt;
+
+    const NOW: i64 = 1_760_000_000;
+
+    pub(crate) fn config() -> SmartConfig {
+        SmartConfig {
+            issuer: "https://auth.example.org".to_string(),
+            jwks_url: "https://auth.example.org/jwks".to_string(),
+            audience: Some("https://fhir.example.org/fhir".to_string()),
+            ..SmartConfig::default()
+        }
+    }
+
+    /// A verifier trusting a new P-256 key, and a signer of tokens with it
+    pub(crate) fn key_pair(kid: &str) -> (TokenVerifier, impl Fn(&Value) -> String) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let point = pair.public_key().as_ref().to_vec();
+        let verifier = TokenVerifier::default();
+        verifier
+            .set_jwks(&json!({ "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]}))
+            .unwrap();
+        let kid = kid.to_string();
+        let sign = move |claims: &Value| {
+            let header = json!({ "alg": "ES256", "typ": "JWT", "kid": kid });
+            let message = format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(header.to_string()),
+                URL_SAFE_NO_PAD.encode(claims.to_string())
+            );
+            let signature = pair.sign(&rng, message.as_bytes()).unwrap();
+            format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+        };
+        (verifier, sign)
+    }
+
+    pub(crate) fn claims(scope: &str) -> Value {
+        json!({
+            "iss": "https://auth.example.org",
+            "aud": "https://fhir.example.org/fhir",
+            "exp": NOW + 300,
+            "sub": "practitioner-7",
+            "scope": scope,
+        })
+    }
+
+    #[test]
+    fn test_verify_accepts_signed_tokens() {
+        let (verifier, sign) = key_pair("k1");
+        let token = verifier
+            .verify(&config(), &sign(&claims("openid user/Patient.read")), NOW)
+            .unwrap();
+        assert_eq!(token.subject.as_deref(), Some("practitioner-7"));
+        assert_eq!(Scope::parse("user/Patient.read").unwrap(), token.scopes[0]);
+        assert_eq!(token.scopes.len(), 1);
+        assert!(token.roles.is_empty());
+
+        let mut patient = claims("patient/*.read");
+        patient["roles"] = json!("patient");
+        patient["fhirUser"] = json!("Patient/p1");
+        let token = verifier.verify(&config(), &sign(&patient), NOW).unwrap();
+        assert_eq!(token.roles, ["patient"]);
+        assert_eq!(token.fhir_user.as_deref(), Some("Patient/p1"));
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_tokens() {
+        let (verifier, sign) = key_pair("k1");
+        let (_, other_sign) = key_pair("k1");
+        let mut expired = claims("user/*.read");
+        expired["exp"] = json!(NOW - 120);
+        let mut stranger = claims("user/*.read");
+        stranger["iss"] = json!("https://evil.example.org");
+        let mut elsewhere = claims("user/*.read");
+        elsewhere["aud"] = json!(["https://other.example.org"]);
+        let token = sign(&claims("user/*.read"));
+        // The claims of a token signed for other claims
+        let parts: Vec<&str> = token.split('.').collect();
+        let tampered = format!(
+            "{}.{}.{}",
+            parts[0],
+            URL_SAFE_NO_PAD.encode(claims("user/*.*").to_string()),
+            parts[2]
+        );
+
+        for (token, message) in [
+            (sign(&expired), "expired"),
+            (sign(&stranger), "not issued by"),
+            (sign(&elsewhere), "not meant for"),
+            (other_sign(&claims("user/*.read")), "signature is not valid"),
+            (tampered, "signature is not valid"),
+            ("a.b.c".to_string(), "not base64url"),
+        ] {
+            let error = verifier.verify(&config(), &token, NOW).unwrap_err();
+            assert!(error.0.contains(message), "{}: {}", message, error);
+        }
+
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(claims("user/*.*").to_string())
+        );
+        assert!(verifier.verify(&config(), &unsigned, NOW).is_err());
+        assert!(TokenVerifier::default()
+            .verify(&config(), &token, NOW)
+            .unwrap_err()
+            .0
+            .contains("known key"));
+    }
+
+    #[test]
+    fn test_scope_parsing() {
+        let scope = Scope::parse("patient/Observation.read").unwrap();
+        assert_eq!(scope.context, ScopeContext::Patient);
+        assert_eq!(scope.resource_type.as_deref(), Some("Observation"));
+        assert!(scope.read && !scope.write);
+
+        let scope = Scope::parse("user/*.write").unwrap();
+        assert_eq!(scope.resource_type, None);
+        assert!(!scope.read && scope.write);
+
+        let scope = Scope::parse("system/Patient.cruds").unwrap();
+        assert!(scope.read && scope.write);
+        let scope = Scope::parse("user/Patient.rs").unwrap();
+        assert!(scope.read && !scope.write);
+
+        for other in [
+            "openid",
+            "launch/patient",
+            "offline_access",
+            "user/Patient.x",
+            "user/patient.read",
+            "user/Observation.rs?category=laboratory",
+        ] {
+            assert_eq!(Scope::parse(other), None, "{}", other);
+        }
+    }
+
+    #[test]
+    fn test_required_access() {
+        let access = required_access(&Method::GET, "/fhir/Patient/p1/_history").unwrap();
+        assert_eq!(access.resource_type.as_deref(), Some("Patient"));
+        assert_eq!(access.interaction, Interaction::Read);
+        assert_eq!(access.patient.as_deref(), Some("p1"));
+
+        let access = required_access(&Method::POST, "/fhir/Patient/$match").unwrap();
+        assert_eq!(access.interaction, Interaction::Read);
+        assert_eq!(access.patient, None);
+        let access = required_access(&Method::POST, "/fhir/Patient/p1/$lock").unwrap();
+        assert_eq!(access.interaction, Interaction::Write);
+
+        let access = required_access(&Method::POST, "/fhir").unwrap();
+        assert_eq!(access.resource_type, None);
+        assert_eq!(access.interaction, Interaction::Write);
+        assert_eq!(
+            required_access(&Method::GET, "/fhir/_history")
+                .unwrap()
+                .resource_type,
+            None
+        );
+        assert_eq!(required_access(&Method::GET, "/admin/about"), None);
+        assert_eq!(required_access(&Method::GET, "/fhirx"), None);
+    }
+
+    #[test]
+    fn test_scopes_allow_requests() {
+        let token = |scope: &str, patient: Option<&str>| SmartToken {
+            subject: None,
+            client_id: None,
+            patient: patient.map(String::from),
+            fhir_user: None,
+            scopes: scope.split(' ').filter_map(Scope::parse).collect(),
+            roles: Vec::new(),
+        };
+        let access = |method: Method, path: &str| required_access(&method, path).unwrap();
+
+        let reader = token("user/Patient.read", None);
+        assert!(reader.allows(&access(Method::GET, "/fhir/Patient")));
+        assert!(reader.allows(&access(Method::GET, "/fhir/Patient/p1")));
+        assert!(!reader.allows(&access(Method::PUT, "/fhir/Patient/p1")));
+        assert!(!reader.allows(&access(Method::GET, "/fhir/Observation")));
+        assert!(!reader.allows(&access(Method::GET, "/fhir/_history")));
+
+        let writer = token("user/*.write", None);
+        assert!(writer.allows(&access(Method::POST, "/fhir/Observation")));
+        assert!(writer.allows(&access(Method::POST, "/fhir")));
+        assert!(!writer.allows(&access(Method::GET, "/fhir/Observation")));
+
+        let patient = token("patient/Patient.read patient/Observation.read", Some("p1"));
+        assert!(patient.allows(&access(Method::GET, "/fhir/Patient/p1")));
+        assert!(patient.allows(&access(Method::GET, "/fhir/Patient/p1/_history")));
+        assert!(!patient.allows(&access(Method::GET, "/fhir/Patient/p2")));
+        assert!(!patient.allows(&access(Method::GET, "/fhir/Patient")));
+        assert!(!patient.allows(&access(Method::GET, "/fhir/Observation")));
+        assert!(!token("patient/*.read", None).allows(&access(Method::GET, "/fhir/Patient/p1")));
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert!(SmartConfig::default().validate().is_ok());
+        assert!(config().validate().is_ok());
+        let missing_keys = SmartConfig {
+            jwks_url: String::new(),
+            ..config()
+        };
+        assert!(missing_keys.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_router_enforces_scopes() {
+        let config = ServerConfig {
+            auth: AuthConfig {
+                smart: SmartConfig {
+                    audience: None,
+                    ..config()
+                },
+                ..AuthConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (verifier, sign) = key_pair("k1");
+        let mut state = AppState::new(setup_test_db().await, Arc::new(SharedConfig::new(config)));
+        state.tokens = Arc::new(verifier);
+        let app = crate::routes::router(state);
+        let mut current = claims("user/Patient.read");
+        current["exp"] = json!(chrono::Utc::now().timestamp() + 300);
+        let reader = sign(&current);
+        let request = |method: Method, path: &str, token: Option<&str>| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(path)
+                .header("content-type", "application/fhir+json");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request
+                .body(Body::from(r#"{"resourceType":"Patient"}"#))
+                .unwrap()
+        };
+
+        let anonymous = app
+            .clone()
+            .oneshot(request(Method::GET, "/fhir/Patient", None))
+            .await
+            .unwrap();
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        let read = app
+            .clone()
+            .oneshot(request(Method::GET, "/fhir/Patient", Some(&reader)))
+            .await
+            .unwrap();
+        assert_eq!(read.status(), StatusCode::OK);
+        let write = app
+            .clone()
+            .oneshot(request(Method::POST, "/fhir/Patient", Some(&reader)))
+            .await
+            .unwrap();
+        assert_eq!(write.status(), StatusCode::FORBIDDEN);
+        assert!(write.headers()[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .contains("scope=\"user/Patient.write\""));
+        let expired = app
+            .clone()
+            .oneshot(request(
+                Method::GET,
+                "/fhir/Patient",
+                Some(&sign(&claims("user/*.*"))),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(expired.status(), StatusCode::UNAUTHORIZED);
+        let metadata = app
+            .oneshot(request(Method::GET, "/fhir/metadata", None))
+            .await
+            .unwrap();
+        assert_eq!(metadata.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_discovery_documents() {
+        let smart = SmartConfig {
+            authorization_endpoint: Some("https://auth.example.org/authorize".to_string()),
+            token_endpoint: Some("https://auth.example.org/token".to_string()),
+            ..config()
+        };
+        let document = smart_configuration(&smart);
+        assert_eq!(document["issuer"], "https://auth.example.org");
+        assert_eq!(document["token_endpoint"], "https://auth.example.org/token");
+        assert!(document["capabilities"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("permission-v2")));
+
+        let auth = AuthConfig {
+            smart,
+            ..AuthConfig::default()
+        };
+        let security = rest_security(&auth, true).unwrap();
+        assert_eq!(security["service"][0]["coding"][0]["code"], "SMART-on-FHIR");
+        assert_eq!(
+            security["extension"][0]["extension"],
+            json!([
+                { "url": "authorize", "valueUri": "https://auth.example.org/authorize" },
+                { "url": "token", "valueUri": "https://auth.example.org/token" },
+            ])
+        );
+
+        assert_eq!(rest_security(&AuthConfig::default(), false), None);
+        let keys = AuthConfig {
+            required: true,
+            ..AuthConfig::default()
+        };
+        assert!(rest_security(&keys, false)
+            .unwrap()
+            .get("service")
+            .is_none());
+
+        let plain = SmartConfig {
+            token_endpoint: Some("http://auth.example.org/token".to_string()),
+            ..config()
+        };
+        assert!(plain.validate().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::archive::Archiver;
+use crate::config::SharedConfig;
+use crate::db::Database;
+use crate::events::ChangeEvents;
+use crate::features::FeatureFlags;
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::search::SearchParamRegistry;
+use crate::slo::LatencyTracker;
+use crate::smart::TokenVerifier;
+use crate::throttle::WriteThrottle;
+use crate::transform::ResponsePipeline;
+use crate::validation::ValidationHooks;
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+/// Shared application state handed to the router.
+///
+/// Handlers extract only the part they need (e.g. `State<Arc<Database>>`)
+/// via `FromRef`, so adding new shared components does not ripple through
+/// every handler signature.
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Arc<Database>,
+    pub config: Arc<SharedConfig>,
+    pub search_params: Arc<SearchParamRegistry>,
+    pub metrics: Arc<Metrics>,
+    pub validation: Arc<ValidationHooks>,
+    pub transforms: Arc<ResponsePipeline>,
+    pub throttle: Arc<WriteThrottle>,
+    pub jobs: Arc<Jobs>,
+    pub archiver: Arc<Archiver>,
+    pub features: Arc<FeatureFlags>,
+    pub latency: Arc<LatencyTracker>,
+    pub events: Arc<ChangeEvents>,
+    pub tokens: Arc<TokenVerifier>,
+}
+
+impl AppState {
+    pub fn new(db: Database, config: Arc<SharedConfig>) -> Self {
+        Self {
+            jobs: Arc::new(Jobs::new(db.clock())),
+            db: Arc::new(db),
+            config,
+            search_params: Arc::new(SearchParamRegistry::with_builtins()),
+            metrics: Arc::new(Metrics::default()),
+            validation: Arc::new(ValidationHooks::default()),
+            transforms: Arc::new(ResponsePipeline::default()),
+            throttle: Arc::new(WriteThrottle::default()),
+            archiver: Arc::new(Archiver::default()),
+            features: Arc::new(FeatureFlags::from_env()),
+            latency: Arc::new(LatencyTracker::default()),
+            events: Arc::new(ChangeEvents::default()),
+            tokens: Arc::new(TokenVerifier::default()),
+        }
+    }
+}
+
+impl FromRef<AppState> for Arc<Database> {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<SharedConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<SearchParamRegistry> {
+    fn from_ref(state: &AppState) -> Self {
+        state.search_params.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Metrics> {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ValidationHooks> {
+    fn from_ref(state: &AppState) -> Self {
+        state.validation.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ResponsePipeline> {
+    fn from_ref(state: &AppState) -> Self {
+        state.transforms.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<WriteThrottle> {
+    fn from_ref(state: &AppState) -> Self {
+        state.throttle.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Jobs> {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Archiver> {
+    fn from_ref(state: &AppState) -> Self {
+        state.archiver.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<FeatureFlags> {
+    fn from_ref(state: &AppState) -> Self {
+        state.features.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<LatencyTracker> {
+    fn from_ref(state: &AppState) -> Self {
+        state.latency.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ChangeEvents> {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<TokenVerifier> {
+    fn from_ref(state: &AppState) -> Self {
+        state.tokens.clone()
+    }
+}
@@ -0,0 +1,193 @@
+//! Encrypted archives of one tenant's data, for customer off-boarding.
+//!
+//! `POST /admin/tenants/:organization/export` packs what
+//! [`Database::tenant_data`](crate::db::Database::tenant_data) reads into a
+//! gzipped tar of NDJSON files:
+//!
+//! - `manifest.json`: the Organization, when the export was made and how
+//!   many lines each file has
+//! - `resources.ndjson`: the tenant's live resources
+//! - `history.ndjson`: every stored version of its Patients
+//! - `audit.ndjson`: the Provenance of the writes to any of them
+//!
+//! and encrypts it with AES-256-GCM under a key derived from the request's
+//! passphrase with PBKDF2-HMAC-SHA256. The sealed archive is
+//! [`MAGIC`], the iteration count (4 bytes, big-endian), a 16-byte salt and
+//! the 12-byte nonce, followed by the ciphertext and its tag; the header
+//! is authenticated with it. `fhir-server open-export` ([`open`]) decrypts
+//! it back to the `.tar.gz`. Nothing of the export is kept on the server.
+
+use crate::db::tenant::TenantData;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use std::num::NonZeroU32;
+use uuid::Uuid;
+
+/// First bytes of a sealed archive
+pub const MAGIC: &[u8; 8] = b"FHIRTEX1";
+
+/// Shortest passphrase accepted for an export
+pub const MIN_PASSPHRASE_CHARS: usize = 12;
+
+/// PBKDF2 iterations of new archives
+const ITERATIONS: u32 = 600_000;
+
+const SALT_LEN: usize = 16;
+
+const HEADER_LEN: usize = MAGIC.len() + 4 + SALT_LEN + NONCE_LEN;
+
+/// The `.tar.gz` of `data`, exported from Organization `organization` at
+/// `exported_at`
+pub fn archive(
+    organization: Uuid,
+    exported_at: DateTime<Utc>,
+    data: &TenantData,
+) -> Result<Vec<u8>> {
+    let manifest = json!({
+        "organization": format!("Organization/{}", organization),
+        "exportedAt": exported_at,
+        "server": env!("CARGO_PKG_VERSION"),
+        "files": {
+            "resources.ndjson": data.resources.len(),
+            "history.ndjson": data.history.len(),
+            "audit.ndjson": data.audit.len(),
+        },
+    });
+    let files = [
+        ("manifest.json", serde_json::to_vec_pretty(&manifest)?),
+        ("resources.ndjson", ndjson(&data.resources)?),
+        ("history.ndjson", ndjson(&data.history)?),
+        ("audit.ndjson", ndjson(&data.audit)?),
+    ];
+
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, bytes) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(exported_at.timestamp().max(0) as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, path, bytes.as_slice())?;
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+fn ndjson(resources: &[Value]) -> Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for resource in resources {
+        serde_json::to_writer(&mut lines, resource)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+/// `archive` encrypted under `passphrase`
+pub fn seal(passphrase: &str, archive: Vec<u8>) -> Result<Vec<u8>> {
+    seal_with(passphrase, archive, ITERATIONS)
+}
+
+fn seal_with(passphrase: &str, mut archive: Vec<u8>, iterations: u32) -> Result<Vec<u8>> {
+    let random = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    random
+        .fill(&mut salt)
+        .and_then(|_| random.fill(&mut nonce))
+        .map_err(|_| anyhow::anyhow!("No random bytes for the archive key"))?;
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + archive.len() + AES_256_GCM.tag_len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&iterations.to_be_bytes());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    key(passphrase, &salt, iterations)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&sealed[..]),
+            &mut archive,
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the archive"))?;
+    sealed.extend_from_slice(&archive);
+    Ok(sealed)
+}
+
+/// The `.tar.gz` inside a sealed archive
+pub fn open(passphrase: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < HEADER_LEN + AES_256_GCM.tag_len() || !sealed.starts_with(MAGIC) {
+        anyhow::bail!("Not a tenant export archive");
+    }
+    let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+    let iterations = u32::from_be_bytes(header[8..12].try_into()?);
+    let salt = &header[12..12 + SALT_LEN];
+    let nonce = Nonce::try_assume_unique_for_key(&header[12 + SALT_LEN..])
+        .map_err(|_| anyhow::anyhow!("Not a tenant export archive"))?;
+
+    let mut archive = ciphertext.to_vec();
+    let len = key(passphrase, salt, iterations)?
+        .open_in_place(nonce, Aad::from(header), &mut archive)
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the archive was altered"))?
+        .len();
+    archive.truncate(len);
+    Ok(archive)
+}
+
+fn key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations).context("The archive has no iteration count")?;
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key =
+        UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow::anyhow!("Invalid archive key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_sealed_archive_opens_with_its_passphrase() {
+        let data = TenantData {
+            resources: vec![json!({ "resourceType": "Organization", "id": "1" })],
+            history: vec![],
+            audit: vec![json!({ "resourceType": "Provenance" })],
+        };
+        let archive = archive(Uuid::nil(), Utc::now(), &data).unwrap();
+        let sealed = seal_with("correct horse battery", archive.clone(), 1_000).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+
+        assert_eq!(open("correct horse battery", &sealed).unwrap(), archive);
+        assert!(open("wrong horse battery", &sealed).is_err());
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(open("correct horse battery", &altered).is_err());
+        assert!(open("correct horse battery", b"FHIRTEX1").is_err());
+
+        let mut files = tar::Archive::new(GzDecoder::new(archive.as_slice()));
+        let mut contents = std::collections::BTreeMap::new();
+        for entry in files.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut text = String::new();
+            entry.read_to_string(&mut text).unwrap();
+            contents.insert(path, text);
+        }
+        assert_eq!(contents["history.ndjson"], "");
+        assert_eq!(contents["audit.ndjson"].lines().count(), 1);
+        let manifest: Value = serde_json::from_str(&contents["manifest.json"]).unwrap();
+        assert_eq!(manifest["files"]["resources.ndjson"], 1);
+    }
+}
@@ -0,0 +1,352 @@
+//! Per-resource-type write rate limits.
+//!
+//! `[write_limits.<Type>]` entries cap creates, updates, patches and deletes
+//! under `/fhir/<Type>` (and in Bundle entries) with a token bucket: `burst`
+//! writes may be made at once, refilled at `per_second`. Clients without an
+//! `Authorization` header share one bucket per type; each credential gets its
+//! own. Writes over the limit are answered with `429 Too Many Requests` and a
+//! `Retry-After`.
+//!
+//! The same buckets hold the `requests_per_minute` of API keys, which count
+//! every request made with the key; see [`crate::api_keys`].
+
+use crate::config::{ServerConfig, SharedConfig};
+use crate::models::OperationOutcome;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Once this many buckets are tracked, full ones are dropped
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// Resource type of the buckets of API keys, which count requests of any type
+const ALL_REQUESTS: &str = "*";
+
+/// One `[write_limits.<Type>]` entry of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WriteLimit {
+    /// Sustained writes per second
+    pub per_second: f64,
+    /// Writes allowed in a burst
+    pub burst: u32,
+    /// Only limit clients that send no `Authorization` header
+    pub anonymous_only: bool,
+}
+
+impl Default for WriteLimit {
+    fn default() -> Self {
+        Self {
+            per_second: 10.0,
+            burst: 20,
+            anonymous_only: false,
+        }
+    }
+}
+
+/// Who a bucket belongs to; credentials are kept only as a hash
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Anonymous,
+    Credential(u64),
+    ApiKey(String),
+}
+
+impl ClientKey {
+    fn of(headers: &HeaderMap) -> Self {
+        match headers.get(header::AUTHORIZATION) {
+            Some(value) => {
+                let mut hasher = DefaultHasher::new();
+                value.as_bytes().hash(&mut hasher);
+                ClientKey::Credential(hasher.finish())
+            }
+            None => ClientKey::Anonymous,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token buckets for every (resource type, client) pair seen so far
+#[derive(Debug, Default)]
+pub struct WriteThrottle {
+    buckets: Mutex<HashMap<(String, ClientKey), Bucket>>,
+}
+
+impl WriteThrottle {
+    /// Take a token for a write; `Err` holds the seconds until one is available
+    fn acquire(
+        &self,
+        resource_type: &str,
+        client: ClientKey,
+        limit: &WriteLimit,
+        now: Instant,
+    ) -> Result<(), u64> {
+        let burst = f64::from(limit.burst);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * limit.per_second
+                    < burst
+            });
+        }
+
+        let bucket = buckets
+            .entry((resource_type.to_string(), client))
+            .or_insert(Bucket {
+                tokens: burst,
+                refilled: now,
+            });
+        // Limits can shrink on reload, so the bucket is capped at the current burst
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / limit.per_second).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// Resource type addressed by a write, if the request is one
+fn written_type<'a>(method: &Method, path: &'a str) -> Option<&'a str> {
+    if !matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) {
+        return None;
+    }
+    let mut segments = path.strip_prefix("/fhir/")?.split('/');
+    let resource_type = segments.next()?;
+    // Operations such as `$cohort` only read
+    if segments.any(|segment| segment.starts_with('$')) {
+        return None;
+    }
+    Some(resource_type)
+}
+
+impl WriteThrottle {
+    /// Take a token for a write of `resource_type` by the client sending
+    /// `headers`, if a limit applies to it
+    pub(crate) fn admit(
+        &self,
+        config: &ServerConfig,
+        resource_type: &str,
+        headers: &HeaderMap,
+    ) -> Result<(), u64> {
+        let Some(limit) = config.write_limits.get(resource_type) else {
+            return Ok(());
+        };
+        let client = ClientKey::of(headers);
+        if limit.anonymous_only && client != ClientKey::Anonymous {
+            return Ok(());
+        }
+        self.acquire(resource_type, client, limit, Instant::now())
+    }
+}
+
+impl WriteThrottle {
+    /// Take a token for a request made with the API key `name`, which may
+    /// make `per_minute` requests a minute and as many at once
+    pub(crate) fn admit_api_key(&self, name: &str, per_minute: u32) -> Result<(), u64> {
+        let limit = WriteLimit {
+            per_second: f64::from(per_minute) / 60.0,
+            burst: per_minute,
+            anonymous_only: false,
+        };
+        self.acquire(
+            ALL_REQUESTS,
+            ClientKey::ApiKey(name.to_string()),
+            &limit,
+            Instant::now(),
+        )
+    }
+}
+
+/// Outcome for a write over the limit of its resource type
+pub(crate) fn throttled_outcome(resource_type: &str, retry_after: u64) -> OperationOutcome {
+    tracing::info!(resource_type, retry_after, "Write rate limit exceeded");
+    OperationOutcome::error(
+        "throttled",
+        format!(
+            "Write rate limit for {} exceeded; retry after {} s",
+            resource_type, retry_after
+        ),
+    )
+}
+
+/// Reject writes that exceed the configured limit of their resource type
+pub async fn limit_writes(
+    State(throttle): State<Arc<WriteThrottle>>,
+    State(config): State<Arc<SharedConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(resource_type) = written_type(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    match throttle.admit(&config.get(), resource_type, request.headers()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            Json(throttled_outcome(resource_type, retry_after)),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::setup_test_db;
+    use crate::state::AppState;
+    use axum::body::Body;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn limit(per_second: f64, burst: u32) -> WriteLimit {
+        WriteLimit {
+            per_second,
+            burst,
+            anonymous_only: false,
+        }
+    }
+
+    #[test]
+    fn test_bucket_allows_burst_then_refills() {
+        let throttle = WriteThrottle::default();
+        let limit = limit(2.0, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(throttle
+                .acquire("Patient", ClientKey::Anonymous, &limit, start)
+                .is_ok());
+        }
+        assert_eq!(
+            throttle.acquire("Patient", ClientKey::Anonymous, &limit, start),
+            Err(1)
+        );
+        // Other types and clients have their own buckets
+        assert!(throttle
+            .acquire("Observation", ClientKey::Anonymous, &limit, start)
+            .is_ok());
+        assert!(throttle
+            .acquire("Patient", ClientKey::Credential(1), &limit, start)
+            .is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(throttle
+            .acquire("Patient", ClientKey::Anonymous, &limit, later)
+            .is_ok());
+        assert!(throttle
+            .acquire("Patient", ClientKey::Anonymous, &limit, later)
+            .is_err());
+    }
+
+    #[test]
+    fn test_retry_after_reflects_refill_rate() {
+        let throttle = WriteThrottle::default();
+        let limit = limit(0.1, 1);
+        let now = Instant::now();
+
+        assert!(throttle
+            .acquire("Patient", ClientKey::Anonymous, &limit, now)
+            .is_ok());
+        assert_eq!(
+            throttle.acquire("Patient", ClientKey::Anonymous, &limit, now),
+            Err(10)
+        );
+    }
+
+    #[test]
+    fn test_api_key_buckets() {
+        let throttle = WriteThrottle::default();
+        assert!(throttle.admit_api_key("etl", 2).is_ok());
+        assert!(throttle.admit_api_key("etl", 2).is_ok());
+        assert_eq!(throttle.admit_api_key("etl", 2), Err(30));
+        assert!(throttle.admit_api_key("reporting", 2).is_ok());
+    }
+
+    #[test]
+    fn test_written_type() {
+        assert_eq!(
+            written_type(&Method::POST, "/fhir/Patient"),
+            Some("Patient")
+        );
+        assert_eq!(
+            written_type(&Method::DELETE, "/fhir/Encounter/1"),
+            Some("Encounter")
+        );
+        assert_eq!(written_type(&Method::GET, "/fhir/Patient"), None);
+        assert_eq!(written_type(&Method::POST, "/admin/requests/1"), None);
+        assert_eq!(written_type(&Method::POST, "/fhir/Patient/$cohort"), None);
+    }
+
+    #[tokio::test]
+    async fn test_router_throttles_anonymous_writes() {
+        let mut config = ServerConfig::default();
+        config.write_limits.insert(
+            "Patient".to_string(),
+            WriteLimit {
+                per_second: 0.01,
+                burst: 1,
+                anonymous_only: true,
+            },
+        );
+        let app = crate::routes::router(AppState::new(
+            setup_test_db().await,
+            Arc::new(SharedConfig::new(config)),
+        ));
+        let create = |authorization: Option<&str>| {
+            let mut request =
+                Request::post("/fhir/Patient").header("content-type", "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request
+                .body(Body::from(
+                    r#"{"resourceType":"Patient","gender":"unknown"}"#,
+                ))
+                .unwrap()
+        };
+
+        let first = app.clone().oneshot(create(None)).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let second = app.clone().oneshot(create(None)).await.unwrap();
+        assert_eq!(second.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(second.headers()[header::RETRY_AFTER], "100");
+
+        let authenticated = app
+            .clone()
+            .oneshot(create(Some("Bearer token")))
+            .await
+            .unwrap();
+        assert_eq!(authenticated.status(), StatusCode::CREATED);
+        let read = app
+            .oneshot(Request::get("/fhir/metadata").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(read.status(), StatusCode::OK);
+    }
+}
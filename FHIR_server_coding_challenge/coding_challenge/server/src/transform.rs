@@ -0,0 +1,378 @@
+//! Post-read response transformation pipeline.
+//!
+//! Resources returned by read, search and history pass through the stages
+//! listed under `[[response_transforms]]`, in order. Redaction,
+//! de-identification, narrative injection and deployer scripts are all
+//! stages, so they compose (e.g. de-identify, then generate a narrative from
+//! what is left) instead of each being special-cased in the handlers.
+
+use crate::sandbox::{default_fuel, SandboxModule};
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// A `[[response_transforms]]` entry of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "stage", rename_all = "kebab-case")]
+pub enum TransformConfig {
+    /// Remove elements by dot path, e.g. `telecom` or `address.line`
+    Redact { paths: Vec<String> },
+    /// Strip direct identifiers, keeping only coarse demographics
+    Deidentify,
+    /// Add a generated `text` narrative to resources that have none
+    Narrative,
+    /// WebAssembly module exporting `fhir_transform(ptr, len) -> i64`, which
+    /// receives the resource JSON and returns the replacement JSON (0 keeps it)
+    Script {
+        name: String,
+        module: PathBuf,
+        #[serde(default = "default_fuel")]
+        fuel: u64,
+    },
+}
+
+/// One step of the pipeline
+pub trait TransformStage: Send + Sync {
+    fn name(&self) -> &str;
+    fn apply(&self, resource: &mut Value) -> Result<()>;
+}
+
+pub fn build_stage(config: &TransformConfig) -> Result<Box<dyn TransformStage>> {
+    Ok(match config {
+        TransformConfig::Redact { paths } => Box::new(Redact::new(paths)),
+        TransformConfig::Deidentify => Box::new(Deidentify),
+        TransformConfig::Narrative => Box::new(Narrative),
+        TransformConfig::Script { name, module, fuel } => {
+            let bytes = std::fs::read(module).with_context(|| {
+                format!(
+                    "Failed to read transform script {} from {}",
+                    name,
+                    module.display()
+                )
+            })?;
+            Box::new(Script::from_bytes(name, &bytes, *fuel)?)
+        }
+    })
+}
+
+/// Removes the elements at the configured paths
+pub struct Redact {
+    paths: Vec<Vec<String>>,
+}
+
+impl Redact {
+    pub fn new(paths: &[String]) -> Self {
+        Self {
+            paths: paths
+                .iter()
+                .map(|p| p.split('.').map(str::to_string).collect())
+                .collect(),
+        }
+    }
+}
+
+/// Remove `path` below `value`, descending into every element of arrays
+fn remove_path(value: &mut Value, path: &[String]) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| remove_path(item, path)),
+        Value::Object(map) => match path {
+            [] => {}
+            [last] => {
+                map.remove(last);
+            }
+            [first, rest @ ..] => {
+                if let Some(child) = map.get_mut(first) {
+                    remove_path(child, rest);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+impl TransformStage for Redact {
+    fn name(&self) -> &str {
+        "redact"
+    }
+
+    fn apply(&self, resource: &mut Value) -> Result<()> {
+        for path in &self.paths {
+            remove_path(resource, path);
+        }
+        Ok(())
+    }
+}
+
+/// Elements that directly identify a patient and are dropped entirely
+const DIRECT_IDENTIFIERS: &[&str] = &["identifier", "name", "telecom", "photo", "contact", "text"];
+
+/// Address parts coarse enough to keep
+const KEPT_ADDRESS_PARTS: &[&str] = &["use", "type", "state", "country"];
+
+/// Drops direct identifiers, reduces addresses to state/country and
+/// birth dates to the year
+pub struct Deidentify;
+
+impl TransformStage for Deidentify {
+    fn name(&self) -> &str {
+        "deidentify"
+    }
+
+    fn apply(&self, resource: &mut Value) -> Result<()> {
+        let Value::Object(map) = resource else {
+            return Ok(());
+        };
+
+        for element in DIRECT_IDENTIFIERS {
+            map.remove(*element);
+        }
+        if let Some(Value::Array(addresses)) = map.get_mut("address") {
+            for address in addresses.iter_mut() {
+                if let Value::Object(parts) = address {
+                    parts.retain(|k, _| KEPT_ADDRESS_PARTS.contains(&k.as_str()));
+                }
+            }
+        }
+        if let Some(Value::String(birth_date)) = map.get_mut("birthDate") {
+            birth_date.truncate(4);
+        }
+        Ok(())
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Generates `text` for Patients from name, gender and birth date
+pub struct Narrative;
+
+impl TransformStage for Narrative {
+    fn name(&self) -> &str {
+        "narrative"
+    }
+
+    fn apply(&self, resource: &mut Value) -> Result<()> {
+        if resource.get("text").is_some() || resource["resourceType"] != "Patient" {
+            return Ok(());
+        }
+
+        let name = resource
+            .pointer("/name/0")
+            .map(|name| {
+                let given = name["given"]
+                    .as_array()
+                    .map(|g| g.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+                    .unwrap_or_default();
+                let family = name["family"].as_str();
+                given
+                    .into_iter()
+                    .chain(family)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| "Unnamed patient".to_string());
+
+        let mut parts = vec![format!("<b>{}</b>", escape_html(&name))];
+        if let Some(gender) = resource["gender"].as_str() {
+            parts.push(escape_html(gender));
+        }
+        if let Some(birth_date) = resource["birthDate"].as_str() {
+            parts.push(format!("born {}", escape_html(birth_date)));
+        }
+
+        resource["text"] = json!({
+            "status": "generated",
+            "div": format!(
+                "<div xmlns=\"http://www.w3.org/1999/xhtml\">{}</div>",
+                parts.join(", ")
+            ),
+        });
+        Ok(())
+    }
+}
+
+/// Deployer-supplied transformation running in the sandbox
+pub struct Script {
+    name: String,
+    module: SandboxModule,
+}
+
+impl Script {
+    pub fn from_bytes(name: &str, bytes: &[u8], fuel: u64) -> Result<Self> {
+        let module = SandboxModule::compile(bytes, fuel)
+            .with_context(|| format!("Failed to compile transform script {}", name))?;
+        Ok(Self {
+            name: name.to_string(),
+            module,
+        })
+    }
+}
+
+impl TransformStage for Script {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&self, resource: &mut Value) -> Result<()> {
+        let input = serde_json::to_vec(resource)?;
+        if let Some(output) = self.module.call("fhir_transform", &input)? {
+            *resource = serde_json::from_slice(&output)
+                .context("fhir_transform returned malformed JSON")?;
+        }
+        Ok(())
+    }
+}
+
+/// The stages currently in effect; swapped as a whole on config reload
+#[derive(Default)]
+pub struct ResponsePipeline {
+    stages: RwLock<Arc<Vec<Box<dyn TransformStage>>>>,
+}
+
+impl ResponsePipeline {
+    /// Build `configs` and replace the active stages. If any stage fails to
+    /// build the previous pipeline stays in effect.
+    pub fn reload(&self, configs: &[TransformConfig]) -> Result<()> {
+        let stages = configs
+            .iter()
+            .map(build_stage)
+            .collect::<Result<Vec<_>>>()?;
+        self.set(stages);
+        Ok(())
+    }
+
+    pub fn set(&self, stages: Vec<Box<dyn TransformStage>>) {
+        *self.stages.write().unwrap() = Arc::new(stages);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.read().unwrap().is_empty()
+    }
+
+    /// Run every stage over each resource. A failing stage fails the whole
+    /// response, so a broken redaction never leaks the untransformed data.
+    pub async fn apply(&self, resources: Vec<Value>) -> Result<Vec<Value>> {
+        let stages = self.stages.read().unwrap().clone();
+        if stages.is_empty() {
+            return Ok(resources);
+        }
+
+        // Script stages are CPU-bound (bounded by fuel), keep them off the async workers
+        tokio::task::spawn_blocking(move || {
+            resources
+                .into_iter()
+                .map(|mut resource| {
+                    for stage in stages.iter() {
+                        stage
+                            .apply(&mut resource)
+                            .with_context(|| format!("Transform {} failed", stage.name()))?;
+                    }
+                    Ok(resource)
+                })
+                .collect()
+        })
+        .await?
+    }
+
+    /// Typed wrapper around [`apply`](Self::apply) for model responses
+    pub async fn apply_resources<T>(&self, resources: Vec<T>) -> Result<Vec<T>>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        if self.is_empty() {
+            return Ok(resources);
+        }
+
+        let values = resources
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.apply(values)
+            .await?
+            .into_iter()
+            .map(|v| serde_json::from_value(v).context("Transform produced an invalid resource"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Value {
+        json!({
+            "resourceType": "Patient",
+            "identifier": [{ "system": "urn:mrn", "value": "42" }],
+            "name": [{ "family": "Gauß", "given": ["Carl", "Friedrich"] }],
+            "telecom": [{ "system": "phone", "value": "555" }],
+            "address": [{ "line": ["Hauptstraße 1"], "city": "Braunschweig", "postalCode": "38100", "state": "NI", "country": "DE" }],
+            "gender": "male",
+            "birthDate": "1777-04-30"
+        })
+    }
+
+    #[test]
+    fn test_redact_nested_paths() {
+        let mut resource = sample();
+        Redact::new(&["telecom".to_string(), "address.line".to_string()])
+            .apply(&mut resource)
+            .unwrap();
+
+        assert!(resource.get("telecom").is_none());
+        assert!(resource["address"][0].get("line").is_none());
+        assert_eq!(resource["address"][0]["city"], "Braunschweig");
+    }
+
+    #[test]
+    fn test_deidentify_keeps_coarse_demographics() {
+        let mut resource = sample();
+        Deidentify.apply(&mut resource).unwrap();
+
+        assert!(resource.get("identifier").is_none());
+        assert!(resource.get("name").is_none());
+        assert_eq!(
+            resource["address"][0],
+            json!({ "state": "NI", "country": "DE" })
+        );
+        assert_eq!(resource["birthDate"], "1777");
+        assert_eq!(resource["gender"], "male");
+    }
+
+    #[tokio::test]
+    async fn test_stages_compose_in_order() {
+        let pipeline = ResponsePipeline::default();
+        pipeline
+            .reload(&[TransformConfig::Deidentify, TransformConfig::Narrative])
+            .unwrap();
+
+        let out = pipeline.apply(vec![sample()]).await.unwrap();
+        let div = out[0]["text"]["div"].as_str().unwrap();
+
+        // The narrative is generated from the de-identified resource
+        assert!(div.contains("Unnamed patient"));
+        assert!(div.contains("born 1777<"));
+        assert!(!div.contains("Gauß"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_script_fails_response() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "fhir_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "fhir_transform") (param i32 i32) (result i64) unreachable))"#;
+        let pipeline = ResponsePipeline::default();
+        pipeline.set(vec![Box::new(
+            Script::from_bytes("broken", wat.as_bytes(), default_fuel()).unwrap(),
+        )]);
+
+        assert!(pipeline.apply(vec![sample()]).await.is_err());
+    }
+}
@@ -0,0 +1,615 @@
+//! Site-specific validation rules supplied as WebAssembly modules.
+//!
+//! Each hook listed under `[[validation_hooks]]` is run in the
+//! [`sandbox`](crate::sandbox) for every create/update. Besides the sandbox
+//! ABI a module exports `fhir_validate(ptr: i32, len: i32) -> i64`, which
+//! receives the UTF-8 JSON `{"operation": "create"|"update", "resource": {...}}`
+//! and returns a JSON array of OperationOutcome issues, or 0 when there is
+//! nothing to report.
+//!
+//! Independently of the hooks, every `modifierExtension` in a resource must
+//! be listed in `understood_modifier_extensions`. A modifier changes the
+//! meaning of the element it sits on, so FHIR requires a server that does
+//! not know it to reject the resource rather than store it as if it were
+//! absent.
+//!
+//! Canonical references to conformance resources, such as `meta.profile`
+//! or a ValueSet's `compose.include.valueSet`, must name a stored
+//! StructureDefinition, ValueSet or SearchParameter (see
+//! [`conformance`](crate::db::conformance)). Definitions of the
+//! specification itself, under `http://hl7.org/fhir/`, are not stored and
+//! are not checked.
+//!
+//! Profiles of the loaded [profile packs](crate::profiles) are checked for
+//! every resource claiming them in `meta.profile`, and need not be stored.
+//! With `profile_validation` on, claimed profiles that are stored
+//! StructureDefinitions are checked too, as are the base definitions of
+//! loaded packs and all the constraints [`Profile::check_constraints`]
+//! covers.
+
+use crate::models::OperationOutcomeIssue;
+use crate::profiles::Profile;
+use crate::sandbox::{default_fuel, SandboxModule};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// A `[[validation_hooks]]` entry of the server config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ValidationHookConfig {
+    pub name: String,
+    /// Path to the `.wasm` or `.wat` module
+    pub module: PathBuf,
+    /// Instructions a single invocation may execute before it is aborted
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+}
+
+/// A compiled validation module
+pub struct ValidationHook {
+    name: String,
+    module: SandboxModule,
+}
+
+impl ValidationHook {
+    pub fn load(config: &ValidationHookConfig) -> Result<Self> {
+        let bytes = std::fs::read(&config.module).with_context(|| {
+            format!(
+                "Failed to read validation hook {} from {}",
+                config.name,
+                config.module.display()
+            )
+        })?;
+        Self::from_bytes(&config.name, &bytes, config.fuel)
+    }
+
+    pub fn from_bytes(name: &str, bytes: &[u8], fuel: u64) -> Result<Self> {
+        let module = SandboxModule::compile(bytes, fuel)
+            .with_context(|| format!("Failed to compile validation hook {}", name))?;
+        Ok(Self {
+            name: name.to_string(),
+            module,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the hook against `input`. A hook that traps, runs out of fuel or
+    /// returns malformed issues yields a single `exception` error, so broken
+    /// rules block writes instead of being silently skipped.
+    pub fn run(&self, input: &[u8]) -> Vec<OperationOutcomeIssue> {
+        match self.call(input) {
+            Ok(issues) => issues,
+            Err(e) => vec![exception(format!(
+                "Validation hook {} failed: {:#}",
+                self.name, e
+            ))],
+        }
+    }
+
+    fn call(&self, input: &[u8]) -> Result<Vec<OperationOutcomeIssue>> {
+        match self.module.call("fhir_validate", input)? {
+            Some(output) => {
+                serde_json::from_slice(&output).context("fhir_validate returned malformed issues")
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// The hooks currently in effect; swapped as a whole on config reload
+#[derive(Default)]
+pub struct ValidationHooks {
+    hooks: RwLock<Arc<Vec<ValidationHook>>>,
+    understood_modifiers: RwLock<Arc<Vec<String>>>,
+    profiles: RwLock<Arc<Vec<Profile>>>,
+    profile_validation: AtomicBool,
+}
+
+impl ValidationHooks {
+    /// Compile `configs` and replace the active hooks. If any module fails to
+    /// load the previous hooks stay in effect.
+    pub fn reload(&self, configs: &[ValidationHookConfig]) -> Result<()> {
+        let hooks = configs
+            .iter()
+            .map(ValidationHook::load)
+            .collect::<Result<Vec<_>>>()?;
+        self.set(hooks);
+        Ok(())
+    }
+
+    pub fn set(&self, hooks: Vec<ValidationHook>) {
+        *self.hooks.write().unwrap() = Arc::new(hooks);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.read().unwrap().is_empty()
+    }
+
+    /// Replace the modifier extension URLs that writes may carry
+    pub fn set_understood_modifiers(&self, urls: Vec<String>) {
+        *self.understood_modifiers.write().unwrap() = Arc::new(urls);
+    }
+
+    /// An `extension` error for every modifier extension in `resource`
+    /// that is not understood
+    pub fn unknown_modifiers(&self, resource: &Value) -> Vec<OperationOutcomeIssue> {
+        let understood = self.understood_modifiers.read().unwrap().clone();
+        let path = resource
+            .get("resourceType")
+            .and_then(Value::as_str)
+            .unwrap_or("Resource");
+        let mut issues = Vec::new();
+        find_unknown_modifiers(resource, path, &understood, &mut issues);
+        issues
+    }
+
+    /// Replace the profiles of the loaded profile packs
+    pub fn set_profiles(&self, profiles: Vec<Profile>) {
+        *self.profiles.write().unwrap() = Arc::new(profiles);
+    }
+
+    /// Add the profiles of a pack imported at runtime, replacing loaded
+    /// profiles of the same URL
+    pub fn add_profiles(&self, profiles: Vec<Profile>) {
+        let mut current = self.profiles.write().unwrap();
+        let mut merged: Vec<Profile> = current
+            .iter()
+            .filter(|p| !profiles.iter().any(|added| added.url == p.url))
+            .cloned()
+            .collect();
+        merged.extend(profiles);
+        *current = Arc::new(merged);
+    }
+
+    /// Whether `canonical` (`url` or `url|version`) names a loaded profile
+    pub fn has_profile(&self, canonical: &str) -> bool {
+        let url = canonical.split('|').next().unwrap_or_default();
+        self.profiles.read().unwrap().iter().any(|p| p.url == url)
+    }
+
+    /// Turn the full profile validation of `profile_validation` on or off
+    pub fn set_profile_validation(&self, enabled: bool) {
+        self.profile_validation.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn profile_validation(&self) -> bool {
+        self.profile_validation.load(Ordering::Relaxed)
+    }
+
+    /// The issues of `resource` against each loaded profile it claims and
+    /// the `stored` profiles it claims. With profile validation on, base
+    /// definitions of its type apply as well.
+    pub fn profile_issues(
+        &self,
+        resource: &Value,
+        stored: &[Profile],
+    ) -> Vec<OperationOutcomeIssue> {
+        let profiles = self.profiles.read().unwrap().clone();
+        let full = self.profile_validation();
+        let resource_type = resource
+            .get("resourceType")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let claimed = claimed_profiles(resource);
+        let mut issues = Vec::new();
+        for profile in profiles
+            .iter()
+            .filter(|profile| !profile.base && claimed.contains(&profile.url.as_str()))
+            .chain(stored)
+        {
+            if full && profile.resource_type != resource_type {
+                issues.push(OperationOutcomeIssue {
+                    severity: "error".to_string(),
+                    code: "invalid".to_string(),
+                    details: None,
+                    diagnostics: Some(format!(
+                        "Profile {} constrains {}, not {}",
+                        profile.url, profile.resource_type, resource_type
+                    )),
+                    location: Some(vec![format!("{}.meta.profile", resource_type)]),
+                    expression: None,
+                });
+                continue;
+            }
+            issues.extend(profile.check(resource));
+            if full {
+                issues.extend(profile.check_constraints(resource));
+            }
+        }
+        if full {
+            for base in profiles
+                .iter()
+                .filter(|profile| profile.base && profile.resource_type == resource_type)
+            {
+                issues.extend(base.check(resource));
+                issues.extend(base.check_constraints(resource));
+            }
+        }
+        issues
+    }
+
+    /// Issues reported by all hooks for `resource`, in hook order
+    pub async fn validate(&self, operation: &str, resource: &Value) -> Vec<OperationOutcomeIssue> {
+        let hooks = self.hooks.read().unwrap().clone();
+        if hooks.is_empty() {
+            return Vec::new();
+        }
+
+        let input = json!({ "operation": operation, "resource": resource }).to_string();
+        // Hooks are CPU-bound (bounded by fuel), keep them off the async workers
+        tokio::task::spawn_blocking(move || {
+            hooks
+                .iter()
+                .flat_map(|hook| {
+                    let issues = hook.run(input.as_bytes());
+                    if !issues.is_empty() {
+                        tracing::debug!(
+                            hook = hook.name(),
+                            issues = issues.len(),
+                            "validation hook reported issues"
+                        );
+                    }
+                    issues
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_else(|e| vec![exception(format!("Validation hooks failed: {}", e))])
+    }
+}
+
+/// The profile URLs `resource` claims in `meta.profile`, without versions
+pub fn claimed_profiles(resource: &Value) -> Vec<&str> {
+    resource
+        .pointer("/meta/profile")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(|canonical| canonical.split('|').next().unwrap_or_default())
+        .collect()
+}
+
+fn find_unknown_modifiers(
+    value: &Value,
+    path: &str,
+    understood: &[String],
+    issues: &mut Vec<OperationOutcomeIssue>,
+) {
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::Array(modifiers)) = map.get("modifierExtension") {
+                for (i, modifier) in modifiers.iter().enumerate() {
+                    let url = modifier.get("url").and_then(Value::as_str);
+                    if !url.is_some_and(|url| understood.iter().any(|u| u == url)) {
+                        issues.push(unknown_modifier(
+                            url,
+                            format!("{}.modifierExtension[{}]", path, i),
+                        ));
+                    }
+                }
+            }
+            for (key, member) in map {
+                find_unknown_modifiers(member, &format!("{}.{}", path, key), understood, issues);
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                find_unknown_modifiers(item, &format!("{}[{}]", path, i), understood, issues);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Canonicals of the FHIR specification's own definitions
+const CORE_CANONICAL_BASE: &str = "http://hl7.org/fhir/";
+
+/// Elements holding canonical references: the resource type they occur in
+/// (`None` for any), their path and the type the reference must resolve to
+const CANONICAL_ELEMENTS: &[(Option<&str>, &[&str], &str)] = &[
+    (None, &["meta", "profile"], "StructureDefinition"),
+    (
+        Some("StructureDefinition"),
+        &["baseDefinition"],
+        "StructureDefinition",
+    ),
+    (
+        Some("StructureDefinition"),
+        &["snapshot", "element", "binding", "valueSet"],
+        "ValueSet",
+    ),
+    (
+        Some("StructureDefinition"),
+        &["differential", "element", "binding", "valueSet"],
+        "ValueSet",
+    ),
+    (
+        Some("ValueSet"),
+        &["compose", "include", "valueSet"],
+        "ValueSet",
+    ),
+    (
+        Some("ValueSet"),
+        &["compose", "exclude", "valueSet"],
+        "ValueSet",
+    ),
+    (Some("SearchParameter"), &["derivedFrom"], "SearchParameter"),
+];
+
+/// A canonical reference found in a resource
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalReference {
+    pub location: String,
+    /// The conformance resource type it must resolve to
+    pub target: &'static str,
+    pub canonical: String,
+}
+
+/// The canonical references of `resource` that must resolve to a stored
+/// conformance resource
+pub fn canonical_references(resource: &Value) -> Vec<CanonicalReference> {
+    let resource_type = resource.get("resourceType").and_then(Value::as_str);
+    let mut references = Vec::new();
+    for (base, path, target) in CANONICAL_ELEMENTS {
+        if base.is_some_and(|base| Some(base) != resource_type) {
+            continue;
+        }
+        find_canonicals(
+            resource,
+            path,
+            resource_type.unwrap_or("Resource").to_string(),
+            target,
+            &mut references,
+        );
+    }
+    references.retain(|r| !r.canonical.starts_with(CORE_CANONICAL_BASE));
+    references
+}
+
+fn find_canonicals(
+    value: &Value,
+    path: &[&str],
+    location: String,
+    target: &'static str,
+    references: &mut Vec<CanonicalReference>,
+) {
+    match (value, path.split_first()) {
+        (Value::Array(items), _) => {
+            for (i, item) in items.iter().enumerate() {
+                let location = format!("{}[{}]", location, i);
+                find_canonicals(item, path, location, target, references);
+            }
+        }
+        (Value::Object(map), Some((name, rest))) => {
+            if let Some(member) = map.get(*name) {
+                let location = format!("{}.{}", location, name);
+                find_canonicals(member, rest, location, target, references);
+            }
+        }
+        (Value::String(canonical), None) => references.push(CanonicalReference {
+            location,
+            target,
+            canonical: canonical.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// The `not-found` error for a canonical reference that resolves to nothing
+pub fn unresolved_canonical(reference: &CanonicalReference) -> OperationOutcomeIssue {
+    OperationOutcomeIssue {
+        severity: "error".to_string(),
+        code: "not-found".to_string(),
+        details: None,
+        diagnostics: Some(format!(
+            "{} does not resolve to a stored {}",
+            reference.canonical, reference.target
+        )),
+        location: Some(vec![reference.location.clone()]),
+        expression: None,
+    }
+}
+
+/// A warning that the stored StructureDefinition a resource claims
+/// defines no resource profile, so nothing of it is checked
+pub fn unchecked_profile(reference: &CanonicalReference) -> OperationOutcomeIssue {
+    OperationOutcomeIssue {
+        severity: "warning".to_string(),
+        code: "not-supported".to_string(),
+        details: None,
+        diagnostics: Some(format!(
+            "{} is not a resource profile and was not checked",
+            reference.canonical
+        )),
+        location: Some(vec![reference.location.clone()]),
+        expression: None,
+    }
+}
+
+fn unknown_modifier(url: Option<&str>, location: String) -> OperationOutcomeIssue {
+    OperationOutcomeIssue {
+        severity: "error".to_string(),
+        code: "extension".to_string(),
+        details: None,
+        diagnostics: Some(format!(
+            "Modifier extension {} is not understood by this server",
+            url.unwrap_or("without url")
+        )),
+        location: Some(vec![location]),
+        expression: None,
+    }
+}
+
+fn exception(diagnostics: String) -> OperationOutcomeIssue {
+    OperationOutcomeIssue {
+        severity: "error".to_string(),
+        code: "exception".to_string(),
+        details: None,
+        diagnostics: Some(diagnostics),
+        location: None,
+        expression: None,
+    }
+}
+
+/// Whether any issue must reject the write
+pub fn has_errors(issues: &[OperationOutcomeIssue]) -> bool {
+    issues
+        .iter()
+        .any(|i| i.severity == "error" || i.severity == "fatal")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hook that ignores its input and reports `issues`
+    fn reporting_hook(issues: &str) -> ValidationHook {
+        let wat = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 16) "{data}")
+                (func (export "fhir_alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "fhir_validate") (param i32 i32) (result i64)
+                    i64.const {packed}))"#,
+            data = issues.replace('"', "\\\""),
+            packed = (16i64 << 32) | issues.len() as i64,
+        );
+        ValidationHook::from_bytes("reporting", wat.as_bytes(), default_fuel()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hook_issues_are_collected() {
+        let hooks = ValidationHooks::default();
+        hooks.set(vec![reporting_hook(
+            r#"[{"severity":"error","code":"business-rule","diagnostics":"MRN required"}]"#,
+        )]);
+
+        let issues = hooks
+            .validate("create", &json!({"resourceType": "Patient"}))
+            .await;
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].code, "business-rule");
+        assert!(has_errors(&issues));
+    }
+
+    #[test]
+    fn test_unknown_modifier_extensions_are_reported() {
+        let hooks = ValidationHooks::default();
+        hooks.set_understood_modifiers(vec!["http://example.org/known".to_string()]);
+
+        let issues = hooks.unknown_modifiers(&json!({
+            "resourceType": "Patient",
+            "modifierExtension": [{ "url": "http://example.org/known", "valueBoolean": true }],
+            "name": [{
+                "family": "Meier",
+                "modifierExtension": [
+                    { "url": "http://example.org/known", "valueBoolean": true },
+                    { "url": "http://example.org/unknown", "valueBoolean": true }
+                ]
+            }],
+            "contact": [{ "modifierExtension": [{ "valueBoolean": true }] }]
+        }));
+
+        let locations: Vec<&str> = issues
+            .iter()
+            .map(|i| i.location.as_ref().unwrap()[0].as_str())
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "Patient.contact[0].modifierExtension[0]",
+                "Patient.name[0].modifierExtension[1]",
+            ]
+        );
+        assert!(issues.iter().all(|i| i.code == "extension"));
+        assert!(has_errors(&issues));
+
+        // Plain extensions, including those of primitives, are not modifiers
+        assert!(hooks
+            .unknown_modifiers(&json!({
+                "resourceType": "Patient",
+                "birthDate": "1970-03-30",
+                "_birthDate": { "extension": [{ "url": "http://example.org/unknown" }] }
+            }))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_canonical_references() {
+        let references = canonical_references(&json!({
+            "resourceType": "ValueSet",
+            "meta": { "profile": ["http://example.org/fhir/StructureDefinition/vs"] },
+            "compose": {
+                "include": [
+                    { "system": "http://loinc.org" },
+                    { "valueSet": [
+                        "http://example.org/fhir/ValueSet/a|1.0",
+                        "http://hl7.org/fhir/ValueSet/administrative-gender"
+                    ] }
+                ],
+                "exclude": [{ "valueSet": ["http://example.org/fhir/ValueSet/b"] }]
+            },
+            "derivedFrom": "http://example.org/fhir/SearchParameter/not-a-value-set-element"
+        }));
+
+        let found: Vec<(&str, &str)> = references
+            .iter()
+            .map(|r| (r.location.as_str(), r.target))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("ValueSet.meta.profile[0]", "StructureDefinition"),
+                ("ValueSet.compose.include[1].valueSet[0]", "ValueSet"),
+                ("ValueSet.compose.exclude[0].valueSet[0]", "ValueSet"),
+            ]
+        );
+        assert_eq!(
+            references[1].canonical,
+            "http://example.org/fhir/ValueSet/a|1.0"
+        );
+        assert_eq!(unresolved_canonical(&references[1]).code, "not-found");
+    }
+
+    #[test]
+    fn test_hook_without_issues() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "fhir_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "fhir_validate") (param i32 i32) (result i64) i64.const 0))"#;
+        let hook = ValidationHook::from_bytes("accept", wat.as_bytes(), default_fuel()).unwrap();
+
+        assert!(hook.run(br#"{"operation":"create"}"#).is_empty());
+    }
+
+    #[test]
+    fn test_runaway_hook_is_stopped_by_fuel() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "fhir_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "fhir_validate") (param i32 i32) (result i64)
+                (loop $spin (br $spin))
+                i64.const 0))"#;
+        let hook = ValidationHook::from_bytes("spin", wat.as_bytes(), 10_000).unwrap();
+
+        let issues = hook.run(b"{}");
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].code, "exception");
+        assert!(has_errors(&issues));
+    }
+
+    #[test]
+    fn test_warnings_do_not_reject() {
+        let hook = reporting_hook(r#"[{"severity":"warning","code":"business-rule"}]"#);
+        assert!(!has_errors(&hook.run(b"{}")));
+    }
+}
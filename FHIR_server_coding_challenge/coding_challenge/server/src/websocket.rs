@@ -0,0 +1,340 @@
+//! The server side of RFC 6455, as much as the subscription channel needs:
+//! the opening handshake, and text, ping, pong and close frames of up to
+//! [`MAX_FRAME_BYTES`]. Fragmented messages are reassembled; extensions and
+//! subprotocols are not negotiated, so frames with a reserved bit set are
+//! refused. Frames from clients must be masked and those from servers must
+//! not be (section 5.1), and control frames must be unfragmented and carry
+//! at most [`MAX_CONTROL_BYTES`] (section 5.5); a reader refuses frames
+//! that break these rules with an `InvalidData` error, after which the
+//! connection is to be failed with status 1002.
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message a client may send; clients only send short commands
+pub const MAX_FRAME_BYTES: u64 = 64 * 1024;
+
+/// Largest payload of a control frame
+pub const MAX_CONTROL_BYTES: u64 = 125;
+
+/// Close status of a connection failed for breaking the protocol
+pub const PROTOCOL_ERROR: u16 = 1002;
+
+/// A WebSocket message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// With the status code and reason, if any
+    Close(Option<(u16, String)>),
+}
+
+fn header_has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|sent| sent.trim().eq_ignore_ascii_case(token))
+}
+
+/// The `Sec-WebSocket-Accept` answering a version 13 opening handshake,
+/// or `None` if `headers` are not one
+pub fn handshake_accept(headers: &HeaderMap) -> Option<HeaderValue> {
+    if !header_has_token(headers, header::CONNECTION, "upgrade")
+        || !header_has_token(headers, header::UPGRADE, "websocket")
+        || headers.get(header::SEC_WEBSOCKET_VERSION)? != "13"
+    {
+        return None;
+    }
+    let key = headers.get(header::SEC_WEBSOCKET_KEY)?.to_str().ok()?;
+    HeaderValue::from_str(&accept_key(key.trim())).ok()
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the messages of a connection
+pub struct MessageReader<R> {
+    reader: R,
+    /// Whether the peer is a client, whose frames are masked
+    from_client: bool,
+    /// Opcode and payload of the message whose fragments are arriving
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    /// Reads what a client sends
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            from_client: true,
+            fragments: None,
+        }
+    }
+
+    /// Reads what a server sends, for the client side of a connection
+    pub fn from_server(reader: R) -> Self {
+        Self {
+            from_client: false,
+            ..Self::new(reader)
+        }
+    }
+
+    /// The next message; `None` once the peer has closed the connection.
+    /// Control frames sent between the fragments of a message come first.
+    pub async fn next(&mut self) -> io::Result<Option<Message>> {
+        let reader = &mut self.reader;
+        loop {
+            let mut head = [0u8; 2];
+            match reader.read_exact(&mut head).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0f;
+            let masked = head[1] & 0x80 != 0;
+            if head[0] & 0x70 != 0 {
+                return Err(protocol_error("reserved bits set without an extension"));
+            }
+            if masked != self.from_client {
+                return Err(protocol_error(if masked {
+                    "masked frame from a server"
+                } else {
+                    "unmasked frame from a client"
+                }));
+            }
+            let length = match head[1] & 0x7f {
+                126 => u64::from(reader.read_u16().await?),
+                127 => reader.read_u64().await?,
+                length => u64::from(length),
+            };
+            if opcode & 0x8 != 0 {
+                if !fin {
+                    return Err(protocol_error("fragmented control frame"));
+                }
+                if length > MAX_CONTROL_BYTES {
+                    return Err(protocol_error("control frame longer than 125 bytes"));
+                }
+            }
+            let buffered = self
+                .fragments
+                .as_ref()
+                .map_or(0, |(_, data)| data.len() as u64);
+            if length.saturating_add(buffered) > MAX_FRAME_BYTES {
+                return Err(protocol_error("message too large"));
+            }
+            let mut mask = [0u8; 4];
+            if masked {
+                reader.read_exact(&mut mask).await?;
+            }
+            let mut payload = vec![0u8; length as usize];
+            reader.read_exact(&mut payload).await?;
+            if masked {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+
+            let (opcode, payload) = match opcode {
+                0x8 if payload.len() == 1 => {
+                    return Err(protocol_error("close frame with a one byte body"))
+                }
+                0x8 => {
+                    let close = (payload.len() >= 2).then(|| {
+                        let code = u16::from_be_bytes([payload[0], payload[1]]);
+                        (code, String::from_utf8_lossy(&payload[2..]).into_owned())
+                    });
+                    return Ok(Some(Message::Close(close)));
+                }
+                0x9 => return Ok(Some(Message::Ping(payload))),
+                0xa => return Ok(Some(Message::Pong(payload))),
+                0x0 => {
+                    let (opcode, mut data) = self
+                        .fragments
+                        .take()
+                        .ok_or_else(|| protocol_error("continuation without a message"))?;
+                    data.extend(payload);
+                    (opcode, data)
+                }
+                0x1 | 0x2 if self.fragments.is_none() => (opcode, payload),
+                0x1 | 0x2 => return Err(protocol_error("new message inside a fragmented one")),
+                _ => return Err(protocol_error("unknown opcode")),
+            };
+            if !fin {
+                self.fragments = Some((opcode, payload));
+                continue;
+            }
+            return match opcode {
+                0x1 => String::from_utf8(payload)
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|_| protocol_error("text message is not UTF-8")),
+                _ => Ok(Some(Message::Binary(payload))),
+            };
+        }
+    }
+}
+
+/// Write `message` as one unmasked frame, as servers send them
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> io::Result<()> {
+    let (opcode, payload) = match message {
+        Message::Text(text) => (0x1, text.as_bytes().to_vec()),
+        Message::Binary(data) => (0x2, data.clone()),
+        Message::Close(None) => (0x8, Vec::new()),
+        Message::Close(Some((code, reason))) => {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend(reason.as_bytes());
+            (0x8, payload)
+        }
+        Message::Ping(data) => (0x9, data.clone()),
+        Message::Pong(data) => (0xa, data.clone()),
+    };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend((length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend((length as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_accept() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+        headers.insert(header::UPGRADE, "websocket".parse().unwrap());
+        headers.insert(header::SEC_WEBSOCKET_VERSION, "13".parse().unwrap());
+        headers.insert(
+            header::SEC_WEBSOCKET_KEY,
+            "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap(),
+        );
+        // The example of RFC 6455 section 1.3
+        assert_eq!(
+            handshake_accept(&headers).unwrap(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        headers.insert(header::SEC_WEBSOCKET_VERSION, "8".parse().unwrap());
+        assert!(handshake_accept(&headers).is_none());
+        assert!(handshake_accept(&HeaderMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        // The masked "Hello" of RFC 6455 section 5.7 in two fragments, with
+        // a ping between them
+        let mut client = MessageReader::new(
+            &[
+                0x01, 0x83, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, // "Hel"
+                0x89, 0x80, 0, 0, 0, 0, // ping
+                0x80, 0x82, 0x37, 0xfa, 0x21, 0x3d, 0x5b, 0x95, // "lo"
+                0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8, // close 1000
+            ][..],
+        );
+        assert_eq!(
+            client.next().await.unwrap(),
+            Some(Message::Ping(Vec::new()))
+        );
+        assert_eq!(
+            client.next().await.unwrap(),
+            Some(Message::Text("Hello".to_string()))
+        );
+        assert_eq!(
+            client.next().await.unwrap(),
+            Some(Message::Close(Some((1000, String::new()))))
+        );
+        assert_eq!(client.next().await.unwrap(), None);
+
+        let mut sent = Vec::new();
+        let long = "x".repeat(300);
+        for message in [Message::Text("ping abc".to_string()), Message::Text(long)] {
+            write_message(&mut sent, &message).await.unwrap();
+            let mut reader = MessageReader::from_server(sent.as_slice());
+            assert_eq!(reader.next().await.unwrap(), Some(message));
+            sent.clear();
+        }
+
+        let mut huge = MessageReader::new(&[0x81, 0xff, 0, 0, 0, 0, 0, 1, 0, 1][..]);
+        assert!(huge.next().await.is_err());
+    }
+
+    async fn refusal(frames: &[u8]) -> String {
+        let error = MessageReader::new(frames).next().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        error.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_frames_breaking_the_protocol_are_refused() {
+        // Section 5.1: clients mask every frame, servers none
+        assert_eq!(
+            refusal(&[0x81, 0x02, b'h', b'i']).await,
+            "unmasked frame from a client"
+        );
+        let mut server = MessageReader::from_server(&[0x81, 0x80, 0, 0, 0, 0][..]);
+        assert!(server.next().await.is_err());
+
+        // Section 5.5: control frames are short and never fragmented
+        let mut long_ping = vec![0x89, 0x80 | 126, 0, 126, 0, 0, 0, 0];
+        long_ping.extend([0; 126]);
+        assert_eq!(
+            refusal(&long_ping).await,
+            "control frame longer than 125 bytes"
+        );
+        assert_eq!(
+            refusal(&[0x09, 0x80, 0, 0, 0, 0]).await,
+            "fragmented control frame"
+        );
+        assert_eq!(
+            refusal(&[0x88, 0x81, 0, 0, 0, 0, 0x03]).await,
+            "close frame with a one byte body"
+        );
+
+        // Section 5.2: no extension defines the reserved bits
+        assert_eq!(
+            refusal(&[0xc1, 0x80, 0, 0, 0, 0]).await,
+            "reserved bits set without an extension"
+        );
+
+        // A ping of exactly 125 bytes is fine
+        let mut ping = vec![0x89, 0x80 | 125, 0, 0, 0, 0];
+        ping.extend([7; 125]);
+        assert_eq!(
+            MessageReader::new(&ping[..]).next().await.unwrap(),
+            Some(Message::Ping(vec![7; 125]))
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Round-trips the resources under `tests/fixtures/extensions` through a
+//! running server: everything in `preserved/` must come back unchanged apart
+//! from `id` and `meta`, everything in `rejected/` carries a modifier
+//! extension the server does not understand and must be refused.
+
+use reqwest::Client;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+const BASE_URL: &str = "http://localhost:3000";
+
+fn corpus(kind: &str) -> Vec<(PathBuf, Value)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/extensions")
+        .join(kind);
+    let mut fixtures: Vec<(PathBuf, Value)> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", dir.display(), e))
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let resource = serde_json::from_str(&std::fs::read_to_string(&path).unwrap())
+                .unwrap_or_else(|e| panic!("{} is not JSON: {}", path.display(), e));
+            (path, resource)
+        })
+        .collect();
+    fixtures.sort_by(|a, b| a.0.cmp(&b.0));
+    assert!(!fixtures.is_empty(), "no fixtures in {}", dir.display());
+    fixtures
+}
+
+fn without_server_elements(mut resource: Value) -> Value {
+    let map = resource.as_object_mut().unwrap();
+    map.remove("id");
+    map.remove("meta");
+    resource
+}
+
+#[tokio::test]
+async fn test_extensions_survive_round_trips() {
+    let client = Client::new();
+
+    for (path, fixture) in corpus("preserved") {
+        let resource_type = fixture["resourceType"].as_str().unwrap();
+        let created: Value = client
+            .post(format!("{}/fhir/{}", BASE_URL, resource_type))
+            .header("Content-Type", "application/fhir+json")
+            .json(&fixture)
+            .send()
+            .await
+            .expect("Failed to create resource")
+            .json()
+            .await
+            .expect("Failed to parse response");
+        let id = created["id"]
+            .as_str()
+            .unwrap_or_else(|| panic!("{}: create failed: {}", path.display(), created));
+        let url = format!("{}/fhir/{}/{}", BASE_URL, resource_type, id);
+
+        let read: Value = client.get(&url).send().await.unwrap().json().await.unwrap();
+        assert_eq!(
+            without_server_elements(read.clone()),
+            fixture,
+            "{} changed on read",
+            path.display()
+        );
+
+        // Writing back what was read is a no-op, so nothing moves on update
+        let updated: Value = client
+            .put(&url)
+            .header("Content-Type", "application/fhir+json")
+            .json(&read)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            updated["meta"]["versionId"],
+            "1",
+            "{} was not stable",
+            path.display()
+        );
+        assert_eq!(
+            without_server_elements(updated),
+            fixture,
+            "{} changed on update",
+            path.display()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_modifier_extensions_are_rejected() {
+    let client = Client::new();
+
+    for (path, fixture) in corpus("rejected") {
+        let resource_type = fixture["resourceType"].as_str().unwrap();
+        let response = client
+            .post(format!("{}/fhir/{}", BASE_URL, resource_type))
+            .header("Content-Type", "application/fhir+json")
+            .json(&fixture)
+            .send()
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(response.status(), 422, "{} was accepted", path.display());
+        let outcome: Value = response.json().await.unwrap();
+        assert_eq!(
+            outcome["issue"][0]["code"],
+            "extension",
+            "{}",
+            path.display()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! A short run of the fuzz targets in `fuzz/` on generated input, so a
+//! panic they once found stays found without a nightly toolchain.
+//! `FUZZ_DATABASE_URL` points the request target at a scratch database.
+
+use fhir_server::fuzzing;
+use proptest::prelude::*;
+use serde_json::{json, Value};
+
+const PARAMETERS: &[&str] = &[
+    "name",
+    "name:phonetic",
+    "birthdate",
+    "gender",
+    "identifier",
+    "_id",
+    "_count",
+    "_offset",
+    "_page_token",
+    "_sort",
+    "_elements",
+    "_summary",
+    "_total",
+    "code",
+    "date",
+    "value-quantity",
+];
+
+/// Query strings made of parameter names the server knows, modifiers,
+/// prefixes and escapes
+fn query() -> impl Strategy<Value = String> {
+    let name = prop_oneof![
+        prop::sample::select(PARAMETERS.to_vec()).prop_map(str::to_string),
+        "[a-z_:.-]{1,10}",
+    ];
+    let value = prop_oneof![
+        "(eq|ne|gt|lt|ge|le|sa|eb|ap)?[0-9]{0,4}(-[0-9]{1,2}){0,2}(T[0-9:]{0,8}Z?)?",
+        "[A-Za-z0-9|:,.% +-]{0,16}",
+        "(%[0-9A-Fa-f]{0,2}|[\\p{L}&=;#?/]){0,8}",
+    ];
+    prop::collection::vec((name, value), 0..6).prop_map(|params| {
+        params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&")
+    })
+}
+
+fn pointer() -> impl Strategy<Value = String> {
+    prop::collection::vec(
+        prop_oneof![
+            Just("name".to_string()),
+            Just("given".to_string()),
+            Just("meta".to_string()),
+            Just("id".to_string()),
+            Just("extension".to_string()),
+            Just("-".to_string()),
+            "[0-9]{1,2}",
+            "[a-zA-Z~/]{1,6}",
+        ],
+        0..4,
+    )
+    .prop_map(|segments| segments.iter().map(|s| format!("/{}", s)).collect())
+}
+
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        "[\\PC]{0,8}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 16, 4, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+            prop::collection::btree_map("[a-zA-Z]{1,8}", inner, 0..4)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+fn patch() -> impl Strategy<Value = Vec<u8>> {
+    let op = prop_oneof![
+        Just("add"),
+        Just("remove"),
+        Just("replace"),
+        Just("move"),
+        Just("copy"),
+        Just("test"),
+    ];
+    prop::collection::vec((op, pointer(), pointer(), json_value()), 0..4).prop_map(|ops| {
+        let ops: Vec<Value> = ops
+            .into_iter()
+            .map(|(op, path, from, value)| {
+                json!({ "op": op, "path": path, "from": from, "value": value })
+            })
+            .collect();
+        serde_json::to_vec(&ops).unwrap()
+    })
+}
+
+fn request() -> impl Strategy<Value = Vec<u8>> {
+    let path = prop_oneof![
+        Just("Patient".to_string()),
+        Just("Patient/7c9e6679-7425-40de-944b-e07fc1f90ae7".to_string()),
+        Just("Patient/$cohort".to_string()),
+        Just("Observation".to_string()),
+        Just("metadata".to_string()),
+        Just("".to_string()),
+        "[A-Za-z$/_-]{0,20}",
+    ];
+    let body = prop_oneof![
+        json_value().prop_map(|v| serde_json::to_vec(&v).unwrap()),
+        patch(),
+        prop::collection::vec(any::<u8>(), 0..32),
+    ];
+    (any::<u8>(), path, query(), body).prop_map(|(method, path, query, body)| {
+        let mut data = vec![method];
+        data.extend(format!("{}?{}\n", path, query).into_bytes());
+        data.extend(body);
+        data
+    })
+}
+
+proptest! {
+    #[test]
+    fn test_search_parsing_never_panics(query in query()) {
+        fuzzing::search(query.as_bytes());
+    }
+
+    #[test]
+    fn test_cohort_parsing_never_panics(body in json_value()) {
+        fuzzing::cohort(&serde_json::to_vec(&body).unwrap());
+    }
+
+    #[test]
+    fn test_patient_patches_never_panic(patch in patch()) {
+        fuzzing::patient_patch(&patch);
+    }
+
+}
+
+// Requests that reach storage wait for the pool when there is no database
+proptest! {
+    #![proptest_config(ProptestConfig { cases: 64, ..ProptestConfig::default() })]
+
+    #[test]
+    fn test_requests_never_panic(requests in prop::collection::vec(request(), 1..16)) {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let database_url = std::env::var("FUZZ_DATABASE_URL").ok();
+            let app = fuzzing::app(database_url.as_deref());
+            for data in &requests {
+                fuzzing::request(&app, data).await;
+            }
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use fhir_server::models::{Bundle, Patient};
+use fhir_server::models::{Bundle, Observation, Patient};
 use reqwest::Client;
 use serde_json::json;
 
@@ -123,7 +123,7 @@ async fn test_search_patients() {
     let bundle: Bundle = search_response.json().await.expect("Failed to parse response");
     assert_eq!(bundle.resource_type, "Bundle");
     assert_eq!(bundle.bundle_type, "searchset");
-    assert!(bundle.total > 0);
+    assert!(bundle.total.unwrap() > 0);
 }
 
 #[tokio::test]
@@ -142,3 +142,106 @@ async fn test_search_with_pagination() {
     assert_eq!(bundle.resource_type, "Bundle");
     assert!(bundle.entry.len() <= 5);
 }
+
+#[tokio::test]
+async fn test_search_parameter_introspection() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/fhir/SearchParameter?base=Patient", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to list search parameters");
+
+    assert_eq!(response.status(), 200);
+
+    let bundle: serde_json::Value = response.json().await.expect("Failed to parse response");
+    assert_eq!(bundle["resourceType"], "Bundle");
+    let codes: Vec<&str> = bundle["entry"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|e| e["resource"]["code"].as_str())
+        .collect();
+    assert!(codes.contains(&"name"));
+    assert!(codes.contains(&"birthdate"));
+}
+
+#[tokio::test]
+async fn test_observation_lifecycle() {
+    let client = Client::new();
+    let subject = format!("Patient/{}", uuid::Uuid::new_v4());
+
+    let observation = json!({
+        "resourceType": "Observation",
+        "status": "final",
+        "code": {"coding": [{"system": "http://loinc.org", "code": "8867-4"}]},
+        "subject": {"reference": subject},
+        "effectiveDateTime": "2024-03-01T08:30:00Z",
+        "valueQuantity": {"value": 72, "unit": "beats/minute"}
+    });
+
+    let create_response = client
+        .post(format!("{}/fhir/Observation", BASE_URL))
+        .header("Content-Type", "application/fhir+json")
+        .json(&observation)
+        .send()
+        .await
+        .expect("Failed to create observation");
+    assert_eq!(create_response.status(), 201);
+    let created: Observation = create_response.json().await.expect("Failed to parse response");
+    let observation_id = created.id.unwrap();
+
+    let search_response = client
+        .get(format!(
+            "{}/fhir/Observation?code=http://loinc.org|8867-4&subject={}&date=2024-03",
+            BASE_URL, subject
+        ))
+        .send()
+        .await
+        .expect("Failed to search observations");
+    assert_eq!(search_response.status(), 200);
+    let bundle: Bundle<Observation> = search_response.json().await.expect("Failed to parse response");
+    assert_eq!(bundle.entry.len(), 1);
+    assert_eq!(bundle.entry[0].resource.extra["valueQuantity"]["value"], 72);
+
+    let delete_response = client
+        .delete(format!("{}/fhir/Observation/{}", BASE_URL, observation_id))
+        .send()
+        .await
+        .expect("Failed to delete observation");
+    assert_eq!(delete_response.status(), 204);
+
+    let get_response = client
+        .get(format!("{}/fhir/Observation/{}", BASE_URL, observation_id))
+        .send()
+        .await
+        .expect("Failed to get observation");
+    assert_eq!(get_response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_responses_carry_request_id() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/fhir/metadata", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to get metadata");
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .expect("Missing X-Request-Id")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Successful requests are never captured
+    let capture_response = client
+        .get(format!("{}/admin/requests/{}", BASE_URL, request_id))
+        .send()
+        .await
+        .expect("Failed to look up capture");
+    assert_eq!(capture_response.status(), 404);
+}
@@ -0,0 +1,223 @@
+//! Property-based round trips through a running server: arbitrary Patients
+//! (unicode names, nested extensions, primitive extensions) must come back
+//! unchanged apart from `id` and `meta` after create, read, update and in
+//! every version of their history.
+
+use proptest::prelude::*;
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+
+const BASE_URL: &str = "http://localhost:3000";
+
+/// Printable text including non-ASCII letters, combining marks and emoji
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[A-Za-z][A-Za-z '-]{0,15}",
+        "[\\p{L}\\p{M}\\p{N} ]{1,20}",
+        "[äöüßéèçñøåłŁžŽ中文日本語한국어😀🏥\u{0301}]{1,8}",
+    ]
+}
+
+fn url() -> impl Strategy<Value = String> {
+    "[a-z]{1,8}".prop_map(|name| format!("http://example.org/fhir/StructureDefinition/{}", name))
+}
+
+/// An extension with a value or, up to `depth`, nested extensions
+fn extension(depth: u32) -> BoxedStrategy<Value> {
+    let leaf = (
+        url(),
+        prop_oneof![
+            text().prop_map(|v| json!({ "valueString": v })),
+            any::<bool>().prop_map(|v| json!({ "valueBoolean": v })),
+            (-1_000_000i64..1_000_000).prop_map(|v| json!({ "valueInteger": v })),
+            "[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|1[0-9]|2[0-8])"
+                .prop_map(|v| json!({ "valueDate": v })),
+        ],
+    )
+        .prop_map(|(url, mut value)| {
+            value["url"] = json!(url);
+            value
+        })
+        .boxed();
+    if depth == 0 {
+        return leaf;
+    }
+    prop_oneof![
+        3 => leaf,
+        1 => (url(), prop::collection::vec(extension(depth - 1), 1..3))
+            .prop_map(|(url, nested)| json!({ "url": url, "extension": nested })),
+    ]
+    .boxed()
+}
+
+fn human_name() -> impl Strategy<Value = Value> {
+    (
+        prop::option::of(text()),
+        prop::option::of(prop::collection::vec(text(), 1..4)),
+        prop::option::of(text()),
+        prop::option::of(prop::sample::select(vec![
+            "official", "usual", "nickname", "maiden",
+        ])),
+        prop::option::of(prop::collection::vec(extension(1), 1..3)),
+    )
+        .prop_map(|(family, given, text, name_use, extensions)| {
+            let mut name = Map::new();
+            if let Some(family) = family {
+                name.insert("family".to_string(), json!(family));
+            }
+            if let Some(given) = given {
+                name.insert("given".to_string(), json!(given));
+            }
+            if let Some(text) = text {
+                name.insert("text".to_string(), json!(text));
+            }
+            if let Some(name_use) = name_use {
+                name.insert("use".to_string(), json!(name_use));
+            }
+            if let Some(extensions) = extensions {
+                name.insert("extension".to_string(), json!(extensions));
+            }
+            if name.is_empty() {
+                name.insert("text".to_string(), json!("-"));
+            }
+            Value::Object(name)
+        })
+}
+
+fn patient() -> impl Strategy<Value = Value> {
+    (
+        prop::collection::vec(human_name(), 1..4),
+        prop::option::of(prop::sample::select(vec![
+            "male", "female", "other", "unknown",
+        ])),
+        prop::option::of("(19[0-9]{2}|20[0-2][0-9])-(0[1-9]|1[0-2])-(0[1-9]|1[0-9]|2[0-8])"),
+        prop::option::of(prop::collection::vec(extension(2), 1..3)),
+        prop::option::of(extension(1)),
+        prop::option::of(any::<bool>()),
+    )
+        .prop_map(
+            |(names, gender, birth_date, extensions, birth_time, active)| {
+                let mut patient = json!({ "resourceType": "Patient", "name": names });
+                if let Some(gender) = gender {
+                    patient["gender"] = json!(gender);
+                }
+                if let Some(birth_date) = birth_date {
+                    patient["birthDate"] = json!(birth_date);
+                    // A primitive extension on birthDate
+                    if let Some(birth_time) = birth_time {
+                        patient["_birthDate"] = json!({ "extension": [birth_time] });
+                    }
+                }
+                if let Some(extensions) = extensions {
+                    patient["extension"] = json!(extensions);
+                }
+                if let Some(active) = active {
+                    patient["active"] = json!(active);
+                }
+                patient
+            },
+        )
+}
+
+fn without_server_elements(mut resource: Value) -> Value {
+    let map = resource.as_object_mut().unwrap();
+    map.remove("id");
+    map.remove("meta");
+    resource
+}
+
+async fn send_json(request: reqwest::RequestBuilder) -> (u16, Value) {
+    let response = request.send().await.expect("Failed to send request");
+    let status = response.status().as_u16();
+    (
+        status,
+        response.json().await.expect("Failed to parse response"),
+    )
+}
+
+/// POST `original`, read it, PUT `replacement` and read the history
+async fn round_trip(client: &Client, original: &Value, replacement: &Value) {
+    let (status, created) = send_json(
+        client
+            .post(format!("{}/fhir/Patient", BASE_URL))
+            .header("Content-Type", "application/fhir+json")
+            .json(original),
+    )
+    .await;
+    assert_eq!(status, 201, "create failed: {}", created);
+    assert_eq!(
+        without_server_elements(created.clone()),
+        *original,
+        "changed on create"
+    );
+    let id = created["id"].as_str().unwrap();
+    let url = format!("{}/fhir/Patient/{}", BASE_URL, id);
+
+    let (_, read) = send_json(client.get(&url)).await;
+    assert_eq!(without_server_elements(read), *original, "changed on read");
+
+    let mut body = replacement.clone();
+    body["id"] = json!(id);
+    let (status, updated) = send_json(
+        client
+            .put(&url)
+            .header("Content-Type", "application/fhir+json")
+            .json(&body),
+    )
+    .await;
+    assert_eq!(status, 200, "update failed: {}", updated);
+    assert_eq!(
+        without_server_elements(updated.clone()),
+        *replacement,
+        "changed on update"
+    );
+    let (_, read) = send_json(client.get(&url)).await;
+    assert_eq!(
+        without_server_elements(read),
+        *replacement,
+        "changed on read after update"
+    );
+
+    // Newest first; an update to identical content adds no version
+    let (_, history) = send_json(client.get(format!("{}/_history", url))).await;
+    let versions: Vec<Value> = history["entry"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| without_server_elements(entry["resource"].clone()))
+        .collect();
+    let expected = if original == replacement {
+        vec![original.clone()]
+    } else {
+        vec![replacement.clone(), original.clone()]
+    };
+    assert_eq!(versions, expected, "history of Patient/{}", id);
+    assert_eq!(
+        updated["meta"]["versionId"],
+        json!(expected.len().to_string())
+    );
+}
+
+/// Every case makes a dozen requests against the server, so fewer than
+/// proptest's default unless `PROPTEST_CASES` asks for more
+fn cases() -> u32 {
+    std::env::var("PROPTEST_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(32)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig { cases: cases(), ..ProptestConfig::default() })]
+
+    #[test]
+    fn test_patients_round_trip_unchanged(original in patient(), replacement in patient()) {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let client = Client::new();
+            round_trip(&client, &original, &replacement).await;
+            // Writing back the same content must be exact too
+            round_trip(&client, &original, &original).await;
+        });
+    }
+}
@@ -69,7 +69,7 @@ if [ -f "migrations/run_migrations.sql" ]; then
     echo -e "${GREEN}✓ Migrations completed${NC}"
 elif [ -f "migrations/001_initial_schema.sql" ]; then
     echo "  Running migration files in sequence..."
-    for migration in migrations/001_initial_schema.sql migrations/002_add_search_functions.sql migrations/002_fhir_extension_functions.sql migrations/003_fhir_search_helpers.sql; do
+    for migration in migrations/001_initial_schema.sql migrations/002_add_search_functions.sql migrations/002_fhir_extension_functions.sql migrations/003_fhir_search_helpers.sql migrations/005_resource_identifiers.sql migrations/006_soft_delete.sql migrations/007_request_capture.sql migrations/008_content_hash.sql migrations/009_name_phonetic.sql migrations/010_history_ts_index.sql migrations/011_change_outbox.sql migrations/012_resource_archive.sql migrations/013_schema_changes.sql migrations/014_conformance_canonical.sql migrations/015_id_reservations.sql migrations/016_full_text_search.sql migrations/017_custom_search_parameters.sql migrations/018_snapshots.sql migrations/019_api_keys.sql migrations/020_patient_match.sql migrations/021_conformance_maps.sql migrations/022_record_locks.sql migrations/023_change_notify.sql; do
         if [ -f "$migration" ]; then
             echo "  Running: $migration"
             PGPASSWORD=$DB_PASSWORD psql -U $DB_USER -h $DB_HOST -p $DB_PORT -d $DB_NAME -f "$migration"